@@ -0,0 +1,287 @@
+use crate::time;
+use crate::{
+    audit, authenticate_patient, authorize_auditor, authorize_doctor, authorize_hospital,
+    authorize_nurse, caller, impl_storable, kiosk_principal, Actor, Error, Memory, MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const MAX_ROLES_PER_ACCOUNT: usize = 16;
+
+// One of the accounts a person can act as
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum AccountRole {
+    Hospital(u64),
+    Doctor(u64),
+    Nurse(u64),
+    Patient(u64),
+    Auditor(u64),
+}
+
+// All roles held by one principal. A linked role is authorized by the caller's principal, so
+// the role's password can be left empty when calling an endpoint as that role
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub principal: Principal,
+    pub roles: Vec<AccountRole>,
+    pub updated_at: u64,
+}
+
+impl_storable!(Account, 1024);
+
+thread_local! {
+    static ACCOUNT_STORAGE: RefCell<StableBTreeMap<[u8; 32], Account, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct LinkRolePayload {
+    pub role: AccountRole,
+    // the role's own password, proving the caller owns it
+    pub password: String,
+}
+
+fn account_key(principal: &Principal) -> [u8; 32] {
+    Sha256::digest(principal.as_slice()).into()
+}
+
+pub(crate) fn account_of(principal: &Principal) -> Account {
+    ACCOUNT_STORAGE
+        .with(|s| s.borrow().get(&account_key(principal)))
+        .unwrap_or(Account {
+            principal: *principal,
+            roles: vec![],
+            updated_at: time(),
+        })
+}
+
+fn signed_in_caller() -> Result<Principal, Error> {
+    let caller = caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Sign in with an identity to link accounts".to_string(),
+        });
+    }
+    Ok(caller)
+}
+
+pub(crate) fn actor_of(role: AccountRole) -> Actor {
+    match role {
+        AccountRole::Hospital(id) => Actor::Hospital(id),
+        AccountRole::Doctor(id) => Actor::Doctor(id),
+        AccountRole::Nurse(id) => Actor::Nurse(id),
+        AccountRole::Patient(id) => Actor::Patient(id),
+        AccountRole::Auditor(id) => Actor::Auditor(id),
+    }
+}
+
+// whether the caller's principal has the role linked, checked by the authorize helpers
+pub(crate) fn caller_holds(role: AccountRole) -> bool {
+    let caller = caller();
+    caller != Principal::anonymous() && account_of(&caller).roles.contains(&role)
+}
+
+// link a role to a principal, used when an account is created for a signed-in caller
+pub(crate) fn link_account_role(principal: Principal, role: AccountRole) -> Result<Account, Error> {
+    if principal == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Roles cannot be linked to the anonymous identity".to_string(),
+        });
+    }
+    if kiosk_principal(&principal) {
+        return Err(Error::Unauthorized {
+            msg: "Roles cannot be linked to a kiosk device".to_string(),
+        });
+    }
+    let mut account = account_of(&principal);
+    if !account.roles.contains(&role) {
+        if account.roles.len() >= MAX_ROLES_PER_ACCOUNT {
+            return Err(Error::LimitExceeded {
+                msg: format!(
+                    "An account can hold at most {} roles",
+                    MAX_ROLES_PER_ACCOUNT
+                ),
+            });
+        }
+        account.roles.push(role);
+    }
+    account.updated_at = time();
+    ACCOUNT_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(account_key(&principal), account.clone())
+    });
+    Ok(account)
+}
+
+// add a role to the caller's account after checking the role's password
+#[ic_cdk::update]
+fn link_role(payload: LinkRolePayload) -> Result<Account, Error> {
+    let caller = signed_in_caller()?;
+    let (hospital_id, patient_id) = match payload.role {
+        AccountRole::Hospital(id) => (Some(authorize_hospital(id, &payload.password)?.id), None),
+        AccountRole::Doctor(id) => (
+            Some(authorize_doctor(id, &payload.password)?.hospital_id),
+            None,
+        ),
+        AccountRole::Nurse(id) => (
+            Some(authorize_nurse(id, &payload.password)?.hospital_id),
+            None,
+        ),
+        AccountRole::Patient(id) => (None, Some(authenticate_patient(id, &payload.password)?.id)),
+        AccountRole::Auditor(id) => (
+            Some(authorize_auditor(id, &payload.password)?.hospital_id),
+            None,
+        ),
+    };
+    let account = link_account_role(caller, payload.role)?;
+    audit(
+        actor_of(payload.role),
+        hospital_id,
+        patient_id,
+        "role_linked",
+        format!("linked to principal {}", caller),
+    );
+    Ok(account)
+}
+
+// remove a role from the caller's account, the role's password keeps working
+#[ic_cdk::update]
+fn unlink_role(role: AccountRole) -> Result<Account, Error> {
+    let caller = signed_in_caller()?;
+    let mut account = account_of(&caller);
+    if !account.roles.contains(&role) {
+        return Err(Error::NotFound {
+            msg: "Role is not linked to this account".to_string(),
+        });
+    }
+    account.roles.retain(|linked| *linked != role);
+    account.updated_at = time();
+    ACCOUNT_STORAGE.with(|s| {
+        let mut accounts = s.borrow_mut();
+        if account.roles.is_empty() {
+            accounts.remove(&account_key(&caller));
+        } else {
+            accounts.insert(account_key(&caller), account.clone());
+        }
+    });
+    audit(
+        actor_of(role),
+        None,
+        None,
+        "role_unlinked",
+        format!("unlinked from principal {}", caller),
+    );
+    Ok(account)
+}
+
+// every role the caller can act as without a password
+#[ic_cdk::query]
+fn get_my_account() -> Result<Account, Error> {
+    Ok(account_of(&signed_in_caller()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{clinic, must, principal, refused, sign_in, PASSWORD};
+    use crate::{
+        add_doctor, edit_doctor, edit_hospital, DoctorPayload, EditDoctor, EditHospitalPayload,
+    };
+
+    #[test]
+    fn a_linked_principal_acts_without_the_password() {
+        let clinic = clinic();
+        sign_in(principal(1));
+        must(link_role(LinkRolePayload {
+            role: AccountRole::Doctor(clinic.doctor_id),
+            password: PASSWORD.to_string(),
+        }));
+        assert!(authorize_doctor(clinic.doctor_id, "").is_ok());
+        assert!(authenticate_patient(clinic.patient_id, "").is_err());
+    }
+
+    #[test]
+    fn another_principal_still_needs_the_password() {
+        let clinic = clinic();
+        must(link_account_role(
+            principal(1),
+            AccountRole::Doctor(clinic.doctor_id),
+        ));
+        sign_in(principal(2));
+        assert!(refused(authorize_doctor(clinic.doctor_id, "")));
+        assert!(authorize_doctor(clinic.doctor_id, PASSWORD).is_ok());
+    }
+
+    #[test]
+    fn linking_needs_the_role_password_and_a_signed_in_caller() {
+        let clinic = clinic();
+        let payload = || LinkRolePayload {
+            role: AccountRole::Patient(clinic.patient_id),
+            password: PASSWORD.to_string(),
+        };
+        assert!(refused(link_role(payload())));
+        sign_in(principal(1));
+        assert!(refused(link_role(LinkRolePayload {
+            password: "not-the-password".to_string(),
+            ..payload()
+        })));
+        assert!(!caller_holds(AccountRole::Patient(clinic.patient_id)));
+        must(link_role(payload()));
+        assert!(caller_holds(AccountRole::Patient(clinic.patient_id)));
+    }
+
+    #[test]
+    fn linked_roles_manage_the_hospital_without_passwords() {
+        let clinic = clinic();
+        must(link_account_role(
+            principal(1),
+            AccountRole::Hospital(clinic.hospital_id),
+        ));
+        must(link_account_role(
+            principal(1),
+            AccountRole::Doctor(clinic.doctor_id),
+        ));
+        sign_in(principal(1));
+        must(edit_hospital(EditHospitalPayload {
+            hospital_id: clinic.hospital_id,
+            name: "Renamed".to_string(),
+            password: String::new(),
+        }));
+        must(add_doctor(DoctorPayload {
+            name: "Second doctor".to_string(),
+            hospital_id: clinic.hospital_id,
+            password: PASSWORD.to_string(),
+            hospital_password: String::new(),
+        }));
+        must(edit_doctor(EditDoctor {
+            name: "Renamed doctor".to_string(),
+            doctor_id: clinic.doctor_id,
+            hospital_id: clinic.hospital_id,
+            doctor_password: String::new(),
+            hospital_password: String::new(),
+        }));
+        sign_in(principal(2));
+        assert!(refused(edit_hospital(EditHospitalPayload {
+            hospital_id: clinic.hospital_id,
+            name: "Renamed".to_string(),
+            password: String::new(),
+        })));
+    }
+
+    #[test]
+    fn an_unlinked_role_stops_working() {
+        let clinic = clinic();
+        sign_in(principal(1));
+        must(link_account_role(
+            principal(1),
+            AccountRole::Doctor(clinic.doctor_id),
+        ));
+        must(unlink_role(AccountRole::Doctor(clinic.doctor_id)));
+        assert!(refused(authorize_doctor(clinic.doctor_id, "")));
+    }
+}
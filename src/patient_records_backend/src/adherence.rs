@@ -0,0 +1,201 @@
+use crate::time;
+use crate::{
+    authorize_doctor, check_not_sealed, patient_header, patient_medication_reminders,
+    prescriber_medication_reminders, Error, MedicationDoseStatus, MedicationReminder,
+};
+use std::collections::BTreeMap;
+
+// Doses of one prescription that came due in a period and what the patient logged for them
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct MedicationAdherence {
+    pub prescription_entry_id: u64,
+    pub medication: String,
+    pub due: u64,
+    pub taken: u64,
+    pub skipped: u64,
+    // neither taken nor skipped was logged
+    pub unconfirmed: u64,
+    // share of the due doses that were taken
+    pub adherence_percent: Option<u8>,
+    // most doses in a row that were not taken, and when that run started
+    pub longest_gap_doses: u64,
+    pub longest_gap_from: Option<u64>,
+    pub last_taken_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct PatientAdherence {
+    pub patient_id: u64,
+    pub due: u64,
+    pub taken: u64,
+    pub skipped: u64,
+    pub unconfirmed: u64,
+    pub adherence_percent: Option<u8>,
+    pub medications: Vec<MedicationAdherence>,
+}
+
+// Adherence of the patients a doctor prescribed for, lowest adherence first
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct PanelAdherence {
+    pub doctor_id: u64,
+    pub from: u64,
+    pub to: u64,
+    pub due: u64,
+    pub taken: u64,
+    pub adherence_percent: Option<u8>,
+    pub patients: Vec<PatientAdherence>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AdherencePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub from: u64,
+    pub to: u64,
+}
+
+fn percent(taken: u64, due: u64) -> Option<u8> {
+    (due > 0).then(|| (taken * 100 / due) as u8)
+}
+
+// the adherence of one prescription from its doses, in due order
+fn medication_adherence(doses: &[MedicationReminder]) -> MedicationAdherence {
+    let mut adherence = MedicationAdherence {
+        prescription_entry_id: doses[0].prescription_entry_id,
+        medication: doses[0].medication.clone(),
+        due: doses.len() as u64,
+        ..Default::default()
+    };
+    let mut gap = 0;
+    let mut gap_from = None;
+    for dose in doses {
+        match dose.status {
+            MedicationDoseStatus::Taken => {
+                adherence.taken += 1;
+                adherence.last_taken_at = Some(dose.due_at);
+                gap = 0;
+                continue;
+            }
+            MedicationDoseStatus::Skipped { .. } => adherence.skipped += 1,
+            MedicationDoseStatus::Pending => adherence.unconfirmed += 1,
+        }
+        if gap == 0 {
+            gap_from = Some(dose.due_at);
+        }
+        gap += 1;
+        if gap > adherence.longest_gap_doses {
+            adherence.longest_gap_doses = gap;
+            adherence.longest_gap_from = gap_from;
+        }
+    }
+    adherence.adherence_percent = percent(adherence.taken, adherence.due);
+    adherence
+}
+
+// adherence of a patient over the doses that came due between from and to
+fn patient_adherence(patient_id: u64, reminders: Vec<MedicationReminder>) -> PatientAdherence {
+    let mut by_prescription: BTreeMap<u64, Vec<MedicationReminder>> = BTreeMap::new();
+    for reminder in reminders {
+        by_prescription
+            .entry(reminder.prescription_entry_id)
+            .or_default()
+            .push(reminder);
+    }
+    let mut adherence = PatientAdherence {
+        patient_id,
+        ..Default::default()
+    };
+    for mut doses in by_prescription.into_values() {
+        doses.sort_by_key(|dose| dose.due_at);
+        let medication = medication_adherence(&doses);
+        adherence.due += medication.due;
+        adherence.taken += medication.taken;
+        adherence.skipped += medication.skipped;
+        adherence.unconfirmed += medication.unconfirmed;
+        adherence.medications.push(medication);
+    }
+    adherence.adherence_percent = percent(adherence.taken, adherence.due);
+    adherence
+}
+
+// the doses of a doctor's own prescriptions that came due in the period, for patients still
+// in the doctor's care. other prescribers' doses stay out of view
+fn prescribed_doses(
+    doctor_id: u64,
+    reminders: Vec<MedicationReminder>,
+    from: u64,
+    to: u64,
+) -> Vec<MedicationReminder> {
+    let now = time();
+    reminders
+        .into_iter()
+        .filter(|reminder| {
+            reminder.doctor_id == doctor_id
+                && reminder.due_at >= from
+                && reminder.due_at < to
+                && reminder.due_at <= now
+        })
+        .filter(|reminder| {
+            patient_header(reminder.patient_id)
+                .is_some_and(|patient| patient.doctors_ids.contains(&doctor_id))
+                && check_not_sealed(reminder.patient_id).is_ok()
+        })
+        .collect()
+}
+
+// how one patient keeps to the prescriptions the doctor wrote
+#[ic_cdk::query]
+fn get_patient_adherence(
+    payload: AdherencePayload,
+    patient_id: u64,
+) -> Result<PatientAdherence, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let doses = prescribed_doses(
+        doctor.id,
+        patient_medication_reminders(patient_id),
+        payload.from,
+        payload.to,
+    );
+    if doses.is_empty() {
+        return Err(Error::NotFound {
+            msg: format!(
+                "No doses prescribed by doctor {} for patient {} in this period",
+                doctor.id, patient_id
+            ),
+        });
+    }
+    Ok(patient_adherence(patient_id, doses))
+}
+
+// adherence across every patient the doctor prescribed for
+#[ic_cdk::query]
+fn get_panel_adherence(payload: AdherencePayload) -> Result<PanelAdherence, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let doses = prescribed_doses(
+        doctor.id,
+        prescriber_medication_reminders(doctor.id),
+        payload.from,
+        payload.to,
+    );
+    let mut by_patient: BTreeMap<u64, Vec<MedicationReminder>> = BTreeMap::new();
+    for dose in doses {
+        by_patient.entry(dose.patient_id).or_default().push(dose);
+    }
+    let mut panel = PanelAdherence {
+        doctor_id: doctor.id,
+        from: payload.from,
+        to: payload.to,
+        ..Default::default()
+    };
+    for (patient_id, doses) in by_patient {
+        let adherence = patient_adherence(patient_id, doses);
+        panel.due += adherence.due;
+        panel.taken += adherence.taken;
+        panel.patients.push(adherence);
+    }
+    panel.adherence_percent = percent(panel.taken, panel.due);
+    panel
+        .patients
+        .sort_by_key(|patient| patient.adherence_percent.unwrap_or(100));
+    Ok(panel)
+}
@@ -0,0 +1,215 @@
+use crate::time;
+use crate::{
+    audit, authorize_controller, authorize_doctor, impl_storable, next_id, notify, patient_header,
+    require_acknowledgement, text, Actor, Encounter, EncounterEntry, EncounterEntryKind, Error,
+    Memory, Priority, Recipient, Vitals, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum VitalSign {
+    SystolicBp,
+    DiastolicBp,
+    HeartRate,
+    RespiratoryRate,
+    Temperature,
+    OxygenSaturation,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum AlertMetric {
+    Vital(VitalSign),
+    // lab test name, matched case-insensitively, e.g. "potassium"
+    Lab(String),
+}
+
+// Raise an alert when a recorded value falls outside [min, max]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AlertRule {
+    pub id: u64,
+    // None for canister-wide rules set by the controllers
+    pub hospital_id: Option<u64>,
+    pub metric: AlertMetric,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub message: String,
+    pub enabled: bool,
+    pub created_at: u64,
+}
+
+impl_storable!(AlertRule, 512);
+
+thread_local! {
+    static ALERT_RULE_STORAGE: RefCell<StableBTreeMap<u64, AlertRule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AlertRulePayload {
+    pub metric: AlertMetric,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub message: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum RuleOwner {
+    // canister-wide rule, controllers only
+    Global,
+    Doctor {
+        doctor_id: u64,
+        doctor_password: String,
+    },
+}
+
+fn vital_value(vitals: &Vitals, sign: VitalSign) -> Option<f64> {
+    match sign {
+        VitalSign::SystolicBp => vitals.systolic_bp,
+        VitalSign::DiastolicBp => vitals.diastolic_bp,
+        VitalSign::HeartRate => vitals.heart_rate,
+        VitalSign::RespiratoryRate => vitals.respiratory_rate,
+        VitalSign::Temperature => vitals.temperature,
+        VitalSign::OxygenSaturation => vitals.oxygen_saturation,
+    }
+}
+
+// the value a rule looks at in an entry, if the entry carries it
+fn entry_value(entry: &EncounterEntry, metric: &AlertMetric) -> Option<f64> {
+    match (&entry.kind, metric) {
+        (EncounterEntryKind::Vitals(vitals), AlertMetric::Vital(sign)) => {
+            vital_value(vitals, *sign)
+        }
+        (EncounterEntryKind::LabResult { test, value, .. }, AlertMetric::Lab(name))
+            if test.eq_ignore_ascii_case(name) =>
+        {
+            Some(*value)
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn rule_owner_hospital(owner: &RuleOwner) -> Result<Option<u64>, Error> {
+    match owner {
+        RuleOwner::Global => authorize_controller().map(|_| None),
+        RuleOwner::Doctor {
+            doctor_id,
+            doctor_password,
+        } => authorize_doctor(*doctor_id, doctor_password).map(|doctor| Some(doctor.hospital_id)),
+    }
+}
+
+#[ic_cdk::update]
+fn add_alert_rule(owner: RuleOwner, payload: AlertRulePayload) -> Result<AlertRule, Error> {
+    let hospital_id = rule_owner_hospital(&owner)?;
+    if payload.min.is_none() && payload.max.is_none() {
+        return Err(Error::InvalidPayload {
+            msg: "Alert rules need a minimum or a maximum".to_string(),
+        });
+    }
+    let rule = AlertRule {
+        id: next_id(),
+        hospital_id,
+        metric: payload.metric,
+        min: payload.min,
+        max: payload.max,
+        message: payload.message,
+        enabled: true,
+        created_at: time(),
+    };
+    ALERT_RULE_STORAGE.with(|s| s.borrow_mut().insert(rule.id, rule.clone()));
+    Ok(rule)
+}
+
+#[ic_cdk::update]
+fn set_alert_rule_enabled(
+    owner: RuleOwner,
+    rule_id: u64,
+    enabled: bool,
+) -> Result<AlertRule, Error> {
+    let hospital_id = rule_owner_hospital(&owner)?;
+    let rule = ALERT_RULE_STORAGE
+        .with(|s| s.borrow().get(&rule_id))
+        .filter(|rule| rule.hospital_id == hospital_id)
+        .ok_or(Error::NotFound {
+            msg: format!("Alert rule of id: {} not found", rule_id),
+        })?;
+    let updated = AlertRule { enabled, ..rule };
+    ALERT_RULE_STORAGE.with(|s| s.borrow_mut().insert(updated.id, updated.clone()));
+    Ok(updated)
+}
+
+// global rules and the rules of one hospital
+#[ic_cdk::query]
+fn get_alert_rules(hospital_id: u64) -> Vec<AlertRule> {
+    ALERT_RULE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, rule)| rule)
+            .filter(|rule| rule.hospital_id.is_none() || rule.hospital_id == Some(hospital_id))
+            .collect()
+    })
+}
+
+// the messages of the enabled rules of a hospital that the values breach
+fn breached_rules(hospital_id: u64, value: impl Fn(&AlertMetric) -> Option<f64>) -> Vec<String> {
+    get_alert_rules(hospital_id)
+        .iter()
+        .filter(|rule| rule.enabled)
+        .filter_map(|rule| {
+            let value = value(&rule.metric)?;
+            let low = rule.min.is_some_and(|min| value < min);
+            let high = rule.max.is_some_and(|max| value > max);
+            (low || high).then(|| format!("{} ({})", rule.message, value))
+        })
+        .collect()
+}
+
+// the vital sign rules of a hospital that a reading breaches
+pub(crate) fn vitals_breaches(hospital_id: u64, vitals: &Vitals) -> Vec<String> {
+    breached_rules(hospital_id, |metric| match metric {
+        AlertMetric::Vital(sign) => vital_value(vitals, *sign),
+        AlertMetric::Lab(_) => None,
+    })
+}
+
+// run the rules against a freshly recorded entry and page the care team on breaches
+pub(crate) fn evaluate_alert_rules(encounter: &Encounter, entry: &EncounterEntry) {
+    let breaches = breached_rules(encounter.hospital_id, |metric| entry_value(entry, metric));
+    if breaches.is_empty() {
+        return;
+    }
+
+    let alert_text = || {
+        text(
+            "alert.triggered",
+            "Alert for patient {patient}: {breaches}",
+            vec![
+                ("patient", encounter.patient_id.to_string()),
+                ("breaches", breaches.join("; ")),
+            ],
+        )
+    };
+    let care_team = patient_header(encounter.patient_id)
+        .map(|patient| patient.doctors_ids)
+        .unwrap_or_default();
+    for doctor_id in care_team {
+        notify(Recipient::Doctor(doctor_id), Priority::High, alert_text());
+    }
+    notify(
+        Recipient::Hospital(encounter.hospital_id),
+        Priority::High,
+        alert_text(),
+    );
+    audit(
+        Actor::System,
+        Some(encounter.hospital_id),
+        Some(encounter.patient_id),
+        "clinical_alert",
+        alert_text().render(None),
+    );
+    require_acknowledgement(encounter, entry);
+}
@@ -0,0 +1,138 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_patient_access, get_assigned_patient, impl_storable,
+    next_id, remember_change, Actor, ChangeRef, Error, Memory, PatientAccess, PreviousValue,
+    MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum AllergySeverity {
+    Mild,
+    Moderate,
+    Severe,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Allergy {
+    pub id: u64,
+    pub patient_id: u64,
+    pub substance: String,
+    pub reaction: String,
+    pub severity: AllergySeverity,
+    pub recorded_by: u64,
+    pub recorded_at: u64,
+    pub active: bool,
+}
+
+impl_storable!(Allergy, 512);
+
+thread_local! {
+    static ALLERGY_STORAGE: RefCell<StableBTreeMap<u64, Allergy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AllergyPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub substance: String,
+    pub reaction: String,
+    pub severity: AllergySeverity,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AllergyAccessPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub allergy_id: u64,
+}
+
+// active allergies of a patient
+pub(crate) fn patient_allergies(patient_id: u64) -> Vec<Allergy> {
+    ALLERGY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, allergy)| allergy)
+            .filter(|allergy| allergy.patient_id == patient_id && allergy.active)
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+pub(crate) fn add_allergy(payload: AllergyPayload) -> Result<Allergy, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    if payload.substance.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Allergy substance cannot be empty".to_string(),
+        });
+    }
+    let allergy = Allergy {
+        id: next_id(),
+        patient_id: patient.id,
+        substance: payload.substance,
+        reaction: payload.reaction,
+        severity: payload.severity,
+        recorded_by: doctor.id,
+        recorded_at: time(),
+        active: true,
+    };
+    ALLERGY_STORAGE.with(|s| s.borrow_mut().insert(allergy.id, allergy.clone()));
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "allergy_recorded",
+        format!("allergy {}", allergy.id),
+    );
+    Ok(allergy)
+}
+
+// mark an allergy as entered in error or no longer relevant
+#[ic_cdk::update]
+fn deactivate_allergy(payload: AllergyAccessPayload) -> Result<Allergy, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let allergy = ALLERGY_STORAGE
+        .with(|s| s.borrow().get(&payload.allergy_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Allergy of id: {} not found", payload.allergy_id),
+        })?;
+    get_assigned_patient(&doctor, allergy.patient_id)?;
+    remember_change(
+        ChangeRef::Allergy(allergy.id),
+        "allergy_deactivated",
+        PreviousValue::AllergyActive(allergy.active),
+        Actor::Doctor(doctor.id),
+        vec![doctor.hospital_id],
+    );
+    set_allergy_active(allergy.id, false)
+}
+
+pub(crate) fn allergy_patient(allergy_id: u64) -> Option<u64> {
+    ALLERGY_STORAGE
+        .with(|s| s.borrow().get(&allergy_id))
+        .map(|allergy| allergy.patient_id)
+}
+
+pub(crate) fn set_allergy_active(allergy_id: u64, active: bool) -> Result<Allergy, Error> {
+    let allergy = ALLERGY_STORAGE
+        .with(|s| s.borrow().get(&allergy_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Allergy of id: {} not found", allergy_id),
+        })?;
+    let updated = Allergy { active, ..allergy };
+    ALLERGY_STORAGE.with(|s| s.borrow_mut().insert(updated.id, updated.clone()));
+    Ok(updated)
+}
+
+#[ic_cdk::query]
+fn get_patient_allergies(patient_id: u64, access: PatientAccess) -> Result<Vec<Allergy>, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    Ok(patient_allergies(patient.id))
+}
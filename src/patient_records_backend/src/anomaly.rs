@@ -0,0 +1,402 @@
+use crate::time;
+use crate::{
+    audit, audit_entries_after, audit_entry, authorize_oversight, impl_storable, next_id, notify,
+    oversight_actor, text, utc_offset, Actor, AuditEntry, Error, Memory, OversightRole, Priority,
+    Recipient, DOCTOR_STORAGE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+// audit entries scanned per timer run
+const SCAN_BATCH: usize = 5_000;
+// distinct patients one staff member touches in an hour before it looks like bulk access
+const MAX_PATIENTS_PER_HOUR: usize = 25;
+// patients a doctor is not assigned to touched in an hour
+const MAX_UNRELATED_PATIENTS_PER_HOUR: usize = 5;
+// patient accesses in one off-hours hour, local time of the hospital
+const MAX_OFF_HOURS_ACCESSES: usize = 20;
+const OFF_HOURS_START: u64 = 22;
+const OFF_HOURS_END: u64 = 6;
+const MAX_FAILED_PASSWORDS_PER_HOUR: usize = 5;
+// entries attached to an anomaly
+const MAX_ATTACHED_ENTRIES: usize = 50;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum AnomalyKind {
+    BulkPatientAccess,
+    UnrelatedPatientAccess,
+    OffHoursBulkAccess,
+    RepeatedFailedPasswords,
+}
+
+// Access that looked unusual, for an auditor to look into
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AccessAnomaly {
+    pub id: u64,
+    pub kind: AnomalyKind,
+    pub actor: Actor,
+    pub hospital_id: Option<u64>,
+    pub patient_id: Option<u64>,
+    // the hour the access happened in
+    pub window_start: u64,
+    pub description: String,
+    // sequence numbers of the audit entries behind the flag
+    pub entry_seqs: Vec<u64>,
+    pub detected_at: u64,
+    pub reviewed_by: Option<Actor>,
+    pub reviewed_at: Option<u64>,
+}
+
+// An anomaly with its audit entries, those rolled up since are left out
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AnomalyReport {
+    pub anomaly: AccessAnomaly,
+    pub entries: Vec<AuditEntry>,
+}
+
+// Last audit entry the detector looked at
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AnomalyScanCursor {
+    pub last_seq: Option<u64>,
+}
+
+impl_storable!(AccessAnomaly, 2048);
+impl_storable!(AnomalyScanCursor, 64);
+
+thread_local! {
+    static ANOMALY_STORAGE: RefCell<StableBTreeMap<u64, AccessAnomaly, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78)))
+    ));
+
+    static ANOMALY_SCAN_CURSOR: RefCell<Cell<AnomalyScanCursor, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79))),
+            AnomalyScanCursor::default(),
+        )
+        .expect("Cannot create anomaly scan cursor")
+    );
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AnomalyListPayload {
+    pub role: OversightRole,
+    pub password: String,
+    pub include_reviewed: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AnomalyReviewPayload {
+    pub role: OversightRole,
+    pub password: String,
+    pub anomaly_id: u64,
+}
+
+fn is_staff(actor: &Actor) -> bool {
+    matches!(actor, Actor::Doctor(_) | Actor::Nurse(_))
+}
+
+fn off_hours(entry: &AuditEntry) -> bool {
+    let offset_ns = entry.hospital_id.map_or(0, |id| {
+        utc_offset(&Recipient::Hospital(id)) as i64 * 60 * 1_000_000_000
+    });
+    let local = (entry.timestamp as i64 + offset_ns).max(0) as u64;
+    let hour = local / HOUR_NS % 24;
+    !(OFF_HOURS_END..OFF_HOURS_START).contains(&hour)
+}
+
+fn unrelated(actor: &Actor, patient_id: u64) -> bool {
+    match actor {
+        Actor::Doctor(id) => DOCTOR_STORAGE
+            .with(|s| s.borrow().get(id))
+            .is_some_and(|doctor| !doctor.patient_ids.contains(&patient_id)),
+        _ => false,
+    }
+}
+
+fn flag(kind: AnomalyKind, entries: &[&AuditEntry], description: String) -> AccessAnomaly {
+    let first = entries[0];
+    let patients: BTreeSet<u64> = entries.iter().filter_map(|e| e.patient_id).collect();
+    AccessAnomaly {
+        id: next_id(),
+        kind,
+        actor: first.actor.clone(),
+        hospital_id: entries.iter().find_map(|entry| entry.hospital_id),
+        patient_id: match patients.len() {
+            1 => patients.first().copied(),
+            _ => None,
+        },
+        window_start: first.timestamp / HOUR_NS * HOUR_NS,
+        description,
+        entry_seqs: entries
+            .iter()
+            .take(MAX_ATTACHED_ENTRIES)
+            .map(|entry| entry.seq)
+            .collect(),
+        detected_at: time(),
+        reviewed_by: None,
+        reviewed_at: None,
+    }
+}
+
+// apply the heuristics to the entries of one actor within one hour
+fn detect(entries: &[&AuditEntry]) -> Vec<AccessAnomaly> {
+    let mut anomalies = vec![];
+    let actor = &entries[0].actor;
+    let failed: Vec<&AuditEntry> = entries
+        .iter()
+        .copied()
+        .filter(|entry| entry.action == "password_failed")
+        .collect();
+    if failed.len() >= MAX_FAILED_PASSWORDS_PER_HOUR {
+        anomalies.push(flag(
+            AnomalyKind::RepeatedFailedPasswords,
+            &failed,
+            format!("{} failed password attempts within an hour", failed.len()),
+        ));
+    }
+    if !is_staff(actor) {
+        return anomalies;
+    }
+
+    let accesses: Vec<&AuditEntry> = entries
+        .iter()
+        .copied()
+        .filter(|entry| entry.patient_id.is_some() && entry.action != "password_failed")
+        .collect();
+    let patients: BTreeSet<u64> = accesses.iter().filter_map(|e| e.patient_id).collect();
+    if patients.len() > MAX_PATIENTS_PER_HOUR {
+        anomalies.push(flag(
+            AnomalyKind::BulkPatientAccess,
+            &accesses,
+            format!("{} different patients within an hour", patients.len()),
+        ));
+    }
+    let unrelated_entries: Vec<&AuditEntry> = accesses
+        .iter()
+        .copied()
+        .filter(|entry| entry.patient_id.is_some_and(|id| unrelated(actor, id)))
+        .collect();
+    let unrelated_patients: BTreeSet<u64> = unrelated_entries
+        .iter()
+        .filter_map(|entry| entry.patient_id)
+        .collect();
+    if unrelated_patients.len() >= MAX_UNRELATED_PATIENTS_PER_HOUR {
+        anomalies.push(flag(
+            AnomalyKind::UnrelatedPatientAccess,
+            &unrelated_entries,
+            format!(
+                "{} patients the doctor is not assigned to within an hour",
+                unrelated_patients.len()
+            ),
+        ));
+    }
+    let off_hours_entries: Vec<&AuditEntry> = accesses
+        .iter()
+        .copied()
+        .filter(|entry| off_hours(entry))
+        .collect();
+    if off_hours_entries.len() >= MAX_OFF_HOURS_ACCESSES {
+        anomalies.push(flag(
+            AnomalyKind::OffHoursBulkAccess,
+            &off_hours_entries,
+            format!(
+                "{} patient accesses outside working hours",
+                off_hours_entries.len()
+            ),
+        ));
+    }
+    anomalies
+}
+
+fn raise(anomaly: &AccessAnomaly) {
+    ANOMALY_STORAGE.with(|s| s.borrow_mut().insert(anomaly.id, anomaly.clone()));
+    // oversight reads anomalies through the hospital; a patient's own account alerts the patient
+    let recipient = match (anomaly.hospital_id, anomaly.patient_id) {
+        (Some(hospital_id), _) => Recipient::Hospital(hospital_id),
+        (None, Some(patient_id)) => Recipient::Patient(patient_id),
+        (None, None) => return,
+    };
+    notify(
+        recipient,
+        Priority::High,
+        text(
+            "anomaly.detected",
+            "Unusual access detected: {description}. Review anomaly {id} with its {count} audit entries.",
+            vec![
+                ("description", anomaly.description.clone()),
+                ("id", anomaly.id.to_string()),
+                ("count", anomaly.entry_seqs.len().to_string()),
+            ],
+        ),
+    );
+}
+
+// timer task: run the heuristics over audit entries written since the last run
+pub(crate) fn detect_access_anomalies() {
+    let cursor = ANOMALY_SCAN_CURSOR.with(|c| c.borrow().get().clone());
+    let entries = audit_entries_after(cursor.last_seq, SCAN_BATCH);
+    let Some(last) = entries.last() else {
+        return;
+    };
+    let last_seq = last.seq;
+
+    let mut windows: BTreeMap<(String, u64), Vec<&AuditEntry>> = BTreeMap::new();
+    for entry in &entries {
+        if entry.action.starts_with("anomaly_") {
+            continue;
+        }
+        let actor = serde_json::to_string(&entry.actor).expect("Cannot serialize actor");
+        windows
+            .entry((actor, entry.timestamp / HOUR_NS))
+            .or_default()
+            .push(entry);
+    }
+    for window in windows.values() {
+        for anomaly in detect(window) {
+            raise(&anomaly);
+        }
+    }
+    ANOMALY_SCAN_CURSOR
+        .with(|c| {
+            c.borrow_mut().set(AnomalyScanCursor {
+                last_seq: Some(last_seq),
+            })
+        })
+        .expect("Cannot update anomaly scan cursor");
+}
+
+// the hospital's flagged anomalies with the audit entries behind them, newest first
+#[ic_cdk::query]
+fn get_access_anomalies(payload: AnomalyListPayload) -> Result<Vec<AnomalyReport>, Error> {
+    let hospital_id = authorize_oversight(&payload.role, &payload.password)?;
+    let mut reports: Vec<AnomalyReport> = ANOMALY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, anomaly)| anomaly)
+            .filter(|anomaly| {
+                anomaly.hospital_id == Some(hospital_id)
+                    && (payload.include_reviewed || anomaly.reviewed_at.is_none())
+            })
+            .map(|anomaly| AnomalyReport {
+                entries: anomaly
+                    .entry_seqs
+                    .iter()
+                    .filter_map(|seq| audit_entry(*seq))
+                    .collect(),
+                anomaly,
+            })
+            .collect()
+    });
+    reports.reverse();
+    Ok(reports)
+}
+
+// mark an anomaly as looked into
+#[ic_cdk::update]
+fn review_access_anomaly(payload: AnomalyReviewPayload) -> Result<AccessAnomaly, Error> {
+    let hospital_id = authorize_oversight(&payload.role, &payload.password)?;
+    let anomaly = ANOMALY_STORAGE
+        .with(|s| s.borrow().get(&payload.anomaly_id))
+        .filter(|anomaly| anomaly.hospital_id == Some(hospital_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Anomaly of id: {} not found", payload.anomaly_id),
+        })?;
+    let reviewer = oversight_actor(&payload.role);
+    let reviewed = AccessAnomaly {
+        reviewed_by: Some(reviewer.clone()),
+        reviewed_at: Some(time()),
+        ..anomaly
+    };
+    ANOMALY_STORAGE.with(|s| s.borrow_mut().insert(reviewed.id, reviewed.clone()));
+    audit(
+        reviewer,
+        Some(hospital_id),
+        reviewed.patient_id,
+        "anomaly_reviewed",
+        format!("anomaly {}", reviewed.id),
+    );
+    Ok(reviewed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{clinic, refused, Clinic};
+    use crate::{
+        add_doctor, edit_doctor, edit_hospital, DoctorPayload, EditDoctor, EditHospitalPayload,
+    };
+
+    const WRONG_PASSWORD: &str = "not-the-password";
+
+    fn failed_password_flags(actor: Actor) -> usize {
+        detect_access_anomalies();
+        ANOMALY_STORAGE.with(|s| {
+            s.borrow()
+                .iter()
+                .filter(|(_, anomaly)| {
+                    anomaly.kind == AnomalyKind::RepeatedFailedPasswords && anomaly.actor == actor
+                })
+                .count()
+        })
+    }
+
+    fn guess_doctor_password(clinic: &Clinic) {
+        assert!(refused(edit_doctor(EditDoctor {
+            name: "Renamed".to_string(),
+            doctor_id: clinic.doctor_id,
+            hospital_id: clinic.hospital_id,
+            doctor_password: WRONG_PASSWORD.to_string(),
+            hospital_password: WRONG_PASSWORD.to_string(),
+        })));
+    }
+
+    #[test]
+    fn guessing_a_doctor_password_through_edits_is_flagged() {
+        let clinic = clinic();
+        for _ in 0..MAX_FAILED_PASSWORDS_PER_HOUR {
+            guess_doctor_password(&clinic);
+        }
+        assert_eq!(failed_password_flags(Actor::Doctor(clinic.doctor_id)), 1);
+    }
+
+    #[test]
+    fn a_few_mistyped_passwords_are_not_flagged() {
+        let clinic = clinic();
+        for _ in 1..MAX_FAILED_PASSWORDS_PER_HOUR {
+            guess_doctor_password(&clinic);
+        }
+        assert_eq!(failed_password_flags(Actor::Doctor(clinic.doctor_id)), 0);
+    }
+
+    #[test]
+    fn guessing_a_hospital_password_through_edits_is_flagged() {
+        let clinic = clinic();
+        for attempt in 0..MAX_FAILED_PASSWORDS_PER_HOUR {
+            let result = if attempt % 2 == 0 {
+                edit_hospital(EditHospitalPayload {
+                    hospital_id: clinic.hospital_id,
+                    name: "Renamed".to_string(),
+                    password: WRONG_PASSWORD.to_string(),
+                })
+                .map(|_| ())
+            } else {
+                add_doctor(DoctorPayload {
+                    name: "Intruder".to_string(),
+                    hospital_id: clinic.hospital_id,
+                    password: WRONG_PASSWORD.to_string(),
+                    hospital_password: WRONG_PASSWORD.to_string(),
+                })
+                .map(|_| ())
+            };
+            assert!(refused(result));
+        }
+        assert_eq!(
+            failed_password_flags(Actor::Hospital(clinic.hospital_id)),
+            1
+        );
+    }
+}
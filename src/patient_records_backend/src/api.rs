@@ -0,0 +1,109 @@
+use crate::{
+    authorize_patient_access, custom_field_values, record_encounter_entry, BloodType,
+    CustomFieldValue, EncounterEntry, EncounterEntryPayload, Error, Patient, PatientAccess,
+    ResultWithWarnings, Sex,
+};
+
+// bumped when an endpoint family gets a breaking successor
+const CURRENT_API_VERSION: u32 = 2;
+// versions whose endpoints are still served
+const SUPPORTED_API_VERSIONS: [u32; 2] = [1, 2];
+
+// endpoint, replacement, version it was deprecated in, note
+const DEPRECATIONS: &[(&str, &str, u32, &str)] = &[
+    (
+        "get_patient",
+        "v2_get_patient_details",
+        2,
+        "returns a patient to any caller without credentials",
+    ),
+    (
+        "get_patient_info",
+        "v2_get_patient_details",
+        2,
+        "doctor-only, returns the history entries flattened to text",
+    ),
+    (
+        "add_encounter_entry",
+        "v2_add_encounter_entry",
+        2,
+        "does not return validation warnings",
+    ),
+];
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Deprecation {
+    pub endpoint: String,
+    pub replacement: String,
+    pub deprecated_in: u32,
+    pub note: String,
+}
+
+// What a client needs to pick the endpoints it calls
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ApiInfo {
+    pub current_version: u32,
+    pub supported_versions: Vec<u32>,
+    pub deprecations: Vec<Deprecation>,
+}
+
+// A patient without credentials or the legacy history, which lives in medical records now
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PatientDetailsV2 {
+    pub id: u64,
+    pub name: String,
+    pub blood_type: Option<BloodType>,
+    pub date_of_birth: Option<u64>,
+    pub sex: Option<Sex>,
+    pub doctors_ids: Vec<u64>,
+    pub hospitals_ids: Vec<u64>,
+    pub custom_fields: Vec<CustomFieldValue>,
+}
+
+// shim from the stored patient to the v2 shape
+pub(crate) fn patient_details_v2(patient: Patient) -> PatientDetailsV2 {
+    PatientDetailsV2 {
+        custom_fields: custom_field_values(patient.id),
+        id: patient.id,
+        name: patient.name,
+        blood_type: patient.blood_type,
+        date_of_birth: patient.date_of_birth,
+        sex: patient.sex,
+        doctors_ids: patient.doctors_ids,
+        hospitals_ids: patient.hospitals_ids,
+    }
+}
+
+#[ic_cdk::query]
+fn get_api_info() -> ApiInfo {
+    ApiInfo {
+        current_version: CURRENT_API_VERSION,
+        supported_versions: SUPPORTED_API_VERSIONS.to_vec(),
+        deprecations: DEPRECATIONS
+            .iter()
+            .map(|(endpoint, replacement, deprecated_in, note)| Deprecation {
+                endpoint: endpoint.to_string(),
+                replacement: replacement.to_string(),
+                deprecated_in: *deprecated_in,
+                note: note.to_string(),
+            })
+            .collect(),
+    }
+}
+
+// a patient's details for the patient or one of their assigned doctors
+#[ic_cdk::query]
+fn v2_get_patient_details(
+    patient_id: u64,
+    access: PatientAccess,
+) -> Result<PatientDetailsV2, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    Ok(patient_details_v2(patient))
+}
+
+#[ic_cdk::update]
+fn v2_add_encounter_entry(
+    payload: EncounterEntryPayload,
+) -> Result<ResultWithWarnings<EncounterEntry>, Error> {
+    record_encounter_entry(payload)
+}
@@ -0,0 +1,326 @@
+use crate::time;
+use crate::{
+    audit, authenticate_patient, check_not_sealed, custom_field_values, impl_storable,
+    issue_consent_receipt, next_id, patient_allergies, patient_prescriptions, patient_records,
+    patient_vitals, reject_kiosk_caller, scope_labels, to_hex, upcoming_appointments, Actor,
+    Allergy, Appointment, BloodType, ConsentAction, CustomFieldValue, EncounterEntry, Error,
+    MedicalRecord, Memory, PatientConsent, MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// keeps a token inside its 512 bytes
+const MAX_APP_NAME_BYTES: usize = 100;
+
+// What a third-party app may read
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum AppScope {
+    Demographics,
+    Records,
+    Allergies,
+    Vitals,
+    Medications,
+    Appointments,
+}
+
+// A capability a patient handed to an app; only the token hash is kept
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AppToken {
+    pub id: u64,
+    pub patient_id: u64,
+    pub app_name: String,
+    pub scopes: Vec<AppScope>,
+    pub token_hash: Vec<u8>,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    pub revoked_at: Option<u64>,
+    pub last_used_at: Option<u64>,
+}
+
+// Returned once on issue, the secret is not stored
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct IssuedAppToken {
+    pub token: String,
+    pub details: AppToken,
+}
+
+// The patient's data an app can see, sections outside its scopes are left empty
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AppData {
+    pub patient_id: u64,
+    pub name: Option<String>,
+    pub blood_type: Option<BloodType>,
+    // the hospitals' own fields on the patient, part of the demographics scope
+    pub custom_fields: Option<Vec<CustomFieldValue>>,
+    pub records: Option<Vec<MedicalRecord>>,
+    pub allergies: Option<Vec<Allergy>>,
+    pub vitals: Option<Vec<EncounterEntry>>,
+    pub medications: Option<Vec<EncounterEntry>>,
+    pub appointments: Option<Vec<Appointment>>,
+}
+
+impl_storable!(AppToken, 512);
+
+thread_local! {
+    static APP_TOKEN_STORAGE: RefCell<StableBTreeMap<u64, AppToken, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct IssueAppTokenPayload {
+    pub patient_id: u64,
+    pub patient_password: String,
+    pub app_name: String,
+    pub scopes: Vec<AppScope>,
+    pub expires_at: u64,
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+pub(crate) fn patient_tokens(patient_id: u64) -> Vec<AppToken> {
+    APP_TOKEN_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, token)| token)
+            .filter(|token| token.patient_id == patient_id)
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+async fn issue_app_token(payload: IssueAppTokenPayload) -> Result<IssuedAppToken, Error> {
+    let patient = authenticate_patient(payload.patient_id, &payload.patient_password)?;
+    if payload.scopes.is_empty() || payload.expires_at <= time() {
+        return Err(Error::InvalidPayload {
+            msg: "App tokens need at least one scope and a future expiry".to_string(),
+        });
+    }
+    if payload.app_name.trim().is_empty() || payload.app_name.len() > MAX_APP_NAME_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("App name must be 1 to {} bytes", MAX_APP_NAME_BYTES),
+        });
+    }
+    let mut scopes: Vec<AppScope> = vec![];
+    for scope in payload.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    let (random,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| Error::InvalidPayload {
+            msg: format!("Could not create token: {:?} {}", code, msg),
+        })?;
+    let token = to_hex(&random);
+    let details = AppToken {
+        id: next_id(),
+        patient_id: patient.id,
+        app_name: payload.app_name,
+        scopes,
+        token_hash: hash_token(&token),
+        issued_at: time(),
+        expires_at: payload.expires_at,
+        revoked_at: None,
+        last_used_at: None,
+    };
+    APP_TOKEN_STORAGE.with(|s| s.borrow_mut().insert(details.id, details.clone()));
+    audit(
+        Actor::Patient(patient.id),
+        None,
+        Some(patient.id),
+        "app_token_issued",
+        format!("token {} for {}", details.id, details.app_name),
+    );
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Granted,
+        "app_access",
+        details.id,
+        format!("app {}", details.app_name),
+        scope_labels(&details.scopes),
+        Some(details.expires_at),
+    );
+    Ok(IssuedAppToken { token, details })
+}
+
+#[ic_cdk::query]
+fn get_app_tokens(consent: PatientConsent) -> Result<Vec<AppToken>, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    Ok(patient_tokens(patient.id))
+}
+
+#[ic_cdk::update]
+fn revoke_app_token(consent: PatientConsent, token_id: u64) -> Result<AppToken, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    let token = patient_tokens(patient.id)
+        .into_iter()
+        .find(|token| token.id == token_id)
+        .ok_or(Error::NotFound {
+            msg: format!("App token of id: {} not found", token_id),
+        })?;
+    let revoked = AppToken {
+        revoked_at: Some(time()),
+        ..token
+    };
+    APP_TOKEN_STORAGE.with(|s| s.borrow_mut().insert(revoked.id, revoked.clone()));
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Revoked,
+        "app_access",
+        revoked.id,
+        format!("app {}", revoked.app_name),
+        scope_labels(&revoked.scopes),
+        Some(revoked.expires_at),
+    );
+    Ok(revoked)
+}
+
+// the only endpoint apps can call, an update so each use lands in the audit log
+#[ic_cdk::update]
+fn get_app_data(token: String) -> Result<AppData, Error> {
+    reject_kiosk_caller()?;
+    let now = time();
+    let token_hash = hash_token(&token);
+    let app_token = APP_TOKEN_STORAGE
+        .with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, app_token)| app_token)
+                .find(|app_token| app_token.token_hash == token_hash)
+        })
+        .filter(|app_token| app_token.revoked_at.is_none() && app_token.expires_at > now)
+        .ok_or(Error::Unauthorized {
+            msg: "App token is invalid, expired or revoked".to_string(),
+        })?;
+    check_not_sealed(app_token.patient_id)?;
+    let patient = PATIENT_STORAGE
+        .with(|s| s.borrow().get(&app_token.patient_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Patient of id: {} not found", app_token.patient_id),
+        })?;
+
+    let allowed = |scope: AppScope| app_token.scopes.contains(&scope);
+    let mut data = AppData {
+        patient_id: patient.id,
+        ..Default::default()
+    };
+    if allowed(AppScope::Demographics) {
+        data.name = Some(patient.name.clone());
+        data.blood_type = patient.blood_type;
+        data.custom_fields = Some(custom_field_values(patient.id));
+    }
+    if allowed(AppScope::Records) {
+        data.records = Some(patient_records(patient.id));
+    }
+    if allowed(AppScope::Allergies) {
+        data.allergies = Some(patient_allergies(patient.id));
+    }
+    if allowed(AppScope::Vitals) {
+        data.vitals = Some(patient_vitals(patient.id));
+    }
+    if allowed(AppScope::Medications) {
+        data.medications = Some(
+            patient_prescriptions(patient.id)
+                .into_iter()
+                .map(|(entry, _)| entry)
+                .collect(),
+        );
+    }
+    if allowed(AppScope::Appointments) {
+        data.appointments = Some(upcoming_appointments(patient.id));
+    }
+
+    APP_TOKEN_STORAGE.with(|s| {
+        s.borrow_mut().insert(
+            app_token.id,
+            AppToken {
+                last_used_at: Some(now),
+                ..app_token.clone()
+            },
+        )
+    });
+    audit(
+        Actor::App(app_token.id),
+        None,
+        Some(patient.id),
+        "app_read",
+        format!(
+            "{} read {} scopes",
+            app_token.app_name,
+            app_token.scopes.len()
+        ),
+    );
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{advance_clock, clinic, must, refused, PASSWORD};
+
+    const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+    // issue_app_token draws its secret from raw_rand, so tests store the token directly
+    fn grant(patient_id: u64, token: &str, scopes: Vec<AppScope>) -> AppToken {
+        let details = AppToken {
+            id: next_id(),
+            patient_id,
+            app_name: "Step counter".to_string(),
+            scopes,
+            token_hash: hash_token(token),
+            issued_at: time(),
+            expires_at: time() + DAY_NS,
+            revoked_at: None,
+            last_used_at: None,
+        };
+        APP_TOKEN_STORAGE.with(|s| s.borrow_mut().insert(details.id, details.clone()));
+        details
+    }
+
+    fn consent(patient_id: u64) -> PatientConsent {
+        PatientConsent {
+            patient_id,
+            patient_password: PASSWORD.to_string(),
+        }
+    }
+
+    #[test]
+    fn a_token_reads_only_its_scopes_and_only_its_hash_is_kept() {
+        let clinic = clinic();
+        let details = grant(clinic.patient_id, "secret", vec![AppScope::Demographics]);
+        assert_ne!(details.token_hash, b"secret".to_vec());
+        let data = must(get_app_data("secret".to_string()));
+        assert_eq!(data.patient_id, clinic.patient_id);
+        assert!(data.name.is_some());
+        assert!(data.records.is_none() && data.allergies.is_none());
+        // the stored hash is not a token
+        assert!(refused(get_app_data(to_hex(&details.token_hash))));
+        assert!(refused(get_app_data("guess".to_string())));
+    }
+
+    #[test]
+    fn an_expired_token_is_refused() {
+        let clinic = clinic();
+        grant(clinic.patient_id, "secret", vec![AppScope::Records]);
+        advance_clock(DAY_NS);
+        assert!(refused(get_app_data("secret".to_string())));
+    }
+
+    #[test]
+    fn a_revoked_token_is_refused_and_only_its_patient_can_revoke_it() {
+        let owner = clinic();
+        let other = clinic();
+        let details = grant(owner.patient_id, "secret", vec![AppScope::Records]);
+        assert!(revoke_app_token(consent(other.patient_id), details.id).is_err());
+        must(get_app_data("secret".to_string()));
+        must(revoke_app_token(consent(owner.patient_id), details.id));
+        assert!(refused(get_app_data("secret".to_string())));
+    }
+}
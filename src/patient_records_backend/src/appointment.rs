@@ -0,0 +1,456 @@
+use crate::time;
+use crate::{
+    authenticate_patient, authorize_doctor, check_site, format_local_time, impl_storable, next_id,
+    offer_slot_to_waitlist, parse_local_time, utc_offset, waitlist_offer_claimed, Doctor, Error,
+    Memory, PatientConsent, Recipient, DOCTOR_STORAGE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// how long a held slot waits for confirmation
+const HOLD_NS: u64 = 5 * 60 * 1_000_000_000;
+const MAX_APPOINTMENT_NS: u64 = 12 * 60 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum AppointmentStatus {
+    // slot reserved but waiting for the patient to confirm
+    Held,
+    Scheduled,
+    Cancelled,
+    Completed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Appointment {
+    pub id: u64,
+    pub patient_id: u64,
+    pub doctor_id: u64,
+    pub hospital_id: u64,
+    // the site of the hospital the appointment is at
+    pub site_id: Option<u64>,
+    pub start: u64,
+    pub end: u64,
+    pub reason: String,
+    pub status: AppointmentStatus,
+    pub created_at: u64,
+    pub hold_expires_at: Option<u64>,
+    // the recurring series this is an occurrence of
+    pub series_id: Option<u64>,
+}
+
+impl_storable!(Appointment, 512);
+
+thread_local! {
+    static APPOINTMENT_STORAGE: RefCell<StableBTreeMap<u64, Appointment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // (doctor id, slot start) -> appointment id of every held or scheduled slot
+    static SLOT_INDEX: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct BookAppointmentPayload {
+    pub patient_id: u64,
+    pub patient_password: String,
+    pub doctor_id: u64,
+    pub site_id: Option<u64>,
+    // local times like 2024-03-01T09:30:00+01:00, without an offset in the hospital's timezone
+    pub start: String,
+    pub end: String,
+    pub reason: String,
+}
+
+// An appointment with its times in the viewer's timezone
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AppointmentView {
+    pub appointment: Appointment,
+    pub local_start: String,
+    pub local_end: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum AppointmentActor {
+    Patient { patient_id: u64, password: String },
+    Doctor { doctor_id: u64, password: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DoctorSchedulePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    // only appointments at this site, all sites when None
+    pub site_id: Option<u64>,
+    // local times, without an offset in the hospital's timezone
+    pub from: String,
+    pub to: String,
+}
+
+pub(crate) fn appointment_view(appointment: Appointment, viewer: &Recipient) -> AppointmentView {
+    let offset = match viewer {
+        Recipient::Doctor(_) => utc_offset(&Recipient::Hospital(appointment.hospital_id)),
+        _ => utc_offset(viewer),
+    };
+    AppointmentView {
+        local_start: format_local_time(appointment.start, offset),
+        local_end: format_local_time(appointment.end, offset),
+        appointment,
+    }
+}
+
+pub(crate) fn get_appointment(appointment_id: u64) -> Result<Appointment, Error> {
+    APPOINTMENT_STORAGE
+        .with(|s| s.borrow().get(&appointment_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Appointment of id: {} not found", appointment_id),
+        })
+}
+
+pub(crate) fn save_appointment(appointment: &Appointment) {
+    APPOINTMENT_STORAGE.with(|s| s.borrow_mut().insert(appointment.id, appointment.clone()));
+}
+
+pub(crate) fn all_appointments() -> Vec<Appointment> {
+    APPOINTMENT_STORAGE.with(|s| s.borrow().iter().map(|(_, a)| a).collect())
+}
+
+// scheduled appointments of a patient that have not started yet, soonest first
+pub(crate) fn upcoming_appointments(patient_id: u64) -> Vec<Appointment> {
+    let now = time();
+    let mut appointments: Vec<Appointment> = all_appointments()
+        .into_iter()
+        .filter(|appointment| {
+            appointment.patient_id == patient_id
+                && appointment.status == AppointmentStatus::Scheduled
+                && appointment.start >= now
+        })
+        .collect();
+    appointments.sort_by_key(|appointment| appointment.start);
+    appointments
+}
+
+// held or scheduled appointments of a doctor overlapping [start, end), read from the slot index
+pub(crate) fn doctor_conflicts(doctor_id: u64, start: u64, end: u64) -> Vec<Appointment> {
+    let now = time();
+    let ids: Vec<u64> = SLOT_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((doctor_id, start.saturating_sub(MAX_APPOINTMENT_NS))..(doctor_id, end))
+            .map(|(_, id)| id)
+            .collect()
+    });
+    ids.into_iter()
+        .filter_map(|id| get_appointment(id).ok())
+        .filter(|appointment| appointment.end > start && occupies_slot(appointment, now))
+        .collect()
+}
+
+// whether an appointment still blocks its slot; expired holds do not
+fn occupies_slot(appointment: &Appointment, now: u64) -> bool {
+    match appointment.status {
+        AppointmentStatus::Scheduled => true,
+        AppointmentStatus::Held => appointment
+            .hold_expires_at
+            .is_some_and(|expiry| expiry > now),
+        _ => false,
+    }
+}
+
+fn release_slot(appointment: &Appointment) {
+    SLOT_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if index.get(&(appointment.doctor_id, appointment.start)) == Some(appointment.id) {
+            index.remove(&(appointment.doctor_id, appointment.start));
+        }
+    });
+}
+
+// check the slot and write the appointment and its index entry in one message, so no
+// other call can interleave between the check and the write. a hold_for makes it a hold
+pub(crate) fn place_appointment(
+    patient_id: u64,
+    doctor: &Doctor,
+    site_id: Option<u64>,
+    start: u64,
+    end: u64,
+    reason: String,
+    hold_for: Option<u64>,
+) -> Result<Appointment, Error> {
+    check_site(doctor.hospital_id, site_id)?;
+    let now = time();
+    if start >= end || start < now || end - start > MAX_APPOINTMENT_NS {
+        return Err(Error::InvalidPayload {
+            msg: "Appointment must be in the future, end after it starts and last at most 12 hours"
+                .to_string(),
+        });
+    }
+    if !doctor_conflicts(doctor.id, start, end).is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: format!("Doctor {} is not available at that time", doctor.id),
+        });
+    }
+    let duplicate = all_appointments().into_iter().any(|appointment| {
+        appointment.patient_id == patient_id
+            && occupies_slot(&appointment, now)
+            && appointment.start < end
+            && start < appointment.end
+    });
+    if duplicate {
+        return Err(Error::AlreadyInit {
+            msg: "Patient already has an appointment at that time".to_string(),
+        });
+    }
+
+    let appointment = Appointment {
+        id: next_id(),
+        patient_id,
+        doctor_id: doctor.id,
+        hospital_id: doctor.hospital_id,
+        site_id,
+        start,
+        end,
+        reason,
+        status: match hold_for {
+            Some(_) => AppointmentStatus::Held,
+            None => AppointmentStatus::Scheduled,
+        },
+        created_at: now,
+        hold_expires_at: hold_for.map(|duration| now + duration),
+        series_id: None,
+    };
+    save_appointment(&appointment);
+    SLOT_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert((appointment.doctor_id, appointment.start), appointment.id)
+    });
+    Ok(appointment)
+}
+
+fn reserve_slot(
+    payload: BookAppointmentPayload,
+    hold_for: Option<u64>,
+) -> Result<AppointmentView, Error> {
+    let patient = authenticate_patient(payload.patient_id, &payload.patient_password)?;
+    let doctor = DOCTOR_STORAGE
+        .with(|s| s.borrow().get(&payload.doctor_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Doctor of id: {} not found", payload.doctor_id),
+        })?;
+    let offset = utc_offset(&Recipient::Hospital(doctor.hospital_id));
+    let appointment = place_appointment(
+        patient.id,
+        &doctor,
+        payload.site_id,
+        parse_local_time(&payload.start, offset)?,
+        parse_local_time(&payload.end, offset)?,
+        payload.reason,
+        hold_for,
+    )?;
+    Ok(appointment_view(
+        appointment,
+        &Recipient::Patient(patient.id),
+    ))
+}
+
+// patient books an appointment with a doctor in one step
+#[ic_cdk::update]
+fn book_appointment(payload: BookAppointmentPayload) -> Result<AppointmentView, Error> {
+    reserve_slot(payload, None)
+}
+
+// first phase of a two-step booking: keep the slot for a few minutes while the patient decides
+#[ic_cdk::update]
+fn hold_appointment_slot(payload: BookAppointmentPayload) -> Result<AppointmentView, Error> {
+    reserve_slot(payload, Some(HOLD_NS))
+}
+
+// second phase: turn a live hold into a scheduled appointment
+#[ic_cdk::update]
+fn confirm_appointment(
+    appointment_id: u64,
+    consent: PatientConsent,
+) -> Result<AppointmentView, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    let appointment = get_appointment(appointment_id)?;
+    if appointment.patient_id != patient.id
+        || appointment.status != AppointmentStatus::Held
+        || !occupies_slot(&appointment, time())
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("Appointment of id: {} has no live hold", appointment.id),
+        });
+    }
+    let confirmed = Appointment {
+        status: AppointmentStatus::Scheduled,
+        hold_expires_at: None,
+        ..appointment
+    };
+    save_appointment(&confirmed);
+    waitlist_offer_claimed(&confirmed);
+    Ok(appointment_view(confirmed, &Recipient::Patient(patient.id)))
+}
+
+// timer job: cancel holds that were never confirmed, free their slots and offer them on
+pub(crate) fn expire_appointment_holds() {
+    let now = time();
+    for appointment in all_appointments() {
+        if appointment.status == AppointmentStatus::Held && !occupies_slot(&appointment, now) {
+            release_slot(&appointment);
+            let expired = Appointment {
+                status: AppointmentStatus::Cancelled,
+                ..appointment
+            };
+            save_appointment(&expired);
+            offer_slot_to_waitlist(&expired);
+        }
+    }
+}
+
+// check the actor is the patient or doctor of an appointment and return them as its viewer
+pub(crate) fn authorize_appointment_actor(
+    actor: &AppointmentActor,
+    patient_id: u64,
+    doctor_id: u64,
+) -> Result<Recipient, Error> {
+    let viewer = match actor {
+        AppointmentActor::Patient {
+            patient_id: actor_id,
+            password,
+        } => Recipient::Patient(authenticate_patient(*actor_id, password)?.id),
+        AppointmentActor::Doctor {
+            doctor_id: actor_id,
+            password,
+        } => Recipient::Doctor(authorize_doctor(*actor_id, password)?.id),
+    };
+    if viewer != Recipient::Patient(patient_id) && viewer != Recipient::Doctor(doctor_id) {
+        return Err(Error::Unauthorized {
+            msg: "Only the patient or doctor of the appointment can change it".to_string(),
+        });
+    }
+    Ok(viewer)
+}
+
+// cancel a held or scheduled appointment, free its slot and offer it to the waitlist
+pub(crate) fn release_appointment(appointment: Appointment) -> Result<Appointment, Error> {
+    if !matches!(
+        appointment.status,
+        AppointmentStatus::Scheduled | AppointmentStatus::Held
+    ) {
+        return Err(Error::InvalidPayload {
+            msg: format!("Appointment of id: {} cannot be cancelled", appointment.id),
+        });
+    }
+    release_slot(&appointment);
+    let cancelled = Appointment {
+        status: AppointmentStatus::Cancelled,
+        hold_expires_at: None,
+        ..appointment
+    };
+    save_appointment(&cancelled);
+    offer_slot_to_waitlist(&cancelled);
+    Ok(cancelled)
+}
+
+// move a scheduled appointment to a new time, keeping it when the new slot is taken
+pub(crate) fn reschedule_appointment(
+    appointment: Appointment,
+    start: u64,
+    end: u64,
+) -> Result<Appointment, Error> {
+    if appointment.status != AppointmentStatus::Scheduled {
+        return Err(Error::InvalidPayload {
+            msg: format!("Appointment of id: {} cannot be moved", appointment.id),
+        });
+    }
+    let doctor = DOCTOR_STORAGE
+        .with(|s| s.borrow().get(&appointment.doctor_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Doctor of id: {} not found", appointment.doctor_id),
+        })?;
+    // take the old slot out of the way so the new one may overlap it
+    release_slot(&appointment);
+    let cancelled = Appointment {
+        status: AppointmentStatus::Cancelled,
+        ..appointment.clone()
+    };
+    save_appointment(&cancelled);
+    match place_appointment(
+        appointment.patient_id,
+        &doctor,
+        appointment.site_id,
+        start,
+        end,
+        appointment.reason.clone(),
+        None,
+    ) {
+        Ok(moved) => {
+            let moved = Appointment {
+                series_id: appointment.series_id,
+                ..moved
+            };
+            save_appointment(&moved);
+            offer_slot_to_waitlist(&cancelled);
+            Ok(moved)
+        }
+        Err(e) => {
+            save_appointment(&appointment);
+            SLOT_INDEX.with(|index| {
+                index
+                    .borrow_mut()
+                    .insert((appointment.doctor_id, appointment.start), appointment.id)
+            });
+            Err(e)
+        }
+    }
+}
+
+// cancel an appointment as its patient or doctor, for a series only this occurrence
+#[ic_cdk::update]
+fn cancel_appointment(
+    appointment_id: u64,
+    actor: AppointmentActor,
+) -> Result<AppointmentView, Error> {
+    let appointment = get_appointment(appointment_id)?;
+    let viewer =
+        authorize_appointment_actor(&actor, appointment.patient_id, appointment.doctor_id)?;
+    let cancelled = release_appointment(appointment)?;
+    Ok(appointment_view(cancelled, &viewer))
+}
+
+// the doctor's appointments in a time window
+#[ic_cdk::query]
+fn get_doctor_appointments(payload: DoctorSchedulePayload) -> Result<Vec<AppointmentView>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let offset = utc_offset(&Recipient::Hospital(doctor.hospital_id));
+    let from = parse_local_time(&payload.from, offset)?;
+    let to = parse_local_time(&payload.to, offset)?;
+    let mut appointments: Vec<Appointment> = all_appointments()
+        .into_iter()
+        .filter(|appointment| {
+            appointment.doctor_id == doctor.id
+                && (payload.site_id.is_none() || appointment.site_id == payload.site_id)
+                && appointment.start < to
+                && from < appointment.end
+        })
+        .collect();
+    appointments.sort_by_key(|appointment| appointment.start);
+    Ok(appointments
+        .into_iter()
+        .map(|appointment| appointment_view(appointment, &Recipient::Doctor(doctor.id)))
+        .collect())
+}
+
+#[ic_cdk::query]
+fn get_my_appointments(consent: PatientConsent) -> Result<Vec<AppointmentView>, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    Ok(upcoming_appointments(patient.id)
+        .into_iter()
+        .map(|appointment| appointment_view(appointment, &Recipient::Patient(patient.id)))
+        .collect())
+}
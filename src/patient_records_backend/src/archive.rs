@@ -0,0 +1,162 @@
+use crate::time;
+use crate::{
+    audit, authorize_controller, authorize_doctor, get_assigned_patient, impl_storable,
+    insert_record, records_older_than, remove_record, Actor, Error, MedicalRecord, Memory,
+    RecordKind, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// records moved per timer run, keeping each run well inside the instruction limit
+const ARCHIVE_BATCH: usize = 500;
+
+// How long records of one kind stay in the live store
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub kind: RecordKind,
+    pub retain_days: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct RetentionSettings {
+    // kinds without a policy are kept forever
+    pub policies: Vec<RetentionPolicy>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ArchivedRecord {
+    pub record: MedicalRecord,
+    pub archived_at: u64,
+}
+
+impl_storable!(RetentionSettings, 512);
+impl_storable!(ArchivedRecord, 16448);
+
+thread_local! {
+    static RETENTION_SETTINGS: RefCell<Cell<RetentionSettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))),
+            RetentionSettings::default(),
+        )
+        .expect("Cannot create retention settings")
+    );
+
+    static ARCHIVE_STORAGE: RefCell<StableBTreeMap<u64, ArchivedRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct RestorePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub record_id: u64,
+}
+
+fn retention_settings() -> RetentionSettings {
+    RETENTION_SETTINGS.with(|s| s.borrow().get().clone())
+}
+
+// set or clear the retention window of one record kind
+#[ic_cdk::update]
+fn set_retention_policy(
+    kind: RecordKind,
+    retain_days: Option<u64>,
+) -> Result<RetentionSettings, Error> {
+    authorize_controller()?;
+    let mut settings = retention_settings();
+    settings.policies.retain(|policy| policy.kind != kind);
+    if let Some(retain_days) = retain_days {
+        settings
+            .policies
+            .push(RetentionPolicy { kind, retain_days });
+    }
+    RETENTION_SETTINGS
+        .with(|s| s.borrow_mut().set(settings.clone()))
+        .expect("Cannot update retention settings");
+    Ok(settings)
+}
+
+#[ic_cdk::query]
+fn get_retention_settings() -> RetentionSettings {
+    retention_settings()
+}
+
+// timer job: move records past their retention window into the archive
+pub(crate) fn archive_expired_records() {
+    let settings = retention_settings();
+    let now = time();
+    let cutoff = |kind: RecordKind| {
+        settings
+            .policies
+            .iter()
+            .find(|policy| policy.kind == kind)
+            .map(|policy| now.saturating_sub(policy.retain_days * DAY_NS))
+    };
+    let expired = records_older_than(cutoff, ARCHIVE_BATCH);
+    if expired.is_empty() {
+        return;
+    }
+    for record in expired.iter() {
+        ARCHIVE_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                record.id,
+                ArchivedRecord {
+                    record: record.clone(),
+                    archived_at: now,
+                },
+            )
+        });
+        remove_record(record.id);
+    }
+    audit(
+        Actor::System,
+        None,
+        None,
+        "records_archived",
+        format!("{} records archived", expired.len()),
+    );
+}
+
+// archived records of a patient for a doctor assigned to them
+#[ic_cdk::query]
+fn get_archived_records(payload: crate::AccessPayload) -> Result<Vec<ArchivedRecord>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    Ok(ARCHIVE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, archived)| archived)
+            .filter(|archived| archived.record.patient_id == patient.id)
+            .collect()
+    }))
+}
+
+// bring an archived record back into the live store
+#[ic_cdk::update]
+fn restore_from_archive(payload: RestorePayload) -> Result<MedicalRecord, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let archived = ARCHIVE_STORAGE
+        .with(|s| s.borrow().get(&payload.record_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Archived record of id: {} not found", payload.record_id),
+        })?;
+    let patient = get_assigned_patient(&doctor, archived.record.patient_id)?;
+    let record = MedicalRecord {
+        restored_at: Some(time()),
+        ..archived.record
+    };
+    insert_record(&record);
+    ARCHIVE_STORAGE.with(|s| s.borrow_mut().remove(&record.id));
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "record_restored",
+        format!("record {}", record.id),
+    );
+    Ok(record)
+}
@@ -0,0 +1,401 @@
+use crate::time;
+use crate::{
+    audit, authorize_controller, authorize_doctor, authorize_patient_access, get_assigned_patient,
+    impl_storable, next_id, Actor, Error, Memory, PatientAccess, MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+pub(crate) const ATTACHMENT_CHUNK_SIZE: usize = 512 * 1024;
+const MAX_ATTACHMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum AttachmentLocation {
+    Local,
+    // moved to an overflow canister created by the autoscaler
+    Offloaded(Principal),
+}
+
+// A file kept with a patient's chart, e.g. a scan or a signed consent form, stored in chunks
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: u64,
+    pub patient_id: u64,
+    pub hospital_id: u64,
+    pub doctor_id: u64,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    pub chunks: u64,
+    pub uploaded_chunks: u64,
+    pub created_at: u64,
+    pub location: AttachmentLocation,
+}
+
+impl Attachment {
+    pub(crate) fn complete(&self) -> bool {
+        self.uploaded_chunks == self.chunks
+    }
+}
+
+// A stored piece of an attachment
+#[derive(Clone)]
+pub struct AttachmentChunk(pub(crate) Vec<u8>);
+
+impl Storable for AttachmentChunk {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        AttachmentChunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for AttachmentChunk {
+    const MAX_SIZE: u32 = ATTACHMENT_CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// How many attachment chunks share one stored copy of the same bytes
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+pub struct ChunkRefs {
+    pub refs: u64,
+    pub size: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AttachmentDedupStats {
+    // chunks uploaded across all local attachments
+    pub chunk_refs: u64,
+    // distinct chunks actually stored
+    pub unique_chunks: u64,
+    pub logical_bytes: u64,
+    pub stored_bytes: u64,
+    pub saved_bytes: u64,
+}
+
+impl_storable!(Attachment, 1024);
+impl_storable!(ChunkRefs, 64);
+
+thread_local! {
+    static ATTACHMENT_STORAGE: RefCell<StableBTreeMap<u64, Attachment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(130)))
+    ));
+
+    // chunks uploaded before deduplication, keyed by (attachment id, chunk number)
+    static ATTACHMENT_CHUNKS: RefCell<StableBTreeMap<(u64, u64), AttachmentChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(131)))
+    ));
+
+    // (attachment id, chunk number) -> sha256 of the chunk's bytes
+    static CHUNK_INDEX: RefCell<StableBTreeMap<(u64, u64), [u8; 32], Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(136)))
+    ));
+
+    // sha256 -> bytes, stored once however many attachments contain them
+    static CHUNK_CONTENT: RefCell<StableBTreeMap<[u8; 32], AttachmentChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(137)))
+    ));
+
+    static CHUNK_REFS: RefCell<StableBTreeMap<[u8; 32], ChunkRefs, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(138)))
+    ));
+
+    // chunks an overflow canister keeps for the canister that created it
+    static OFFLOADED_CHUNKS: RefCell<StableBTreeMap<(u64, u64), AttachmentChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(135)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AttachmentUploadPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AttachmentChunkPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub attachment_id: u64,
+    pub chunk: u64,
+    pub data: Vec<u8>,
+}
+
+pub(crate) fn get_attachment(attachment_id: u64) -> Result<Attachment, Error> {
+    ATTACHMENT_STORAGE
+        .with(|s| s.borrow().get(&attachment_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Attachment of id: {} not found", attachment_id),
+        })
+}
+
+pub(crate) fn save_attachment(attachment: &Attachment) {
+    ATTACHMENT_STORAGE.with(|s| s.borrow_mut().insert(attachment.id, attachment.clone()));
+}
+
+pub(crate) fn all_attachments() -> Vec<Attachment> {
+    ATTACHMENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, attachment)| attachment)
+            .collect()
+    })
+}
+
+// keep the bytes once, or count one more reference when identical bytes are already stored
+fn store_content(data: Vec<u8>) -> [u8; 32] {
+    let hash: [u8; 32] = Sha256::digest(&data).into();
+    let mut refs = CHUNK_REFS
+        .with(|s| s.borrow().get(&hash))
+        .unwrap_or_default();
+    if refs.refs == 0 {
+        refs.size = data.len() as u64;
+        CHUNK_CONTENT.with(|s| s.borrow_mut().insert(hash, AttachmentChunk(data)));
+    }
+    refs.refs += 1;
+    CHUNK_REFS.with(|s| s.borrow_mut().insert(hash, refs));
+    hash
+}
+
+// drop one reference, the bytes go with the last one
+fn release_content(hash: &[u8; 32]) {
+    let mut refs = match CHUNK_REFS.with(|s| s.borrow().get(hash)) {
+        Some(refs) => refs,
+        None => return,
+    };
+    refs.refs = refs.refs.saturating_sub(1);
+    if refs.refs == 0 {
+        CHUNK_REFS.with(|s| s.borrow_mut().remove(hash));
+        CHUNK_CONTENT.with(|s| s.borrow_mut().remove(hash));
+    } else {
+        CHUNK_REFS.with(|s| s.borrow_mut().insert(*hash, refs));
+    }
+}
+
+// point a chunk of an attachment at its bytes, returns whether the chunk was already uploaded
+fn put_local_chunk(attachment_id: u64, chunk: u64, data: Vec<u8>) -> bool {
+    let hash = store_content(data);
+    let replaced = CHUNK_INDEX.with(|s| s.borrow_mut().insert((attachment_id, chunk), hash));
+    if let Some(previous) = replaced {
+        release_content(&previous);
+    }
+    let legacy = ATTACHMENT_CHUNKS.with(|s| s.borrow_mut().remove(&(attachment_id, chunk)));
+    replaced.is_some() || legacy.is_some()
+}
+
+pub(crate) fn local_chunk(attachment_id: u64, chunk: u64) -> Option<Vec<u8>> {
+    match CHUNK_INDEX.with(|s| s.borrow().get(&(attachment_id, chunk))) {
+        Some(hash) => CHUNK_CONTENT
+            .with(|s| s.borrow().get(&hash))
+            .map(|chunk| chunk.0),
+        None => ATTACHMENT_CHUNKS
+            .with(|s| s.borrow().get(&(attachment_id, chunk)))
+            .map(|chunk| chunk.0),
+    }
+}
+
+pub(crate) fn remove_local_chunks(attachment: &Attachment) {
+    for chunk in 0..attachment.chunks {
+        let key = (attachment.id, chunk);
+        if let Some(hash) = CHUNK_INDEX.with(|s| s.borrow_mut().remove(&key)) {
+            release_content(&hash);
+        }
+        ATTACHMENT_CHUNKS.with(|s| s.borrow_mut().remove(&key));
+    }
+}
+
+pub(crate) fn attachment_dedup_stats() -> AttachmentDedupStats {
+    let mut stats = AttachmentDedupStats::default();
+    CHUNK_REFS.with(|s| {
+        for (_, refs) in s.borrow().iter() {
+            stats.chunk_refs += refs.refs;
+            stats.unique_chunks += 1;
+            stats.logical_bytes += refs.refs * refs.size;
+            stats.stored_bytes += refs.size;
+        }
+    });
+    // chunks from before deduplication count as stored once each
+    ATTACHMENT_CHUNKS.with(|s| {
+        for (_, chunk) in s.borrow().iter() {
+            stats.chunk_refs += 1;
+            stats.unique_chunks += 1;
+            stats.logical_bytes += chunk.0.len() as u64;
+            stats.stored_bytes += chunk.0.len() as u64;
+        }
+    });
+    stats.saved_bytes = stats.logical_bytes - stats.stored_bytes;
+    stats
+}
+
+// bytes of attachments still held by this canister, identical chunks counted once
+pub(crate) fn local_attachment_bytes() -> u64 {
+    attachment_dedup_stats().stored_bytes
+}
+
+// a doctor announces a file before sending its chunks
+#[ic_cdk::update]
+fn begin_attachment_upload(payload: AttachmentUploadPayload) -> Result<Attachment, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    if payload.name.trim().is_empty() || payload.size == 0 || payload.size > MAX_ATTACHMENT_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Attachment needs a name and a size of 1 to {} bytes",
+                MAX_ATTACHMENT_BYTES
+            ),
+        });
+    }
+    let attachment = Attachment {
+        id: next_id(),
+        patient_id: patient.id,
+        hospital_id: doctor.hospital_id,
+        doctor_id: doctor.id,
+        name: payload.name,
+        content_type: payload.content_type,
+        size: payload.size,
+        chunks: payload.size.div_ceil(ATTACHMENT_CHUNK_SIZE as u64),
+        uploaded_chunks: 0,
+        created_at: time(),
+        location: AttachmentLocation::Local,
+    };
+    save_attachment(&attachment);
+    Ok(attachment)
+}
+
+// every chunk but the last is exactly ATTACHMENT_CHUNK_SIZE bytes, a chunk sent twice replaces
+// the first copy. Bytes already stored for any attachment are referenced, not stored again
+#[ic_cdk::update]
+fn upload_attachment_chunk(payload: AttachmentChunkPayload) -> Result<Attachment, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let mut attachment = get_attachment(payload.attachment_id)?;
+    if attachment.doctor_id != doctor.id {
+        return Err(Error::Unauthorized {
+            msg: format!("Attachment of id: {} has another uploader", attachment.id),
+        });
+    }
+    if attachment.complete() || payload.chunk >= attachment.chunks {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Attachment of id: {} takes chunks 0 to {} and is not complete",
+                attachment.id,
+                attachment.chunks - 1
+            ),
+        });
+    }
+    let expected = if payload.chunk + 1 == attachment.chunks {
+        attachment.size - payload.chunk * ATTACHMENT_CHUNK_SIZE as u64
+    } else {
+        ATTACHMENT_CHUNK_SIZE as u64
+    };
+    if payload.data.len() as u64 != expected {
+        return Err(Error::InvalidPayload {
+            msg: format!("Chunk {} must be {} bytes", payload.chunk, expected),
+        });
+    }
+    if !put_local_chunk(attachment.id, payload.chunk, payload.data) {
+        attachment.uploaded_chunks += 1;
+        save_attachment(&attachment);
+    }
+    if attachment.complete() {
+        audit(
+            Actor::Doctor(doctor.id),
+            Some(attachment.hospital_id),
+            Some(attachment.patient_id),
+            "attachment_uploaded",
+            format!("attachment {} {} bytes", attachment.id, attachment.size),
+        );
+    }
+    Ok(attachment)
+}
+
+#[ic_cdk::query]
+fn get_patient_attachments(
+    patient_id: u64,
+    access: PatientAccess,
+) -> Result<Vec<Attachment>, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    Ok(all_attachments()
+        .into_iter()
+        .filter(|attachment| attachment.patient_id == patient.id && attachment.complete())
+        .collect())
+}
+
+// one chunk of a complete attachment, fetched from the overflow canister when it was moved
+#[ic_cdk::query(composite = true)]
+async fn get_attachment_chunk(
+    patient_id: u64,
+    access: PatientAccess,
+    attachment_id: u64,
+    chunk: u64,
+) -> Result<Vec<u8>, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    let attachment = get_attachment(attachment_id)?;
+    if attachment.patient_id != patient.id || !attachment.complete() {
+        return Err(Error::NotFound {
+            msg: format!("Attachment of id: {} not found", attachment_id),
+        });
+    }
+    let missing = Error::NotFound {
+        msg: format!(
+            "Attachment {} has {} chunks",
+            attachment.id, attachment.chunks
+        ),
+    };
+    match attachment.location {
+        AttachmentLocation::Local => local_chunk(attachment.id, chunk).ok_or(missing),
+        AttachmentLocation::Offloaded(canister_id) => {
+            let reply: Result<(Result<Vec<u8>, Error>,), _> =
+                ic_cdk::call(canister_id, "get_offloaded_chunk", (attachment.id, chunk)).await;
+            match reply {
+                Ok((Ok(data),)) => Ok(data),
+                Ok((Err(_),)) => Err(missing),
+                Err((code, msg)) => Err(Error::NotFound {
+                    msg: format!("Overflow canister unavailable: {:?} {}", code, msg),
+                }),
+            }
+        }
+    }
+}
+
+// on an overflow canister: keep a chunk for the creating canister, which is its controller
+#[ic_cdk::update]
+fn store_offloaded_chunk(attachment_id: u64, chunk: u64, data: Vec<u8>) -> Result<(), Error> {
+    authorize_controller()?;
+    if data.len() > ATTACHMENT_CHUNK_SIZE {
+        return Err(Error::InvalidPayload {
+            msg: format!("A chunk holds at most {} bytes", ATTACHMENT_CHUNK_SIZE),
+        });
+    }
+    OFFLOADED_CHUNKS.with(|s| {
+        s.borrow_mut()
+            .insert((attachment_id, chunk), AttachmentChunk(data))
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_offloaded_chunk(attachment_id: u64, chunk: u64) -> Result<Vec<u8>, Error> {
+    authorize_controller()?;
+    OFFLOADED_CHUNKS
+        .with(|s| s.borrow().get(&(attachment_id, chunk)))
+        .map(|chunk| chunk.0)
+        .ok_or(Error::NotFound {
+            msg: format!("Chunk {} of attachment {} not found", chunk, attachment_id),
+        })
+}
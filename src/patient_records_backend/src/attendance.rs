@@ -0,0 +1,258 @@
+use crate::time;
+use crate::{
+    all_appointments, audit, authorize_doctor, authorize_hospital, get_appointment, impl_storable,
+    require_premium, save_appointment, utc_offset, Actor, Appointment, AppointmentStatus, Error,
+    Memory, PremiumFeature, Recipient, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+const DAY_NS: u64 = 24 * HOUR_NS;
+// no-shows counted towards reminder escalation are those of the last year
+const NO_SHOW_WINDOW_NS: u64 = 365 * DAY_NS;
+const ESCALATION_THRESHOLD: u64 = 2;
+// reminders added on top of the patient's own lead times once escalated
+const ESCALATED_LEAD_HOURS: &[u32] = &[72, 2];
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum AttendanceOutcome {
+    Attended,
+    NoShow,
+}
+
+// Whether the patient turned up to an appointment, keyed by appointment id
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AttendanceRecord {
+    pub appointment_id: u64,
+    pub patient_id: u64,
+    pub doctor_id: u64,
+    pub hospital_id: u64,
+    pub start: u64,
+    pub outcome: AttendanceOutcome,
+    pub recorded_at: u64,
+}
+
+impl_storable!(AttendanceRecord, 256);
+
+thread_local! {
+    static ATTENDANCE_STORAGE: RefCell<StableBTreeMap<u64, AttendanceRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(114)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AttendancePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub appointment_id: u64,
+    pub outcome: AttendanceOutcome,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct NoShowQuery {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    // appointment start times, inclusive
+    pub from: u64,
+    pub to: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AttendanceCounts {
+    pub attended: u64,
+    pub no_shows: u64,
+    // no-shows per hundred recorded appointments
+    pub no_show_rate: f64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct NoShowStats {
+    pub overall: AttendanceCounts,
+    pub by_doctor: Vec<(u64, AttendanceCounts)>,
+    // hour of the day in the hospital's timezone
+    pub by_hour: Vec<(u8, AttendanceCounts)>,
+    // 0 is monday
+    pub by_weekday: Vec<(u8, AttendanceCounts)>,
+    // appointments in the period still waiting for an outcome
+    pub unrecorded: u64,
+    // patients with more than one no-show in the period
+    pub repeat_no_show_patients: u64,
+}
+
+impl AttendanceCounts {
+    fn add(&mut self, outcome: AttendanceOutcome) {
+        match outcome {
+            AttendanceOutcome::Attended => self.attended += 1,
+            AttendanceOutcome::NoShow => self.no_shows += 1,
+        }
+        self.no_show_rate = self.no_shows as f64 * 100.0 / (self.attended + self.no_shows) as f64;
+    }
+}
+
+pub(crate) fn recent_no_shows(patient_id: u64) -> u64 {
+    let since = time().saturating_sub(NO_SHOW_WINDOW_NS);
+    ATTENDANCE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, record)| {
+                record.patient_id == patient_id
+                    && record.outcome == AttendanceOutcome::NoShow
+                    && record.start >= since
+            })
+            .count() as u64
+    })
+}
+
+// extra reminder lead times for a patient who keeps missing appointments
+pub(crate) fn escalated_reminder_leads(patient_id: u64) -> Vec<u32> {
+    if recent_no_shows(patient_id) >= ESCALATION_THRESHOLD {
+        ESCALATED_LEAD_HOURS.to_vec()
+    } else {
+        vec![]
+    }
+}
+
+// the doctor records whether the patient came once the appointment has started
+#[ic_cdk::update]
+fn record_attendance(payload: AttendancePayload) -> Result<AttendanceRecord, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let appointment = get_appointment(payload.appointment_id)?;
+    if appointment.doctor_id != doctor.id {
+        return Err(Error::Unauthorized {
+            msg: "Only the doctor of the appointment can record attendance".to_string(),
+        });
+    }
+    if appointment.status != AppointmentStatus::Scheduled || appointment.start > time() {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Appointment of id: {} is not a scheduled appointment that has started",
+                appointment.id
+            ),
+        });
+    }
+    if ATTENDANCE_STORAGE.with(|s| s.borrow().contains_key(&appointment.id)) {
+        return Err(Error::AlreadyInit {
+            msg: format!(
+                "Attendance of appointment {} is already recorded",
+                appointment.id
+            ),
+        });
+    }
+    let record = AttendanceRecord {
+        appointment_id: appointment.id,
+        patient_id: appointment.patient_id,
+        doctor_id: doctor.id,
+        hospital_id: appointment.hospital_id,
+        start: appointment.start,
+        outcome: payload.outcome,
+        recorded_at: time(),
+    };
+    ATTENDANCE_STORAGE.with(|s| s.borrow_mut().insert(record.appointment_id, record.clone()));
+    // the slot stays taken either way, only attended appointments count as completed
+    if record.outcome == AttendanceOutcome::Attended {
+        save_appointment(&Appointment {
+            status: AppointmentStatus::Completed,
+            ..appointment
+        });
+    } else {
+        audit(
+            Actor::Doctor(doctor.id),
+            Some(record.hospital_id),
+            Some(record.patient_id),
+            "appointment_no_show",
+            format!("appointment {}", record.appointment_id),
+        );
+    }
+    Ok(record)
+}
+
+#[ic_cdk::query]
+fn get_appointment_attendance(
+    appointment_id: u64,
+    doctor_id: u64,
+    doctor_password: String,
+) -> Result<AttendanceRecord, Error> {
+    let doctor = authorize_doctor(doctor_id, &doctor_password)?;
+    ATTENDANCE_STORAGE
+        .with(|s| s.borrow().get(&appointment_id))
+        .filter(|record| record.hospital_id == doctor.hospital_id)
+        .ok_or(Error::NotFound {
+            msg: format!("Attendance of appointment {} not found", appointment_id),
+        })
+}
+
+// attendance of the hospital's appointments in a period, to tune how far slots are overbooked
+#[ic_cdk::query]
+fn get_no_show_stats(query: NoShowQuery) -> Result<NoShowStats, Error> {
+    let hospital = authorize_hospital(query.hospital_id, &query.hospital_password)?;
+    require_premium(hospital.id, PremiumFeature::Analytics)?;
+    if query.from > query.to {
+        return Err(Error::InvalidPayload {
+            msg: "Period must not end before it starts".to_string(),
+        });
+    }
+    let offset_ns = utc_offset(&Recipient::Hospital(hospital.id)) as i128 * 60 * 1_000_000_000;
+    let records: Vec<AttendanceRecord> = ATTENDANCE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, record)| record)
+            .filter(|record| {
+                record.hospital_id == hospital.id
+                    && query.from <= record.start
+                    && record.start <= query.to
+            })
+            .collect()
+    });
+
+    let mut overall = AttendanceCounts::default();
+    let mut by_doctor: BTreeMap<u64, AttendanceCounts> = BTreeMap::new();
+    let mut by_hour: BTreeMap<u8, AttendanceCounts> = BTreeMap::new();
+    let mut by_weekday: BTreeMap<u8, AttendanceCounts> = BTreeMap::new();
+    let mut no_shows_per_patient: BTreeMap<u64, u64> = BTreeMap::new();
+    for record in &records {
+        let local = (record.start as i128 + offset_ns).max(0) as u64;
+        let hour = (local % DAY_NS / HOUR_NS) as u8;
+        // 1970-01-01 was a thursday
+        let weekday = ((local / DAY_NS + 3) % 7) as u8;
+        overall.add(record.outcome);
+        by_doctor
+            .entry(record.doctor_id)
+            .or_default()
+            .add(record.outcome);
+        by_hour.entry(hour).or_default().add(record.outcome);
+        by_weekday.entry(weekday).or_default().add(record.outcome);
+        if record.outcome == AttendanceOutcome::NoShow {
+            *no_shows_per_patient.entry(record.patient_id).or_default() += 1;
+        }
+    }
+
+    let recorded: BTreeSet<u64> = records.iter().map(|record| record.appointment_id).collect();
+    let now = time();
+    let unrecorded = all_appointments()
+        .into_iter()
+        .filter(|appointment| {
+            appointment.hospital_id == hospital.id
+                && appointment.status == AppointmentStatus::Scheduled
+                && query.from <= appointment.start
+                && appointment.start <= query.to
+                && appointment.start <= now
+                && !recorded.contains(&appointment.id)
+        })
+        .count() as u64;
+
+    Ok(NoShowStats {
+        overall,
+        by_doctor: by_doctor.into_iter().collect(),
+        by_hour: by_hour.into_iter().collect(),
+        by_weekday: by_weekday.into_iter().collect(),
+        unrecorded,
+        repeat_no_show_patients: no_shows_per_patient
+            .values()
+            .filter(|count| **count > 1)
+            .count() as u64,
+    })
+}
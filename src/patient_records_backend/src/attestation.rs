@@ -0,0 +1,170 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, get_record, impl_storable, Actor, Error, MedicalRecord, Memory,
+    MEMORY_MANAGER,
+};
+use candid::Encode;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// A doctor's signature over the content hash of a medical record
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RecordSignature {
+    pub record_id: u64,
+    pub doctor_id: u64,
+    pub record_hash: Vec<u8>,
+    pub signed_at: u64,
+    // position in the signature chain and the chain hash after this signature
+    pub seq: u64,
+    pub chain_hash: Vec<u8>,
+}
+
+// Head of the hash chain over all signatures, published as the canister's certified data
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SignatureChain {
+    pub length: u64,
+    pub head: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SignatureVerification {
+    pub signature: RecordSignature,
+    // hash of the record as it is stored now
+    pub current_hash: Vec<u8>,
+    pub valid: bool,
+    pub chain: SignatureChain,
+    // certificate over the chain head, only available in query calls
+    pub certificate: Option<Vec<u8>>,
+}
+
+impl_storable!(RecordSignature, 256);
+impl_storable!(SignatureChain, 128);
+
+thread_local! {
+    static SIGNATURE_STORAGE: RefCell<StableBTreeMap<u64, RecordSignature, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    static SIGNATURE_CHAIN: RefCell<Cell<SignatureChain, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+            SignatureChain::default(),
+        )
+        .expect("Cannot create signature chain")
+    );
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SignRecordPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub record_id: u64,
+}
+
+// hash of the clinical content of a record, leaving out storage bookkeeping
+pub(crate) fn record_hash(record: &MedicalRecord) -> Vec<u8> {
+    let content = Encode!(
+        &record.id,
+        &record.patient_id,
+        &record.doctor_id,
+        &record.hospital_id,
+        &record.kind,
+        &record.title,
+        &record.body,
+        &record.created_at,
+        &record.addendum_to
+    )
+    .expect("Cannot encode record");
+    Sha256::digest(content).to_vec()
+}
+
+pub(crate) fn is_record_signed(record_id: u64) -> bool {
+    SIGNATURE_STORAGE.with(|s| s.borrow().contains_key(&record_id))
+}
+
+fn signature_chain() -> SignatureChain {
+    SIGNATURE_CHAIN.with(|c| c.borrow().get().clone())
+}
+
+// publish the chain head so query answers can carry a certificate over it
+pub(crate) fn certify_signature_chain() {
+    let head = signature_chain().head;
+    if !head.is_empty() {
+        ic_cdk::api::set_certified_data(&head);
+    }
+}
+
+// sign a record as its author, after which it can only be amended with addenda
+#[ic_cdk::update]
+fn sign_medical_record(payload: SignRecordPayload) -> Result<RecordSignature, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let record = get_record(payload.record_id)?;
+    if record.doctor_id != Some(doctor.id) {
+        return Err(Error::Unauthorized {
+            msg: format!("Medical record of id: {} has another author", record.id),
+        });
+    }
+    if is_record_signed(record.id) {
+        return Err(Error::AlreadyInit {
+            msg: format!("Medical record of id: {} is already signed", record.id),
+        });
+    }
+
+    let record_hash = record_hash(&record);
+    let signed_at = time();
+    let chain = signature_chain();
+    let mut hasher = Sha256::new();
+    hasher.update(&chain.head);
+    hasher.update(&record_hash);
+    hasher.update(doctor.id.to_be_bytes());
+    hasher.update(signed_at.to_be_bytes());
+    let chain_hash = hasher.finalize().to_vec();
+
+    let signature = RecordSignature {
+        record_id: record.id,
+        doctor_id: doctor.id,
+        record_hash,
+        signed_at,
+        seq: chain.length,
+        chain_hash: chain_hash.clone(),
+    };
+    SIGNATURE_STORAGE.with(|s| s.borrow_mut().insert(record.id, signature.clone()));
+    SIGNATURE_CHAIN
+        .with(|c| {
+            c.borrow_mut().set(SignatureChain {
+                length: chain.length + 1,
+                head: chain_hash,
+            })
+        })
+        .expect("Cannot update signature chain");
+    certify_signature_chain();
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(record.patient_id),
+        "record_signed",
+        format!("record {}", record.id),
+    );
+    Ok(signature)
+}
+
+// check that a signed record still matches the hash it was signed with
+#[ic_cdk::query]
+fn verify_record_signature(record_id: u64) -> Result<SignatureVerification, Error> {
+    let signature = SIGNATURE_STORAGE
+        .with(|s| s.borrow().get(&record_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Medical record of id: {} is not signed", record_id),
+        })?;
+    let current_hash = record_hash(&get_record(record_id)?);
+    Ok(SignatureVerification {
+        valid: current_hash == signature.record_hash,
+        signature,
+        current_hash,
+        chain: signature_chain(),
+        certificate: ic_cdk::api::data_certificate(),
+    })
+}
@@ -0,0 +1,385 @@
+use crate::time;
+use crate::{
+    authorize_controller, authorize_ref, charge_call, impl_storable, page_after, EntityRef, Error,
+    Memory, Page, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// entries rolled up per timer run, so one run stays well within the instruction limit
+const ROLL_UP_BATCH: usize = 5_000;
+// bounds on a summary so it always fits its stored size
+const MAX_SUMMARY_ACTIONS: usize = 32;
+const MAX_SUMMARY_IDS: usize = 64;
+
+// Who performed an audited action
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum Actor {
+    Hospital(u64),
+    Doctor(u64),
+    Nurse(u64),
+    Patient(u64),
+    Auditor(u64),
+    // a caregiver acting under the given grant id
+    Caregiver(u64),
+    // a third-party app acting with the given token id
+    App(u64),
+    System,
+    // a registered device with the given device id
+    Device(u64),
+}
+
+// One entry of the append-only audit log, keyed by a sequence number
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub actor: Actor,
+    pub hospital_id: Option<u64>,
+    pub patient_id: Option<u64>,
+    pub action: String,
+    pub details: String,
+}
+
+// How long audit entries are kept in full before they are rolled up into daily summaries
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AuditRetention {
+    pub full_fidelity_days: u64,
+}
+
+impl Default for AuditRetention {
+    fn default() -> Self {
+        AuditRetention {
+            full_fidelity_days: 90,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ActionCount {
+    pub action: String,
+    pub count: u64,
+}
+
+// What one actor did on one day, kept after the day's full entries are dropped
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AuditSummary {
+    // days since the unix epoch
+    pub day: u64,
+    pub actor: Actor,
+    pub entries: u64,
+    pub first_seq: u64,
+    pub last_seq: u64,
+    pub hospital_ids: Vec<u64>,
+    // distinct patients touched, the ids list stops growing past its bound
+    pub patients_touched: u64,
+    pub patient_ids: Vec<u64>,
+    // the most frequent actions, any beyond the bound are counted under "other"
+    pub actions: Vec<ActionCount>,
+}
+
+impl_storable!(AuditEntry, 1024);
+impl_storable!(AuditRetention, 64);
+impl_storable!(AuditSummary, 4096);
+
+thread_local! {
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    static AUDIT_RETENTION: RefCell<Cell<AuditRetention, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68))),
+            AuditRetention::default(),
+        )
+        .expect("Cannot create audit retention")
+    );
+
+    // keyed by (day, actor code)
+    static AUDIT_SUMMARIES: RefCell<StableBTreeMap<(u64, u64), AuditSummary, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AuditLogPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    // last sequence number of the previous page
+    pub after: Option<u64>,
+    pub limit: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EntityAuditLogPayload {
+    pub entity: EntityRef,
+    pub password: String,
+    // last sequence number of the previous page
+    pub after: Option<u64>,
+    pub limit: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AuditSummaryPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    // days since the unix epoch, inclusive
+    pub from_day: u64,
+    pub to_day: u64,
+}
+
+// a compact key for an actor: the kind in the top byte, the id below it
+fn actor_code(actor: &Actor) -> u64 {
+    let (kind, id) = match actor {
+        Actor::Hospital(id) => (0, *id),
+        Actor::Doctor(id) => (1, *id),
+        Actor::Nurse(id) => (2, *id),
+        Actor::Patient(id) => (3, *id),
+        Actor::Auditor(id) => (4, *id),
+        Actor::Caregiver(id) => (5, *id),
+        Actor::App(id) => (6, *id),
+        Actor::System => (7, 0),
+        Actor::Device(id) => (8, *id),
+    };
+    (kind << 56) | (id & ((1 << 56) - 1))
+}
+
+fn add_to_summary(summary: &mut AuditSummary, entry: &AuditEntry) {
+    summary.entries += 1;
+    summary.first_seq = summary.first_seq.min(entry.seq);
+    summary.last_seq = summary.last_seq.max(entry.seq);
+    if let Some(hospital_id) = entry.hospital_id {
+        if !summary.hospital_ids.contains(&hospital_id)
+            && summary.hospital_ids.len() < MAX_SUMMARY_IDS
+        {
+            summary.hospital_ids.push(hospital_id);
+        }
+    }
+    if let Some(patient_id) = entry.patient_id {
+        if !summary.patient_ids.contains(&patient_id) {
+            summary.patients_touched += 1;
+            if summary.patient_ids.len() < MAX_SUMMARY_IDS {
+                summary.patient_ids.push(patient_id);
+            }
+        }
+    }
+    let action = if summary.actions.len() < MAX_SUMMARY_ACTIONS
+        || summary
+            .actions
+            .iter()
+            .any(|count| count.action == entry.action)
+    {
+        entry.action.clone()
+    } else {
+        "other".to_string()
+    };
+    match summary
+        .actions
+        .iter_mut()
+        .find(|count| count.action == action)
+    {
+        Some(count) => count.count += 1,
+        None => summary.actions.push(ActionCount { action, count: 1 }),
+    }
+}
+
+// timer task: roll audit entries past the retention window into daily per-actor summaries.
+// the newest entry is always kept so sequence numbers keep counting up
+pub(crate) fn roll_up_audit_log() {
+    let retention = AUDIT_RETENTION.with(|r| r.borrow().get().clone());
+    let cutoff = time().saturating_sub(retention.full_fidelity_days * DAY_NS);
+    let expired: Vec<AuditEntry> = AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let newest = log.last_key_value().map(|(seq, _)| seq);
+        log.iter()
+            .map(|(_, entry)| entry)
+            .take_while(|entry| entry.timestamp < cutoff && Some(entry.seq) != newest)
+            .take(ROLL_UP_BATCH)
+            .collect()
+    });
+    AUDIT_SUMMARIES.with(|s| {
+        let mut summaries = s.borrow_mut();
+        for entry in &expired {
+            let key = (entry.timestamp / DAY_NS, actor_code(&entry.actor));
+            let mut summary = summaries.get(&key).unwrap_or(AuditSummary {
+                day: key.0,
+                actor: entry.actor.clone(),
+                entries: 0,
+                first_seq: entry.seq,
+                last_seq: entry.seq,
+                hospital_ids: vec![],
+                patients_touched: 0,
+                patient_ids: vec![],
+                actions: vec![],
+            });
+            add_to_summary(&mut summary, entry);
+            summaries.insert(key, summary);
+        }
+    });
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        for entry in &expired {
+            log.remove(&entry.seq);
+        }
+    });
+}
+
+// entries after the sequence number in order, at most limit of them
+pub(crate) fn audit_entries_after(after: Option<u64>, limit: usize) -> Vec<AuditEntry> {
+    let start = after.map_or(0, |seq| seq + 1);
+    AUDIT_LOG.with(|log| {
+        log.borrow()
+            .range(start..)
+            .take(limit)
+            .map(|(_, entry)| entry)
+            .collect()
+    })
+}
+
+// every entry about the patient still held in full, in sequence order
+pub(crate) fn patient_audit_entries(patient_id: u64) -> Vec<AuditEntry> {
+    AUDIT_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.patient_id == Some(patient_id))
+            .collect()
+    })
+}
+
+// daily summaries of rolled-up entries that list the patient
+pub(crate) fn patient_audit_summaries(patient_id: u64) -> Vec<AuditSummary> {
+    AUDIT_SUMMARIES.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, summary)| summary)
+            .filter(|summary| summary.patient_ids.contains(&patient_id))
+            .collect()
+    })
+}
+
+pub(crate) fn audit_entry(seq: u64) -> Option<AuditEntry> {
+    AUDIT_LOG.with(|log| log.borrow().get(&seq))
+}
+
+// record a wrong password, so repeated failures can be spotted. only update calls keep it
+pub(crate) fn audit_failed_password(
+    actor: Actor,
+    hospital_id: Option<u64>,
+    patient_id: Option<u64>,
+) {
+    audit(
+        actor,
+        hospital_id,
+        patient_id,
+        "password_failed",
+        String::new(),
+    );
+}
+
+pub(crate) fn audit_log_len() -> u64 {
+    AUDIT_LOG.with(|log| log.borrow().len())
+}
+
+pub(crate) fn audit_summary_count() -> u64 {
+    AUDIT_SUMMARIES.with(|s| s.borrow().len())
+}
+
+// append an entry to the audit log
+pub(crate) fn audit(
+    actor: Actor,
+    hospital_id: Option<u64>,
+    patient_id: Option<u64>,
+    action: &str,
+    details: String,
+) {
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let seq = log.last_key_value().map_or(0, |(seq, _)| seq + 1);
+        log.insert(
+            seq,
+            AuditEntry {
+                seq,
+                timestamp: time(),
+                actor,
+                hospital_id,
+                patient_id,
+                action: action.to_string(),
+                details,
+            },
+        );
+    });
+    if let Some(hospital_id) = hospital_id {
+        charge_call(hospital_id);
+    }
+}
+
+// whether the entity made the entry or the entry is about it
+fn concerns(entry: &AuditEntry, entity: EntityRef) -> bool {
+    entry.actor == Actor::from(entity)
+        || match entity {
+            EntityRef::Hospital(id) => entry.hospital_id == Some(id),
+            EntityRef::Patient(id) => entry.patient_id == Some(id),
+            EntityRef::Doctor(_) => false,
+        }
+}
+
+pub(crate) fn audit_page(entity: EntityRef, after: Option<u64>, limit: u64) -> Page<AuditEntry> {
+    AUDIT_LOG.with(|log| page_after(&log.borrow(), after, limit, |entry| concerns(entry, entity)))
+}
+
+// audit entries of a hospital in sequence order, a page at a time
+#[ic_cdk::query]
+fn get_hospital_audit_log(payload: AuditLogPayload) -> Result<Page<AuditEntry>, Error> {
+    let hospital = crate::authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    Ok(audit_page(
+        EntityRef::Hospital(hospital.id),
+        payload.after,
+        payload.limit,
+    ))
+}
+
+// audit entries made by or about a hospital, doctor or patient, so patients can see who
+// accessed their record and doctors can review their own activity
+#[ic_cdk::query]
+fn get_audit_log(payload: EntityAuditLogPayload) -> Result<Page<AuditEntry>, Error> {
+    authorize_ref(payload.entity, &payload.password)?;
+    Ok(audit_page(payload.entity, payload.after, payload.limit))
+}
+
+// daily summaries of rolled-up audit entries that touched the hospital
+#[ic_cdk::query]
+fn get_hospital_audit_summaries(payload: AuditSummaryPayload) -> Result<Vec<AuditSummary>, Error> {
+    let hospital = crate::authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    Ok(AUDIT_SUMMARIES.with(|s| {
+        s.borrow()
+            .range((payload.from_day, 0)..)
+            .take_while(|((day, _), _)| *day <= payload.to_day)
+            .map(|(_, summary)| summary)
+            .filter(|summary| summary.hospital_ids.contains(&hospital.id))
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_audit_retention() -> AuditRetention {
+    AUDIT_RETENTION.with(|r| r.borrow().get().clone())
+}
+
+#[ic_cdk::update]
+fn set_audit_retention(retention: AuditRetention) -> Result<AuditRetention, Error> {
+    authorize_controller()?;
+    if retention.full_fidelity_days == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "Audit entries must be kept in full for at least one day".to_string(),
+        });
+    }
+    AUDIT_RETENTION
+        .with(|r| r.borrow_mut().set(retention.clone()))
+        .expect("Cannot update audit retention");
+    Ok(retention)
+}
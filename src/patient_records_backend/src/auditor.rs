@@ -0,0 +1,97 @@
+use crate::{
+    audit_failed_password, authorize_hospital, caller_holds, impl_storable, next_id,
+    reject_kiosk_caller, AccountRole, Actor, Error, Memory, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Compliance staff allowed to review confidential data of one hospital
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Auditor {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub name: String,
+    pub password: String,
+}
+
+impl_storable!(Auditor, 512);
+
+thread_local! {
+    static AUDITOR_STORAGE: RefCell<StableBTreeMap<u64, Auditor, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+}
+
+// Either the hospital admin or one of the hospital's auditors
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum OversightRole {
+    HospitalAdmin(u64),
+    Auditor(u64),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AuditorPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub name: String,
+    pub password: String,
+}
+
+// helper function to check an auditor's password and return the auditor
+pub(crate) fn authorize_auditor(auditor_id: u64, password: &str) -> Result<Auditor, Error> {
+    reject_kiosk_caller()?;
+    match AUDITOR_STORAGE.with(|auditors| auditors.borrow().get(&auditor_id)) {
+        Some(auditor)
+            if auditor.password == password || caller_holds(AccountRole::Auditor(auditor_id)) =>
+        {
+            Ok(auditor)
+        }
+        Some(auditor) => {
+            audit_failed_password(Actor::Auditor(auditor.id), Some(auditor.hospital_id), None);
+            Err(Error::Unauthorized {
+                msg: "Auditor access unauthorized, password does not match, try again".to_string(),
+            })
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Auditor of id: {} not found", auditor_id),
+        }),
+    }
+}
+
+// helper function to check hospital admin or auditor credentials, returning the hospital id
+pub(crate) fn authorize_oversight(role: &OversightRole, password: &str) -> Result<u64, Error> {
+    match role {
+        OversightRole::HospitalAdmin(id) => authorize_hospital(*id, password).map(|h| h.id),
+        OversightRole::Auditor(id) => authorize_auditor(*id, password).map(|a| a.hospital_id),
+    }
+}
+
+pub(crate) fn oversight_actor(role: &OversightRole) -> Actor {
+    match role {
+        OversightRole::HospitalAdmin(id) => Actor::Hospital(*id),
+        OversightRole::Auditor(id) => Actor::Auditor(*id),
+    }
+}
+
+#[ic_cdk::update]
+fn add_auditor(payload: AuditorPayload) -> Result<Auditor, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if payload.name.trim().len() < 3 || payload.password.len() < 4 {
+        return Err(Error::InvalidPayload {
+            msg: "Auditor name needs 3 characters and password 4 characters".to_string(),
+        });
+    }
+    let auditor = Auditor {
+        id: next_id(),
+        hospital_id: hospital.id,
+        name: payload.name,
+        password: payload.password,
+    };
+    AUDITOR_STORAGE.with(|s| s.borrow_mut().insert(auditor.id, auditor.clone()));
+    Ok(Auditor {
+        password: "-".to_string(),
+        ..auditor
+    })
+}
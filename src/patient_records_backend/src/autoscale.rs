@@ -0,0 +1,283 @@
+use crate::time;
+use crate::{
+    all_attachments, audit, authorize_controller, get_attachment, impl_storable,
+    local_attachment_bytes, local_chunk, next_id, remove_local_chunks, save_attachment, Actor,
+    AttachmentChunk, AttachmentLocation, Error, Memory, ATTACHMENT_CHUNK_SIZE, MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell as StableCell, StableBTreeMap};
+use std::cell::{Cell, RefCell};
+
+const GIB: u64 = 1024 * 1024 * 1024;
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// bytes of attachments moved per run, the chunks are sent one call at a time
+const MIGRATION_BATCH_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AutoscaleSettings {
+    pub enabled: bool,
+    // attachments start moving out once this canister holds more than this
+    pub local_attachment_bytes_limit: u64,
+    // an overflow canister takes no new attachments past this
+    pub overflow_canister_bytes_limit: u64,
+    // only attachments uploaded at least this long ago are moved
+    pub cold_after_days: u64,
+    // cycles handed to each new overflow canister
+    pub cycles_per_canister: u128,
+    // controllers of overflow canisters besides this canister
+    pub extra_controllers: Vec<Principal>,
+}
+
+impl Default for AutoscaleSettings {
+    fn default() -> Self {
+        AutoscaleSettings {
+            enabled: false,
+            local_attachment_bytes_limit: 64 * GIB,
+            overflow_canister_bytes_limit: 256 * GIB,
+            cold_after_days: 90,
+            cycles_per_canister: 2_000_000_000_000,
+            extra_controllers: vec![],
+        }
+    }
+}
+
+// A canister created to hold attachments this one ran out of room for
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct OverflowCanister {
+    pub id: u64,
+    pub canister_id: Principal,
+    pub created_at: u64,
+    pub bytes: u64,
+    pub attachments: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AutoscaleStatus {
+    pub settings: AutoscaleSettings,
+    pub local_attachment_bytes: u64,
+    pub wasm_bytes: u64,
+    pub overflow_canisters: Vec<OverflowCanister>,
+    pub last_error: Option<String>,
+}
+
+impl_storable!(AutoscaleSettings, 1024);
+impl_storable!(OverflowCanister, 256);
+
+thread_local! {
+    static AUTOSCALE_SETTINGS: RefCell<StableCell<AutoscaleSettings, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(132))),
+            AutoscaleSettings::default(),
+        )
+        .expect("Cannot create autoscale settings")
+    );
+
+    // the module installed on new overflow canisters, a build of this canister, in chunks
+    static OVERFLOW_WASM: RefCell<StableBTreeMap<u64, AttachmentChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(133)))
+    ));
+
+    static OVERFLOW_CANISTERS: RefCell<StableBTreeMap<u64, OverflowCanister, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(134)))
+    ));
+
+    // set while a run is in flight so timer ticks do not overlap
+    static SCALING: Cell<bool> = const { Cell::new(false) };
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn autoscale_settings() -> AutoscaleSettings {
+    AUTOSCALE_SETTINGS.with(|s| s.borrow().get().clone())
+}
+
+fn overflow_canisters() -> Vec<OverflowCanister> {
+    OVERFLOW_CANISTERS.with(|s| s.borrow().iter().map(|(_, canister)| canister).collect())
+}
+
+fn save_overflow_canister(canister: &OverflowCanister) {
+    OVERFLOW_CANISTERS.with(|s| s.borrow_mut().insert(canister.id, canister.clone()));
+}
+
+fn overflow_wasm() -> Vec<u8> {
+    OVERFLOW_WASM.with(|s| s.borrow().iter().flat_map(|(_, chunk)| chunk.0).collect())
+}
+
+// create and install a new overflow canister controlled by this one
+async fn create_overflow_canister(
+    settings: &AutoscaleSettings,
+) -> Result<OverflowCanister, String> {
+    let wasm_module = overflow_wasm();
+    if wasm_module.is_empty() {
+        return Err("No overflow canister wasm uploaded".to_string());
+    }
+    let mut controllers = vec![ic_cdk::id()];
+    controllers.extend(settings.extra_controllers.iter().copied());
+    let (record,) = create_canister(
+        CreateCanisterArgument {
+            settings: Some(CanisterSettings {
+                controllers: Some(controllers),
+                ..Default::default()
+            }),
+        },
+        settings.cycles_per_canister,
+    )
+    .await
+    .map_err(|(code, msg)| format!("create_canister: {:?} {}", code, msg))?;
+    install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id: record.canister_id,
+        wasm_module,
+        arg: vec![],
+    })
+    .await
+    .map_err(|(code, msg)| format!("install_code: {:?} {}", code, msg))?;
+    let canister = OverflowCanister {
+        id: next_id(),
+        canister_id: record.canister_id,
+        created_at: time(),
+        bytes: 0,
+        attachments: 0,
+    };
+    save_overflow_canister(&canister);
+    audit(
+        Actor::System,
+        None,
+        None,
+        "overflow_canister_created",
+        canister.canister_id.to_string(),
+    );
+    Ok(canister)
+}
+
+// copy every chunk of an attachment to the overflow canister, then drop the local copy
+async fn migrate_attachment(attachment_id: u64, canister_id: Principal) -> Result<u64, String> {
+    let attachment = get_attachment(attachment_id).map_err(|_| "attachment removed".to_string())?;
+    for chunk in 0..attachment.chunks {
+        let data = local_chunk(attachment.id, chunk).unwrap_or_default();
+        let reply: Result<(Result<(), Error>,), _> = ic_cdk::call(
+            canister_id,
+            "store_offloaded_chunk",
+            (attachment.id, chunk, data),
+        )
+        .await;
+        match reply {
+            Ok((Ok(()),)) => {}
+            Ok((Err(_),)) => return Err("overflow canister refused a chunk".to_string()),
+            Err((code, msg)) => return Err(format!("store_offloaded_chunk: {:?} {}", code, msg)),
+        }
+    }
+    // the attachment may have changed while chunks were in flight
+    let mut current =
+        get_attachment(attachment_id).map_err(|_| "attachment removed".to_string())?;
+    if current.location != AttachmentLocation::Local {
+        return Ok(0);
+    }
+    current.location = AttachmentLocation::Offloaded(canister_id);
+    save_attachment(&current);
+    remove_local_chunks(&current);
+    Ok(current.size)
+}
+
+async fn scale() -> Result<(), String> {
+    let settings = autoscale_settings();
+    if !settings.enabled || local_attachment_bytes() <= settings.local_attachment_bytes_limit {
+        return Ok(());
+    }
+    let mut target = match overflow_canisters()
+        .into_iter()
+        .find(|canister| canister.bytes < settings.overflow_canister_bytes_limit)
+    {
+        Some(canister) => canister,
+        None => create_overflow_canister(&settings).await?,
+    };
+    let cutoff = time().saturating_sub(settings.cold_after_days * DAY_NS);
+    let mut cold: Vec<_> = all_attachments()
+        .into_iter()
+        .filter(|attachment| {
+            attachment.location == AttachmentLocation::Local
+                && attachment.complete()
+                && attachment.created_at < cutoff
+        })
+        .collect();
+    cold.sort_by_key(|attachment| attachment.created_at);
+    let mut moved = 0;
+    for attachment in cold {
+        if moved >= MIGRATION_BATCH_BYTES
+            || local_attachment_bytes() <= settings.local_attachment_bytes_limit
+        {
+            break;
+        }
+        let bytes = migrate_attachment(attachment.id, target.canister_id).await?;
+        moved += bytes;
+        target.bytes += bytes;
+        target.attachments += 1;
+        save_overflow_canister(&target);
+    }
+    Ok(())
+}
+
+// timer task: move the oldest attachments out once local storage passes the limit,
+// creating an overflow canister when none has room left
+pub(crate) async fn autoscale_attachments() {
+    if SCALING.with(|scaling| scaling.replace(true)) {
+        return;
+    }
+    let result = scale().await;
+    LAST_ERROR.with(|last| *last.borrow_mut() = result.err());
+    SCALING.with(|scaling| scaling.set(false));
+}
+
+#[ic_cdk::update]
+fn set_autoscale_settings(settings: AutoscaleSettings) -> Result<AutoscaleSettings, Error> {
+    authorize_controller()?;
+    AUTOSCALE_SETTINGS
+        .with(|s| s.borrow_mut().set(settings.clone()))
+        .expect("Cannot update autoscale settings");
+    Ok(settings)
+}
+
+// controllers upload the module for overflow canisters in chunks, chunk 0 starts over
+#[ic_cdk::update]
+fn upload_overflow_wasm_chunk(chunk: u64, data: Vec<u8>) -> Result<u64, Error> {
+    authorize_controller()?;
+    if data.is_empty() || data.len() > ATTACHMENT_CHUNK_SIZE {
+        return Err(Error::InvalidPayload {
+            msg: format!("A wasm chunk holds 1 to {} bytes", ATTACHMENT_CHUNK_SIZE),
+        });
+    }
+    Ok(OVERFLOW_WASM.with(|s| {
+        let mut wasm = s.borrow_mut();
+        if chunk == 0 {
+            let keys: Vec<u64> = wasm.iter().map(|(key, _)| key).collect();
+            for key in keys {
+                wasm.remove(&key);
+            }
+        }
+        wasm.insert(chunk, AttachmentChunk(data));
+        wasm.iter().map(|(_, chunk)| chunk.0.len() as u64).sum()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_autoscale_status() -> Result<AutoscaleStatus, Error> {
+    authorize_controller()?;
+    Ok(AutoscaleStatus {
+        settings: autoscale_settings(),
+        local_attachment_bytes: local_attachment_bytes(),
+        wasm_bytes: OVERFLOW_WASM.with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, chunk)| chunk.0.len() as u64)
+                .sum()
+        }),
+        overflow_canisters: overflow_canisters(),
+        last_error: LAST_ERROR.with(|last| last.borrow().clone()),
+    })
+}
@@ -0,0 +1,101 @@
+use crate::{
+    authenticate_patient, authorize_auditor, authorize_doctor, authorize_hospital, authorize_nurse,
+    check_not_sealed, directory_entry, resolve_ref, AccountRole, DirectoryEntry, Doctor, Entity,
+    EntityRef, Error, Patient, MAX_PAGE_SIZE,
+};
+
+// Who is asking, checked once for the whole batch
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct BatchAuth {
+    pub role: AccountRole,
+    pub password: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum EntityView {
+    Patient(Patient),
+    Doctor(Doctor),
+    Hospital(DirectoryEntry),
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct BatchItem {
+    pub entity: EntityRef,
+    pub result: Result<EntityView, Error>,
+}
+
+// the checked credentials of the asker
+#[derive(Clone, Copy)]
+enum Viewer {
+    Patient(u64),
+    Doctor(u64),
+    // hospital admins, nurses and auditors see the patients of their hospital
+    HospitalStaff(u64),
+}
+
+fn authorize_viewer(auth: BatchAuth) -> Result<Viewer, Error> {
+    let password = &auth.password;
+    Ok(match auth.role {
+        AccountRole::Patient(id) => Viewer::Patient(authenticate_patient(id, password)?.id),
+        AccountRole::Doctor(id) => Viewer::Doctor(authorize_doctor(id, password)?.id),
+        AccountRole::Hospital(id) => Viewer::HospitalStaff(authorize_hospital(id, password)?.id),
+        AccountRole::Nurse(id) => Viewer::HospitalStaff(authorize_nurse(id, password)?.hospital_id),
+        AccountRole::Auditor(id) => {
+            Viewer::HospitalStaff(authorize_auditor(id, password)?.hospital_id)
+        }
+    })
+}
+
+fn may_view_patient(viewer: Option<Viewer>, patient: &Patient) -> bool {
+    match viewer {
+        Some(Viewer::Patient(id)) => id == patient.id,
+        Some(Viewer::Doctor(id)) => patient.doctors_ids.contains(&id),
+        Some(Viewer::HospitalStaff(hospital_id)) => patient.hospitals_ids.contains(&hospital_id),
+        None => false,
+    }
+}
+
+fn lookup(entity: EntityRef, viewer: Option<Viewer>) -> Result<EntityView, Error> {
+    match resolve_ref(entity)? {
+        Entity::Hospital(hospital) => Ok(EntityView::Hospital(directory_entry(hospital))),
+        Entity::Doctor(doctor) => Ok(EntityView::Doctor(Doctor {
+            password: "-".to_string(),
+            ..doctor
+        })),
+        Entity::Patient(patient) => {
+            check_not_sealed(patient.id)?;
+            if !may_view_patient(viewer, &patient) {
+                return Err(Error::Unauthorized {
+                    msg: format!("Not allowed to view patient {}", patient.id),
+                });
+            }
+            Ok(EntityView::Patient(Patient {
+                password: "-".to_string(),
+                history: "-".to_string(),
+                ..patient
+            }))
+        }
+    }
+}
+
+// resolve a mixed list of patients, doctors and hospitals in one call. Hospitals and doctors
+// are public, patients need credentials that give access to them; each item gets its own result
+#[ic_cdk::query]
+fn batch_get(
+    entity_refs: Vec<EntityRef>,
+    auth: Option<BatchAuth>,
+) -> Result<Vec<BatchItem>, Error> {
+    if entity_refs.len() as u64 > MAX_PAGE_SIZE {
+        return Err(Error::LimitExceeded {
+            msg: format!("A batch can hold at most {} items", MAX_PAGE_SIZE),
+        });
+    }
+    let viewer = auth.map(authorize_viewer).transpose()?;
+    Ok(entity_refs
+        .into_iter()
+        .map(|entity| BatchItem {
+            entity,
+            result: lookup(entity, viewer),
+        })
+        .collect())
+}
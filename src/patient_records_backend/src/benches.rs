@@ -0,0 +1,118 @@
+// Instruction-count benchmarks for the hot paths, run with `canbench` from the backend crate.
+// Each benchmark also fails when it goes over its budget, so CI catches regressions in
+// serialization or indexing before a deploy
+use crate::{
+    add_hospital, add_patient, audit, build_chart, get_all_hospitals, insert_record,
+    patient_records, Actor, HospitalPayload, MedicalRecord, PatientPayload, RecordKind,
+    MAX_PAGE_SIZE,
+};
+use canbench_rs::{bench, bench_fn, BenchResult};
+
+// budgets in instructions, with headroom over what canbench reports; tighten them when a path
+// gets cheaper
+const ADD_PATIENT_BUDGET: u64 = 5_000_000;
+const PATIENT_CHART_BUDGET: u64 = 60_000_000;
+const AUDIT_APPEND_BUDGET: u64 = 2_000_000;
+const HOSPITAL_PAGE_BUDGET: u64 = 40_000_000;
+
+fn within_budget(name: &str, result: BenchResult, budget: u64) -> BenchResult {
+    assert!(
+        result.total.instructions <= budget,
+        "{} used {} instructions, over its budget of {}",
+        name,
+        result.total.instructions,
+        budget
+    );
+    result
+}
+
+fn patient_payload(n: u64) -> PatientPayload {
+    PatientPayload {
+        name: format!("Bench patient {}", n),
+        history: "No known conditions".to_string(),
+        password: "bench-password".to_string(),
+        language: None,
+    }
+}
+
+fn hospital_payload(n: u64) -> HospitalPayload {
+    HospitalPayload {
+        name: format!("Bench hospital {}", n),
+        address: format!("{} Bench Street", n),
+        password: "bench-password".to_string(),
+        city: "Nairobi".to_string(),
+        ..Default::default()
+    }
+}
+
+#[bench(raw)]
+fn add_patient_to_populated_store() -> BenchResult {
+    for n in 0..1_000 {
+        assert!(add_patient(patient_payload(n)).is_ok());
+    }
+    let result = bench_fn(|| {
+        assert!(add_patient(patient_payload(1_000)).is_ok());
+    });
+    within_budget("add_patient", result, ADD_PATIENT_BUDGET)
+}
+
+#[bench(raw)]
+fn patient_chart_with_many_records() -> BenchResult {
+    let Ok(patient) = add_patient(patient_payload(0)) else {
+        panic!("Could not create the benchmark patient");
+    };
+    for n in 0..200 {
+        insert_record(&MedicalRecord {
+            id: crate::next_id(),
+            patient_id: patient.id,
+            doctor_id: None,
+            hospital_id: None,
+            kind: if n % 4 == 0 {
+                RecordKind::Diagnosis
+            } else {
+                RecordKind::Note
+            },
+            title: format!("Record {}", n),
+            body: "Seen in clinic, stable, follow up in three months".repeat(10),
+            created_at: 0,
+            migrated: false,
+            restored_at: None,
+            addendum_to: None,
+        });
+    }
+    let result = bench_fn(|| {
+        build_chart(patient.clone(), patient_records(patient.id), vec![]);
+    });
+    within_budget("get_patient_chart", result, PATIENT_CHART_BUDGET)
+}
+
+#[bench(raw)]
+fn audit_append_to_long_log() -> BenchResult {
+    for n in 0..10_000 {
+        audit(Actor::System, None, Some(n), "bench", String::new());
+    }
+    let result = bench_fn(|| {
+        audit(
+            Actor::Doctor(1),
+            Some(1),
+            Some(1),
+            "patient_record_read",
+            "record 1".to_string(),
+        );
+    });
+    within_budget("audit", result, AUDIT_APPEND_BUDGET)
+}
+
+#[bench(raw)]
+fn hospital_directory_page() -> BenchResult {
+    for n in 0..300 {
+        assert!(add_hospital(hospital_payload(n)).is_ok());
+    }
+    let result = bench_fn(|| {
+        let Ok(first) = get_all_hospitals(None, MAX_PAGE_SIZE) else {
+            panic!("Could not list hospitals");
+        };
+        assert!(get_all_hospitals(first.next_cursor, MAX_PAGE_SIZE).is_ok());
+    });
+    within_budget("get_all_hospitals", result, HOSPITAL_PAGE_BUDGET)
+}
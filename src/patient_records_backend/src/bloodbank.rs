@@ -0,0 +1,417 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_hospital, get_assigned_patient, impl_storable, next_id,
+    save_patient, Actor, Error, HospitalAccessPayload, Memory, Patient, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// the reason goes into the audit entry, which holds 1024 bytes
+const MAX_DISCARD_REASON_BYTES: usize = 512;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub enum BloodType {
+    APositive,
+    ANegative,
+    BPositive,
+    BNegative,
+    ABPositive,
+    ABNegative,
+    OPositive,
+    ONegative,
+}
+
+impl BloodType {
+    // (has A antigen, has B antigen, is Rh positive)
+    fn antigens(&self) -> (bool, bool, bool) {
+        match self {
+            BloodType::APositive => (true, false, true),
+            BloodType::ANegative => (true, false, false),
+            BloodType::BPositive => (false, true, true),
+            BloodType::BNegative => (false, true, false),
+            BloodType::ABPositive => (true, true, true),
+            BloodType::ABNegative => (true, true, false),
+            BloodType::OPositive => (false, false, true),
+            BloodType::ONegative => (false, false, false),
+        }
+    }
+
+    // red cells can be given when the donor carries no antigen the recipient lacks
+    pub fn can_donate_to(&self, recipient: &BloodType) -> bool {
+        let (donor_a, donor_b, donor_rh) = self.antigens();
+        let (recipient_a, recipient_b, recipient_rh) = recipient.antigens();
+        (!donor_a || recipient_a) && (!donor_b || recipient_b) && (!donor_rh || recipient_rh)
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum BloodUnitStatus {
+    Available,
+    Reserved,
+    Transfused,
+    Discarded,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct BloodUnit {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub blood_type: BloodType,
+    pub collected_at: u64,
+    pub expires_at: u64,
+    pub status: BloodUnitStatus,
+    pub patient_id: Option<u64>,
+    pub updated_at: u64,
+}
+
+impl_storable!(BloodUnit, 512);
+
+thread_local! {
+    static BLOOD_UNIT_STORAGE: RefCell<StableBTreeMap<u64, BloodUnit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct BloodTypePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub blood_type: BloodType,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RegisterUnitPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub blood_type: BloodType,
+    pub collected_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct BloodUnitPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub unit_id: u64,
+    pub patient_id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DiscardUnitPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub unit_id: u64,
+    pub reason: String,
+}
+
+fn get_unit(unit_id: u64) -> Result<BloodUnit, Error> {
+    BLOOD_UNIT_STORAGE
+        .with(|s| s.borrow().get(&unit_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Blood unit of id: {} not found", unit_id),
+        })
+}
+
+fn save_unit(unit: &BloodUnit) {
+    BLOOD_UNIT_STORAGE.with(|s| s.borrow_mut().insert(unit.id, unit.clone()));
+}
+
+// helper function to check that a unit can be given to the patient, logging refusals
+fn check_compatibility(unit: &BloodUnit, patient: &Patient, doctor_id: u64) -> Result<(), Error> {
+    let refusal = match patient.blood_type {
+        _ if unit.expires_at <= time() => Some(format!("blood unit {} has expired", unit.id)),
+        None => Some(format!("patient {} has no recorded blood type", patient.id)),
+        Some(recipient) if !unit.blood_type.can_donate_to(&recipient) => Some(format!(
+            "blood unit {} of type {:?} is incompatible with patient type {:?}",
+            unit.id, unit.blood_type, recipient
+        )),
+        Some(_) => None,
+    };
+    match refusal {
+        Some(msg) => {
+            audit(
+                Actor::Doctor(doctor_id),
+                Some(unit.hospital_id),
+                Some(patient.id),
+                "blood_unit_refused",
+                msg.clone(),
+            );
+            Err(Error::InvalidPayload { msg })
+        }
+        None => Ok(()),
+    }
+}
+
+// record a patient's blood type, needed before any transfusion
+#[ic_cdk::update]
+fn set_patient_blood_type(payload: BloodTypePayload) -> Result<Patient, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let updated = Patient {
+        blood_type: Some(payload.blood_type),
+        ..patient
+    };
+    save_patient(&updated);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(updated.id),
+        "blood_type_recorded",
+        format!("{:?}", payload.blood_type),
+    );
+    Ok(Patient {
+        password: "-".to_string(),
+        ..updated
+    })
+}
+
+// add a collected blood unit to the hospital's inventory
+#[ic_cdk::update]
+fn register_unit(payload: RegisterUnitPayload) -> Result<BloodUnit, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if payload.expires_at <= time() || payload.expires_at <= payload.collected_at {
+        return Err(Error::InvalidPayload {
+            msg: "Blood unit expiry must be in the future and after collection".to_string(),
+        });
+    }
+    let unit = BloodUnit {
+        id: next_id(),
+        hospital_id: hospital.id,
+        blood_type: payload.blood_type,
+        collected_at: payload.collected_at,
+        expires_at: payload.expires_at,
+        status: BloodUnitStatus::Available,
+        patient_id: None,
+        updated_at: time(),
+    };
+    save_unit(&unit);
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        None,
+        "blood_unit_registered",
+        format!("unit {} of type {:?}", unit.id, unit.blood_type),
+    );
+    Ok(unit)
+}
+
+// reserve an available, compatible unit of the doctor's hospital for a patient
+#[ic_cdk::update]
+fn reserve_unit_for_patient(payload: BloodUnitPayload) -> Result<BloodUnit, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let unit = get_unit(payload.unit_id)?;
+    if unit.hospital_id != doctor.hospital_id {
+        return Err(Error::Unauthorized {
+            msg: format!("Blood unit of id: {} belongs to another hospital", unit.id),
+        });
+    }
+    if unit.status != BloodUnitStatus::Available {
+        return Err(Error::InvalidPayload {
+            msg: format!("Blood unit of id: {} is not available", unit.id),
+        });
+    }
+    check_compatibility(&unit, &patient, doctor.id)?;
+
+    let reserved = BloodUnit {
+        status: BloodUnitStatus::Reserved,
+        patient_id: Some(patient.id),
+        updated_at: time(),
+        ..unit
+    };
+    save_unit(&reserved);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(reserved.hospital_id),
+        Some(patient.id),
+        "blood_unit_reserved",
+        format!("unit {} of type {:?}", reserved.id, reserved.blood_type),
+    );
+    Ok(reserved)
+}
+
+// transfuse a unit that was reserved for the patient
+#[ic_cdk::update]
+fn transfuse_unit(payload: BloodUnitPayload) -> Result<BloodUnit, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let unit = get_unit(payload.unit_id)?;
+    if unit.status != BloodUnitStatus::Reserved || unit.patient_id != Some(patient.id) {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Blood unit of id: {} is not reserved for patient {}",
+                unit.id, patient.id
+            ),
+        });
+    }
+    check_compatibility(&unit, &patient, doctor.id)?;
+
+    let transfused = BloodUnit {
+        status: BloodUnitStatus::Transfused,
+        updated_at: time(),
+        ..unit
+    };
+    save_unit(&transfused);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(transfused.hospital_id),
+        Some(patient.id),
+        "blood_unit_transfused",
+        format!("unit {} of type {:?}", transfused.id, transfused.blood_type),
+    );
+    Ok(transfused)
+}
+
+// take an expired or damaged unit out of the inventory
+#[ic_cdk::update]
+fn discard_unit(payload: DiscardUnitPayload) -> Result<BloodUnit, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let unit = get_unit(payload.unit_id)?;
+    if payload.reason.len() > MAX_DISCARD_REASON_BYTES {
+        return Err(Error::LimitExceeded {
+            msg: format!("Discard reason exceeds {} bytes", MAX_DISCARD_REASON_BYTES),
+        });
+    }
+    if unit.hospital_id != hospital.id
+        || matches!(
+            unit.status,
+            BloodUnitStatus::Transfused | BloodUnitStatus::Discarded
+        )
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("Blood unit of id: {} cannot be discarded", unit.id),
+        });
+    }
+    let discarded = BloodUnit {
+        status: BloodUnitStatus::Discarded,
+        updated_at: time(),
+        ..unit
+    };
+    save_unit(&discarded);
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        discarded.patient_id,
+        "blood_unit_discarded",
+        format!("unit {}: {}", discarded.id, payload.reason),
+    );
+    Ok(discarded)
+}
+
+// all units held by the hospital that are not yet used or discarded
+#[ic_cdk::query]
+fn get_blood_inventory(payload: HospitalAccessPayload) -> Result<Vec<BloodUnit>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    Ok(BLOOD_UNIT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, unit)| unit)
+            .filter(|unit| {
+                unit.hospital_id == hospital.id
+                    && matches!(
+                        unit.status,
+                        BloodUnitStatus::Available | BloodUnitStatus::Reserved
+                    )
+            })
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{advance_clock, clinic, must, Clinic, PASSWORD};
+
+    const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+    const ALL: [BloodType; 8] = [
+        BloodType::APositive,
+        BloodType::ANegative,
+        BloodType::BPositive,
+        BloodType::BNegative,
+        BloodType::ABPositive,
+        BloodType::ABNegative,
+        BloodType::OPositive,
+        BloodType::ONegative,
+    ];
+
+    fn unit(clinic: &Clinic, blood_type: BloodType) -> BloodUnit {
+        must(register_unit(RegisterUnitPayload {
+            hospital_id: clinic.hospital_id,
+            hospital_password: PASSWORD.to_string(),
+            blood_type,
+            collected_at: time(),
+            expires_at: time() + DAY_NS,
+        }))
+    }
+
+    fn type_patient(clinic: &Clinic, blood_type: BloodType) {
+        must(set_patient_blood_type(BloodTypePayload {
+            doctor_id: clinic.doctor_id,
+            doctor_password: PASSWORD.to_string(),
+            patient_id: clinic.patient_id,
+            blood_type,
+        }));
+    }
+
+    fn payload(clinic: &Clinic, unit_id: u64) -> BloodUnitPayload {
+        BloodUnitPayload {
+            doctor_id: clinic.doctor_id,
+            doctor_password: PASSWORD.to_string(),
+            unit_id,
+            patient_id: clinic.patient_id,
+        }
+    }
+
+    #[test]
+    fn abo_and_rh_compatibility() {
+        for recipient in ALL {
+            assert!(BloodType::ONegative.can_donate_to(&recipient));
+            assert!(recipient.can_donate_to(&BloodType::ABPositive));
+            assert!(recipient.can_donate_to(&recipient));
+        }
+        assert!(!BloodType::APositive.can_donate_to(&BloodType::ANegative));
+        assert!(!BloodType::ANegative.can_donate_to(&BloodType::BNegative));
+        assert!(!BloodType::BPositive.can_donate_to(&BloodType::OPositive));
+        assert!(!BloodType::ABNegative.can_donate_to(&BloodType::APositive));
+        let compatible = ALL
+            .iter()
+            .flat_map(|donor| {
+                ALL.iter()
+                    .filter(|recipient| donor.can_donate_to(recipient))
+            })
+            .count();
+        assert_eq!(compatible, 27);
+    }
+
+    #[test]
+    fn only_compatible_unexpired_units_are_reserved_for_a_typed_patient() {
+        let clinic = clinic();
+        let donor = unit(&clinic, BloodType::ONegative);
+        // no transfusion before the patient's type is known
+        assert!(reserve_unit_for_patient(payload(&clinic, donor.id)).is_err());
+        type_patient(&clinic, BloodType::ANegative);
+        let positive = unit(&clinic, BloodType::APositive);
+        assert!(reserve_unit_for_patient(payload(&clinic, positive.id)).is_err());
+        let expiring = unit(&clinic, BloodType::ANegative);
+        must(reserve_unit_for_patient(payload(&clinic, donor.id)));
+        advance_clock(DAY_NS);
+        assert!(reserve_unit_for_patient(payload(&clinic, expiring.id)).is_err());
+        assert!(get_unit(positive.id).is_ok_and(|unit| unit.status == BloodUnitStatus::Available));
+    }
+
+    #[test]
+    fn a_transfusion_rechecks_compatibility() {
+        let clinic = clinic();
+        type_patient(&clinic, BloodType::BPositive);
+        let reserved = unit(&clinic, BloodType::BPositive);
+        must(reserve_unit_for_patient(payload(&clinic, reserved.id)));
+        type_patient(&clinic, BloodType::ONegative);
+        assert!(transfuse_unit(payload(&clinic, reserved.id)).is_err());
+        type_patient(&clinic, BloodType::BPositive);
+        let transfused = must(transfuse_unit(payload(&clinic, reserved.id)));
+        assert!(transfused.status == BloodUnitStatus::Transfused);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::Memory;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Deref;
+
+// entries each cached store keeps decoded on the heap
+pub(crate) const ENTITY_CACHE_CAPACITY: usize = 512;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub entries: u64,
+    pub capacity: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// Least recently used entries go first once the cache is full
+struct Lru<V> {
+    entries: HashMap<u64, (V, u64)>,
+    // last use -> key, the first entry is the one to evict
+    order: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl<V: Clone> Lru<V> {
+    fn touch(&mut self, key: u64) -> Option<V> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (value, used) = self.entries.get_mut(&key)?;
+        self.order.remove(used);
+        *used = tick;
+        self.order.insert(tick, key);
+        Some(value.clone())
+    }
+
+    fn put(&mut self, key: u64, value: V, capacity: usize) {
+        self.forget(key);
+        if self.entries.len() >= capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key);
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    fn forget(&mut self, key: u64) {
+        if let Some((_, used)) = self.entries.remove(&key) {
+            self.order.remove(&used);
+        }
+    }
+}
+
+// A stable map with a bounded heap cache of decoded values in front of it. Writes go through
+// insert, which drops the cached copy; reads not by key go to the map itself. The cache lives
+// on the heap, so an upgrade starts it empty, and what queries add to it is discarded with them
+pub(crate) struct CachedMap<V: BoundedStorable + Clone> {
+    map: StableBTreeMap<u64, V, Memory>,
+    cache: RefCell<Lru<V>>,
+    capacity: usize,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl<V: BoundedStorable + Clone> CachedMap<V> {
+    pub(crate) fn new(map: StableBTreeMap<u64, V, Memory>, capacity: usize) -> Self {
+        CachedMap {
+            map,
+            cache: RefCell::new(Lru {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+            capacity,
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &u64) -> Option<V> {
+        if let Some(value) = self.cache.borrow_mut().touch(*key) {
+            self.hits.set(self.hits.get() + 1);
+            return Some(value);
+        }
+        self.misses.set(self.misses.get() + 1);
+        let value = self.map.get(key)?;
+        self.cache
+            .borrow_mut()
+            .put(*key, value.clone(), self.capacity);
+        Some(value)
+    }
+
+    pub(crate) fn insert(&mut self, key: u64, value: V) -> Option<V> {
+        self.cache.get_mut().forget(key);
+        self.map.insert(key, value)
+    }
+
+    // the map itself, for rewrites that store back the values they read
+    pub(crate) fn stable_mut(&mut self) -> &mut StableBTreeMap<u64, V, Memory> {
+        &mut self.map
+    }
+
+    pub(crate) fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.cache.borrow().entries.len() as u64,
+            capacity: self.capacity as u64,
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+        }
+    }
+}
+
+impl<V: BoundedStorable + Clone> Deref for CachedMap<V> {
+    type Target = StableBTreeMap<u64, V, Memory>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MEMORY_MANAGER;
+    use ic_stable_structures::memory_manager::MemoryId;
+
+    // a memory no store uses, tests run on their own thread so it starts empty
+    fn cached(capacity: usize) -> CachedMap<u64> {
+        let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(254)));
+        CachedMap::new(StableBTreeMap::init(memory), capacity)
+    }
+
+    #[test]
+    fn repeated_reads_hit_the_cache() {
+        let mut map = cached(4);
+        map.insert(1, 10);
+        assert_eq!(map.get(&1), Some(10));
+        assert_eq!(map.get(&1), Some(10));
+        assert_eq!(map.get(&2), None);
+        let stats = map.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 1));
+    }
+
+    #[test]
+    fn writes_replace_the_cached_copy() {
+        let mut map = cached(4);
+        map.insert(1, 10);
+        assert_eq!(map.get(&1), Some(10));
+        map.insert(1, 11);
+        assert_eq!(map.get(&1), Some(11));
+    }
+
+    #[test]
+    fn the_least_recently_used_entry_is_evicted() {
+        let mut map = cached(2);
+        for key in 1..=3 {
+            map.insert(key, key * 10);
+        }
+        map.get(&1);
+        map.get(&2);
+        map.get(&1);
+        // 2 is now the least recently used, reading 3 evicts it
+        map.get(&3);
+        assert_eq!(map.cache_stats().entries, 2);
+        let misses = map.cache_stats().misses;
+        map.get(&1);
+        map.get(&3);
+        assert_eq!(map.cache_stats().misses, misses);
+        map.get(&2);
+        assert_eq!(map.cache_stats().misses, misses + 1);
+    }
+}
@@ -0,0 +1,348 @@
+use crate::time;
+use crate::{
+    age_in_years, authorize_doctor, check_not_sealed, get_encounter_entries, impl_storable,
+    next_id, patient_encounters, patient_problems, rule_owner_hospital, EncounterEntryKind, Error,
+    Memory, Patient, RuleOwner, Sex, DOCTOR_STORAGE, MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::{Cell, RefCell};
+use std::ops::Bound;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// patients checked per timer run, the job walks round all of them
+const CARE_GAP_BATCH: usize = 200;
+const MAX_RULE_NAME_LEN: usize = 100;
+const MAX_SATISFIED_BY: usize = 10;
+const MAX_KEYWORD_LEN: usize = 60;
+
+// A preventive care item patients who match the criteria should have had within the interval,
+// e.g. an eye exam every 365 days for active E11 (type 2 diabetes) problems
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CareGapRule {
+    pub id: u64,
+    // None for canister-wide rules set by the controllers
+    pub hospital_id: Option<u64>,
+    pub name: String,
+    // an active problem whose ICD-10 code starts with this, e.g. "E11"
+    pub icd_prefix: Option<String>,
+    pub min_age: Option<u32>,
+    pub max_age: Option<u32>,
+    pub sex: Option<Sex>,
+    // an encounter reason, order or lab test mentioning one of these counts as done
+    pub satisfied_by: Vec<String>,
+    pub interval_days: u32,
+    pub enabled: bool,
+    pub created_at: u64,
+}
+
+// A rule a patient is overdue for, on the worklists of the patient's doctors the rule reaches
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CareGap {
+    pub rule_id: u64,
+    pub rule_name: String,
+    pub patient_id: u64,
+    pub doctors_ids: Vec<u64>,
+    pub last_done_at: Option<u64>,
+    // None when it was never done
+    pub due_since: Option<u64>,
+    pub detected_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CareGapRulePayload {
+    pub name: String,
+    pub icd_prefix: Option<String>,
+    pub min_age: Option<u32>,
+    pub max_age: Option<u32>,
+    pub sex: Option<Sex>,
+    pub satisfied_by: Vec<String>,
+    pub interval_days: u32,
+}
+
+impl_storable!(CareGapRule, 1024);
+impl_storable!(CareGap, 512);
+
+thread_local! {
+    static CARE_GAP_RULE_STORAGE: RefCell<StableBTreeMap<u64, CareGapRule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(147)))
+    ));
+
+    // (patient id, rule id) -> gap
+    static CARE_GAP_STORAGE: RefCell<StableBTreeMap<(u64, u64), CareGap, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(148)))
+    ));
+
+    // last patient the timer checked, an upgrade starts the round again
+    static CARE_GAP_CURSOR: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+fn validate_rule(payload: &CareGapRulePayload) -> Result<(), Error> {
+    let invalid = |msg: &str| {
+        Err(Error::InvalidPayload {
+            msg: msg.to_string(),
+        })
+    };
+    if payload.name.trim().is_empty() || payload.name.chars().count() > MAX_RULE_NAME_LEN {
+        return invalid("Care gap rule names hold 1 to 100 characters");
+    }
+    if payload.satisfied_by.is_empty()
+        || payload.satisfied_by.len() > MAX_SATISFIED_BY
+        || payload
+            .satisfied_by
+            .iter()
+            .any(|keyword| keyword.trim().is_empty() || keyword.chars().count() > MAX_KEYWORD_LEN)
+    {
+        return invalid("Give 1 to 10 keywords of at most 60 characters that close the gap");
+    }
+    if payload.interval_days == 0 || payload.interval_days > 10 * 365 {
+        return invalid("The interval must be between 1 day and 10 years");
+    }
+    if let (Some(min), Some(max)) = (payload.min_age, payload.max_age) {
+        if min > max {
+            return invalid("The minimum age cannot be above the maximum age");
+        }
+    }
+    if payload
+        .icd_prefix
+        .as_ref()
+        .is_some_and(|prefix| prefix.trim().is_empty() || prefix.len() > 8)
+    {
+        return invalid("ICD-10 prefixes hold 1 to 8 characters");
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn add_care_gap_rule(owner: RuleOwner, payload: CareGapRulePayload) -> Result<CareGapRule, Error> {
+    let hospital_id = rule_owner_hospital(&owner)?;
+    validate_rule(&payload)?;
+    let rule = CareGapRule {
+        id: next_id(),
+        hospital_id,
+        name: payload.name,
+        icd_prefix: payload
+            .icd_prefix
+            .map(|prefix| prefix.trim().to_uppercase()),
+        min_age: payload.min_age,
+        max_age: payload.max_age,
+        sex: payload.sex,
+        satisfied_by: payload
+            .satisfied_by
+            .iter()
+            .map(|keyword| keyword.trim().to_lowercase())
+            .collect(),
+        interval_days: payload.interval_days,
+        enabled: true,
+        created_at: time(),
+    };
+    CARE_GAP_RULE_STORAGE.with(|s| s.borrow_mut().insert(rule.id, rule.clone()));
+    Ok(rule)
+}
+
+// a disabled rule drops off the worklists on the next run over its patients
+#[ic_cdk::update]
+fn set_care_gap_rule_enabled(
+    owner: RuleOwner,
+    rule_id: u64,
+    enabled: bool,
+) -> Result<CareGapRule, Error> {
+    let hospital_id = rule_owner_hospital(&owner)?;
+    let rule = CARE_GAP_RULE_STORAGE
+        .with(|s| s.borrow().get(&rule_id))
+        .filter(|rule| rule.hospital_id == hospital_id)
+        .ok_or(Error::NotFound {
+            msg: format!("Care gap rule of id: {} not found", rule_id),
+        })?;
+    let updated = CareGapRule { enabled, ..rule };
+    CARE_GAP_RULE_STORAGE.with(|s| s.borrow_mut().insert(updated.id, updated.clone()));
+    Ok(updated)
+}
+
+// global rules and the rules of one hospital
+#[ic_cdk::query]
+fn get_care_gap_rules(hospital_id: u64) -> Vec<CareGapRule> {
+    CARE_GAP_RULE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, rule)| rule)
+            .filter(|rule| rule.hospital_id.is_none() || rule.hospital_id == Some(hospital_id))
+            .collect()
+    })
+}
+
+fn rule_applies(rule: &CareGapRule, patient: &Patient, active_codes: &[String]) -> bool {
+    if let Some(prefix) = &rule.icd_prefix {
+        if !active_codes.iter().any(|code| code.starts_with(prefix)) {
+            return false;
+        }
+    }
+    if rule.min_age.is_some() || rule.max_age.is_some() {
+        let Some(age) = age_in_years(patient) else {
+            return false;
+        };
+        if rule.min_age.is_some_and(|min| age < min as u64)
+            || rule.max_age.is_some_and(|max| age > max as u64)
+        {
+            return false;
+        }
+    }
+    match rule.sex {
+        Some(sex) => patient.sex == Some(sex),
+        None => true,
+    }
+}
+
+// when each of the patient's encounters did something, as lowercase text: the reason when it
+// was opened, and its orders and lab tests when they were recorded
+fn care_events(patient_id: u64) -> Vec<(u64, String)> {
+    let mut events = vec![];
+    for encounter in patient_encounters(patient_id) {
+        events.push((encounter.opened_at, encounter.reason.to_lowercase()));
+        for entry in get_encounter_entries(&encounter) {
+            match &entry.kind {
+                EncounterEntryKind::Order { description } => {
+                    events.push((entry.recorded_at, description.to_lowercase()))
+                }
+                EncounterEntryKind::LabResult { test, .. } => {
+                    events.push((entry.recorded_at, test.to_lowercase()))
+                }
+                _ => {}
+            }
+        }
+    }
+    events
+}
+
+// the gaps of one patient under the current rules, for each doctor of the patient the rule
+// reaches: all of them for global rules, those of the rule's hospital otherwise
+fn patient_care_gaps(patient: &Patient, rules: &[CareGapRule], now: u64) -> Vec<CareGap> {
+    if check_not_sealed(patient.id).is_err() {
+        return vec![];
+    }
+    let active_codes: Vec<String> = patient_problems(patient.id, true)
+        .into_iter()
+        .map(|problem| problem.icd_code.to_uppercase())
+        .collect();
+    let applicable: Vec<&CareGapRule> = rules
+        .iter()
+        .filter(|rule| match rule.hospital_id {
+            Some(hospital_id) => patient.hospitals_ids.contains(&hospital_id),
+            None => true,
+        })
+        .filter(|rule| rule_applies(rule, patient, &active_codes))
+        .collect();
+    if applicable.is_empty() {
+        return vec![];
+    }
+    let events = care_events(patient.id);
+    let mut gaps = vec![];
+    for rule in applicable {
+        let last_done_at = events
+            .iter()
+            .filter(|(_, text)| {
+                rule.satisfied_by
+                    .iter()
+                    .any(|keyword| text.contains(keyword))
+            })
+            .map(|(at, _)| *at)
+            .max();
+        let due_since = last_done_at.map(|at| at + rule.interval_days as u64 * DAY_NS);
+        if due_since.is_some_and(|due| due > now) {
+            continue;
+        }
+        let doctors_ids: Vec<u64> = patient
+            .doctors_ids
+            .iter()
+            .copied()
+            .filter(|doctor_id| match rule.hospital_id {
+                Some(hospital_id) => DOCTOR_STORAGE
+                    .with(|s| s.borrow().get(doctor_id))
+                    .is_some_and(|doctor| doctor.hospital_id == hospital_id),
+                None => true,
+            })
+            .collect();
+        if doctors_ids.is_empty() {
+            continue;
+        }
+        gaps.push(CareGap {
+            rule_id: rule.id,
+            rule_name: rule.name.clone(),
+            patient_id: patient.id,
+            doctors_ids,
+            last_done_at,
+            due_since,
+            detected_at: now,
+        });
+    }
+    gaps
+}
+
+// replace the stored gaps of a patient, keeping when each still open gap was first found
+fn store_patient_care_gaps(patient_id: u64, gaps: Vec<CareGap>) {
+    CARE_GAP_STORAGE.with(|s| {
+        let mut stored = s.borrow_mut();
+        let previous: Vec<((u64, u64), CareGap)> = stored
+            .range((patient_id, 0)..=(patient_id, u64::MAX))
+            .collect();
+        for (key, _) in &previous {
+            stored.remove(key);
+        }
+        for mut gap in gaps {
+            let key = (gap.patient_id, gap.rule_id);
+            if let Some((_, earlier)) = previous.iter().find(|(k, _)| *k == key) {
+                gap.detected_at = earlier.detected_at;
+            }
+            stored.insert(key, gap);
+        }
+    });
+}
+
+// timer job: check the next batch of patients against the enabled rules, starting over after
+// the last patient
+pub(crate) fn detect_care_gaps() {
+    let rules: Vec<CareGapRule> = CARE_GAP_RULE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, rule)| rule)
+            .filter(|rule| rule.enabled)
+            .collect()
+    });
+    let start = match CARE_GAP_CURSOR.with(|c| c.get()) {
+        Some(after) => Bound::Excluded(after),
+        None => Bound::Unbounded,
+    };
+    let patients: Vec<Patient> = PATIENT_STORAGE.with(|s| {
+        s.borrow()
+            .range((start, Bound::Unbounded))
+            .take(CARE_GAP_BATCH)
+            .map(|(_, patient)| patient)
+            .collect()
+    });
+    let now = time();
+    for patient in &patients {
+        store_patient_care_gaps(patient.id, patient_care_gaps(patient, &rules, now));
+    }
+    let cursor = match patients.last() {
+        Some(last) if patients.len() == CARE_GAP_BATCH => Some(last.id),
+        _ => None,
+    };
+    CARE_GAP_CURSOR.with(|c| c.set(cursor));
+}
+
+// the doctor's care gaps, the longest overdue first and never done before those
+#[ic_cdk::query]
+fn get_care_gap_worklist(doctor_id: u64, doctor_password: String) -> Result<Vec<CareGap>, Error> {
+    let doctor = authorize_doctor(doctor_id, &doctor_password)?;
+    let mut gaps: Vec<CareGap> = CARE_GAP_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, gap)| gap)
+            .filter(|gap| gap.doctors_ids.contains(&doctor.id))
+            .collect()
+    });
+    gaps.sort_by_key(|gap| (gap.due_since.is_some(), gap.due_since, gap.patient_id));
+    Ok(gaps)
+}
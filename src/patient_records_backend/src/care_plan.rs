@@ -0,0 +1,265 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_patient_access, get_assigned_patient, impl_storable,
+    next_id, notify, text, Actor, Error, Memory, PatientAccess, Priority, Recipient,
+    MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum CarePlanStatus {
+    Active,
+    Closed,
+}
+
+// A checkup or lab the plan expects by a due date
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ScheduledCheckup {
+    pub description: String,
+    pub due_at: u64,
+    pub completed_at: Option<u64>,
+    // set once the overdue reminder went out so it is sent only once
+    pub reminded_at: Option<u64>,
+}
+
+// A metric the plan aims for, e.g. HbA1c below 7 %
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TargetMetric {
+    pub name: String,
+    pub unit: String,
+    pub target_min: Option<f64>,
+    pub target_max: Option<f64>,
+    pub latest_value: Option<f64>,
+    pub latest_at: Option<u64>,
+}
+
+// Long-term plan for a chronic condition, owned by one doctor
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CarePlan {
+    pub id: u64,
+    pub patient_id: u64,
+    pub doctor_id: u64,
+    pub title: String,
+    pub goals: Vec<String>,
+    pub checkups: Vec<ScheduledCheckup>,
+    pub targets: Vec<TargetMetric>,
+    pub status: CarePlanStatus,
+    pub created_at: u64,
+}
+
+impl_storable!(CarePlan, 8192);
+
+thread_local! {
+    static CARE_PLAN_STORAGE: RefCell<StableBTreeMap<u64, CarePlan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CheckupPayload {
+    pub description: String,
+    pub due_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TargetPayload {
+    pub name: String,
+    pub unit: String,
+    pub target_min: Option<f64>,
+    pub target_max: Option<f64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CarePlanPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub title: String,
+    pub goals: Vec<String>,
+    pub checkups: Vec<CheckupPayload>,
+    pub targets: Vec<TargetPayload>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum CarePlanUpdate {
+    AddCheckup(CheckupPayload),
+    // index into the plan's checkups
+    CompleteCheckup(u64),
+    RecordTarget { name: String, value: f64 },
+    Close,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CarePlanUpdatePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub plan_id: u64,
+    pub update: CarePlanUpdate,
+}
+
+fn new_checkup(payload: CheckupPayload) -> ScheduledCheckup {
+    ScheduledCheckup {
+        description: payload.description,
+        due_at: payload.due_at,
+        completed_at: None,
+        reminded_at: None,
+    }
+}
+
+fn save_plan(plan: &CarePlan) {
+    CARE_PLAN_STORAGE.with(|s| s.borrow_mut().insert(plan.id, plan.clone()));
+}
+
+#[ic_cdk::update]
+fn create_care_plan(payload: CarePlanPayload) -> Result<CarePlan, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    if payload.title.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Care plan title cannot be empty".to_string(),
+        });
+    }
+    let plan = CarePlan {
+        id: next_id(),
+        patient_id: patient.id,
+        doctor_id: doctor.id,
+        title: payload.title,
+        goals: payload.goals,
+        checkups: payload.checkups.into_iter().map(new_checkup).collect(),
+        targets: payload
+            .targets
+            .into_iter()
+            .map(|target| TargetMetric {
+                name: target.name,
+                unit: target.unit,
+                target_min: target.target_min,
+                target_max: target.target_max,
+                latest_value: None,
+                latest_at: None,
+            })
+            .collect(),
+        status: CarePlanStatus::Active,
+        created_at: time(),
+    };
+    save_plan(&plan);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "care_plan_created",
+        format!("care plan {}", plan.id),
+    );
+    Ok(plan)
+}
+
+// only the owning doctor changes a plan
+#[ic_cdk::update]
+fn update_care_plan(payload: CarePlanUpdatePayload) -> Result<CarePlan, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let mut plan = CARE_PLAN_STORAGE
+        .with(|s| s.borrow().get(&payload.plan_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Care plan of id: {} not found", payload.plan_id),
+        })?;
+    if plan.doctor_id != doctor.id {
+        return Err(Error::Unauthorized {
+            msg: format!("Care plan of id: {} is owned by another doctor", plan.id),
+        });
+    }
+    if plan.status != CarePlanStatus::Active {
+        return Err(Error::InvalidPayload {
+            msg: format!("Care plan of id: {} is closed", plan.id),
+        });
+    }
+    let now = time();
+    match payload.update {
+        CarePlanUpdate::AddCheckup(checkup) => plan.checkups.push(new_checkup(checkup)),
+        CarePlanUpdate::CompleteCheckup(index) => match plan.checkups.get_mut(index as usize) {
+            Some(checkup) => checkup.completed_at = Some(now),
+            None => {
+                return Err(Error::NotFound {
+                    msg: format!("Checkup {} not found", index),
+                })
+            }
+        },
+        CarePlanUpdate::RecordTarget { name, value } => {
+            match plan.targets.iter_mut().find(|target| target.name == name) {
+                Some(target) => {
+                    target.latest_value = Some(value);
+                    target.latest_at = Some(now);
+                }
+                None => {
+                    return Err(Error::NotFound {
+                        msg: format!("Target metric {} not found", name),
+                    })
+                }
+            }
+        }
+        CarePlanUpdate::Close => plan.status = CarePlanStatus::Closed,
+    }
+    save_plan(&plan);
+    Ok(plan)
+}
+
+#[ic_cdk::query]
+fn get_care_plans(patient_id: u64, access: PatientAccess) -> Result<Vec<CarePlan>, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    Ok(CARE_PLAN_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, plan)| plan)
+            .filter(|plan| plan.patient_id == patient.id)
+            .collect()
+    }))
+}
+
+// timer job: remind the owning doctor and the patient once about each overdue checkup
+pub(crate) fn send_care_plan_reminders() {
+    let now = time();
+    let plans: Vec<CarePlan> = CARE_PLAN_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, plan)| plan)
+            .filter(|plan| plan.status == CarePlanStatus::Active)
+            .collect()
+    });
+    for mut plan in plans {
+        let mut changed = false;
+        for checkup in plan.checkups.iter_mut() {
+            if checkup.completed_at.is_some()
+                || checkup.reminded_at.is_some()
+                || checkup.due_at > now
+            {
+                continue;
+            }
+            let message = || {
+                text(
+                    "care_plan.checkup_overdue",
+                    "Overdue for care plan {plan}: {checkup}",
+                    vec![
+                        ("plan", plan.title.clone()),
+                        ("checkup", checkup.description.clone()),
+                    ],
+                )
+            };
+            notify(
+                Recipient::Doctor(plan.doctor_id),
+                Priority::Normal,
+                message(),
+            );
+            notify(
+                Recipient::Patient(plan.patient_id),
+                Priority::Normal,
+                message(),
+            );
+            checkup.reminded_at = Some(now);
+            changed = true;
+        }
+        if changed {
+            save_plan(&plan);
+        }
+    }
+}
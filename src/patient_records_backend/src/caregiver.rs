@@ -0,0 +1,320 @@
+use crate::time;
+use crate::{
+    appointment_view, audit, authenticate_patient, caller, check_not_sealed, impl_storable, inbox,
+    issue_consent_receipt, kiosk_principal, next_id, scope_labels, upcoming_appointments, Actor,
+    AppointmentView, ConsentAction, Error, Memory, Notification, Page, PatientConsent, Recipient,
+    MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// What a caregiver may do on the patient's behalf, caregivers can never edit records
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum CaregiverScope {
+    ViewAppointments,
+    ReceiveNotifications,
+}
+
+// Access a patient delegated to a family member or other caregiver, identified by principal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CaregiverGrant {
+    pub id: u64,
+    pub patient_id: u64,
+    pub caregiver: Principal,
+    pub caregiver_name: String,
+    pub scopes: Vec<CaregiverScope>,
+    pub granted_at: u64,
+    pub expires_at: Option<u64>,
+    pub revoked_at: Option<u64>,
+}
+
+impl CaregiverGrant {
+    pub fn is_active(&self, now: u64) -> bool {
+        match self.expires_at {
+            _ if self.revoked_at.is_some() => false,
+            Some(expiry) => now < expiry,
+            None => true,
+        }
+    }
+}
+
+impl_storable!(CaregiverGrant, 512);
+
+thread_local! {
+    static CAREGIVER_STORAGE: RefCell<StableBTreeMap<u64, CaregiverGrant, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct GrantCaregiverPayload {
+    pub patient_id: u64,
+    pub patient_password: String,
+    pub caregiver: Principal,
+    pub caregiver_name: String,
+    pub scopes: Vec<CaregiverScope>,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct CaregiverInboxPayload {
+    pub patient_id: u64,
+    // id of the last notification of the previous page
+    pub after: Option<u64>,
+    pub limit: u64,
+}
+
+pub(crate) fn patient_caregivers(patient_id: u64) -> Vec<CaregiverGrant> {
+    CAREGIVER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, grant)| grant)
+            .filter(|grant| grant.patient_id == patient_id)
+            .collect()
+    })
+}
+
+// helper function to find the caller's active grant for the patient covering the scope
+fn authorize_caregiver(patient_id: u64, scope: CaregiverScope) -> Result<CaregiverGrant, Error> {
+    check_not_sealed(patient_id)?;
+    let caller = caller();
+    let now = time();
+    patient_caregivers(patient_id)
+        .into_iter()
+        .find(|grant| {
+            grant.caregiver == caller && grant.is_active(now) && grant.scopes.contains(&scope)
+        })
+        .ok_or(Error::Unauthorized {
+            msg: format!(
+                "Caller has no active caregiver access to patient of id: {}",
+                patient_id
+            ),
+        })
+}
+
+// a patient delegates scoped access to a caregiver's principal
+#[ic_cdk::update]
+fn grant_caregiver_access(payload: GrantCaregiverPayload) -> Result<CaregiverGrant, Error> {
+    let patient = authenticate_patient(payload.patient_id, &payload.patient_password)?;
+    if payload.caregiver == Principal::anonymous()
+        || payload.scopes.is_empty()
+        || payload.caregiver_name.trim().is_empty()
+        || payload.expires_at.is_some_and(|expiry| expiry <= time())
+    {
+        return Err(Error::InvalidPayload {
+            msg: "Caregivers need a signed-in principal, a name, at least one scope and a future expiry"
+                .to_string(),
+        });
+    }
+    let grant = CaregiverGrant {
+        id: next_id(),
+        patient_id: patient.id,
+        caregiver: payload.caregiver,
+        caregiver_name: payload.caregiver_name,
+        scopes: payload.scopes,
+        granted_at: time(),
+        expires_at: payload.expires_at,
+        revoked_at: None,
+    };
+    CAREGIVER_STORAGE.with(|s| s.borrow_mut().insert(grant.id, grant.clone()));
+    audit(
+        Actor::Patient(patient.id),
+        None,
+        Some(patient.id),
+        "caregiver_granted",
+        format!("grant {} to {}", grant.id, grant.caregiver),
+    );
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Granted,
+        "caregiver_access",
+        grant.id,
+        format!("caregiver {} ({})", grant.caregiver_name, grant.caregiver),
+        scope_labels(&grant.scopes),
+        grant.expires_at,
+    );
+    Ok(grant)
+}
+
+#[ic_cdk::update]
+fn revoke_caregiver_access(
+    consent: PatientConsent,
+    grant_id: u64,
+) -> Result<CaregiverGrant, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    let grant = patient_caregivers(patient.id)
+        .into_iter()
+        .find(|grant| grant.id == grant_id && grant.revoked_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("Caregiver grant of id: {} not found", grant_id),
+        })?;
+    let revoked = CaregiverGrant {
+        revoked_at: Some(time()),
+        ..grant
+    };
+    CAREGIVER_STORAGE.with(|s| s.borrow_mut().insert(revoked.id, revoked.clone()));
+    audit(
+        Actor::Patient(patient.id),
+        None,
+        Some(patient.id),
+        "caregiver_revoked",
+        format!("grant {}", revoked.id),
+    );
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Revoked,
+        "caregiver_access",
+        revoked.id,
+        format!(
+            "caregiver {} ({})",
+            revoked.caregiver_name, revoked.caregiver
+        ),
+        scope_labels(&revoked.scopes),
+        revoked.expires_at,
+    );
+    Ok(revoked)
+}
+
+// all grants a patient has given, including revoked and expired ones
+#[ic_cdk::query]
+fn get_caregivers(consent: PatientConsent) -> Result<Vec<CaregiverGrant>, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    Ok(patient_caregivers(patient.id))
+}
+
+// active grants held by the calling caregiver
+#[ic_cdk::query]
+fn get_my_caregiver_grants() -> Vec<CaregiverGrant> {
+    caller_caregiver_grants()
+}
+
+// active grants made out to the caller's principal
+pub(crate) fn caller_caregiver_grants() -> Vec<CaregiverGrant> {
+    let caller = caller();
+    if kiosk_principal(&caller) {
+        return vec![];
+    }
+    let now = time();
+    CAREGIVER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, grant)| grant)
+            .filter(|grant| grant.caregiver == caller && grant.is_active(now))
+            .collect()
+    })
+}
+
+// a caregiver views the patient's upcoming appointments; reads are audited
+#[ic_cdk::update]
+fn get_caregiver_appointments(patient_id: u64) -> Result<Vec<AppointmentView>, Error> {
+    let grant = authorize_caregiver(patient_id, CaregiverScope::ViewAppointments)?;
+    audit(
+        Actor::Caregiver(grant.id),
+        None,
+        Some(patient_id),
+        "caregiver_read_appointments",
+        format!("{} read appointments", grant.caregiver_name),
+    );
+    Ok(upcoming_appointments(patient_id)
+        .into_iter()
+        .map(|appointment| appointment_view(appointment, &Recipient::Patient(patient_id)))
+        .collect())
+}
+
+// a caregiver reads the patient's notifications without marking them read
+#[ic_cdk::query]
+fn get_caregiver_notifications(
+    payload: CaregiverInboxPayload,
+) -> Result<Page<Notification>, Error> {
+    authorize_caregiver(payload.patient_id, CaregiverScope::ReceiveNotifications)?;
+    Ok(inbox(
+        &Recipient::Patient(payload.patient_id),
+        false,
+        payload.after,
+        payload.limit,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{advance_clock, clinic, must, principal, refused, sign_in, PASSWORD};
+
+    const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+
+    fn grant(patient_id: u64, caregiver: Principal, expires_at: Option<u64>) -> CaregiverGrant {
+        must(grant_caregiver_access(GrantCaregiverPayload {
+            patient_id,
+            patient_password: PASSWORD.to_string(),
+            caregiver,
+            caregiver_name: "Daughter".to_string(),
+            scopes: vec![CaregiverScope::ViewAppointments],
+            expires_at,
+        }))
+    }
+
+    fn notifications(patient_id: u64) -> Result<Page<Notification>, Error> {
+        get_caregiver_notifications(CaregiverInboxPayload {
+            patient_id,
+            after: None,
+            limit: 10,
+        })
+    }
+
+    #[test]
+    fn a_caregiver_acts_only_within_the_granted_scopes() {
+        let other = clinic();
+        let clinic = clinic();
+        grant(clinic.patient_id, principal(1), None);
+        sign_in(principal(1));
+        must(get_caregiver_appointments(clinic.patient_id));
+        assert!(refused(notifications(clinic.patient_id)));
+        // the grant covers one patient and one principal
+        assert!(refused(get_caregiver_appointments(other.patient_id)));
+        sign_in(principal(2));
+        assert!(refused(get_caregiver_appointments(clinic.patient_id)));
+    }
+
+    #[test]
+    fn revoked_and_expired_grants_are_refused() {
+        let clinic = clinic();
+        let revoked = grant(clinic.patient_id, principal(1), None);
+        grant(clinic.patient_id, principal(2), Some(time() + HOUR_NS));
+        must(revoke_caregiver_access(
+            PatientConsent {
+                patient_id: clinic.patient_id,
+                patient_password: PASSWORD.to_string(),
+            },
+            revoked.id,
+        ));
+        sign_in(principal(1));
+        assert!(refused(get_caregiver_appointments(clinic.patient_id)));
+        sign_in(principal(2));
+        must(get_caregiver_appointments(clinic.patient_id));
+        advance_clock(HOUR_NS);
+        assert!(refused(get_caregiver_appointments(clinic.patient_id)));
+        assert!(get_my_caregiver_grants().is_empty());
+    }
+
+    #[test]
+    fn only_the_patient_grants_access_and_never_to_the_anonymous_principal() {
+        let clinic = clinic();
+        let payload = |patient_password: &str, caregiver: Principal| GrantCaregiverPayload {
+            patient_id: clinic.patient_id,
+            patient_password: patient_password.to_string(),
+            caregiver,
+            caregiver_name: "Neighbour".to_string(),
+            scopes: vec![CaregiverScope::ReceiveNotifications],
+            expires_at: None,
+        };
+        assert!(refused(grant_caregiver_access(payload(
+            "wrong",
+            principal(1)
+        ))));
+        assert!(grant_caregiver_access(payload(PASSWORD, Principal::anonymous())).is_err());
+        assert!(patient_caregivers(clinic.patient_id).is_empty());
+    }
+}
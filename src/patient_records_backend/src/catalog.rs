@@ -0,0 +1,234 @@
+use crate::{
+    authorize_controller, authorize_hospital, directory_entry, hospital_in_city, impl_storable,
+    next_id, DirectoryEntry, Error, Memory, Page, HOSPITAL_STORAGE, MAX_PAGE_SIZE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+// what a fresh canister starts with, controllers extend or retire entries afterwards
+const DEFAULT_SPECIALTIES: [(&str, &str); 12] = [
+    ("cardiology", "Cardiology"),
+    ("dermatology", "Dermatology"),
+    ("emergency_medicine", "Emergency medicine"),
+    ("general_practice", "General practice"),
+    ("neurology", "Neurology"),
+    ("obstetrics_gynecology", "Obstetrics and gynecology"),
+    ("oncology", "Oncology"),
+    ("orthopedics", "Orthopedics"),
+    ("pediatrics", "Pediatrics"),
+    ("psychiatry", "Psychiatry"),
+    ("radiology", "Radiology"),
+    ("surgery", "Surgery"),
+];
+const DEFAULT_SERVICES: [(&str, &str); 6] = [
+    ("imaging", "Imaging"),
+    ("laboratory", "Laboratory"),
+    ("maternity", "Maternity ward"),
+    ("pharmacy", "Pharmacy"),
+    ("physiotherapy", "Physiotherapy"),
+    ("vaccination", "Vaccination"),
+];
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum CatalogKind {
+    Specialty,
+    Service,
+}
+
+// One entry of the managed specialty and service taxonomy, referred to by its code
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub id: u64,
+    pub code: String,
+    pub name: String,
+    pub kind: CatalogKind,
+    // retired entries stay on existing hospitals but cannot be declared or searched
+    pub retired: bool,
+}
+
+impl_storable!(CatalogEntry, 256);
+
+thread_local! {
+    static CATALOG_STORAGE: RefCell<StableBTreeMap<u64, CatalogEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+    ));
+
+    // (catalog entry id, hospital id) of every declared offering
+    static OFFERING_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CatalogEntryPayload {
+    pub code: String,
+    pub name: String,
+    pub kind: CatalogKind,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct HospitalServicesPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    // the full set of codes the hospital offers, replacing what it declared before
+    pub codes: Vec<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct FindHospitalsPayload {
+    pub code: String,
+    // empty matches every city
+    pub city: String,
+    // hospital id of the last entry of the previous page
+    pub after: Option<u64>,
+    pub limit: u64,
+}
+
+fn catalog() -> Vec<CatalogEntry> {
+    CATALOG_STORAGE.with(|s| s.borrow().iter().map(|(_, entry)| entry).collect())
+}
+
+// the active catalog entry of a code
+pub(crate) fn catalog_entry(code: &str) -> Result<CatalogEntry, Error> {
+    let code = code.trim().to_lowercase();
+    catalog()
+        .into_iter()
+        .find(|entry| entry.code == code && !entry.retired)
+        .ok_or(Error::NotFound {
+            msg: format!("No specialty or service with code: {}", code),
+        })
+}
+
+fn insert_entry(code: &str, name: &str, kind: CatalogKind) -> CatalogEntry {
+    let entry = CatalogEntry {
+        id: next_id(),
+        code: code.to_string(),
+        name: name.to_string(),
+        kind,
+        retired: false,
+    };
+    CATALOG_STORAGE.with(|s| s.borrow_mut().insert(entry.id, entry.clone()));
+    entry
+}
+
+// fill an empty catalog with the default specialties and services
+pub(crate) fn seed_catalog() {
+    if CATALOG_STORAGE.with(|s| !s.borrow().is_empty()) {
+        return;
+    }
+    for (code, name) in DEFAULT_SPECIALTIES {
+        insert_entry(code, name, CatalogKind::Specialty);
+    }
+    for (code, name) in DEFAULT_SERVICES {
+        insert_entry(code, name, CatalogKind::Service);
+    }
+}
+
+// catalog entries a hospital declared, in catalog order
+pub(crate) fn hospital_offerings(hospital_id: u64) -> Vec<CatalogEntry> {
+    let declared: Vec<u64> = OFFERING_INDEX.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .filter(|((_, id), _)| *id == hospital_id)
+            .map(|((entry_id, _), _)| entry_id)
+            .collect()
+    });
+    CATALOG_STORAGE.with(|s| {
+        let catalog = s.borrow();
+        declared.iter().filter_map(|id| catalog.get(id)).collect()
+    })
+}
+
+#[ic_cdk::update]
+fn add_catalog_entry(payload: CatalogEntryPayload) -> Result<CatalogEntry, Error> {
+    authorize_controller()?;
+    let code = payload.code.trim().to_lowercase();
+    if code.is_empty()
+        || !code
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        || payload.name.trim().is_empty()
+    {
+        return Err(Error::InvalidPayload {
+            msg: "Codes use lowercase letters, digits and underscores and need a name".to_string(),
+        });
+    }
+    if catalog().iter().any(|entry| entry.code == code) {
+        return Err(Error::AlreadyInit {
+            msg: format!("Code {} is already in the catalog", code),
+        });
+    }
+    Ok(insert_entry(&code, &payload.name, payload.kind))
+}
+
+#[ic_cdk::update]
+fn retire_catalog_entry(code: String) -> Result<CatalogEntry, Error> {
+    authorize_controller()?;
+    let retired = CatalogEntry {
+        retired: true,
+        ..catalog_entry(&code)?
+    };
+    CATALOG_STORAGE.with(|s| s.borrow_mut().insert(retired.id, retired.clone()));
+    Ok(retired)
+}
+
+#[ic_cdk::query]
+fn get_catalog() -> Vec<CatalogEntry> {
+    catalog()
+}
+
+// declare the specialties and services the hospital offers
+#[ic_cdk::update]
+fn set_hospital_services(payload: HospitalServicesPayload) -> Result<Vec<CatalogEntry>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let entries = payload
+        .codes
+        .iter()
+        .map(|code| catalog_entry(code))
+        .collect::<Result<Vec<CatalogEntry>, Error>>()?;
+    OFFERING_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for entry in hospital_offerings(hospital.id) {
+            index.remove(&(entry.id, hospital.id));
+        }
+        for entry in entries.iter() {
+            index.insert((entry.id, hospital.id), ());
+        }
+    });
+    Ok(hospital_offerings(hospital.id))
+}
+
+// hospitals offering a specialty or service, optionally only in one city
+#[ic_cdk::query]
+fn find_hospitals_offering(payload: FindHospitalsPayload) -> Result<Page<DirectoryEntry>, Error> {
+    let entry = catalog_entry(&payload.code)?;
+    let start = payload.after.map_or(0, |after| after + 1);
+    let limit = payload.limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let city = payload.city.trim();
+    let mut matches = OFFERING_INDEX
+        .with(|index| {
+            index
+                .borrow()
+                .range((
+                    Bound::Included((entry.id, start)),
+                    Bound::Included((entry.id, u64::MAX)),
+                ))
+                .map(|((_, hospital_id), _)| hospital_id)
+                .collect::<Vec<u64>>()
+        })
+        .into_iter()
+        .filter(|hospital_id| city.is_empty() || hospital_in_city(*hospital_id, city))
+        .filter_map(|hospital_id| HOSPITAL_STORAGE.with(|s| s.borrow().get(&hospital_id)))
+        .map(directory_entry);
+    let items: Vec<DirectoryEntry> = matches.by_ref().take(limit).collect();
+    let next_cursor = match matches.next() {
+        Some(_) => items.last().map(|entry| entry.hospital_id),
+        None => None,
+    };
+    Ok(Page { items, next_cursor })
+}
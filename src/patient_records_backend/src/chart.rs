@@ -0,0 +1,84 @@
+use crate::time;
+use crate::{
+    active_infection_flags, authorize_patient_access, patient_allergies, patient_pins,
+    patient_prescriptions, patient_problems, patient_records, patient_vitals,
+    shard_patient_records, upcoming_appointments, Allergy, Appointment, BloodType, EncounterEntry,
+    Error, InfectionFlag, MedicalRecord, Patient, PatientAccess, Pin, Problem, RecordKind,
+};
+use candid::Principal;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// number of vitals readings included in the chart
+const RECENT_VITALS: usize = 5;
+
+// Everything a clinician needs on the first screen, assembled in one call
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PatientChart {
+    pub patient_id: u64,
+    pub name: String,
+    pub blood_type: Option<BloodType>,
+    pub doctors_ids: Vec<u64>,
+    // infection and isolation flags not yet cleared, shown first
+    pub infection_flags: Vec<InfectionFlag>,
+    // items the care team pinned, shown above everything else
+    pub pinned: Vec<Pin>,
+    pub active_problems: Vec<Problem>,
+    // diagnosis records, including those held by other shards
+    pub diagnoses: Vec<MedicalRecord>,
+    pub allergies: Vec<Allergy>,
+    pub current_medications: Vec<EncounterEntry>,
+    pub recent_vitals: Vec<EncounterEntry>,
+    pub upcoming_appointments: Vec<Appointment>,
+    // shards that did not answer, so the chart may be missing their records
+    pub unavailable_shards: Vec<Principal>,
+}
+
+// composite so records held by other shards can be read without going through consensus
+#[ic_cdk::query(composite = true)]
+async fn get_patient_chart(patient_id: u64, access: PatientAccess) -> Result<PatientChart, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    let mut records = patient_records(patient.id);
+    let (shard_records, unavailable_shards) = shard_patient_records(patient.id).await;
+    records.extend(shard_records);
+    Ok(build_chart(patient, records, unavailable_shards))
+}
+
+// assemble the chart from the patient's records, local and from other shards
+pub(crate) fn build_chart(
+    patient: Patient,
+    records: Vec<MedicalRecord>,
+    unavailable_shards: Vec<Principal>,
+) -> PatientChart {
+    let now = time();
+    let diagnoses = records
+        .into_iter()
+        .filter(|record| record.kind == RecordKind::Diagnosis)
+        .collect();
+    // prescriptions whose course has not run out yet
+    let current_medications = patient_prescriptions(patient.id)
+        .into_iter()
+        .filter(|(entry, prescription)| {
+            entry.recorded_at + prescription.duration_days as u64 * DAY_NS > now
+        })
+        .map(|(entry, _)| entry)
+        .collect();
+    let mut recent_vitals = patient_vitals(patient.id);
+    recent_vitals.sort_by_key(|entry| std::cmp::Reverse(entry.recorded_at));
+    recent_vitals.truncate(RECENT_VITALS);
+
+    PatientChart {
+        patient_id: patient.id,
+        name: patient.name,
+        blood_type: patient.blood_type,
+        doctors_ids: patient.doctors_ids,
+        infection_flags: active_infection_flags(patient.id),
+        pinned: patient_pins(patient.id),
+        active_problems: patient_problems(patient.id, true),
+        diagnoses,
+        allergies: patient_allergies(patient.id),
+        current_medications,
+        recent_vitals,
+        upcoming_appointments: upcoming_appointments(patient.id),
+        unavailable_shards,
+    }
+}
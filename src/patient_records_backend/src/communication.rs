@@ -0,0 +1,307 @@
+use crate::time;
+use crate::{
+    all_appointments, authenticate_patient, authorize_controller, deliver_notification,
+    escalated_reminder_leads, format_local_time, impl_storable, notify, page_after, text,
+    utc_offset, AppointmentStatus, Error, Memory, Notification, Page, Priority, Recipient, Text,
+    MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+const DAY_NS: u64 = 24 * HOUR_NS;
+const DEFAULT_REMINDER_LEAD_HOURS: u32 = 24;
+const MAX_REMINDER_LEADS: usize = 5;
+// outbox messages delivered per timer run
+const DELIVERY_BATCH: usize = 200;
+
+// Ways a patient can be reached besides the portal inbox, which always gets a copy
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Channel {
+    Email,
+    Sms,
+    Push,
+}
+
+// Local hours, start inclusive and end exclusive, in which only urgent messages are sent.
+// The window wraps past midnight when start is after end, e.g. 22 to 7
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start_hour: u8,
+    pub end_hour: u8,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CommunicationPreferences {
+    pub patient_id: u64,
+    pub channels: Vec<Channel>,
+    // hours before an appointment at which a reminder is sent
+    pub reminder_lead_hours: Vec<u32>,
+    pub quiet_hours: Option<QuietHours>,
+    pub updated_at: u64,
+}
+
+// A patient notification waiting for its delivery time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct OutboxMessage {
+    pub notification: Notification,
+    pub channels: Vec<Channel>,
+    pub deliver_at: u64,
+    pub delivered_at: Option<u64>,
+}
+
+impl_storable!(CommunicationPreferences, 256);
+impl_storable!(OutboxMessage, 1536);
+
+thread_local! {
+    static PREFERENCE_STORAGE: RefCell<StableBTreeMap<u64, CommunicationPreferences, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(111)))
+    ));
+
+    // keyed by notification id
+    static OUTBOX: RefCell<StableBTreeMap<u64, OutboxMessage, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(112)))
+    ));
+
+    // (appointment id, lead hours) -> when that reminder went out
+    static REMINDERS_SENT: RefCell<StableBTreeMap<(u64, u32), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(113)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct CommunicationPreferencesPayload {
+    pub patient_id: u64,
+    pub password: String,
+    pub channels: Vec<Channel>,
+    pub reminder_lead_hours: Vec<u32>,
+    pub quiet_hours: Option<QuietHours>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct OutboxQuery {
+    pub channel: Option<Channel>,
+    pub undelivered_only: bool,
+    // notification id of the last message of the previous page
+    pub after: Option<u64>,
+    pub limit: u64,
+}
+
+fn default_preferences(patient_id: u64) -> CommunicationPreferences {
+    CommunicationPreferences {
+        patient_id,
+        channels: vec![],
+        reminder_lead_hours: vec![DEFAULT_REMINDER_LEAD_HOURS],
+        quiet_hours: None,
+        updated_at: 0,
+    }
+}
+
+pub(crate) fn communication_preferences(patient_id: u64) -> CommunicationPreferences {
+    PREFERENCE_STORAGE
+        .with(|s| s.borrow().get(&patient_id))
+        .unwrap_or_else(|| default_preferences(patient_id))
+}
+
+// end of the patient's quiet hours when the time falls inside them
+fn quiet_until(quiet: &QuietHours, now: u64, offset_minutes: i16) -> Option<u64> {
+    let local = now as i128 + offset_minutes as i128 * 60 * 1_000_000_000;
+    let into_day = local.rem_euclid(DAY_NS as i128) as u64;
+    let hour = (into_day / HOUR_NS) as u8;
+    let (start, end) = (quiet.start_hour, quiet.end_hour);
+    let quiet_now = if start < end {
+        start <= hour && hour < end
+    } else {
+        hour >= start || hour < end
+    };
+    if !quiet_now {
+        return None;
+    }
+    let end_of_quiet = end as u64 * HOUR_NS;
+    let wait = if into_day < end_of_quiet {
+        end_of_quiet - into_day
+    } else {
+        DAY_NS - into_day + end_of_quiet
+    };
+    Some(now + wait)
+}
+
+// hand a patient notification to the outbox, it reaches the inbox straight away unless the
+// patient's quiet hours hold it back. urgent messages are never held
+pub(crate) fn queue_patient_notification(patient_id: u64, notification: Notification) {
+    let preferences = communication_preferences(patient_id);
+    let now = time();
+    let deliver_at = match (&preferences.quiet_hours, notification.priority) {
+        (Some(quiet), priority) if priority != Priority::High => {
+            quiet_until(quiet, now, utc_offset(&Recipient::Patient(patient_id))).unwrap_or(now)
+        }
+        _ => now,
+    };
+    let mut message = OutboxMessage {
+        notification,
+        channels: preferences.channels,
+        deliver_at,
+        delivered_at: None,
+    };
+    if deliver_at <= now {
+        deliver_notification(&message.notification);
+        message.delivered_at = Some(now);
+    }
+    OUTBOX.with(|s| s.borrow_mut().insert(message.notification.id, message));
+}
+
+// page a doctor or hospital: the inbox gets the message at once and the relay also forwards it
+// by push and sms
+pub(crate) fn page(recipient: Recipient, message: Text) -> Notification {
+    let notification = notify(recipient, Priority::High, message);
+    let now = time();
+    let message = OutboxMessage {
+        notification: notification.clone(),
+        channels: vec![Channel::Push, Channel::Sms],
+        deliver_at: now,
+        delivered_at: Some(now),
+    };
+    OUTBOX.with(|s| s.borrow_mut().insert(notification.id, message));
+    notification
+}
+
+// timer job: deliver the held messages whose quiet hours are over
+pub(crate) fn deliver_outbox() {
+    let now = time();
+    let due: Vec<OutboxMessage> = OUTBOX.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, message)| message)
+            .filter(|message| message.delivered_at.is_none() && message.deliver_at <= now)
+            .take(DELIVERY_BATCH)
+            .collect()
+    });
+    for mut message in due {
+        message.notification.created_at = now;
+        deliver_notification(&message.notification);
+        message.delivered_at = Some(now);
+        OUTBOX.with(|s| s.borrow_mut().insert(message.notification.id, message));
+    }
+}
+
+// timer job: remind patients of scheduled appointments at each of their lead times. a lead
+// that passed before the job saw the appointment is folded into the next reminder
+pub(crate) fn send_appointment_reminders() {
+    let now = time();
+    let upcoming = all_appointments().into_iter().filter(|appointment| {
+        appointment.status == AppointmentStatus::Scheduled && appointment.start > now
+    });
+    for appointment in upcoming {
+        // patients who keep missing appointments get extra reminders on top of their own
+        let mut leads = communication_preferences(appointment.patient_id).reminder_lead_hours;
+        leads.extend(escalated_reminder_leads(appointment.patient_id));
+        let due: Vec<u32> = leads
+            .into_iter()
+            .filter(|lead| appointment.start.saturating_sub(*lead as u64 * HOUR_NS) <= now)
+            .filter(|lead| {
+                !REMINDERS_SENT.with(|s| s.borrow().contains_key(&(appointment.id, *lead)))
+            })
+            .collect();
+        if due.is_empty() {
+            continue;
+        }
+        for lead in &due {
+            REMINDERS_SENT.with(|s| s.borrow_mut().insert((appointment.id, *lead), now));
+        }
+        let patient = Recipient::Patient(appointment.patient_id);
+        notify(
+            patient,
+            Priority::Normal,
+            text(
+                "appointment.reminder",
+                "Reminder: appointment on {start}",
+                vec![(
+                    "start",
+                    format_local_time(appointment.start, utc_offset(&patient)),
+                )],
+            ),
+        );
+    }
+}
+
+fn validate_preferences(payload: &CommunicationPreferencesPayload) -> Result<(), Error> {
+    let invalid = |msg: &str| {
+        Err(Error::InvalidPayload {
+            msg: msg.to_string(),
+        })
+    };
+    if payload.reminder_lead_hours.len() > MAX_REMINDER_LEADS {
+        return invalid("At most 5 reminder lead times can be set");
+    }
+    if payload
+        .reminder_lead_hours
+        .iter()
+        .any(|lead| *lead == 0 || *lead > 14 * 24)
+    {
+        return invalid("Reminder lead times must be between 1 hour and 14 days");
+    }
+    if let Some(quiet) = &payload.quiet_hours {
+        if quiet.start_hour > 23 || quiet.end_hour > 23 || quiet.start_hour == quiet.end_hour {
+            return invalid("Quiet hours must be two different hours between 0 and 23");
+        }
+    }
+    Ok(())
+}
+
+// patients choose how and when they are contacted
+#[ic_cdk::update]
+fn set_communication_preferences(
+    payload: CommunicationPreferencesPayload,
+) -> Result<CommunicationPreferences, Error> {
+    authenticate_patient(payload.patient_id, &payload.password)?;
+    validate_preferences(&payload)?;
+    let mut channels: Vec<Channel> = vec![];
+    for channel in payload.channels {
+        if !channels.contains(&channel) {
+            channels.push(channel);
+        }
+    }
+    let mut reminder_lead_hours = payload.reminder_lead_hours;
+    reminder_lead_hours.sort_unstable();
+    reminder_lead_hours.dedup();
+    let preferences = CommunicationPreferences {
+        patient_id: payload.patient_id,
+        channels,
+        reminder_lead_hours,
+        quiet_hours: payload.quiet_hours,
+        updated_at: time(),
+    };
+    PREFERENCE_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(preferences.patient_id, preferences.clone())
+    });
+    Ok(preferences)
+}
+
+#[ic_cdk::query]
+fn get_communication_preferences(
+    patient_id: u64,
+    password: String,
+) -> Result<CommunicationPreferences, Error> {
+    authenticate_patient(patient_id, &password)?;
+    Ok(communication_preferences(patient_id))
+}
+
+// outbox for the relay that forwards messages to email, sms and push, controllers only
+#[ic_cdk::query]
+fn get_outbox(query: OutboxQuery) -> Result<Page<OutboxMessage>, Error> {
+    authorize_controller()?;
+    Ok(OUTBOX.with(|s| {
+        page_after(&s.borrow(), query.after, query.limit, |message| {
+            (!query.undelivered_only || message.delivered_at.is_none())
+                && match query.channel {
+                    Some(channel) => message.channels.contains(&channel),
+                    None => true,
+                }
+        })
+    }))
+}
@@ -0,0 +1,168 @@
+use std::borrow::Cow;
+
+// marks an lz4-compressed value, the stored encodings start with "DIDL" or a 0xCB format tag
+// so they never collide
+const COMPRESSED_TAG: &[u8; 4] = b"LZ4C";
+// encoded values up to this size are stored as they are, compressing them gains little
+const COMPRESS_ABOVE: usize = 256;
+
+// compress an encoded value for stable memory when it is large and compression pays off
+pub(crate) fn compress_value(encoded: Vec<u8>) -> Vec<u8> {
+    if encoded.len() <= COMPRESS_ABOVE {
+        return encoded;
+    }
+    let mut compressed = COMPRESSED_TAG.to_vec();
+    compressed.extend(lz4_flex::compress_prepend_size(&encoded));
+    if compressed.len() < encoded.len() {
+        compressed
+    } else {
+        encoded
+    }
+}
+
+// undo compress_value, values written before compression pass through unchanged
+pub(crate) fn decompress_value(bytes: Cow<[u8]>) -> Cow<[u8]> {
+    if !bytes.starts_with(COMPRESSED_TAG) {
+        return bytes;
+    }
+    Cow::Owned(
+        lz4_flex::decompress_size_prepended(&bytes[COMPRESSED_TAG.len()..])
+            .expect("Cannot decompress stored value"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MedicalRecord, Patient, RecordKind};
+    use candid::Encode;
+    use ic_stable_structures::{BoundedStorable, Storable};
+
+    // history entries the way doctors write them, reused across the corpus
+    const NOTES: [&str; 6] = [
+        "Patient presents with intermittent chest pain radiating to the left arm, worse on \
+         exertion. ECG shows sinus rhythm, no ST changes. Troponin negative at 0 and 3 hours. \
+         Started on aspirin 75mg daily, referred to cardiology for stress testing.",
+        "Follow-up for type 2 diabetes. HbA1c 7.9%, up from 7.2% three months ago. Reports \
+         missing evening doses of metformin. Discussed diet and adherence, increased metformin \
+         to 1000mg twice daily. Recheck HbA1c in three months.",
+        "Blood pressure 152/94 on two readings. No headache, no visual changes. Continue \
+         amlodipine 5mg daily, add lisinopril 10mg daily. Home blood pressure monitoring advised, \
+         review in four weeks with readings.",
+        "Post-operative review after laparoscopic cholecystectomy. Wounds clean and dry, no signs \
+         of infection. Tolerating normal diet, bowels open. Pain controlled with paracetamol. \
+         Discharged from surgical follow-up.",
+        "Child brought in with fever of 39.1C for two days and ear pain. Right tympanic membrane \
+         red and bulging. Diagnosed acute otitis media, started amoxicillin for five days. \
+         Parents advised to return if no improvement in 48 hours.",
+        "Annual review. No new complaints. Weight stable, BMI 27. Lipids: total cholesterol \
+         5.8 mmol/L, LDL 3.9 mmol/L. Discussed statin therapy, patient prefers lifestyle changes \
+         first. Repeat lipid panel in six months.",
+    ];
+
+    fn corpus() -> Vec<String> {
+        (1..=24)
+            .map(|entries| {
+                (0..entries)
+                    .map(|i| {
+                        format!(
+                            "Doctor {} : Dr. Amina Okafor at 2024-0{}-1{} 09:30 UTC\n{}\n",
+                            i % 3 + 1,
+                            i % 9 + 1,
+                            i % 10,
+                            NOTES[i % NOTES.len()]
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn record(body: String) -> MedicalRecord {
+        MedicalRecord {
+            id: 7,
+            patient_id: 3,
+            doctor_id: Some(2),
+            hospital_id: Some(1),
+            kind: RecordKind::History,
+            title: "History entry by Dr. Amina Okafor".to_string(),
+            body,
+            created_at: 1_700_000_000_000_000_000,
+            migrated: false,
+            restored_at: None,
+            addendum_to: None,
+        }
+    }
+
+    #[test]
+    fn records_round_trip_and_shrink() {
+        let mut encoded = 0;
+        let mut stored = 0;
+        for body in corpus() {
+            let original = record(body.clone());
+            let bytes = original.to_bytes();
+            assert!(bytes.len() <= MedicalRecord::MAX_SIZE as usize);
+            let decoded = MedicalRecord::from_bytes(bytes.clone());
+            assert_eq!(decoded.body, body);
+            assert_eq!(decoded.title, original.title);
+            assert_eq!(decoded.created_at, original.created_at);
+            encoded += Encode!(&original).unwrap().len();
+            stored += bytes.len();
+        }
+        // clinical free text repeats a lot, the corpus stores in well under two thirds the space
+        assert!(stored * 3 < encoded * 2, "{} of {} bytes", stored, encoded);
+    }
+
+    #[test]
+    fn patient_history_round_trips() {
+        let history = NOTES.concat();
+        let patient = Patient {
+            id: 3,
+            name: "Jonas Berg".to_string(),
+            history: history.clone(),
+            password: "secret".to_string(),
+            doctors_ids: vec![2],
+            hospitals_ids: vec![1],
+            ..Default::default()
+        };
+        let bytes = patient.to_bytes();
+        assert!(bytes.starts_with(COMPRESSED_TAG));
+        assert!(bytes.len() < Encode!(&patient).unwrap().len());
+        let decoded = Patient::from_bytes(bytes);
+        assert_eq!(decoded.history, history);
+        assert_eq!(decoded.name, patient.name);
+        assert_eq!(decoded.doctors_ids, patient.doctors_ids);
+    }
+
+    #[test]
+    fn small_values_are_stored_uncompressed() {
+        let original = record("Seen, no concerns.".to_string());
+        let bytes = original.to_bytes();
+        assert!(!bytes.starts_with(COMPRESSED_TAG));
+        assert_eq!(MedicalRecord::from_bytes(bytes).body, original.body);
+    }
+
+    #[test]
+    fn values_written_before_compression_still_decode() {
+        let body = NOTES.concat();
+        let legacy = Encode!(&record(body.clone())).unwrap();
+        let decoded = MedicalRecord::from_bytes(Cow::Owned(legacy));
+        assert_eq!(decoded.body, body);
+    }
+
+    #[test]
+    fn incompressible_values_are_kept_as_they_are() {
+        // a pseudo-random byte string lz4 cannot shrink
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        let noise: Vec<u8> = (0..2048)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect();
+        assert_eq!(compress_value(noise.clone()), noise);
+        assert_eq!(decompress_value(Cow::Borrowed(&noise)), noise);
+    }
+}
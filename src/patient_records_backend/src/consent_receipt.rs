@@ -0,0 +1,196 @@
+use crate::time;
+use crate::{
+    authenticate_patient, impl_storable, next_id, sign_hash, signing_settings, Error, Memory,
+    PatientConsent, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+
+// receipts signed per timer run, each signature is a call to the management canister
+const SIGNING_BATCH: usize = 10;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ConsentAction {
+    Granted,
+    Revoked,
+}
+
+// Evidence of one consent transaction: who allowed whom to do what, from when and until when.
+// The canister signs the receipt json shortly after it is issued
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ConsentReceipt {
+    pub id: u64,
+    pub patient_id: u64,
+    pub action: ConsentAction,
+    // what kind of consent, e.g. "data_sharing" or "caregiver_access"
+    pub kind: String,
+    // id of the agreement, grant or token the consent is about
+    pub reference_id: u64,
+    // who received or lost access, e.g. "hospital 12"
+    pub grantee: String,
+    pub scope: Vec<String>,
+    pub effective_at: u64,
+    pub expires_at: Option<u64>,
+    // secp256k1 signature (r || s) over the SHA-256 of the receipt json, empty until signed
+    pub signature: Vec<u8>,
+}
+
+// Exactly what gets hashed and signed, verifiers rebuild the hash from this json
+#[derive(Serialize)]
+struct ReceiptBody<'a> {
+    id: u64,
+    patient_id: u64,
+    action: ConsentAction,
+    kind: &'a str,
+    reference_id: u64,
+    grantee: &'a str,
+    scope: &'a [String],
+    effective_at: u64,
+    expires_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ConsentReceiptView {
+    pub receipt: ConsentReceipt,
+    // the exact json that was signed
+    pub signed_json: String,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl_storable!(ConsentReceipt, 2048);
+
+thread_local! {
+    static RECEIPT_STORAGE: RefCell<StableBTreeMap<u64, ConsentReceipt, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(120)))
+    ));
+
+    // set while a signing run is in flight so timer ticks do not overlap
+    static SIGNING: Cell<bool> = const { Cell::new(false) };
+}
+
+// names of scope values as they appear in candid, e.g. "Demographics"
+pub(crate) fn scope_labels<T: Serialize>(scopes: &[T]) -> Vec<String> {
+    scopes
+        .iter()
+        .map(|scope| {
+            serde_json::to_string(scope)
+                .unwrap_or_default()
+                .trim_matches('"')
+                .to_string()
+        })
+        .collect()
+}
+
+fn receipt_json(receipt: &ConsentReceipt) -> String {
+    serde_json::to_string(&ReceiptBody {
+        id: receipt.id,
+        patient_id: receipt.patient_id,
+        action: receipt.action,
+        kind: &receipt.kind,
+        reference_id: receipt.reference_id,
+        grantee: &receipt.grantee,
+        scope: &receipt.scope,
+        effective_at: receipt.effective_at,
+        expires_at: receipt.expires_at,
+    })
+    .expect("Cannot serialize consent receipt")
+}
+
+fn receipt_view(receipt: ConsentReceipt) -> ConsentReceiptView {
+    ConsentReceiptView {
+        signed_json: receipt_json(&receipt),
+        receipt,
+        public_key: signing_settings().public_key,
+    }
+}
+
+// record a consent transaction, called wherever a patient grants or revokes access
+pub(crate) fn issue_consent_receipt(
+    patient_id: u64,
+    action: ConsentAction,
+    kind: &str,
+    reference_id: u64,
+    grantee: String,
+    scope: Vec<String>,
+    expires_at: Option<u64>,
+) -> ConsentReceipt {
+    let receipt = ConsentReceipt {
+        id: next_id(),
+        patient_id,
+        action,
+        kind: kind.to_string(),
+        reference_id,
+        grantee,
+        scope,
+        effective_at: time(),
+        expires_at,
+        signature: vec![],
+    };
+    RECEIPT_STORAGE.with(|s| s.borrow_mut().insert(receipt.id, receipt.clone()));
+    receipt
+}
+
+// timer task: sign the receipts issued since the last run. a receipt whose signing fails
+// stays unsigned and is tried again next time
+pub(crate) async fn sign_consent_receipts() {
+    if SIGNING.with(|signing| signing.replace(true)) {
+        return;
+    }
+    let pending: Vec<ConsentReceipt> = RECEIPT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, receipt)| receipt)
+            .filter(|receipt| receipt.signature.is_empty())
+            .take(SIGNING_BATCH)
+            .collect()
+    });
+    for receipt in pending {
+        let hash = Sha256::digest(receipt_json(&receipt).as_bytes()).to_vec();
+        match sign_hash(hash).await {
+            Ok(signature) => RECEIPT_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    receipt.id,
+                    ConsentReceipt {
+                        signature,
+                        ..receipt
+                    },
+                )
+            }),
+            Err(_) => break,
+        };
+    }
+    SIGNING.with(|signing| signing.set(false));
+}
+
+// every consent receipt of the patient with what is needed to verify it offline
+#[ic_cdk::query]
+fn get_consent_receipts(consent: PatientConsent) -> Result<Vec<ConsentReceiptView>, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    Ok(RECEIPT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, receipt)| receipt)
+            .filter(|receipt| receipt.patient_id == patient.id)
+            .map(receipt_view)
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_consent_receipt(
+    consent: PatientConsent,
+    receipt_id: u64,
+) -> Result<ConsentReceiptView, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    RECEIPT_STORAGE
+        .with(|s| s.borrow().get(&receipt_id))
+        .filter(|receipt| receipt.patient_id == patient.id)
+        .map(receipt_view)
+        .ok_or(Error::NotFound {
+            msg: format!("Consent receipt of id: {} not found", receipt_id),
+        })
+}
@@ -0,0 +1,409 @@
+use crate::time;
+use crate::{
+    audit, authorize_controller, authorize_hospital, authorize_oversight, impl_storable, Actor,
+    Doctor, Encounter, Error, Memory, OversightRole, Prescription, DOCTOR_STORAGE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// A drug under prescribing controls; schedule 1 has no accepted medical use and cannot be
+// prescribed, schedules 2 to 5 are progressively less restricted
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ControlledSubstance {
+    // lowercase, matched against prescription medication names
+    pub name: String,
+    pub schedule: u8,
+    // most doses one prescription may cover, refills included
+    pub max_quantity: u32,
+    pub max_refills: u32,
+}
+
+// A doctor's prescribing license as verified by their hospital
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PrescriberLicense {
+    pub doctor_id: u64,
+    pub license_number: String,
+    // the controlled schedules the doctor may prescribe
+    pub schedules: Vec<u8>,
+    pub verified_by_hospital: u64,
+    pub verified_at: u64,
+    pub expires_at: u64,
+}
+
+// One controlled prescription, written when it is issued and never changed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ControlledRegisterEntry {
+    pub seq: u64,
+    pub entry_id: u64,
+    pub encounter_id: u64,
+    pub patient_id: u64,
+    pub doctor_id: u64,
+    pub hospital_id: u64,
+    pub license_number: String,
+    pub substance: String,
+    pub schedule: u8,
+    pub medication: String,
+    pub quantity: u64,
+    pub refills: u32,
+    pub issued_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct LicensePayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub doctor_id: u64,
+    pub license_number: String,
+    pub schedules: Vec<u8>,
+    pub expires_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ControlledRegisterQuery {
+    pub role: OversightRole,
+    pub password: String,
+    // issue time bounds, start inclusive and end exclusive
+    pub from: u64,
+    pub to: u64,
+}
+
+// the number is kept in the license and copied into every register entry
+const MAX_LICENSE_NUMBER_BYTES: usize = 64;
+
+impl_storable!(ControlledSubstance, 256);
+impl_storable!(PrescriberLicense, 256);
+impl_storable!(ControlledRegisterEntry, 1024);
+
+thread_local! {
+    static SUBSTANCE_STORAGE: RefCell<StableBTreeMap<u64, ControlledSubstance, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(108)))
+    ));
+
+    static LICENSE_STORAGE: RefCell<StableBTreeMap<u64, PrescriberLicense, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(109)))
+    ));
+
+    static CONTROLLED_REGISTER: RefCell<StableBTreeMap<u64, ControlledRegisterEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(110)))
+    ));
+}
+
+fn substance_key(name: &str) -> u64 {
+    let digest = Sha256::digest(name.trim().to_lowercase().as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+}
+
+// the controlled substance a medication name refers to, the strictest one if several match
+fn controlled_substance(medication: &str) -> Option<ControlledSubstance> {
+    let medication = medication.to_lowercase();
+    SUBSTANCE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, substance)| substance)
+            .filter(|substance| medication.contains(&substance.name))
+            .min_by_key(|substance| substance.schedule)
+    })
+}
+
+fn prescribed_quantity(prescription: &Prescription) -> u64 {
+    prescription.doses_per_day as u64
+        * prescription.duration_days as u64
+        * (1 + prescription.refills as u64)
+}
+
+// refuse a controlled prescription the doctor is not licensed for or that exceeds the caps;
+// returns the substance so the issue can be registered once the entry is stored
+pub(crate) fn check_controlled_prescription(
+    doctor: &Doctor,
+    prescription: &Prescription,
+) -> Result<Option<(ControlledSubstance, PrescriberLicense)>, Error> {
+    let Some(substance) = controlled_substance(&prescription.medication) else {
+        return Ok(None);
+    };
+    if substance.schedule <= 1 {
+        return Err(Error::InvalidPayload {
+            msg: format!("{} is schedule 1 and cannot be prescribed", substance.name),
+        });
+    }
+    let license = LICENSE_STORAGE
+        .with(|s| s.borrow().get(&doctor.id))
+        .filter(|license| license.expires_at > time())
+        .filter(|license| license.schedules.contains(&substance.schedule))
+        .ok_or(Error::Unauthorized {
+            msg: format!(
+                "Doctor {} has no current license for schedule {} substances",
+                doctor.id, substance.schedule
+            ),
+        })?;
+    let quantity = prescribed_quantity(prescription);
+    if quantity > substance.max_quantity as u64 || prescription.refills > substance.max_refills {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "{} is capped at {} doses and {} refills per prescription",
+                substance.name, substance.max_quantity, substance.max_refills
+            ),
+        });
+    }
+    Ok(Some((substance, license)))
+}
+
+pub(crate) fn register_controlled_prescription(
+    substance: &ControlledSubstance,
+    license: &PrescriberLicense,
+    encounter: &Encounter,
+    entry_id: u64,
+    prescription: &Prescription,
+) {
+    CONTROLLED_REGISTER.with(|register| {
+        let mut register = register.borrow_mut();
+        let seq = register.last_key_value().map_or(0, |(seq, _)| seq + 1);
+        register.insert(
+            seq,
+            ControlledRegisterEntry {
+                seq,
+                entry_id,
+                encounter_id: encounter.id,
+                patient_id: encounter.patient_id,
+                doctor_id: license.doctor_id,
+                hospital_id: encounter.hospital_id,
+                license_number: license.license_number.clone(),
+                substance: substance.name.clone(),
+                schedule: substance.schedule,
+                medication: prescription.medication.clone(),
+                quantity: prescribed_quantity(prescription),
+                refills: prescription.refills,
+                issued_at: time(),
+            },
+        );
+    });
+    audit(
+        Actor::Doctor(license.doctor_id),
+        Some(encounter.hospital_id),
+        Some(encounter.patient_id),
+        "controlled_prescription_issued",
+        format!("entry {} {}", entry_id, substance.name),
+    );
+}
+
+// add or change a controlled substance
+#[ic_cdk::update]
+fn set_controlled_substance(substance: ControlledSubstance) -> Result<ControlledSubstance, Error> {
+    authorize_controller()?;
+    let name = substance.name.trim().to_lowercase();
+    if name.len() < 3 || !(1..=5).contains(&substance.schedule) {
+        return Err(Error::InvalidPayload {
+            msg: "A controlled substance needs a name and a schedule from 1 to 5".to_string(),
+        });
+    }
+    let substance = ControlledSubstance { name, ..substance };
+    SUBSTANCE_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(substance_key(&substance.name), substance.clone())
+    });
+    Ok(substance)
+}
+
+#[ic_cdk::update]
+fn remove_controlled_substance(name: String) -> Result<(), Error> {
+    authorize_controller()?;
+    SUBSTANCE_STORAGE
+        .with(|s| s.borrow_mut().remove(&substance_key(&name)))
+        .map(|_| ())
+        .ok_or(Error::NotFound {
+            msg: format!("Controlled substance {} not found", name),
+        })
+}
+
+#[ic_cdk::query]
+fn get_controlled_substances() -> Vec<ControlledSubstance> {
+    SUBSTANCE_STORAGE.with(|s| s.borrow().iter().map(|(_, substance)| substance).collect())
+}
+
+// the hospital records the license it verified for one of its doctors
+#[ic_cdk::update]
+fn verify_prescriber_license(payload: LicensePayload) -> Result<PrescriberLicense, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let doctor = DOCTOR_STORAGE
+        .with(|s| s.borrow().get(&payload.doctor_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Doctor of id: {} not found", payload.doctor_id),
+        })?;
+    if doctor.hospital_id != hospital.id {
+        return Err(Error::Unauthorized {
+            msg: format!("Doctor of id: {} works at another hospital", doctor.id),
+        });
+    }
+    if payload.license_number.trim().len() > MAX_LICENSE_NUMBER_BYTES || payload.schedules.len() > 4
+    {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "A license number holds at most {} bytes and a license at most 4 schedules",
+                MAX_LICENSE_NUMBER_BYTES
+            ),
+        });
+    }
+    if payload.license_number.trim().is_empty()
+        || payload.expires_at <= time()
+        || payload
+            .schedules
+            .iter()
+            .any(|schedule| !(2..=5).contains(schedule))
+    {
+        return Err(Error::InvalidPayload {
+            msg: "A license needs a number, a future expiry and schedules from 2 to 5".to_string(),
+        });
+    }
+    let license = PrescriberLicense {
+        doctor_id: doctor.id,
+        license_number: payload.license_number.trim().to_string(),
+        schedules: payload.schedules,
+        verified_by_hospital: hospital.id,
+        verified_at: time(),
+        expires_at: payload.expires_at,
+    };
+    LICENSE_STORAGE.with(|s| s.borrow_mut().insert(license.doctor_id, license.clone()));
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        None,
+        "prescriber_license_verified",
+        format!("doctor {} license {}", doctor.id, license.license_number),
+    );
+    Ok(license)
+}
+
+#[ic_cdk::query]
+fn get_prescriber_license(doctor_id: u64) -> Option<PrescriberLicense> {
+    LICENSE_STORAGE.with(|s| s.borrow().get(&doctor_id))
+}
+
+// the hospital's controlled-substance register for a period, for its admin and auditors
+#[ic_cdk::query]
+fn get_controlled_substance_register(
+    query: ControlledRegisterQuery,
+) -> Result<Vec<ControlledRegisterEntry>, Error> {
+    let hospital_id = authorize_oversight(&query.role, &query.password)?;
+    Ok(CONTROLLED_REGISTER.with(|register| {
+        register
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| {
+                entry.hospital_id == hospital_id
+                    && entry.issued_at >= query.from
+                    && entry.issued_at < query.to
+            })
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{advance_clock, clinic, must, refused, Clinic, PASSWORD};
+
+    const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+    // set_controlled_substance is for controllers, which tests are not
+    fn control(name: &str, schedule: u8) {
+        let substance = ControlledSubstance {
+            name: name.to_string(),
+            schedule,
+            max_quantity: 30,
+            max_refills: 1,
+        };
+        SUBSTANCE_STORAGE.with(|s| s.borrow_mut().insert(substance_key(name), substance));
+    }
+
+    fn license(clinic: &Clinic, schedules: Vec<u8>) -> Result<PrescriberLicense, Error> {
+        verify_prescriber_license(LicensePayload {
+            hospital_id: clinic.hospital_id,
+            hospital_password: PASSWORD.to_string(),
+            doctor_id: clinic.doctor_id,
+            license_number: "MD-1042".to_string(),
+            schedules,
+            expires_at: time() + DAY_NS,
+        })
+    }
+
+    fn prescribe(clinic: &Clinic, medication: &str, days: u32, refills: u32) -> Result<(), Error> {
+        let doctor = DOCTOR_STORAGE
+            .with(|s| s.borrow().get(&clinic.doctor_id))
+            .unwrap();
+        let prescription = Prescription {
+            medication: medication.to_string(),
+            dosage: "10 mg".to_string(),
+            doses_per_day: 2,
+            duration_days: days,
+            refills,
+        };
+        check_controlled_prescription(&doctor, &prescription).map(|_| ())
+    }
+
+    #[test]
+    fn controlled_prescriptions_need_a_current_license_for_the_schedule() {
+        let clinic = clinic();
+        control("oxycodone", 2);
+        must(prescribe(&clinic, "Amoxicillin 500mg", 5, 0));
+        assert!(refused(prescribe(&clinic, "Oxycodone 10mg", 5, 0)));
+        must(license(&clinic, vec![3, 4]));
+        assert!(refused(prescribe(&clinic, "Oxycodone 10mg", 5, 0)));
+        must(license(&clinic, vec![2]));
+        must(prescribe(&clinic, "Oxycodone 10mg", 5, 0));
+        advance_clock(DAY_NS);
+        assert!(refused(prescribe(&clinic, "Oxycodone 10mg", 5, 0)));
+    }
+
+    #[test]
+    fn schedule_one_and_prescriptions_over_the_caps_are_refused() {
+        let clinic = clinic();
+        control("heroin", 1);
+        control("morphine", 2);
+        must(license(&clinic, vec![2, 3, 4, 5]));
+        assert!(prescribe(&clinic, "Heroin", 1, 0).is_err());
+        must(prescribe(&clinic, "Morphine", 15, 0));
+        assert!(matches!(
+            prescribe(&clinic, "Morphine", 16, 0),
+            Err(Error::LimitExceeded { .. })
+        ));
+        assert!(matches!(
+            prescribe(&clinic, "Morphine", 5, 2),
+            Err(Error::LimitExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn only_controllers_list_substances_and_only_the_hospital_verifies_licenses() {
+        let other = clinic();
+        let clinic = clinic();
+        assert!(refused(set_controlled_substance(ControlledSubstance {
+            name: "fentanyl".to_string(),
+            schedule: 2,
+            max_quantity: 10,
+            max_refills: 0,
+        })));
+        assert!(refused(verify_prescriber_license(LicensePayload {
+            hospital_id: other.hospital_id,
+            hospital_password: PASSWORD.to_string(),
+            doctor_id: clinic.doctor_id,
+            license_number: "MD-1042".to_string(),
+            schedules: vec![2],
+            expires_at: time() + DAY_NS,
+        })));
+        assert!(license(&clinic, vec![1]).is_err());
+        assert!(verify_prescriber_license(LicensePayload {
+            hospital_id: clinic.hospital_id,
+            hospital_password: PASSWORD.to_string(),
+            doctor_id: clinic.doctor_id,
+            license_number: "9".repeat(MAX_LICENSE_NUMBER_BYTES + 1),
+            schedules: vec![2],
+            expires_at: time() + DAY_NS,
+        })
+        .is_err());
+    }
+}
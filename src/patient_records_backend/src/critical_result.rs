@@ -0,0 +1,265 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_hospital, impl_storable, next_id, notify, patient_header,
+    text, Actor, Encounter, EncounterEntry, EncounterEntryKind, Error, HospitalAccessPayload,
+    Memory, Priority, Recipient, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// how long the ordering doctor has to acknowledge, and the gap between later escalations
+const ACKNOWLEDGE_WITHIN_NS: u64 = 60 * 60 * 1_000_000_000;
+
+// A lab result that breached an alert rule and must be acknowledged by the ordering doctor
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CriticalResult {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub patient_id: u64,
+    pub encounter_id: u64,
+    pub entry_id: u64,
+    pub ordering_doctor_id: u64,
+    pub test: String,
+    pub value: f64,
+    pub unit: String,
+    pub filed_at: u64,
+    pub due_at: u64,
+    pub acknowledged_at: Option<u64>,
+    pub escalations: u32,
+    pub next_escalation_at: u64,
+}
+
+// Acknowledgement compliance of a hospital over the results filed in a period
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct CriticalResultReport {
+    pub hospital_id: u64,
+    pub from: u64,
+    pub to: u64,
+    pub filed: u64,
+    pub acknowledged_on_time: u64,
+    pub acknowledged_late: u64,
+    pub unacknowledged: u64,
+    pub escalated: u64,
+    pub average_minutes_to_acknowledge: Option<u64>,
+}
+
+impl_storable!(CriticalResult, 512);
+
+thread_local! {
+    static CRITICAL_RESULT_STORAGE: RefCell<StableBTreeMap<u64, CriticalResult, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct CriticalResultReportPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub from: u64,
+    pub to: u64,
+}
+
+fn save_critical_result(result: &CriticalResult) {
+    CRITICAL_RESULT_STORAGE.with(|s| s.borrow_mut().insert(result.id, result.clone()));
+}
+
+fn critical_results(filter: impl Fn(&CriticalResult) -> bool) -> Vec<CriticalResult> {
+    CRITICAL_RESULT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, result)| result)
+            .filter(|result| filter(result))
+            .collect()
+    })
+}
+
+// open an acknowledgement obligation for a lab result that breached an alert rule. The doctor
+// who opened the encounter ordered its labs
+pub(crate) fn require_acknowledgement(encounter: &Encounter, entry: &EncounterEntry) {
+    let EncounterEntryKind::LabResult { test, value, unit } = &entry.kind else {
+        return;
+    };
+    let now = time();
+    let result = CriticalResult {
+        id: next_id(),
+        hospital_id: encounter.hospital_id,
+        patient_id: encounter.patient_id,
+        encounter_id: encounter.id,
+        entry_id: entry.id,
+        ordering_doctor_id: encounter.doctor_id,
+        test: test.clone(),
+        value: *value,
+        unit: unit.clone(),
+        filed_at: now,
+        due_at: now + ACKNOWLEDGE_WITHIN_NS,
+        acknowledged_at: None,
+        escalations: 0,
+        next_escalation_at: now + ACKNOWLEDGE_WITHIN_NS,
+    };
+    save_critical_result(&result);
+    notify(
+        Recipient::Doctor(result.ordering_doctor_id),
+        Priority::High,
+        text(
+            "critical_result.filed",
+            "Critical {test} result of {value} {unit} for patient {patient} needs your acknowledgement",
+            vec![
+                ("test", result.test.clone()),
+                ("value", result.value.to_string()),
+                ("unit", result.unit.clone()),
+                ("patient", result.patient_id.to_string()),
+            ],
+        ),
+    );
+}
+
+// the ordering doctor confirms they have seen the result
+#[ic_cdk::update]
+fn acknowledge_critical_result(
+    doctor_id: u64,
+    doctor_password: String,
+    result_id: u64,
+) -> Result<CriticalResult, Error> {
+    let doctor = authorize_doctor(doctor_id, &doctor_password)?;
+    let result = CRITICAL_RESULT_STORAGE
+        .with(|s| s.borrow().get(&result_id))
+        .filter(|result| result.ordering_doctor_id == doctor.id)
+        .ok_or(Error::NotFound {
+            msg: format!("Critical result of id: {} not found", result_id),
+        })?;
+    if result.acknowledged_at.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Critical result of id: {} is already acknowledged",
+                result.id
+            ),
+        });
+    }
+    let acknowledged = CriticalResult {
+        acknowledged_at: Some(time()),
+        ..result
+    };
+    save_critical_result(&acknowledged);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(acknowledged.hospital_id),
+        Some(acknowledged.patient_id),
+        "critical_result_acknowledged",
+        format!("critical result {}", acknowledged.id),
+    );
+    Ok(acknowledged)
+}
+
+// results still waiting for the doctor's acknowledgement
+#[ic_cdk::query]
+fn get_my_critical_results(
+    doctor_id: u64,
+    doctor_password: String,
+) -> Result<Vec<CriticalResult>, Error> {
+    let doctor = authorize_doctor(doctor_id, &doctor_password)?;
+    Ok(critical_results(|result| {
+        result.ordering_doctor_id == doctor.id && result.acknowledged_at.is_none()
+    }))
+}
+
+// unacknowledged critical results of a hospital, oldest first
+#[ic_cdk::query]
+fn get_unacknowledged_critical_results(
+    payload: HospitalAccessPayload,
+) -> Result<Vec<CriticalResult>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    Ok(critical_results(|result| {
+        result.hospital_id == hospital.id && result.acknowledged_at.is_none()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_critical_result_report(
+    payload: CriticalResultReportPayload,
+) -> Result<CriticalResultReport, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let results = critical_results(|result| {
+        result.hospital_id == hospital.id
+            && result.filed_at >= payload.from
+            && result.filed_at < payload.to
+    });
+    let mut report = CriticalResultReport {
+        hospital_id: hospital.id,
+        from: payload.from,
+        to: payload.to,
+        filed: results.len() as u64,
+        ..Default::default()
+    };
+    let mut minutes_to_acknowledge = vec![];
+    for result in &results {
+        match result.acknowledged_at {
+            Some(at) if at <= result.due_at => report.acknowledged_on_time += 1,
+            Some(_) => report.acknowledged_late += 1,
+            None => report.unacknowledged += 1,
+        }
+        if let Some(at) = result.acknowledged_at {
+            minutes_to_acknowledge.push((at - result.filed_at) / 60_000_000_000);
+        }
+        if result.escalations > 0 {
+            report.escalated += 1;
+        }
+    }
+    if !minutes_to_acknowledge.is_empty() {
+        report.average_minutes_to_acknowledge =
+            Some(minutes_to_acknowledge.iter().sum::<u64>() / minutes_to_acknowledge.len() as u64);
+    }
+    Ok(report)
+}
+
+// timer task: escalate overdue results to the hospital and the patient's care team, then
+// remind the hospital again after every further deadline period
+pub(crate) fn escalate_critical_results() {
+    let now = time();
+    let overdue = critical_results(|result| {
+        result.acknowledged_at.is_none() && result.next_escalation_at <= now
+    });
+    for result in overdue {
+        let escalation_text = || {
+            text(
+                "critical_result.overdue",
+                "Critical {test} result for patient {patient} is still unacknowledged by doctor {doctor}",
+                vec![
+                    ("test", result.test.clone()),
+                    ("patient", result.patient_id.to_string()),
+                    ("doctor", result.ordering_doctor_id.to_string()),
+                ],
+            )
+        };
+        notify(
+            Recipient::Hospital(result.hospital_id),
+            Priority::High,
+            escalation_text(),
+        );
+        if result.escalations == 0 {
+            let care_team = patient_header(result.patient_id)
+                .map(|patient| patient.doctors_ids)
+                .unwrap_or_default();
+            for doctor_id in care_team {
+                notify(
+                    Recipient::Doctor(doctor_id),
+                    Priority::High,
+                    escalation_text(),
+                );
+            }
+        }
+        audit(
+            Actor::System,
+            Some(result.hospital_id),
+            Some(result.patient_id),
+            "critical_result_escalated",
+            format!("critical result {}", result.id),
+        );
+        save_critical_result(&CriticalResult {
+            escalations: result.escalations + 1,
+            next_escalation_at: now + ACKNOWLEDGE_WITHIN_NS,
+            ..result.clone()
+        });
+    }
+}
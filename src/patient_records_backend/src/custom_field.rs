@@ -0,0 +1,353 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_hospital, authorize_patient_access, get_assigned_patient,
+    get_encounter_by_id, impl_storable, next_id, require_premium, Actor, Error, Memory,
+    PatientAccess, PremiumFeature, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_FIELDS_PER_HOSPITAL: usize = 50;
+const MAX_CHOICES: usize = 20;
+const MAX_TEXT_LEN: usize = 500;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum FieldTarget {
+    Patient,
+    Encounter,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum FieldType {
+    Text,
+    Number,
+    Boolean,
+    // nanoseconds since the unix epoch
+    Date,
+    Choice(Vec<String>),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum FieldValue {
+    Text(String),
+    Number(f64),
+    Boolean(bool),
+    Date(u64),
+    Choice(String),
+}
+
+// A structured field a hospital added to its patients or encounters for a local need
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CustomField {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub target: FieldTarget,
+    // machine name, unique per hospital and target, e.g. "referral_source"
+    pub key: String,
+    pub label: String,
+    pub field_type: FieldType,
+    pub created_at: u64,
+    // retired fields keep their values but can no longer be filled
+    pub retired: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CustomFieldValue {
+    pub field_id: u64,
+    pub hospital_id: u64,
+    pub key: String,
+    pub label: String,
+    pub value: FieldValue,
+}
+
+impl_storable!(CustomField, 2048);
+impl_storable!(FieldValue, 1024);
+
+thread_local! {
+    static CUSTOM_FIELD_STORAGE: RefCell<StableBTreeMap<u64, CustomField, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74)))
+    ));
+
+    // the extension map, keyed by (patient or encounter id, field id)
+    static CUSTOM_FIELD_VALUES: RefCell<StableBTreeMap<(u64, u64), FieldValue, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct DefineCustomFieldPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub target: FieldTarget,
+    pub key: String,
+    pub label: String,
+    pub field_type: FieldType,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct RetireCustomFieldPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub field_id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FieldEntry {
+    pub key: String,
+    // None clears the field
+    pub value: Option<FieldValue>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SetCustomFieldsPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub target: FieldTarget,
+    // the patient or encounter id
+    pub entity_id: u64,
+    pub fields: Vec<FieldEntry>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CustomFieldExportPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub target: FieldTarget,
+}
+
+fn hospital_fields(hospital_id: u64) -> Vec<CustomField> {
+    CUSTOM_FIELD_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, field)| field)
+            .filter(|field| field.hospital_id == hospital_id)
+            .collect()
+    })
+}
+
+fn get_custom_field(field_id: u64) -> Option<CustomField> {
+    CUSTOM_FIELD_STORAGE.with(|s| s.borrow().get(&field_id))
+}
+
+// helper function to check a value has the field's type
+fn check_value(field: &CustomField, value: &FieldValue) -> Result<(), Error> {
+    let matches = match (&field.field_type, value) {
+        (FieldType::Text, FieldValue::Text(text)) => text.chars().count() <= MAX_TEXT_LEN,
+        (FieldType::Number, FieldValue::Number(number)) => number.is_finite(),
+        (FieldType::Boolean, FieldValue::Boolean(_)) | (FieldType::Date, FieldValue::Date(_)) => {
+            true
+        }
+        (FieldType::Choice(choices), FieldValue::Choice(choice)) => choices.contains(choice),
+        _ => false,
+    };
+    if !matches {
+        return Err(Error::InvalidPayload {
+            msg: format!("Value does not fit custom field \"{}\"", field.key),
+        });
+    }
+    Ok(())
+}
+
+// the custom field values of a patient or encounter, in field order
+pub(crate) fn custom_field_values(entity_id: u64) -> Vec<CustomFieldValue> {
+    CUSTOM_FIELD_VALUES.with(|s| {
+        s.borrow()
+            .range((entity_id, 0)..=(entity_id, u64::MAX))
+            .filter_map(|((_, field_id), value)| {
+                let field = get_custom_field(field_id)?;
+                Some(CustomFieldValue {
+                    field_id,
+                    hospital_id: field.hospital_id,
+                    key: field.key,
+                    label: field.label,
+                    value,
+                })
+            })
+            .collect()
+    })
+}
+
+fn value_text(value: &FieldValue) -> String {
+    match value {
+        FieldValue::Text(text) | FieldValue::Choice(text) => text.clone(),
+        FieldValue::Number(number) => number.to_string(),
+        FieldValue::Boolean(flag) => flag.to_string(),
+        FieldValue::Date(date) => date.to_string(),
+    }
+}
+
+// add a typed field to the hospital's patients or encounters
+#[ic_cdk::update]
+fn define_custom_field(payload: DefineCustomFieldPayload) -> Result<CustomField, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let key = payload.key.trim().to_lowercase();
+    if key.is_empty()
+        || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        || payload.label.trim().is_empty()
+    {
+        return Err(Error::InvalidPayload {
+            msg: "Custom field needs a label and a key of letters, digits or underscores"
+                .to_string(),
+        });
+    }
+    if let FieldType::Choice(choices) = &payload.field_type {
+        if choices.is_empty() || choices.len() > MAX_CHOICES {
+            return Err(Error::InvalidPayload {
+                msg: format!("Choice fields need 1 to {} choices", MAX_CHOICES),
+            });
+        }
+    }
+    let fields = hospital_fields(hospital.id);
+    if fields
+        .iter()
+        .any(|field| field.target == payload.target && field.key == key)
+    {
+        return Err(Error::AlreadyInit {
+            msg: format!("Custom field \"{}\" already exists", key),
+        });
+    }
+    if fields.len() >= MAX_FIELDS_PER_HOSPITAL {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "A hospital can define at most {} custom fields",
+                MAX_FIELDS_PER_HOSPITAL
+            ),
+        });
+    }
+
+    let field = CustomField {
+        id: next_id(),
+        hospital_id: hospital.id,
+        target: payload.target,
+        key,
+        label: payload.label,
+        field_type: payload.field_type,
+        created_at: time(),
+        retired: false,
+    };
+    CUSTOM_FIELD_STORAGE.with(|s| s.borrow_mut().insert(field.id, field.clone()));
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        None,
+        "custom_field_defined",
+        format!("field {}: {}", field.id, field.key),
+    );
+    Ok(field)
+}
+
+#[ic_cdk::update]
+fn retire_custom_field(payload: RetireCustomFieldPayload) -> Result<CustomField, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let field = get_custom_field(payload.field_id)
+        .filter(|field| field.hospital_id == hospital.id)
+        .ok_or(Error::NotFound {
+            msg: format!("Custom field of id: {} not found", payload.field_id),
+        })?;
+    let retired = CustomField {
+        retired: true,
+        ..field
+    };
+    CUSTOM_FIELD_STORAGE.with(|s| s.borrow_mut().insert(retired.id, retired.clone()));
+    Ok(retired)
+}
+
+// the schema of a hospital's custom fields, retired ones included
+#[ic_cdk::query]
+fn get_custom_fields(hospital_id: u64) -> Vec<CustomField> {
+    hospital_fields(hospital_id)
+}
+
+// fill or clear custom fields of the doctor's hospital on a patient or encounter
+#[ic_cdk::update]
+fn set_custom_fields(payload: SetCustomFieldsPayload) -> Result<Vec<CustomFieldValue>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient_id = match payload.target {
+        FieldTarget::Patient => payload.entity_id,
+        FieldTarget::Encounter => {
+            let encounter = get_encounter_by_id(payload.entity_id)?;
+            if encounter.hospital_id != doctor.hospital_id {
+                return Err(Error::Unauthorized {
+                    msg: format!(
+                        "Encounter of id: {} belongs to another hospital",
+                        encounter.id
+                    ),
+                });
+            }
+            encounter.patient_id
+        }
+    };
+    let patient = get_assigned_patient(&doctor, patient_id)?;
+
+    let fields: Vec<CustomField> = hospital_fields(doctor.hospital_id)
+        .into_iter()
+        .filter(|field| field.target == payload.target && !field.retired)
+        .collect();
+    let mut changes = vec![];
+    for entry in payload.fields {
+        let field = fields
+            .iter()
+            .find(|field| field.key == entry.key)
+            .ok_or(Error::NotFound {
+                msg: format!("Custom field \"{}\" not found", entry.key),
+            })?;
+        if let Some(value) = &entry.value {
+            check_value(field, value)?;
+        }
+        changes.push((field.id, entry.value));
+    }
+    CUSTOM_FIELD_VALUES.with(|s| {
+        let mut values = s.borrow_mut();
+        for (field_id, value) in &changes {
+            match value {
+                Some(value) => values.insert((payload.entity_id, *field_id), value.clone()),
+                None => values.remove(&(payload.entity_id, *field_id)),
+            };
+        }
+    });
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "custom_fields_set",
+        format!("{} fields on {}", changes.len(), payload.entity_id),
+    );
+    Ok(custom_field_values(payload.entity_id))
+}
+
+#[ic_cdk::query]
+fn get_patient_custom_fields(
+    patient_id: u64,
+    access: PatientAccess,
+) -> Result<Vec<CustomFieldValue>, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    Ok(custom_field_values(patient.id))
+}
+
+// every filled value of the hospital's fields on patients or encounters as csv
+#[ic_cdk::query]
+fn export_custom_fields(payload: CustomFieldExportPayload) -> Result<String, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    require_premium(hospital.id, PremiumFeature::DataExport)?;
+    let fields: Vec<CustomField> = hospital_fields(hospital.id)
+        .into_iter()
+        .filter(|field| field.target == payload.target)
+        .collect();
+    let mut csv = String::from("entity_id,key,value\n");
+    CUSTOM_FIELD_VALUES.with(|s| {
+        for ((entity_id, field_id), value) in s.borrow().iter() {
+            if let Some(field) = fields.iter().find(|field| field.id == field_id) {
+                csv.push_str(&format!(
+                    "{},{},\"{}\"\n",
+                    entity_id,
+                    field.key,
+                    value_text(&value).replace('"', "\"\"")
+                ));
+            }
+        }
+    });
+    Ok(csv)
+}
@@ -0,0 +1,351 @@
+use crate::time;
+use crate::{
+    audit, authorize_hospital, end_patient_series, impl_storable, insert_record,
+    leave_all_waitlists, next_id, patient_encounters, patient_history, patient_records,
+    release_appointment, upcoming_appointments, void_prescription_codes, Actor, BloodType,
+    Encounter, Error, HistoryEntry, HospitalAccessPayload, MedicalRecord, Memory, RecordKind,
+    MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_CAUSE_BYTES: usize = 500;
+const MAX_PLACE_BYTES: usize = 200;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DeathDetails {
+    pub date_of_death: u64,
+    pub place_of_death: String,
+    pub cause_of_death: String,
+    pub certifying_doctor_id: Option<u64>,
+}
+
+// A registered death. The patient's record is sealed from then on
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct DeathRegistration {
+    pub patient_id: u64,
+    pub hospital_id: u64,
+    pub details: DeathDetails,
+    pub certificate_record_id: u64,
+    pub registered_at: u64,
+    pub cancelled_appointments: u32,
+    pub voided_prescriptions: u32,
+}
+
+// The legal grounds for reading a sealed record
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub enum LegalBasis {
+    CourtOrder,
+    CoronerInquest,
+    EstateExecutor,
+    RegulatorInvestigation,
+}
+
+// A sealed record as released for a legal request
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SealedRecord {
+    pub patient_id: u64,
+    pub name: String,
+    // history entries with their amendments
+    pub history: Vec<HistoryEntry>,
+    pub blood_type: Option<BloodType>,
+    pub death: DeathRegistration,
+    pub records: Vec<MedicalRecord>,
+    pub encounters: Vec<Encounter>,
+}
+
+impl_storable!(DeathRegistration, 2048);
+
+thread_local! {
+    static DEATH_STORAGE: RefCell<StableBTreeMap<u64, DeathRegistration, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct LegalAccessPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub patient_id: u64,
+    pub basis: Option<LegalBasis>,
+    // court case number, inquest or investigation reference
+    pub reference: String,
+}
+
+pub(crate) fn death_registration(patient_id: u64) -> Option<DeathRegistration> {
+    DEATH_STORAGE.with(|s| s.borrow().get(&patient_id))
+}
+
+// helper function to refuse routine access to the record of a deceased patient
+pub(crate) fn check_not_sealed(patient_id: u64) -> Result<(), Error> {
+    if DEATH_STORAGE.with(|s| s.borrow().contains_key(&patient_id)) {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "Record of patient {} is sealed, only legally-scoped access is allowed",
+                patient_id
+            ),
+        });
+    }
+    Ok(())
+}
+
+// a hospital of the patient registers their death, which seals the record, cancels future
+// appointments and prescriptions and files a death certificate entry
+#[ic_cdk::update]
+pub(crate) fn register_death(
+    patient_id: u64,
+    hospital_auth: HospitalAccessPayload,
+    details: DeathDetails,
+) -> Result<DeathRegistration, Error> {
+    let hospital = authorize_hospital(hospital_auth.hospital_id, &hospital_auth.hospital_password)?;
+    let patient = PATIENT_STORAGE
+        .with(|s| s.borrow().get(&patient_id))
+        .filter(|patient| patient.hospitals_ids.contains(&hospital.id))
+        .ok_or(Error::NotFound {
+            msg: format!("Patient of id: {} not found", patient_id),
+        })?;
+    if death_registration(patient.id).is_some() {
+        return Err(Error::AlreadyInit {
+            msg: format!("Death of patient {} is already registered", patient.id),
+        });
+    }
+    let now = time();
+    if details.date_of_death > now || details.cause_of_death.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Death registrations need a cause and a date that is not in the future"
+                .to_string(),
+        });
+    }
+    if details.cause_of_death.len() > MAX_CAUSE_BYTES
+        || details.place_of_death.len() > MAX_PLACE_BYTES
+    {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "Causes of death hold at most {} bytes and places {} bytes",
+                MAX_CAUSE_BYTES, MAX_PLACE_BYTES
+            ),
+        });
+    }
+    if let Some(doctor_id) = details.certifying_doctor_id {
+        if !hospital.doctors_ids.contains(&doctor_id) {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "Certifying doctor {} is not a doctor of hospital {}",
+                    doctor_id, hospital.id
+                ),
+            });
+        }
+    }
+
+    // every check is done, from here on state changes. Releasing a booking that cannot be
+    // released traps so none of the changes before it are kept
+    let cancelled_appointments = match release_bookings(patient.id, now) {
+        Ok(cancelled) => cancelled,
+        Err(_) => {
+            ic_cdk::trap("Cannot release the patient's bookings, the death was not registered")
+        }
+    };
+    leave_all_waitlists(patient.id);
+    let voided_prescriptions = void_prescription_codes(patient.id);
+
+    let certificate = MedicalRecord {
+        id: next_id(),
+        patient_id: patient.id,
+        doctor_id: details.certifying_doctor_id,
+        hospital_id: Some(hospital.id),
+        kind: RecordKind::DeathCertificate,
+        title: "Death certificate".to_string(),
+        body: format!(
+            "Date of death: {}\nPlace of death: {}\nCause of death: {}",
+            details.date_of_death, details.place_of_death, details.cause_of_death
+        ),
+        created_at: now,
+        migrated: false,
+        restored_at: None,
+        addendum_to: None,
+    };
+    insert_record(&certificate);
+    let registration = DeathRegistration {
+        patient_id: patient.id,
+        hospital_id: hospital.id,
+        details,
+        certificate_record_id: certificate.id,
+        registered_at: now,
+        cancelled_appointments,
+        voided_prescriptions,
+    };
+    DEATH_STORAGE.with(|s| s.borrow_mut().insert(patient.id, registration.clone()));
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        Some(patient.id),
+        "death_registered",
+        format!("certificate record {}", certificate.id),
+    );
+    Ok(registration)
+}
+
+// end the patient's series and cancel their upcoming appointments, returns how many were
+fn release_bookings(patient_id: u64, now: u64) -> Result<u32, Error> {
+    end_patient_series(patient_id, now)?;
+    let mut cancelled = 0;
+    for appointment in upcoming_appointments(patient_id) {
+        release_appointment(appointment)?;
+        cancelled += 1;
+    }
+    Ok(cancelled)
+}
+
+// release a sealed record to one of the patient's hospitals for a documented legal request;
+// every release is audited with its basis and reference
+#[ic_cdk::update]
+fn get_sealed_record(payload: LegalAccessPayload) -> Result<SealedRecord, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let death = death_registration(payload.patient_id).ok_or(Error::NotFound {
+        msg: format!("No death registered for patient {}", payload.patient_id),
+    })?;
+    let (basis, reference) = match payload.basis {
+        Some(basis) if !payload.reference.trim().is_empty() => (basis, payload.reference),
+        _ => {
+            return Err(Error::InvalidPayload {
+                msg: "Sealed records are only released with a legal basis and reference"
+                    .to_string(),
+            })
+        }
+    };
+    let patient = PATIENT_STORAGE
+        .with(|s| s.borrow().get(&payload.patient_id))
+        .filter(|patient| patient.hospitals_ids.contains(&hospital.id))
+        .ok_or(Error::Unauthorized {
+            msg: format!(
+                "Hospital {} did not treat patient {}",
+                hospital.id, payload.patient_id
+            ),
+        })?;
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        Some(patient.id),
+        "sealed_record_released",
+        format!("{:?}: {}", basis, reference),
+    );
+    Ok(SealedRecord {
+        patient_id: patient.id,
+        records: patient_records(patient.id),
+        encounters: patient_encounters(patient.id),
+        name: patient.name,
+        history: patient_history(patient.id),
+        blood_type: patient.blood_type,
+        death,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{clinic, must, Clinic, PASSWORD};
+    use crate::{
+        add_medical_record, edit_medical_record, edit_patient, get_patient_info, AccessPayload,
+        EditPatientPayload, EditRecordPayload, MedicalRecordPayload,
+    };
+
+    fn register(clinic: &Clinic, certifying_doctor_id: u64) -> Result<DeathRegistration, Error> {
+        register_death(
+            clinic.patient_id,
+            HospitalAccessPayload {
+                hospital_id: clinic.hospital_id,
+                hospital_password: PASSWORD.to_string(),
+            },
+            DeathDetails {
+                date_of_death: time(),
+                place_of_death: "Ward 3".to_string(),
+                cause_of_death: "Cardiac arrest".to_string(),
+                certifying_doctor_id: Some(certifying_doctor_id),
+            },
+        )
+    }
+
+    // the reads and writes a doctor and the patient can make with correct passwords
+    fn access(clinic: &Clinic, record_id: u64) -> [Result<(), Error>; 3] {
+        [
+            get_patient_info(AccessPayload {
+                doctor_id: clinic.doctor_id,
+                patient_id: clinic.patient_id,
+                doctor_password: PASSWORD.to_string(),
+            })
+            .map(|_| ()),
+            edit_patient(EditPatientPayload {
+                name: "Renamed patient".to_string(),
+                password: PASSWORD.to_string(),
+                patient_id: clinic.patient_id,
+            })
+            .map(|_| ()),
+            edit_medical_record(EditRecordPayload {
+                doctor_id: clinic.doctor_id,
+                doctor_password: PASSWORD.to_string(),
+                record_id,
+                title: "Visit".to_string(),
+                body: "Corrected note".to_string(),
+            })
+            .map(|_| ()),
+        ]
+    }
+
+    fn add_note(clinic: &Clinic) -> u64 {
+        must(add_medical_record(MedicalRecordPayload {
+            doctor_id: clinic.doctor_id,
+            doctor_password: PASSWORD.to_string(),
+            patient_id: clinic.patient_id,
+            kind: RecordKind::Note,
+            title: "Visit".to_string(),
+            body: "Seen in clinic".to_string(),
+            sensitivity: None,
+        }))
+        .id
+    }
+
+    #[test]
+    fn a_registered_death_seals_the_patient() {
+        let clinic = clinic();
+        let record_id = add_note(&clinic);
+        assert!(access(&clinic, record_id).iter().all(Result::is_ok));
+        must(register(&clinic, clinic.doctor_id));
+        assert!(access(&clinic, record_id)
+            .iter()
+            .all(|result| matches!(result, Err(Error::Unauthorized { .. }))));
+    }
+
+    #[test]
+    fn a_certifier_from_another_hospital_seals_nothing() {
+        let clinic = clinic();
+        let record_id = add_note(&clinic);
+        assert!(matches!(
+            register(&clinic, clinic.doctor_id + 1000),
+            Err(Error::InvalidPayload { .. })
+        ));
+        assert!(death_registration(clinic.patient_id).is_none());
+        assert!(access(&clinic, record_id).iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn an_overlong_cause_seals_nothing() {
+        let clinic = clinic();
+        let result = register_death(
+            clinic.patient_id,
+            HospitalAccessPayload {
+                hospital_id: clinic.hospital_id,
+                hospital_password: PASSWORD.to_string(),
+            },
+            DeathDetails {
+                date_of_death: time(),
+                place_of_death: "Ward 3".to_string(),
+                cause_of_death: "x".repeat(MAX_CAUSE_BYTES + 1),
+                certifying_doctor_id: Some(clinic.doctor_id),
+            },
+        );
+        assert!(matches!(result, Err(Error::LimitExceeded { .. })));
+        assert!(death_registration(clinic.patient_id).is_none());
+    }
+}
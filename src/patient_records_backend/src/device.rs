@@ -0,0 +1,181 @@
+use crate::time;
+use crate::{
+    audit, authorize_vitals_writer, check_not_sealed, impl_storable, next_id, raise_vitals_alerts,
+    store_vitals, to_hex, Actor, BatchAuth, Error, Memory, VitalsReading, MEMORY_MANAGER,
+};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const MAX_DEVICE_NAME_LEN: usize = 100;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum DeviceKind {
+    Wearable,
+    BedsideMonitor,
+    HomeMonitor,
+    Other,
+}
+
+// A wearable or monitor bound to one patient. Its token can only add vitals to that patient's
+// series; only the token hash is kept
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MedicalDevice {
+    pub id: u64,
+    pub patient_id: u64,
+    pub name: String,
+    pub kind: DeviceKind,
+    pub token_hash: Vec<u8>,
+    pub registered_by: Actor,
+    pub registered_at: u64,
+    pub revoked_at: Option<u64>,
+    pub last_seen_at: Option<u64>,
+}
+
+// Returned once on registration, the secret is not stored
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct IssuedDeviceToken {
+    pub token: String,
+    pub device: MedicalDevice,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RegisterDevicePayload {
+    pub patient_id: u64,
+    pub auth: BatchAuth,
+    pub name: String,
+    pub kind: DeviceKind,
+}
+
+impl_storable!(MedicalDevice, 512);
+
+thread_local! {
+    static DEVICE_STORAGE: RefCell<StableBTreeMap<u64, MedicalDevice, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(142)))
+    ));
+
+    // token hash -> device id, for devices that are not revoked
+    static DEVICE_TOKENS: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(143)))
+    ));
+}
+
+fn hash_token(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+fn save_device(device: &MedicalDevice) {
+    DEVICE_STORAGE.with(|s| s.borrow_mut().insert(device.id, device.clone()));
+}
+
+pub(crate) fn patient_devices(patient_id: u64) -> Vec<MedicalDevice> {
+    DEVICE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, device)| device)
+            .filter(|device| device.patient_id == patient_id)
+            .collect()
+    })
+}
+
+// the patient, an assigned doctor or a nurse of the patient's hospital pairs a device
+#[ic_cdk::update]
+async fn register_device(payload: RegisterDevicePayload) -> Result<IssuedDeviceToken, Error> {
+    let (actor, hospital_id) = authorize_vitals_writer(payload.patient_id, &payload.auth)?;
+    if payload.name.trim().is_empty() || payload.name.chars().count() > MAX_DEVICE_NAME_LEN {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Device name must be 1 to {} characters",
+                MAX_DEVICE_NAME_LEN
+            ),
+        });
+    }
+    let (random,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| Error::InvalidPayload {
+            msg: format!("Could not create token: {:?} {}", code, msg),
+        })?;
+    let token = to_hex(&random);
+    let token_hash = hash_token(&token);
+    let device = MedicalDevice {
+        id: next_id(),
+        patient_id: payload.patient_id,
+        name: payload.name,
+        kind: payload.kind,
+        token_hash: token_hash.to_vec(),
+        registered_by: actor.clone(),
+        registered_at: time(),
+        revoked_at: None,
+        last_seen_at: None,
+    };
+    save_device(&device);
+    DEVICE_TOKENS.with(|s| s.borrow_mut().insert(token_hash, device.id));
+    audit(
+        actor,
+        hospital_id,
+        Some(device.patient_id),
+        "device_registered",
+        format!("device {} {}", device.id, device.name),
+    );
+    Ok(IssuedDeviceToken { token, device })
+}
+
+#[ic_cdk::query]
+fn get_patient_devices(patient_id: u64, auth: BatchAuth) -> Result<Vec<MedicalDevice>, Error> {
+    authorize_vitals_writer(patient_id, &auth)?;
+    Ok(patient_devices(patient_id))
+}
+
+// a lost or replaced device stops working at once, the others keep theirs
+#[ic_cdk::update]
+fn revoke_device(patient_id: u64, auth: BatchAuth, device_id: u64) -> Result<MedicalDevice, Error> {
+    let (actor, hospital_id) = authorize_vitals_writer(patient_id, &auth)?;
+    let mut device = patient_devices(patient_id)
+        .into_iter()
+        .find(|device| device.id == device_id && device.revoked_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("Active device of id: {} not found", device_id),
+        })?;
+    device.revoked_at = Some(time());
+    save_device(&device);
+    if let Ok(token_hash) = <[u8; 32]>::try_from(device.token_hash.as_slice()) {
+        DEVICE_TOKENS.with(|s| s.borrow_mut().remove(&token_hash));
+    }
+    audit(
+        actor,
+        hospital_id,
+        Some(patient_id),
+        "device_revoked",
+        format!("device {} {}", device.id, device.name),
+    );
+    Ok(device)
+}
+
+// the only endpoint devices can call: add readings to the bound patient's vitals series
+#[ic_cdk::update]
+fn record_device_vitals(token: String, readings: Vec<VitalsReading>) -> Result<u64, Error> {
+    let mut device = DEVICE_TOKENS
+        .with(|s| s.borrow().get(&hash_token(&token)))
+        .and_then(|device_id| DEVICE_STORAGE.with(|s| s.borrow().get(&device_id)))
+        .filter(|device| device.revoked_at.is_none())
+        .ok_or(Error::Unauthorized {
+            msg: "Device token is not valid or was revoked".to_string(),
+        })?;
+    check_not_sealed(device.patient_id)?;
+    let points = store_vitals(device.patient_id, readings, &Actor::Device(device.id))?;
+    device.last_seen_at = Some(time());
+    save_device(&device);
+    audit(
+        Actor::Device(device.id),
+        None,
+        Some(device.patient_id),
+        "device_vitals_recorded",
+        format!("{} readings", points.len()),
+    );
+    raise_vitals_alerts(device.id, &points);
+    Ok(points.len() as u64)
+}
@@ -0,0 +1,327 @@
+use crate::time;
+use crate::{
+    authorize_hospital, authorize_nurse, get_authorized_encounter, get_encounter_by_id,
+    get_hospital_ward, impl_storable, next_id, patient_allergies, EncounterStatus, Error, Memory,
+    MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// meals can be ordered up to a week ahead
+const MAX_DAYS_AHEAD: u64 = 7;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum DietaryRestriction {
+    Vegetarian,
+    Vegan,
+    Halal,
+    Kosher,
+    GlutenFree,
+    LactoseFree,
+    Diabetic,
+    LowSodium,
+    Renal,
+    Soft,
+    Liquid,
+    // no food at all, for example before surgery
+    NilByMouth,
+    Other(String),
+}
+
+// The diet of one admission, set by the treating doctor
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AdmissionDiet {
+    pub encounter_id: u64,
+    pub patient_id: u64,
+    pub restrictions: Vec<DietaryRestriction>,
+    // ingredients to leave out on top of the patient's recorded allergies
+    pub avoid: Vec<String>,
+    pub notes: String,
+    pub updated_by: u64,
+    pub updated_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Meal {
+    Breakfast,
+    Lunch,
+    Dinner,
+    Snack,
+}
+
+// A menu item as the kitchen describes it: the diets it suits and what goes into it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MealItem {
+    pub name: String,
+    pub suitable_for: Vec<DietaryRestriction>,
+    pub ingredients: Vec<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MealOrder {
+    pub id: u64,
+    pub encounter_id: u64,
+    pub patient_id: u64,
+    pub hospital_id: u64,
+    pub ward_id: u64,
+    pub nurse_id: u64,
+    // days since the unix epoch
+    pub day: u64,
+    pub meal: Meal,
+    pub items: Vec<MealItem>,
+    // the diet at the time of ordering, so the kitchen sees it next to the order
+    pub restrictions: Vec<DietaryRestriction>,
+    pub cancelled: bool,
+    pub ordered_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AdmissionDietPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub encounter_id: u64,
+    pub restrictions: Vec<DietaryRestriction>,
+    pub avoid: Vec<String>,
+    pub notes: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MealOrderPayload {
+    pub nurse_id: u64,
+    pub nurse_password: String,
+    pub encounter_id: u64,
+    pub ward_id: u64,
+    pub day: u64,
+    pub meal: Meal,
+    pub items: Vec<MealItem>,
+}
+
+impl_storable!(AdmissionDiet, 4096);
+impl_storable!(MealOrder, 8192);
+
+thread_local! {
+    static DIET_STORAGE: RefCell<StableBTreeMap<u64, AdmissionDiet, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94)))
+    ));
+
+    static MEAL_ORDER_STORAGE: RefCell<StableBTreeMap<u64, MealOrder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95)))
+    ));
+}
+
+fn admission_diet(encounter_id: u64) -> Option<AdmissionDiet> {
+    DIET_STORAGE.with(|s| s.borrow().get(&encounter_id))
+}
+
+// the reasons an item does not fit the diet or the patient's allergies, empty when it does
+fn item_conflicts(
+    item: &MealItem,
+    diet: Option<&AdmissionDiet>,
+    allergens: &[String],
+) -> Vec<String> {
+    let mut conflicts = vec![];
+    let unsuitable = diet.is_some_and(|diet| {
+        diet.restrictions
+            .iter()
+            .any(|restriction| !item.suitable_for.contains(restriction))
+    });
+    if unsuitable {
+        conflicts.push(format!("{} does not suit the patient's diet", item.name));
+    }
+    let avoided = diet.map(|diet| diet.avoid.as_slice()).unwrap_or_default();
+    for ingredient in &item.ingredients {
+        let ingredient = ingredient.to_lowercase();
+        if let Some(substance) = allergens
+            .iter()
+            .chain(avoided)
+            .find(|substance| ingredient.contains(&substance.to_lowercase()))
+        {
+            conflicts.push(format!("{} contains {}", item.name, substance));
+        }
+    }
+    conflicts
+}
+
+// set or replace the diet of an open admission
+#[ic_cdk::update]
+fn set_admission_diet(payload: AdmissionDietPayload) -> Result<AdmissionDiet, Error> {
+    let encounter = get_authorized_encounter(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.encounter_id,
+    )?;
+    if encounter.status != EncounterStatus::Open {
+        return Err(Error::InvalidPayload {
+            msg: format!("Encounter of id: {} is already closed", encounter.id),
+        });
+    }
+    let diet = AdmissionDiet {
+        encounter_id: encounter.id,
+        patient_id: encounter.patient_id,
+        restrictions: payload.restrictions,
+        avoid: payload
+            .avoid
+            .into_iter()
+            .map(|substance| substance.trim().to_string())
+            .filter(|substance| !substance.is_empty())
+            .collect(),
+        notes: payload.notes,
+        updated_by: payload.doctor_id,
+        updated_at: time(),
+    };
+    DIET_STORAGE.with(|s| s.borrow_mut().insert(diet.encounter_id, diet.clone()));
+    Ok(diet)
+}
+
+// the diet of an admission at the nurse's hospital, None when no restrictions were set
+#[ic_cdk::query]
+fn get_admission_diet(
+    nurse_id: u64,
+    nurse_password: String,
+    encounter_id: u64,
+) -> Result<Option<AdmissionDiet>, Error> {
+    let nurse = authorize_nurse(nurse_id, &nurse_password)?;
+    let encounter = get_encounter_by_id(encounter_id)?;
+    if encounter.hospital_id != nurse.hospital_id {
+        return Err(Error::Unauthorized {
+            msg: format!("Encounter of id: {} is at another hospital", encounter.id),
+        });
+    }
+    Ok(admission_diet(encounter.id))
+}
+
+// order a meal for an admitted patient; every item must suit the diet and avoid the patient's
+// active allergies
+#[ic_cdk::update]
+fn place_meal_order(payload: MealOrderPayload) -> Result<MealOrder, Error> {
+    let nurse = authorize_nurse(payload.nurse_id, &payload.nurse_password)?;
+    let encounter = get_encounter_by_id(payload.encounter_id)?;
+    if encounter.hospital_id != nurse.hospital_id {
+        return Err(Error::Unauthorized {
+            msg: format!("Encounter of id: {} is at another hospital", encounter.id),
+        });
+    }
+    if encounter.status != EncounterStatus::Open {
+        return Err(Error::InvalidPayload {
+            msg: format!("Encounter of id: {} is already closed", encounter.id),
+        });
+    }
+    let ward = get_hospital_ward(nurse.hospital_id, payload.ward_id)?;
+    let today = time() / DAY_NS;
+    if payload.day < today || payload.day > today + MAX_DAYS_AHEAD {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Meals can be ordered from today up to {} days ahead",
+                MAX_DAYS_AHEAD
+            ),
+        });
+    }
+    if payload.items.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "A meal order needs at least one item".to_string(),
+        });
+    }
+    let diet = admission_diet(encounter.id);
+    let restrictions = diet
+        .as_ref()
+        .map(|diet| diet.restrictions.clone())
+        .unwrap_or_default();
+    if restrictions.contains(&DietaryRestriction::NilByMouth) {
+        return Err(Error::InvalidPayload {
+            msg: "The patient is nil by mouth".to_string(),
+        });
+    }
+    let allergens: Vec<String> = patient_allergies(encounter.patient_id)
+        .into_iter()
+        .map(|allergy| allergy.substance)
+        .collect();
+    let conflicts: Vec<String> = payload
+        .items
+        .iter()
+        .flat_map(|item| item_conflicts(item, diet.as_ref(), &allergens))
+        .collect();
+    if !conflicts.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: conflicts.join("; "),
+        });
+    }
+    let duplicate = MEAL_ORDER_STORAGE.with(|s| {
+        s.borrow().iter().any(|(_, order)| {
+            order.encounter_id == encounter.id
+                && order.day == payload.day
+                && order.meal == payload.meal
+                && !order.cancelled
+        })
+    });
+    if duplicate {
+        return Err(Error::AlreadyInit {
+            msg: "That meal is already ordered, cancel it first to change it".to_string(),
+        });
+    }
+    let order = MealOrder {
+        id: next_id(),
+        encounter_id: encounter.id,
+        patient_id: encounter.patient_id,
+        hospital_id: encounter.hospital_id,
+        ward_id: ward.id,
+        nurse_id: nurse.id,
+        day: payload.day,
+        meal: payload.meal,
+        items: payload.items,
+        restrictions,
+        cancelled: false,
+        ordered_at: time(),
+    };
+    MEAL_ORDER_STORAGE.with(|s| s.borrow_mut().insert(order.id, order.clone()));
+    Ok(order)
+}
+
+#[ic_cdk::update]
+fn cancel_meal_order(
+    nurse_id: u64,
+    nurse_password: String,
+    order_id: u64,
+) -> Result<MealOrder, Error> {
+    let nurse = authorize_nurse(nurse_id, &nurse_password)?;
+    let order = MEAL_ORDER_STORAGE
+        .with(|s| s.borrow().get(&order_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Meal order of id: {} not found", order_id),
+        })?;
+    if order.hospital_id != nurse.hospital_id {
+        return Err(Error::Unauthorized {
+            msg: format!("Meal order of id: {} is at another hospital", order.id),
+        });
+    }
+    let cancelled = MealOrder {
+        cancelled: true,
+        ..order
+    };
+    MEAL_ORDER_STORAGE.with(|s| s.borrow_mut().insert(cancelled.id, cancelled.clone()));
+    Ok(cancelled)
+}
+
+// the kitchen's list for one ward and day, by meal
+#[ic_cdk::query]
+fn get_ward_meal_orders(
+    hospital_id: u64,
+    hospital_password: String,
+    ward_id: u64,
+    day: u64,
+) -> Result<Vec<MealOrder>, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    let ward = get_hospital_ward(hospital.id, ward_id)?;
+    let mut orders: Vec<MealOrder> = MEAL_ORDER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| order.ward_id == ward.id && order.day == day && !order.cancelled)
+            .collect()
+    });
+    orders.sort_by_key(|order| order.meal as u8);
+    Ok(orders)
+}
@@ -0,0 +1,257 @@
+use crate::{
+    authorize_hospital, hospital_offerings, impl_storable, CatalogKind, Error, Hospital, Memory,
+    Page, HOSPITAL_STORAGE, MAX_PAGE_SIZE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::ops::Bound;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub struct GeoPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+// Where a hospital is, kept next to the hospital so its stored size stays unchanged
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct HospitalLocation {
+    pub hospital_id: u64,
+    pub city: String,
+    pub region: String,
+    pub location: Option<GeoPoint>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct HospitalContact {
+    pub phone: String,
+    pub email: String,
+    pub website: String,
+}
+
+// The public view of a hospital, without its password or its doctor and patient rosters
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct DirectoryEntry {
+    pub hospital_id: u64,
+    pub name: String,
+    pub address: String,
+    pub city: String,
+    pub region: String,
+    pub location: Option<GeoPoint>,
+    // names of the specialties and services the hospital declared
+    pub specialties: Vec<String>,
+    pub services: Vec<String>,
+    pub contact: Option<HospitalContact>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct NearbyHospital {
+    pub hospital: DirectoryEntry,
+    pub distance_km: f64,
+}
+
+impl_storable!(HospitalLocation, 512);
+impl_storable!(HospitalContact, 512);
+
+thread_local! {
+    static LOCATION_STORAGE: RefCell<StableBTreeMap<u64, HospitalLocation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
+
+    // (hash of the normalized city, hospital id) for lookups by city
+    static CITY_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+
+    static CONTACT_STORAGE: RefCell<StableBTreeMap<u64, HospitalContact, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct HospitalContactPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub contact: HospitalContact,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct HospitalLocationPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub city: String,
+    pub region: String,
+    pub location: Option<GeoPoint>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SearchHospitalsPayload {
+    // empty matches every city
+    pub city: String,
+    pub name_prefix: String,
+    // hospital id of the last entry of the previous page
+    pub after: Option<u64>,
+    pub limit: u64,
+}
+
+fn city_key(city: &str) -> u64 {
+    let digest = Sha256::digest(city.trim().to_lowercase().as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+}
+
+pub(crate) fn hospital_location(hospital_id: u64) -> HospitalLocation {
+    LOCATION_STORAGE
+        .with(|s| s.borrow().get(&hospital_id))
+        .unwrap_or(HospitalLocation {
+            hospital_id,
+            ..Default::default()
+        })
+}
+
+// store where a hospital is and move it to its city in the index
+pub(crate) fn save_hospital_location(location: HospitalLocation) -> Result<(), Error> {
+    if let Some(point) = location.location {
+        if !(-90.0..=90.0).contains(&point.latitude) || !(-180.0..=180.0).contains(&point.longitude)
+        {
+            return Err(Error::InvalidPayload {
+                msg: "Latitude must be within ±90 and longitude within ±180".to_string(),
+            });
+        }
+    }
+    let previous = hospital_location(location.hospital_id);
+    CITY_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        index.remove(&(city_key(&previous.city), location.hospital_id));
+        if !location.city.trim().is_empty() {
+            index.insert((city_key(&location.city), location.hospital_id), ());
+        }
+    });
+    LOCATION_STORAGE.with(|s| s.borrow_mut().insert(location.hospital_id, location));
+    Ok(())
+}
+
+pub(crate) fn directory_entry(hospital: Hospital) -> DirectoryEntry {
+    let location = hospital_location(hospital.id);
+    let offerings = hospital_offerings(hospital.id);
+    let names = |kind: CatalogKind| {
+        offerings
+            .iter()
+            .filter(|entry| entry.kind == kind)
+            .map(|entry| entry.name.clone())
+            .collect()
+    };
+    DirectoryEntry {
+        hospital_id: hospital.id,
+        name: hospital.name,
+        address: hospital.address,
+        city: location.city,
+        region: location.region,
+        location: location.location,
+        specialties: names(CatalogKind::Specialty),
+        services: names(CatalogKind::Service),
+        contact: CONTACT_STORAGE.with(|s| s.borrow().get(&hospital.id)),
+    }
+}
+
+pub(crate) fn hospital_in_city(hospital_id: u64, city: &str) -> bool {
+    CITY_INDEX.with(|index| index.borrow().contains_key(&(city_key(city), hospital_id)))
+}
+
+fn hospital_entry(hospital_id: u64) -> Option<DirectoryEntry> {
+    HOSPITAL_STORAGE
+        .with(|s| s.borrow().get(&hospital_id))
+        .map(directory_entry)
+}
+
+// great-circle distance between two points
+fn distance_km(a: GeoPoint, b: GeoPoint) -> f64 {
+    let (lat_a, lat_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.longitude - a.longitude).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+#[ic_cdk::update]
+fn set_hospital_location(payload: HospitalLocationPayload) -> Result<HospitalLocation, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let location = HospitalLocation {
+        hospital_id: hospital.id,
+        city: payload.city,
+        region: payload.region,
+        location: payload.location,
+    };
+    save_hospital_location(location.clone())?;
+    Ok(location)
+}
+
+// contact details shown in the directory
+#[ic_cdk::update]
+fn set_hospital_contact(payload: HospitalContactPayload) -> Result<HospitalContact, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    CONTACT_STORAGE.with(|s| s.borrow_mut().insert(hospital.id, payload.contact.clone()));
+    Ok(payload.contact)
+}
+
+// hospitals in a city whose name starts with the prefix, a page at a time in id order
+#[ic_cdk::query]
+fn search_hospitals(payload: SearchHospitalsPayload) -> Page<DirectoryEntry> {
+    let prefix = payload.name_prefix.trim().to_lowercase();
+    let start = payload.after.map_or(0, |after| after + 1);
+    let ids: Vec<u64> = if payload.city.trim().is_empty() {
+        HOSPITAL_STORAGE.with(|s| s.borrow().range(start..).map(|(id, _)| id).collect())
+    } else {
+        let key = city_key(&payload.city);
+        CITY_INDEX.with(|index| {
+            index
+                .borrow()
+                .range((
+                    Bound::Included((key, start)),
+                    Bound::Included((key, u64::MAX)),
+                ))
+                .map(|((_, id), _)| id)
+                .collect()
+        })
+    };
+
+    let limit = payload.limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut matches = ids
+        .into_iter()
+        .filter_map(hospital_entry)
+        .filter(|entry| entry.name.to_lowercase().starts_with(&prefix));
+    let items: Vec<DirectoryEntry> = matches.by_ref().take(limit).collect();
+    let next_cursor = match matches.next() {
+        Some(_) => items.last().map(|entry| entry.hospital_id),
+        None => None,
+    };
+    Page { items, next_cursor }
+}
+
+// the closest hospitals with a known location
+#[ic_cdk::query]
+fn get_nearest_hospitals(from: GeoPoint, limit: u64) -> Vec<NearbyHospital> {
+    let mut nearby: Vec<NearbyHospital> = LOCATION_STORAGE
+        .with(|s| {
+            s.borrow()
+                .iter()
+                .filter_map(|(id, location)| Some((id, location.location?)))
+                .collect::<Vec<_>>()
+        })
+        .into_iter()
+        .filter_map(|(id, point)| {
+            Some(NearbyHospital {
+                hospital: hospital_entry(id)?,
+                distance_km: distance_km(from, point),
+            })
+        })
+        .collect();
+    nearby.sort_by(|a, b| a.distance_km.total_cmp(&b.distance_km));
+    nearby.truncate(limit.clamp(1, MAX_PAGE_SIZE) as usize);
+    nearby
+}
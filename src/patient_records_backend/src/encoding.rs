@@ -0,0 +1,280 @@
+use crate::time;
+use crate::{
+    authorize_controller, impl_storable, reencode_records, Error, Memory, DOCTOR_STORAGE,
+    HOSPITAL_STORAGE, MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell as StableCell, StableBTreeMap};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+// stored values start with this byte and the format version, Candid encodings start with "DIDL"
+const COMPACT_TAG: u8 = 0xCB;
+// version 1: CBOR with struct fields and enum variants by name, so reordering or inserting
+// fields in a stored type does not change how earlier values decode
+const COMPACT_VERSION: u8 = 1;
+// entries rewritten per store and timer tick
+const REENCODE_BATCH: usize = 200;
+// the stores rewritten after an upgrade, the rest move to the new format on their next write
+const REENCODED_STORES: [&str; 4] = ["patients", "hospitals", "doctors", "medical_records"];
+
+// Progress of rewriting the stores in the current format
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct EncodingMigration {
+    // format version the stores were last fully rewritten in, 0 for Candid
+    pub version: u8,
+    pub store: u8,
+    pub after: Option<u64>,
+    pub rewritten: u64,
+    pub finished_at: Option<u64>,
+}
+
+impl_storable!(EncodingMigration, 128);
+
+thread_local! {
+    static ENCODING_MIGRATION: RefCell<StableCell<EncodingMigration, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(139))),
+            EncodingMigration::default(),
+        )
+        .expect("Cannot create encoding migration state")
+    );
+}
+
+// encode a value for stable memory. The few values that grow under CBOR past the store's size
+// bound, e.g. byte vectors near it, keep their Candid encoding
+pub(crate) fn encode_stored<T: CandidType + Serialize>(value: &T, max_size: u32) -> Vec<u8> {
+    let mut bytes = vec![COMPACT_TAG, COMPACT_VERSION];
+    ciborium::ser::into_writer(value, &mut bytes).expect("Cannot encode stored value");
+    if bytes.len() > max_size as usize {
+        return Encode!(value).unwrap();
+    }
+    bytes
+}
+
+// decode a stored value in whichever format it was written
+pub(crate) fn decode_stored<T: CandidType + DeserializeOwned>(bytes: &[u8]) -> T {
+    match bytes {
+        [COMPACT_TAG, COMPACT_VERSION, rest @ ..] => {
+            ciborium::de::from_reader(rest).expect("Cannot decode stored value")
+        }
+        [COMPACT_TAG, version, ..] => panic!("Stored value has unknown format {}", version),
+        _ => Decode!(bytes, T).unwrap(),
+    }
+}
+
+// rewrite a page of a store so its values take the current format, returns the last key
+// rewritten and how many were, None once the store is done
+pub(crate) fn reencode_page<V: BoundedStorable>(
+    map: &mut StableBTreeMap<u64, V, Memory>,
+    after: Option<u64>,
+    limit: usize,
+) -> Option<(u64, u64)> {
+    let start = match after {
+        Some(key) => Bound::Excluded(key),
+        None => Bound::Unbounded,
+    };
+    let page: Vec<(u64, V)> = map.range((start, Bound::Unbounded)).take(limit).collect();
+    let last = page.last().map(|(key, _)| (*key, page.len() as u64));
+    for (key, value) in page {
+        map.insert(key, value);
+    }
+    last
+}
+
+fn reencode_store(store: u8, after: Option<u64>) -> Option<(u64, u64)> {
+    match store {
+        0 => PATIENT_STORAGE.with(|s| reencode_page(&mut s.borrow_mut(), after, REENCODE_BATCH)),
+        1 => HOSPITAL_STORAGE
+            .with(|s| reencode_page(s.borrow_mut().stable_mut(), after, REENCODE_BATCH)),
+        2 => DOCTOR_STORAGE
+            .with(|s| reencode_page(s.borrow_mut().stable_mut(), after, REENCODE_BATCH)),
+        _ => reencode_records(after, REENCODE_BATCH),
+    }
+}
+
+fn encoding_migration() -> EncodingMigration {
+    ENCODING_MIGRATION.with(|s| s.borrow().get().clone())
+}
+
+// timer job: after an upgrade that changed the format, rewrite the main stores a batch at a time
+pub(crate) fn migrate_stored_encoding() {
+    let mut migration = encoding_migration();
+    if migration.version == COMPACT_VERSION {
+        return;
+    }
+    if migration.finished_at.is_some() {
+        migration = EncodingMigration::default();
+    }
+    match reencode_store(migration.store, migration.after) {
+        Some((last, rewritten)) => {
+            migration.after = Some(last);
+            migration.rewritten += rewritten;
+        }
+        None => {
+            migration.store += 1;
+            migration.after = None;
+        }
+    }
+    if migration.store as usize == REENCODED_STORES.len() {
+        migration.version = COMPACT_VERSION;
+        migration.finished_at = Some(time());
+    }
+    ENCODING_MIGRATION
+        .with(|s| s.borrow_mut().set(migration))
+        .expect("Cannot update encoding migration state");
+}
+
+#[ic_cdk::query]
+fn get_encoding_migration() -> Result<EncodingMigration, Error> {
+    authorize_controller()?;
+    Ok(encoding_migration())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Hospital, MedicalRecord, RecordKind};
+    use candid::{Nat, Principal};
+
+    #[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Debug)]
+    enum Status {
+        Active,
+        Closed(u64),
+    }
+
+    #[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Debug)]
+    struct Entry {
+        id: u64,
+        owner: Principal,
+        amount: Nat,
+        status: Status,
+        note: Option<String>,
+    }
+
+    // Entry after an upgrade added a field, the way stored structs grow
+    #[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Debug)]
+    struct EntryV2 {
+        id: u64,
+        owner: Principal,
+        amount: Nat,
+        status: Status,
+        note: Option<String>,
+        closed_by: Option<u64>,
+    }
+
+    // Entry with its fields moved around and a new one in the middle
+    #[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Debug)]
+    struct EntryReordered {
+        note: Option<String>,
+        status: Status,
+        reviewed_at: Option<u64>,
+        amount: Nat,
+        owner: Principal,
+        id: u64,
+    }
+
+    fn entry() -> Entry {
+        Entry {
+            id: 42,
+            owner: Principal::from_text("aaaaa-aa").unwrap(),
+            amount: Nat::from(1_000_000u64),
+            status: Status::Closed(7),
+            note: Some("paid".to_string()),
+        }
+    }
+
+    #[test]
+    fn values_round_trip_in_the_compact_format() {
+        let bytes = encode_stored(&entry(), 1024);
+        assert_eq!(bytes[..2], [COMPACT_TAG, COMPACT_VERSION]);
+        assert_eq!(decode_stored::<Entry>(&bytes), entry());
+    }
+
+    #[test]
+    fn compact_values_are_smaller_than_candid() {
+        let hospital = Hospital {
+            id: 3,
+            name: "St. Mary".to_string(),
+            address: "12 Harbour Road".to_string(),
+            password: "secret".to_string(),
+            patients_ids: (100..160).collect(),
+            doctors_ids: vec![4, 5, 6],
+        };
+        let record = MedicalRecord {
+            id: 9,
+            patient_id: 100,
+            doctor_id: Some(4),
+            hospital_id: Some(3),
+            kind: RecordKind::Diagnosis,
+            title: "Asthma".to_string(),
+            body: "Mild persistent".to_string(),
+            created_at: 1_700_000_000_000_000_000,
+            migrated: false,
+            restored_at: None,
+            addendum_to: None,
+        };
+        assert!(encode_stored(&hospital, 1024).len() < Encode!(&hospital).unwrap().len());
+        assert!(encode_stored(&record, 16384).len() < Encode!(&record).unwrap().len());
+    }
+
+    #[test]
+    fn candid_values_written_before_still_decode() {
+        let legacy = Encode!(&entry()).unwrap();
+        assert_eq!(decode_stored::<Entry>(&legacy), entry());
+    }
+
+    #[test]
+    fn added_optional_fields_decode_as_none() {
+        let bytes = encode_stored(&entry(), 1024);
+        let upgraded: EntryV2 = decode_stored(&bytes);
+        assert_eq!(upgraded.id, 42);
+        assert_eq!(upgraded.note, Some("paid".to_string()));
+        assert_eq!(upgraded.closed_by, None);
+    }
+
+    #[test]
+    fn moved_fields_decode_by_name() {
+        let bytes = encode_stored(&entry(), 1024);
+        let reordered: EntryReordered = decode_stored(&bytes);
+        assert_eq!(reordered.id, 42);
+        assert_eq!(reordered.status, Status::Closed(7));
+        assert_eq!(reordered.note, Some("paid".to_string()));
+        assert_eq!(reordered.reviewed_at, None);
+    }
+
+    // pins the stored layout: a map keyed by field name, enum variants by name
+    #[test]
+    fn stored_layout_is_pinned() {
+        #[derive(candid::CandidType, Serialize, Deserialize)]
+        struct Small {
+            id: u64,
+            status: Status,
+        }
+        let bytes = encode_stored(
+            &Small {
+                id: 1,
+                status: Status::Active,
+            },
+            1024,
+        );
+        let mut expected = vec![COMPACT_TAG, COMPACT_VERSION, 0xA2, 0x62];
+        expected.extend(b"id");
+        expected.extend([0x01, 0x66]);
+        expected.extend(b"status");
+        expected.push(0x66);
+        expected.extend(b"Active");
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn values_over_the_bound_keep_candid() {
+        let blob: Vec<u8> = (0..=255).collect();
+        let bytes = encode_stored(&blob, 300);
+        assert!(bytes.starts_with(b"DIDL"));
+        assert_eq!(decode_stored::<Vec<u8>>(&bytes), blob);
+    }
+}
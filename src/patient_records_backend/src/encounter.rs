@@ -0,0 +1,399 @@
+use crate::time;
+use crate::{
+    authorize_doctor, cancel_pending_doses, check_controlled_prescription, check_limit,
+    custom_field_values, entry_warnings, evaluate_alert_rules, get_assigned_patient, impl_storable,
+    next_id, offer_survey, plan_medication_reminders, register_controlled_prescription,
+    release_admission_bed, schedule_doses, CustomFieldValue, Error, Memory, ResultWithWarnings,
+    MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// entry ids share the encounter's 4096 bytes with its reason
+const MAX_ENCOUNTER_ENTRIES: u64 = 300;
+const MAX_ENCOUNTER_REASON_BYTES: usize = 512;
+// free text of one entry, inside the entry's 2048 bytes
+const MAX_ENTRY_TEXT_BYTES: usize = 1024;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum EncounterStatus {
+    Open,
+    Closed,
+}
+
+// An encounter groups everything that happens during one visit under one id
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Encounter {
+    pub id: u64,
+    pub patient_id: u64,
+    pub doctor_id: u64,
+    pub hospital_id: u64,
+    pub reason: String,
+    pub status: EncounterStatus,
+    pub opened_at: u64,
+    pub closed_at: Option<u64>,
+    pub entry_ids: Vec<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct Vitals {
+    pub systolic_bp: Option<f64>,
+    pub diastolic_bp: Option<f64>,
+    pub heart_rate: Option<f64>,
+    pub respiratory_rate: Option<f64>,
+    pub temperature: Option<f64>,
+    pub oxygen_saturation: Option<f64>,
+    pub height_cm: Option<f64>,
+    pub weight_kg: Option<f64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct Prescription {
+    pub medication: String,
+    pub dosage: String,
+    pub doses_per_day: u32,
+    pub duration_days: u32,
+    pub refills: u32,
+}
+
+// The different things that can be recorded during an encounter
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum EncounterEntryKind {
+    Note {
+        text: String,
+    },
+    Vitals(Vitals),
+    Order {
+        description: String,
+    },
+    Prescription(Prescription),
+    Charge {
+        description: String,
+        amount: u64,
+    },
+    LabResult {
+        test: String,
+        value: f64,
+        unit: String,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EncounterEntry {
+    pub id: u64,
+    pub encounter_id: u64,
+    pub doctor_id: u64,
+    pub recorded_at: u64,
+    pub kind: EncounterEntryKind,
+}
+
+// Full view of a visit with all its entries in recording order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EncounterDetails {
+    pub encounter: Encounter,
+    pub entries: Vec<EncounterEntry>,
+    pub custom_fields: Vec<CustomFieldValue>,
+}
+
+impl_storable!(Encounter, 4096);
+impl_storable!(EncounterEntry, 2048);
+
+thread_local! {
+    static ENCOUNTER_STORAGE: RefCell<StableBTreeMap<u64, Encounter, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    static ENCOUNTER_ENTRY_STORAGE: RefCell<StableBTreeMap<u64, EncounterEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct OpenEncounterPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub reason: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EncounterEntryPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub encounter_id: u64,
+    pub kind: EncounterEntryKind,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct EncounterAccessPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub encounter_id: u64,
+}
+
+// helper function to get an encounter the doctor may access through the patient's care team
+pub(crate) fn get_authorized_encounter(
+    doctor_id: u64,
+    doctor_password: &str,
+    encounter_id: u64,
+) -> Result<Encounter, Error> {
+    let doctor = authorize_doctor(doctor_id, doctor_password)?;
+    let encounter = get_encounter_by_id(encounter_id)?;
+    get_assigned_patient(&doctor, encounter.patient_id)?;
+    Ok(encounter)
+}
+
+pub(crate) fn get_encounter_by_id(encounter_id: u64) -> Result<Encounter, Error> {
+    ENCOUNTER_STORAGE
+        .with(|s| s.borrow().get(&encounter_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Encounter of id: {} not found", encounter_id),
+        })
+}
+
+pub(crate) fn get_encounter_entry(entry_id: u64) -> Result<EncounterEntry, Error> {
+    ENCOUNTER_ENTRY_STORAGE
+        .with(|s| s.borrow().get(&entry_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Encounter entry of id: {} not found", entry_id),
+        })
+}
+
+pub(crate) fn get_encounter_entries(encounter: &Encounter) -> Vec<EncounterEntry> {
+    ENCOUNTER_ENTRY_STORAGE.with(|s| {
+        let entries = s.borrow();
+        encounter
+            .entry_ids
+            .iter()
+            .filter_map(|id| entries.get(id))
+            .collect()
+    })
+}
+
+// the free text an entry carries, which is what can outgrow its store
+fn entry_text_bytes(kind: &EncounterEntryKind) -> usize {
+    match kind {
+        EncounterEntryKind::Note { text } => text.len(),
+        EncounterEntryKind::Vitals(_) => 0,
+        EncounterEntryKind::Order { description } => description.len(),
+        EncounterEntryKind::Prescription(prescription) => {
+            prescription.medication.len() + prescription.dosage.len()
+        }
+        EncounterEntryKind::Charge { description, .. } => description.len(),
+        EncounterEntryKind::LabResult { test, unit, .. } => test.len() + unit.len(),
+    }
+}
+
+// open a new encounter for a patient at the doctor's hospital
+#[ic_cdk::update]
+fn open_encounter(payload: OpenEncounterPayload) -> Result<Encounter, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    if payload.reason.len() > MAX_ENCOUNTER_REASON_BYTES {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "Encounter reason exceeds {} bytes",
+                MAX_ENCOUNTER_REASON_BYTES
+            ),
+        });
+    }
+
+    let id = next_id();
+    let encounter = Encounter {
+        id,
+        patient_id: patient.id,
+        doctor_id: doctor.id,
+        hospital_id: doctor.hospital_id,
+        reason: payload.reason,
+        status: EncounterStatus::Open,
+        opened_at: time(),
+        closed_at: None,
+        entry_ids: vec![],
+    };
+    ENCOUNTER_STORAGE.with(|s| s.borrow_mut().insert(id, encounter.clone()));
+    Ok(encounter)
+}
+
+// record a note, vitals, order, prescription, charge or lab result on an open encounter,
+// with warnings about unusual dosages or readings that the doctor may want to double-check
+pub(crate) fn record_encounter_entry(
+    payload: EncounterEntryPayload,
+) -> Result<ResultWithWarnings<EncounterEntry>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let mut encounter = get_encounter_by_id(payload.encounter_id)?;
+    let patient = get_assigned_patient(&doctor, encounter.patient_id)?;
+    if encounter.status != EncounterStatus::Open {
+        return Err(Error::InvalidPayload {
+            msg: format!("Encounter of id: {} is already closed", encounter.id),
+        });
+    }
+    check_limit(
+        "entries per encounter",
+        encounter.entry_ids.len() as u64,
+        MAX_ENCOUNTER_ENTRIES,
+    )?;
+    if entry_text_bytes(&payload.kind) > MAX_ENTRY_TEXT_BYTES {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "Encounter entry text exceeds {} bytes",
+                MAX_ENTRY_TEXT_BYTES
+            ),
+        });
+    }
+
+    let controlled = match &payload.kind {
+        EncounterEntryKind::Prescription(prescription) => {
+            check_controlled_prescription(&doctor, prescription)?
+        }
+        _ => None,
+    };
+
+    let entry = EncounterEntry {
+        id: next_id(),
+        encounter_id: encounter.id,
+        doctor_id: payload.doctor_id,
+        recorded_at: time(),
+        kind: payload.kind,
+    };
+    ENCOUNTER_ENTRY_STORAGE.with(|s| s.borrow_mut().insert(entry.id, entry.clone()));
+    if let (Some((substance, license)), EncounterEntryKind::Prescription(prescription)) =
+        (&controlled, &entry.kind)
+    {
+        register_controlled_prescription(substance, license, &encounter, entry.id, prescription);
+    }
+
+    encounter.entry_ids.push(entry.id);
+    ENCOUNTER_STORAGE.with(|s| s.borrow_mut().insert(encounter.id, encounter.clone()));
+    if matches!(entry.kind, EncounterEntryKind::Prescription(_)) {
+        schedule_doses(&encounter);
+    }
+    evaluate_alert_rules(&encounter, &entry);
+    let warnings = entry_warnings(&patient, &entry.kind);
+    Ok(ResultWithWarnings {
+        value: entry,
+        warnings,
+    })
+}
+
+// v1 shape of the call, without warnings; v2_add_encounter_entry returns them
+#[ic_cdk::update]
+fn add_encounter_entry(payload: EncounterEntryPayload) -> Result<EncounterEntry, Error> {
+    record_encounter_entry(payload).map(|recorded| recorded.value)
+}
+
+// close an encounter, after which no more entries can be recorded, and offer the patient
+// a satisfaction survey
+#[ic_cdk::update]
+async fn close_encounter(payload: EncounterAccessPayload) -> Result<Encounter, Error> {
+    let encounter = get_authorized_encounter(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.encounter_id,
+    )?;
+    if encounter.status != EncounterStatus::Open {
+        return Err(Error::InvalidPayload {
+            msg: format!("Encounter of id: {} is already closed", encounter.id),
+        });
+    }
+
+    let closed = Encounter {
+        status: EncounterStatus::Closed,
+        closed_at: Some(time()),
+        ..encounter
+    };
+    ENCOUNTER_STORAGE.with(|s| s.borrow_mut().insert(closed.id, closed.clone()));
+    cancel_pending_doses(closed.id);
+    plan_medication_reminders(&closed);
+    release_admission_bed(closed.id);
+    offer_survey(&closed).await;
+    Ok(closed)
+}
+
+// reconstruct a visit end-to-end
+#[ic_cdk::query]
+fn get_encounter(payload: EncounterAccessPayload) -> Result<EncounterDetails, Error> {
+    let encounter = get_authorized_encounter(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.encounter_id,
+    )?;
+    let entries = get_encounter_entries(&encounter);
+    let custom_fields = custom_field_values(encounter.id);
+    Ok(EncounterDetails {
+        encounter,
+        entries,
+        custom_fields,
+    })
+}
+
+// list all encounters of a patient, newest first
+#[ic_cdk::query]
+fn get_patient_encounters(payload: crate::AccessPayload) -> Result<Vec<Encounter>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    Ok(patient_encounters(patient.id))
+}
+
+// all entries recorded during the patient's encounters
+pub(crate) fn patient_entries(patient_id: u64) -> Vec<EncounterEntry> {
+    patient_encounters(patient_id)
+        .iter()
+        .flat_map(get_encounter_entries)
+        .collect()
+}
+
+pub(crate) fn patient_prescriptions(patient_id: u64) -> Vec<(EncounterEntry, Prescription)> {
+    patient_entries(patient_id)
+        .into_iter()
+        .filter_map(|entry| match &entry.kind {
+            EncounterEntryKind::Prescription(prescription) => {
+                let prescription = prescription.clone();
+                Some((entry, prescription))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+pub(crate) fn patient_vitals(patient_id: u64) -> Vec<EncounterEntry> {
+    patient_entries(patient_id)
+        .into_iter()
+        .filter(|entry| matches!(entry.kind, EncounterEntryKind::Vitals(_)))
+        .collect()
+}
+
+pub(crate) fn patient_encounters(patient_id: u64) -> Vec<Encounter> {
+    let mut encounters: Vec<Encounter> = ENCOUNTER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, encounter)| encounter)
+            .filter(|encounter| encounter.patient_id == patient_id)
+            .collect()
+    });
+    encounters.sort_by_key(|encounter| std::cmp::Reverse(encounter.opened_at));
+    encounters
+}
+
+// encounters still open, i.e. patients currently admitted
+pub(crate) fn open_encounters() -> Vec<Encounter> {
+    ENCOUNTER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, encounter)| encounter)
+            .filter(|encounter| encounter.status == EncounterStatus::Open)
+            .collect()
+    })
+}
+
+pub(crate) fn doctor_encounters(doctor_id: u64) -> Vec<Encounter> {
+    ENCOUNTER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, encounter)| encounter)
+            .filter(|encounter| encounter.doctor_id == doctor_id)
+            .collect()
+    })
+}
@@ -0,0 +1,75 @@
+use crate::{
+    authorize_doctor, authorize_hospital, authorize_patient, Actor, Doctor, Error, Hospital,
+    Patient, DOCTOR_STORAGE, HOSPITAL_STORAGE, PATIENT_STORAGE,
+};
+
+// A reference to a patient, doctor or hospital, used wherever one of them has to be named:
+// notification inboxes, language and time zone preferences, audit queries and batch lookups
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub enum EntityRef {
+    Hospital(u64),
+    Doctor(u64),
+    Patient(u64),
+}
+
+// The stored entity a reference points to
+pub(crate) enum Entity {
+    Hospital(Hospital),
+    Doctor(Doctor),
+    Patient(Patient),
+}
+
+impl EntityRef {
+    // compact key for stores keyed by entity
+    pub(crate) fn key(&self) -> (u8, u64) {
+        match self {
+            EntityRef::Hospital(id) => (0, *id),
+            EntityRef::Doctor(id) => (1, *id),
+            EntityRef::Patient(id) => (2, *id),
+        }
+    }
+}
+
+impl From<EntityRef> for Actor {
+    fn from(entity: EntityRef) -> Actor {
+        match entity {
+            EntityRef::Hospital(id) => Actor::Hospital(id),
+            EntityRef::Doctor(id) => Actor::Doctor(id),
+            EntityRef::Patient(id) => Actor::Patient(id),
+        }
+    }
+}
+
+// look up the entity a reference points to
+pub(crate) fn resolve_ref(entity: EntityRef) -> Result<Entity, Error> {
+    let found = match entity {
+        EntityRef::Hospital(id) => HOSPITAL_STORAGE
+            .with(|s| s.borrow().get(&id))
+            .map(Entity::Hospital),
+        EntityRef::Doctor(id) => DOCTOR_STORAGE
+            .with(|s| s.borrow().get(&id))
+            .map(Entity::Doctor),
+        EntityRef::Patient(id) => PATIENT_STORAGE
+            .with(|s| s.borrow().get(&id))
+            .map(Entity::Patient),
+    };
+    found.ok_or_else(|| {
+        let (kind, id) = match entity {
+            EntityRef::Hospital(id) => ("Hospital", id),
+            EntityRef::Doctor(id) => ("Doctor", id),
+            EntityRef::Patient(id) => ("Patient", id),
+        };
+        Error::NotFound {
+            msg: format!("{} of id: {} not found", kind, id),
+        }
+    })
+}
+
+// helper function to check the password of the referenced entity and return it
+pub(crate) fn authorize_ref(entity: EntityRef, password: &str) -> Result<Entity, Error> {
+    match entity {
+        EntityRef::Hospital(id) => authorize_hospital(id, password).map(Entity::Hospital),
+        EntityRef::Doctor(id) => authorize_doctor(id, password).map(Entity::Doctor),
+        EntityRef::Patient(id) => authorize_patient(id, password).map(Entity::Patient),
+    }
+}
@@ -0,0 +1,280 @@
+use crate::time;
+use crate::{
+    audit, authorize_hospital, get_hospital_ward, impl_storable, next_id, notify, text, Actor,
+    Error, HospitalAccessPayload, Memory, Priority, Recipient, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// maintenance tasks are created this many days before they are due
+const TASK_LEAD_DAYS: u64 = 7;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Equipment {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub name: String,
+    pub serial_number: String,
+    pub ward_id: Option<u64>,
+    pub maintenance_interval_days: u64,
+    pub last_maintained_at: Option<u64>,
+    pub next_maintenance_due: u64,
+    pub retired: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MaintenanceTask {
+    pub id: u64,
+    pub equipment_id: u64,
+    pub hospital_id: u64,
+    pub due_at: u64,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+    pub notes: String,
+}
+
+impl_storable!(Equipment, 512);
+impl_storable!(MaintenanceTask, 1024);
+
+thread_local! {
+    static EQUIPMENT_STORAGE: RefCell<StableBTreeMap<u64, Equipment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    static MAINTENANCE_TASK_STORAGE: RefCell<StableBTreeMap<u64, MaintenanceTask, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct EquipmentPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub name: String,
+    pub serial_number: String,
+    pub ward_id: Option<u64>,
+    pub maintenance_interval_days: u64,
+    pub next_maintenance_due: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AssignEquipmentPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub equipment_id: u64,
+    // None takes the equipment out of its ward
+    pub ward_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct CompleteMaintenancePayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub task_id: u64,
+    pub notes: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct EquipmentAccessPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub equipment_id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct MaintenanceTasksPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub open_only: bool,
+}
+
+// helper function to get equipment and check it belongs to the hospital
+fn get_hospital_equipment(hospital_id: u64, equipment_id: u64) -> Result<Equipment, Error> {
+    match EQUIPMENT_STORAGE.with(|s| s.borrow().get(&equipment_id)) {
+        Some(equipment) if equipment.hospital_id == hospital_id => Ok(equipment),
+        Some(_) => Err(Error::Unauthorized {
+            msg: format!(
+                "Equipment of id: {} belongs to another hospital",
+                equipment_id
+            ),
+        }),
+        None => Err(Error::NotFound {
+            msg: format!("Equipment of id: {} not found", equipment_id),
+        }),
+    }
+}
+
+fn save_equipment(equipment: &Equipment) {
+    EQUIPMENT_STORAGE.with(|s| s.borrow_mut().insert(equipment.id, equipment.clone()));
+}
+
+fn has_open_task(equipment_id: u64) -> bool {
+    MAINTENANCE_TASK_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .any(|(_, task)| task.equipment_id == equipment_id && task.completed_at.is_none())
+    })
+}
+
+#[ic_cdk::update]
+fn add_equipment(payload: EquipmentPayload) -> Result<Equipment, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if let Some(ward_id) = payload.ward_id {
+        get_hospital_ward(hospital.id, ward_id)?;
+    }
+    if payload.maintenance_interval_days == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "Maintenance interval must be at least one day".to_string(),
+        });
+    }
+    let equipment = Equipment {
+        id: next_id(),
+        hospital_id: hospital.id,
+        name: payload.name,
+        serial_number: payload.serial_number,
+        ward_id: payload.ward_id,
+        maintenance_interval_days: payload.maintenance_interval_days,
+        last_maintained_at: None,
+        next_maintenance_due: payload.next_maintenance_due,
+        retired: false,
+    };
+    save_equipment(&equipment);
+    Ok(equipment)
+}
+
+// move equipment to another ward of the same hospital
+#[ic_cdk::update]
+fn assign_equipment_to_ward(payload: AssignEquipmentPayload) -> Result<Equipment, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let equipment = get_hospital_equipment(hospital.id, payload.equipment_id)?;
+    if let Some(ward_id) = payload.ward_id {
+        get_hospital_ward(hospital.id, ward_id)?;
+    }
+    let moved = Equipment {
+        ward_id: payload.ward_id,
+        ..equipment
+    };
+    save_equipment(&moved);
+    Ok(moved)
+}
+
+#[ic_cdk::update]
+fn retire_equipment(payload: EquipmentAccessPayload) -> Result<Equipment, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let equipment = get_hospital_equipment(hospital.id, payload.equipment_id)?;
+    let retired = Equipment {
+        retired: true,
+        ward_id: None,
+        ..equipment
+    };
+    save_equipment(&retired);
+    Ok(retired)
+}
+
+// close a maintenance task and schedule the next maintenance
+#[ic_cdk::update]
+fn complete_maintenance_task(payload: CompleteMaintenancePayload) -> Result<Equipment, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let task = match MAINTENANCE_TASK_STORAGE.with(|s| s.borrow().get(&payload.task_id)) {
+        Some(task) if task.hospital_id == hospital.id && task.completed_at.is_none() => task,
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!("Open maintenance task of id: {} not found", payload.task_id),
+            })
+        }
+    };
+    let equipment = get_hospital_equipment(hospital.id, task.equipment_id)?;
+
+    let now = time();
+    let completed = MaintenanceTask {
+        completed_at: Some(now),
+        notes: payload.notes,
+        ..task
+    };
+    MAINTENANCE_TASK_STORAGE.with(|s| s.borrow_mut().insert(completed.id, completed.clone()));
+    let maintained = Equipment {
+        last_maintained_at: Some(now),
+        next_maintenance_due: now + equipment.maintenance_interval_days * DAY_NS,
+        ..equipment
+    };
+    save_equipment(&maintained);
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        None,
+        "equipment_maintained",
+        format!("equipment {} task {}", maintained.id, completed.id),
+    );
+    Ok(maintained)
+}
+
+#[ic_cdk::query]
+fn get_equipment(payload: HospitalAccessPayload) -> Result<Vec<Equipment>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    Ok(EQUIPMENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, equipment)| equipment)
+            .filter(|equipment| equipment.hospital_id == hospital.id)
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_maintenance_tasks(payload: MaintenanceTasksPayload) -> Result<Vec<MaintenanceTask>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    Ok(MAINTENANCE_TASK_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, task)| task)
+            .filter(|task| {
+                task.hospital_id == hospital.id
+                    && (!payload.open_only || task.completed_at.is_none())
+            })
+            .collect()
+    }))
+}
+
+// timer job: open a maintenance task for equipment that is due soon
+pub(crate) fn generate_maintenance_tasks() {
+    let limit = time() + TASK_LEAD_DAYS * DAY_NS;
+    let due: Vec<Equipment> = EQUIPMENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, equipment)| equipment)
+            .filter(|equipment| !equipment.retired && equipment.next_maintenance_due <= limit)
+            .collect()
+    });
+    for equipment in due {
+        if has_open_task(equipment.id) {
+            continue;
+        }
+        let task = MaintenanceTask {
+            id: next_id(),
+            equipment_id: equipment.id,
+            hospital_id: equipment.hospital_id,
+            due_at: equipment.next_maintenance_due,
+            created_at: time(),
+            completed_at: None,
+            notes: String::new(),
+        };
+        MAINTENANCE_TASK_STORAGE.with(|s| s.borrow_mut().insert(task.id, task.clone()));
+        notify(
+            Recipient::Hospital(equipment.hospital_id),
+            Priority::Normal,
+            text(
+                "equipment.maintenance_due",
+                "Maintenance due for {equipment} ({serial}), task {task}",
+                vec![
+                    ("equipment", equipment.name.clone()),
+                    ("serial", equipment.serial_number.clone()),
+                    ("task", task.id.to_string()),
+                ],
+            ),
+        );
+    }
+}
@@ -0,0 +1,368 @@
+use crate::time;
+use crate::{
+    audit, authenticate_patient, authorize_doctor, authorize_patient, get_assigned_patient,
+    impl_storable, next_id, Actor, Error, Memory, PatientConsent, MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// How the relative is related to the patient who requested the link
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Relationship {
+    Parent,
+    Child,
+    Sibling,
+    Grandparent,
+    Grandchild,
+    AuntOrUncle,
+    NieceOrNephew,
+    Cousin,
+}
+
+impl Relationship {
+    // the same relationship seen from the relative's side
+    pub fn inverse(self) -> Relationship {
+        match self {
+            Relationship::Parent => Relationship::Child,
+            Relationship::Child => Relationship::Parent,
+            Relationship::Sibling => Relationship::Sibling,
+            Relationship::Grandparent => Relationship::Grandchild,
+            Relationship::Grandchild => Relationship::Grandparent,
+            Relationship::AuntOrUncle => Relationship::NieceOrNephew,
+            Relationship::NieceOrNephew => Relationship::AuntOrUncle,
+            Relationship::Cousin => Relationship::Cousin,
+        }
+    }
+}
+
+// What a patient lets the clinicians of a linked relative see of their risk flags
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum FamilySharing {
+    Nothing,
+    Conditions,
+    ConditionsAndNotes,
+}
+
+// An opt-in link between two patient records, active once the relative accepts it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FamilyLink {
+    pub id: u64,
+    pub requester_id: u64,
+    pub relative_id: u64,
+    pub relationship: Relationship,
+    pub requester_sharing: FamilySharing,
+    pub relative_sharing: FamilySharing,
+    pub requested_at: u64,
+    pub accepted_at: Option<u64>,
+    pub removed_at: Option<u64>,
+}
+
+impl FamilyLink {
+    pub fn is_active(&self) -> bool {
+        self.accepted_at.is_some() && self.removed_at.is_none()
+    }
+}
+
+// A hereditary risk entered by a clinician, e.g. "BRCA1 mutation"
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct HereditaryRiskFlag {
+    pub id: u64,
+    pub patient_id: u64,
+    pub doctor_id: u64,
+    pub condition: String,
+    pub notes: String,
+    pub recorded_at: u64,
+    pub retracted_at: Option<u64>,
+}
+
+// A relative's risk flag as shown to the patient's clinicians
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FamilyRiskFlag {
+    pub relationship: Relationship,
+    pub condition: String,
+    pub notes: Option<String>,
+    pub recorded_at: u64,
+}
+
+impl_storable!(FamilyLink, 256);
+impl_storable!(HereditaryRiskFlag, 2048);
+
+thread_local! {
+    static FAMILY_LINK_STORAGE: RefCell<StableBTreeMap<u64, FamilyLink, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))
+    ));
+
+    static RISK_FLAG_STORAGE: RefCell<StableBTreeMap<u64, HereditaryRiskFlag, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FamilyLinkRequest {
+    pub relative_id: u64,
+    pub relationship: Relationship,
+    pub sharing: FamilySharing,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct RiskFlagPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub condition: String,
+    pub notes: String,
+}
+
+fn save_link(link: &FamilyLink) {
+    FAMILY_LINK_STORAGE.with(|s| s.borrow_mut().insert(link.id, link.clone()));
+}
+
+fn patient_links(patient_id: u64) -> Vec<FamilyLink> {
+    FAMILY_LINK_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, link)| link)
+            .filter(|link| link.requester_id == patient_id || link.relative_id == patient_id)
+            .collect()
+    })
+}
+
+// helper function to get a link the patient is part of
+fn get_patient_link(patient_id: u64, link_id: u64) -> Result<FamilyLink, Error> {
+    patient_links(patient_id)
+        .into_iter()
+        .find(|link| link.id == link_id && link.removed_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("Family link of id: {} not found", link_id),
+        })
+}
+
+fn patient_risk_flags(patient_id: u64) -> Vec<HereditaryRiskFlag> {
+    RISK_FLAG_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, flag)| flag)
+            .filter(|flag| flag.patient_id == patient_id && flag.retracted_at.is_none())
+            .collect()
+    })
+}
+
+// a patient asks another patient to link their records
+#[ic_cdk::update]
+fn request_family_link(
+    consent: PatientConsent,
+    request: FamilyLinkRequest,
+) -> Result<FamilyLink, Error> {
+    let patient = authorize_patient(consent.patient_id, &consent.patient_password)?;
+    if request.relative_id == patient.id
+        || !PATIENT_STORAGE.with(|s| s.borrow().contains_key(&request.relative_id))
+    {
+        return Err(Error::NotFound {
+            msg: format!("Patient of id: {} not found", request.relative_id),
+        });
+    }
+    if patient_links(patient.id).iter().any(|link| {
+        link.removed_at.is_none()
+            && (link.requester_id == request.relative_id || link.relative_id == request.relative_id)
+    }) {
+        return Err(Error::AlreadyInit {
+            msg: format!(
+                "A family link with patient {} already exists",
+                request.relative_id
+            ),
+        });
+    }
+    let link = FamilyLink {
+        id: next_id(),
+        requester_id: patient.id,
+        relative_id: request.relative_id,
+        relationship: request.relationship,
+        requester_sharing: request.sharing,
+        relative_sharing: FamilySharing::Nothing,
+        requested_at: time(),
+        accepted_at: None,
+        removed_at: None,
+    };
+    save_link(&link);
+    Ok(link)
+}
+
+// the relative accepts a link request and chooses what they share
+#[ic_cdk::update]
+fn accept_family_link(
+    consent: PatientConsent,
+    link_id: u64,
+    sharing: FamilySharing,
+) -> Result<FamilyLink, Error> {
+    let patient = authorize_patient(consent.patient_id, &consent.patient_password)?;
+    let link = get_patient_link(patient.id, link_id)?;
+    if link.relative_id != patient.id || link.accepted_at.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: format!("Family link of id: {} is not waiting for you", link.id),
+        });
+    }
+    let accepted = FamilyLink {
+        relative_sharing: sharing,
+        accepted_at: Some(time()),
+        ..link
+    };
+    save_link(&accepted);
+    audit(
+        Actor::Patient(patient.id),
+        None,
+        Some(patient.id),
+        "family_link_accepted",
+        format!(
+            "link {} with patient {}",
+            accepted.id, accepted.requester_id
+        ),
+    );
+    Ok(accepted)
+}
+
+// change what the relative's clinicians may see
+#[ic_cdk::update]
+fn set_family_sharing(
+    consent: PatientConsent,
+    link_id: u64,
+    sharing: FamilySharing,
+) -> Result<FamilyLink, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    let mut link = get_patient_link(patient.id, link_id)?;
+    if link.requester_id == patient.id {
+        link.requester_sharing = sharing;
+    } else {
+        link.relative_sharing = sharing;
+    }
+    save_link(&link);
+    Ok(link)
+}
+
+// either side can remove a link or decline a request at any time
+#[ic_cdk::update]
+fn remove_family_link(consent: PatientConsent, link_id: u64) -> Result<FamilyLink, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    let link = get_patient_link(patient.id, link_id)?;
+    let removed = FamilyLink {
+        removed_at: Some(time()),
+        ..link
+    };
+    save_link(&removed);
+    audit(
+        Actor::Patient(patient.id),
+        None,
+        Some(patient.id),
+        "family_link_removed",
+        format!("link {}", removed.id),
+    );
+    Ok(removed)
+}
+
+#[ic_cdk::query]
+fn get_family_links(consent: PatientConsent) -> Result<Vec<FamilyLink>, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    Ok(patient_links(patient.id)
+        .into_iter()
+        .filter(|link| link.removed_at.is_none())
+        .collect())
+}
+
+// a clinician records a hereditary risk on their patient
+#[ic_cdk::update]
+fn add_hereditary_risk_flag(payload: RiskFlagPayload) -> Result<HereditaryRiskFlag, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    if payload.condition.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Risk flags need a condition".to_string(),
+        });
+    }
+    let flag = HereditaryRiskFlag {
+        id: next_id(),
+        patient_id: patient.id,
+        doctor_id: doctor.id,
+        condition: payload.condition,
+        notes: payload.notes,
+        recorded_at: time(),
+        retracted_at: None,
+    };
+    RISK_FLAG_STORAGE.with(|s| s.borrow_mut().insert(flag.id, flag.clone()));
+    Ok(flag)
+}
+
+#[ic_cdk::update]
+fn retract_hereditary_risk_flag(
+    payload: crate::AccessPayload,
+    flag_id: u64,
+) -> Result<HereditaryRiskFlag, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let flag = patient_risk_flags(patient.id)
+        .into_iter()
+        .find(|flag| flag.id == flag_id)
+        .ok_or(Error::NotFound {
+            msg: format!("Risk flag of id: {} not found", flag_id),
+        })?;
+    let retracted = HereditaryRiskFlag {
+        retracted_at: Some(time()),
+        ..flag
+    };
+    RISK_FLAG_STORAGE.with(|s| s.borrow_mut().insert(retracted.id, retracted.clone()));
+    Ok(retracted)
+}
+
+// the patient's own flags
+#[ic_cdk::query]
+fn get_hereditary_risk_flags(
+    payload: crate::AccessPayload,
+) -> Result<Vec<HereditaryRiskFlag>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    Ok(patient_risk_flags(patient.id))
+}
+
+// risk flags of linked relatives, limited to what each relative shares; reads are audited
+#[ic_cdk::update]
+fn get_family_risk_flags(payload: crate::AccessPayload) -> Result<Vec<FamilyRiskFlag>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let mut flags = vec![];
+    for link in patient_links(patient.id)
+        .iter()
+        .filter(|link| link.is_active())
+    {
+        let (relative_id, relationship, sharing) = if link.requester_id == patient.id {
+            (link.relative_id, link.relationship, link.relative_sharing)
+        } else {
+            (
+                link.requester_id,
+                link.relationship.inverse(),
+                link.requester_sharing,
+            )
+        };
+        if sharing == FamilySharing::Nothing {
+            continue;
+        }
+        flags.extend(
+            patient_risk_flags(relative_id)
+                .into_iter()
+                .map(|flag| FamilyRiskFlag {
+                    relationship,
+                    condition: flag.condition,
+                    notes: (sharing == FamilySharing::ConditionsAndNotes).then_some(flag.notes),
+                    recorded_at: flag.recorded_at,
+                }),
+        );
+    }
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "family_risk_flags_read",
+        format!("{} flags", flags.len()),
+    );
+    Ok(flags)
+}
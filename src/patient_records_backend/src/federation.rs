@@ -0,0 +1,521 @@
+use crate::time;
+use crate::{
+    audit, authenticate_patient, authorize_controller, authorize_hospital, authorize_patient,
+    authorize_patient_access, caller, check_not_sealed, check_residency, impl_storable,
+    issue_consent_receipt, next_id, patient_allergies, patient_header, patient_records,
+    require_premium, to_hex, Actor, Allergy, BloodType, ConsentAction, Error, MedicalRecord,
+    Memory, Patient, PatientAccess, PatientConsent, PremiumFeature, TransferDestination,
+    DOCTOR_STORAGE, MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use candid::Principal;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// A record system run by another organisation that this canister exchanges data with
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FederationPeer {
+    pub id: u64,
+    pub canister_id: Principal,
+    pub name: String,
+    pub added_at: u64,
+}
+
+// The identifier a patient shares across record systems, e.g. a national health number. It is
+// only used once a hospital of the patient has checked the patient's documents for it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FederatedIdentity {
+    pub patient_id: u64,
+    pub federation_id: String,
+    pub linked_at: u64,
+    pub verified_by: Option<u64>,
+    pub verified_at: Option<u64>,
+}
+
+// A patient's permission to read their data on this canister from peers. The patient grants
+// it on every canister that holds their data, and each canister only honours its own tokens
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FederationConsent {
+    pub token: String,
+    pub patient_id: u64,
+    pub federation_id: String,
+    pub expires_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FederatedRecord {
+    pub name: String,
+    pub blood_type: Option<BloodType>,
+    pub records: Vec<MedicalRecord>,
+    pub allergies: Vec<Allergy>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PeerRecord {
+    pub canister_id: Principal,
+    pub peer_name: String,
+    // None when the peer has no patient with this identifier
+    pub record: Option<FederatedRecord>,
+    pub error: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FederatedView {
+    pub federation_id: String,
+    pub local: FederatedRecord,
+    pub peers: Vec<PeerRecord>,
+}
+
+// What a peer sends when forwarding a read, with the token the patient was issued on the
+// receiving canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FederationRequest {
+    pub federation_id: String,
+    pub consent_token: String,
+}
+
+// A consent token the patient was issued on a peer, to be forwarded to that peer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PeerConsentToken {
+    pub canister_id: Principal,
+    pub token: String,
+}
+
+impl_storable!(FederationPeer, 256);
+impl_storable!(FederatedIdentity, 256);
+impl_storable!(FederationConsent, 512);
+
+thread_local! {
+    static PEER_STORAGE: RefCell<StableBTreeMap<u64, FederationPeer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    static IDENTITY_STORAGE: RefCell<StableBTreeMap<u64, FederatedIdentity, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+
+    static FEDERATION_CONSENT_STORAGE: RefCell<StableBTreeMap<u64, FederationConsent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct LinkIdentityPayload {
+    pub patient_id: u64,
+    pub patient_password: String,
+    pub federation_id: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct VerifyIdentityPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub patient_id: u64,
+    // the identifier as read from the patient's documents
+    pub federation_id: String,
+}
+
+const MAX_FEDERATION_ID_LEN: usize = 64;
+
+fn federation_peers() -> Vec<FederationPeer> {
+    PEER_STORAGE.with(|s| s.borrow().iter().map(|(_, peer)| peer).collect())
+}
+
+// the patient's identifier once a hospital verified it
+fn federation_id_of(patient_id: u64) -> Result<String, Error> {
+    IDENTITY_STORAGE
+        .with(|s| s.borrow().get(&patient_id))
+        .filter(|identity| identity.verified_at.is_some())
+        .map(|identity| identity.federation_id)
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "Patient of id: {} has no verified federated identity",
+                patient_id
+            ),
+        })
+}
+
+// whether another patient holds the identifier with a verification
+fn verified_elsewhere(patient_id: u64, federation_id: &str) -> bool {
+    IDENTITY_STORAGE.with(|s| {
+        s.borrow().iter().any(|(id, identity)| {
+            id != patient_id
+                && identity.verified_at.is_some()
+                && identity.federation_id == federation_id
+        })
+    })
+}
+
+fn federated_record(patient: Patient) -> FederatedRecord {
+    FederatedRecord {
+        records: patient_records(patient.id),
+        allergies: patient_allergies(patient.id),
+        name: patient.name,
+        blood_type: patient.blood_type,
+    }
+}
+
+// register a peer record system; the peer has to register this canister as well
+#[ic_cdk::update]
+fn register_federation_peer(canister_id: Principal, name: String) -> Result<FederationPeer, Error> {
+    authorize_controller()?;
+    if federation_peers()
+        .iter()
+        .any(|peer| peer.canister_id == canister_id)
+    {
+        return Err(Error::AlreadyInit {
+            msg: format!("Peer {} is already registered", canister_id),
+        });
+    }
+    let peer = FederationPeer {
+        id: next_id(),
+        canister_id,
+        name,
+        added_at: time(),
+    };
+    PEER_STORAGE.with(|s| s.borrow_mut().insert(peer.id, peer.clone()));
+    Ok(peer)
+}
+
+#[ic_cdk::update]
+fn remove_federation_peer(peer_id: u64) -> Result<FederationPeer, Error> {
+    authorize_controller()?;
+    PEER_STORAGE
+        .with(|s| s.borrow_mut().remove(&peer_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Peer of id: {} not found", peer_id),
+        })
+}
+
+#[ic_cdk::query]
+fn get_federation_peers() -> Vec<FederationPeer> {
+    federation_peers()
+}
+
+fn clean_federation_id(federation_id: &str) -> Result<String, Error> {
+    let federation_id = federation_id.trim().to_string();
+    if federation_id.is_empty() || federation_id.len() > MAX_FEDERATION_ID_LEN {
+        return Err(Error::InvalidPayload {
+            msg: format!("Federation ids hold 1 to {} bytes", MAX_FEDERATION_ID_LEN),
+        });
+    }
+    Ok(federation_id)
+}
+
+// patient opts in to federation by claiming their shared identifier. The claim is not used
+// until one of the patient's hospitals verifies it
+#[ic_cdk::update]
+fn link_federated_identity(payload: LinkIdentityPayload) -> Result<FederatedIdentity, Error> {
+    let patient = authorize_patient(payload.patient_id, &payload.patient_password)?;
+    let federation_id = clean_federation_id(&payload.federation_id)?;
+    if verified_elsewhere(patient.id, &federation_id) {
+        return Err(Error::AlreadyInit {
+            msg: "Federation id is linked to another patient".to_string(),
+        });
+    }
+    let identity = FederatedIdentity {
+        patient_id: patient.id,
+        federation_id,
+        linked_at: time(),
+        verified_by: None,
+        verified_at: None,
+    };
+    IDENTITY_STORAGE.with(|s| s.borrow_mut().insert(patient.id, identity.clone()));
+    Ok(identity)
+}
+
+// a hospital of the patient confirms the claimed identifier against the patient's documents
+#[ic_cdk::update]
+fn verify_federated_identity(payload: VerifyIdentityPayload) -> Result<FederatedIdentity, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    match patient_header(payload.patient_id) {
+        Some(patient) if patient.hospitals_ids.contains(&hospital.id) => {}
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!("Patient of id: {} not found", payload.patient_id),
+            })
+        }
+    }
+    let federation_id = clean_federation_id(&payload.federation_id)?;
+    let identity = IDENTITY_STORAGE
+        .with(|s| s.borrow().get(&payload.patient_id))
+        .filter(|identity| identity.federation_id == federation_id)
+        .ok_or(Error::InvalidPayload {
+            msg: "The patient has not claimed this federation id".to_string(),
+        })?;
+    if verified_elsewhere(payload.patient_id, &federation_id) {
+        return Err(Error::AlreadyInit {
+            msg: "Federation id is linked to another patient".to_string(),
+        });
+    }
+    let verified = FederatedIdentity {
+        verified_by: Some(hospital.id),
+        verified_at: Some(time()),
+        ..identity
+    };
+    IDENTITY_STORAGE.with(|s| s.borrow_mut().insert(verified.patient_id, verified.clone()));
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        Some(verified.patient_id),
+        "federated_identity_verified",
+        verified.federation_id.clone(),
+    );
+    Ok(verified)
+}
+
+// patient issues a token that lets their doctors pull the federated view until it expires. The
+// same call on a peer issues the token that peer accepts
+#[ic_cdk::update]
+async fn grant_federation_consent(
+    consent: PatientConsent,
+    expires_at: u64,
+) -> Result<FederationConsent, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    let federation_id = federation_id_of(patient.id)?;
+    if expires_at <= time() {
+        return Err(Error::InvalidPayload {
+            msg: "Consent must expire in the future".to_string(),
+        });
+    }
+    let (random,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| Error::InvalidPayload {
+            msg: format!("Could not create token: {:?} {}", code, msg),
+        })?;
+    let grant = FederationConsent {
+        token: to_hex(&random),
+        patient_id: patient.id,
+        federation_id,
+        expires_at,
+    };
+    let grant_id = next_id();
+    FEDERATION_CONSENT_STORAGE.with(|s| s.borrow_mut().insert(grant_id, grant.clone()));
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Granted,
+        "federation",
+        grant_id,
+        format!("federation peers for {}", grant.federation_id),
+        vec![],
+        Some(grant.expires_at),
+    );
+    Ok(grant)
+}
+
+// assemble the patient's record from this canister and every peer the patient gave a token for
+#[ic_cdk::update]
+async fn get_federated_record(
+    patient_id: u64,
+    access: PatientAccess,
+    consent_token: String,
+    peer_tokens: Vec<PeerConsentToken>,
+) -> Result<FederatedView, Error> {
+    let (patient, actor) = authorize_patient_access(patient_id, &access)?;
+    // federation is a premium feature of the doctor's hospital, patients reach it for free
+    if let PatientAccess::Doctor { doctor_id, .. } = &access {
+        if let Some(doctor) = DOCTOR_STORAGE.with(|s| s.borrow().get(doctor_id)) {
+            require_premium(doctor.hospital_id, PremiumFeature::Federation)?;
+        }
+    }
+    let now = time();
+    let grant = FEDERATION_CONSENT_STORAGE
+        .with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, grant)| grant)
+                .find(|grant| grant.token == consent_token && grant.patient_id == patient.id)
+        })
+        .filter(|grant| grant.expires_at > now)
+        .ok_or(Error::Unauthorized {
+            msg: "No valid federation consent for this patient".to_string(),
+        })?;
+    audit(
+        actor,
+        None,
+        Some(patient.id),
+        "federated_read",
+        format!("{} peers", federation_peers().len()),
+    );
+
+    let mut peers = vec![];
+    for peer in federation_peers() {
+        let Some(peer_token) = peer_tokens
+            .iter()
+            .find(|token| token.canister_id == peer.canister_id)
+        else {
+            peers.push(PeerRecord {
+                canister_id: peer.canister_id,
+                peer_name: peer.name,
+                record: None,
+                error: Some("no consent token for this peer".to_string()),
+            });
+            continue;
+        };
+        let request = FederationRequest {
+            federation_id: grant.federation_id.clone(),
+            consent_token: peer_token.token.clone(),
+        };
+        let reply: Result<(Result<Option<FederatedRecord>, Error>,), _> =
+            ic_cdk::call(peer.canister_id, "federation_fetch", (request,)).await;
+        let (record, error) = match reply {
+            Ok((Ok(record),)) => (record, None),
+            Ok((Err(_),)) => (None, Some("peer refused the request".to_string())),
+            Err((code, msg)) => (None, Some(format!("{:?} {}", code, msg))),
+        };
+        peers.push(PeerRecord {
+            canister_id: peer.canister_id,
+            peer_name: peer.name,
+            record,
+            error,
+        });
+    }
+    Ok(FederatedView {
+        federation_id: grant.federation_id,
+        local: federated_record(patient),
+        peers,
+    })
+}
+
+// answer a forwarded read from a registered peer. Only a consent this canister issued to its own
+// patient, for the patient's verified identifier, is honoured
+#[ic_cdk::update]
+fn federation_fetch(request: FederationRequest) -> Result<Option<FederatedRecord>, Error> {
+    let caller = caller();
+    if !federation_peers()
+        .iter()
+        .any(|peer| peer.canister_id == caller)
+    {
+        return Err(Error::Unauthorized {
+            msg: "Caller is not a registered federation peer".to_string(),
+        });
+    }
+    let refused = || Error::Unauthorized {
+        msg: "Consent token is not valid here".to_string(),
+    };
+    let now = time();
+    let grant = FEDERATION_CONSENT_STORAGE
+        .with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, grant)| grant)
+                .find(|grant| !grant.token.is_empty() && grant.token == request.consent_token)
+        })
+        .filter(|grant| grant.expires_at > now && grant.federation_id == request.federation_id)
+        .ok_or_else(refused)?;
+    if federation_id_of(grant.patient_id).ok() != Some(grant.federation_id.clone()) {
+        return Err(refused());
+    }
+    let patient = match PATIENT_STORAGE.with(|s| s.borrow().get(&grant.patient_id)) {
+        Some(patient) => patient,
+        None => return Ok(None),
+    };
+    check_not_sealed(patient.id)?;
+    check_residency(
+        &patient,
+        &TransferDestination::FederationPeer(caller),
+        Actor::System,
+        None,
+        "federation fetch",
+    )?;
+    audit(
+        Actor::System,
+        None,
+        Some(patient.id),
+        "federation_fetch",
+        format!("peer {}", caller),
+    );
+    Ok(Some(federated_record(patient)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{clinic, must, principal, refused, sign_in, Clinic, PASSWORD};
+
+    const FEDERATION_ID: &str = "NHN-1234";
+
+    const PEER: u8 = 9;
+
+    // a peer registered by the controllers and a patient whose identifier a hospital checked
+    fn federated_clinic(verified: bool) -> Clinic {
+        let clinic = clinic();
+        PEER_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                1,
+                FederationPeer {
+                    id: 1,
+                    canister_id: principal(PEER),
+                    name: "Peer".to_string(),
+                    added_at: time(),
+                },
+            )
+        });
+        must(link_federated_identity(LinkIdentityPayload {
+            patient_id: clinic.patient_id,
+            patient_password: PASSWORD.to_string(),
+            federation_id: FEDERATION_ID.to_string(),
+        }));
+        if verified {
+            must(verify_federated_identity(VerifyIdentityPayload {
+                hospital_id: clinic.hospital_id,
+                hospital_password: PASSWORD.to_string(),
+                patient_id: clinic.patient_id,
+                federation_id: FEDERATION_ID.to_string(),
+            }));
+        }
+        clinic
+    }
+
+    // what grant_federation_consent stores, without the random token
+    fn grant(clinic: &Clinic, token: &str, expires_at: u64) {
+        let grant = FederationConsent {
+            token: token.to_string(),
+            patient_id: clinic.patient_id,
+            federation_id: FEDERATION_ID.to_string(),
+            expires_at,
+        };
+        FEDERATION_CONSENT_STORAGE.with(|s| s.borrow_mut().insert(next_id(), grant));
+    }
+
+    fn fetch(federation_id: &str, token: &str) -> Result<Option<FederatedRecord>, Error> {
+        federation_fetch(FederationRequest {
+            federation_id: federation_id.to_string(),
+            consent_token: token.to_string(),
+        })
+    }
+
+    #[test]
+    fn a_peer_with_the_patients_token_reads_the_record() {
+        let clinic = federated_clinic(true);
+        grant(&clinic, "token", time() + 1);
+        sign_in(principal(PEER));
+        assert!(matches!(fetch(FEDERATION_ID, "token"), Ok(Some(_))));
+    }
+
+    #[test]
+    fn forged_and_misused_tokens_are_refused() {
+        let clinic = federated_clinic(true);
+        grant(&clinic, "token", time() + 1);
+        grant(&clinic, "expired", time());
+        sign_in(principal(PEER));
+        assert!(refused(fetch(FEDERATION_ID, "forged")));
+        assert!(refused(fetch(FEDERATION_ID, "")));
+        assert!(refused(fetch(FEDERATION_ID, "expired")));
+        assert!(refused(fetch("NHN-9999", "token")));
+        sign_in(principal(10));
+        assert!(refused(fetch(FEDERATION_ID, "token")));
+    }
+
+    #[test]
+    fn an_unverified_identity_is_not_shared() {
+        let clinic = federated_clinic(false);
+        grant(&clinic, "token", time() + 1);
+        sign_in(principal(PEER));
+        assert!(refused(fetch(FEDERATION_ID, "token")));
+    }
+}
@@ -0,0 +1,409 @@
+// Property-based state fuzzing: random sequences of calls go through the endpoints against a
+// simulated runtime, and the invariants the helper functions are meant to keep are checked
+// after every call. Each case runs on a fresh thread, so it starts from empty stores and a
+// failing sequence replays exactly
+use crate::test_support::{advance_clock, error_message, PASSWORD};
+use crate::{
+    add_doctor, add_hospital, add_medical_record, add_patient, all_patient_records,
+    assign_patient_to_doctor, authorize_controller, edit_doctor, edit_patient, patient_header,
+    record_count, AddPatientToDoctor, DoctorPayload, EditDoctor, EditPatientPayload, Error,
+    HospitalPayload, MedicalRecordPayload, PatientPayload, RecordKind, DOCTOR_STORAGE,
+    HOSPITAL_STORAGE, ID_COUNTER, PATIENT_STORAGE,
+};
+use ic_stable_structures::Storable;
+use proptest::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+const WRONG_PASSWORD: &str = "not-the-password";
+const MINUTE_NS: u64 = 60_000_000_000;
+
+// One call, with indexes into what the sequence created so far
+#[derive(Clone, Debug)]
+enum Op {
+    AddHospital,
+    AddDoctor {
+        hospital: usize,
+        authorized: bool,
+    },
+    AddPatient,
+    Assign {
+        doctor: usize,
+        patient: usize,
+        authorized: bool,
+    },
+    MoveDoctor {
+        doctor: usize,
+        hospital: usize,
+        authorized: bool,
+    },
+    EditPatient {
+        patient: usize,
+        authorized: bool,
+    },
+    AddRecord {
+        doctor: usize,
+        patient: usize,
+        authorized: bool,
+    },
+    ControllerOnly,
+    Tick {
+        minutes: u64,
+    },
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        Just(Op::AddHospital),
+        (any::<usize>(), any::<bool>()).prop_map(|(hospital, authorized)| Op::AddDoctor {
+            hospital,
+            authorized
+        }),
+        Just(Op::AddPatient),
+        (any::<usize>(), any::<usize>(), any::<bool>()).prop_map(
+            |(doctor, patient, authorized)| Op::Assign {
+                doctor,
+                patient,
+                authorized
+            }
+        ),
+        (any::<usize>(), any::<usize>(), any::<bool>()).prop_map(
+            |(doctor, hospital, authorized)| Op::MoveDoctor {
+                doctor,
+                hospital,
+                authorized
+            }
+        ),
+        (any::<usize>(), any::<bool>()).prop_map(|(patient, authorized)| Op::EditPatient {
+            patient,
+            authorized
+        }),
+        (any::<usize>(), any::<usize>(), any::<bool>()).prop_map(
+            |(doctor, patient, authorized)| Op::AddRecord {
+                doctor,
+                patient,
+                authorized
+            }
+        ),
+        Just(Op::ControllerOnly),
+        (1u64..600).prop_map(|minutes| Op::Tick { minutes }),
+    ]
+}
+
+// What the sequence created, to pick targets and check counts against
+#[derive(Default)]
+struct Model {
+    hospitals: Vec<u64>,
+    doctors: Vec<u64>,
+    patients: Vec<u64>,
+    records: u64,
+}
+
+fn nth(ids: &[u64], index: usize) -> Option<u64> {
+    if ids.is_empty() {
+        None
+    } else {
+        Some(ids[index % ids.len()])
+    }
+}
+
+fn password(authorized: bool) -> String {
+    if authorized { PASSWORD } else { WRONG_PASSWORD }.to_string()
+}
+
+// digest of the entities and their links, which a refused call must leave untouched
+fn fingerprint() -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    PATIENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .for_each(|(_, p)| hasher.update(p.to_bytes()))
+    });
+    DOCTOR_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .for_each(|(_, d)| hasher.update(d.to_bytes()))
+    });
+    HOSPITAL_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .for_each(|(_, h)| hasher.update(h.to_bytes()))
+    });
+    hasher.update(record_count().to_le_bytes());
+    hasher.finalize().to_vec()
+}
+
+// run one call; None when the sequence has nothing to call it on yet
+fn apply(op: &Op, model: &mut Model) -> Option<(bool, Result<(), Error>)> {
+    let result = match *op {
+        Op::AddHospital => add_hospital(HospitalPayload {
+            name: format!("Hospital {}", model.hospitals.len()),
+            address: "1 Fuzz Road".to_string(),
+            password: PASSWORD.to_string(),
+            city: "Nairobi".to_string(),
+            ..Default::default()
+        })
+        .map(|hospital| model.hospitals.push(hospital.id)),
+        Op::AddDoctor {
+            hospital,
+            authorized,
+        } => add_doctor(DoctorPayload {
+            name: format!("Doctor {}", model.doctors.len()),
+            hospital_id: nth(&model.hospitals, hospital)?,
+            password: PASSWORD.to_string(),
+            hospital_password: password(authorized),
+        })
+        .map(|doctor| model.doctors.push(doctor.id)),
+        Op::AddPatient => add_patient(PatientPayload {
+            name: format!("Patient {}", model.patients.len()),
+            history: "No known conditions".to_string(),
+            password: PASSWORD.to_string(),
+            language: None,
+        })
+        .map(|patient| model.patients.push(patient.id)),
+        Op::Assign {
+            doctor,
+            patient,
+            authorized,
+        } => assign_patient_to_doctor(AddPatientToDoctor {
+            doctor_id: nth(&model.doctors, doctor)?,
+            patient_id: nth(&model.patients, patient)?,
+            doctor_password: PASSWORD.to_string(),
+            patient_password: password(authorized),
+        })
+        .map(|_| ()),
+        Op::MoveDoctor {
+            doctor,
+            hospital,
+            authorized,
+        } => edit_doctor(EditDoctor {
+            name: "Moved doctor".to_string(),
+            doctor_id: nth(&model.doctors, doctor)?,
+            hospital_id: nth(&model.hospitals, hospital)?,
+            doctor_password: password(authorized),
+            hospital_password: PASSWORD.to_string(),
+        })
+        .map(|_| ()),
+        Op::EditPatient {
+            patient,
+            authorized,
+        } => edit_patient(EditPatientPayload {
+            name: "Renamed patient".to_string(),
+            password: password(authorized),
+            patient_id: nth(&model.patients, patient)?,
+        })
+        .map(|_| ()),
+        Op::AddRecord {
+            doctor,
+            patient,
+            authorized,
+        } => add_medical_record(MedicalRecordPayload {
+            doctor_id: nth(&model.doctors, doctor)?,
+            doctor_password: password(authorized),
+            patient_id: nth(&model.patients, patient)?,
+            kind: RecordKind::Note,
+            title: "Visit".to_string(),
+            body: "Seen in clinic".to_string(),
+            sensitivity: None,
+        })
+        .map(|_| model.records += 1),
+        Op::ControllerOnly => return Some((false, authorize_controller())),
+        Op::Tick { minutes } => {
+            advance_clock(minutes * MINUTE_NS);
+            Ok(())
+        }
+    };
+    let authorized = match *op {
+        Op::AddDoctor { authorized, .. }
+        | Op::Assign { authorized, .. }
+        | Op::MoveDoctor { authorized, .. }
+        | Op::EditPatient { authorized, .. }
+        | Op::AddRecord { authorized, .. } => authorized,
+        _ => true,
+    };
+    Some((authorized, result))
+}
+
+// links must point at existing entities and be mirrored on the other side, without repeats
+fn check_links() -> Result<(), String> {
+    let patients: Vec<_> = PATIENT_STORAGE.with(|s| s.borrow().iter().collect());
+    let doctors: Vec<_> = DOCTOR_STORAGE.with(|s| s.borrow().iter().collect());
+    let hospitals: Vec<_> = HOSPITAL_STORAGE.with(|s| s.borrow().iter().collect());
+    let doctor = |id: &u64| doctors.iter().find(|(d, _)| d == id).map(|(_, d)| d);
+    let patient = |id: &u64| patients.iter().find(|(p, _)| p == id).map(|(_, p)| p);
+    let hospital = |id: &u64| hospitals.iter().find(|(h, _)| h == id).map(|(_, h)| h);
+    let unique = |ids: &[u64]| ids.iter().collect::<HashSet<_>>().len() == ids.len();
+
+    for (id, p) in &patients {
+        if !unique(&p.doctors_ids) || !unique(&p.hospitals_ids) {
+            return Err(format!("patient {} lists a doctor or hospital twice", id));
+        }
+        match patient_header(*id) {
+            Some(h)
+                if h.name == p.name
+                    && h.password == p.password
+                    && h.doctors_ids == p.doctors_ids
+                    && h.hospitals_ids == p.hospitals_ids => {}
+            _ => return Err(format!("patient {} header out of step", id)),
+        }
+        for doctor_id in &p.doctors_ids {
+            match doctor(doctor_id) {
+                Some(d) if d.patient_ids.contains(id) => {}
+                _ => {
+                    return Err(format!(
+                        "patient {} -> doctor {} not mirrored",
+                        id, doctor_id
+                    ))
+                }
+            }
+        }
+        for hospital_id in &p.hospitals_ids {
+            match hospital(hospital_id) {
+                Some(h) if h.patients_ids.contains(id) => {}
+                _ => {
+                    return Err(format!(
+                        "patient {} -> hospital {} not mirrored",
+                        id, hospital_id
+                    ))
+                }
+            }
+        }
+    }
+    for (id, d) in &doctors {
+        if !unique(&d.patient_ids) {
+            return Err(format!("doctor {} lists a patient twice", id));
+        }
+        match hospital(&d.hospital_id) {
+            Some(h) if h.doctors_ids.contains(id) => {}
+            _ => {
+                return Err(format!(
+                    "doctor {} -> hospital {} not mirrored",
+                    id, d.hospital_id
+                ))
+            }
+        }
+        for patient_id in &d.patient_ids {
+            match patient(patient_id) {
+                Some(p) if p.doctors_ids.contains(id) => {}
+                _ => {
+                    return Err(format!(
+                        "doctor {} -> patient {} not mirrored",
+                        id, patient_id
+                    ))
+                }
+            }
+        }
+    }
+    for (id, h) in &hospitals {
+        if !unique(&h.doctors_ids) || !unique(&h.patients_ids) {
+            return Err(format!("hospital {} lists a doctor or patient twice", id));
+        }
+        for doctor_id in &h.doctors_ids {
+            match doctor(doctor_id) {
+                Some(d) if d.hospital_id == *id => {}
+                _ => {
+                    return Err(format!(
+                        "hospital {} -> doctor {} not mirrored",
+                        id, doctor_id
+                    ))
+                }
+            }
+        }
+        for patient_id in &h.patients_ids {
+            match patient(patient_id) {
+                Some(p) if p.hospitals_ids.contains(id) => {}
+                _ => {
+                    return Err(format!(
+                        "hospital {} -> patient {} not mirrored",
+                        id, patient_id
+                    ))
+                }
+            }
+        }
+    }
+    for (id, _) in &patients {
+        for record in all_patient_records(*id) {
+            if record.doctor_id.is_some_and(|d| doctor(&d).is_none()) {
+                return Err(format!("record {} has a missing author", record.id));
+            }
+        }
+    }
+    Ok(())
+}
+
+// ids come from one counter, so they are unique across stores and below the counter
+fn check_counters(model: &Model) -> Result<(), String> {
+    let next = ID_COUNTER.with(|counter| *counter.borrow().get());
+    let mut ids: Vec<u64> = vec![];
+    ids.extend(PATIENT_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect::<Vec<_>>()));
+    ids.extend(DOCTOR_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect::<Vec<_>>()));
+    ids.extend(HOSPITAL_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect::<Vec<_>>()));
+    if ids.iter().collect::<HashSet<_>>().len() != ids.len() {
+        return Err("an id is used by two entities".to_string());
+    }
+    if ids.iter().any(|id| *id >= next) {
+        return Err(format!("an id is not below the counter {}", next));
+    }
+    let stored = (
+        HOSPITAL_STORAGE.with(|s| s.borrow().len()),
+        DOCTOR_STORAGE.with(|s| s.borrow().len()),
+        PATIENT_STORAGE.with(|s| s.borrow().len()),
+        record_count(),
+    );
+    let created = (
+        model.hospitals.len() as u64,
+        model.doctors.len() as u64,
+        model.patients.len() as u64,
+        model.records,
+    );
+    if stored != created {
+        return Err(format!("stored {:?}, created {:?}", stored, created));
+    }
+    Ok(())
+}
+
+fn run(ops: Vec<Op>) -> Result<(), String> {
+    let mut model = Model::default();
+    for (step, op) in ops.iter().enumerate() {
+        let before = fingerprint();
+        let Some((authorized, result)) = apply(op, &mut model) else {
+            continue;
+        };
+        if !authorized {
+            match &result {
+                Err(Error::Unauthorized { .. }) => {}
+                Err(error) => {
+                    return Err(format!(
+                        "step {} {:?}: refused with {} instead of unauthorized",
+                        step,
+                        op,
+                        error_message(error)
+                    ))
+                }
+                Ok(_) => return Err(format!("step {} {:?}: went through unauthorized", step, op)),
+            }
+            if fingerprint() != before {
+                return Err(format!(
+                    "step {} {:?}: refused call changed state",
+                    step, op
+                ));
+            }
+        }
+        check_links()
+            .and_then(|_| check_counters(&model))
+            .map_err(|broken| format!("step {} {:?}: {}", step, op, broken))?;
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn call_sequences_keep_invariants(ops in prop::collection::vec(op(), 1..80)) {
+        let outcome = std::thread::spawn(move || run(ops))
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        prop_assert!(outcome.is_ok(), "{}", outcome.unwrap_err());
+    }
+}
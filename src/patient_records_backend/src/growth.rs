@@ -0,0 +1,215 @@
+use crate::{
+    authorize_patient_access, patient_vitals, EncounterEntryKind, Error, PatientAccess, Sex,
+};
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MONTH_DAYS: f64 = 30.4375;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum GrowthMetric {
+    Height,
+    Weight,
+    Bmi,
+}
+
+// One measurement on the chart; percentile is None outside the reference range
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct GrowthPoint {
+    pub recorded_at: u64,
+    pub age_months: f64,
+    pub value: f64,
+    pub z_score: Option<f64>,
+    pub percentile: Option<f64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct GrowthChart {
+    pub patient_id: u64,
+    pub metric: GrowthMetric,
+    pub sex: Sex,
+    pub points: Vec<GrowthPoint>,
+}
+
+// WHO Child Growth Standards LMS parameters (age in months, L, M, S) at selected ages from
+// birth to five years, interpolated linearly in between
+type LmsTable = &'static [(f64, f64, f64, f64)];
+
+const WEIGHT_BOYS: LmsTable = &[
+    (0.0, 0.3487, 3.3464, 0.14602),
+    (3.0, 0.1738, 6.3762, 0.11727),
+    (6.0, 0.1257, 7.9340, 0.11080),
+    (9.0, 0.0917, 8.9014, 0.10881),
+    (12.0, 0.0644, 9.6479, 0.10925),
+    (18.0, 0.0211, 10.9385, 0.11119),
+    (24.0, -0.0137, 12.1515, 0.11426),
+    (36.0, -0.0656, 14.3429, 0.12036),
+    (48.0, -0.1034, 16.3489, 0.12632),
+    (60.0, -0.1373, 18.3366, 0.13030),
+];
+
+const WEIGHT_GIRLS: LmsTable = &[
+    (0.0, 0.3809, 3.2322, 0.14171),
+    (3.0, 0.1395, 5.8458, 0.12385),
+    (6.0, 0.0809, 7.2970, 0.12204),
+    (9.0, 0.0363, 8.2254, 0.12068),
+    (12.0, -0.0022, 8.9481, 0.12014),
+    (18.0, -0.0595, 10.2315, 0.12177),
+    (24.0, -0.1097, 11.4775, 0.12478),
+    (36.0, -0.2033, 13.8503, 0.13171),
+    (48.0, -0.2886, 16.0697, 0.13776),
+    (60.0, -0.3653, 18.2193, 0.14230),
+];
+
+const HEIGHT_BOYS: LmsTable = &[
+    (0.0, 1.0, 49.8842, 0.03795),
+    (3.0, 1.0, 61.4292, 0.03328),
+    (6.0, 1.0, 67.6236, 0.03165),
+    (9.0, 1.0, 72.0000, 0.03175),
+    (12.0, 1.0, 75.7488, 0.03137),
+    (18.0, 1.0, 82.2587, 0.03272),
+    (24.0, 1.0, 87.8161, 0.03507),
+    (36.0, 1.0, 96.0835, 0.03777),
+    (48.0, 1.0, 103.3273, 0.03963),
+    (60.0, 1.0, 110.0000, 0.04063),
+];
+
+const HEIGHT_GIRLS: LmsTable = &[
+    (0.0, 1.0, 49.1477, 0.03790),
+    (3.0, 1.0, 59.8029, 0.03541),
+    (6.0, 1.0, 65.7311, 0.03448),
+    (9.0, 1.0, 70.1435, 0.03473),
+    (12.0, 1.0, 74.0150, 0.03500),
+    (18.0, 1.0, 80.7079, 0.03608),
+    (24.0, 1.0, 86.4153, 0.03764),
+    (36.0, 1.0, 95.0515, 0.03837),
+    (48.0, 1.0, 102.7312, 0.04047),
+    (60.0, 1.0, 109.4233, 0.04140),
+];
+
+const BMI_BOYS: LmsTable = &[
+    (0.0, -0.3053, 13.4069, 0.09560),
+    (3.0, -0.0090, 16.8987, 0.08165),
+    (6.0, -0.0631, 17.3422, 0.08217),
+    (9.0, -0.1194, 17.1463, 0.08210),
+    (12.0, -0.1603, 16.9083, 0.08103),
+    (18.0, -0.2202, 16.3658, 0.07992),
+    (24.0, -0.2592, 16.0189, 0.07991),
+    (36.0, -0.3720, 15.6550, 0.07853),
+    (48.0, -0.4768, 15.3984, 0.07996),
+    (60.0, -0.5712, 15.2641, 0.08245),
+];
+
+const BMI_GIRLS: LmsTable = &[
+    (0.0, -0.0631, 13.3363, 0.09272),
+    (3.0, 0.0400, 16.1459, 0.08612),
+    (6.0, -0.0280, 16.6958, 0.08582),
+    (9.0, -0.0960, 16.5780, 0.08524),
+    (12.0, -0.1480, 16.3861, 0.08459),
+    (18.0, -0.2180, 15.9183, 0.08451),
+    (24.0, -0.2680, 15.6920, 0.08543),
+    (36.0, -0.4080, 15.4054, 0.08768),
+    (48.0, -0.5190, 15.2533, 0.09058),
+    (60.0, -0.6160, 15.2000, 0.09381),
+];
+
+fn reference_table(metric: GrowthMetric, sex: Sex) -> LmsTable {
+    match (metric, sex) {
+        (GrowthMetric::Height, Sex::Male) => HEIGHT_BOYS,
+        (GrowthMetric::Height, Sex::Female) => HEIGHT_GIRLS,
+        (GrowthMetric::Weight, Sex::Male) => WEIGHT_BOYS,
+        (GrowthMetric::Weight, Sex::Female) => WEIGHT_GIRLS,
+        (GrowthMetric::Bmi, Sex::Male) => BMI_BOYS,
+        (GrowthMetric::Bmi, Sex::Female) => BMI_GIRLS,
+    }
+}
+
+// LMS parameters at an age, None outside the table
+fn lms_at(table: LmsTable, age_months: f64) -> Option<(f64, f64, f64)> {
+    table.windows(2).find_map(|pair| {
+        let (a0, l0, m0, s0) = pair[0];
+        let (a1, l1, m1, s1) = pair[1];
+        if age_months < a0 || age_months > a1 {
+            return None;
+        }
+        let t = (age_months - a0) / (a1 - a0);
+        Some((l0 + t * (l1 - l0), m0 + t * (m1 - m0), s0 + t * (s1 - s0)))
+    })
+}
+
+fn z_score(value: f64, (l, m, s): (f64, f64, f64)) -> f64 {
+    if l.abs() < 1e-9 {
+        (value / m).ln() / s
+    } else {
+        ((value / m).powf(l) - 1.0) / (l * s)
+    }
+}
+
+// standard normal CDF as a percentile, using the Abramowitz-Stegun erf approximation
+fn percentile(z: f64) -> f64 {
+    let x = z.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let erf = 1.0 - poly * (-x * x).exp();
+    let cdf = if z >= 0.0 {
+        0.5 * (1.0 + erf)
+    } else {
+        0.5 * (1.0 - erf)
+    };
+    cdf * 100.0
+}
+
+// measurements of a metric from the patient's recorded vitals, with WHO percentiles
+#[ic_cdk::query]
+fn get_growth_chart(
+    patient_id: u64,
+    access: PatientAccess,
+    metric: GrowthMetric,
+) -> Result<GrowthChart, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    let (date_of_birth, sex) = match (patient.date_of_birth, patient.sex) {
+        (Some(date_of_birth), Some(sex)) => (date_of_birth, sex),
+        _ => {
+            return Err(Error::InvalidPayload {
+                msg: "Growth charts need the patient's date of birth and sex".to_string(),
+            })
+        }
+    };
+    let table = reference_table(metric, sex);
+
+    let mut points: Vec<GrowthPoint> = patient_vitals(patient.id)
+        .into_iter()
+        .filter_map(|entry| {
+            let vitals = match entry.kind {
+                EncounterEntryKind::Vitals(vitals) => vitals,
+                _ => return None,
+            };
+            let value = match metric {
+                GrowthMetric::Height => vitals.height_cm?,
+                GrowthMetric::Weight => vitals.weight_kg?,
+                GrowthMetric::Bmi => {
+                    let height_m = vitals.height_cm? / 100.0;
+                    vitals.weight_kg? / (height_m * height_m)
+                }
+            };
+            let age_days = entry.recorded_at.saturating_sub(date_of_birth) as f64 / DAY_NS as f64;
+            let age_months = age_days / MONTH_DAYS;
+            let z_score = lms_at(table, age_months).map(|lms| z_score(value, lms));
+            Some(GrowthPoint {
+                recorded_at: entry.recorded_at,
+                age_months,
+                value,
+                z_score,
+                percentile: z_score.map(percentile),
+            })
+        })
+        .collect();
+    points.sort_by_key(|point| point.recorded_at);
+    Ok(GrowthChart {
+        patient_id: patient.id,
+        metric,
+        sex,
+        points,
+    })
+}
@@ -0,0 +1,297 @@
+use crate::time;
+use crate::{
+    authorize_doctor, get_assigned_patient, get_authorized_encounter, impl_storable, next_id,
+    AccessPayload, EncounterAccessPayload, EncounterStatus, Error, Memory, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// longest report a radiologist can attach to a study
+const MAX_REPORT_LEN: usize = 8000;
+// most external references one study can carry
+const MAX_REFERENCES: usize = 16;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ImagingModality {
+    XRay,
+    Ct,
+    Mri,
+    Ultrasound,
+    Mammography,
+    Pet,
+    NuclearMedicine,
+    Fluoroscopy,
+    Other(String),
+}
+
+// Where the images themselves live; the canister only keeps the pointer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum ImagingReference {
+    // a DICOM study held in a PACS, addressed by its study instance UID
+    Pacs {
+        study_instance_uid: String,
+        endpoint: String,
+    },
+    Url(String),
+    Attachment(String),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ImagingStudy {
+    pub id: u64,
+    pub encounter_id: u64,
+    pub patient_id: u64,
+    pub hospital_id: u64,
+    pub doctor_id: u64,
+    pub modality: ImagingModality,
+    pub body_part: String,
+    pub accession_number: String,
+    pub performed_at: u64,
+    pub report: Option<String>,
+    pub reported_by: Option<u64>,
+    pub references: Vec<ImagingReference>,
+    pub recorded_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ImagingStudyPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub encounter_id: u64,
+    pub modality: ImagingModality,
+    pub body_part: String,
+    pub accession_number: String,
+    pub performed_at: u64,
+    pub references: Vec<ImagingReference>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ImagingReportPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub study_id: u64,
+    pub report: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ImagingReferencePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub study_id: u64,
+    pub reference: ImagingReference,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ImagingQuery {
+    // None for every modality
+    pub modality: Option<ImagingModality>,
+    // inclusive bounds on when the study was performed
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl_storable!(ImagingStudy, 16384);
+
+thread_local! {
+    static IMAGING_STORAGE: RefCell<StableBTreeMap<u64, ImagingStudy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91)))
+    ));
+}
+
+// a DICOM UID is dot separated digit groups, at most 64 characters
+fn is_dicom_uid(uid: &str) -> bool {
+    !uid.is_empty()
+        && uid.len() <= 64
+        && uid
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn validate_reference(reference: &ImagingReference) -> Result<(), Error> {
+    let valid = match reference {
+        ImagingReference::Pacs {
+            study_instance_uid,
+            endpoint,
+        } => is_dicom_uid(study_instance_uid) && endpoint.starts_with("https://"),
+        ImagingReference::Url(url) => url.starts_with("https://"),
+        ImagingReference::Attachment(id) => !id.trim().is_empty(),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidPayload {
+            msg: "Imaging reference needs a valid DICOM UID, an https address or an attachment id"
+                .to_string(),
+        })
+    }
+}
+
+fn get_study(study_id: u64) -> Result<ImagingStudy, Error> {
+    IMAGING_STORAGE
+        .with(|s| s.borrow().get(&study_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Imaging study of id: {} not found", study_id),
+        })
+}
+
+// helper function to get a study the doctor may access through the patient's care team
+fn get_authorized_study(
+    doctor_id: u64,
+    doctor_password: &str,
+    study_id: u64,
+) -> Result<ImagingStudy, Error> {
+    let doctor = authorize_doctor(doctor_id, doctor_password)?;
+    let study = get_study(study_id)?;
+    get_assigned_patient(&doctor, study.patient_id)?;
+    Ok(study)
+}
+
+fn save_study(study: &ImagingStudy) {
+    IMAGING_STORAGE.with(|s| s.borrow_mut().insert(study.id, study.clone()));
+}
+
+// record a performed study against an open encounter
+#[ic_cdk::update]
+fn add_imaging_study(payload: ImagingStudyPayload) -> Result<ImagingStudy, Error> {
+    let encounter = get_authorized_encounter(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.encounter_id,
+    )?;
+    if encounter.status != EncounterStatus::Open {
+        return Err(Error::InvalidPayload {
+            msg: format!("Encounter of id: {} is closed", encounter.id),
+        });
+    }
+    if payload.body_part.trim().is_empty() || payload.accession_number.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Body part and accession number are required".to_string(),
+        });
+    }
+    if payload.references.len() > MAX_REFERENCES {
+        return Err(Error::LimitExceeded {
+            msg: format!("A study can carry at most {} references", MAX_REFERENCES),
+        });
+    }
+    for reference in &payload.references {
+        validate_reference(reference)?;
+    }
+    let duplicate = IMAGING_STORAGE.with(|s| {
+        s.borrow().iter().any(|(_, study)| {
+            study.hospital_id == encounter.hospital_id
+                && study.accession_number == payload.accession_number
+        })
+    });
+    if duplicate {
+        return Err(Error::AlreadyInit {
+            msg: format!(
+                "Accession number {} is already used at hospital {}",
+                payload.accession_number, encounter.hospital_id
+            ),
+        });
+    }
+    let study = ImagingStudy {
+        id: next_id(),
+        encounter_id: encounter.id,
+        patient_id: encounter.patient_id,
+        hospital_id: encounter.hospital_id,
+        doctor_id: payload.doctor_id,
+        modality: payload.modality,
+        body_part: payload.body_part,
+        accession_number: payload.accession_number,
+        performed_at: payload.performed_at,
+        report: None,
+        reported_by: None,
+        references: payload.references,
+        recorded_at: time(),
+    };
+    save_study(&study);
+    Ok(study)
+}
+
+// write or replace the radiology report of a study
+#[ic_cdk::update]
+fn set_imaging_report(payload: ImagingReportPayload) -> Result<ImagingStudy, Error> {
+    let study = get_authorized_study(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.study_id,
+    )?;
+    if payload.report.trim().is_empty() || payload.report.len() > MAX_REPORT_LEN {
+        return Err(Error::InvalidPayload {
+            msg: format!("Report must be between 1 and {} characters", MAX_REPORT_LEN),
+        });
+    }
+    let reported = ImagingStudy {
+        report: Some(payload.report),
+        reported_by: Some(payload.doctor_id),
+        ..study
+    };
+    save_study(&reported);
+    Ok(reported)
+}
+
+// link another PACS study, URL or attachment to an existing study
+#[ic_cdk::update]
+fn add_imaging_reference(payload: ImagingReferencePayload) -> Result<ImagingStudy, Error> {
+    let mut study = get_authorized_study(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.study_id,
+    )?;
+    validate_reference(&payload.reference)?;
+    if study.references.len() >= MAX_REFERENCES {
+        return Err(Error::LimitExceeded {
+            msg: format!("A study can carry at most {} references", MAX_REFERENCES),
+        });
+    }
+    study.references.push(payload.reference);
+    save_study(&study);
+    Ok(study)
+}
+
+#[ic_cdk::query]
+fn get_encounter_imaging(payload: EncounterAccessPayload) -> Result<Vec<ImagingStudy>, Error> {
+    let encounter = get_authorized_encounter(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.encounter_id,
+    )?;
+    Ok(IMAGING_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, study)| study)
+            .filter(|study| study.encounter_id == encounter.id)
+            .collect()
+    }))
+}
+
+// a patient's studies filtered by modality and date, most recent first
+#[ic_cdk::query]
+fn search_imaging_studies(
+    payload: AccessPayload,
+    query: ImagingQuery,
+) -> Result<Vec<ImagingStudy>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let from = query.from.unwrap_or(0);
+    let to = query.to.unwrap_or(u64::MAX);
+    let mut studies: Vec<ImagingStudy> = IMAGING_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, study)| study)
+            .filter(|study| {
+                study.patient_id == patient.id
+                    && (from..=to).contains(&study.performed_at)
+                    && match &query.modality {
+                        Some(modality) => *modality == study.modality,
+                        None => true,
+                    }
+            })
+            .collect()
+    });
+    studies.sort_by_key(|study| std::cmp::Reverse(study.performed_at));
+    Ok(studies)
+}
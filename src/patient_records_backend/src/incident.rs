@@ -0,0 +1,215 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_nurse, authorize_oversight, impl_storable, next_id, Actor,
+    Error, Memory, OversightRole, StaffRef, MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// bounds that keep a report with all its notes inside its store
+const MAX_DESCRIPTION_BYTES: usize = 1500;
+const MAX_KIND_BYTES: usize = 100;
+const MAX_NOTE_BYTES: usize = 400;
+const MAX_NOTES: usize = 10;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum IncidentKind {
+    MedicationError,
+    Fall,
+    NearMiss,
+    Other(String),
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum IncidentSeverity {
+    NoHarm,
+    Minor,
+    Moderate,
+    Severe,
+}
+
+// Investigation workflow, a report only ever moves forward
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub enum IncidentStatus {
+    Reported,
+    UnderInvestigation,
+    Resolved,
+    Closed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct InvestigationNote {
+    pub by: OversightRole,
+    pub at: u64,
+    pub status: IncidentStatus,
+    pub note: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct IncidentReport {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub reporter: StaffRef,
+    pub kind: IncidentKind,
+    pub severity: IncidentSeverity,
+    pub patient_id: Option<u64>,
+    pub description: String,
+    pub status: IncidentStatus,
+    pub notes: Vec<InvestigationNote>,
+    pub reported_at: u64,
+}
+
+impl_storable!(IncidentReport, 8192);
+
+thread_local! {
+    static INCIDENT_STORAGE: RefCell<StableBTreeMap<u64, IncidentReport, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct IncidentPayload {
+    pub reporter: StaffRef,
+    pub password: String,
+    pub kind: IncidentKind,
+    pub severity: IncidentSeverity,
+    pub patient_id: Option<u64>,
+    pub description: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct IncidentUpdatePayload {
+    pub role: OversightRole,
+    pub password: String,
+    pub incident_id: u64,
+    pub status: IncidentStatus,
+    pub note: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct IncidentListPayload {
+    pub role: OversightRole,
+    pub password: String,
+    pub status: Option<IncidentStatus>,
+}
+
+// file a confidential report; the reporter gets the id back but cannot read reports
+#[ic_cdk::update]
+fn file_incident_report(payload: IncidentPayload) -> Result<u64, Error> {
+    let hospital_id = match payload.reporter {
+        StaffRef::Doctor(id) => authorize_doctor(id, &payload.password)?.hospital_id,
+        StaffRef::Nurse(id) => authorize_nurse(id, &payload.password)?.hospital_id,
+    };
+    if let Some(patient_id) = payload.patient_id {
+        if !PATIENT_STORAGE.with(|patients| patients.borrow().contains_key(&patient_id)) {
+            return Err(Error::NotFound {
+                msg: format!("Patient of id: {} not found", patient_id),
+            });
+        }
+    }
+    if payload.description.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Incident description cannot be empty".to_string(),
+        });
+    }
+    if payload.description.len() > MAX_DESCRIPTION_BYTES {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "Incident descriptions hold at most {} bytes",
+                MAX_DESCRIPTION_BYTES
+            ),
+        });
+    }
+    if matches!(&payload.kind, IncidentKind::Other(kind) if kind.len() > MAX_KIND_BYTES) {
+        return Err(Error::LimitExceeded {
+            msg: format!("Incident kinds hold at most {} bytes", MAX_KIND_BYTES),
+        });
+    }
+
+    let report = IncidentReport {
+        id: next_id(),
+        hospital_id,
+        reporter: payload.reporter,
+        kind: payload.kind,
+        severity: payload.severity,
+        patient_id: payload.patient_id,
+        description: payload.description,
+        status: IncidentStatus::Reported,
+        notes: vec![],
+        reported_at: time(),
+    };
+    INCIDENT_STORAGE.with(|s| s.borrow_mut().insert(report.id, report.clone()));
+    let actor = match report.reporter {
+        StaffRef::Doctor(id) => Actor::Doctor(id),
+        StaffRef::Nurse(id) => Actor::Nurse(id),
+    };
+    audit(
+        actor,
+        Some(hospital_id),
+        report.patient_id,
+        "incident_reported",
+        format!("incident {}", report.id),
+    );
+    Ok(report.id)
+}
+
+// move an incident through the investigation workflow with a note
+#[ic_cdk::update]
+fn update_incident_status(payload: IncidentUpdatePayload) -> Result<IncidentReport, Error> {
+    let hospital_id = authorize_oversight(&payload.role, &payload.password)?;
+    let mut report = match INCIDENT_STORAGE.with(|s| s.borrow().get(&payload.incident_id)) {
+        Some(report) if report.hospital_id == hospital_id => report,
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!("Incident of id: {} not found", payload.incident_id),
+            })
+        }
+    };
+    if payload.status < report.status {
+        return Err(Error::InvalidPayload {
+            msg: "Incident status cannot move backwards".to_string(),
+        });
+    }
+    if payload.note.len() > MAX_NOTE_BYTES {
+        return Err(Error::LimitExceeded {
+            msg: format!("Investigation notes hold at most {} bytes", MAX_NOTE_BYTES),
+        });
+    }
+    // the last note is kept for closing, so a report can always be closed
+    let closing = payload.status == IncidentStatus::Closed;
+    if report.notes.len() >= MAX_NOTES || (report.notes.len() + 1 == MAX_NOTES && !closing) {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "An incident holds at most {} notes, the last one closes it",
+                MAX_NOTES
+            ),
+        });
+    }
+    report.status = payload.status;
+    report.notes.push(InvestigationNote {
+        by: payload.role,
+        at: time(),
+        status: payload.status,
+        note: payload.note,
+    });
+    INCIDENT_STORAGE.with(|s| s.borrow_mut().insert(report.id, report.clone()));
+    Ok(report)
+}
+
+// incident reports of the hospital, only for its admin and auditors
+#[ic_cdk::query]
+fn get_incident_reports(payload: IncidentListPayload) -> Result<Vec<IncidentReport>, Error> {
+    let hospital_id = authorize_oversight(&payload.role, &payload.password)?;
+    Ok(INCIDENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, report)| report)
+            .filter(|report| {
+                report.hospital_id == hospital_id
+                    && (payload.status.is_none() || payload.status == Some(report.status))
+            })
+            .collect()
+    }))
+}
@@ -0,0 +1,233 @@
+use crate::time;
+use crate::{
+    admission_bed, audit, authorize_doctor, authorize_hospital, get_assigned_patient,
+    get_authorized_encounter, get_ward, impl_storable, next_id, Actor, EncounterStatus, Error,
+    Memory, ResultWithWarnings, ValidationWarning, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// review dates can be set at most this far ahead
+const MAX_REVIEW_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum InfectionStatus {
+    Mrsa,
+    Vre,
+    ClostridioidesDifficile,
+    Esbl,
+    Cpe,
+    Covid19,
+    Tuberculosis,
+    Influenza,
+    Norovirus,
+    Other(String),
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum IsolationPrecaution {
+    // flagged for awareness, standard precautions only
+    Standard,
+    Contact,
+    Droplet,
+    Airborne,
+    // protecting an immunocompromised patient from others
+    Protective,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct InfectionFlag {
+    pub id: u64,
+    pub patient_id: u64,
+    pub encounter_id: u64,
+    pub hospital_id: u64,
+    pub status: InfectionStatus,
+    pub precaution: IsolationPrecaution,
+    pub notes: String,
+    // the flag has to be reviewed, and cleared or renewed, by then
+    pub review_by: u64,
+    pub raised_by: u64,
+    pub raised_at: u64,
+    pub cleared_at: Option<u64>,
+    pub cleared_by: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct InfectionFlagPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub encounter_id: u64,
+    pub status: InfectionStatus,
+    pub precaution: IsolationPrecaution,
+    pub notes: String,
+    pub review_by: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct InfectionReviewPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub flag_id: u64,
+    // Some to keep the flag until a new review date, None to clear it
+    pub review_by: Option<u64>,
+}
+
+impl_storable!(InfectionFlag, 2048);
+
+thread_local! {
+    static INFECTION_FLAGS: RefCell<StableBTreeMap<u64, InfectionFlag, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(96)))
+    ));
+}
+
+// flags of a patient that have not been cleared
+pub(crate) fn active_infection_flags(patient_id: u64) -> Vec<InfectionFlag> {
+    INFECTION_FLAGS.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, flag)| flag)
+            .filter(|flag| flag.patient_id == patient_id && flag.cleared_at.is_none())
+            .collect()
+    })
+}
+
+// whether the patient must be kept out of shared wards
+pub(crate) fn requires_isolation(patient_id: u64) -> bool {
+    active_infection_flags(patient_id)
+        .iter()
+        .any(|flag| flag.precaution != IsolationPrecaution::Standard)
+}
+
+fn check_review_date(review_by: u64) -> Result<(), Error> {
+    let now = time();
+    if review_by <= now || review_by > now + MAX_REVIEW_NS {
+        return Err(Error::InvalidPayload {
+            msg: "Review date must be in the next 30 days".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// raise an infection flag on an admission, warning when an isolated patient is still in a
+// shared ward
+#[ic_cdk::update]
+fn raise_infection_flag(
+    payload: InfectionFlagPayload,
+) -> Result<ResultWithWarnings<InfectionFlag>, Error> {
+    let encounter = get_authorized_encounter(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.encounter_id,
+    )?;
+    if encounter.status != EncounterStatus::Open {
+        return Err(Error::InvalidPayload {
+            msg: format!("Encounter of id: {} is already closed", encounter.id),
+        });
+    }
+    check_review_date(payload.review_by)?;
+    let flag = InfectionFlag {
+        id: next_id(),
+        patient_id: encounter.patient_id,
+        encounter_id: encounter.id,
+        hospital_id: encounter.hospital_id,
+        status: payload.status,
+        precaution: payload.precaution,
+        notes: payload.notes,
+        review_by: payload.review_by,
+        raised_by: payload.doctor_id,
+        raised_at: time(),
+        cleared_at: None,
+        cleared_by: None,
+    };
+    INFECTION_FLAGS.with(|s| s.borrow_mut().insert(flag.id, flag.clone()));
+    audit(
+        Actor::Doctor(payload.doctor_id),
+        Some(flag.hospital_id),
+        Some(flag.patient_id),
+        "infection_flag_raised",
+        format!("flag {} on encounter {}", flag.id, flag.encounter_id),
+    );
+    let mut warnings = vec![];
+    if flag.precaution != IsolationPrecaution::Standard {
+        let shared_ward = admission_bed(encounter.id)
+            .and_then(|bed| get_ward(bed.ward_id).ok())
+            .filter(|ward| ward.beds > 1);
+        if let Some(ward) = shared_ward {
+            warnings.push(ValidationWarning {
+                code: "isolation_required".to_string(),
+                message: format!(
+                    "The patient is in shared ward {}, move them to a single room",
+                    ward.name
+                ),
+            });
+        }
+    }
+    Ok(ResultWithWarnings {
+        value: flag,
+        warnings,
+    })
+}
+
+// record the review of a flag: renew it until a later date or clear it
+#[ic_cdk::update]
+fn review_infection_flag(payload: InfectionReviewPayload) -> Result<InfectionFlag, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let flag = INFECTION_FLAGS
+        .with(|s| s.borrow().get(&payload.flag_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Infection flag of id: {} not found", payload.flag_id),
+        })?;
+    get_assigned_patient(&doctor, flag.patient_id)?;
+    if flag.cleared_at.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: format!("Infection flag of id: {} is already cleared", flag.id),
+        });
+    }
+    let reviewed = match payload.review_by {
+        Some(review_by) => {
+            check_review_date(review_by)?;
+            InfectionFlag { review_by, ..flag }
+        }
+        None => InfectionFlag {
+            cleared_at: Some(time()),
+            cleared_by: Some(doctor.id),
+            ..flag
+        },
+    };
+    INFECTION_FLAGS.with(|s| s.borrow_mut().insert(reviewed.id, reviewed.clone()));
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(reviewed.hospital_id),
+        Some(reviewed.patient_id),
+        if reviewed.cleared_at.is_some() {
+            "infection_flag_cleared"
+        } else {
+            "infection_flag_renewed"
+        },
+        format!("flag {}", reviewed.id),
+    );
+    Ok(reviewed)
+}
+
+// active flags at the hospital whose review date has passed, for infection control
+#[ic_cdk::query]
+fn get_overdue_infection_reviews(
+    hospital_id: u64,
+    hospital_password: String,
+) -> Result<Vec<InfectionFlag>, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    let now = time();
+    let mut overdue: Vec<InfectionFlag> = INFECTION_FLAGS.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, flag)| flag)
+            .filter(|flag| {
+                flag.hospital_id == hospital.id && flag.cleared_at.is_none() && flag.review_by < now
+            })
+            .collect()
+    });
+    overdue.sort_by_key(|flag| flag.review_by);
+    Ok(overdue)
+}
@@ -0,0 +1,130 @@
+use crate::time;
+use crate::{
+    authorize_doctor, get_assigned_patient, patient_allergies, patient_prescriptions,
+    patient_problems, Error,
+};
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// drugs that are risky with a condition, matched on the ICD-10 code prefix
+const CONDITION_CONTRAINDICATIONS: &[(&str, &str, &str)] = &[
+    ("ibuprofen", "K25", "NSAID with gastric ulcer"),
+    ("ibuprofen", "N18", "NSAID with chronic kidney disease"),
+    ("ibuprofen", "I50", "NSAID with heart failure"),
+    ("naproxen", "K25", "NSAID with gastric ulcer"),
+    ("naproxen", "N18", "NSAID with chronic kidney disease"),
+    ("diclofenac", "I50", "NSAID with heart failure"),
+    ("aspirin", "K25", "aspirin with gastric ulcer"),
+    ("metformin", "N18", "metformin with chronic kidney disease"),
+    (
+        "propranolol",
+        "J45",
+        "non-selective beta blocker with asthma",
+    ),
+    ("sumatriptan", "I25", "triptan with ischaemic heart disease"),
+    ("warfarin", "K25", "anticoagulant with gastric ulcer"),
+];
+
+// pairs of drugs that interact
+const DRUG_INTERACTIONS: &[(&str, &str, &str)] = &[
+    ("warfarin", "aspirin", "increased bleeding risk"),
+    ("warfarin", "ibuprofen", "increased bleeding risk"),
+    ("sildenafil", "nitroglycerin", "severe hypotension"),
+    ("simvastatin", "clarithromycin", "risk of rhabdomyolysis"),
+    ("lisinopril", "spironolactone", "risk of hyperkalaemia"),
+];
+
+// allergy groups whose members cross-react
+const ALLERGY_CLASSES: &[(&str, &[&str])] = &[
+    ("penicillin", &["amoxicillin", "ampicillin", "penicillin"]),
+    ("sulfa", &["sulfamethoxazole", "sulfasalazine"]),
+    ("nsaid", &["ibuprofen", "naproxen", "diclofenac", "aspirin"]),
+];
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum InteractionKind {
+    Allergy { substance: String },
+    Condition { icd_code: String },
+    Drug { other_medication: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct InteractionWarning {
+    pub kind: InteractionKind,
+    pub reason: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct InteractionCheckPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub medication: String,
+}
+
+fn mentions(medication: &str, drug: &str) -> bool {
+    medication.to_lowercase().contains(drug)
+}
+
+// warnings for prescribing a medication given the patient's allergies, problems and current drugs
+pub(crate) fn prescription_warnings(patient_id: u64, medication: &str) -> Vec<InteractionWarning> {
+    let mut warnings = vec![];
+
+    for allergy in patient_allergies(patient_id) {
+        let substance = allergy.substance.to_lowercase();
+        let class_match = ALLERGY_CLASSES.iter().any(|(class, members)| {
+            substance.contains(class) && members.iter().any(|drug| mentions(medication, drug))
+        });
+        if mentions(medication, &substance) || class_match {
+            warnings.push(InteractionWarning {
+                reason: format!("patient is allergic to {}", allergy.substance),
+                kind: InteractionKind::Allergy {
+                    substance: allergy.substance,
+                },
+            });
+        }
+    }
+
+    for problem in patient_problems(patient_id, true) {
+        for (drug, icd_prefix, reason) in CONDITION_CONTRAINDICATIONS {
+            if mentions(medication, drug) && problem.icd_code.starts_with(icd_prefix) {
+                warnings.push(InteractionWarning {
+                    kind: InteractionKind::Condition {
+                        icd_code: problem.icd_code.clone(),
+                    },
+                    reason: reason.to_string(),
+                });
+            }
+        }
+    }
+
+    let now = time();
+    for (entry, current) in patient_prescriptions(patient_id) {
+        if entry.recorded_at + current.duration_days as u64 * DAY_NS <= now {
+            continue;
+        }
+        for (a, b, reason) in DRUG_INTERACTIONS {
+            let pair = (mentions(medication, a) && mentions(&current.medication, b))
+                || (mentions(medication, b) && mentions(&current.medication, a));
+            if pair {
+                warnings.push(InteractionWarning {
+                    kind: InteractionKind::Drug {
+                        other_medication: current.medication.clone(),
+                    },
+                    reason: reason.to_string(),
+                });
+            }
+        }
+    }
+    warnings
+}
+
+// check a medication before prescribing it
+#[ic_cdk::query]
+fn check_prescription_interactions(
+    payload: InteractionCheckPayload,
+) -> Result<Vec<InteractionWarning>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    Ok(prescription_warnings(patient.id, &payload.medication))
+}
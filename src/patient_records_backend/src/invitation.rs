@@ -0,0 +1,252 @@
+use crate::time;
+use crate::{
+    actor_of, add_doctor, add_nurse, add_patient, admit_patient, audit, authorize_hospital, caller,
+    check_limit, impl_storable, link_account_role, next_id, patient_quota, to_hex, AccountRole,
+    DoctorPayload, Error, HospitalAccessPayload, Memory, NursePayload, PatientPayload,
+    HOSPITAL_STORAGE, MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const MAX_INVITATION_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum InvitedRole {
+    Doctor,
+    Nurse,
+    Patient,
+}
+
+// A single-use code for joining a hospital in a role. Only the code's hash is stored
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Invitation {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub role: InvitedRole,
+    pub code_hash: Vec<u8>,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub revoked_at: Option<u64>,
+    pub redeemed_at: Option<u64>,
+    pub redeemed_by: Option<Principal>,
+    // id of the doctor, nurse or patient created with the code
+    pub account_id: Option<u64>,
+}
+
+// returned once on creation, the code cannot be read back later
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct IssuedInvitation {
+    pub code: String,
+    pub invitation: Invitation,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RedeemedInvitation {
+    pub role: InvitedRole,
+    pub hospital_id: u64,
+    pub account_id: u64,
+    // medical record number, for patients
+    pub mrn: Option<String>,
+}
+
+impl_storable!(Invitation, 512);
+
+thread_local! {
+    static INVITATION_STORAGE: RefCell<StableBTreeMap<u64, Invitation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CreateInvitationPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub role: InvitedRole,
+    pub expires_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct RedeemInvitationPayload {
+    pub code: String,
+    pub name: String,
+    pub password: String,
+    // history and language are only used for patients
+    pub history: String,
+    pub language: Option<String>,
+}
+
+fn hash_code(code: &str) -> Vec<u8> {
+    Sha256::digest(code.trim().to_lowercase().as_bytes()).to_vec()
+}
+
+fn save_invitation(invitation: &Invitation) {
+    INVITATION_STORAGE.with(|s| s.borrow_mut().insert(invitation.id, invitation.clone()));
+}
+
+// a hospital invites someone to join it as a doctor, nurse or patient
+#[ic_cdk::update]
+async fn create_invitation(payload: CreateInvitationPayload) -> Result<IssuedInvitation, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let now = time();
+    if payload.expires_at <= now || payload.expires_at - now > MAX_INVITATION_NS {
+        return Err(Error::InvalidPayload {
+            msg: "Invitations must expire within the next 30 days".to_string(),
+        });
+    }
+    let (random,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| Error::InvalidPayload {
+            msg: format!("Could not create invitation: {:?} {}", code, msg),
+        })?;
+    let code = to_hex(&random[..10]);
+    let invitation = Invitation {
+        id: next_id(),
+        hospital_id: hospital.id,
+        role: payload.role,
+        code_hash: hash_code(&code),
+        created_at: now,
+        expires_at: payload.expires_at,
+        revoked_at: None,
+        redeemed_at: None,
+        redeemed_by: None,
+        account_id: None,
+    };
+    save_invitation(&invitation);
+    Ok(IssuedInvitation { code, invitation })
+}
+
+#[ic_cdk::query]
+fn get_invitations(payload: HospitalAccessPayload) -> Result<Vec<Invitation>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    Ok(INVITATION_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, invitation)| invitation)
+            .filter(|invitation| invitation.hospital_id == hospital.id)
+            .collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn revoke_invitation(
+    payload: HospitalAccessPayload,
+    invitation_id: u64,
+) -> Result<Invitation, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    match INVITATION_STORAGE.with(|s| s.borrow().get(&invitation_id)) {
+        Some(invitation)
+            if invitation.hospital_id == hospital.id && invitation.redeemed_at.is_none() =>
+        {
+            let revoked = Invitation {
+                revoked_at: Some(time()),
+                ..invitation
+            };
+            save_invitation(&revoked);
+            Ok(revoked)
+        }
+        _ => Err(Error::NotFound {
+            msg: format!("Open invitation of id: {} not found", invitation_id),
+        }),
+    }
+}
+
+// the invited person creates their own account with the code and credentials they choose
+#[ic_cdk::update]
+fn redeem_invitation(payload: RedeemInvitationPayload) -> Result<RedeemedInvitation, Error> {
+    let code_hash = hash_code(&payload.code);
+    let invitation = INVITATION_STORAGE
+        .with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, invitation)| invitation)
+                .find(|invitation| invitation.code_hash == code_hash)
+        })
+        .ok_or(Error::NotFound {
+            msg: "Invitation not found".to_string(),
+        })?;
+    if invitation.redeemed_at.is_some()
+        || invitation.revoked_at.is_some()
+        || invitation.expires_at <= time()
+    {
+        return Err(Error::InvalidPayload {
+            msg: "Invitation was already used, revoked or has expired".to_string(),
+        });
+    }
+    let hospital = HOSPITAL_STORAGE
+        .with(|s| s.borrow().get(&invitation.hospital_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Hospital of id: {} not found", invitation.hospital_id),
+        })?;
+
+    let (account_id, mrn) = match invitation.role {
+        InvitedRole::Doctor => {
+            let doctor = add_doctor(DoctorPayload {
+                name: payload.name,
+                hospital_id: hospital.id,
+                password: payload.password,
+                hospital_password: hospital.password,
+            })?;
+            (doctor.id, None)
+        }
+        InvitedRole::Nurse => {
+            let nurse = add_nurse(NursePayload {
+                hospital_id: hospital.id,
+                hospital_password: hospital.password,
+                name: payload.name,
+                password: payload.password,
+            })?;
+            (nurse.id, None)
+        }
+        InvitedRole::Patient => {
+            check_limit(
+                "patients per hospital",
+                hospital.patients_ids.len() as u64,
+                patient_quota(hospital.id),
+            )?;
+            let patient = add_patient(PatientPayload {
+                name: payload.name,
+                history: payload.history,
+                password: payload.password,
+                language: payload.language,
+            })?;
+            (patient.id, Some(admit_patient(hospital.id, patient.id)?))
+        }
+    };
+
+    let role = match invitation.role {
+        InvitedRole::Doctor => AccountRole::Doctor(account_id),
+        InvitedRole::Nurse => AccountRole::Nurse(account_id),
+        InvitedRole::Patient => AccountRole::Patient(account_id),
+    };
+    let caller = caller();
+    if caller != Principal::anonymous() {
+        link_account_role(caller, role).ok();
+    }
+    save_invitation(&Invitation {
+        redeemed_at: Some(time()),
+        redeemed_by: Some(caller),
+        account_id: Some(account_id),
+        ..invitation.clone()
+    });
+    audit(
+        actor_of(role),
+        Some(hospital.id),
+        mrn.as_ref().map(|_| account_id),
+        "invitation_redeemed",
+        format!(
+            "invitation {} created account {}",
+            invitation.id, account_id
+        ),
+    );
+    Ok(RedeemedInvitation {
+        role: invitation.role,
+        hospital_id: hospital.id,
+        account_id,
+        mrn,
+    })
+}
@@ -0,0 +1,412 @@
+use crate::time;
+use crate::{
+    account_of, audit, authorize_hospital, caller, check_site, enqueue_ticket, get_appointment,
+    impl_storable, next_id, queue_position, queue_version, waiting_queue, Actor, AppointmentStatus,
+    Error, Memory, Urgency, MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+// how early and late patients can check in for an appointment
+const CHECK_IN_EARLY_NS: u64 = 2 * HOUR_NS;
+const CHECK_IN_LATE_NS: u64 = HOUR_NS;
+
+// The only things a waiting room device may do
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum KioskPermission {
+    CheckIn,
+    QueueDisplay,
+}
+
+// A hospital device identified by its principal. Registered principals are refused by every
+// role check, so a compromised kiosk cannot read clinical data with a leaked password
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct KioskDevice {
+    pub id: u64,
+    pub principal: Principal,
+    pub hospital_id: u64,
+    pub site_id: Option<u64>,
+    pub name: String,
+    pub permissions: Vec<KioskPermission>,
+    pub registered_at: u64,
+    pub revoked_at: Option<u64>,
+}
+
+impl_storable!(KioskDevice, 512);
+
+thread_local! {
+    // keyed by the hash of the device principal
+    static KIOSK_STORAGE: RefCell<StableBTreeMap<[u8; 32], KioskDevice, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(116)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RegisterKioskPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub principal: Principal,
+    pub site_id: Option<u64>,
+    pub name: String,
+    pub permissions: Vec<KioskPermission>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct KioskCheckInPayload {
+    pub appointment_id: u64,
+    pub patient_id: u64,
+}
+
+// What a kiosk shows after a check-in, the ticket to poll get_queue_status with
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct KioskCheckIn {
+    pub ticket_id: u64,
+    pub position: u64,
+    pub estimated_wait_ns: u64,
+}
+
+// The waiting queue of the kiosk's site by ticket number only
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct KioskQueueDisplay {
+    pub version: u64,
+    // ticket ids in the order they will be called
+    pub waiting: Vec<u64>,
+    pub changed: bool,
+}
+
+fn kiosk_key(principal: &Principal) -> [u8; 32] {
+    Sha256::digest(principal.as_slice()).into()
+}
+
+fn active_kiosk(principal: &Principal) -> Option<KioskDevice> {
+    KIOSK_STORAGE
+        .with(|s| s.borrow().get(&kiosk_key(principal)))
+        .filter(|kiosk| kiosk.revoked_at.is_none())
+}
+
+pub(crate) fn kiosk_principal(principal: &Principal) -> bool {
+    active_kiosk(principal).is_some()
+}
+
+// what the caller may do as a kiosk, nothing for any other principal
+pub(crate) fn caller_kiosk_permissions() -> Vec<KioskPermission> {
+    active_kiosk(&caller()).map_or(vec![], |kiosk| kiosk.permissions)
+}
+
+// called by the role checks: registered devices never act as a hospital, doctor, nurse,
+// patient or auditor, whatever credentials they present
+pub(crate) fn reject_kiosk_caller() -> Result<(), Error> {
+    match active_kiosk(&caller()) {
+        Some(_) => Err(Error::Unauthorized {
+            msg: "Kiosk devices can only check patients in and show the queue".to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+// check the caller is an active kiosk allowed to do this
+fn authorize_kiosk(permission: KioskPermission) -> Result<KioskDevice, Error> {
+    match active_kiosk(&caller()) {
+        Some(kiosk) if kiosk.permissions.contains(&permission) => Ok(kiosk),
+        _ => Err(Error::Unauthorized {
+            msg: "Caller is not a kiosk with this permission".to_string(),
+        }),
+    }
+}
+
+fn hospital_kiosk(hospital_id: u64, kiosk_id: u64) -> Result<KioskDevice, Error> {
+    KIOSK_STORAGE
+        .with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, kiosk)| kiosk)
+                .find(|kiosk| kiosk.id == kiosk_id && kiosk.hospital_id == hospital_id)
+        })
+        .ok_or(Error::NotFound {
+            msg: format!("Kiosk of id: {} not found", kiosk_id),
+        })
+}
+
+// hospital admins register a waiting room device by its principal
+#[ic_cdk::update]
+fn register_kiosk(payload: RegisterKioskPayload) -> Result<KioskDevice, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    check_site(hospital.id, payload.site_id)?;
+    if payload.principal == Principal::anonymous() || payload.permissions.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "A kiosk needs its own principal and at least one permission".to_string(),
+        });
+    }
+    if !account_of(&payload.principal).roles.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Principal already has accounts linked and cannot be a kiosk".to_string(),
+        });
+    }
+    if active_kiosk(&payload.principal).is_some() {
+        return Err(Error::AlreadyInit {
+            msg: "Principal is already registered as a kiosk".to_string(),
+        });
+    }
+    let mut permissions: Vec<KioskPermission> = vec![];
+    for permission in payload.permissions {
+        if !permissions.contains(&permission) {
+            permissions.push(permission);
+        }
+    }
+    let kiosk = KioskDevice {
+        id: next_id(),
+        principal: payload.principal,
+        hospital_id: hospital.id,
+        site_id: payload.site_id,
+        name: payload.name,
+        permissions,
+        registered_at: time(),
+        revoked_at: None,
+    };
+    KIOSK_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(kiosk_key(&kiosk.principal), kiosk.clone())
+    });
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        None,
+        "kiosk_registered",
+        format!("kiosk {} {}", kiosk.id, kiosk.principal),
+    );
+    Ok(kiosk)
+}
+
+// a revoked device is kept so its principal stays recognisable in the audit log
+#[ic_cdk::update]
+fn revoke_kiosk(
+    hospital_id: u64,
+    hospital_password: String,
+    kiosk_id: u64,
+) -> Result<KioskDevice, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    let kiosk = hospital_kiosk(hospital.id, kiosk_id)?;
+    let revoked = KioskDevice {
+        revoked_at: Some(kiosk.revoked_at.unwrap_or(time())),
+        ..kiosk
+    };
+    KIOSK_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(kiosk_key(&revoked.principal), revoked.clone())
+    });
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        None,
+        "kiosk_revoked",
+        format!("kiosk {}", revoked.id),
+    );
+    Ok(revoked)
+}
+
+#[ic_cdk::query]
+fn get_kiosks(hospital_id: u64, hospital_password: String) -> Result<Vec<KioskDevice>, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    Ok(KIOSK_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, kiosk)| kiosk)
+            .filter(|kiosk| kiosk.hospital_id == hospital.id)
+            .collect()
+    }))
+}
+
+// a patient checks in for today's appointment and joins the queue of the kiosk's site
+#[ic_cdk::update]
+fn kiosk_check_in(payload: KioskCheckInPayload) -> Result<KioskCheckIn, Error> {
+    let kiosk = authorize_kiosk(KioskPermission::CheckIn)?;
+    let appointment = get_appointment(payload.appointment_id)?;
+    let now = time();
+    if appointment.patient_id != payload.patient_id
+        || appointment.hospital_id != kiosk.hospital_id
+        || (kiosk.site_id.is_some() && appointment.site_id != kiosk.site_id)
+    {
+        return Err(Error::NotFound {
+            msg: format!("Appointment of id: {} not found", payload.appointment_id),
+        });
+    }
+    if appointment.status != AppointmentStatus::Scheduled
+        || now + CHECK_IN_EARLY_NS < appointment.start
+        || appointment.start + CHECK_IN_LATE_NS < now
+    {
+        return Err(Error::InvalidPayload {
+            msg: "Check-in is open from two hours before to one hour after the appointment"
+                .to_string(),
+        });
+    }
+    let ticket = enqueue_ticket(
+        kiosk.hospital_id,
+        kiosk.site_id,
+        appointment.patient_id,
+        Urgency::Standard,
+        format!("Checked in for appointment {}", appointment.id),
+    )?;
+    audit(
+        Actor::System,
+        Some(kiosk.hospital_id),
+        Some(appointment.patient_id),
+        "kiosk_check_in",
+        format!("kiosk {} appointment {}", kiosk.id, appointment.id),
+    );
+    let position = queue_position(ticket);
+    Ok(KioskCheckIn {
+        ticket_id: position.ticket.id,
+        position: position.position,
+        estimated_wait_ns: position.estimated_wait_ns,
+    })
+}
+
+// the waiting room screen, polled with the version it last showed
+#[ic_cdk::query]
+fn kiosk_queue_display(known_version: Option<u64>) -> Result<KioskQueueDisplay, Error> {
+    let kiosk = authorize_kiosk(KioskPermission::QueueDisplay)?;
+    let version = queue_version(kiosk.hospital_id, kiosk.site_id);
+    if known_version == Some(version) {
+        return Ok(KioskQueueDisplay {
+            version,
+            waiting: vec![],
+            changed: false,
+        });
+    }
+    Ok(KioskQueueDisplay {
+        version,
+        waiting: waiting_queue(kiosk.hospital_id, kiosk.site_id)
+            .iter()
+            .map(|ticket| ticket.id)
+            .collect(),
+        changed: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{clinic, must, principal, refused, sign_in, Clinic, PASSWORD};
+    use crate::{
+        add_doctor, authenticate_patient, authorize_doctor, edit_doctor, edit_hospital,
+        get_patient_info, link_account_role, AccessPayload, AccountRole, DoctorPayload, EditDoctor,
+        EditHospitalPayload,
+    };
+
+    const DEVICE: u8 = 7;
+
+    fn register(clinic: &Clinic) -> KioskDevice {
+        must(register_kiosk(RegisterKioskPayload {
+            hospital_id: clinic.hospital_id,
+            hospital_password: PASSWORD.to_string(),
+            principal: principal(DEVICE),
+            site_id: None,
+            name: "Lobby".to_string(),
+            permissions: vec![KioskPermission::CheckIn],
+        }))
+    }
+
+    fn read_patient(clinic: &Clinic) -> Result<(), Error> {
+        get_patient_info(AccessPayload {
+            doctor_id: clinic.doctor_id,
+            patient_id: clinic.patient_id,
+            doctor_password: PASSWORD.to_string(),
+        })
+        .map(|_| ())
+    }
+
+    #[test]
+    fn a_kiosk_is_refused_every_role_with_correct_passwords() {
+        let clinic = clinic();
+        register(&clinic);
+        sign_in(principal(DEVICE));
+        assert!(refused(read_patient(&clinic)));
+        assert!(refused(
+            authorize_doctor(clinic.doctor_id, PASSWORD).map(|_| ())
+        ));
+        assert!(refused(
+            authenticate_patient(clinic.patient_id, PASSWORD).map(|_| ())
+        ));
+        assert!(refused(
+            authorize_hospital(clinic.hospital_id, PASSWORD).map(|_| ())
+        ));
+    }
+
+    #[test]
+    fn a_kiosk_cannot_manage_the_hospital_with_correct_passwords() {
+        let clinic = clinic();
+        register(&clinic);
+        sign_in(principal(DEVICE));
+        assert!(refused(
+            edit_hospital(EditHospitalPayload {
+                hospital_id: clinic.hospital_id,
+                name: "Renamed".to_string(),
+                password: PASSWORD.to_string(),
+            })
+            .map(|_| ())
+        ));
+        assert!(refused(
+            add_doctor(DoctorPayload {
+                name: "Intruder".to_string(),
+                hospital_id: clinic.hospital_id,
+                password: PASSWORD.to_string(),
+                hospital_password: PASSWORD.to_string(),
+            })
+            .map(|_| ())
+        ));
+        assert!(refused(
+            edit_doctor(EditDoctor {
+                name: "Renamed".to_string(),
+                doctor_id: clinic.doctor_id,
+                hospital_id: clinic.hospital_id,
+                doctor_password: PASSWORD.to_string(),
+                hospital_password: PASSWORD.to_string(),
+            })
+            .map(|_| ())
+        ));
+    }
+
+    #[test]
+    fn a_revoked_kiosk_acts_like_any_other_caller() {
+        let clinic = clinic();
+        let kiosk = register(&clinic);
+        must(revoke_kiosk(
+            clinic.hospital_id,
+            PASSWORD.to_string(),
+            kiosk.id,
+        ));
+        sign_in(principal(DEVICE));
+        assert!(read_patient(&clinic).is_ok());
+    }
+
+    #[test]
+    fn kiosks_and_accounts_do_not_share_principals() {
+        let clinic = clinic();
+        register(&clinic);
+        assert!(refused(link_account_role(
+            principal(DEVICE),
+            AccountRole::Doctor(clinic.doctor_id)
+        )));
+        let user = principal(8);
+        must(link_account_role(
+            user,
+            AccountRole::Doctor(clinic.doctor_id),
+        ));
+        assert!(matches!(
+            register_kiosk(RegisterKioskPayload {
+                hospital_id: clinic.hospital_id,
+                hospital_password: PASSWORD.to_string(),
+                principal: user,
+                site_id: None,
+                name: "Desk".to_string(),
+                permissions: vec![KioskPermission::QueueDisplay],
+            }),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+}
@@ -0,0 +1,471 @@
+use crate::time;
+use crate::{
+    audit, authorize_auditor, authorize_hospital, authorize_oversight, check_residency,
+    custom_field_values, death_registration, get_encounter_entries, impl_storable, next_id,
+    patient_allergies, patient_audit_entries, patient_audit_summaries, patient_details_v2,
+    patient_encounters, patient_problems, patient_records, sign_hash, to_hex, Actor,
+    EncounterDetails, Error, LegalBasis, Memory, OversightRole, TransferDestination,
+    MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// bytes of the export package per stored chunk
+const CHUNK_SIZE: usize = 512 * 1024;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum LegalExportStatus {
+    // requested by the hospital admin, waiting for an auditor
+    PendingApproval,
+    Rejected,
+    Released,
+}
+
+// One part of the package, a single json line
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ExportSection {
+    pub name: String,
+    pub bytes: u64,
+    // SHA-256 of the section's line
+    pub hash: Vec<u8>,
+    // SHA-256 of the previous chain hash || name || hash
+    pub chain_hash: Vec<u8>,
+}
+
+// A court-ordered disclosure of one patient's record and access logs
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct LegalExport {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub patient_id: u64,
+    pub basis: LegalBasis,
+    // court case number, inquest or investigation reference
+    pub reference: String,
+    pub requesting_party: String,
+    pub requested_at: u64,
+    pub status: LegalExportStatus,
+    pub approved_by: Option<u64>,
+    pub decided_at: Option<u64>,
+    // chain hash of the previous released export, linking every disclosure of the canister
+    pub prev_export_hash: Vec<u8>,
+    pub sections: Vec<ExportSection>,
+    pub chunks: u64,
+    // chain hash of the last section, what the canister signs
+    pub head: Vec<u8>,
+    // secp256k1 signature over head, empty until signing succeeds
+    pub signature: Vec<u8>,
+}
+
+// A stored piece of a package
+#[derive(Clone)]
+pub struct ExportChunk(Vec<u8>);
+
+impl Storable for ExportChunk {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        ExportChunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for ExportChunk {
+    const MAX_SIZE: u32 = CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ExportChainHead {
+    pub head: Vec<u8>,
+}
+
+impl_storable!(LegalExport, 4096);
+impl_storable!(ExportChainHead, 128);
+
+thread_local! {
+    static LEGAL_EXPORT_STORAGE: RefCell<StableBTreeMap<u64, LegalExport, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80)))
+    ));
+
+    // keyed by (export id, chunk number)
+    static EXPORT_CHUNKS: RefCell<StableBTreeMap<(u64, u64), ExportChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81)))
+    ));
+
+    static EXPORT_CHAIN: RefCell<Cell<ExportChainHead, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82))),
+            ExportChainHead::default(),
+        )
+        .expect("Cannot create export chain")
+    );
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct LegalExportRequestPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub patient_id: u64,
+    pub basis: LegalBasis,
+    pub reference: String,
+    // the court, coroner or regulator the export goes to
+    pub requesting_party: String,
+    // jurisdiction of the requesting party when it is outside the hospital's own
+    pub requesting_jurisdiction: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct LegalExportDecisionPayload {
+    pub auditor_id: u64,
+    pub auditor_password: String,
+    pub export_id: u64,
+    pub approve: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct LegalExportChunkPayload {
+    pub role: OversightRole,
+    pub password: String,
+    pub export_id: u64,
+    pub chunk: u64,
+}
+
+#[derive(Serialize)]
+struct SectionLine<'a, T: serde::Serialize> {
+    section: &'a str,
+    data: T,
+}
+
+// The first section, saying who disclosed what to whom and why
+#[derive(Serialize)]
+struct Disclosure<'a> {
+    export_id: u64,
+    hospital_id: u64,
+    patient_id: u64,
+    basis: LegalBasis,
+    reference: &'a str,
+    requesting_party: &'a str,
+    approved_by: Option<u64>,
+    released_at: Option<u64>,
+}
+
+fn get_export(export_id: u64) -> Result<LegalExport, Error> {
+    LEGAL_EXPORT_STORAGE
+        .with(|s| s.borrow().get(&export_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Legal export of id: {} not found", export_id),
+        })
+}
+
+fn save_export(export: &LegalExport) {
+    LEGAL_EXPORT_STORAGE.with(|s| s.borrow_mut().insert(export.id, export.clone()));
+}
+
+// helper function to append a section to the package and extend the chain
+fn add_section<T: serde::Serialize>(
+    package: &mut Vec<u8>,
+    sections: &mut Vec<ExportSection>,
+    chain: &mut Vec<u8>,
+    name: &str,
+    data: T,
+) {
+    let mut line = serde_json::to_vec(&SectionLine {
+        section: name,
+        data,
+    })
+    .expect("Cannot serialize export section");
+    line.push(b'\n');
+    let hash = Sha256::digest(&line).to_vec();
+    let mut hasher = Sha256::new();
+    hasher.update(&chain);
+    hasher.update(name.as_bytes());
+    hasher.update(&hash);
+    *chain = hasher.finalize().to_vec();
+    sections.push(ExportSection {
+        name: name.to_string(),
+        bytes: line.len() as u64,
+        hash,
+        chain_hash: chain.clone(),
+    });
+    package.extend(line);
+}
+
+// build the package, store it in chunks and fill in the manifest
+fn generate_package(export: &mut LegalExport) -> Result<(), Error> {
+    let patient = PATIENT_STORAGE
+        .with(|s| s.borrow().get(&export.patient_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Patient of id: {} not found", export.patient_id),
+        })?;
+    let encounters: Vec<EncounterDetails> = patient_encounters(patient.id)
+        .into_iter()
+        .map(|encounter| EncounterDetails {
+            entries: get_encounter_entries(&encounter),
+            custom_fields: custom_field_values(encounter.id),
+            encounter,
+        })
+        .collect();
+
+    let mut package = vec![];
+    let mut sections = vec![];
+    let mut chain = EXPORT_CHAIN.with(|c| c.borrow().get().head.clone());
+    export.prev_export_hash = chain.clone();
+    add_section(
+        &mut package,
+        &mut sections,
+        &mut chain,
+        "disclosure",
+        Disclosure {
+            export_id: export.id,
+            hospital_id: export.hospital_id,
+            patient_id: export.patient_id,
+            basis: export.basis,
+            reference: &export.reference,
+            requesting_party: &export.requesting_party,
+            approved_by: export.approved_by,
+            released_at: export.decided_at,
+        },
+    );
+    let legacy_history = patient.history.clone();
+    add_section(
+        &mut package,
+        &mut sections,
+        &mut chain,
+        "patient",
+        patient_details_v2(patient),
+    );
+    add_section(
+        &mut package,
+        &mut sections,
+        &mut chain,
+        "legacy_history",
+        legacy_history,
+    );
+    add_section(
+        &mut package,
+        &mut sections,
+        &mut chain,
+        "death",
+        death_registration(export.patient_id),
+    );
+    add_section(
+        &mut package,
+        &mut sections,
+        &mut chain,
+        "records",
+        patient_records(export.patient_id),
+    );
+    add_section(
+        &mut package,
+        &mut sections,
+        &mut chain,
+        "encounters",
+        encounters,
+    );
+    add_section(
+        &mut package,
+        &mut sections,
+        &mut chain,
+        "allergies",
+        patient_allergies(export.patient_id),
+    );
+    add_section(
+        &mut package,
+        &mut sections,
+        &mut chain,
+        "problems",
+        patient_problems(export.patient_id, false),
+    );
+    add_section(
+        &mut package,
+        &mut sections,
+        &mut chain,
+        "access_log",
+        patient_audit_entries(export.patient_id),
+    );
+    add_section(
+        &mut package,
+        &mut sections,
+        &mut chain,
+        "access_log_summaries",
+        patient_audit_summaries(export.patient_id),
+    );
+
+    EXPORT_CHUNKS.with(|s| {
+        let mut chunks = s.borrow_mut();
+        for (n, chunk) in package.chunks(CHUNK_SIZE).enumerate() {
+            chunks.insert((export.id, n as u64), ExportChunk(chunk.to_vec()));
+        }
+    });
+    export.chunks = package.len().div_ceil(CHUNK_SIZE) as u64;
+    export.sections = sections;
+    export.head = chain.clone();
+    EXPORT_CHAIN
+        .with(|c| c.borrow_mut().set(ExportChainHead { head: chain }))
+        .expect("Cannot update export chain");
+    Ok(())
+}
+
+// a hospital admin asks to disclose a patient's record under a court order or similar, an
+// auditor of the hospital has to approve before anything is generated
+#[ic_cdk::update]
+fn request_legal_export(payload: LegalExportRequestPayload) -> Result<LegalExport, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if payload.reference.trim().is_empty() || payload.requesting_party.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Legal exports need a reference and a requesting party".to_string(),
+        });
+    }
+    let patient = PATIENT_STORAGE
+        .with(|s| s.borrow().get(&payload.patient_id))
+        .filter(|patient| patient.hospitals_ids.contains(&hospital.id))
+        .ok_or(Error::Unauthorized {
+            msg: format!(
+                "Hospital {} did not treat patient {}",
+                hospital.id, payload.patient_id
+            ),
+        })?;
+    if let Some(jurisdiction) = &payload.requesting_jurisdiction {
+        check_residency(
+            &patient,
+            &TransferDestination::Jurisdiction(jurisdiction.clone()),
+            Actor::Hospital(hospital.id),
+            Some(hospital.id),
+            "legal export",
+        )?;
+    }
+    let export = LegalExport {
+        id: next_id(),
+        hospital_id: hospital.id,
+        patient_id: payload.patient_id,
+        basis: payload.basis,
+        reference: payload.reference,
+        requesting_party: payload.requesting_party,
+        requested_at: time(),
+        status: LegalExportStatus::PendingApproval,
+        approved_by: None,
+        decided_at: None,
+        prev_export_hash: vec![],
+        sections: vec![],
+        chunks: 0,
+        head: vec![],
+        signature: vec![],
+    };
+    save_export(&export);
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        Some(export.patient_id),
+        "legal_export_requested",
+        format!(
+            "export {}: {:?} {}",
+            export.id, export.basis, export.reference
+        ),
+    );
+    Ok(export)
+}
+
+// the second approval: an auditor releases or rejects the export. releasing generates and
+// signs the package and records the disclosure in the audit trail. calling it again on a
+// released export whose signing failed retries the signature
+#[ic_cdk::update]
+async fn decide_legal_export(payload: LegalExportDecisionPayload) -> Result<LegalExport, Error> {
+    let auditor = authorize_auditor(payload.auditor_id, &payload.auditor_password)?;
+    let mut export = get_export(payload.export_id)?;
+    if export.hospital_id != auditor.hospital_id {
+        return Err(Error::NotFound {
+            msg: format!("Legal export of id: {} not found", export.id),
+        });
+    }
+    match export.status {
+        LegalExportStatus::PendingApproval if !payload.approve => {
+            export.status = LegalExportStatus::Rejected;
+            export.approved_by = Some(auditor.id);
+            export.decided_at = Some(time());
+            save_export(&export);
+            audit(
+                Actor::Auditor(auditor.id),
+                Some(export.hospital_id),
+                Some(export.patient_id),
+                "legal_export_rejected",
+                format!("export {}", export.id),
+            );
+            return Ok(export);
+        }
+        LegalExportStatus::PendingApproval => {
+            export.status = LegalExportStatus::Released;
+            export.approved_by = Some(auditor.id);
+            export.decided_at = Some(time());
+            generate_package(&mut export)?;
+            save_export(&export);
+            audit(
+                Actor::Auditor(auditor.id),
+                Some(export.hospital_id),
+                Some(export.patient_id),
+                "legal_export_released",
+                format!(
+                    "export {}: {:?} {} to {}, chain head {}",
+                    export.id,
+                    export.basis,
+                    export.reference,
+                    export.requesting_party,
+                    to_hex(&export.head)
+                ),
+            );
+        }
+        LegalExportStatus::Released if export.signature.is_empty() => {}
+        _ => {
+            return Err(Error::InvalidPayload {
+                msg: format!("Legal export of id: {} was already decided", export.id),
+            })
+        }
+    }
+
+    let signature = sign_hash(export.head.clone()).await?;
+    let signed = LegalExport {
+        signature,
+        ..get_export(export.id)?
+    };
+    save_export(&signed);
+    Ok(signed)
+}
+
+// the hospital's legal exports, newest first
+#[ic_cdk::query]
+fn get_legal_exports(role: OversightRole, password: String) -> Result<Vec<LegalExport>, Error> {
+    let hospital_id = authorize_oversight(&role, &password)?;
+    let mut exports: Vec<LegalExport> = LEGAL_EXPORT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, export)| export)
+            .filter(|export| export.hospital_id == hospital_id)
+            .collect()
+    });
+    exports.reverse();
+    Ok(exports)
+}
+
+// one chunk of a released package; the chunks joined in order are json lines, one per
+// section, that hash to the manifest's sections
+#[ic_cdk::query]
+fn get_legal_export_chunk(payload: LegalExportChunkPayload) -> Result<Vec<u8>, Error> {
+    let hospital_id = authorize_oversight(&payload.role, &payload.password)?;
+    let export = get_export(payload.export_id)?;
+    if export.hospital_id != hospital_id || export.status != LegalExportStatus::Released {
+        return Err(Error::NotFound {
+            msg: format!("No released legal export of id: {}", export.id),
+        });
+    }
+    EXPORT_CHUNKS
+        .with(|s| s.borrow().get(&(export.id, payload.chunk)))
+        .map(|chunk| chunk.0)
+        .ok_or(Error::NotFound {
+            msg: format!("Legal export {} has {} chunks", export.id, export.chunks),
+        })
+}
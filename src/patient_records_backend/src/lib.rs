@@ -1,16 +1,269 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
-use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use std::{borrow::Cow, cell::RefCell, ops::Bound, time::Duration};
 use validator::Validate;
 
+// the canister runtime; tests run natively against the simulated one in test_support.rs
+#[cfg(not(test))]
+use ic_cdk::api::{caller, instruction_counter, is_controller, time};
+#[cfg(test)]
+use test_support::{caller, instruction_counter, is_controller, time};
+
+mod account;
+mod adherence;
+mod alert;
+mod allergy;
+mod anomaly;
+mod api;
+mod app_token;
+mod appointment;
+mod archive;
+mod attachment;
+mod attendance;
+mod attestation;
+mod audit;
+mod auditor;
+mod autoscale;
+mod batch;
+#[cfg(feature = "canbench-rs")]
+mod benches;
+mod bloodbank;
+mod cache;
+mod care_gap;
+mod care_plan;
+mod caregiver;
+mod catalog;
+mod chart;
+mod communication;
+mod compression;
+mod consent_receipt;
+mod controlled;
+mod critical_result;
+mod custom_field;
+mod death;
+mod device;
+mod diet;
+mod directory;
+mod encoding;
+mod encounter;
+mod entity;
+mod equipment;
+mod family;
+mod federation;
+#[cfg(test)]
+mod fuzz;
+mod growth;
+mod imaging;
+mod incident;
+mod infection;
+mod interaction;
+mod invitation;
+mod kiosk;
+mod legal_export;
+mod limits;
+mod locale;
+mod mar;
+mod maternity;
+mod medication_reminder;
+mod newborn;
+mod notification;
+mod nurse;
+mod patient_header;
+mod pharmacy;
+mod pin;
+mod placement;
+mod premium;
+mod prescription_code;
+mod problem;
+mod procedure;
+mod procedure_code;
+mod procedure_consent;
+mod public_health;
+mod record;
+mod registration;
+mod replication;
+mod report;
+mod residency;
+mod search;
+#[cfg(feature = "dev")]
+mod seed;
+mod sensitivity;
+mod series;
+mod shard;
+mod sharing;
+mod shift;
+mod siem;
+mod signing;
+mod site;
+mod storage;
+mod survey;
+mod tag;
+mod terminology;
+#[cfg(test)]
+mod test_support;
+mod tier;
+mod timeline;
+mod timezone;
+mod transplant;
+mod triage;
+mod trial;
+mod undo;
+mod upgrade;
+mod usage;
+mod validation;
+mod vitals;
+mod vitals_alert;
+mod waitlist;
+mod ward;
+mod whoami;
+
+use account::*;
+use adherence::*;
+use alert::*;
+use allergy::*;
+use anomaly::*;
+use api::*;
+use app_token::*;
+use appointment::*;
+use archive::*;
+use attachment::*;
+use attendance::*;
+use attestation::*;
+use audit::*;
+use auditor::*;
+use autoscale::*;
+use batch::*;
+use bloodbank::*;
+use cache::*;
+use care_gap::*;
+use care_plan::*;
+use caregiver::*;
+use catalog::*;
+use chart::*;
+use communication::*;
+use compression::*;
+use consent_receipt::*;
+use controlled::*;
+use critical_result::*;
+use custom_field::*;
+use death::*;
+use device::*;
+use diet::*;
+use directory::*;
+use encoding::*;
+use encounter::*;
+use entity::*;
+use equipment::*;
+use family::*;
+use federation::*;
+use growth::*;
+use imaging::*;
+use incident::*;
+use infection::*;
+use interaction::*;
+use invitation::*;
+use kiosk::*;
+use legal_export::*;
+use limits::*;
+use locale::*;
+use mar::*;
+use maternity::*;
+use medication_reminder::*;
+use newborn::*;
+use notification::*;
+use nurse::*;
+use patient_header::*;
+use pharmacy::*;
+use pin::*;
+use placement::*;
+use premium::*;
+use prescription_code::*;
+use problem::*;
+use procedure::*;
+use procedure_code::*;
+use procedure_consent::*;
+use public_health::*;
+use record::*;
+use registration::*;
+use replication::*;
+use report::*;
+use residency::*;
+use search::*;
+#[cfg(feature = "dev")]
+use seed::*;
+use sensitivity::*;
+use series::*;
+use shard::*;
+use sharing::*;
+use shift::*;
+use siem::*;
+use signing::*;
+use site::*;
+use storage::*;
+use survey::*;
+use tag::*;
+use terminology::*;
+use tier::*;
+use timeline::*;
+use timezone::*;
+use transplant::*;
+use triage::*;
+use trial::*;
+use undo::*;
+use upgrade::*;
+use usage::*;
+use validation::*;
+use vitals::*;
+use vitals_alert::*;
+use waitlist::*;
+use ward::*;
+use whoami::*;
+
 // Define type aliases for convenience
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+type Memory = JournaledMemory;
 type IdCell = Cell<u64, Memory>;
 
+// Implement 'Storable' and 'BoundedStorable' for a candid type with the given max size
+macro_rules! impl_storable {
+    ($type:ty, $max_size:expr) => {
+        impl ic_stable_structures::Storable for $type {
+            fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+                std::borrow::Cow::Owned($crate::encode_stored(self, $max_size))
+            }
+            fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                $crate::decode_stored(bytes.as_ref())
+            }
+        }
+
+        impl ic_stable_structures::BoundedStorable for $type {
+            const MAX_SIZE: u32 = $max_size;
+            const IS_FIXED_SIZE: bool = false;
+        }
+    };
+    // for types holding long free text, stored lz4-compressed once large enough
+    ($type:ty, $max_size:expr, compressed) => {
+        impl ic_stable_structures::Storable for $type {
+            fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+                std::borrow::Cow::Owned($crate::compress_value($crate::encode_stored(
+                    self, $max_size,
+                )))
+            }
+            fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                $crate::decode_stored($crate::decompress_value(bytes).as_ref())
+            }
+        }
+
+        impl ic_stable_structures::BoundedStorable for $type {
+            const MAX_SIZE: u32 = $max_size;
+            const IS_FIXED_SIZE: bool = false;
+        }
+    };
+}
+pub(crate) use impl_storable;
+
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Patient {
     id: u64,
@@ -19,18 +272,27 @@ struct Patient {
     password: String,
     doctors_ids: Vec<u64>,
     hospitals_ids: Vec<u64>,
+    blood_type: Option<BloodType>,
+    date_of_birth: Option<u64>,
+    sex: Option<Sex>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum Sex {
+    Female,
+    Male,
 }
 
 // Implement the 'Storable' traits
 
 impl Storable for Patient {
-    // Conversion to bytes
+    // Conversion to bytes, compressed when the history makes it large
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(compress_value(encode_stored(self, Self::MAX_SIZE)))
     }
     // Conversion from bytes
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_stored(decompress_value(bytes).as_ref())
     }
 }
 
@@ -47,11 +309,11 @@ struct Hospital {
 impl Storable for Hospital {
     // Conversion to bytes
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(encode_stored(self, Self::MAX_SIZE))
     }
     // Conversion from bytes
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_stored(bytes.as_ref())
     }
 }
 
@@ -62,16 +324,17 @@ struct Doctor {
     password: String,
     hospital_id: u64,
     patient_ids: Vec<u64>,
+    specialty: Option<String>,
 }
 
 impl Storable for Doctor {
     // Conversion to bytes
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(encode_stored(self, Self::MAX_SIZE))
     }
     // Conversion from bytes
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        decode_stored(bytes.as_ref())
     }
 }
 
@@ -91,10 +354,13 @@ impl BoundedStorable for Doctor {
     const IS_FIXED_SIZE: bool = false;
 }
 
+// largest page any listing endpoint returns
+const MAX_PAGE_SIZE: u64 = 100;
+
 // Define thread-local static variables for memory management and storage
 thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
+    static MEMORY_MANAGER: RefCell<ReplicatedMemoryManager> = RefCell::new(
+        ReplicatedMemoryManager::init(DefaultMemoryImpl::default())
     );
 
     static ID_COUNTER: RefCell<IdCell> = RefCell::new(
@@ -107,14 +373,15 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
     ));
 
-    static HOSPITAL_STORAGE: RefCell<StableBTreeMap<u64, Hospital, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    // hospitals and doctors are read on every staff call, so the busiest stay decoded on the heap
+    static HOSPITAL_STORAGE: RefCell<CachedMap<Hospital>> = RefCell::new(CachedMap::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))),
+        ENTITY_CACHE_CAPACITY,
     ));
 
-    static DOCTOR_STORAGE: RefCell<StableBTreeMap<u64, Doctor, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    static DOCTOR_STORAGE: RefCell<CachedMap<Doctor>> = RefCell::new(CachedMap::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))),
+        ENTITY_CACHE_CAPACITY,
     ));
 }
 
@@ -127,6 +394,10 @@ struct HospitalPayload {
     address: String,
     password: String,
     city: String,
+    region: String,
+    location: Option<GeoPoint>,
+    // language for texts sent to the hospital, e.g. "fr"
+    language: Option<String>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
@@ -136,6 +407,8 @@ struct PatientPayload {
     #[validate(length(min = 6))]
     history: String,
     password: String,
+    // language for texts sent to the patient, e.g. "fr"
+    language: Option<String>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
@@ -187,6 +460,31 @@ struct EditHospitalPayload {
     password: String,
 }
 
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct HospitalAccessPayload {
+    hospital_id: u64,
+    hospital_password: String,
+}
+
+// One page of a listing; pass next_cursor back as `after` to continue, None means the end
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Page<T> {
+    items: Vec<T>,
+    next_cursor: Option<u64>,
+}
+
+// Credentials for reading a patient's data: an assigned doctor or the patient themselves
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum PatientAccess {
+    Doctor {
+        doctor_id: u64,
+        doctor_password: String,
+    },
+    Patient {
+        patient_password: String,
+    },
+}
+
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct AccessPayload {
     doctor_id: u64,
@@ -194,32 +492,28 @@ struct AccessPayload {
     doctor_password: String,
 }
 
-// Query function to get all hospitals
+// Query function to get all hospitals, a page at a time
 #[ic_cdk::query]
-fn get_all_hospitals() -> Result<Vec<Hospital>, Error> {
-    // Retrieve all Hospitals from the storage
-    let hospital_map: Vec<(u64, Hospital)> = HOSPITAL_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the Hospitals from the tuple and create a vector
-    let hospitals: Vec<Hospital> = hospital_map
-        .into_iter()
-        .map(|(_, hospital)| hospital)
-        .map(|hospital| Hospital {
-            password: "-".to_string(),
-            ..hospital
-        })
-        .collect();
+fn get_all_hospitals(after: Option<u64>, limit: u64) -> Result<Page<DirectoryEntry>, Error> {
+    // Retrieve the next page of Hospitals from the storage
+    let page = HOSPITAL_STORAGE.with(|s| page_after(&s.borrow(), after, limit, |_| true));
+    // Only the public directory view leaves the canister
+    let hospitals: Vec<DirectoryEntry> = page.items.into_iter().map(directory_entry).collect();
 
-    match hospitals.len() {
-        0 => Err(Error::NotFound {
+    match (hospitals.len(), after) {
+        (0, None) => Err(Error::NotFound {
             msg: format!("no Hospitals found"),
         }),
-        _ => Ok(hospitals),
+        _ => Ok(Page {
+            items: hospitals,
+            next_cursor: page.next_cursor,
+        }),
     }
 }
 
 // Get Hospitals by city and name content
 #[ic_cdk::query]
-fn get_hospital_by_name(search: String) -> Result<Vec<Hospital>, Error> {
+fn get_hospital_by_name(search: String) -> Result<Vec<DirectoryEntry>, Error> {
     let query = search.to_lowercase();
     // Retrieve all Hospitals from the storage
     let hospital_map: Vec<(u64, Hospital)> = HOSPITAL_STORAGE.with(|s| s.borrow().iter().collect());
@@ -229,13 +523,10 @@ fn get_hospital_by_name(search: String) -> Result<Vec<Hospital>, Error> {
         .collect();
 
     // Filter the hospitals by name
-    let incomplete_patients: Vec<Hospital> = hospitals
+    let incomplete_patients: Vec<DirectoryEntry> = hospitals
         .into_iter()
         .filter(|hospital| (hospital.name).to_lowercase().contains(&query))
-        .map(|hospital| Hospital {
-            password: "-".to_string(),
-            ..hospital
-        })
+        .map(directory_entry)
         .collect();
 
     // Check if any hospitals are found
@@ -249,18 +540,25 @@ fn get_hospital_by_name(search: String) -> Result<Vec<Hospital>, Error> {
 
 // get hospital by ID
 #[ic_cdk::query]
-fn get_hospital_by_id(id: u64) -> Result<Hospital, Error> {
+fn get_hospital_by_id(id: u64) -> Result<DirectoryEntry, Error> {
     match HOSPITAL_STORAGE.with(|hospitals| hospitals.borrow().get(&id)) {
-        Some(hospital) => Ok(Hospital {
-            password: "-".to_string(),
-            ..hospital
-        }),
+        Some(hospital) => Ok(directory_entry(hospital)),
         None => Err(Error::NotFound {
             msg: format!("hospital of id: {} not found", id),
         }),
     }
 }
 
+// full hospital record with its doctor and patient rosters, for the hospital itself
+#[ic_cdk::query]
+fn get_hospital_details(payload: HospitalAccessPayload) -> Result<Hospital, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    Ok(Hospital {
+        password: "-".to_string(),
+        ..hospital
+    })
+}
+
 // Create new Hospital
 #[ic_cdk::update]
 fn add_hospital(payload: HospitalPayload) -> Result<Hospital, Error> {
@@ -268,7 +566,7 @@ fn add_hospital(payload: HospitalPayload) -> Result<Hospital, Error> {
     let validate_payload = payload.validate();
     if validate_payload.is_err() {
         return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
+            msg: localize_validation(&validate_payload.unwrap_err(), payload.language.as_deref()),
         });
     }
 
@@ -288,6 +586,13 @@ fn add_hospital(payload: HospitalPayload) -> Result<Hospital, Error> {
         doctors_ids: vec![],
     };
 
+    store_language(&Recipient::Hospital(id), payload.language)?;
+    save_hospital_location(HospitalLocation {
+        hospital_id: id,
+        city: payload.city,
+        region: payload.region,
+        location: payload.location,
+    })?;
     match HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(id, hospital.clone())) {
         Some(_) => Err(Error::InvalidPayload {
             msg: format!("Could not add hospital name: {}", payload.name),
@@ -299,33 +604,23 @@ fn add_hospital(payload: HospitalPayload) -> Result<Hospital, Error> {
 // update function to edit a hospital where only owners of hospitals can edit title, is_community, price and description. Non owners can only edit descriptions of communtiy hospitals. authorizations is by password
 #[ic_cdk::update]
 fn edit_hospital(payload: EditHospitalPayload) -> Result<Hospital, Error> {
-    let hospital = HOSPITAL_STORAGE.with(|hospitals| hospitals.borrow().get(&payload.hospital_id));
-
-    match hospital {
-        Some(hospital) => {
-            // check if the password provided matches hospital
-            if hospital.password != payload.password {
-                return Err(Error::Unauthorized {
-                    msg: format!("Unauthorized, password does not match, try again"),
-                });
-            }
-
-            let new_hospital = Hospital {
-                name: payload.name,
-                ..hospital.clone()
-            };
+    let hospital = authorize_hospital(payload.hospital_id, &payload.password)?;
+    let new_hospital = Hospital {
+        name: payload.name,
+        ..hospital.clone()
+    };
+    remember_change(
+        ChangeRef::Hospital(hospital.id),
+        "hospital_edited",
+        PreviousValue::HospitalName(hospital.name.clone()),
+        Actor::Hospital(hospital.id),
+        vec![hospital.id],
+    );
 
-            match HOSPITAL_STORAGE
-                .with(|s| s.borrow_mut().insert(hospital.id, new_hospital.clone()))
-            {
-                Some(_) => Ok(new_hospital),
-                None => Err(Error::InvalidPayload {
-                    msg: format!("Could not edit hospital title: {}", hospital.name),
-                }),
-            }
-        }
-        None => Err(Error::NotFound {
-            msg: format!("hospital of id: {} not found", payload.hospital_id),
+    match HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(hospital.id, new_hospital.clone())) {
+        Some(_) => Ok(new_hospital),
+        None => Err(Error::InvalidPayload {
+            msg: format!("Could not edit hospital title: {}", hospital.name),
         }),
     }
 }
@@ -333,79 +628,63 @@ fn edit_hospital(payload: EditHospitalPayload) -> Result<Hospital, Error> {
 // function to assign patient to doctor and add patient to doctor's hospital
 #[ic_cdk::update]
 fn assign_patient_to_doctor(payload: AddPatientToDoctor) -> Result<String, Error> {
-    // get patient
-    let doctor = DOCTOR_STORAGE.with(|doctors| doctors.borrow().get(&payload.doctor_id));
-    match doctor {
-        Some(doctor) => {
-            if doctor.password != payload.doctor_password {
-                return Err(Error::Unauthorized {
-                    msg: format!("Doctor Access unauthorized, password does not match, try again"),
-                });
-            }
-            let patient =
-                PATIENT_STORAGE.with(|patients| patients.borrow().get(&payload.patient_id));
-            match patient {
-                Some(patient) => {
-                    // check if the password provided matches patient
-                    if patient.password != payload.patient_password {
-                        return Err(Error::Unauthorized {
-                            msg: format!(
-                                "Patient access unauthorized, password does not match, try again"
-                            ),
-                        });
-                    }
-                    let mut new_doctor_patient_ids = doctor.patient_ids.clone();
-                    new_doctor_patient_ids.push(patient.id);
-                    let new_doctor = Doctor {
-                        patient_ids: new_doctor_patient_ids,
-                        name: doctor.name.clone(),
-                        ..doctor
-                    };
-                    // add patient to hospital
-                    match add_patient_to_hospital(doctor.hospital_id, patient.id) {
-                        Ok(_) => {
-                            // update doctor in storage
-                            match DOCTOR_STORAGE
-                                .with(|s| s.borrow_mut().insert(doctor.id, new_doctor.clone()))
-                            {
-                                Some(_) => {
-                                    // update patient
-                                    let mut new_patient_doctors_ids = patient.doctors_ids.clone();
-                                    new_patient_doctors_ids.push(doctor.id);
-                                    let new_patient = Patient {
-                                        doctors_ids: new_patient_doctors_ids,
-                                        ..patient.clone()
-                                    };
-                                    // update patient in storage
-                                    match PATIENT_STORAGE
-                                        .with(|s| s.borrow_mut().insert(patient.id, new_patient.clone()))
-                                    {
-                                        Some(_) => Ok(format!(
-                                            "Succesfully assigned patient {} to doctor: {} and hospital: {} ",
-                                            patient.name, doctor.name, doctor.hospital_id
-                                        )),
-                                        None => Err(Error::InvalidPayload {
-                                            msg: format!("Could not update patient"),
-                                        }),
-                                    }
-                                }
-                                None => Err(Error::InvalidPayload {
-                                    msg: format!("Could not update doctor"),
-                                }),
-                            }
-                        }
-                        Err(e) => Err(e),
-                    }
-                }
-                None => Err(Error::NotFound {
-                    msg: format!("Doctor of id: {} not found", payload.doctor_id),
-                }),
-            }
-        }
-        None => Err(Error::NotFound {
-            msg: format!("patient of id: {} not found", payload.patient_id),
-        }),
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    // the patient consents with their own credentials, sealed patients cannot be assigned
+    let patient = authorize_patient(payload.patient_id, &payload.patient_password)?;
+    let mut new_doctor_patient_ids = doctor.patient_ids.clone();
+    if !new_doctor_patient_ids.contains(&patient.id) {
+        new_doctor_patient_ids.push(patient.id);
+    }
+    let new_doctor = Doctor {
+        patient_ids: new_doctor_patient_ids,
+        name: doctor.name.clone(),
+        ..doctor.clone()
+    };
+    // add patient to hospital
+    add_patient_to_hospital(doctor.hospital_id, patient.id)?;
+    // update doctor in storage
+    if DOCTOR_STORAGE
+        .with(|s| s.borrow_mut().insert(doctor.id, new_doctor))
+        .is_none()
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("Could not update doctor"),
+        });
+    }
+    // update patient
+    let mut new_patient_doctors_ids = patient.doctors_ids.clone();
+    if !new_patient_doctors_ids.contains(&doctor.id) {
+        new_patient_doctors_ids.push(doctor.id);
     }
+    // link back to the hospital the patient was just added to
+    let mut new_patient_hospitals_ids = patient.hospitals_ids.clone();
+    if !new_patient_hospitals_ids.contains(&doctor.hospital_id) {
+        new_patient_hospitals_ids.push(doctor.hospital_id);
+    }
+    let new_patient = Patient {
+        doctors_ids: new_patient_doctors_ids,
+        hospitals_ids: new_patient_hospitals_ids,
+        ..patient.clone()
+    };
+    // update patient in storage
+    if save_patient(&new_patient).is_none() {
+        return Err(Error::InvalidPayload {
+            msg: format!("Could not update patient"),
+        });
+    }
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Granted,
+        "doctor_access",
+        doctor.id,
+        format!("doctor {} of hospital {}", doctor.id, doctor.hospital_id),
+        vec![],
+        None,
+    );
+    Ok(format!(
+        "Succesfully assigned patient {} to doctor: {} and hospital: {} ",
+        patient.name, doctor.name, doctor.hospital_id
+    ))
 }
 
 // helper function to add patient to hospital
@@ -415,6 +694,14 @@ fn add_patient_to_hospital(hospital_id: u64, patient_id: u64) -> Result<(), Erro
     // get doctor
     match hospital {
         Some(hospital) => {
+            if hospital.patients_ids.contains(&patient_id) {
+                return Ok(());
+            }
+            check_limit(
+                "patients per hospital",
+                hospital.patients_ids.len() as u64,
+                patient_quota(hospital.id),
+            )?;
             // add patient Id to hospital patients
             let mut new_hospital_patients_ids = hospital.patients_ids.clone();
             new_hospital_patients_ids.push(patient_id);
@@ -438,73 +725,30 @@ fn add_patient_to_hospital(hospital_id: u64, patient_id: u64) -> Result<(), Erro
     }
 }
 
-// function to add to patients medical history by patient's doctor. authorizations is by doctor password
+// function to add an entry to a patient's medical history by the patient's doctor.
+// entries are immutable once filed, corrections go through amend_patient_history
 #[ic_cdk::update]
 fn update_patient_history(payload: PatientHistoryUpdate) -> Result<String, Error> {
-    // get patient
-    let doctor = DOCTOR_STORAGE.with(|doctors| doctors.borrow().get(&payload.doctor_id));
-    match doctor {
-        Some(doctor) => {
-            if doctor.password != payload.doctor_password {
-                return Err(Error::Unauthorized {
-                    msg: format!("Doctor Access unauthorized, password does not match, try again"),
-                });
-            }
-            let patient =
-                PATIENT_STORAGE.with(|patients| patients.borrow().get(&payload.patient_id));
-            match patient {
-                Some(patient) => {
-                    // check if the password provided matches patient
-                    if patient.doctors_ids.contains(&doctor.id) == false {
-                        return Err(Error::Unauthorized {
-                            msg: format!(
-                                "Patient access unauthorized, doctor is not assigned to patient, try again"
-                            ),
-                        });
-                    }
-                    let new_patient = Patient {
-                        // add new history to patient.history with current time and doctor id
-                        history: format!(
-                            "{} \n Doctor {} : {} at {} \n {}",
-                            patient.history,
-                            doctor.id,
-                            { doctor.name },
-                            time(),
-                            payload.new_history
-                        ),
-                        ..patient.clone()
-                    };
-                    // update patient in storage
-                    match PATIENT_STORAGE
-                        .with(|s| s.borrow_mut().insert(patient.id, new_patient.clone()))
-                    {
-                        Some(_) => Ok(format!(
-                            "Succesfully updated patient {} history",
-                            patient.name
-                        )),
-                        None => Err(Error::InvalidPayload {
-                            msg: format!("Could not update patient"),
-                        }),
-                    }
-                }
-                None => Err(Error::NotFound {
-                    msg: format!("Patient of id: {} not found", payload.patient_id),
-                }),
-            }
-        }
-        None => Err(Error::NotFound {
-            msg: format!("Doctor of id: {} not found", payload.doctor_id),
-        }),
-    }
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let entry = add_history_entry(&doctor, &patient, payload.new_history)?;
+    Ok(format!(
+        "Succesfully added entry {} to patient {} history",
+        entry.id, patient.name
+    ))
 }
 
-// Define query function to get a patient by ID
+// Define query function to get a patient by ID. Anyone can call it, so clinical fields and
+// the demographics that would identify the patient with the name are cleared
 #[ic_cdk::query]
 fn get_patient(id: u64) -> Result<Patient, Error> {
     match PATIENT_STORAGE.with(|patients| patients.borrow().get(&id)) {
         Some(patient) => Ok(Patient {
             password: "-".to_string(),
             history: "-".to_string(),
+            blood_type: None,
+            date_of_birth: None,
+            sex: None,
             ..patient
         }),
         None => Err(Error::NotFound {
@@ -516,41 +760,14 @@ fn get_patient(id: u64) -> Result<Patient, Error> {
 // query function for doctor to get patient info by patient id and doctor password
 #[ic_cdk::query]
 fn get_patient_info(payload: AccessPayload) -> Result<Patient, Error> {
-    // get patient
-    let doctor = DOCTOR_STORAGE.with(|doctors| doctors.borrow().get(&payload.doctor_id));
-    match doctor {
-        Some(doctor) => {
-            if doctor.password != payload.doctor_password {
-                return Err(Error::Unauthorized {
-                    msg: format!("Doctor Access unauthorized, password does not match, try again"),
-                });
-            }
-            let patient =
-                PATIENT_STORAGE.with(|patients| patients.borrow().get(&payload.patient_id));
-            match patient {
-                Some(patient) => {
-                    // check if the password provided matches patient
-                    if patient.doctors_ids.contains(&doctor.id) == false {
-                        return Err(Error::Unauthorized {
-                            msg: format!(
-                                "Patient access unauthorized, doctor is not assigned to patient, get patient permission"
-                            ),
-                        });
-                    }
-                    Ok(Patient {
-                        password: "-".to_string(),
-                        ..patient.clone()
-                    })
-                }
-                None => Err(Error::NotFound {
-                    msg: format!("Doctor of id: {} not found", payload.doctor_id),
-                }),
-            }
-        }
-        None => Err(Error::NotFound {
-            msg: format!("patient of id: {} not found", payload.patient_id),
-        }),
-    }
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    // the history comes from the immutable entries and their amendments, not the frozen text
+    Ok(Patient {
+        password: "-".to_string(),
+        history: render_history(&patient_history(patient.id)),
+        ..patient
+    })
 }
 
 // Update function to add a patient
@@ -560,7 +777,7 @@ fn add_patient(payload: PatientPayload) -> Result<Patient, Error> {
     let validate_payload = payload.validate();
     if validate_payload.is_err() {
         return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
+            msg: localize_validation(&validate_payload.unwrap_err(), payload.language.as_deref()),
         });
     }
 
@@ -578,9 +795,13 @@ fn add_patient(payload: PatientPayload) -> Result<Patient, Error> {
         password: payload.password,
         doctors_ids: vec![],
         hospitals_ids: vec![],
+        blood_type: None,
+        date_of_birth: None,
+        sex: None,
     };
 
-    match PATIENT_STORAGE.with(|s| s.borrow_mut().insert(id, patient.clone())) {
+    store_language(&Recipient::Patient(id), payload.language)?;
+    match save_patient(&patient) {
         None => Ok(patient),
         Some(_) => Err(Error::InvalidPayload {
             msg: format!("Could not add patient name: {}", payload.name),
@@ -593,35 +814,65 @@ fn add_patient(payload: PatientPayload) -> Result<Patient, Error> {
 // update function to edit a patient where authorizations is by password
 #[ic_cdk::update]
 fn edit_patient(payload: EditPatientPayload) -> Result<Patient, Error> {
-    let patient = PATIENT_STORAGE.with(|patients| patients.borrow().get(&payload.patient_id));
-
-    match patient {
-        Some(patient) => {
-            // check if the password provided matches patient
-            if patient.password != payload.password {
-                return Err(Error::Unauthorized {
-                    msg: format!("Unauthorized, password does not match, try again"),
-                });
-            }
-
-            let new_patient = Patient {
-                name: payload.name,
-                ..patient.clone()
-            };
+    // rejects sealed patients along with wrong passwords
+    let patient = authorize_patient(payload.patient_id, &payload.password)?;
+    let new_patient = Patient {
+        name: payload.name,
+        ..patient.clone()
+    };
+    remember_change(
+        ChangeRef::Patient(patient.id),
+        "patient_edited",
+        previous_patient_details(&patient),
+        Actor::Patient(patient.id),
+        patient.hospitals_ids.clone(),
+    );
 
-            match PATIENT_STORAGE.with(|s| s.borrow_mut().insert(patient.id, new_patient.clone())) {
-                Some(_) => Ok(new_patient),
-                None => Err(Error::InvalidPayload {
-                    msg: format!("Could not edit patient name: {}", patient.name),
-                }),
-            }
-        }
-        None => Err(Error::NotFound {
-            msg: format!("patient of id: {} not found", payload.patient_id),
+    match save_patient(&new_patient) {
+        Some(_) => Ok(new_patient),
+        None => Err(Error::InvalidPayload {
+            msg: format!("Could not edit patient name: {}", patient.name),
         }),
     }
 }
 
+// set a patient's date of birth and sex, by the patient or an assigned doctor
+#[ic_cdk::update]
+fn set_patient_demographics(
+    patient_id: u64,
+    access: PatientAccess,
+    date_of_birth: u64,
+    sex: Sex,
+) -> Result<Patient, Error> {
+    let (patient, actor) = authorize_patient_access(patient_id, &access)?;
+    if date_of_birth > time() {
+        return Err(Error::InvalidPayload {
+            msg: "Date of birth cannot be in the future".to_string(),
+        });
+    }
+    remember_change(
+        ChangeRef::Patient(patient.id),
+        "demographics_updated",
+        previous_patient_details(&patient),
+        actor.clone(),
+        patient.hospitals_ids.clone(),
+    );
+    let new_patient = Patient {
+        date_of_birth: Some(date_of_birth),
+        sex: Some(sex),
+        ..patient
+    };
+    save_patient(&new_patient);
+    audit(
+        actor,
+        None,
+        Some(new_patient.id),
+        "demographics_updated",
+        String::new(),
+    );
+    Ok(new_patient)
+}
+
 // get doctor by ID
 #[ic_cdk::query]
 fn get_doctor_by_id(id: u64) -> Result<Doctor, Error> {
@@ -639,36 +890,20 @@ fn get_doctor_by_id(id: u64) -> Result<Doctor, Error> {
 // add doctor to hospital
 #[ic_cdk::update]
 fn add_doctor(payload: DoctorPayload) -> Result<Doctor, Error> {
-    let hospital = HOSPITAL_STORAGE.with(|hospitals| hospitals.borrow().get(&payload.hospital_id));
-    match hospital {
-        Some(hospital) => {
-            // check if the password provided matches hospital
-            if hospital.password != payload.hospital_password {
-                return Err(Error::Unauthorized {
-                    msg: format!(
-                        "Hospital access unauthorized, password does not match, try again"
-                    ),
-                });
-            }
-            let validate_payload = payload.validate();
-            if validate_payload.is_err() {
-                return Err(Error::InvalidPayload {
-                    msg: validate_payload.unwrap_err().to_string(),
-                });
-            }
-
-            match add_doctor_to_storage(payload.clone()) {
-                Ok(doctor) => match add_doctor_to_hospital(doctor, hospital.clone()) {
-                    Ok(response) => Ok(response),
-                    Err(e) => Err(e),
-                },
-                Err(e) => return Err(e),
-            }
-        }
-        None => Err(Error::NotFound {
-            msg: format!("Hospital of id: {} not found", payload.hospital_id),
-        }),
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let validate_payload = payload.validate();
+    if validate_payload.is_err() {
+        return Err(Error::InvalidPayload {
+            msg: localize_validation(
+                &validate_payload.unwrap_err(),
+                language_of(&Recipient::Hospital(hospital.id)).as_deref(),
+            ),
+        });
     }
+
+    check_doctor_quota(&hospital)?;
+    let doctor = add_doctor_to_storage(payload)?;
+    add_doctor_to_hospital(doctor, hospital)
 }
 
 // helper function to add doctor to storage
@@ -686,6 +921,7 @@ fn add_doctor_to_storage(payload: DoctorPayload) -> Result<Doctor, Error> {
         hospital_id: payload.hospital_id,
         password: payload.password,
         patient_ids: vec![],
+        specialty: None,
     };
     match DOCTOR_STORAGE.with(|s| s.borrow_mut().insert(id, doctor.clone())) {
         None => Ok(doctor),
@@ -726,73 +962,315 @@ fn add_doctor_to_hospital(doctor: Doctor, hospital: Hospital) -> Result<Doctor,
     }
 }
 
+// helper function to drop a doctor from a hospital's staff list
+fn remove_doctor_from_hospital(hospital_id: u64, doctor_id: u64) {
+    HOSPITAL_STORAGE.with(|s| {
+        let mut hospitals = s.borrow_mut();
+        if let Some(mut hospital) = hospitals.get(&hospital_id) {
+            hospital.doctors_ids.retain(|id| *id != doctor_id);
+            hospitals.insert(hospital_id, hospital);
+        }
+    });
+}
+
 // add doctor to hospital
 #[ic_cdk::update]
 fn edit_doctor(payload: EditDoctor) -> Result<String, Error> {
-    // get doctor
-    let doctor = DOCTOR_STORAGE.with(|doctors| doctors.borrow().get(&payload.doctor_id));
-    match doctor {
-        Some(doctor) => {
-            if doctor.password != payload.doctor_password {
-                return Err(Error::Unauthorized {
-                    msg: format!("Doctor Access unauthorized, password does not match, try again"),
-                });
-            }
-            let hospital =
-                HOSPITAL_STORAGE.with(|hospitals| hospitals.borrow().get(&payload.hospital_id));
-            match hospital {
-                Some(hospital) => {
-                    // check if the password provided matches hospital
-                    if hospital.password != payload.hospital_password {
-                        return Err(Error::Unauthorized {
-                            msg: format!(
-                                "Hospital access unauthorized, password does not match, try again"
-                            ),
-                        });
-                    }
-                    let mut new_hospital_doctors_ids = hospital.doctors_ids.clone();
-                    new_hospital_doctors_ids.push(doctor.id);
-                    let new_hospital = Hospital {
-                        doctors_ids: new_hospital_doctors_ids,
-                        name: hospital.name.clone(),
-                        ..hospital.clone()
-                    };
-                    // update hospital in storage
-                    match HOSPITAL_STORAGE
-                        .with(|s| s.borrow_mut().insert(hospital.id, new_hospital.clone()))
-                    {
-                        Some(_) => {
-                            // update doctor
-                            let new_doctor = Doctor {
-                                hospital_id: hospital.id,
-                                name: payload.name.clone(),
-                                ..doctor.clone()
-                            };
-                            // update doctor in storage
-                            match DOCTOR_STORAGE
-                                .with(|s| s.borrow_mut().insert(doctor.id, new_doctor.clone()))
-                            {
-                                Some(_) => Ok(format!(
-                                    "Succesfully assigned doctor {} to hospital: {} ",
-                                    payload.name, hospital.name
-                                )),
-                                None => Err(Error::InvalidPayload {
-                                    msg: format!("Could not update doctor"),
-                                }),
-                            }
-                        }
-                        None => Err(Error::InvalidPayload {
-                            msg: format!("Could not update hospital"),
-                        }),
-                    }
-                }
-                None => Err(Error::NotFound {
-                    msg: format!("Hospital of id: {} not found", payload.hospital_id),
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if !hospital.doctors_ids.contains(&doctor.id) {
+        check_doctor_quota(&hospital)?;
+    }
+    let mut new_hospital_doctors_ids = hospital.doctors_ids.clone();
+    if !new_hospital_doctors_ids.contains(&doctor.id) {
+        new_hospital_doctors_ids.push(doctor.id);
+    }
+    let new_hospital = Hospital {
+        doctors_ids: new_hospital_doctors_ids,
+        name: hospital.name.clone(),
+        ..hospital.clone()
+    };
+    // a doctor works at one hospital, so leave the previous one
+    if doctor.hospital_id != hospital.id {
+        remove_doctor_from_hospital(doctor.hospital_id, doctor.id);
+    }
+    // update hospital in storage
+    match HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(hospital.id, new_hospital.clone())) {
+        Some(_) => {
+            // update doctor
+            let new_doctor = Doctor {
+                hospital_id: hospital.id,
+                name: payload.name.clone(),
+                ..doctor.clone()
+            };
+            // update doctor in storage
+            match DOCTOR_STORAGE.with(|s| s.borrow_mut().insert(doctor.id, new_doctor.clone())) {
+                Some(_) => Ok(format!(
+                    "Succesfully assigned doctor {} to hospital: {} ",
+                    payload.name, hospital.name
+                )),
+                None => Err(Error::InvalidPayload {
+                    msg: format!("Could not update doctor"),
                 }),
             }
         }
+        None => Err(Error::InvalidPayload {
+            msg: format!("Could not update hospital"),
+        }),
+    }
+}
+
+// start the periodic jobs, timers do not survive upgrades so this runs after each one
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(24 * 60 * 60), check_stock_alerts);
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(24 * 60 * 60),
+        generate_maintenance_tasks,
+    );
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(24 * 60 * 60), archive_expired_records);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), prune_code_tables);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60), migrate_stored_encoding);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), || {
+        ic_cdk::spawn(autoscale_attachments())
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(24 * 60 * 60), || {
+        ic_cdk::spawn(refresh_premium_entitlements())
+    });
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(STORAGE_SAMPLE_SECS),
+        sample_storage_usage,
+    );
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), send_care_plan_reminders);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(10 * 60), detect_care_gaps);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), roll_up_audit_log);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), detect_access_anomalies);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), update_mar);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(15 * 60), send_medication_reminders);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), materialize_appointment_series);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60), expire_appointment_holds);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60), deliver_outbox);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(15 * 60), send_appointment_reminders);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(30), || {
+        ic_cdk::spawn(replicate_to_standby())
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(5 * 60), escalate_critical_results);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60), escalate_vitals_alerts);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60), || {
+        ic_cdk::spawn(sign_consent_receipts())
+    });
+}
+
+#[ic_cdk::init]
+fn init() {
+    seed_catalog();
+    seed_terminology();
+    start_timers();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    // a promoted standby has no snapshot of its own, its stores were checked on the old primary
+    let promoted = finish_promotion();
+    // a standby stays passive until promoted
+    if is_standby() {
+        return;
+    }
+    // the stores are rehashed by timer and compared with the snapshot taken before the upgrade
+    if !promoted {
+        start_upgrade_verification();
+    }
+    backfill_patient_headers();
+    backfill_record_usage();
+    backfill_open_vitals_alerts();
+    seed_catalog();
+    seed_terminology();
+    start_timers();
+    certify_signature_chain();
+}
+
+// helper function to get the next id from the shared counter
+fn next_id() -> u64 {
+    ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids")
+}
+
+// helper function to read a page of a map in key order, starting after the last seen key.
+// keys are never reused, so items inserted between pages cannot shift or repeat entries
+fn page_after<V: BoundedStorable>(
+    map: &StableBTreeMap<u64, V, Memory>,
+    after: Option<u64>,
+    limit: u64,
+    keep: impl Fn(&V) -> bool,
+) -> Page<V> {
+    let start = match after {
+        Some(key) => Bound::Excluded(key),
+        None => Bound::Unbounded,
+    };
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut items = vec![];
+    let mut last_key = None;
+    for (key, value) in map.range((start, Bound::Unbounded)) {
+        if !keep(&value) {
+            continue;
+        }
+        if items.len() == limit {
+            return Page {
+                items,
+                next_cursor: last_key,
+            };
+        }
+        last_key = Some(key);
+        items.push(value);
+    }
+    Page {
+        items,
+        next_cursor: None,
+    }
+}
+
+// helper function to render bytes as lowercase hex for codes and tokens
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// helper function to check a doctor's password and return the doctor
+fn authorize_doctor(doctor_id: u64, password: &str) -> Result<Doctor, Error> {
+    reject_kiosk_caller()?;
+    match DOCTOR_STORAGE.with(|doctors| doctors.borrow().get(&doctor_id)) {
+        Some(doctor)
+            if doctor.password == password || caller_holds(AccountRole::Doctor(doctor_id)) =>
+        {
+            Ok(doctor)
+        }
+        Some(doctor) => {
+            audit_failed_password(Actor::Doctor(doctor.id), Some(doctor.hospital_id), None);
+            Err(Error::Unauthorized {
+                msg: "Doctor Access unauthorized, password does not match, try again".to_string(),
+            })
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Doctor of id: {} not found", doctor_id),
+        }),
+    }
+}
+
+// helper function to restrict admin endpoints to the canister controllers
+fn authorize_controller() -> Result<(), Error> {
+    if is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only canister controllers can call this endpoint".to_string(),
+        })
+    }
+}
+
+// helper function to check a hospital's password and return the hospital
+fn authorize_hospital(hospital_id: u64, password: &str) -> Result<Hospital, Error> {
+    reject_kiosk_caller()?;
+    match HOSPITAL_STORAGE.with(|hospitals| hospitals.borrow().get(&hospital_id)) {
+        Some(hospital)
+            if hospital.password == password
+                || caller_holds(AccountRole::Hospital(hospital_id)) =>
+        {
+            Ok(hospital)
+        }
+        Some(hospital) => {
+            audit_failed_password(Actor::Hospital(hospital.id), Some(hospital.id), None);
+            Err(Error::Unauthorized {
+                msg: "Hospital access unauthorized, password does not match, try again".to_string(),
+            })
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Hospital of id: {} not found", hospital_id),
+        }),
+    }
+}
+
+// helper function to check a patient's password against the patient's header, for callers that
+// need no more than the patient's id, name or care team
+fn authenticate_patient(patient_id: u64, password: &str) -> Result<PatientHeader, Error> {
+    reject_kiosk_caller()?;
+    check_not_sealed(patient_id)?;
+    match patient_header(patient_id) {
+        Some(patient)
+            if patient.password == password
+                || caller_holds(AccountRole::Patient(patient_id))
+                || opens_linked_newborn(patient_id, password) =>
+        {
+            Ok(patient)
+        }
+        Some(patient) => {
+            audit_failed_password(Actor::Patient(patient.id), None, Some(patient.id));
+            Err(Error::Unauthorized {
+                msg: "Patient access unauthorized, password does not match, try again".to_string(),
+            })
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Patient of id: {} not found", patient_id),
+        }),
+    }
+}
+
+// helper function to check a patient's password and return the patient
+fn authorize_patient(patient_id: u64, password: &str) -> Result<Patient, Error> {
+    authenticate_patient(patient_id, password)?;
+    PATIENT_STORAGE
+        .with(|patients| patients.borrow().get(&patient_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Patient of id: {} not found", patient_id),
+        })
+}
+
+// the fields of a patient that edits overwrite, kept for undo
+fn previous_patient_details(patient: &Patient) -> PreviousValue {
+    PreviousValue::PatientDetails {
+        name: patient.name.clone(),
+        date_of_birth: patient.date_of_birth,
+        sex: patient.sex,
+    }
+}
+
+// helper function to check patient access credentials, returning the patient and who is reading
+fn authorize_patient_access(
+    patient_id: u64,
+    access: &PatientAccess,
+) -> Result<(Patient, Actor), Error> {
+    match access {
+        PatientAccess::Doctor {
+            doctor_id,
+            doctor_password,
+        } => {
+            let doctor = authorize_doctor(*doctor_id, doctor_password)?;
+            let patient = get_assigned_patient(&doctor, patient_id)?;
+            Ok((patient, Actor::Doctor(doctor.id)))
+        }
+        PatientAccess::Patient { patient_password } => {
+            let patient = authorize_patient(patient_id, patient_password)?;
+            Ok((patient, Actor::Patient(patient_id)))
+        }
+    }
+}
+
+// helper function to get a patient the doctor is assigned to
+fn get_assigned_patient(doctor: &Doctor, patient_id: u64) -> Result<Patient, Error> {
+    check_not_sealed(patient_id)?;
+    match patient_header(patient_id) {
+        Some(header) if header.doctors_ids.contains(&doctor.id) => PATIENT_STORAGE
+            .with(|patients| patients.borrow().get(&patient_id))
+            .ok_or(Error::NotFound {
+                msg: format!("Patient of id: {} not found", patient_id),
+            }),
+        Some(_) => Err(Error::Unauthorized {
+            msg: "Patient access unauthorized, doctor is not assigned to patient, get patient permission"
+                .to_string(),
+        }),
         None => Err(Error::NotFound {
-            msg: format!("doctor of id: {} not found", payload.doctor_id),
+            msg: format!("Patient of id: {} not found", patient_id),
         }),
     }
 }
@@ -804,6 +1282,7 @@ enum Error {
     AlreadyInit { msg: String },
     InvalidPayload { msg: String },
     Unauthorized { msg: String },
+    LimitExceeded { msg: String },
 }
 
 // Candid generator for exporting the Candid interface
@@ -0,0 +1,79 @@
+use crate::{authorize_controller, impl_storable, Error, Memory, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Cell;
+use std::cell::RefCell;
+
+// Caps on entity counts and sizes, kept below the stores' encoded size bounds
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Limits {
+    pub max_patients_per_hospital: u64,
+    pub max_records_per_patient: u64,
+    // size of a medical record body, the largest free-form content the canister accepts
+    pub max_record_body_bytes: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_patients_per_hospital: 100,
+            max_records_per_patient: 1000,
+            max_record_body_bytes: 8 * 1024,
+        }
+    }
+}
+
+impl_storable!(Limits, 128);
+
+// hard ceilings set by the stored types' MAX_SIZE
+pub(crate) const PATIENTS_PER_HOSPITAL_CEILING: u64 = 100;
+// doctor ids share the hospital's 1024 bytes with the patient ids
+pub(crate) const DOCTORS_PER_HOSPITAL_CEILING: u64 = 15;
+const RECORD_BODY_CEILING: u64 = 15 * 1024;
+
+thread_local! {
+    static LIMITS: RefCell<Cell<Limits, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))),
+            Limits::default(),
+        )
+        .expect("Cannot create limits")
+    );
+}
+
+pub(crate) fn limits() -> Limits {
+    LIMITS.with(|l| l.borrow().get().clone())
+}
+
+// helper function to reject a write that would go past a cap
+pub(crate) fn check_limit(what: &str, current: u64, max: u64) -> Result<(), Error> {
+    if current >= max {
+        return Err(Error::LimitExceeded {
+            msg: format!("Limit of {} {} reached", max, what),
+        });
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_limits() -> Limits {
+    limits()
+}
+
+#[ic_cdk::update]
+fn set_limits(new_limits: Limits) -> Result<Limits, Error> {
+    authorize_controller()?;
+    if new_limits.max_patients_per_hospital > PATIENTS_PER_HOSPITAL_CEILING
+        || new_limits.max_record_body_bytes > RECORD_BODY_CEILING
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Limits cannot exceed {} patients per hospital or {} bytes per record body",
+                PATIENTS_PER_HOSPITAL_CEILING, RECORD_BODY_CEILING
+            ),
+        });
+    }
+    LIMITS
+        .with(|l| l.borrow_mut().set(new_limits.clone()))
+        .expect("Cannot update limits");
+    Ok(new_limits)
+}
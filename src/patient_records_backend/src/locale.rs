@@ -0,0 +1,201 @@
+use crate::time;
+use crate::{
+    authorize_controller, authorize_ref, impl_storable, Error, Memory, Recipient, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use validator::ValidationErrors;
+
+// Translations of one language, e.g. "fr" or "pt-BR", from message key to template.
+// Templates use {name} placeholders for their arguments
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TranslationTable {
+    pub language: String,
+    pub entries: BTreeMap<String, String>,
+    pub updated_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LanguagePreference {
+    language: String,
+}
+
+impl_storable!(TranslationTable, 65536);
+impl_storable!(LanguagePreference, 64);
+
+thread_local! {
+    // keyed by the language tag padded to 16 bytes
+    static TRANSLATIONS: RefCell<StableBTreeMap<[u8; 16], TranslationTable, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+
+    static LANGUAGE_PREFERENCES: RefCell<StableBTreeMap<(u8, u64), LanguagePreference, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct TranslationsPayload {
+    pub language: String,
+    pub entries: Vec<(String, String)>,
+    // drop every entry not in this upload instead of patching the table
+    pub replace: bool,
+}
+
+// A user-facing string: its translation key, the english template and the arguments
+pub(crate) struct Text {
+    key: &'static str,
+    template: &'static str,
+    args: Vec<(&'static str, String)>,
+}
+
+pub(crate) fn text(
+    key: &'static str,
+    template: &'static str,
+    args: Vec<(&'static str, String)>,
+) -> Text {
+    Text {
+        key,
+        template,
+        args,
+    }
+}
+
+impl Text {
+    // the text in the given language, english when there is no translation for it
+    pub(crate) fn render(&self, language: Option<&str>) -> String {
+        let template = language
+            .and_then(|language| translation(language, self.key))
+            .unwrap_or_else(|| self.template.to_string());
+        self.args.iter().fold(template, |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), value)
+        })
+    }
+}
+
+// language tags are at most 16 bytes ("zh-Hant-TW"), which makes them a fixed size key
+fn table_key(language: &str) -> Result<[u8; 16], Error> {
+    let bytes = language.as_bytes();
+    if bytes.is_empty()
+        || bytes.len() > 16
+        || !bytes
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || *b == b'-')
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("Invalid language tag: {}", language),
+        });
+    }
+    let mut key = [0u8; 16];
+    key[..bytes.len()].copy_from_slice(bytes);
+    Ok(key)
+}
+
+// look the key up in the language, then in its base language ("pt" for "pt-BR")
+fn translation(language: &str, key: &str) -> Option<String> {
+    let lookup = |language: &str| {
+        let table_key = table_key(language).ok()?;
+        TRANSLATIONS.with(|t| t.borrow().get(&table_key)?.entries.get(key).cloned())
+    };
+    lookup(language).or_else(|| lookup(language.split('-').next()?))
+}
+
+pub(crate) fn language_of(entity: &Recipient) -> Option<String> {
+    LANGUAGE_PREFERENCES
+        .with(|p| p.borrow().get(&entity.key()))
+        .map(|preference| preference.language)
+}
+
+// validator errors in the language, one "field: message" per failed check
+pub(crate) fn localize_validation(errors: &ValidationErrors, language: Option<&str>) -> String {
+    if language.is_none() {
+        return errors.to_string();
+    }
+    errors
+        .field_errors()
+        .iter()
+        .flat_map(|(field, field_errors)| {
+            field_errors.iter().map(move |error| {
+                let code = error.code.to_string();
+                let key = format!("validation.{}", code);
+                let template = language
+                    .and_then(|language| translation(language, &key))
+                    .unwrap_or_else(|| "{field} is invalid ({code})".to_string());
+                template.replace("{field}", field).replace("{code}", &code)
+            })
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub(crate) fn store_language(entity: &Recipient, language: Option<String>) -> Result<(), Error> {
+    match language {
+        Some(language) => {
+            table_key(&language)?;
+            LANGUAGE_PREFERENCES.with(|p| {
+                p.borrow_mut()
+                    .insert(entity.key(), LanguagePreference { language })
+            });
+        }
+        None => {
+            LANGUAGE_PREFERENCES.with(|p| p.borrow_mut().remove(&entity.key()));
+        }
+    }
+    Ok(())
+}
+
+// set or clear the language a hospital, doctor or patient receives texts in
+#[ic_cdk::update]
+fn set_preferred_language(
+    entity: Recipient,
+    password: String,
+    language: Option<String>,
+) -> Result<Option<String>, Error> {
+    authorize_ref(entity, &password)?;
+    store_language(&entity, language.clone())?;
+    Ok(language)
+}
+
+// upload or patch the translation table of a language
+#[ic_cdk::update]
+fn upload_translations(payload: TranslationsPayload) -> Result<TranslationTable, Error> {
+    authorize_controller()?;
+    let key = table_key(&payload.language)?;
+    let mut table = TRANSLATIONS
+        .with(|t| t.borrow().get(&key))
+        .filter(|_| !payload.replace)
+        .unwrap_or(TranslationTable {
+            language: payload.language.clone(),
+            entries: BTreeMap::new(),
+            updated_at: 0,
+        });
+    for (message_key, template) in payload.entries {
+        if template.is_empty() {
+            table.entries.remove(&message_key);
+        } else {
+            table.entries.insert(message_key, template);
+        }
+    }
+    table.updated_at = time();
+    if !candid::encode_one(&table).is_ok_and(|bytes| bytes.len() <= 65536) {
+        return Err(Error::LimitExceeded {
+            msg: "Translation table is larger than 64 KiB".to_string(),
+        });
+    }
+    TRANSLATIONS.with(|t| t.borrow_mut().insert(key, table.clone()));
+    Ok(table)
+}
+
+#[ic_cdk::query]
+fn get_translations(language: String) -> Result<TranslationTable, Error> {
+    let key = table_key(&language)?;
+    TRANSLATIONS
+        .with(|t| t.borrow().get(&key))
+        .ok_or(Error::NotFound {
+            msg: format!("No translations for language {}", language),
+        })
+}
@@ -0,0 +1,294 @@
+use crate::{
+    audit, authorize_nurse, get_authorized_encounter, get_encounter_by_id, get_encounter_entries,
+    impl_storable, next_id, notify, open_encounters, text, time, Actor, Encounter,
+    EncounterAccessPayload, EncounterEntryKind, EncounterStatus, Error, Memory, Priority,
+    Recipient, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+const DAY_NS: u64 = 24 * HOUR_NS;
+// doses are scheduled this far ahead, the hourly timer keeps extending it
+const SCHEDULE_AHEAD_NS: u64 = DAY_NS;
+// a dose not signed off this long after its time is flagged as missed
+const MISSED_AFTER_NS: u64 = HOUR_NS;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum DoseStatus {
+    Scheduled,
+    Given,
+    // deliberately not given, e.g. patient nil by mouth or refusing
+    Withheld { reason: String },
+    Missed,
+}
+
+// One scheduled administration of a prescribed medication during an admission
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Dose {
+    pub id: u64,
+    pub encounter_id: u64,
+    pub patient_id: u64,
+    // the prescription entry the dose comes from
+    pub prescription_entry_id: u64,
+    pub medication: String,
+    pub dosage: String,
+    pub scheduled_at: u64,
+    pub status: DoseStatus,
+    pub signed_by: Option<u64>,
+    pub signed_at: Option<u64>,
+    pub note: Option<String>,
+    // kept when a missed dose is given late
+    pub missed_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum DoseOutcome {
+    Given,
+    Withheld { reason: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct DoseSignOff {
+    pub nurse_id: u64,
+    pub nurse_password: String,
+    pub encounter_id: u64,
+    pub dose_id: u64,
+    pub outcome: DoseOutcome,
+    pub note: Option<String>,
+}
+
+// The medication administration record of one admission
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MarView {
+    pub encounter: Encounter,
+    pub doses: Vec<Dose>,
+    pub given: u32,
+    pub withheld: u32,
+    pub missed: u32,
+    pub scheduled: u32,
+}
+
+impl_storable!(Dose, 1024);
+
+thread_local! {
+    // keyed by (encounter id, dose id)
+    static DOSE_STORAGE: RefCell<StableBTreeMap<(u64, u64), Dose, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89)))
+    ));
+}
+
+fn encounter_doses(encounter_id: u64) -> Vec<Dose> {
+    DOSE_STORAGE.with(|s| {
+        s.borrow()
+            .range((encounter_id, 0)..=(encounter_id, u64::MAX))
+            .map(|(_, dose)| dose)
+            .collect()
+    })
+}
+
+fn save_dose(dose: &Dose) {
+    DOSE_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert((dose.encounter_id, dose.id), dose.clone())
+    });
+}
+
+// create the doses of the encounter's running prescriptions up to a day ahead, spreading each
+// prescription's daily doses evenly from the time it was written
+pub(crate) fn schedule_doses(encounter: &Encounter) {
+    if encounter.status != EncounterStatus::Open {
+        return;
+    }
+    let now = time();
+    let horizon = now + SCHEDULE_AHEAD_NS;
+    let existing = encounter_doses(encounter.id);
+    for entry in get_encounter_entries(encounter) {
+        let EncounterEntryKind::Prescription(prescription) = &entry.kind else {
+            continue;
+        };
+        if prescription.doses_per_day == 0 {
+            continue;
+        }
+        let interval = DAY_NS / prescription.doses_per_day as u64;
+        let ends_at = entry.recorded_at + prescription.duration_days as u64 * DAY_NS;
+        // nothing is scheduled in the past, the first dose is due when prescribed
+        let mut at = match existing
+            .iter()
+            .filter(|dose| dose.prescription_entry_id == entry.id)
+            .map(|dose| dose.scheduled_at)
+            .max()
+        {
+            Some(last) => last + interval,
+            None => {
+                let elapsed = now.saturating_sub(entry.recorded_at);
+                entry.recorded_at + elapsed.div_ceil(interval) * interval
+            }
+        };
+        while at < ends_at.min(horizon) {
+            save_dose(&Dose {
+                id: next_id(),
+                encounter_id: encounter.id,
+                patient_id: encounter.patient_id,
+                prescription_entry_id: entry.id,
+                medication: prescription.medication.clone(),
+                dosage: prescription.dosage.clone(),
+                scheduled_at: at,
+                status: DoseStatus::Scheduled,
+                signed_by: None,
+                signed_at: None,
+                note: None,
+                missed_at: None,
+            });
+            at += interval;
+        }
+    }
+}
+
+// drop doses that had not come due when the patient was discharged
+pub(crate) fn cancel_pending_doses(encounter_id: u64) {
+    let now = time();
+    DOSE_STORAGE.with(|s| {
+        let mut doses = s.borrow_mut();
+        let pending: Vec<(u64, u64)> = doses
+            .range((encounter_id, 0)..=(encounter_id, u64::MAX))
+            .filter(|(_, dose)| dose.status == DoseStatus::Scheduled && dose.scheduled_at > now)
+            .map(|(key, _)| key)
+            .collect();
+        for key in pending {
+            doses.remove(&key);
+        }
+    });
+}
+
+// timer task: extend the schedule of every admission and flag doses nobody signed off,
+// telling the attending doctor
+pub(crate) fn update_mar() {
+    let now = time();
+    for encounter in open_encounters() {
+        schedule_doses(&encounter);
+        for dose in encounter_doses(encounter.id) {
+            if dose.status != DoseStatus::Scheduled || dose.scheduled_at + MISSED_AFTER_NS > now {
+                continue;
+            }
+            save_dose(&Dose {
+                status: DoseStatus::Missed,
+                missed_at: Some(now),
+                ..dose.clone()
+            });
+            notify(
+                Recipient::Doctor(encounter.doctor_id),
+                Priority::High,
+                text(
+                    "mar.dose_missed",
+                    "{medication} {dosage} for patient {patient} due at {due} was not given",
+                    vec![
+                        ("medication", dose.medication.clone()),
+                        ("dosage", dose.dosage.clone()),
+                        ("patient", dose.patient_id.to_string()),
+                        ("due", dose.scheduled_at.to_string()),
+                    ],
+                ),
+            );
+            audit(
+                Actor::System,
+                Some(encounter.hospital_id),
+                Some(dose.patient_id),
+                "dose_missed",
+                format!("dose {} of {}", dose.id, dose.medication),
+            );
+        }
+    }
+}
+
+// a nurse of the admitting hospital signs a dose off as given or withheld; a missed dose can
+// still be signed off as given late
+#[ic_cdk::update]
+fn sign_off_dose(payload: DoseSignOff) -> Result<Dose, Error> {
+    let nurse = authorize_nurse(payload.nurse_id, &payload.nurse_password)?;
+    let encounter = get_encounter_by_id(payload.encounter_id)?;
+    if encounter.hospital_id != nurse.hospital_id {
+        return Err(Error::Unauthorized {
+            msg: format!("Encounter of id: {} is at another hospital", encounter.id),
+        });
+    }
+    let dose = DOSE_STORAGE
+        .with(|s| s.borrow().get(&(encounter.id, payload.dose_id)))
+        .ok_or(Error::NotFound {
+            msg: format!("Dose of id: {} not found", payload.dose_id),
+        })?;
+    if !matches!(dose.status, DoseStatus::Scheduled | DoseStatus::Missed) {
+        return Err(Error::AlreadyInit {
+            msg: format!("Dose of id: {} is already signed off", dose.id),
+        });
+    }
+    let (status, action) = match payload.outcome {
+        DoseOutcome::Given => (DoseStatus::Given, "dose_given"),
+        DoseOutcome::Withheld { reason } => {
+            if reason.trim().is_empty() {
+                return Err(Error::InvalidPayload {
+                    msg: "A withheld dose needs a reason".to_string(),
+                });
+            }
+            (DoseStatus::Withheld { reason }, "dose_withheld")
+        }
+    };
+    let signed = Dose {
+        status,
+        signed_by: Some(nurse.id),
+        signed_at: Some(time()),
+        note: payload.note,
+        ..dose
+    };
+    save_dose(&signed);
+    audit(
+        Actor::Nurse(nurse.id),
+        Some(nurse.hospital_id),
+        Some(signed.patient_id),
+        action,
+        format!("dose {} of {}", signed.id, signed.medication),
+    );
+    Ok(signed)
+}
+
+// the whole medication administration record of an admission, for its doctors
+#[ic_cdk::query]
+fn get_admission_mar(payload: EncounterAccessPayload) -> Result<MarView, Error> {
+    let encounter = get_authorized_encounter(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.encounter_id,
+    )?;
+    let doses = encounter_doses(encounter.id);
+    let count = |matches: fn(&DoseStatus) -> bool| {
+        doses.iter().filter(|dose| matches(&dose.status)).count() as u32
+    };
+    Ok(MarView {
+        given: count(|status| *status == DoseStatus::Given),
+        withheld: count(|status| matches!(status, DoseStatus::Withheld { .. })),
+        missed: count(|status| *status == DoseStatus::Missed),
+        scheduled: count(|status| *status == DoseStatus::Scheduled),
+        encounter,
+        doses,
+    })
+}
+
+// doses due at the nurse's hospital within the coming hours, and those already missed
+#[ic_cdk::query]
+fn get_due_doses(nurse_id: u64, nurse_password: String, hours: u64) -> Result<Vec<Dose>, Error> {
+    let nurse = authorize_nurse(nurse_id, &nurse_password)?;
+    let until = time() + hours.min(24) * HOUR_NS;
+    let mut due: Vec<Dose> = open_encounters()
+        .iter()
+        .filter(|encounter| encounter.hospital_id == nurse.hospital_id)
+        .flat_map(|encounter| encounter_doses(encounter.id))
+        .filter(|dose| {
+            dose.status == DoseStatus::Missed
+                || (dose.status == DoseStatus::Scheduled && dose.scheduled_at <= until)
+        })
+        .collect();
+    due.sort_by_key(|dose| dose.scheduled_at);
+    Ok(due)
+}
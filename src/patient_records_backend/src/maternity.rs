@@ -0,0 +1,402 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_hospital, get_appointment, get_assigned_patient,
+    get_encounter_by_id, impl_storable, next_id, place_appointment, release_appointment,
+    AccessPayload, Actor, Appointment, AppointmentStatus, Doctor, EncounterStatus, Error, Memory,
+    ResultWithWarnings, Sex, ValidationWarning, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MINUTE_NS: u64 = 60 * 1_000_000_000;
+const DAY_NS: u64 = 24 * 60 * MINUTE_NS;
+const WEEK_NS: u64 = 7 * DAY_NS;
+// a due date is 40 weeks after the last menstrual period
+const GESTATION_NS: u64 = 40 * WEEK_NS;
+// antenatal visits are booked in clinic hours, 09:00 to 17:00 UTC
+const CLINIC_OPENS_NS: u64 = 9 * 60 * MINUTE_NS;
+const CLINIC_CLOSES_NS: u64 = 17 * 60 * MINUTE_NS;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum PregnancyStatus {
+    Active,
+    Delivered { delivered_at: u64 },
+    Ended { reason: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PregnancyEpisode {
+    pub id: u64,
+    pub patient_id: u64,
+    pub hospital_id: u64,
+    pub doctor_id: u64,
+    pub expected_due_date: u64,
+    pub status: PregnancyStatus,
+    // antenatal appointments generated from the hospital's visit template
+    pub appointment_ids: Vec<u64>,
+    pub delivery_encounter_id: Option<u64>,
+    pub newborn_ids: Vec<u64>,
+    pub created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AntenatalVisit {
+    pub gestation_week: u32,
+    pub label: String,
+    pub duration_minutes: u32,
+}
+
+// The antenatal visits a hospital schedules for every pregnancy
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AntenatalTemplate {
+    pub hospital_id: u64,
+    pub visits: Vec<AntenatalVisit>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct PregnancyPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub expected_due_date: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DeliveryEncounterPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub episode_id: u64,
+    pub encounter_id: u64,
+}
+
+impl_storable!(PregnancyEpisode, 2048);
+impl_storable!(AntenatalTemplate, 4096);
+
+thread_local! {
+    static PREGNANCY_STORAGE: RefCell<StableBTreeMap<u64, PregnancyEpisode, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(104)))
+    ));
+
+    static ANTENATAL_TEMPLATES: RefCell<StableBTreeMap<u64, AntenatalTemplate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(105)))
+    ));
+}
+
+fn visit(gestation_week: u32, label: &str) -> AntenatalVisit {
+    AntenatalVisit {
+        gestation_week,
+        label: label.to_string(),
+        duration_minutes: 30,
+    }
+}
+
+// the usual schedule for an uncomplicated pregnancy, used until a hospital sets its own
+fn default_visits() -> Vec<AntenatalVisit> {
+    vec![
+        AntenatalVisit {
+            duration_minutes: 60,
+            ..visit(10, "Booking visit")
+        },
+        visit(16, "Antenatal review"),
+        visit(20, "Anomaly scan review"),
+        visit(25, "Antenatal review"),
+        visit(28, "Antenatal review and bloods"),
+        visit(31, "Antenatal review"),
+        visit(34, "Antenatal review"),
+        visit(36, "Birth planning"),
+        visit(38, "Antenatal review"),
+        visit(40, "Antenatal review"),
+        visit(41, "Post-dates review"),
+    ]
+}
+
+fn antenatal_visits(hospital_id: u64) -> Vec<AntenatalVisit> {
+    ANTENATAL_TEMPLATES
+        .with(|s| s.borrow().get(&hospital_id))
+        .map(|template| template.visits)
+        .unwrap_or_else(default_visits)
+}
+
+fn get_episode(episode_id: u64) -> Result<PregnancyEpisode, Error> {
+    PREGNANCY_STORAGE
+        .with(|s| s.borrow().get(&episode_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Pregnancy episode of id: {} not found", episode_id),
+        })
+}
+
+fn save_episode(episode: &PregnancyEpisode) {
+    PREGNANCY_STORAGE.with(|s| s.borrow_mut().insert(episode.id, episode.clone()));
+}
+
+fn active_episode(patient_id: u64) -> Option<PregnancyEpisode> {
+    PREGNANCY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, episode)| episode)
+            .find(|episode| {
+                episode.patient_id == patient_id && episode.status == PregnancyStatus::Active
+            })
+    })
+}
+
+// book the visit on its day at the first time the doctor is free during clinic hours
+fn book_visit(
+    patient_id: u64,
+    doctor: &Doctor,
+    day_start: u64,
+    visit: &AntenatalVisit,
+) -> Option<Appointment> {
+    let duration = visit.duration_minutes.max(1) as u64 * MINUTE_NS;
+    let mut start = day_start + CLINIC_OPENS_NS;
+    while start + duration <= day_start + CLINIC_CLOSES_NS {
+        if let Ok(appointment) = place_appointment(
+            patient_id,
+            doctor,
+            None,
+            start,
+            start + duration,
+            visit.label.clone(),
+            None,
+        ) {
+            return Some(appointment);
+        }
+        start += 30 * MINUTE_NS;
+    }
+    None
+}
+
+// cancel antenatal visits still ahead, once the pregnancy is over
+fn cancel_remaining_visits(episode: &PregnancyEpisode) {
+    let now = time();
+    for appointment_id in &episode.appointment_ids {
+        if let Ok(appointment) = get_appointment(*appointment_id) {
+            if appointment.start > now && appointment.status == AppointmentStatus::Scheduled {
+                let _ = release_appointment(appointment);
+            }
+        }
+    }
+}
+
+// called when a newborn is registered from the mother's delivery encounter: the active
+// pregnancy is marked delivered and linked to the encounter and the newborn
+pub(crate) fn record_birth(mother_id: u64, encounter_id: u64, newborn_id: u64, born_at: u64) {
+    let episode = PREGNANCY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, episode)| episode)
+            .filter(|episode| episode.patient_id == mother_id)
+            .find(|episode| {
+                episode.delivery_encounter_id == Some(encounter_id)
+                    || (episode.status == PregnancyStatus::Active
+                        && episode.delivery_encounter_id.is_none())
+            })
+    });
+    let Some(mut episode) = episode else {
+        return;
+    };
+    if episode.status == PregnancyStatus::Active {
+        cancel_remaining_visits(&episode);
+        episode.status = PregnancyStatus::Delivered {
+            delivered_at: born_at,
+        };
+    }
+    episode.delivery_encounter_id = Some(encounter_id);
+    if !episode.newborn_ids.contains(&newborn_id) {
+        episode.newborn_ids.push(newborn_id);
+    }
+    save_episode(&episode);
+}
+
+// replace the hospital's antenatal visit template
+#[ic_cdk::update]
+fn set_antenatal_template(
+    hospital_id: u64,
+    hospital_password: String,
+    mut visits: Vec<AntenatalVisit>,
+) -> Result<AntenatalTemplate, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    if visits.is_empty()
+        || visits.len() > 30
+        || visits
+            .iter()
+            .any(|visit| visit.gestation_week > 42 || visit.duration_minutes == 0)
+    {
+        return Err(Error::InvalidPayload {
+            msg: "A template has 1 to 30 visits, each by week 42 and lasting at least a minute"
+                .to_string(),
+        });
+    }
+    visits.sort_by_key(|visit| visit.gestation_week);
+    let template = AntenatalTemplate {
+        hospital_id: hospital.id,
+        visits,
+    };
+    ANTENATAL_TEMPLATES.with(|s| {
+        s.borrow_mut()
+            .insert(template.hospital_id, template.clone())
+    });
+    Ok(template)
+}
+
+#[ic_cdk::query]
+fn get_antenatal_template(hospital_id: u64) -> AntenatalTemplate {
+    AntenatalTemplate {
+        hospital_id,
+        visits: antenatal_visits(hospital_id),
+    }
+}
+
+// open a pregnancy and book its remaining antenatal visits with the doctor; visits that
+// could not be booked come back as warnings
+#[ic_cdk::update]
+fn open_pregnancy_episode(
+    payload: PregnancyPayload,
+) -> Result<ResultWithWarnings<PregnancyEpisode>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    if patient.sex == Some(Sex::Male) {
+        return Err(Error::InvalidPayload {
+            msg: format!("Patient of id: {} is recorded as male", patient.id),
+        });
+    }
+    let now = time();
+    if payload.expected_due_date <= now || payload.expected_due_date > now + GESTATION_NS {
+        return Err(Error::InvalidPayload {
+            msg: "Expected due date must be within the next 40 weeks".to_string(),
+        });
+    }
+    if active_episode(patient.id).is_some() {
+        return Err(Error::AlreadyInit {
+            msg: format!(
+                "Patient of id: {} already has an active pregnancy",
+                patient.id
+            ),
+        });
+    }
+    let pregnancy_start = payload.expected_due_date - GESTATION_NS;
+    let mut appointment_ids = vec![];
+    let mut warnings = vec![];
+    for visit in antenatal_visits(doctor.hospital_id) {
+        let visit_day = pregnancy_start + visit.gestation_week as u64 * WEEK_NS;
+        let day_start = visit_day - visit_day % DAY_NS;
+        if day_start + CLINIC_CLOSES_NS <= now {
+            continue;
+        }
+        match book_visit(patient.id, &doctor, day_start, &visit) {
+            Some(appointment) => appointment_ids.push(appointment.id),
+            None => warnings.push(ValidationWarning {
+                code: "antenatal_visit_unbooked".to_string(),
+                message: format!(
+                    "{} in week {} could not be booked, the doctor has no free slot that day",
+                    visit.label, visit.gestation_week
+                ),
+            }),
+        }
+    }
+    let episode = PregnancyEpisode {
+        id: next_id(),
+        patient_id: patient.id,
+        hospital_id: doctor.hospital_id,
+        doctor_id: doctor.id,
+        expected_due_date: payload.expected_due_date,
+        status: PregnancyStatus::Active,
+        appointment_ids,
+        delivery_encounter_id: None,
+        newborn_ids: vec![],
+        created_at: now,
+    };
+    save_episode(&episode);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(episode.hospital_id),
+        Some(patient.id),
+        "pregnancy_opened",
+        format!(
+            "episode {} with {} visits",
+            episode.id,
+            episode.appointment_ids.len()
+        ),
+    );
+    Ok(ResultWithWarnings {
+        value: episode,
+        warnings,
+    })
+}
+
+// mark the mother's open encounter as the delivery, so newborns registered from it join the
+// episode
+#[ic_cdk::update]
+fn link_delivery_encounter(payload: DeliveryEncounterPayload) -> Result<PregnancyEpisode, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let episode = get_episode(payload.episode_id)?;
+    get_assigned_patient(&doctor, episode.patient_id)?;
+    let encounter = get_encounter_by_id(payload.encounter_id)?;
+    if encounter.patient_id != episode.patient_id || encounter.status != EncounterStatus::Open {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Encounter of id: {} is not an open encounter of the mother",
+                encounter.id
+            ),
+        });
+    }
+    if episode.status != PregnancyStatus::Active {
+        return Err(Error::InvalidPayload {
+            msg: format!("Pregnancy episode of id: {} is not active", episode.id),
+        });
+    }
+    let linked = PregnancyEpisode {
+        delivery_encounter_id: Some(encounter.id),
+        ..episode
+    };
+    save_episode(&linked);
+    Ok(linked)
+}
+
+// close a pregnancy that ended without a delivery recorded here, cancelling remaining visits
+#[ic_cdk::update]
+fn end_pregnancy_episode(
+    doctor_id: u64,
+    doctor_password: String,
+    episode_id: u64,
+    reason: String,
+) -> Result<PregnancyEpisode, Error> {
+    let doctor = authorize_doctor(doctor_id, &doctor_password)?;
+    let episode = get_episode(episode_id)?;
+    get_assigned_patient(&doctor, episode.patient_id)?;
+    if episode.status != PregnancyStatus::Active {
+        return Err(Error::InvalidPayload {
+            msg: format!("Pregnancy episode of id: {} is not active", episode.id),
+        });
+    }
+    cancel_remaining_visits(&episode);
+    let ended = PregnancyEpisode {
+        status: PregnancyStatus::Ended { reason },
+        ..episode
+    };
+    save_episode(&ended);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(ended.hospital_id),
+        Some(ended.patient_id),
+        "pregnancy_ended",
+        format!("episode {}", ended.id),
+    );
+    Ok(ended)
+}
+
+#[ic_cdk::query]
+fn get_pregnancy_episodes(payload: AccessPayload) -> Result<Vec<PregnancyEpisode>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    Ok(PREGNANCY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, episode)| episode)
+            .filter(|episode| episode.patient_id == patient.id)
+            .collect()
+    }))
+}
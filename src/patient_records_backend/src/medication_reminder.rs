@@ -0,0 +1,446 @@
+use crate::time;
+use crate::{
+    audit, authenticate_patient, get_encounter_entries, impl_storable, next_id, notify, text,
+    utc_offset, Actor, Encounter, EncounterEntryKind, Error, Memory, Priority, Recipient,
+    MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MINUTE_NS: u64 = 60 * 1_000_000_000;
+const DAY_NS: u64 = 24 * 60 * MINUTE_NS;
+// reminders are created this far ahead, the timer keeps extending them
+const REMIND_AHEAD_NS: u64 = DAY_NS;
+// a dose can be confirmed a little before its time, e.g. taken with an early breakfast
+const CONFIRM_EARLY_NS: u64 = 60 * MINUTE_NS;
+// default local dose times spread over the waking day, from 08:00 to 20:00
+const FIRST_DOSE_MINUTE: u16 = 8 * 60;
+const WAKING_MINUTES: u16 = 12 * 60;
+const MAX_SCHEDULE_ITEMS: usize = 200;
+const MAX_SKIP_REASON_LEN: usize = 200;
+
+// When a patient is reminded of one running prescription, in local minutes after midnight.
+// Plans start when the encounter that prescribed it is closed, admitted patients have the MAR
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MedicationReminderPlan {
+    pub patient_id: u64,
+    pub prescription_entry_id: u64,
+    pub doctor_id: u64,
+    pub medication: String,
+    pub dosage: String,
+    pub dose_minutes: Vec<u16>,
+    pub ends_at: u64,
+    // reminders exist up to this time
+    pub scheduled_until: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum MedicationDoseStatus {
+    Pending,
+    Taken,
+    Skipped { reason: Option<String> },
+}
+
+// One dose the patient is reminded of, confirmed by the patient as taken or skipped
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MedicationReminder {
+    pub id: u64,
+    pub patient_id: u64,
+    pub prescription_entry_id: u64,
+    // the prescribing doctor
+    pub doctor_id: u64,
+    pub medication: String,
+    pub dosage: String,
+    pub due_at: u64,
+    pub reminded_at: Option<u64>,
+    pub status: MedicationDoseStatus,
+    pub confirmed_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum MedicationDoseOutcome {
+    Taken,
+    Skipped { reason: Option<String> },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MedicationDoseConfirmation {
+    pub patient_id: u64,
+    pub password: String,
+    pub reminder_id: u64,
+    pub outcome: MedicationDoseOutcome,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ReminderTimesPayload {
+    pub patient_id: u64,
+    pub password: String,
+    pub prescription_entry_id: u64,
+    // local minutes after midnight, one per daily dose
+    pub dose_minutes: Vec<u16>,
+}
+
+// The running plans of a patient with their reminders from a given time on
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MedicationSchedule {
+    pub plans: Vec<MedicationReminderPlan>,
+    pub reminders: Vec<MedicationReminder>,
+}
+
+impl_storable!(MedicationReminderPlan, 1024);
+impl_storable!(MedicationReminder, 1024);
+
+thread_local! {
+    // (patient id, prescription entry id) -> plan
+    static REMINDER_PLAN_STORAGE: RefCell<StableBTreeMap<(u64, u64), MedicationReminderPlan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(145)))
+    ));
+
+    // (patient id, reminder id) -> reminder
+    static MEDICATION_REMINDER_STORAGE: RefCell<StableBTreeMap<(u64, u64), MedicationReminder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(146)))
+    ));
+}
+
+fn save_plan(plan: &MedicationReminderPlan) {
+    REMINDER_PLAN_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert((plan.patient_id, plan.prescription_entry_id), plan.clone())
+    });
+}
+
+fn save_reminder(reminder: &MedicationReminder) {
+    MEDICATION_REMINDER_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert((reminder.patient_id, reminder.id), reminder.clone())
+    });
+}
+
+fn patient_plans(patient_id: u64) -> Vec<MedicationReminderPlan> {
+    REMINDER_PLAN_STORAGE.with(|s| {
+        s.borrow()
+            .range((patient_id, 0)..=(patient_id, u64::MAX))
+            .map(|(_, plan)| plan)
+            .collect()
+    })
+}
+
+// every reminder of a patient, oldest id first
+pub(crate) fn patient_medication_reminders(patient_id: u64) -> Vec<MedicationReminder> {
+    MEDICATION_REMINDER_STORAGE.with(|s| {
+        s.borrow()
+            .range((patient_id, 0)..=(patient_id, u64::MAX))
+            .map(|(_, reminder)| reminder)
+            .collect()
+    })
+}
+
+// the reminders of the prescriptions a doctor wrote
+pub(crate) fn prescriber_medication_reminders(doctor_id: u64) -> Vec<MedicationReminder> {
+    MEDICATION_REMINDER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, reminder)| reminder)
+            .filter(|reminder| reminder.doctor_id == doctor_id)
+            .collect()
+    })
+}
+
+// local dose times of a prescription taken a number of times a day
+fn default_dose_minutes(doses_per_day: u32) -> Vec<u16> {
+    let doses = doses_per_day.min(24 * 60) as u16;
+    match doses {
+        0 => vec![],
+        1 => vec![FIRST_DOSE_MINUTE],
+        // up to four doses fit the waking day, more run round the clock
+        2..=4 => (0..doses)
+            .map(|i| FIRST_DOSE_MINUTE + i * WAKING_MINUTES / (doses - 1))
+            .collect(),
+        _ => (0..doses)
+            .map(|i| (FIRST_DOSE_MINUTE + i * (24 * 60 / doses)) % (24 * 60))
+            .collect(),
+    }
+}
+
+// the times after from and up to to at which a local minute of the day comes round
+fn occurrences(minute: u16, offset_minutes: i16, from: u64, to: u64) -> Vec<u64> {
+    let offset = offset_minutes as i128 * MINUTE_NS as i128;
+    let local_from = from as i128 + offset;
+    let mut at = local_from - local_from.rem_euclid(DAY_NS as i128)
+        + minute as i128 * MINUTE_NS as i128
+        - offset;
+    let mut times = vec![];
+    while at <= to as i128 {
+        if at > from as i128 {
+            times.push(at as u64);
+        }
+        at += DAY_NS as i128;
+    }
+    times
+}
+
+// create the reminders of a plan up to the given time
+fn extend_plan(plan: &mut MedicationReminderPlan, until: u64) {
+    let until = until.min(plan.ends_at);
+    if until <= plan.scheduled_until {
+        return;
+    }
+    let offset = utc_offset(&Recipient::Patient(plan.patient_id));
+    let mut due: Vec<u64> = plan
+        .dose_minutes
+        .iter()
+        .flat_map(|minute| occurrences(*minute, offset, plan.scheduled_until, until))
+        .collect();
+    due.sort_unstable();
+    for due_at in due {
+        save_reminder(&MedicationReminder {
+            id: next_id(),
+            patient_id: plan.patient_id,
+            prescription_entry_id: plan.prescription_entry_id,
+            doctor_id: plan.doctor_id,
+            medication: plan.medication.clone(),
+            dosage: plan.dosage.clone(),
+            due_at,
+            reminded_at: None,
+            status: MedicationDoseStatus::Pending,
+            confirmed_at: None,
+        });
+    }
+    plan.scheduled_until = until;
+    save_plan(plan);
+}
+
+// drop the reminders of a plan that have not come due yet
+fn drop_future_reminders(plan: &MedicationReminderPlan, now: u64) {
+    MEDICATION_REMINDER_STORAGE.with(|s| {
+        let mut reminders = s.borrow_mut();
+        let future: Vec<(u64, u64)> = reminders
+            .range((plan.patient_id, 0)..=(plan.patient_id, u64::MAX))
+            .filter(|(_, reminder)| {
+                reminder.prescription_entry_id == plan.prescription_entry_id
+                    && reminder.status == MedicationDoseStatus::Pending
+                    && reminder.due_at > now
+            })
+            .map(|(key, _)| key)
+            .collect();
+        for key in future {
+            reminders.remove(&key);
+        }
+    });
+}
+
+// start reminding the patient of the prescriptions of a closed encounter that are still running
+pub(crate) fn plan_medication_reminders(encounter: &Encounter) {
+    let now = time();
+    for entry in get_encounter_entries(encounter) {
+        let EncounterEntryKind::Prescription(prescription) = &entry.kind else {
+            continue;
+        };
+        let ends_at = entry.recorded_at + prescription.duration_days as u64 * DAY_NS;
+        if prescription.doses_per_day == 0 || ends_at <= now {
+            continue;
+        }
+        let mut plan = MedicationReminderPlan {
+            patient_id: encounter.patient_id,
+            prescription_entry_id: entry.id,
+            doctor_id: entry.doctor_id,
+            medication: prescription.medication.clone(),
+            dosage: prescription.dosage.clone(),
+            dose_minutes: default_dose_minutes(prescription.doses_per_day),
+            ends_at,
+            scheduled_until: now,
+        };
+        extend_plan(&mut plan, now + REMIND_AHEAD_NS);
+    }
+}
+
+// timer job: extend the running plans and remind patients of the doses that came due. the
+// reminder goes through the outbox, so it waits out the patient's quiet hours
+pub(crate) fn send_medication_reminders() {
+    let now = time();
+    let running: Vec<MedicationReminderPlan> = REMINDER_PLAN_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, plan)| plan)
+            .filter(|plan| plan.scheduled_until < plan.ends_at)
+            .collect()
+    });
+    for mut plan in running {
+        extend_plan(&mut plan, now + REMIND_AHEAD_NS);
+    }
+    let due: Vec<MedicationReminder> = MEDICATION_REMINDER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, reminder)| reminder)
+            .filter(|reminder| {
+                reminder.reminded_at.is_none()
+                    && reminder.status == MedicationDoseStatus::Pending
+                    && reminder.due_at <= now
+            })
+            .collect()
+    });
+    for reminder in due {
+        notify(
+            Recipient::Patient(reminder.patient_id),
+            Priority::Normal,
+            text(
+                "medication.reminder",
+                "Time to take {medication} {dosage}",
+                vec![
+                    ("medication", reminder.medication.clone()),
+                    ("dosage", reminder.dosage.clone()),
+                ],
+            ),
+        );
+        save_reminder(&MedicationReminder {
+            reminded_at: Some(now),
+            ..reminder
+        });
+    }
+}
+
+// the patient's running plans and their reminders due from the given time on
+#[ic_cdk::query]
+fn get_medication_schedule(
+    patient_id: u64,
+    password: String,
+    from: u64,
+) -> Result<MedicationSchedule, Error> {
+    authenticate_patient(patient_id, &password)?;
+    let mut reminders: Vec<MedicationReminder> = patient_medication_reminders(patient_id)
+        .into_iter()
+        .filter(|reminder| reminder.due_at >= from)
+        .collect();
+    reminders.sort_by_key(|reminder| reminder.due_at);
+    reminders.truncate(MAX_SCHEDULE_ITEMS);
+    let now = time();
+    Ok(MedicationSchedule {
+        plans: patient_plans(patient_id)
+            .into_iter()
+            .filter(|plan| plan.ends_at > now)
+            .collect(),
+        reminders,
+    })
+}
+
+// patients move the dose times of a prescription to suit their day, from the next dose on
+#[ic_cdk::update]
+fn set_medication_reminder_times(
+    payload: ReminderTimesPayload,
+) -> Result<MedicationReminderPlan, Error> {
+    authenticate_patient(payload.patient_id, &payload.password)?;
+    let mut plan = REMINDER_PLAN_STORAGE
+        .with(|s| {
+            s.borrow()
+                .get(&(payload.patient_id, payload.prescription_entry_id))
+        })
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "Reminder plan of prescription: {} not found",
+                payload.prescription_entry_id
+            ),
+        })?;
+    let mut minutes = payload.dose_minutes;
+    minutes.sort_unstable();
+    minutes.dedup();
+    if minutes.len() != plan.dose_minutes.len() || minutes.iter().any(|m| *m >= 24 * 60) {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Give {} different times between 0 and 1439 minutes after midnight",
+                plan.dose_minutes.len()
+            ),
+        });
+    }
+    let now = time();
+    drop_future_reminders(&plan, now);
+    plan.dose_minutes = minutes;
+    plan.scheduled_until = now;
+    extend_plan(&mut plan, now + REMIND_AHEAD_NS);
+    Ok(plan)
+}
+
+// the patient logs a dose as taken or skipped, the prescriber sees it in adherence reports
+#[ic_cdk::update]
+fn confirm_medication_dose(
+    payload: MedicationDoseConfirmation,
+) -> Result<MedicationReminder, Error> {
+    authenticate_patient(payload.patient_id, &payload.password)?;
+    let now = time();
+    let reminder = MEDICATION_REMINDER_STORAGE
+        .with(|s| s.borrow().get(&(payload.patient_id, payload.reminder_id)))
+        .filter(|reminder| reminder.due_at <= now + CONFIRM_EARLY_NS)
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "Due medication dose of id: {} not found",
+                payload.reminder_id
+            ),
+        })?;
+    if reminder.status != MedicationDoseStatus::Pending {
+        return Err(Error::InvalidPayload {
+            msg: format!("Medication dose of id: {} is already logged", reminder.id),
+        });
+    }
+    let status = match payload.outcome {
+        MedicationDoseOutcome::Taken => MedicationDoseStatus::Taken,
+        MedicationDoseOutcome::Skipped { reason } => {
+            if reason
+                .as_ref()
+                .is_some_and(|reason| reason.chars().count() > MAX_SKIP_REASON_LEN)
+            {
+                return Err(Error::InvalidPayload {
+                    msg: format!(
+                        "Skip reasons hold at most {} characters",
+                        MAX_SKIP_REASON_LEN
+                    ),
+                });
+            }
+            MedicationDoseStatus::Skipped { reason }
+        }
+    };
+    let confirmed = MedicationReminder {
+        status,
+        confirmed_at: Some(now),
+        ..reminder
+    };
+    save_reminder(&confirmed);
+    audit(
+        Actor::Patient(confirmed.patient_id),
+        None,
+        Some(confirmed.patient_id),
+        "medication_dose_logged",
+        format!("dose {} of {}", confirmed.id, confirmed.medication),
+    );
+    Ok(confirmed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_NS: u64 = 60 * MINUTE_NS;
+
+    #[test]
+    fn default_times_spread_over_the_waking_day() {
+        assert_eq!(default_dose_minutes(1), vec![480]);
+        assert_eq!(default_dose_minutes(3), vec![480, 840, 1200]);
+        assert_eq!(default_dose_minutes(6), vec![480, 720, 960, 1200, 0, 240]);
+    }
+
+    #[test]
+    fn occurrences_follow_the_local_clock() {
+        let day = 20_000 * DAY_NS;
+        // 08:00 at UTC+02:00 is 06:00 UTC
+        assert_eq!(
+            occurrences(480, 120, day, day + 2 * DAY_NS),
+            vec![day + 6 * HOUR_NS, day + DAY_NS + 6 * HOUR_NS]
+        );
+        // a time that has passed today comes round tomorrow
+        assert_eq!(
+            occurrences(480, 0, day + 9 * HOUR_NS, day + DAY_NS + 9 * HOUR_NS),
+            vec![day + DAY_NS + 8 * HOUR_NS]
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use crate::time;
+use crate::{
+    admit_patient, audit, authenticate_patient, authorize_doctor, authorize_patient, check_limit,
+    get_assigned_patient, get_encounter_by_id, impl_storable, next_id, patient_quota, record_birth,
+    save_patient, to_hex, Actor, EncounterStatus, Error, Memory, Patient, PatientConsent, Sex,
+    DOCTOR_STORAGE, HOSPITAL_STORAGE, MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// A newborn's record linked to the mother's. Until it is split, the mother's credentials open
+// the newborn's record
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct NewbornLink {
+    pub newborn_id: u64,
+    pub mother_id: u64,
+    pub encounter_id: u64,
+    pub hospital_id: u64,
+    pub born_at: u64,
+    pub mrn: String,
+    pub split_at: Option<u64>,
+}
+
+impl_storable!(NewbornLink, 256);
+
+thread_local! {
+    static NEWBORN_STORAGE: RefCell<StableBTreeMap<u64, NewbornLink, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct NewbornPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub mother_id: u64,
+    // the mother's open delivery encounter
+    pub encounter_id: u64,
+    pub name: String,
+    pub born_at: u64,
+    pub sex: Option<Sex>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SplitNewbornPayload {
+    pub mother_id: u64,
+    pub mother_password: String,
+    pub newborn_id: u64,
+    // the newborn's own password from now on
+    pub new_password: String,
+}
+
+// whether the password is the mother's for a newborn whose record was not split yet
+pub(crate) fn opens_linked_newborn(newborn_id: u64, password: &str) -> bool {
+    NEWBORN_STORAGE
+        .with(|s| s.borrow().get(&newborn_id))
+        .filter(|link| link.split_at.is_none())
+        .and_then(|link| PATIENT_STORAGE.with(|s| s.borrow().get(&link.mother_id)))
+        .is_some_and(|mother| mother.password == password)
+}
+
+fn mothers_newborns(mother_id: u64) -> Vec<NewbornLink> {
+    NEWBORN_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, link)| link)
+            .filter(|link| link.mother_id == mother_id)
+            .collect()
+    })
+}
+
+// create the newborn's record from the mother's delivery encounter. The newborn joins the
+// hospital and the mother's care team there
+#[ic_cdk::update]
+async fn register_newborn(payload: NewbornPayload) -> Result<NewbornLink, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let mother = get_assigned_patient(&doctor, payload.mother_id)?;
+    let encounter = get_encounter_by_id(payload.encounter_id)?;
+    if encounter.patient_id != mother.id || encounter.status != EncounterStatus::Open {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Encounter of id: {} is not an open encounter of the mother",
+                encounter.id
+            ),
+        });
+    }
+    if payload.name.trim().len() < 3 || payload.born_at > time() {
+        return Err(Error::InvalidPayload {
+            msg: "Newborns need a name of at least 3 characters and a birth time that is not in the future"
+                .to_string(),
+        });
+    }
+    let hospital = HOSPITAL_STORAGE
+        .with(|s| s.borrow().get(&encounter.hospital_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Hospital of id: {} not found", encounter.hospital_id),
+        })?;
+    check_limit(
+        "patients per hospital",
+        hospital.patients_ids.len() as u64,
+        patient_quota(hospital.id),
+    )?;
+    // the newborn gets a password nobody knows until the record is split
+    let (random,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| Error::InvalidPayload {
+            msg: format!("Could not register newborn: {:?} {}", code, msg),
+        })?;
+
+    let care_team: Vec<u64> = mother
+        .doctors_ids
+        .iter()
+        .copied()
+        .filter(|doctor_id| {
+            DOCTOR_STORAGE
+                .with(|s| s.borrow().get(doctor_id))
+                .is_some_and(|doctor| doctor.hospital_id == encounter.hospital_id)
+        })
+        .collect();
+    let newborn = Patient {
+        id: next_id(),
+        name: payload.name,
+        history: format!(
+            "Born to patient {} during encounter {}",
+            mother.id, encounter.id
+        ),
+        password: to_hex(&random),
+        doctors_ids: care_team.clone(),
+        hospitals_ids: vec![],
+        blood_type: None,
+        date_of_birth: Some(payload.born_at),
+        sex: payload.sex,
+    };
+    save_patient(&newborn);
+    let mrn = admit_patient(encounter.hospital_id, newborn.id)?;
+    DOCTOR_STORAGE.with(|s| {
+        let mut doctors = s.borrow_mut();
+        for doctor_id in &care_team {
+            if let Some(mut member) = doctors.get(doctor_id) {
+                member.patient_ids.push(newborn.id);
+                doctors.insert(member.id, member);
+            }
+        }
+    });
+
+    let link = NewbornLink {
+        newborn_id: newborn.id,
+        mother_id: mother.id,
+        encounter_id: encounter.id,
+        hospital_id: encounter.hospital_id,
+        born_at: payload.born_at,
+        mrn,
+        split_at: None,
+    };
+    NEWBORN_STORAGE.with(|s| s.borrow_mut().insert(link.newborn_id, link.clone()));
+    record_birth(mother.id, encounter.id, newborn.id, payload.born_at);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(encounter.hospital_id),
+        Some(newborn.id),
+        "newborn_registered",
+        format!("mother {} encounter {}", mother.id, encounter.id),
+    );
+    Ok(link)
+}
+
+// newborn records linked to the mother
+#[ic_cdk::query]
+fn get_newborns(consent: PatientConsent) -> Result<Vec<NewbornLink>, Error> {
+    let mother = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    Ok(mothers_newborns(mother.id))
+}
+
+// give the newborn an independent record with its own password, after which the mother's
+// credentials no longer open it
+#[ic_cdk::update]
+fn split_newborn_record(payload: SplitNewbornPayload) -> Result<NewbornLink, Error> {
+    let mother = authorize_patient(payload.mother_id, &payload.mother_password)?;
+    let link = mothers_newborns(mother.id)
+        .into_iter()
+        .find(|link| link.newborn_id == payload.newborn_id && link.split_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("Linked newborn of id: {} not found", payload.newborn_id),
+        })?;
+    if payload.new_password.len() < 4 {
+        return Err(Error::InvalidPayload {
+            msg: "Password must be at least 4 characters".to_string(),
+        });
+    }
+    if let Some(newborn) = PATIENT_STORAGE.with(|s| s.borrow().get(&link.newborn_id)) {
+        save_patient(&Patient {
+            password: payload.new_password,
+            ..newborn
+        });
+    }
+    let split = NewbornLink {
+        split_at: Some(time()),
+        ..link
+    };
+    NEWBORN_STORAGE.with(|s| s.borrow_mut().insert(split.newborn_id, split.clone()));
+    audit(
+        Actor::Patient(mother.id),
+        Some(split.hospital_id),
+        Some(split.newborn_id),
+        "newborn_record_split",
+        format!("split from mother {}", mother.id),
+    );
+    Ok(split)
+}
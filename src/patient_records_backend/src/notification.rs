@@ -0,0 +1,122 @@
+use crate::time;
+use crate::{
+    authorize_ref, impl_storable, language_of, next_id, page_after, queue_patient_notification,
+    EntityRef, Error, Memory, Page, Text, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Notifications are addressed to a hospital, doctor or patient
+pub type Recipient = EntityRef;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub id: u64,
+    pub recipient: Recipient,
+    pub priority: Priority,
+    pub message: String,
+    pub created_at: u64,
+    pub read: bool,
+}
+
+impl_storable!(Notification, 1024);
+
+thread_local! {
+    static NOTIFICATION_STORAGE: RefCell<StableBTreeMap<u64, Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct InboxPayload {
+    pub recipient: Recipient,
+    pub password: String,
+    pub unread_only: bool,
+    // id of the last notification of the previous page
+    pub after: Option<u64>,
+    pub limit: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MarkReadPayload {
+    pub recipient: Recipient,
+    pub password: String,
+    pub notification_id: u64,
+}
+
+// put a notification into the recipient's inbox, in their preferred language
+pub(crate) fn notify(recipient: Recipient, priority: Priority, message: Text) -> Notification {
+    let notification = Notification {
+        id: next_id(),
+        recipient,
+        priority,
+        message: message.render(language_of(&recipient).as_deref()),
+        created_at: time(),
+        read: false,
+    };
+    // patients are reached through the outbox, which respects their quiet hours
+    match recipient {
+        Recipient::Patient(patient_id) => {
+            queue_patient_notification(patient_id, notification.clone())
+        }
+        _ => deliver_notification(&notification),
+    }
+    notification
+}
+
+// write a notification into its recipient's inbox
+pub(crate) fn deliver_notification(notification: &Notification) {
+    NOTIFICATION_STORAGE.with(|s| s.borrow_mut().insert(notification.id, notification.clone()));
+}
+
+// inbox of a hospital, doctor or patient, oldest first a page at a time
+#[ic_cdk::query]
+fn get_notifications(payload: InboxPayload) -> Result<Page<Notification>, Error> {
+    authorize_ref(payload.recipient, &payload.password)?;
+    Ok(inbox(
+        &payload.recipient,
+        payload.unread_only,
+        payload.after,
+        payload.limit,
+    ))
+}
+
+pub(crate) fn inbox(
+    recipient: &Recipient,
+    unread_only: bool,
+    after: Option<u64>,
+    limit: u64,
+) -> Page<Notification> {
+    NOTIFICATION_STORAGE.with(|s| {
+        page_after(&s.borrow(), after, limit, |notification| {
+            notification.recipient == *recipient && (!unread_only || !notification.read)
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn mark_notification_read(payload: MarkReadPayload) -> Result<Notification, Error> {
+    authorize_ref(payload.recipient, &payload.password)?;
+    match NOTIFICATION_STORAGE.with(|s| s.borrow().get(&payload.notification_id)) {
+        Some(notification) if notification.recipient == payload.recipient => {
+            let read = Notification {
+                read: true,
+                ..notification
+            };
+            NOTIFICATION_STORAGE.with(|s| s.borrow_mut().insert(read.id, read.clone()));
+            Ok(read)
+        }
+        _ => Err(Error::NotFound {
+            msg: format!("Notification of id: {} not found", payload.notification_id),
+        }),
+    }
+}
@@ -0,0 +1,100 @@
+use crate::{
+    audit_failed_password, authorize_hospital, caller_holds, check_nurse_quota, impl_storable,
+    next_id, reject_kiosk_caller, AccountRole, Actor, Error, Memory, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Nurse {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub name: String,
+    pub password: String,
+}
+
+impl_storable!(Nurse, 512);
+
+thread_local! {
+    static NURSE_STORAGE: RefCell<StableBTreeMap<u64, Nurse, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct NursePayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub name: String,
+    pub password: String,
+}
+
+pub(crate) fn hospital_nurse_count(hospital_id: u64) -> u64 {
+    NURSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, nurse)| nurse.hospital_id == hospital_id)
+            .count() as u64
+    })
+}
+
+// helper function to check a nurse's password and return the nurse
+pub(crate) fn authorize_nurse(nurse_id: u64, password: &str) -> Result<Nurse, Error> {
+    reject_kiosk_caller()?;
+    match NURSE_STORAGE.with(|nurses| nurses.borrow().get(&nurse_id)) {
+        Some(nurse) if nurse.password == password || caller_holds(AccountRole::Nurse(nurse_id)) => {
+            Ok(nurse)
+        }
+        Some(nurse) => {
+            audit_failed_password(Actor::Nurse(nurse.id), Some(nurse.hospital_id), None);
+            Err(Error::Unauthorized {
+                msg: "Nurse access unauthorized, password does not match, try again".to_string(),
+            })
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Nurse of id: {} not found", nurse_id),
+        }),
+    }
+}
+
+pub(crate) fn get_nurse(nurse_id: u64) -> Option<Nurse> {
+    NURSE_STORAGE.with(|nurses| nurses.borrow().get(&nurse_id))
+}
+
+// add a nurse to the hospital's staff
+#[ic_cdk::update]
+pub(crate) fn add_nurse(payload: NursePayload) -> Result<Nurse, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    check_nurse_quota(hospital.id)?;
+    if payload.name.trim().len() < 3 || payload.password.len() < 4 {
+        return Err(Error::InvalidPayload {
+            msg: "Nurse name needs 3 characters and password 4 characters".to_string(),
+        });
+    }
+    let nurse = Nurse {
+        id: next_id(),
+        hospital_id: hospital.id,
+        name: payload.name,
+        password: payload.password,
+    };
+    NURSE_STORAGE.with(|s| s.borrow_mut().insert(nurse.id, nurse.clone()));
+    Ok(Nurse {
+        password: "-".to_string(),
+        ..nurse
+    })
+}
+
+#[ic_cdk::query]
+fn get_nurse_by_id(id: u64) -> Result<Nurse, Error> {
+    match get_nurse(id) {
+        Some(nurse) => Ok(Nurse {
+            password: "-".to_string(),
+            ..nurse
+        }),
+        None => Err(Error::NotFound {
+            msg: format!("nurse id:{} does not exist", id),
+        }),
+    }
+}
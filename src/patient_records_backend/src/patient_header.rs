@@ -0,0 +1,64 @@
+use crate::{impl_storable, Memory, Patient, MEMORY_MANAGER, PATIENT_STORAGE};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// The fields of a patient that password checks, care team and name lookups need, kept beside
+// the full patient so those do not decode the history text
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PatientHeader {
+    pub id: u64,
+    pub name: String,
+    pub password: String,
+    pub doctors_ids: Vec<u64>,
+    pub hospitals_ids: Vec<u64>,
+}
+
+impl From<&Patient> for PatientHeader {
+    fn from(patient: &Patient) -> Self {
+        PatientHeader {
+            id: patient.id,
+            name: patient.name.clone(),
+            password: patient.password.clone(),
+            doctors_ids: patient.doctors_ids.clone(),
+            hospitals_ids: patient.hospitals_ids.clone(),
+        }
+    }
+}
+
+impl_storable!(PatientHeader, 1024);
+
+thread_local! {
+    static PATIENT_HEADERS: RefCell<StableBTreeMap<u64, PatientHeader, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(140)))
+    ));
+}
+
+pub(crate) fn patient_header(patient_id: u64) -> Option<PatientHeader> {
+    PATIENT_HEADERS.with(|s| s.borrow().get(&patient_id))
+}
+
+// every patient write goes through here so the header stays in step, returns the previous value
+pub(crate) fn save_patient(patient: &Patient) -> Option<Patient> {
+    PATIENT_HEADERS.with(|s| s.borrow_mut().insert(patient.id, patient.into()));
+    PATIENT_STORAGE.with(|s| s.borrow_mut().insert(patient.id, patient.clone()))
+}
+
+// build the headers of patients stored before headers existed, once after the upgrade
+// that introduced them
+pub(crate) fn backfill_patient_headers() {
+    let missing =
+        PATIENT_HEADERS.with(|s| s.borrow().len()) != PATIENT_STORAGE.with(|s| s.borrow().len());
+    if !missing {
+        return;
+    }
+    PATIENT_STORAGE.with(|patients| {
+        PATIENT_HEADERS.with(|headers| {
+            let mut headers = headers.borrow_mut();
+            for (id, patient) in patients.borrow().iter() {
+                headers.insert(id, (&patient).into());
+            }
+        })
+    });
+}
@@ -0,0 +1,311 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_hospital, get_assigned_patient, impl_storable, next_id,
+    notify, text, Actor, Error, HospitalAccessPayload, Memory, Priority, Recipient,
+    HOSPITAL_STORAGE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// longest expiry warning window a hospital can set, ten years
+const MAX_EXPIRY_WARNING_DAYS: u64 = 3650;
+// room for the drugs or batches listed in one alert, inside the notification's size bound
+const MAX_ALERT_LIST_BYTES: usize = 512;
+// keep a batch inside its store
+const MAX_DRUG_BYTES: usize = 200;
+const MAX_BATCH_BYTES: usize = 100;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct StockBatch {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub drug: String,
+    pub batch: String,
+    pub quantity: u64,
+    pub expires_at: u64,
+    pub received_at: u64,
+}
+
+// Per hospital thresholds used by the stock queries and the daily alert job
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PharmacySettings {
+    pub hospital_id: u64,
+    pub low_stock_threshold: u64,
+    pub expiry_warning_days: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct DrugStockLevel {
+    pub drug: String,
+    pub quantity: u64,
+}
+
+impl_storable!(StockBatch, 512);
+impl_storable!(PharmacySettings, 128);
+
+thread_local! {
+    static STOCK_STORAGE: RefCell<StableBTreeMap<u64, StockBatch, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    static PHARMACY_SETTINGS: RefCell<StableBTreeMap<u64, PharmacySettings, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct StockBatchPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub drug: String,
+    pub batch: String,
+    pub quantity: u64,
+    pub expires_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DispensePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub drug: String,
+    pub quantity: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct PharmacySettingsPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub low_stock_threshold: u64,
+    pub expiry_warning_days: u64,
+}
+
+fn settings(hospital_id: u64) -> PharmacySettings {
+    PHARMACY_SETTINGS
+        .with(|s| s.borrow().get(&hospital_id))
+        .unwrap_or(PharmacySettings {
+            hospital_id,
+            low_stock_threshold: 10,
+            expiry_warning_days: 30,
+        })
+}
+
+fn hospital_stock(hospital_id: u64) -> Vec<StockBatch> {
+    STOCK_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, batch)| batch)
+            .filter(|batch| batch.hospital_id == hospital_id)
+            .collect()
+    })
+}
+
+// usable quantity per drug, ignoring expired batches
+fn stock_levels(hospital_id: u64) -> BTreeMap<String, u64> {
+    let now = time();
+    let mut levels = BTreeMap::new();
+    for batch in hospital_stock(hospital_id) {
+        let level = levels.entry(batch.drug.to_lowercase()).or_insert(0);
+        if batch.expires_at > now {
+            *level += batch.quantity;
+        }
+    }
+    levels
+}
+
+fn low_stock(hospital_id: u64) -> Vec<DrugStockLevel> {
+    let threshold = settings(hospital_id).low_stock_threshold;
+    stock_levels(hospital_id)
+        .into_iter()
+        .filter(|(_, quantity)| *quantity <= threshold)
+        .map(|(drug, quantity)| DrugStockLevel { drug, quantity })
+        .collect()
+}
+
+fn expiring_stock(hospital_id: u64, within_days: u64) -> Vec<StockBatch> {
+    let limit = time().saturating_add(within_days.saturating_mul(DAY_NS));
+    let mut batches: Vec<StockBatch> = hospital_stock(hospital_id)
+        .into_iter()
+        .filter(|batch| batch.quantity > 0 && batch.expires_at <= limit)
+        .collect();
+    batches.sort_by_key(|batch| batch.expires_at);
+    batches
+}
+
+// receive a batch of a drug into the hospital pharmacy
+#[ic_cdk::update]
+fn add_stock_batch(payload: StockBatchPayload) -> Result<StockBatch, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if payload.drug.trim().is_empty() || payload.quantity == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "Stock batch needs a drug name and a quantity".to_string(),
+        });
+    }
+    if payload.drug.len() > MAX_DRUG_BYTES || payload.batch.len() > MAX_BATCH_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Drug names hold at most {} bytes and batch numbers {} bytes",
+                MAX_DRUG_BYTES, MAX_BATCH_BYTES
+            ),
+        });
+    }
+    let batch = StockBatch {
+        id: next_id(),
+        hospital_id: hospital.id,
+        drug: payload.drug,
+        batch: payload.batch,
+        quantity: payload.quantity,
+        expires_at: payload.expires_at,
+        received_at: time(),
+    };
+    STOCK_STORAGE.with(|s| s.borrow_mut().insert(batch.id, batch.clone()));
+    Ok(batch)
+}
+
+// dispense a drug to a patient, taking from the batches that expire first
+#[ic_cdk::update]
+fn dispense_medication(payload: DispensePayload) -> Result<Vec<StockBatch>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let drug = payload.drug.to_lowercase();
+    let now = time();
+    let mut batches: Vec<StockBatch> = hospital_stock(doctor.hospital_id)
+        .into_iter()
+        .filter(|batch| batch.drug.to_lowercase() == drug && batch.expires_at > now)
+        .filter(|batch| batch.quantity > 0)
+        .collect();
+    batches.sort_by_key(|batch| batch.expires_at);
+
+    let available: u64 = batches.iter().map(|batch| batch.quantity).sum();
+    if available < payload.quantity {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Not enough {} in stock: {} requested, {} available",
+                payload.drug, payload.quantity, available
+            ),
+        });
+    }
+
+    let mut remaining = payload.quantity;
+    let mut used = vec![];
+    for mut batch in batches {
+        if remaining == 0 {
+            break;
+        }
+        let taken = remaining.min(batch.quantity);
+        batch.quantity -= taken;
+        remaining -= taken;
+        STOCK_STORAGE.with(|s| s.borrow_mut().insert(batch.id, batch.clone()));
+        used.push(batch);
+    }
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "medication_dispensed",
+        format!("{} x {}", payload.quantity, payload.drug),
+    );
+    Ok(used)
+}
+
+#[ic_cdk::update]
+fn set_pharmacy_settings(payload: PharmacySettingsPayload) -> Result<PharmacySettings, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if payload.expiry_warning_days > MAX_EXPIRY_WARNING_DAYS {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Expiry warning window cannot exceed {} days",
+                MAX_EXPIRY_WARNING_DAYS
+            ),
+        });
+    }
+    let settings = PharmacySettings {
+        hospital_id: hospital.id,
+        low_stock_threshold: payload.low_stock_threshold,
+        expiry_warning_days: payload.expiry_warning_days,
+    };
+    PHARMACY_SETTINGS.with(|s| s.borrow_mut().insert(hospital.id, settings.clone()));
+    Ok(settings)
+}
+
+// drugs at or below the hospital's low stock threshold
+#[ic_cdk::query]
+fn get_low_stock(payload: HospitalAccessPayload) -> Result<Vec<DrugStockLevel>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    Ok(low_stock(hospital.id))
+}
+
+// batches with stock left that expire within the hospital's warning window
+#[ic_cdk::query]
+fn get_expiring_stock(payload: HospitalAccessPayload) -> Result<Vec<StockBatch>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    Ok(expiring_stock(
+        hospital.id,
+        settings(hospital.id).expiry_warning_days,
+    ))
+}
+
+// join as many items as fit in an alert, counting the ones left out
+fn alert_list(items: Vec<String>) -> String {
+    let mut listed = String::new();
+    let mut shown = 0;
+    for item in &items {
+        let separator = if shown == 0 { "" } else { ", " };
+        if listed.len() + separator.len() + item.len() > MAX_ALERT_LIST_BYTES {
+            break;
+        }
+        listed.push_str(separator);
+        listed.push_str(item);
+        shown += 1;
+    }
+    match items.len() - shown {
+        0 => listed,
+        more if shown == 0 => format!("{} items", more),
+        more => format!("{} +{} more", listed, more),
+    }
+}
+
+// timer job: send low stock and near-expiry alerts to every hospital inbox
+pub(crate) fn check_stock_alerts() {
+    let hospital_ids: Vec<u64> =
+        HOSPITAL_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect());
+    for hospital_id in hospital_ids {
+        let low = low_stock(hospital_id);
+        if !low.is_empty() {
+            let drugs: Vec<String> = low
+                .iter()
+                .map(|level| format!("{} ({})", level.drug, level.quantity))
+                .collect();
+            notify(
+                Recipient::Hospital(hospital_id),
+                Priority::High,
+                text(
+                    "pharmacy.low_stock",
+                    "Low stock: {drugs}",
+                    vec![("drugs", alert_list(drugs))],
+                ),
+            );
+        }
+        let expiring = expiring_stock(hospital_id, settings(hospital_id).expiry_warning_days);
+        if !expiring.is_empty() {
+            let batches: Vec<String> = expiring
+                .iter()
+                .map(|batch| format!("{} batch {}", batch.drug, batch.batch))
+                .collect();
+            notify(
+                Recipient::Hospital(hospital_id),
+                Priority::Normal,
+                text(
+                    "pharmacy.stock_expiring",
+                    "Stock expiring soon: {batches}",
+                    vec![("batches", alert_list(batches))],
+                ),
+            );
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, get_assigned_patient, get_encounter_by_id, get_encounter_entry,
+    get_record, impl_storable, next_id, patient_allergies, patient_problems, remember_change,
+    Actor, ChangeRef, Error, Memory, PreviousValue, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_PINS_PER_PATIENT: usize = 10;
+const MAX_PIN_TEXT_LEN: usize = 200;
+
+// What a pin points at, free text covers things without an entry of their own, such as an
+// implanted device
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum PinnedItem {
+    Record(u64),
+    Allergy(u64),
+    Problem(u64),
+    EncounterEntry(u64),
+    Note(String),
+}
+
+// A critical item the care team keeps at the top of the patient's chart
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Pin {
+    pub id: u64,
+    pub patient_id: u64,
+    pub item: PinnedItem,
+    // why the item matters, e.g. "on warfarin, check INR before procedures"
+    pub label: String,
+    pub pinned_by: u64,
+    pub pinned_at: u64,
+}
+
+impl_storable!(Pin, 1024);
+
+thread_local! {
+    // keyed by (patient id, pin id)
+    static PIN_STORAGE: RefCell<StableBTreeMap<(u64, u64), Pin, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PinPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub item: PinnedItem,
+    pub label: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct UnpinPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub pin_id: u64,
+}
+
+// the patient's pins, newest first
+pub(crate) fn patient_pins(patient_id: u64) -> Vec<Pin> {
+    let mut pins: Vec<Pin> = PIN_STORAGE.with(|s| {
+        s.borrow()
+            .range((patient_id, 0)..=(patient_id, u64::MAX))
+            .map(|(_, pin)| pin)
+            .collect()
+    });
+    pins.reverse();
+    pins
+}
+
+// helper function to check the pinned item exists and belongs to the patient
+fn check_pinned_item(patient_id: u64, item: &PinnedItem) -> Result<(), Error> {
+    let belongs = match item {
+        PinnedItem::Record(record_id) => get_record(*record_id)?.patient_id == patient_id,
+        PinnedItem::Allergy(allergy_id) => patient_allergies(patient_id)
+            .iter()
+            .any(|allergy| allergy.id == *allergy_id),
+        PinnedItem::Problem(problem_id) => patient_problems(patient_id, false)
+            .iter()
+            .any(|problem| problem.id == *problem_id),
+        PinnedItem::EncounterEntry(entry_id) => {
+            let entry = get_encounter_entry(*entry_id)?;
+            get_encounter_by_id(entry.encounter_id)?.patient_id == patient_id
+        }
+        PinnedItem::Note(text) => {
+            if text.trim().is_empty() || text.chars().count() > MAX_PIN_TEXT_LEN {
+                return Err(Error::InvalidPayload {
+                    msg: format!("Pinned note must be 1 to {} characters", MAX_PIN_TEXT_LEN),
+                });
+            }
+            true
+        }
+    };
+    if !belongs {
+        return Err(Error::NotFound {
+            msg: format!("Pinned item not found for patient of id: {}", patient_id),
+        });
+    }
+    Ok(())
+}
+
+// pin a critical item so it always shows at the top of the patient's chart
+#[ic_cdk::update]
+fn pin_chart_item(payload: PinPayload) -> Result<Pin, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    if payload.label.chars().count() > MAX_PIN_TEXT_LEN {
+        return Err(Error::InvalidPayload {
+            msg: format!("Pin label exceeds {} characters", MAX_PIN_TEXT_LEN),
+        });
+    }
+    check_pinned_item(patient.id, &payload.item)?;
+    let pins = patient_pins(patient.id);
+    if pins.iter().any(|pin| pin.item == payload.item) {
+        return Err(Error::AlreadyInit {
+            msg: "Item is already pinned".to_string(),
+        });
+    }
+    if pins.len() >= MAX_PINS_PER_PATIENT {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "A patient can have at most {} pinned items, unpin one first",
+                MAX_PINS_PER_PATIENT
+            ),
+        });
+    }
+
+    let pin = Pin {
+        id: next_id(),
+        patient_id: patient.id,
+        item: payload.item,
+        label: payload.label,
+        pinned_by: doctor.id,
+        pinned_at: time(),
+    };
+    PIN_STORAGE.with(|s| s.borrow_mut().insert((patient.id, pin.id), pin.clone()));
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "chart_item_pinned",
+        format!("pin {}: {}", pin.id, pin.label),
+    );
+    Ok(pin)
+}
+
+#[ic_cdk::update]
+fn unpin_chart_item(payload: UnpinPayload) -> Result<Pin, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let pin = PIN_STORAGE
+        .with(|s| s.borrow_mut().remove(&(patient.id, payload.pin_id)))
+        .ok_or(Error::NotFound {
+            msg: format!("Pin of id: {} not found", payload.pin_id),
+        })?;
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "chart_item_unpinned",
+        format!("pin {}: {}", pin.id, pin.label),
+    );
+    remember_change(
+        ChangeRef::Pin(patient.id, pin.id),
+        "chart_item_unpinned",
+        PreviousValue::Pin(pin.clone()),
+        Actor::Doctor(doctor.id),
+        vec![doctor.hospital_id],
+    );
+    Ok(pin)
+}
+
+// put back a pin removed by mistake, still counting against the per-patient limit
+pub(crate) fn restore_pin(pin: &Pin) -> Result<(), Error> {
+    if patient_pins(pin.patient_id).len() >= MAX_PINS_PER_PATIENT {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "A patient can have at most {} pinned items, unpin one first",
+                MAX_PINS_PER_PATIENT
+            ),
+        });
+    }
+    PIN_STORAGE.with(|s| s.borrow_mut().insert((pin.patient_id, pin.id), pin.clone()));
+    Ok(())
+}
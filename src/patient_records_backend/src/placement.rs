@@ -0,0 +1,209 @@
+use crate::time;
+use crate::{
+    audit, authorize_hospital, catalog_entry, check_site, impl_storable, Actor, Error, Memory,
+    DOCTOR_STORAGE, MAX_PAGE_SIZE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Where a doctor works within their hospital: a department, named by a specialty or service
+// code of the catalog, and the site they are based at
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct DoctorPlacement {
+    pub doctor_id: u64,
+    pub hospital_id: u64,
+    pub department: Option<String>,
+    pub site_id: Option<u64>,
+    pub updated_at: u64,
+}
+
+impl_storable!(DoctorPlacement, 256);
+
+thread_local! {
+    static PLACEMENT_STORAGE: RefCell<StableBTreeMap<u64, DoctorPlacement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(123)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DoctorReassignment {
+    pub doctor_id: u64,
+    // None leaves the doctor without a department or site
+    pub department: Option<String>,
+    pub site_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct BulkReassignPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub reassignments: Vec<DoctorReassignment>,
+    // when set, nothing is changed unless every reassignment is valid
+    pub all_or_nothing: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub struct ReassignmentResult {
+    pub doctor_id: u64,
+    pub result: Result<DoctorPlacement, Error>,
+}
+
+// the placement of a doctor at their current hospital, placements made at a hospital the
+// doctor has since left are ignored
+pub(crate) fn doctor_placement(doctor_id: u64) -> Option<DoctorPlacement> {
+    let hospital_id = DOCTOR_STORAGE
+        .with(|s| s.borrow().get(&doctor_id))?
+        .hospital_id;
+    PLACEMENT_STORAGE
+        .with(|s| s.borrow().get(&doctor_id))
+        .filter(|placement| placement.hospital_id == hospital_id)
+}
+
+// check a reassignment against the hospital and build the placement it would store
+fn prepare_placement(
+    hospital_id: u64,
+    reassignment: &DoctorReassignment,
+) -> Result<DoctorPlacement, Error> {
+    let doctor = DOCTOR_STORAGE
+        .with(|s| s.borrow().get(&reassignment.doctor_id))
+        .filter(|doctor| doctor.hospital_id == hospital_id)
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "Doctor of id: {} not found at this hospital",
+                reassignment.doctor_id
+            ),
+        })?;
+    check_site(hospital_id, reassignment.site_id)?;
+    let department = match &reassignment.department {
+        Some(code) => Some(catalog_entry(code)?.code),
+        None => None,
+    };
+    Ok(DoctorPlacement {
+        doctor_id: doctor.id,
+        hospital_id,
+        department,
+        site_id: reassignment.site_id,
+        updated_at: time(),
+    })
+}
+
+fn describe(placement: Option<&DoctorPlacement>) -> String {
+    match placement {
+        Some(placement) => format!(
+            "department {} site {}",
+            placement.department.as_deref().unwrap_or("-"),
+            placement
+                .site_id
+                .map_or("-".to_string(), |site_id| site_id.to_string())
+        ),
+        None => "unplaced".to_string(),
+    }
+}
+
+fn save_placement(placement: &DoctorPlacement) {
+    let previous = doctor_placement(placement.doctor_id);
+    PLACEMENT_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(placement.doctor_id, placement.clone())
+    });
+    audit(
+        Actor::Hospital(placement.hospital_id),
+        Some(placement.hospital_id),
+        None,
+        "doctor_reassigned",
+        format!(
+            "doctor {} from {} to {}",
+            placement.doctor_id,
+            describe(previous.as_ref()),
+            describe(Some(placement))
+        ),
+    );
+}
+
+// hospital admins move many doctors between departments and sites at once, e.g. during a
+// reorganization. Each reassignment gets its own result; in all-or-nothing mode a single
+// invalid one leaves every doctor where they were
+#[ic_cdk::update]
+fn bulk_reassign_doctors(payload: BulkReassignPayload) -> Result<Vec<ReassignmentResult>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if payload.reassignments.len() as u64 > MAX_PAGE_SIZE {
+        return Err(Error::LimitExceeded {
+            msg: format!("A batch can hold at most {} doctors", MAX_PAGE_SIZE),
+        });
+    }
+    let mut seen: Vec<u64> = vec![];
+    let prepared: Vec<(u64, Result<DoctorPlacement, Error>)> = payload
+        .reassignments
+        .iter()
+        .map(|reassignment| {
+            let doctor_id = reassignment.doctor_id;
+            if seen.contains(&doctor_id) {
+                return (
+                    doctor_id,
+                    Err(Error::InvalidPayload {
+                        msg: format!("Doctor of id: {} is listed more than once", doctor_id),
+                    }),
+                );
+            }
+            seen.push(doctor_id);
+            (doctor_id, prepare_placement(hospital.id, reassignment))
+        })
+        .collect();
+    let failed = prepared
+        .iter()
+        .filter(|(_, result)| result.is_err())
+        .count();
+    let apply = failed == 0 || !payload.all_or_nothing;
+    Ok(prepared
+        .into_iter()
+        .map(|(doctor_id, result)| ReassignmentResult {
+            doctor_id,
+            result: match result {
+                Ok(placement) if apply => {
+                    save_placement(&placement);
+                    Ok(placement)
+                }
+                Ok(_) => Err(Error::InvalidPayload {
+                    msg: format!(
+                        "Not applied, {} other reassignments in the batch are invalid",
+                        failed
+                    ),
+                }),
+                Err(e) => Err(e),
+            },
+        })
+        .collect())
+}
+
+// where the hospital's doctors work, optionally only one department or site
+#[ic_cdk::query]
+fn get_doctor_placements(
+    hospital_id: u64,
+    department: Option<String>,
+    site_id: Option<u64>,
+) -> Vec<DoctorPlacement> {
+    let department = department.map(|code| code.trim().to_lowercase());
+    PLACEMENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, placement)| placement)
+            .filter(|placement| placement.hospital_id == hospital_id)
+            .filter(|placement| {
+                department.is_none() || placement.department.as_deref() == department.as_deref()
+            })
+            .filter(|placement| site_id.is_none() || placement.site_id == site_id)
+            .filter(|placement| {
+                DOCTOR_STORAGE
+                    .with(|s| s.borrow().get(&placement.doctor_id))
+                    .is_some_and(|doctor| doctor.hospital_id == hospital_id)
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_doctor_placement(doctor_id: u64) -> Option<DoctorPlacement> {
+    doctor_placement(doctor_id)
+}
@@ -0,0 +1,331 @@
+use crate::time;
+use crate::{
+    authorize_controller, authorize_hospital, impl_storable, Error, Memory, HOSPITAL_STORAGE,
+    MEMORY_MANAGER,
+};
+use candid::{Nat, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell as StableCell, StableBTreeMap};
+use std::cell::{Cell, RefCell};
+
+const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+const DAY_NS: u64 = 24 * HOUR_NS;
+// a balance check counts for this long, the daily refresh renews it while the tokens stay
+const HOLDING_VALID_NS: u64 = 26 * HOUR_NS;
+
+// Features only hospitals holding tokens or paying a subscription may use, once gated
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum PremiumFeature {
+    Analytics,
+    DataExport,
+    Federation,
+}
+
+impl PremiumFeature {
+    fn label(&self) -> &'static str {
+        match self {
+            PremiumFeature::Analytics => "analytics",
+            PremiumFeature::DataExport => "data export",
+            PremiumFeature::Federation => "federation",
+        }
+    }
+}
+
+// An ICRC-1 account, owner and optional 32 byte subaccount
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LedgerAccount {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct PremiumSettings {
+    // the ICRC-1 ledger of the token, nothing is gated without one
+    pub ledger: Option<Principal>,
+    pub gated_features: Vec<PremiumFeature>,
+    // balance a hospital's registered account has to hold, staked or not
+    pub min_balance: Nat,
+    // what a subscription period costs and where payments are swept to
+    pub subscription_price: Nat,
+    pub subscription_days: u64,
+    pub treasury: Option<LedgerAccount>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct PremiumEntitlement {
+    pub hospital_id: u64,
+    // the account whose balance is checked against min_balance
+    pub holding_account: Option<LedgerAccount>,
+    pub holding_verified_until: Option<u64>,
+    pub subscribed_until: Option<u64>,
+    pub last_checked_at: Option<u64>,
+    // why the last refresh could not reach the ledger, if it failed
+    pub last_error: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PremiumStatus {
+    pub entitlement: PremiumEntitlement,
+    // where to transfer the subscription price to pay for a period
+    pub payment_account: LedgerAccount,
+    pub subscription_price: Nat,
+    pub gated_features: Vec<PremiumFeature>,
+    pub entitled: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: LedgerAccount,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+impl_storable!(PremiumSettings, 512);
+impl_storable!(PremiumEntitlement, 512);
+
+thread_local! {
+    static PREMIUM_SETTINGS: RefCell<StableCell<PremiumSettings, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(128))),
+            PremiumSettings::default(),
+        )
+        .expect("Cannot create premium settings")
+    );
+
+    static ENTITLEMENT_STORAGE: RefCell<StableBTreeMap<u64, PremiumEntitlement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(129)))
+    ));
+
+    // set while the daily refresh runs so timer ticks do not overlap
+    static REFRESHING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn premium_settings() -> PremiumSettings {
+    PREMIUM_SETTINGS.with(|s| s.borrow().get().clone())
+}
+
+fn entitlement(hospital_id: u64) -> PremiumEntitlement {
+    ENTITLEMENT_STORAGE
+        .with(|s| s.borrow().get(&hospital_id))
+        .unwrap_or(PremiumEntitlement {
+            hospital_id,
+            ..Default::default()
+        })
+}
+
+fn save_entitlement(entitlement: &PremiumEntitlement) {
+    ENTITLEMENT_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(entitlement.hospital_id, entitlement.clone())
+    });
+}
+
+// the subaccount of this canister a hospital pays its subscription into
+fn payment_subaccount(hospital_id: u64) -> Vec<u8> {
+    let mut subaccount = vec![0; 32];
+    subaccount[0] = 1;
+    subaccount[24..].copy_from_slice(&hospital_id.to_be_bytes());
+    subaccount
+}
+
+fn is_entitled(entitlement: &PremiumEntitlement, now: u64) -> bool {
+    entitlement.holding_verified_until.unwrap_or(0) > now
+        || entitlement.subscribed_until.unwrap_or(0) > now
+}
+
+// refuse a gated feature to a hospital without a current holding or subscription
+pub(crate) fn require_premium(hospital_id: u64, feature: PremiumFeature) -> Result<(), Error> {
+    let settings = premium_settings();
+    if settings.ledger.is_none() || !settings.gated_features.contains(&feature) {
+        return Ok(());
+    }
+    if is_entitled(&entitlement(hospital_id), time()) {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: format!(
+            "The {} feature needs a token holding or a subscription, see get_premium_status",
+            feature.label()
+        ),
+    })
+}
+
+async fn balance_of(ledger: Principal, account: LedgerAccount) -> Result<Nat, String> {
+    let reply: Result<(Nat,), _> = ic_cdk::call(ledger, "icrc1_balance_of", (account,)).await;
+    reply
+        .map(|(balance,)| balance)
+        .map_err(|(code, msg)| format!("icrc1_balance_of: {:?} {}", code, msg))
+}
+
+// move a paid subscription from the hospital's payment subaccount to the treasury
+async fn collect_payment(
+    ledger: Principal,
+    treasury: LedgerAccount,
+    hospital_id: u64,
+    balance: Nat,
+) -> Result<(), String> {
+    let reply: Result<(Nat,), _> = ic_cdk::call(ledger, "icrc1_fee", ()).await;
+    let (fee,) = reply.map_err(|(code, msg)| format!("icrc1_fee: {:?} {}", code, msg))?;
+    if balance <= fee {
+        return Err("payment does not cover the ledger fee".to_string());
+    }
+    let transfer = TransferArg {
+        from_subaccount: Some(payment_subaccount(hospital_id)),
+        to: treasury,
+        amount: balance - fee.clone(),
+        fee: Some(fee),
+        memo: Some(hospital_id.to_be_bytes().to_vec()),
+        created_at_time: None,
+    };
+    let reply: Result<(Result<Nat, candid::Reserved>,), _> =
+        ic_cdk::call(ledger, "icrc1_transfer", (transfer,)).await;
+    match reply {
+        Ok((Ok(_),)) => Ok(()),
+        Ok((Err(_),)) => Err("icrc1_transfer was rejected by the ledger".to_string()),
+        Err((code, msg)) => Err(format!("icrc1_transfer: {:?} {}", code, msg)),
+    }
+}
+
+// check the hospital's holding and collect any subscription payment waiting for it
+async fn refresh_entitlement(hospital_id: u64) -> PremiumEntitlement {
+    let settings = premium_settings();
+    let mut current = entitlement(hospital_id);
+    let ledger = match settings.ledger {
+        Some(ledger) => ledger,
+        None => return current,
+    };
+    let mut error = None;
+    if let Some(account) = current.holding_account.clone() {
+        match balance_of(ledger, account).await {
+            Ok(balance) if balance >= settings.min_balance => {
+                current.holding_verified_until = Some(time() + HOLDING_VALID_NS)
+            }
+            Ok(_) => current.holding_verified_until = None,
+            Err(e) => error = Some(e),
+        }
+    }
+    if let Some(treasury) = settings.treasury {
+        let deposit = LedgerAccount {
+            owner: ic_cdk::id(),
+            subaccount: Some(payment_subaccount(hospital_id)),
+        };
+        match balance_of(ledger, deposit).await {
+            Ok(balance)
+                if settings.subscription_days > 0 && balance >= settings.subscription_price =>
+            {
+                match collect_payment(ledger, treasury, hospital_id, balance).await {
+                    Ok(()) => {
+                        let now = time();
+                        let start = current.subscribed_until.unwrap_or(now).max(now);
+                        current.subscribed_until =
+                            Some(start + settings.subscription_days * DAY_NS);
+                    }
+                    Err(e) => error = Some(e),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error = Some(e),
+        }
+    }
+    // keep what changed in storage while the ledger calls were in flight
+    let stored = entitlement(hospital_id);
+    if stored.holding_account != current.holding_account {
+        current.holding_account = stored.holding_account;
+        current.holding_verified_until = stored.holding_verified_until;
+    }
+    current.subscribed_until = current.subscribed_until.max(stored.subscribed_until);
+    current.last_checked_at = Some(time());
+    current.last_error = error;
+    save_entitlement(&current);
+    current
+}
+
+// timer task: renew the holding checks and collect payments of every registered hospital
+pub(crate) async fn refresh_premium_entitlements() {
+    if premium_settings().ledger.is_none() || REFRESHING.with(|r| r.replace(true)) {
+        return;
+    }
+    let hospital_ids: Vec<u64> =
+        ENTITLEMENT_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect());
+    for hospital_id in hospital_ids {
+        refresh_entitlement(hospital_id).await;
+    }
+    REFRESHING.with(|r| r.set(false));
+}
+
+// controllers pick the ledger, thresholds and which features are gated
+#[ic_cdk::update]
+fn set_premium_settings(settings: PremiumSettings) -> Result<PremiumSettings, Error> {
+    authorize_controller()?;
+    if settings.ledger.is_none() && !settings.gated_features.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Gating features needs a ledger to check".to_string(),
+        });
+    }
+    PREMIUM_SETTINGS
+        .with(|s| s.borrow_mut().set(settings.clone()))
+        .expect("Cannot update premium settings");
+    Ok(settings)
+}
+
+#[ic_cdk::query]
+fn get_premium_settings() -> PremiumSettings {
+    premium_settings()
+}
+
+// hospital admins name the account holding or staking their tokens
+#[ic_cdk::update]
+fn set_premium_holding_account(
+    hospital_id: u64,
+    hospital_password: String,
+    account: Option<LedgerAccount>,
+) -> Result<PremiumEntitlement, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    let mut current = entitlement(hospital.id);
+    if current.holding_account != account {
+        current.holding_account = account;
+        current.holding_verified_until = None;
+    }
+    save_entitlement(&current);
+    Ok(current)
+}
+
+// check the ledger now instead of waiting for the daily refresh, e.g. right after paying
+#[ic_cdk::update]
+async fn refresh_premium_status(
+    hospital_id: u64,
+    hospital_password: String,
+) -> Result<PremiumEntitlement, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    if premium_settings().ledger.is_none() {
+        return Err(Error::NotFound {
+            msg: "No ledger is configured".to_string(),
+        });
+    }
+    Ok(refresh_entitlement(hospital.id).await)
+}
+
+#[ic_cdk::query]
+fn get_premium_status(hospital_id: u64) -> Result<PremiumStatus, Error> {
+    if !HOSPITAL_STORAGE.with(|s| s.borrow().contains_key(&hospital_id)) {
+        return Err(Error::NotFound {
+            msg: format!("Hospital of id: {} not found", hospital_id),
+        });
+    }
+    let settings = premium_settings();
+    let entitlement = entitlement(hospital_id);
+    Ok(PremiumStatus {
+        entitled: is_entitled(&entitlement, time()),
+        entitlement,
+        payment_account: LedgerAccount {
+            owner: ic_cdk::id(),
+            subaccount: Some(payment_subaccount(hospital_id)),
+        },
+        subscription_price: settings.subscription_price,
+        gated_features: settings.gated_features,
+    })
+}
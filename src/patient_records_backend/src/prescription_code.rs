@@ -0,0 +1,202 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_hospital, get_assigned_patient, get_encounter_by_id,
+    get_encounter_entry, impl_storable, sign_hash, to_hex, Actor, EncounterEntryKind, Error,
+    Memory, Prescription, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const CODE_VERSION: &str = "RX1";
+
+// A prescription that can be presented as a QR code, keyed by its encounter entry
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PrescriptionCode {
+    pub entry_id: u64,
+    pub patient_id: u64,
+    pub doctor_id: u64,
+    pub prescription: Prescription,
+    pub valid_from: u64,
+    pub valid_until: u64,
+    pub fills_used: u32,
+    pub signature: Vec<u8>,
+    pub code: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PrescriptionCodeCheck {
+    pub authentic: bool,
+    pub within_validity: bool,
+    pub remaining_fills: u32,
+    pub medication: String,
+    pub dosage: String,
+    pub valid_until: u64,
+}
+
+impl_storable!(PrescriptionCode, 1024);
+
+thread_local! {
+    static CODE_STORAGE: RefCell<StableBTreeMap<u64, PrescriptionCode, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct IssueCodePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub entry_id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct RedeemCodePayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub code: String,
+}
+
+// the signed part of the code: version, entry, patient, expiry in seconds and refills
+fn code_message(code: &PrescriptionCode) -> String {
+    format!(
+        "{}.{}.{}.{}.{}",
+        CODE_VERSION,
+        code.entry_id,
+        code.patient_id,
+        code.valid_until / 1_000_000_000,
+        code.prescription.refills
+    )
+}
+
+// helper function to look up the stored code a presented one refers to
+fn find_code(code: &str) -> Result<PrescriptionCode, Error> {
+    let invalid = || Error::InvalidPayload {
+        msg: "Malformed prescription code".to_string(),
+    };
+    let mut parts = code.split('.');
+    if parts.next() != Some(CODE_VERSION) {
+        return Err(invalid());
+    }
+    let entry_id: u64 = parts
+        .next()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(invalid)?;
+    CODE_STORAGE
+        .with(|s| s.borrow().get(&entry_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Prescription code for entry: {} not found", entry_id),
+        })
+}
+
+fn check_code(stored: &PrescriptionCode, presented: &str) -> PrescriptionCodeCheck {
+    let now = time();
+    PrescriptionCodeCheck {
+        authentic: stored.code == presented,
+        within_validity: stored.valid_from <= now && now <= stored.valid_until,
+        remaining_fills: (stored.prescription.refills + 1).saturating_sub(stored.fills_used),
+        medication: stored.prescription.medication.clone(),
+        dosage: stored.prescription.dosage.clone(),
+        valid_until: stored.valid_until,
+    }
+}
+
+// turn a prescription entry into a compact signed code for a QR image
+#[ic_cdk::update]
+async fn issue_prescription_code(payload: IssueCodePayload) -> Result<PrescriptionCode, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let entry = get_encounter_entry(payload.entry_id)?;
+    let encounter = get_encounter_by_id(entry.encounter_id)?;
+    let patient = get_assigned_patient(&doctor, encounter.patient_id)?;
+    let prescription = match entry.kind {
+        EncounterEntryKind::Prescription(prescription) => prescription,
+        _ => {
+            return Err(Error::InvalidPayload {
+                msg: format!("Entry {} is not a prescription", entry.id),
+            })
+        }
+    };
+    if CODE_STORAGE.with(|s| s.borrow().contains_key(&entry.id)) {
+        return Err(Error::AlreadyInit {
+            msg: format!("Prescription entry {} already has a code", entry.id),
+        });
+    }
+
+    // every fill covers one course of the prescription
+    let fills = prescription.refills as u64 + 1;
+    let valid_from = time();
+    let mut code = PrescriptionCode {
+        entry_id: entry.id,
+        patient_id: patient.id,
+        doctor_id: doctor.id,
+        valid_from,
+        valid_until: valid_from + fills * prescription.duration_days.max(1) as u64 * DAY_NS,
+        prescription,
+        fills_used: 0,
+        signature: vec![],
+        code: String::new(),
+    };
+    let message = code_message(&code);
+    code.signature = sign_hash(Sha256::digest(message.as_bytes()).to_vec()).await?;
+    code.code = format!("{}.{}", message, to_hex(&code.signature));
+    CODE_STORAGE.with(|s| s.borrow_mut().insert(code.entry_id, code.clone()));
+    Ok(code)
+}
+
+// stop every code of the patient from being filled again
+pub(crate) fn void_prescription_codes(patient_id: u64) -> u32 {
+    let now = time();
+    CODE_STORAGE.with(|s| {
+        let mut codes = s.borrow_mut();
+        let open: Vec<PrescriptionCode> = codes
+            .iter()
+            .map(|(_, code)| code)
+            .filter(|code| code.patient_id == patient_id && code.valid_until > now)
+            .collect();
+        for code in &open {
+            codes.insert(
+                code.entry_id,
+                PrescriptionCode {
+                    valid_until: now,
+                    ..code.clone()
+                },
+            );
+        }
+        open.len() as u32
+    })
+}
+
+// pharmacies check a scanned code without needing an account
+#[ic_cdk::query]
+fn verify_prescription_code(code: String) -> Result<PrescriptionCodeCheck, Error> {
+    let stored = find_code(&code)?;
+    Ok(check_code(&stored, &code))
+}
+
+// record that a pharmacy filled the prescription once
+#[ic_cdk::update]
+fn redeem_prescription_code(payload: RedeemCodePayload) -> Result<PrescriptionCodeCheck, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let stored = find_code(&payload.code)?;
+    let check = check_code(&stored, &payload.code);
+    if !check.authentic || !check.within_validity || check.remaining_fills == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "Prescription code is not valid for another fill".to_string(),
+        });
+    }
+    let redeemed = PrescriptionCode {
+        fills_used: stored.fills_used + 1,
+        ..stored
+    };
+    CODE_STORAGE.with(|s| s.borrow_mut().insert(redeemed.entry_id, redeemed.clone()));
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        Some(redeemed.patient_id),
+        "prescription_filled",
+        format!("entry {} fill {}", redeemed.entry_id, redeemed.fills_used),
+    );
+    Ok(check_code(&redeemed, &payload.code))
+}
@@ -0,0 +1,176 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_patient_access, get_assigned_patient, impl_storable,
+    next_id, Actor, Error, Memory, PatientAccess, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ProblemStatus {
+    Active,
+    Resolved,
+}
+
+// One condition on the patient's problem list
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Problem {
+    pub id: u64,
+    pub patient_id: u64,
+    pub condition: String,
+    // ICD-10 code, e.g. "E11.9"
+    pub icd_code: String,
+    pub onset_date: Option<u64>,
+    pub status: ProblemStatus,
+    pub recorded_by: u64,
+    pub recorded_at: u64,
+    pub resolved_at: Option<u64>,
+    pub notes: String,
+}
+
+impl_storable!(Problem, 1024);
+
+thread_local! {
+    static PROBLEM_STORAGE: RefCell<StableBTreeMap<u64, Problem, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ProblemPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub condition: String,
+    pub icd_code: String,
+    pub onset_date: Option<u64>,
+    pub notes: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ProblemStatusPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub problem_id: u64,
+    pub status: ProblemStatus,
+}
+
+// problems of every patient recorded in [from, to)
+pub(crate) fn problems_recorded_between(from: u64, to: u64) -> Vec<Problem> {
+    PROBLEM_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, problem)| problem)
+            .filter(|problem| problem.recorded_at >= from && problem.recorded_at < to)
+            .collect()
+    })
+}
+
+// problems of a patient, optionally only the active ones, oldest first
+pub(crate) fn patient_problems(patient_id: u64, active_only: bool) -> Vec<Problem> {
+    PROBLEM_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, problem)| problem)
+            .filter(|problem| {
+                problem.patient_id == patient_id
+                    && (!active_only || problem.status == ProblemStatus::Active)
+            })
+            .collect()
+    })
+}
+
+// ICD-10 codes are a letter, two digits and an optional dotted extension
+fn is_icd_code(code: &str) -> bool {
+    let bytes = code.as_bytes();
+    bytes.len() >= 3
+        && bytes[0].is_ascii_uppercase()
+        && bytes[1..3].iter().all(u8::is_ascii_digit)
+        && match code.get(3..) {
+            Some("") => true,
+            Some(rest) => {
+                rest.starts_with('.')
+                    && rest.len() > 1
+                    && rest[1..].chars().all(|c| c.is_ascii_alphanumeric())
+            }
+            None => false,
+        }
+}
+
+#[ic_cdk::update]
+fn add_problem(payload: ProblemPayload) -> Result<Problem, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let icd_code = payload.icd_code.trim().to_uppercase();
+    if payload.condition.trim().is_empty() || !is_icd_code(&icd_code) {
+        return Err(Error::InvalidPayload {
+            msg: "Problems need a condition and a valid ICD-10 code".to_string(),
+        });
+    }
+    if patient_problems(patient.id, true)
+        .iter()
+        .any(|problem| problem.icd_code == icd_code)
+    {
+        return Err(Error::AlreadyInit {
+            msg: format!("Patient already has an active problem coded {}", icd_code),
+        });
+    }
+    let problem = Problem {
+        id: next_id(),
+        patient_id: patient.id,
+        condition: payload.condition,
+        icd_code,
+        onset_date: payload.onset_date,
+        status: ProblemStatus::Active,
+        recorded_by: doctor.id,
+        recorded_at: time(),
+        resolved_at: None,
+        notes: payload.notes,
+    };
+    PROBLEM_STORAGE.with(|s| s.borrow_mut().insert(problem.id, problem.clone()));
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "problem_added",
+        format!("problem {} {}", problem.id, problem.icd_code),
+    );
+    Ok(problem)
+}
+
+// resolve a problem, or reactivate it when the condition recurs
+#[ic_cdk::update]
+fn set_problem_status(payload: ProblemStatusPayload) -> Result<Problem, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let problem = PROBLEM_STORAGE
+        .with(|s| s.borrow().get(&payload.problem_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Problem of id: {} not found", payload.problem_id),
+        })?;
+    get_assigned_patient(&doctor, problem.patient_id)?;
+    let updated = Problem {
+        status: payload.status,
+        resolved_at: match payload.status {
+            ProblemStatus::Resolved => Some(time()),
+            ProblemStatus::Active => None,
+        },
+        ..problem
+    };
+    PROBLEM_STORAGE.with(|s| s.borrow_mut().insert(updated.id, updated.clone()));
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(updated.patient_id),
+        "problem_status",
+        format!("problem {}", updated.id),
+    );
+    Ok(updated)
+}
+
+#[ic_cdk::query]
+fn get_problem_list(patient_id: u64, access: PatientAccess) -> Result<Vec<Problem>, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    Ok(patient_problems(patient.id, false))
+}
@@ -0,0 +1,376 @@
+use crate::time;
+use crate::{
+    authorize_doctor, authorize_hospital, get_assigned_patient, has_valid_procedure_consent,
+    impl_storable, next_id, Error, Memory, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// the checklist and procedure name share the booking's 4096 bytes
+const MAX_CHECKLIST_ITEMS: usize = 20;
+const MAX_CHECKLIST_ITEM_BYTES: usize = 100;
+const MAX_PROCEDURE_NAME_BYTES: usize = 200;
+// a resource's name and kind share its 512 bytes
+const MAX_RESOURCE_NAME_BYTES: usize = 200;
+const MAX_RESOURCE_KIND_BYTES: usize = 100;
+
+// A bookable hospital resource such as an operating room or a scanner
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ProcedureResource {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub name: String,
+    pub kind: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum BookingStatus {
+    Scheduled,
+    Cancelled,
+    Performed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ChecklistItem {
+    pub name: String,
+    pub done: bool,
+    pub checked_at: Option<u64>,
+    pub checked_by: Option<u64>,
+}
+
+// A reservation of a resource for a procedure over [start, end)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ProcedureBooking {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub resource_id: u64,
+    pub patient_id: u64,
+    pub doctor_id: u64,
+    pub procedure: String,
+    pub start: u64,
+    pub end: u64,
+    pub status: BookingStatus,
+    pub checklist: Vec<ChecklistItem>,
+    pub created_at: u64,
+}
+
+impl_storable!(ProcedureResource, 512);
+impl_storable!(ProcedureBooking, 4096);
+
+thread_local! {
+    static RESOURCE_STORAGE: RefCell<StableBTreeMap<u64, ProcedureResource, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    static BOOKING_STORAGE: RefCell<StableBTreeMap<u64, ProcedureBooking, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ResourcePayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub name: String,
+    pub kind: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct BookProcedurePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub resource_id: u64,
+    pub patient_id: u64,
+    pub procedure: String,
+    pub start: u64,
+    pub end: u64,
+    // names of the pre-op checks that must be done before the procedure
+    pub checklist: Vec<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ChecklistUpdatePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub booking_id: u64,
+    pub item: String,
+    pub done: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct BookingAccessPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub booking_id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ResourceSchedulePayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub resource_id: u64,
+    pub from: u64,
+    pub to: u64,
+}
+
+fn get_resource(resource_id: u64) -> Result<ProcedureResource, Error> {
+    RESOURCE_STORAGE
+        .with(|s| s.borrow().get(&resource_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Resource of id: {} not found", resource_id),
+        })
+}
+
+pub(crate) fn get_booking(booking_id: u64) -> Result<ProcedureBooking, Error> {
+    BOOKING_STORAGE
+        .with(|s| s.borrow().get(&booking_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Procedure booking of id: {} not found", booking_id),
+        })
+}
+
+// helper function to get a booking the doctor may change
+fn get_authorized_booking(
+    doctor_id: u64,
+    doctor_password: &str,
+    booking_id: u64,
+) -> Result<ProcedureBooking, Error> {
+    let doctor = authorize_doctor(doctor_id, doctor_password)?;
+    let booking = get_booking(booking_id)?;
+    get_assigned_patient(&doctor, booking.patient_id)?;
+    if booking.status != BookingStatus::Scheduled {
+        return Err(Error::InvalidPayload {
+            msg: format!("Procedure booking of id: {} is not scheduled", booking.id),
+        });
+    }
+    Ok(booking)
+}
+
+pub(crate) fn save_booking(booking: &ProcedureBooking) {
+    BOOKING_STORAGE.with(|s| s.borrow_mut().insert(booking.id, booking.clone()));
+}
+
+// scheduled bookings of a resource overlapping the window [start, end)
+fn overlapping_bookings(resource_id: u64, start: u64, end: u64) -> Vec<ProcedureBooking> {
+    BOOKING_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, booking)| booking)
+            .filter(|booking| {
+                booking.resource_id == resource_id
+                    && booking.status == BookingStatus::Scheduled
+                    && booking.start < end
+                    && start < booking.end
+            })
+            .collect()
+    })
+}
+
+// register an operating room, scanner or other bookable resource
+#[ic_cdk::update]
+fn add_procedure_resource(payload: ResourcePayload) -> Result<ProcedureResource, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if payload.name.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Resource name cannot be empty".to_string(),
+        });
+    }
+    if payload.name.len() > MAX_RESOURCE_NAME_BYTES || payload.kind.len() > MAX_RESOURCE_KIND_BYTES
+    {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "A resource takes a name of {} bytes and a kind of {} bytes",
+                MAX_RESOURCE_NAME_BYTES, MAX_RESOURCE_KIND_BYTES
+            ),
+        });
+    }
+    let resource = ProcedureResource {
+        id: next_id(),
+        hospital_id: hospital.id,
+        name: payload.name,
+        kind: payload.kind,
+    };
+    RESOURCE_STORAGE.with(|s| s.borrow_mut().insert(resource.id, resource.clone()));
+    Ok(resource)
+}
+
+// list the bookable resources of a hospital
+#[ic_cdk::query]
+fn get_procedure_resources(hospital_id: u64) -> Vec<ProcedureResource> {
+    RESOURCE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, resource)| resource)
+            .filter(|resource| resource.hospital_id == hospital_id)
+            .collect()
+    })
+}
+
+// reserve a resource for a procedure, rejecting overlapping reservations
+#[ic_cdk::update]
+fn book_procedure(payload: BookProcedurePayload) -> Result<ProcedureBooking, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let resource = get_resource(payload.resource_id)?;
+    if resource.hospital_id != doctor.hospital_id {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "Resource of id: {} belongs to another hospital",
+                resource.id
+            ),
+        });
+    }
+    if payload.start >= payload.end || payload.start < time() {
+        return Err(Error::InvalidPayload {
+            msg: "Booking window must be in the future and end after it starts".to_string(),
+        });
+    }
+    if payload.checklist.len() > MAX_CHECKLIST_ITEMS
+        || payload.procedure.len() > MAX_PROCEDURE_NAME_BYTES
+        || payload
+            .checklist
+            .iter()
+            .any(|item| item.len() > MAX_CHECKLIST_ITEM_BYTES)
+    {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "A booking takes up to {} checklist items of {} bytes and a procedure name of {} bytes",
+                MAX_CHECKLIST_ITEMS, MAX_CHECKLIST_ITEM_BYTES, MAX_PROCEDURE_NAME_BYTES
+            ),
+        });
+    }
+    if let Some(conflict) = overlapping_bookings(resource.id, payload.start, payload.end).first() {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Resource {} is already booked from {} to {} (booking {})",
+                resource.name, conflict.start, conflict.end, conflict.id
+            ),
+        });
+    }
+
+    let booking = ProcedureBooking {
+        id: next_id(),
+        hospital_id: resource.hospital_id,
+        resource_id: resource.id,
+        patient_id: patient.id,
+        doctor_id: doctor.id,
+        procedure: payload.procedure,
+        start: payload.start,
+        end: payload.end,
+        status: BookingStatus::Scheduled,
+        checklist: payload
+            .checklist
+            .into_iter()
+            .map(|name| ChecklistItem {
+                name,
+                done: false,
+                checked_at: None,
+                checked_by: None,
+            })
+            .collect(),
+        created_at: time(),
+    };
+    save_booking(&booking);
+    Ok(booking)
+}
+
+// tick or untick a pre-op checklist item
+#[ic_cdk::update]
+fn update_checklist_item(payload: ChecklistUpdatePayload) -> Result<ProcedureBooking, Error> {
+    let mut booking = get_authorized_booking(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.booking_id,
+    )?;
+    match booking
+        .checklist
+        .iter_mut()
+        .find(|item| item.name == payload.item)
+    {
+        Some(item) => {
+            item.done = payload.done;
+            item.checked_at = Some(time());
+            item.checked_by = Some(payload.doctor_id);
+        }
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("Checklist item: {} not found", payload.item),
+            })
+        }
+    }
+    save_booking(&booking);
+    Ok(booking)
+}
+
+// cancel a booking and free the resource
+#[ic_cdk::update]
+fn cancel_procedure_booking(payload: BookingAccessPayload) -> Result<ProcedureBooking, Error> {
+    let booking = get_authorized_booking(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.booking_id,
+    )?;
+    let cancelled = ProcedureBooking {
+        status: BookingStatus::Cancelled,
+        ..booking
+    };
+    save_booking(&cancelled);
+    Ok(cancelled)
+}
+
+// mark a procedure as performed once the patient's signed consent is on file and the whole
+// pre-op checklist is done
+#[ic_cdk::update]
+fn mark_procedure_performed(payload: BookingAccessPayload) -> Result<ProcedureBooking, Error> {
+    let booking = get_authorized_booking(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.booking_id,
+    )?;
+    if !has_valid_procedure_consent(booking.id) {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Procedure booking of id: {} has no valid signed consent",
+                booking.id
+            ),
+        });
+    }
+    let open_items: Vec<String> = booking
+        .checklist
+        .iter()
+        .filter(|item| !item.done)
+        .map(|item| item.name.clone())
+        .collect();
+    if !open_items.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: format!("Pre-op checklist incomplete: {}", open_items.join(", ")),
+        });
+    }
+    let performed = ProcedureBooking {
+        status: BookingStatus::Performed,
+        ..booking
+    };
+    save_booking(&performed);
+    Ok(performed)
+}
+
+// bookings of a resource overlapping a time window
+#[ic_cdk::query]
+fn get_resource_schedule(payload: ResourceSchedulePayload) -> Result<Vec<ProcedureBooking>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let resource = get_resource(payload.resource_id)?;
+    if resource.hospital_id != hospital.id {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "Resource of id: {} belongs to another hospital",
+                resource.id
+            ),
+        });
+    }
+    let mut bookings = overlapping_bookings(resource.id, payload.from, payload.to);
+    bookings.sort_by_key(|booking| booking.start);
+    Ok(bookings)
+}
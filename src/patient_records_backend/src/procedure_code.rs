@@ -0,0 +1,333 @@
+use crate::time;
+use crate::{
+    authorize_hospital, get_authorized_encounter, impl_storable, next_id, record_encounter_entry,
+    EncounterEntryKind, EncounterEntryPayload, EncounterStatus, Error, Memory, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// most codes one hospital's fee schedule can price
+const MAX_FEE_ENTRIES: usize = 500;
+
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum ProcedureCodeSystem {
+    // AMA Current Procedural Terminology
+    Cpt,
+    // NHS OPCS Classification of Interventions and Procedures, version 4
+    Opcs,
+}
+
+impl ProcedureCodeSystem {
+    fn label(&self) -> &'static str {
+        match self {
+            ProcedureCodeSystem::Cpt => "CPT",
+            ProcedureCodeSystem::Opcs => "OPCS",
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FeeScheduleEntry {
+    pub system: ProcedureCodeSystem,
+    pub code: String,
+    pub description: String,
+    pub charge: u64,
+}
+
+// The charge a hospital bills for each procedure code
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct FeeSchedule {
+    pub hospital_id: u64,
+    pub entries: Vec<FeeScheduleEntry>,
+    pub updated_at: u64,
+}
+
+// A procedure code recorded on an encounter, with the charge it raised if it was priced
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CodedProcedure {
+    pub id: u64,
+    pub encounter_id: u64,
+    pub patient_id: u64,
+    pub hospital_id: u64,
+    pub doctor_id: u64,
+    pub system: ProcedureCodeSystem,
+    pub code: String,
+    pub charge: Option<u64>,
+    // the charge entry raised on the encounter for billing
+    pub charge_entry_id: Option<u64>,
+    pub coded_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct FeeEntryPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub entry: FeeScheduleEntry,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RemoveFeeEntryPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub system: ProcedureCodeSystem,
+    pub code: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ProcedureCodePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub encounter_id: u64,
+    pub system: ProcedureCodeSystem,
+    pub code: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ProcedureVolumePayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    // reporting period, start inclusive and end exclusive
+    pub from: u64,
+    pub to: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ProcedureVolume {
+    pub system: ProcedureCodeSystem,
+    pub code: String,
+    pub description: String,
+    pub count: u64,
+    pub total_charges: u64,
+}
+
+impl_storable!(FeeSchedule, 65536);
+impl_storable!(CodedProcedure, 512);
+
+thread_local! {
+    static FEE_SCHEDULES: RefCell<StableBTreeMap<u64, FeeSchedule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92)))
+    ));
+
+    static CODED_PROCEDURES: RefCell<StableBTreeMap<u64, CodedProcedure, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93)))
+    ));
+}
+
+// check a code against its system's format and return it in canonical form: CPT is four
+// digits then a digit, F (category II) or T (category III); OPCS-4 is a chapter letter,
+// two digits and an optional subcategory digit, written as W37.1
+fn normalize_code(system: ProcedureCodeSystem, code: &str) -> Result<String, Error> {
+    let code = code.trim().to_ascii_uppercase();
+    let bytes = code.as_bytes();
+    let normalized = match (system, bytes) {
+        (ProcedureCodeSystem::Cpt, [d1, d2, d3, d4, last])
+            if [d1, d2, d3, d4].iter().all(|b| b.is_ascii_digit())
+                && (last.is_ascii_digit() || *last == b'F' || *last == b'T') =>
+        {
+            Some(code.clone())
+        }
+        (ProcedureCodeSystem::Opcs, [chapter, d1, d2, rest @ ..])
+            if chapter.is_ascii_alphabetic()
+                && *chapter != b'I'
+                && d1.is_ascii_digit()
+                && d2.is_ascii_digit() =>
+        {
+            match rest {
+                [] => Some(code.clone()),
+                [d3] | [b'.', d3] if d3.is_ascii_digit() => {
+                    Some(format!("{}.{}", &code[..3], *d3 as char))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    normalized.ok_or(Error::InvalidPayload {
+        msg: format!("{} is not a valid {} code", code, system.label()),
+    })
+}
+
+fn fee_schedule(hospital_id: u64) -> FeeSchedule {
+    FEE_SCHEDULES
+        .with(|s| s.borrow().get(&hospital_id))
+        .unwrap_or(FeeSchedule {
+            hospital_id,
+            ..Default::default()
+        })
+}
+
+fn save_fee_schedule(mut schedule: FeeSchedule) -> FeeSchedule {
+    schedule.updated_at = time();
+    FEE_SCHEDULES.with(|s| {
+        s.borrow_mut()
+            .insert(schedule.hospital_id, schedule.clone())
+    });
+    schedule
+}
+
+fn fee_entry(
+    hospital_id: u64,
+    system: ProcedureCodeSystem,
+    code: &str,
+) -> Option<FeeScheduleEntry> {
+    fee_schedule(hospital_id)
+        .entries
+        .into_iter()
+        .find(|entry| entry.system == system && entry.code == code)
+}
+
+// add or reprice a code on the hospital's fee schedule
+#[ic_cdk::update]
+fn set_fee_schedule_entry(payload: FeeEntryPayload) -> Result<FeeSchedule, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let code = normalize_code(payload.entry.system, &payload.entry.code)?;
+    let mut schedule = fee_schedule(hospital.id);
+    schedule
+        .entries
+        .retain(|entry| !(entry.system == payload.entry.system && entry.code == code));
+    if schedule.entries.len() >= MAX_FEE_ENTRIES {
+        return Err(Error::LimitExceeded {
+            msg: format!("A fee schedule can price at most {} codes", MAX_FEE_ENTRIES),
+        });
+    }
+    schedule.entries.push(FeeScheduleEntry {
+        code,
+        ..payload.entry
+    });
+    schedule
+        .entries
+        .sort_by(|a, b| (a.system, &a.code).cmp(&(b.system, &b.code)));
+    Ok(save_fee_schedule(schedule))
+}
+
+#[ic_cdk::update]
+fn remove_fee_schedule_entry(payload: RemoveFeeEntryPayload) -> Result<FeeSchedule, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let code = normalize_code(payload.system, &payload.code)?;
+    let mut schedule = fee_schedule(hospital.id);
+    let before = schedule.entries.len();
+    schedule
+        .entries
+        .retain(|entry| !(entry.system == payload.system && entry.code == code));
+    if schedule.entries.len() == before {
+        return Err(Error::NotFound {
+            msg: format!("Code {} is not on the fee schedule", code),
+        });
+    }
+    Ok(save_fee_schedule(schedule))
+}
+
+// fee schedules are published so patients can see prices up front
+#[ic_cdk::query]
+fn get_fee_schedule(hospital_id: u64) -> FeeSchedule {
+    fee_schedule(hospital_id)
+}
+
+// code a procedure on an open encounter; a priced code also raises a charge entry on the
+// encounter so it is billed with the rest of the visit
+#[ic_cdk::update]
+fn add_procedure_code(payload: ProcedureCodePayload) -> Result<CodedProcedure, Error> {
+    let encounter = get_authorized_encounter(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.encounter_id,
+    )?;
+    if encounter.status != EncounterStatus::Open {
+        return Err(Error::InvalidPayload {
+            msg: format!("Encounter of id: {} is already closed", encounter.id),
+        });
+    }
+    let code = normalize_code(payload.system, &payload.code)?;
+    let fee = fee_entry(encounter.hospital_id, payload.system, &code);
+    let charge_entry_id = match &fee {
+        Some(fee) => Some(
+            record_encounter_entry(EncounterEntryPayload {
+                doctor_id: payload.doctor_id,
+                doctor_password: payload.doctor_password,
+                encounter_id: encounter.id,
+                kind: EncounterEntryKind::Charge {
+                    description: format!("{} {} {}", fee.system.label(), fee.code, fee.description),
+                    amount: fee.charge,
+                },
+            })?
+            .value
+            .id,
+        ),
+        None => None,
+    };
+    let coded = CodedProcedure {
+        id: next_id(),
+        encounter_id: encounter.id,
+        patient_id: encounter.patient_id,
+        hospital_id: encounter.hospital_id,
+        doctor_id: payload.doctor_id,
+        system: payload.system,
+        code,
+        charge: fee.map(|fee| fee.charge),
+        charge_entry_id,
+        coded_at: time(),
+    };
+    CODED_PROCEDURES.with(|s| s.borrow_mut().insert(coded.id, coded.clone()));
+    Ok(coded)
+}
+
+#[ic_cdk::query]
+fn get_encounter_procedure_codes(
+    payload: crate::EncounterAccessPayload,
+) -> Result<Vec<CodedProcedure>, Error> {
+    let encounter = get_authorized_encounter(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.encounter_id,
+    )?;
+    Ok(CODED_PROCEDURES.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, coded)| coded)
+            .filter(|coded| coded.encounter_id == encounter.id)
+            .collect()
+    }))
+}
+
+// how often each procedure was coded at the hospital in the period, busiest first
+#[ic_cdk::query]
+fn get_procedure_volume(payload: ProcedureVolumePayload) -> Result<Vec<ProcedureVolume>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let schedule = fee_schedule(hospital.id);
+    let mut volumes: BTreeMap<(ProcedureCodeSystem, String), ProcedureVolume> = BTreeMap::new();
+    CODED_PROCEDURES.with(|s| {
+        for (_, coded) in s.borrow().iter() {
+            if coded.hospital_id != hospital.id
+                || coded.coded_at < payload.from
+                || coded.coded_at >= payload.to
+            {
+                continue;
+            }
+            let volume = volumes
+                .entry((coded.system, coded.code.clone()))
+                .or_insert_with(|| ProcedureVolume {
+                    system: coded.system,
+                    code: coded.code.clone(),
+                    description: schedule
+                        .entries
+                        .iter()
+                        .find(|entry| entry.system == coded.system && entry.code == coded.code)
+                        .map(|entry| entry.description.clone())
+                        .unwrap_or_default(),
+                    count: 0,
+                    total_charges: 0,
+                });
+            volume.count += 1;
+            volume.total_charges += coded.charge.unwrap_or(0);
+        }
+    });
+    let mut volumes: Vec<ProcedureVolume> = volumes.into_values().collect();
+    volumes.sort_by_key(|volume| std::cmp::Reverse(volume.count));
+    Ok(volumes)
+}
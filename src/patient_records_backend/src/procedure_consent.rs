@@ -0,0 +1,292 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_patient, authorize_patient_access, caller,
+    get_assigned_patient, get_booking, impl_storable, issue_consent_receipt, next_id, to_hex,
+    Actor, BookingStatus, ConsentAction, Error, Memory, PatientAccess, MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// longest consent wording a form can carry
+const MAX_CONSENT_TEXT: usize = 8000;
+// risks and alternatives share the rest of the form's 16384 bytes with the text
+const MAX_CONSENT_RISKS: usize = 20;
+const MAX_CONSENT_RISK_BYTES: usize = 200;
+const MAX_CONSENT_ALTERNATIVES_BYTES: usize = 2000;
+
+// The patient's signature on a consent form, bound to the principal that signed it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ConsentSignature {
+    pub signer: Principal,
+    pub signed_at: u64,
+    // hash of the document as the patient was shown it
+    pub document_hash: String,
+}
+
+// A procedure consent document prepared by the doctor for a booked procedure
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ProcedureConsentForm {
+    pub id: u64,
+    pub booking_id: u64,
+    pub patient_id: u64,
+    pub doctor_id: u64,
+    pub procedure: String,
+    pub risks: Vec<String>,
+    pub alternatives: String,
+    pub text: String,
+    pub document_hash: String,
+    pub created_at: u64,
+    pub signature: Option<ConsentSignature>,
+    pub revoked_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ConsentFormPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub booking_id: u64,
+    pub risks: Vec<String>,
+    pub alternatives: String,
+    pub text: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SignConsentPayload {
+    pub patient_id: u64,
+    pub patient_password: String,
+    pub consent_id: u64,
+    pub document_hash: String,
+}
+
+impl_storable!(ProcedureConsentForm, 16384);
+
+thread_local! {
+    static PROCEDURE_CONSENT_STORAGE: RefCell<StableBTreeMap<u64, ProcedureConsentForm, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90)))
+    ));
+}
+
+// hash over everything the patient agrees to, so a later edit would not match the signature
+fn document_hash(form: &ProcedureConsentForm) -> String {
+    let mut hasher = Sha256::new();
+    for part in [
+        form.booking_id.to_string(),
+        form.patient_id.to_string(),
+        form.doctor_id.to_string(),
+        form.procedure.clone(),
+        form.risks.join("\n"),
+        form.alternatives.clone(),
+        form.text.clone(),
+        form.created_at.to_string(),
+    ] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part.as_bytes());
+    }
+    to_hex(&hasher.finalize())
+}
+
+fn get_consent_form(consent_id: u64) -> Result<ProcedureConsentForm, Error> {
+    PROCEDURE_CONSENT_STORAGE
+        .with(|s| s.borrow().get(&consent_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Consent form of id: {} not found", consent_id),
+        })
+}
+
+fn save_consent_form(form: &ProcedureConsentForm) {
+    PROCEDURE_CONSENT_STORAGE.with(|s| s.borrow_mut().insert(form.id, form.clone()));
+}
+
+fn is_valid(form: &ProcedureConsentForm) -> bool {
+    form.revoked_at.is_none()
+        && form.signature.as_ref().is_some_and(|signature| {
+            signature.document_hash == form.document_hash
+                && form.document_hash == document_hash(form)
+        })
+}
+
+// whether the booking has a signed, unrevoked consent whose document is unchanged
+pub(crate) fn has_valid_procedure_consent(booking_id: u64) -> bool {
+    PROCEDURE_CONSENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .any(|(_, form)| form.booking_id == booking_id && is_valid(&form))
+    })
+}
+
+// prepare the consent document for a booked procedure, for the patient to read and sign
+#[ic_cdk::update]
+fn create_procedure_consent(payload: ConsentFormPayload) -> Result<ProcedureConsentForm, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let booking = get_booking(payload.booking_id)?;
+    get_assigned_patient(&doctor, booking.patient_id)?;
+    if booking.status != BookingStatus::Scheduled {
+        return Err(Error::InvalidPayload {
+            msg: format!("Procedure booking of id: {} is not scheduled", booking.id),
+        });
+    }
+    if payload.text.trim().is_empty() || payload.text.len() > MAX_CONSENT_TEXT {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Consent text must be between 1 and {} characters",
+                MAX_CONSENT_TEXT
+            ),
+        });
+    }
+    if payload.risks.len() > MAX_CONSENT_RISKS
+        || payload
+            .risks
+            .iter()
+            .any(|risk| risk.len() > MAX_CONSENT_RISK_BYTES)
+        || payload.alternatives.len() > MAX_CONSENT_ALTERNATIVES_BYTES
+    {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "A consent form lists up to {} risks of {} bytes and {} bytes of alternatives",
+                MAX_CONSENT_RISKS, MAX_CONSENT_RISK_BYTES, MAX_CONSENT_ALTERNATIVES_BYTES
+            ),
+        });
+    }
+    let mut form = ProcedureConsentForm {
+        id: next_id(),
+        booking_id: booking.id,
+        patient_id: booking.patient_id,
+        doctor_id: doctor.id,
+        procedure: booking.procedure,
+        risks: payload.risks,
+        alternatives: payload.alternatives,
+        text: payload.text,
+        document_hash: String::new(),
+        created_at: time(),
+        signature: None,
+        revoked_at: None,
+    };
+    form.document_hash = document_hash(&form);
+    save_consent_form(&form);
+    Ok(form)
+}
+
+// the patient signs from their own identity, echoing the hash of the document they read
+#[ic_cdk::update]
+fn sign_procedure_consent(payload: SignConsentPayload) -> Result<ProcedureConsentForm, Error> {
+    let patient = authorize_patient(payload.patient_id, &payload.patient_password)?;
+    let signer = caller();
+    if signer == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Consent must be signed from an authenticated identity".to_string(),
+        });
+    }
+    let form = get_consent_form(payload.consent_id)?;
+    if form.patient_id != patient.id {
+        return Err(Error::Unauthorized {
+            msg: format!("Consent form of id: {} is for another patient", form.id),
+        });
+    }
+    if form.signature.is_some() || form.revoked_at.is_some() {
+        return Err(Error::AlreadyInit {
+            msg: format!(
+                "Consent form of id: {} is already signed or revoked",
+                form.id
+            ),
+        });
+    }
+    if payload.document_hash != form.document_hash {
+        return Err(Error::InvalidPayload {
+            msg: "Document hash does not match the consent form, reload it and sign again"
+                .to_string(),
+        });
+    }
+    let signed = ProcedureConsentForm {
+        signature: Some(ConsentSignature {
+            signer,
+            signed_at: time(),
+            document_hash: payload.document_hash,
+        }),
+        ..form
+    };
+    save_consent_form(&signed);
+    audit(
+        Actor::Patient(patient.id),
+        None,
+        Some(patient.id),
+        "procedure_consent_signed",
+        format!("consent {} for booking {}", signed.id, signed.booking_id),
+    );
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Granted,
+        "procedure",
+        signed.id,
+        format!("doctor {}", signed.doctor_id),
+        vec![signed.procedure.clone()],
+        None,
+    );
+    Ok(signed)
+}
+
+// withdraw consent any time before the procedure is performed
+#[ic_cdk::update]
+fn revoke_procedure_consent(
+    patient_id: u64,
+    patient_password: String,
+    consent_id: u64,
+) -> Result<ProcedureConsentForm, Error> {
+    let patient = authorize_patient(patient_id, &patient_password)?;
+    let form = get_consent_form(consent_id)?;
+    if form.patient_id != patient.id {
+        return Err(Error::Unauthorized {
+            msg: format!("Consent form of id: {} is for another patient", form.id),
+        });
+    }
+    if form.revoked_at.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: format!("Consent form of id: {} is already revoked", form.id),
+        });
+    }
+    if get_booking(form.booking_id)?.status == BookingStatus::Performed {
+        return Err(Error::InvalidPayload {
+            msg: "The procedure has already been performed".to_string(),
+        });
+    }
+    let revoked = ProcedureConsentForm {
+        revoked_at: Some(time()),
+        ..form
+    };
+    save_consent_form(&revoked);
+    audit(
+        Actor::Patient(patient.id),
+        None,
+        Some(patient.id),
+        "procedure_consent_revoked",
+        format!("consent {} for booking {}", revoked.id, revoked.booking_id),
+    );
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Revoked,
+        "procedure",
+        revoked.id,
+        format!("doctor {}", revoked.doctor_id),
+        vec![revoked.procedure.clone()],
+        None,
+    );
+    Ok(revoked)
+}
+
+#[ic_cdk::query]
+fn get_procedure_consents(
+    patient_id: u64,
+    access: PatientAccess,
+) -> Result<Vec<ProcedureConsentForm>, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    Ok(PROCEDURE_CONSENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, form)| form)
+            .filter(|form| form.patient_id == patient.id)
+            .collect()
+    }))
+}
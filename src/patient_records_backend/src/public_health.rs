@@ -0,0 +1,179 @@
+use crate::time;
+use crate::{
+    authorize_controller, caller, hospital_location, impl_storable, next_id,
+    problems_recorded_between, Error, Memory, DOCTOR_STORAGE, MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+const WEEK_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+// counts below this are withheld so small groups of patients cannot be singled out
+const SUPPRESSION_THRESHOLD: u64 = 5;
+// the widest window one query may cover
+const MAX_WEEKS: u64 = 52;
+
+// A public-health body allowed to read outbreak aggregates, and nothing else
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PublicHealthAgency {
+    pub id: u64,
+    pub principal: Principal,
+    pub name: String,
+    pub registered_at: u64,
+    pub active: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct OutbreakQuery {
+    // ICD-10 category or chapter prefix, e.g. "A09" or "J1"; empty for every code
+    pub icd_prefix: String,
+    // weeks since the unix epoch, inclusive
+    pub from_week: u64,
+    pub to_week: u64,
+    // empty for every city
+    pub city: String,
+}
+
+// Patients newly diagnosed with one ICD-10 category in one city and week
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct OutbreakCount {
+    pub icd_category: String,
+    pub city: String,
+    pub week: u64,
+    // None when the count is below the suppression threshold
+    pub cases: Option<u64>,
+}
+
+impl_storable!(PublicHealthAgency, 512);
+
+thread_local! {
+    static AGENCY_STORAGE: RefCell<StableBTreeMap<u64, PublicHealthAgency, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(98)))
+    ));
+}
+
+fn authorize_agency() -> Result<PublicHealthAgency, Error> {
+    let principal = caller();
+    AGENCY_STORAGE
+        .with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, agency)| agency)
+                .find(|agency| agency.active && agency.principal == principal)
+        })
+        .ok_or(Error::Unauthorized {
+            msg: "Caller is not a registered public-health agency".to_string(),
+        })
+}
+
+// city of the hospital the diagnosing doctor works at, empty when unknown
+fn diagnosis_city(doctor_id: u64) -> String {
+    DOCTOR_STORAGE
+        .with(|s| s.borrow().get(&doctor_id))
+        .map(|doctor| hospital_location(doctor.hospital_id).city)
+        .unwrap_or_default()
+}
+
+#[ic_cdk::update]
+fn register_public_health_agency(
+    principal: Principal,
+    name: String,
+) -> Result<PublicHealthAgency, Error> {
+    authorize_controller()?;
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "An agency needs a non-anonymous principal".to_string(),
+        });
+    }
+    let exists = AGENCY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .any(|(_, agency)| agency.active && agency.principal == principal)
+    });
+    if exists {
+        return Err(Error::AlreadyInit {
+            msg: format!("Principal {} is already registered", principal),
+        });
+    }
+    let agency = PublicHealthAgency {
+        id: next_id(),
+        principal,
+        name,
+        registered_at: time(),
+        active: true,
+    };
+    AGENCY_STORAGE.with(|s| s.borrow_mut().insert(agency.id, agency.clone()));
+    Ok(agency)
+}
+
+#[ic_cdk::update]
+fn revoke_public_health_agency(agency_id: u64) -> Result<PublicHealthAgency, Error> {
+    authorize_controller()?;
+    let agency = AGENCY_STORAGE
+        .with(|s| s.borrow().get(&agency_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Agency of id: {} not found", agency_id),
+        })?;
+    let revoked = PublicHealthAgency {
+        active: false,
+        ..agency
+    };
+    AGENCY_STORAGE.with(|s| s.borrow_mut().insert(revoked.id, revoked.clone()));
+    Ok(revoked)
+}
+
+#[ic_cdk::query]
+fn get_public_health_agencies() -> Result<Vec<PublicHealthAgency>, Error> {
+    authorize_controller()?;
+    Ok(AGENCY_STORAGE.with(|s| s.borrow().iter().map(|(_, agency)| agency).collect()))
+}
+
+// new diagnoses by ICD-10 category, city and week, counting each patient once per group;
+// the answer never carries patient-level data and small counts are suppressed
+#[ic_cdk::query]
+fn get_outbreak_counts(query: OutbreakQuery) -> Result<Vec<OutbreakCount>, Error> {
+    authorize_agency()?;
+    if query.to_week < query.from_week || query.to_week - query.from_week >= MAX_WEEKS {
+        return Err(Error::InvalidPayload {
+            msg: format!("A query covers between 1 and {} weeks", MAX_WEEKS),
+        });
+    }
+    let prefix = query.icd_prefix.trim().to_uppercase();
+    let city = query.city.trim().to_lowercase();
+    let mut cities: BTreeMap<u64, String> = BTreeMap::new();
+    let mut patients: BTreeMap<(String, String, u64), BTreeSet<u64>> = BTreeMap::new();
+    for problem in
+        problems_recorded_between(query.from_week * WEEK_NS, (query.to_week + 1) * WEEK_NS)
+    {
+        if !problem.icd_code.starts_with(&prefix) {
+            continue;
+        }
+        let problem_city = cities
+            .entry(problem.recorded_by)
+            .or_insert_with(|| diagnosis_city(problem.recorded_by))
+            .clone();
+        if !city.is_empty() && problem_city.to_lowercase() != city {
+            continue;
+        }
+        let category: String = problem.icd_code.chars().take(3).collect();
+        patients
+            .entry((category, problem_city, problem.recorded_at / WEEK_NS))
+            .or_default()
+            .insert(problem.patient_id);
+    }
+    Ok(patients
+        .into_iter()
+        .map(|((icd_category, city, week), patients)| {
+            let cases = patients.len() as u64;
+            OutbreakCount {
+                icd_category,
+                city,
+                week,
+                cases: (cases >= SUPPRESSION_THRESHOLD).then_some(cases),
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,576 @@
+use crate::time;
+use crate::{
+    audit, authenticate_patient, authorize_controller, authorize_doctor, backfill_record_bytes,
+    check_limit, check_record_bytes_quota, classify_record, get_assigned_patient, impl_storable,
+    index_record, is_record_signed, is_restricted, limits, next_id, record_sensitivity,
+    records_for_doctor, reencode_page, remember_change, track_record_bytes, unindex_record, Actor,
+    ChangeRef, Doctor, Error, Memory, Patient, PreviousValue, Sensitivity, MEMORY_MANAGER,
+    PATIENT_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum RecordKind {
+    Note,
+    Diagnosis,
+    LabResult,
+    Imaging,
+    Procedure,
+    // free-text history carried over from Patient.history
+    Legacy,
+    // an immutable history entry, changed only through amendments
+    History,
+    DeathCertificate,
+}
+
+// One structured entry of a patient's medical record
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MedicalRecord {
+    pub id: u64,
+    pub patient_id: u64,
+    pub doctor_id: Option<u64>,
+    pub hospital_id: Option<u64>,
+    pub kind: RecordKind,
+    pub title: String,
+    pub body: String,
+    pub created_at: u64,
+    pub migrated: bool,
+    // set when the record came back from the archive, restarting its retention window
+    pub restored_at: Option<u64>,
+    // the record this one adds to, used to amend signed records
+    pub addendum_to: Option<u64>,
+}
+
+impl_storable!(MedicalRecord, 16384, compressed);
+
+thread_local! {
+    static RECORD_STORAGE: RefCell<StableBTreeMap<u64, MedicalRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MedicalRecordPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub kind: RecordKind,
+    pub title: String,
+    pub body: String,
+    // None for a normal record
+    pub sensitivity: Option<Sensitivity>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum MigrationStatus {
+    Migrated { record_id: u64, doctor_entries: u64 },
+    Skipped { reason: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MigrationResult {
+    pub patient_id: u64,
+    pub status: MigrationStatus,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct EditRecordPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub record_id: u64,
+    pub title: String,
+    pub body: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AddendumPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub record_id: u64,
+    pub body: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct HistoryAmendmentPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub entry_id: u64,
+    // true when the amendment corrects an error rather than adding information
+    pub correction: bool,
+    pub text: String,
+}
+
+// A history entry together with the amendments filed against it, oldest first
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub entry: MedicalRecord,
+    pub amendments: Vec<MedicalRecord>,
+}
+
+pub(crate) fn get_record(record_id: u64) -> Result<MedicalRecord, Error> {
+    RECORD_STORAGE
+        .with(|s| s.borrow().get(&record_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Medical record of id: {} not found", record_id),
+        })
+}
+
+// helper function to check a record body against the size cap
+fn check_record_body(body: &str) -> Result<(), Error> {
+    let max = limits().max_record_body_bytes;
+    if body.len() as u64 > max {
+        return Err(Error::LimitExceeded {
+            msg: format!("Record body exceeds {} bytes", max),
+        });
+    }
+    Ok(())
+}
+
+// helper function to check a new record against the size and count caps
+fn check_new_record(patient_id: u64, hospital_id: u64, body: &str) -> Result<(), Error> {
+    check_record_body(body)?;
+    check_record_bytes_quota(hospital_id, body.len() as u64)?;
+    check_limit(
+        "records per patient",
+        all_patient_records(patient_id).len() as u64,
+        limits().max_records_per_patient,
+    )
+}
+
+// every record write goes through here so the search index stays in step
+pub(crate) fn insert_record(record: &MedicalRecord) {
+    let previous = RECORD_STORAGE.with(|s| s.borrow_mut().insert(record.id, record.clone()));
+    track_record_bytes(previous.as_ref(), Some(record));
+    if let Some(previous) = previous {
+        unindex_record(&previous);
+    }
+    index_record(record);
+}
+
+// start the per-hospital storage usage from the records already stored
+pub(crate) fn backfill_record_usage() {
+    RECORD_STORAGE.with(|s| backfill_record_bytes(s.borrow().iter().map(|(_, record)| record)));
+}
+
+// rewrite a batch of records in the current storage format
+pub(crate) fn reencode_records(after: Option<u64>, limit: usize) -> Option<(u64, u64)> {
+    RECORD_STORAGE.with(|s| reencode_page(&mut s.borrow_mut(), after, limit))
+}
+
+pub(crate) fn remove_record(record_id: u64) -> Option<MedicalRecord> {
+    let removed = RECORD_STORAGE.with(|s| s.borrow_mut().remove(&record_id));
+    if let Some(record) = &removed {
+        track_record_bytes(Some(record), None);
+        unindex_record(record);
+    }
+    removed
+}
+
+pub(crate) fn records_after(start_after: u64, limit: usize) -> Vec<MedicalRecord> {
+    RECORD_STORAGE.with(|s| {
+        s.borrow()
+            .range((Bound::Excluded(start_after), Bound::Unbounded))
+            .take(limit)
+            .map(|(_, record)| record)
+            .collect()
+    })
+}
+
+// records whose retention clock started before the cutoff, at most limit of them
+pub(crate) fn records_older_than(
+    cutoff: impl Fn(RecordKind) -> Option<u64>,
+    limit: usize,
+) -> Vec<MedicalRecord> {
+    RECORD_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, record)| record)
+            .filter(|record| match cutoff(record.kind) {
+                Some(cutoff) => record.restored_at.unwrap_or(record.created_at) < cutoff,
+                None => false,
+            })
+            .take(limit)
+            .collect()
+    })
+}
+
+pub(crate) fn record_count() -> u64 {
+    RECORD_STORAGE.with(|s| s.borrow().len())
+}
+
+// all records of a patient, restricted ones included, oldest first
+pub(crate) fn all_patient_records(patient_id: u64) -> Vec<MedicalRecord> {
+    RECORD_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, record)| record)
+            .filter(|record| record.patient_id == patient_id)
+            .collect()
+    })
+}
+
+// the records of a patient that general views like the chart and exports may show, oldest
+// first; restricted records are left out
+pub(crate) fn patient_records(patient_id: u64) -> Vec<MedicalRecord> {
+    all_patient_records(patient_id)
+        .into_iter()
+        .filter(|record| !is_restricted(record.id))
+        .collect()
+}
+
+// count the "Doctor <id> : <name> at <time>" entries appended by update_patient_history
+fn count_doctor_entries(history: &str) -> u64 {
+    history
+        .lines()
+        .filter(|line| {
+            let line = line.trim();
+            line.starts_with("Doctor ") && line.contains(" : ") && line.contains(" at ")
+        })
+        .count() as u64
+}
+
+// add a structured record to the patient's chart
+#[ic_cdk::update]
+pub(crate) fn add_medical_record(payload: MedicalRecordPayload) -> Result<MedicalRecord, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    if payload.title.trim().is_empty()
+        || payload.kind == RecordKind::Legacy
+        || payload.kind == RecordKind::History
+    {
+        return Err(Error::InvalidPayload {
+            msg: "Medical record needs a title and cannot be of kind Legacy or History".to_string(),
+        });
+    }
+    check_new_record(patient.id, doctor.hospital_id, &payload.body)?;
+    let record = MedicalRecord {
+        id: next_id(),
+        patient_id: patient.id,
+        doctor_id: Some(doctor.id),
+        hospital_id: Some(doctor.hospital_id),
+        kind: payload.kind,
+        title: payload.title,
+        body: payload.body,
+        created_at: time(),
+        migrated: false,
+        restored_at: None,
+        addendum_to: None,
+    };
+    insert_record(&record);
+    if let Some(sensitivity) = payload.sensitivity {
+        classify_record(&record, sensitivity, doctor.id);
+    }
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "record_created",
+        format!("record {}", record.id),
+    );
+    Ok(record)
+}
+
+// correct a record before it is signed, only its author may do so
+#[ic_cdk::update]
+pub(crate) fn edit_medical_record(payload: EditRecordPayload) -> Result<MedicalRecord, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let record = get_record(payload.record_id)?;
+    if record.doctor_id != Some(doctor.id) {
+        return Err(Error::Unauthorized {
+            msg: format!("Medical record of id: {} has another author", record.id),
+        });
+    }
+    // the author must still be caring for a patient who is not sealed
+    get_assigned_patient(&doctor, record.patient_id)?;
+    if is_record_signed(record.id)
+        || matches!(
+            record.kind,
+            RecordKind::History | RecordKind::DeathCertificate
+        )
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Medical record of id: {} is immutable, add an addendum instead",
+                record.id
+            ),
+        });
+    }
+    check_record_body(&payload.body)?;
+    if let Some(hospital_id) = record.hospital_id {
+        check_record_bytes_quota(
+            hospital_id,
+            (payload.body.len() as u64).saturating_sub(record.body.len() as u64),
+        )?;
+    }
+    remember_change(
+        ChangeRef::MedicalRecord(record.id),
+        "record_edited",
+        PreviousValue::RecordContent {
+            title: record.title.clone(),
+            body: record.body.clone(),
+        },
+        Actor::Doctor(doctor.id),
+        record.hospital_id.into_iter().collect(),
+    );
+    let edited = MedicalRecord {
+        title: payload.title,
+        body: payload.body,
+        ..record
+    };
+    insert_record(&edited);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(edited.patient_id),
+        "record_edited",
+        format!("record {}", edited.id),
+    );
+    Ok(edited)
+}
+
+// append a note to an existing record, the way signed records are amended
+#[ic_cdk::update]
+fn add_record_addendum(payload: AddendumPayload) -> Result<MedicalRecord, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let original = get_record(payload.record_id)?;
+    let patient = get_assigned_patient(&doctor, original.patient_id)?;
+    check_new_record(patient.id, doctor.hospital_id, &payload.body)?;
+    let addendum = MedicalRecord {
+        id: next_id(),
+        patient_id: patient.id,
+        doctor_id: Some(doctor.id),
+        hospital_id: Some(doctor.hospital_id),
+        kind: RecordKind::Note,
+        title: format!("Addendum to {}", original.title),
+        body: payload.body,
+        created_at: time(),
+        migrated: false,
+        restored_at: None,
+        addendum_to: Some(original.id),
+    };
+    insert_record(&addendum);
+    // an addendum is as sensitive as what it amends
+    classify_record(&addendum, record_sensitivity(original.id), doctor.id);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "record_addendum",
+        format!("record {} amends {}", addendum.id, original.id),
+    );
+    Ok(addendum)
+}
+
+// a patient's history entries, each with its amendments
+pub(crate) fn patient_history(patient_id: u64) -> Vec<HistoryEntry> {
+    let history: Vec<MedicalRecord> = patient_records(patient_id)
+        .into_iter()
+        .filter(|record| record.kind == RecordKind::History)
+        .collect();
+    history
+        .iter()
+        .filter(|record| record.addendum_to.is_none())
+        .map(|entry| HistoryEntry {
+            entry: entry.clone(),
+            amendments: history
+                .iter()
+                .filter(|record| record.addendum_to == Some(entry.id))
+                .cloned()
+                .collect(),
+        })
+        .collect()
+}
+
+// the history entries as text for endpoints that return it in Patient.history, amendments
+// indented under their entry
+pub(crate) fn render_history(history: &[HistoryEntry]) -> String {
+    let mut text = String::new();
+    for HistoryEntry { entry, amendments } in history {
+        text.push_str(&format!(
+            "{} at {}: {}\n",
+            entry.title, entry.created_at, entry.body
+        ));
+        for amendment in amendments {
+            text.push_str(&format!(
+                "  {} at {}: {}\n",
+                amendment.title, amendment.created_at, amendment.body
+            ));
+        }
+    }
+    text
+}
+
+// file a new immutable history entry attributed to the doctor
+pub(crate) fn add_history_entry(
+    doctor: &Doctor,
+    patient: &Patient,
+    text: String,
+) -> Result<MedicalRecord, Error> {
+    if text.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "History entry cannot be empty".to_string(),
+        });
+    }
+    check_new_record(patient.id, doctor.hospital_id, &text)?;
+    let entry = MedicalRecord {
+        id: next_id(),
+        patient_id: patient.id,
+        doctor_id: Some(doctor.id),
+        hospital_id: Some(doctor.hospital_id),
+        kind: RecordKind::History,
+        title: format!("History entry by {}", doctor.name),
+        body: text,
+        created_at: time(),
+        migrated: false,
+        restored_at: None,
+        addendum_to: None,
+    };
+    insert_record(&entry);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "history_entry_added",
+        format!("record {}", entry.id),
+    );
+    Ok(entry)
+}
+
+// file a dated, attributed amendment or correction against an immutable history entry
+#[ic_cdk::update]
+fn amend_patient_history(payload: HistoryAmendmentPayload) -> Result<MedicalRecord, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let entry = get_record(payload.entry_id)?;
+    let patient = get_assigned_patient(&doctor, entry.patient_id)?;
+    if entry.kind != RecordKind::History || entry.addendum_to.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Record of id: {} is not an original history entry",
+                entry.id
+            ),
+        });
+    }
+    if payload.text.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Amendment text cannot be empty".to_string(),
+        });
+    }
+    check_new_record(patient.id, doctor.hospital_id, &payload.text)?;
+    let label = if payload.correction {
+        "Correction"
+    } else {
+        "Amendment"
+    };
+    let amendment = MedicalRecord {
+        id: next_id(),
+        patient_id: patient.id,
+        doctor_id: Some(doctor.id),
+        hospital_id: Some(doctor.hospital_id),
+        kind: RecordKind::History,
+        title: format!("{} to entry {}", label, entry.id),
+        body: payload.text,
+        created_at: time(),
+        migrated: false,
+        restored_at: None,
+        addendum_to: Some(entry.id),
+    };
+    insert_record(&amendment);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "history_amended",
+        format!("record {} amends {}", amendment.id, entry.id),
+    );
+    Ok(amendment)
+}
+
+// the patient's history entries with their amendments, for a doctor assigned to them
+#[ic_cdk::query]
+fn get_patient_history(payload: crate::AccessPayload) -> Result<Vec<HistoryEntry>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    Ok(patient_history(patient.id))
+}
+
+// records of a patient for a doctor assigned to them, restricted ones only in the categories
+// the patient authorized; an update so reads of sensitive records are audited
+#[ic_cdk::update]
+fn get_patient_records(payload: crate::AccessPayload) -> Result<Vec<MedicalRecord>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    Ok(records_for_doctor(patient.id, doctor.id))
+}
+
+// a patient reads all of their own records
+#[ic_cdk::query]
+fn get_my_records(consent: crate::PatientConsent) -> Result<Vec<MedicalRecord>, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    Ok(all_patient_records(patient.id))
+}
+
+// one-shot admin migration of Patient.history into legacy records, in batches of patient ids
+#[ic_cdk::update]
+fn migrate_patient_histories(start_after: u64, limit: u64) -> Result<Vec<MigrationResult>, Error> {
+    authorize_controller()?;
+    let patients: Vec<(u64, String)> = PATIENT_STORAGE.with(|s| {
+        s.borrow()
+            .range((Bound::Excluded(start_after), Bound::Unbounded))
+            .take(limit as usize)
+            .map(|(id, patient)| (id, patient.history))
+            .collect()
+    });
+
+    let mut results = vec![];
+    for (patient_id, history) in patients {
+        let already_migrated = all_patient_records(patient_id)
+            .iter()
+            .find(|record| record.migrated)
+            .map(|record| record.id);
+        let status = match already_migrated {
+            Some(record_id) => MigrationStatus::Skipped {
+                reason: format!("already migrated as record {}", record_id),
+            },
+            None if history.trim().is_empty() => MigrationStatus::Skipped {
+                reason: "history is empty".to_string(),
+            },
+            None => {
+                let record = MedicalRecord {
+                    id: next_id(),
+                    patient_id,
+                    doctor_id: None,
+                    hospital_id: None,
+                    kind: RecordKind::Legacy,
+                    title: "Migrated history".to_string(),
+                    body: history.clone(),
+                    created_at: time(),
+                    migrated: true,
+                    restored_at: None,
+                    addendum_to: None,
+                };
+                insert_record(&record);
+                MigrationStatus::Migrated {
+                    record_id: record.id,
+                    doctor_entries: count_doctor_entries(&history),
+                }
+            }
+        };
+        results.push(MigrationResult { patient_id, status });
+    }
+    audit(
+        Actor::System,
+        None,
+        None,
+        "history_migration",
+        format!(
+            "{} patients processed after id {}",
+            results.len(),
+            start_after
+        ),
+    );
+    Ok(results)
+}
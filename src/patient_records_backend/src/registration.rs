@@ -0,0 +1,348 @@
+use crate::time;
+use crate::{
+    add_patient, add_patient_to_hospital, audit, authenticate_patient, authorize_hospital, caller,
+    check_limit, impl_storable, link_account_role, next_id, patient_header, patient_quota,
+    save_patient, AccountRole, Actor, Error, HospitalAccessPayload, Memory, PatientConsent,
+    PatientPayload, HOSPITAL_STORAGE, MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use validator::Validate;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum AffiliationStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+// Details of an account that only gets created once a hospital approves it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub struct NewPatientAccount {
+    #[validate(length(min = 3))]
+    pub name: String,
+    #[validate(length(min = 6))]
+    pub history: String,
+    pub password: String,
+    pub language: Option<String>,
+}
+
+// A patient asking to join a hospital, either a new self-registered account or an
+// existing patient. Staff approve it instead of entering the patient's password themselves
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AffiliationRequest {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub principal: Principal,
+    // set for self-registrations until the account is created
+    pub new_account: Option<NewPatientAccount>,
+    pub patient_id: Option<u64>,
+    pub status: AffiliationStatus,
+    // medical record number the hospital assigned on approval
+    pub mrn: Option<String>,
+    pub requested_at: u64,
+    pub decided_at: Option<u64>,
+}
+
+// The view of a request staff get to see, without the chosen password
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AffiliationRequestView {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub principal: Principal,
+    pub name: String,
+    pub patient_id: Option<u64>,
+    pub status: AffiliationStatus,
+    pub mrn: Option<String>,
+    pub requested_at: u64,
+    pub decided_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct MedicalRecordNumber {
+    value: String,
+}
+
+impl_storable!(AffiliationRequest, 2048);
+impl_storable!(MedicalRecordNumber, 64);
+
+thread_local! {
+    static AFFILIATION_STORAGE: RefCell<StableBTreeMap<u64, AffiliationRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+
+    // (hospital id, patient id) -> the patient's medical record number at that hospital
+    static MRN_STORAGE: RefCell<StableBTreeMap<(u64, u64), MedicalRecordNumber, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SelfRegistrationPayload {
+    pub hospital_id: u64,
+    pub account: NewPatientAccount,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AffiliationDecisionPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub request_id: u64,
+    pub approve: bool,
+}
+
+fn save_request(request: &AffiliationRequest) {
+    AFFILIATION_STORAGE.with(|s| s.borrow_mut().insert(request.id, request.clone()));
+}
+
+fn requests(keep: impl Fn(&AffiliationRequest) -> bool) -> Vec<AffiliationRequest> {
+    AFFILIATION_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| keep(request))
+            .collect()
+    })
+}
+
+fn request_view(request: AffiliationRequest) -> AffiliationRequestView {
+    let name = match (&request.new_account, request.patient_id) {
+        (Some(account), _) => account.name.clone(),
+        (None, Some(patient_id)) => patient_header(patient_id)
+            .map(|patient| patient.name)
+            .unwrap_or_default(),
+        (None, None) => String::new(),
+    };
+    AffiliationRequestView {
+        id: request.id,
+        hospital_id: request.hospital_id,
+        principal: request.principal,
+        name,
+        patient_id: request.patient_id,
+        status: request.status,
+        mrn: request.mrn,
+        requested_at: request.requested_at,
+        decided_at: request.decided_at,
+    }
+}
+
+fn caller_principal() -> Result<Principal, Error> {
+    let caller = caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Sign in with an identity to register".to_string(),
+        });
+    }
+    Ok(caller)
+}
+
+fn check_hospital(hospital_id: u64) -> Result<(), Error> {
+    if !HOSPITAL_STORAGE.with(|s| s.borrow().contains_key(&hospital_id)) {
+        return Err(Error::NotFound {
+            msg: format!("Hospital of id: {} not found", hospital_id),
+        });
+    }
+    Ok(())
+}
+
+// the patient's medical record number at a hospital, assigned in order of approval
+pub(crate) fn assign_mrn(hospital_id: u64, patient_id: u64) -> String {
+    MRN_STORAGE.with(|s| {
+        let mut mrns = s.borrow_mut();
+        if let Some(mrn) = mrns.get(&(hospital_id, patient_id)) {
+            return mrn.value;
+        }
+        let issued = mrns
+            .range((hospital_id, 0)..=(hospital_id, u64::MAX))
+            .count();
+        let value = format!("H{}-{:06}", hospital_id, issued + 1);
+        mrns.insert(
+            (hospital_id, patient_id),
+            MedicalRecordNumber {
+                value: value.clone(),
+            },
+        );
+        value
+    })
+}
+
+// make an existing patient a patient of the hospital and return their medical record number
+pub(crate) fn admit_patient(hospital_id: u64, patient_id: u64) -> Result<String, Error> {
+    add_patient_to_hospital(hospital_id, patient_id)?;
+    if let Some(mut patient) = PATIENT_STORAGE.with(|s| s.borrow().get(&patient_id)) {
+        if !patient.hospitals_ids.contains(&hospital_id) {
+            patient.hospitals_ids.push(hospital_id);
+            save_patient(&patient);
+        }
+    }
+    Ok(assign_mrn(hospital_id, patient_id))
+}
+
+// create a pending account bound to the caller's principal and ask a hospital to accept it
+#[ic_cdk::update]
+fn register_patient(payload: SelfRegistrationPayload) -> Result<AffiliationRequestView, Error> {
+    let principal = caller_principal()?;
+    check_hospital(payload.hospital_id)?;
+    if let Err(errors) = payload.account.validate() {
+        return Err(Error::InvalidPayload {
+            msg: errors.to_string(),
+        });
+    }
+    if !requests(|request| {
+        request.principal == principal && request.status != AffiliationStatus::Rejected
+    })
+    .is_empty()
+    {
+        return Err(Error::AlreadyInit {
+            msg: "This identity already registered".to_string(),
+        });
+    }
+    let request = AffiliationRequest {
+        id: next_id(),
+        hospital_id: payload.hospital_id,
+        principal,
+        new_account: Some(payload.account),
+        patient_id: None,
+        status: AffiliationStatus::Pending,
+        mrn: None,
+        requested_at: time(),
+        decided_at: None,
+    };
+    save_request(&request);
+    Ok(request_view(request))
+}
+
+// an existing patient asks to become a patient of another hospital
+#[ic_cdk::update]
+fn request_hospital_affiliation(
+    consent: PatientConsent,
+    hospital_id: u64,
+) -> Result<AffiliationRequestView, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    check_hospital(hospital_id)?;
+    if !requests(|request| {
+        request.patient_id == Some(patient.id)
+            && request.hospital_id == hospital_id
+            && request.status == AffiliationStatus::Pending
+    })
+    .is_empty()
+    {
+        return Err(Error::AlreadyInit {
+            msg: "A request to this hospital is already pending".to_string(),
+        });
+    }
+    let request = AffiliationRequest {
+        id: next_id(),
+        hospital_id,
+        principal: caller(),
+        new_account: None,
+        patient_id: Some(patient.id),
+        status: AffiliationStatus::Pending,
+        mrn: None,
+        requested_at: time(),
+        decided_at: None,
+    };
+    save_request(&request);
+    Ok(request_view(request))
+}
+
+// the caller's own self-registrations and their status
+#[ic_cdk::query]
+fn get_my_registrations() -> Vec<AffiliationRequestView> {
+    let caller = caller();
+    requests(|request| request.principal == caller)
+        .into_iter()
+        .map(request_view)
+        .collect()
+}
+
+#[ic_cdk::query]
+fn get_affiliation_requests(
+    payload: HospitalAccessPayload,
+) -> Result<Vec<AffiliationRequestView>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    Ok(requests(|request| {
+        request.hospital_id == hospital.id && request.status == AffiliationStatus::Pending
+    })
+    .into_iter()
+    .map(request_view)
+    .collect())
+}
+
+// hospital staff accept or turn down a request. accepting creates the account if needed,
+// adds the patient to the hospital and assigns their medical record number
+#[ic_cdk::update]
+fn decide_affiliation_request(
+    payload: AffiliationDecisionPayload,
+) -> Result<AffiliationRequestView, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let mut request =
+        requests(|request| request.id == payload.request_id && request.hospital_id == hospital.id)
+            .pop()
+            .ok_or(Error::NotFound {
+                msg: format!(
+                    "Affiliation request of id: {} not found",
+                    payload.request_id
+                ),
+            })?;
+    if request.status != AffiliationStatus::Pending {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Affiliation request of id: {} was already decided",
+                request.id
+            ),
+        });
+    }
+
+    request.decided_at = Some(time());
+    if !payload.approve {
+        request.status = AffiliationStatus::Rejected;
+        request.new_account = None;
+        save_request(&request);
+        return Ok(request_view(request));
+    }
+
+    // check the cap before creating an account that could not join the hospital
+    if request.patient_id.is_none() {
+        check_limit(
+            "patients per hospital",
+            hospital.patients_ids.len() as u64,
+            patient_quota(hospital.id),
+        )?;
+    }
+    let patient_id = match (request.patient_id, request.new_account.take()) {
+        (Some(patient_id), _) => patient_id,
+        (None, Some(account)) => {
+            add_patient(PatientPayload {
+                name: account.name,
+                history: account.history,
+                password: account.password,
+                language: account.language,
+            })?
+            .id
+        }
+        (None, None) => {
+            return Err(Error::InvalidPayload {
+                msg: format!("Affiliation request of id: {} has no patient", request.id),
+            })
+        }
+    };
+    request.mrn = Some(admit_patient(hospital.id, patient_id)?);
+    request.patient_id = Some(patient_id);
+    // a full account only loses the principal sign-in, the password still works
+    link_account_role(request.principal, AccountRole::Patient(patient_id)).ok();
+    request.status = AffiliationStatus::Approved;
+    save_request(&request);
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        Some(patient_id),
+        "affiliation_approved",
+        format!("request {}", request.id),
+    );
+    Ok(request_view(request))
+}
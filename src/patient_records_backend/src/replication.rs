@@ -0,0 +1,723 @@
+use crate::time;
+use crate::{authorize_controller, caller, impl_storable, Error, MEMORY_MANAGER};
+use candid::Principal;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{Cell, DefaultMemoryImpl, Memory as _, StableBTreeMap};
+use std::cell::RefCell;
+
+// the replication stores live outside the journal, they differ between primary and standby
+const JOURNAL_MEMORY_ID: u8 = 85;
+const STATE_MEMORY_ID: u8 = 86;
+// memories holding state of this canister only, neither journaled nor copied
+pub(crate) const LOCAL_MEMORY_IDS: [u8; 4] = [JOURNAL_MEMORY_ID, STATE_MEMORY_ID, 87, 88];
+// writes are split so every journal event fits its store
+const MAX_EVENT_BYTES: usize = 64 * 1024;
+// stay well below the 2MB inter-canister message limit
+const MAX_BATCH_BYTES: usize = 1536 * 1024;
+// past either limit the standby is better served by a fresh snapshot than by replaying, and
+// the journal stops recording until that copy starts
+const MAX_PENDING_EVENTS: u64 = 500_000;
+const MAX_PENDING_BYTES: u64 = 1024 * 1024 * 1024;
+// lag above which the standby is reported as lagging
+const MAX_HEALTHY_LAG_SECONDS: u64 = 5 * 60;
+// errors are cut to this length so the state always fits its cell
+const MAX_ERROR_BYTES: usize = 256;
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+const SECOND_NS: u64 = 1_000_000_000;
+
+pub(crate) type RawMemory = VirtualMemory<DefaultMemoryImpl>;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ReplicationRole {
+    Primary,
+    // a read-only replica that only accepts batches from its primary
+    Standby { primary: Principal },
+    // promoted, but still refusing writes until the upgrade that follows reloads the stores
+    PromotionPending,
+}
+
+// One change to a store's memory, replayed in order on the standby
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum MemoryChange {
+    // grown to this many pages
+    Grow { size_pages: u64 },
+    Write { offset: u64, bytes: Vec<u8> },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct JournalEvent {
+    pub memory_id: u8,
+    pub change: MemoryChange,
+    pub at: u64,
+}
+
+// Where the initial copy of the stores has got to
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub struct SnapshotCursor {
+    pub memory_id: u8,
+    pub offset: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ReplicationState {
+    pub role: ReplicationRole,
+    pub standby: Option<Principal>,
+    // sequence number the next journal event gets
+    pub next_seq: u64,
+    // events before this one are on the standby; on a standby, the next one it expects
+    pub acked_seq: u64,
+    pub snapshot: Option<SnapshotCursor>,
+    pub last_success_at: Option<u64>,
+    pub last_error: Option<String>,
+    pub promoted_at: Option<u64>,
+    // size of the journal events the standby does not have yet
+    #[serde(default)]
+    pub pending_bytes: u64,
+    // set when the journal reached its limits and dropped changes, the next round copies the
+    // stores afresh
+    #[serde(default)]
+    pub journal_overflow: bool,
+}
+
+impl Default for ReplicationState {
+    fn default() -> Self {
+        ReplicationState {
+            role: ReplicationRole::Primary,
+            standby: None,
+            next_seq: 0,
+            acked_seq: 0,
+            snapshot: None,
+            last_success_at: None,
+            last_error: None,
+            promoted_at: None,
+            pending_bytes: 0,
+            journal_overflow: false,
+        }
+    }
+}
+
+// What the primary sends the standby on each round
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum ReplicationBatch {
+    Snapshot {
+        memory_id: u8,
+        size_pages: u64,
+        offset: u64,
+        bytes: Vec<u8>,
+        // the journal event the standby continues from once the snapshot is done
+        resume_seq: u64,
+    },
+    Journal {
+        first_seq: u64,
+        events: Vec<JournalEvent>,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ReplicationStatus {
+    pub role: ReplicationRole,
+    pub standby: Option<Principal>,
+    pub snapshot: Option<SnapshotCursor>,
+    pub pending_events: u64,
+    pub pending_bytes: u64,
+    pub oldest_pending_at: Option<u64>,
+    // how far behind the standby is, zero when it has everything
+    pub lag_seconds: u64,
+    pub lagging: bool,
+    pub last_success_at: Option<u64>,
+    pub last_error: Option<String>,
+    pub promoted_at: Option<u64>,
+}
+
+impl_storable!(JournalEvent, 66 * 1024);
+impl_storable!(ReplicationState, 1024);
+
+thread_local! {
+    static JOURNAL: RefCell<StableBTreeMap<u64, JournalEvent, RawMemory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().raw(MemoryId::new(JOURNAL_MEMORY_ID)))
+    ));
+
+    static REPLICATION_STATE: RefCell<Cell<ReplicationState, RawMemory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().raw(MemoryId::new(STATE_MEMORY_ID))),
+            ReplicationState::default(),
+        )
+        .expect("Cannot create replication state")
+    );
+
+    // the state as last set, so store writes do not decode the cell; filled on first use
+    static STATE_CACHE: RefCell<Option<ReplicationState>> = const { RefCell::new(None) };
+
+    // set while a round is in flight so timer ticks do not overlap, cleared by RoundGuard
+    static REPLICATING: RefCell<bool> = const { RefCell::new(false) };
+
+    // changes to the stores since this code was installed, so digests taken over several calls
+    // can tell whether traffic moved the stores under them
+    static STORE_WRITES: RefCell<u64> = const { RefCell::new(0) };
+}
+
+pub(crate) fn store_writes() -> u64 {
+    STORE_WRITES.with(|w| *w.borrow())
+}
+
+fn count_store_write() {
+    STORE_WRITES.with(|w| *w.borrow_mut() += 1);
+}
+
+// Memory manager handing out memories whose changes are journaled for the standby
+pub struct ReplicatedMemoryManager {
+    inner: MemoryManager<DefaultMemoryImpl>,
+}
+
+impl ReplicatedMemoryManager {
+    pub fn init(memory: DefaultMemoryImpl) -> Self {
+        ReplicatedMemoryManager {
+            inner: MemoryManager::init(memory),
+        }
+    }
+
+    pub fn get(&self, id: MemoryId) -> JournaledMemory {
+        // MemoryId does not expose its number, which the journal needs
+        JournaledMemory {
+            memory_id: (0..u8::MAX)
+                .find(|n| MemoryId::new(*n) == id)
+                .unwrap_or_default(),
+            inner: self.inner.get(id),
+        }
+    }
+
+    // the memory without journaling, for replication itself
+    pub(crate) fn raw(&self, id: MemoryId) -> RawMemory {
+        self.inner.get(id)
+    }
+}
+
+// The memory every store lives in. On a primary with a standby each change is journaled,
+// on a standby local changes are refused so the replica stays identical to its primary
+#[derive(Clone)]
+pub struct JournaledMemory {
+    memory_id: u8,
+    inner: RawMemory,
+}
+
+impl ic_stable_structures::Memory for JournaledMemory {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        guard_standby();
+        count_store_write();
+        let previous = self.inner.grow(pages);
+        if previous >= 0 {
+            journal(
+                self.memory_id,
+                MemoryChange::Grow {
+                    size_pages: previous as u64 + pages,
+                },
+            );
+        }
+        previous
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        self.inner.read(offset, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        guard_standby();
+        count_store_write();
+        self.inner.write(offset, src);
+        if !journaling() {
+            return;
+        }
+        for (i, piece) in src.chunks(MAX_EVENT_BYTES).enumerate() {
+            journal(
+                self.memory_id,
+                MemoryChange::Write {
+                    offset: offset + (i * MAX_EVENT_BYTES) as u64,
+                    bytes: piece.to_vec(),
+                },
+            );
+        }
+    }
+}
+
+fn with_replication_state<R>(f: impl FnOnce(&ReplicationState) -> R) -> R {
+    STATE_CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        let state =
+            cache.get_or_insert_with(|| REPLICATION_STATE.with(|s| s.borrow().get().clone()));
+        f(state)
+    })
+}
+
+fn replication_state() -> ReplicationState {
+    with_replication_state(ReplicationState::clone)
+}
+
+fn set_replication_state(state: ReplicationState) {
+    REPLICATION_STATE
+        .with(|s| s.borrow_mut().set(state.clone()))
+        .expect("Cannot update replication state");
+    STATE_CACHE.with(|c| *c.borrow_mut() = Some(state));
+}
+
+// true until a promotion is finished, so a promoted standby keeps refusing writes
+pub(crate) fn is_standby() -> bool {
+    with_replication_state(|state| {
+        matches!(
+            state.role,
+            ReplicationRole::Standby { .. } | ReplicationRole::PromotionPending
+        )
+    })
+}
+
+fn journaling() -> bool {
+    with_replication_state(|state| {
+        state.role == ReplicationRole::Primary && state.standby.is_some()
+    })
+}
+
+// what an event counts against the journal and batch limits
+fn event_size(change: &MemoryChange) -> u64 {
+    match change {
+        MemoryChange::Write { bytes, .. } => bytes.len() as u64 + 32,
+        MemoryChange::Grow { .. } => 32,
+    }
+}
+
+fn guard_standby() {
+    if is_standby() {
+        ic_cdk::trap("This canister is a standby replica, send changes to its primary");
+    }
+}
+
+fn journal(memory_id: u8, change: MemoryChange) {
+    if !journaling() {
+        return;
+    }
+    let mut state = replication_state();
+    if state.journal_overflow {
+        return;
+    }
+    let size = event_size(&change);
+    if state.next_seq - state.acked_seq >= MAX_PENDING_EVENTS
+        || state.pending_bytes + size > MAX_PENDING_BYTES
+    {
+        // the copy the next round starts carries this change
+        state.journal_overflow = true;
+        set_replication_state(state);
+        return;
+    }
+    let event = JournalEvent {
+        memory_id,
+        change,
+        at: time(),
+    };
+    JOURNAL.with(|s| s.borrow_mut().insert(state.next_seq, event));
+    state.next_seq += 1;
+    state.pending_bytes += size;
+    set_replication_state(state);
+}
+
+fn clear_journal() {
+    JOURNAL.with(|s| {
+        let mut journal = s.borrow_mut();
+        let seqs: Vec<u64> = journal.iter().map(|(seq, _)| seq).collect();
+        for seq in seqs {
+            journal.remove(&seq);
+        }
+    });
+}
+
+// the stores that get copied, in memory id order
+fn next_replicated_memory(from: u8) -> Option<u8> {
+    // 255 is reserved by the memory manager
+    (from..u8::MAX).find(|id| {
+        !LOCAL_MEMORY_IDS.contains(id)
+            && MEMORY_MANAGER.with(|m| m.borrow().raw(MemoryId::new(*id)).size()) > 0
+    })
+}
+
+// ids of the memories the stores live in, in order
+pub(crate) fn store_memory_ids() -> Vec<u8> {
+    let mut ids = vec![];
+    let mut from = 0;
+    while let Some(id) = next_replicated_memory(from) {
+        ids.push(id);
+        match id.checked_add(1) {
+            Some(next) => from = next,
+            None => break,
+        }
+    }
+    ids
+}
+
+// helper function to build the next batch: snapshot chunks until the copy is done, then
+// journal events the standby has not acknowledged
+fn next_batch(state: &ReplicationState) -> Option<ReplicationBatch> {
+    if let Some(cursor) = state.snapshot {
+        let memory_id = next_replicated_memory(cursor.memory_id)?;
+        let memory = MEMORY_MANAGER.with(|m| m.borrow().raw(MemoryId::new(memory_id)));
+        let offset = if memory_id == cursor.memory_id {
+            cursor.offset
+        } else {
+            0
+        };
+        let end = (memory.size() * WASM_PAGE_SIZE).min(offset + MAX_BATCH_BYTES as u64);
+        let mut bytes = vec![0; (end - offset) as usize];
+        memory.read(offset, &mut bytes);
+        return Some(ReplicationBatch::Snapshot {
+            memory_id,
+            size_pages: memory.size(),
+            offset,
+            bytes,
+            resume_seq: state.acked_seq,
+        });
+    }
+    let mut size = 0;
+    let events: Vec<JournalEvent> = JOURNAL.with(|s| {
+        s.borrow()
+            .range(state.acked_seq..)
+            .map(|(_, event)| event)
+            .take_while(|event| {
+                size += event_size(&event.change);
+                size <= MAX_BATCH_BYTES as u64
+            })
+            .collect()
+    });
+    if events.is_empty() {
+        return None;
+    }
+    Some(ReplicationBatch::Journal {
+        first_seq: state.acked_seq,
+        events,
+    })
+}
+
+// advance the state once the standby has taken the batch
+fn batch_delivered(batch: &ReplicationBatch) {
+    let mut state = replication_state();
+    match batch {
+        ReplicationBatch::Snapshot {
+            memory_id,
+            offset,
+            bytes,
+            size_pages,
+            ..
+        } => {
+            let next = offset + bytes.len() as u64;
+            state.snapshot = if next < size_pages * WASM_PAGE_SIZE {
+                Some(SnapshotCursor {
+                    memory_id: *memory_id,
+                    offset: next,
+                })
+            } else {
+                memory_id
+                    .checked_add(1)
+                    .and_then(next_replicated_memory)
+                    .map(|memory_id| SnapshotCursor {
+                        memory_id,
+                        offset: 0,
+                    })
+            };
+        }
+        ReplicationBatch::Journal { first_seq, events } => {
+            let acked = first_seq + events.len() as u64;
+            JOURNAL.with(|s| {
+                let mut journal = s.borrow_mut();
+                for seq in *first_seq..acked {
+                    journal.remove(&seq);
+                }
+            });
+            let delivered: u64 = events.iter().map(|event| event_size(&event.change)).sum();
+            state.pending_bytes = state.pending_bytes.saturating_sub(delivered);
+            state.acked_seq = acked;
+        }
+    }
+    state.last_success_at = Some(time());
+    state.last_error = None;
+    set_replication_state(state);
+}
+
+// Clears the in-flight flag when a round ends. A trap in the reply callback drops the round's
+// future during cleanup, so the flag is cleared then too and the next tick starts a new round
+struct RoundGuard;
+
+impl Drop for RoundGuard {
+    fn drop(&mut self) {
+        REPLICATING.with(|r| *r.borrow_mut() = false);
+    }
+}
+
+// one round of shipping changes to the standby, run by a timer; an unreachable standby
+// keeps the events queued until it answers again
+pub(crate) async fn replicate_to_standby() {
+    let state = replication_state();
+    let standby = match (&state.role, state.standby) {
+        (ReplicationRole::Primary, Some(standby)) => standby,
+        _ => return,
+    };
+    if REPLICATING.with(|r| r.replace(true)) {
+        return;
+    }
+    let _round = RoundGuard;
+    if state.journal_overflow {
+        restart_snapshot(standby);
+    }
+
+    while let Some(batch) = next_batch(&replication_state()) {
+        let reply: Result<(Result<u64, Error>,), _> =
+            ic_cdk::call(standby, "apply_replication_batch", (batch.clone(),)).await;
+        let error = match reply {
+            Ok((Ok(_),)) => {
+                batch_delivered(&batch);
+                continue;
+            }
+            Ok((Err(error),)) => error_message(error),
+            Err((code, msg)) => format!("{:?} {}", code, msg),
+        };
+        let mut state = replication_state();
+        state.last_error = Some(clip_error(error));
+        set_replication_state(state);
+        break;
+    }
+}
+
+fn error_message(error: Error) -> String {
+    match error {
+        Error::NotFound { msg }
+        | Error::AlreadyInit { msg }
+        | Error::InvalidPayload { msg }
+        | Error::Unauthorized { msg }
+        | Error::LimitExceeded { msg } => msg,
+    }
+}
+
+// the error cut to MAX_ERROR_BYTES on a character boundary, the standby controls its length
+fn clip_error(mut error: String) -> String {
+    if error.len() > MAX_ERROR_BYTES {
+        let end = (0..=MAX_ERROR_BYTES)
+            .rev()
+            .find(|i| error.is_char_boundary(*i))
+            .unwrap_or(0);
+        error.truncate(end);
+    }
+    error
+}
+
+// start copying every store from scratch, journaling what changes in the meantime
+fn restart_snapshot(standby: Principal) {
+    clear_journal();
+    let state = replication_state();
+    set_replication_state(ReplicationState {
+        role: ReplicationRole::Primary,
+        standby: Some(standby),
+        acked_seq: state.next_seq,
+        snapshot: Some(SnapshotCursor {
+            memory_id: 0,
+            offset: 0,
+        }),
+        last_error: None,
+        pending_bytes: 0,
+        journal_overflow: false,
+        ..state
+    });
+}
+
+// point this canister at a standby on another subnet; the standby must first be put in
+// standby mode with this canister as its primary
+#[ic_cdk::update]
+fn configure_standby(standby: Principal) -> Result<ReplicationStatus, Error> {
+    authorize_controller()?;
+    if is_standby() {
+        return Err(Error::InvalidPayload {
+            msg: "A standby cannot replicate to another standby".to_string(),
+        });
+    }
+    if standby == ic_cdk::id() {
+        return Err(Error::InvalidPayload {
+            msg: "A canister cannot be its own standby".to_string(),
+        });
+    }
+    restart_snapshot(standby);
+    Ok(replication_status())
+}
+
+#[ic_cdk::update]
+fn stop_replication() -> Result<ReplicationStatus, Error> {
+    authorize_controller()?;
+    clear_journal();
+    let state = replication_state();
+    set_replication_state(ReplicationState {
+        standby: None,
+        snapshot: None,
+        acked_seq: state.next_seq,
+        pending_bytes: 0,
+        journal_overflow: false,
+        ..state
+    });
+    Ok(replication_status())
+}
+
+// turn a freshly installed canister into a standby of the given primary; from then on it
+// refuses local changes until promoted
+#[ic_cdk::update]
+fn set_standby_mode(primary: Principal) -> Result<ReplicationStatus, Error> {
+    authorize_controller()?;
+    let state = replication_state();
+    if state.standby.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: "This canister replicates to a standby, stop replication first".to_string(),
+        });
+    }
+    set_replication_state(ReplicationState {
+        role: ReplicationRole::Standby { primary },
+        acked_seq: 0,
+        snapshot: None,
+        ..state
+    });
+    Ok(replication_status())
+}
+
+// called by the primary on its standby, returns the next journal event expected
+#[ic_cdk::update]
+fn apply_replication_batch(batch: ReplicationBatch) -> Result<u64, Error> {
+    let mut state = replication_state();
+    match &state.role {
+        ReplicationRole::Standby { primary } if *primary == caller() => {}
+        _ => {
+            return Err(Error::Unauthorized {
+                msg: "Caller is not the primary of this standby".to_string(),
+            })
+        }
+    }
+    match batch {
+        ReplicationBatch::Snapshot {
+            memory_id,
+            size_pages,
+            offset,
+            bytes,
+            resume_seq,
+        } => {
+            let memory = MEMORY_MANAGER.with(|m| m.borrow().raw(MemoryId::new(memory_id)));
+            if memory.size() < size_pages && memory.grow(size_pages - memory.size()) < 0 {
+                return Err(Error::LimitExceeded {
+                    msg: format!("Standby cannot grow memory {}", memory_id),
+                });
+            }
+            memory.write(offset, &bytes);
+            state.acked_seq = resume_seq;
+        }
+        ReplicationBatch::Journal { first_seq, events } => {
+            // a batch repeated after a lost reply is skipped up to what was already applied
+            if first_seq > state.acked_seq {
+                return Err(Error::InvalidPayload {
+                    msg: format!(
+                        "Standby expects event {}, got {}",
+                        state.acked_seq, first_seq
+                    ),
+                });
+            }
+            let skip = (state.acked_seq - first_seq) as usize;
+            for event in events.iter().skip(skip) {
+                let memory =
+                    MEMORY_MANAGER.with(|m| m.borrow().raw(MemoryId::new(event.memory_id)));
+                match &event.change {
+                    MemoryChange::Grow { size_pages } => {
+                        if memory.size() < *size_pages {
+                            memory.grow(size_pages - memory.size());
+                        }
+                    }
+                    MemoryChange::Write { offset, bytes } => {
+                        let end = offset + bytes.len() as u64;
+                        let size = memory.size() * WASM_PAGE_SIZE;
+                        if end > size {
+                            memory.grow((end - size).div_ceil(WASM_PAGE_SIZE));
+                        }
+                        memory.write(*offset, bytes);
+                    }
+                }
+                state.acked_seq += 1;
+            }
+        }
+    }
+    state.last_success_at = Some(time());
+    let acked = state.acked_seq;
+    set_replication_state(state);
+    Ok(acked)
+}
+
+fn replication_status() -> ReplicationStatus {
+    let state = replication_state();
+    let oldest_pending_at = JOURNAL.with(|s| {
+        s.borrow()
+            .range(state.acked_seq..)
+            .next()
+            .map(|(_, event)| event.at)
+    });
+    // measured on the primary as the age of the oldest change the standby does not have yet
+    let lag_seconds = oldest_pending_at
+        .map(|at| time().saturating_sub(at) / SECOND_NS)
+        .unwrap_or(0);
+    ReplicationStatus {
+        pending_events: state.next_seq.saturating_sub(state.acked_seq),
+        pending_bytes: state.pending_bytes,
+        oldest_pending_at,
+        lag_seconds,
+        // the standby is not usable until its first copy is complete
+        lagging: lag_seconds > MAX_HEALTHY_LAG_SECONDS || state.snapshot.is_some(),
+        role: state.role,
+        standby: state.standby,
+        snapshot: state.snapshot,
+        last_success_at: state.last_success_at,
+        last_error: state.last_error,
+        promoted_at: state.promoted_at,
+    }
+}
+
+// replication health for monitoring, on the primary or the standby
+#[ic_cdk::query]
+fn get_replication_status() -> Result<ReplicationStatus, Error> {
+    authorize_controller()?;
+    Ok(replication_status())
+}
+
+// make the standby the primary after its primary's subnet is lost. Stores keep their
+// in-memory view until the canister is upgraded, so writes stay refused until the upgrade
+// that must follow finishes the promotion
+#[ic_cdk::update]
+fn promote_standby() -> Result<ReplicationStatus, Error> {
+    authorize_controller()?;
+    let state = replication_state();
+    if !matches!(state.role, ReplicationRole::Standby { .. }) {
+        return Err(Error::InvalidPayload {
+            msg: "This canister is not a standby".to_string(),
+        });
+    }
+    set_replication_state(ReplicationState {
+        role: ReplicationRole::PromotionPending,
+        standby: None,
+        snapshot: None,
+        acked_seq: state.next_seq,
+        promoted_at: Some(time()),
+        ..state
+    });
+    Ok(replication_status())
+}
+
+// run from post_upgrade: a pending promotion becomes a primary once the stores are reloaded.
+// returns whether this upgrade finished a promotion
+pub(crate) fn finish_promotion() -> bool {
+    let state = replication_state();
+    if state.role != ReplicationRole::PromotionPending {
+        return false;
+    }
+    set_replication_state(ReplicationState {
+        role: ReplicationRole::Primary,
+        ..state
+    });
+    true
+}
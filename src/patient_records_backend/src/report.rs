@@ -0,0 +1,155 @@
+use crate::time;
+use crate::{
+    all_appointments, authorize_hospital, doctor_encounters, get_encounter_entries,
+    require_premium, Appointment, AppointmentStatus, Doctor, EncounterEntryKind, EncounterStatus,
+    Error, PremiumFeature, DOCTOR_STORAGE,
+};
+use std::collections::BTreeSet;
+
+// an appointment counts as attended when the doctor opened an encounter for the patient
+// from an hour before its start until its end
+const ATTENDANCE_GRACE_NS: u64 = 60 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DoctorReport {
+    pub doctor_id: u64,
+    pub doctor_name: String,
+    pub patients_seen: u64,
+    pub encounters: u64,
+    // mean time from opening an encounter to its first note, None without notes
+    pub average_note_turnaround_ns: Option<u64>,
+    // encounters still open and past appointments nobody followed up
+    pub open_tasks: u64,
+    pub appointments: u64,
+    pub no_shows: u64,
+    // no-shows per thousand past appointments
+    pub no_show_rate_permille: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DoctorReportPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    // reporting period, start inclusive and end exclusive
+    pub from: u64,
+    pub to: u64,
+}
+
+fn attended(appointment: &Appointment) -> bool {
+    doctor_encounters(appointment.doctor_id)
+        .iter()
+        .any(|encounter| {
+            encounter.patient_id == appointment.patient_id
+                && encounter.opened_at + ATTENDANCE_GRACE_NS >= appointment.start
+                && encounter.opened_at <= appointment.end
+        })
+}
+
+fn doctor_report(doctor: &Doctor, from: u64, to: u64) -> DoctorReport {
+    let now = time();
+    let in_period = |at: u64| from <= at && at < to;
+
+    let encounters: Vec<_> = doctor_encounters(doctor.id)
+        .into_iter()
+        .filter(|encounter| in_period(encounter.opened_at))
+        .collect();
+    let patients: BTreeSet<u64> = encounters
+        .iter()
+        .map(|encounter| encounter.patient_id)
+        .collect();
+    let turnarounds: Vec<u64> = encounters
+        .iter()
+        .filter_map(|encounter| {
+            get_encounter_entries(encounter)
+                .iter()
+                .find(|entry| matches!(entry.kind, EncounterEntryKind::Note { .. }))
+                .map(|note| note.recorded_at.saturating_sub(encounter.opened_at))
+        })
+        .collect();
+    let open_encounters = encounters
+        .iter()
+        .filter(|encounter| encounter.status == EncounterStatus::Open)
+        .count() as u64;
+
+    let past: Vec<Appointment> = all_appointments()
+        .into_iter()
+        .filter(|appointment| {
+            appointment.doctor_id == doctor.id
+                && appointment.status == AppointmentStatus::Scheduled
+                && in_period(appointment.start)
+                && appointment.end <= now
+        })
+        .collect();
+    let no_shows = past
+        .iter()
+        .filter(|appointment| !attended(appointment))
+        .count() as u64;
+    let appointments = past.len() as u64;
+
+    DoctorReport {
+        doctor_id: doctor.id,
+        doctor_name: doctor.name.clone(),
+        patients_seen: patients.len() as u64,
+        encounters: encounters.len() as u64,
+        average_note_turnaround_ns: match turnarounds.len() as u64 {
+            0 => None,
+            count => Some(turnarounds.iter().sum::<u64>() / count),
+        },
+        open_tasks: open_encounters + no_shows,
+        appointments,
+        no_shows,
+        no_show_rate_permille: match appointments {
+            0 => 0,
+            count => no_shows * 1000 / count,
+        },
+    }
+}
+
+fn hospital_reports(payload: &DoctorReportPayload) -> Result<Vec<DoctorReport>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    require_premium(hospital.id, PremiumFeature::Analytics)?;
+    if payload.from >= payload.to {
+        return Err(Error::InvalidPayload {
+            msg: "Report period must end after it starts".to_string(),
+        });
+    }
+    Ok(DOCTOR_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, doctor)| doctor)
+            .filter(|doctor| doctor.hospital_id == hospital.id)
+            .map(|doctor| doctor_report(&doctor, payload.from, payload.to))
+            .collect()
+    }))
+}
+
+// workload and performance of every doctor of the hospital over a period
+#[ic_cdk::query]
+fn get_doctor_reports(payload: DoctorReportPayload) -> Result<Vec<DoctorReport>, Error> {
+    hospital_reports(&payload)
+}
+
+// the same reports as csv, one doctor per line
+#[ic_cdk::query]
+fn export_doctor_reports(payload: DoctorReportPayload) -> Result<String, Error> {
+    let mut csv = String::from(
+        "doctor_id,doctor_name,patients_seen,encounters,average_note_turnaround_ns,open_tasks,appointments,no_shows,no_show_rate_permille\n",
+    );
+    for report in hospital_reports(&payload)? {
+        csv.push_str(&format!(
+            "{},\"{}\",{},{},{},{},{},{},{}\n",
+            report.doctor_id,
+            report.doctor_name.replace('"', "\"\""),
+            report.patients_seen,
+            report.encounters,
+            report
+                .average_note_turnaround_ns
+                .map_or(String::new(), |ns| ns.to_string()),
+            report.open_tasks,
+            report.appointments,
+            report.no_shows,
+            report.no_show_rate_permille,
+        ));
+    }
+    Ok(csv)
+}
@@ -0,0 +1,241 @@
+use crate::time;
+use crate::{
+    audit, authorize_controller, impl_storable, Actor, Error, Memory, Patient, HOSPITAL_STORAGE,
+    MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// The legal area whose residency rules apply to data held by a hospital or peer system,
+// e.g. "EU", "UK" or "US-CA"
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct JurisdictionTag {
+    pub jurisdiction: String,
+    pub set_at: u64,
+}
+
+// Data may move from one jurisdiction to another only with an entry like this. Moves within
+// a jurisdiction are always allowed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct JurisdictionTransfer {
+    pub from: String,
+    pub to: String,
+    pub added_at: u64,
+}
+
+// Where data of a patient is about to go
+pub(crate) enum TransferDestination {
+    Hospital(u64),
+    FederationPeer(Principal),
+    // an outside party in the given jurisdiction, e.g. a court for a legal export
+    Jurisdiction(String),
+}
+
+impl_storable!(JurisdictionTag, 128);
+impl_storable!(JurisdictionTransfer, 128);
+
+thread_local! {
+    static HOSPITAL_JURISDICTIONS: RefCell<StableBTreeMap<u64, JurisdictionTag, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(117)))
+    ));
+
+    // keyed by the hash of the peer canister id
+    static PEER_JURISDICTIONS: RefCell<StableBTreeMap<[u8; 32], JurisdictionTag, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(118)))
+    ));
+
+    // (hash of from, hash of to) -> allowed transfer
+    static TRANSFER_MATRIX: RefCell<StableBTreeMap<(u64, u64), JurisdictionTransfer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(119)))
+    ));
+}
+
+fn normalize_jurisdiction(jurisdiction: &str) -> Result<String, Error> {
+    let jurisdiction = jurisdiction.trim().to_uppercase();
+    if jurisdiction.len() < 2
+        || jurisdiction.len() > 16
+        || !jurisdiction
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("Invalid jurisdiction code: {}", jurisdiction),
+        });
+    }
+    Ok(jurisdiction)
+}
+
+fn jurisdiction_key(jurisdiction: &str) -> u64 {
+    let digest = Sha256::digest(jurisdiction.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+}
+
+fn peer_key(canister_id: &Principal) -> [u8; 32] {
+    Sha256::digest(canister_id.as_slice()).into()
+}
+
+pub(crate) fn hospital_jurisdiction(hospital_id: u64) -> Option<String> {
+    HOSPITAL_JURISDICTIONS
+        .with(|s| s.borrow().get(&hospital_id))
+        .map(|tag| tag.jurisdiction)
+}
+
+fn transfer_allowed(from: &str, to: &str) -> bool {
+    from == to
+        || TRANSFER_MATRIX.with(|m| {
+            m.borrow()
+                .contains_key(&(jurisdiction_key(from), jurisdiction_key(to)))
+        })
+}
+
+// check that every jurisdiction holding the patient's data lets it go to the destination.
+// untagged hospitals have no residency rules, but data from a tagged one never goes somewhere
+// untagged. refusals are written to the audit log
+pub(crate) fn check_residency(
+    patient: &Patient,
+    destination: &TransferDestination,
+    actor: Actor,
+    hospital_id: Option<u64>,
+    channel: &str,
+) -> Result<(), Error> {
+    let (to, label) = match destination {
+        TransferDestination::Hospital(id) => {
+            (hospital_jurisdiction(*id), format!("hospital {}", id))
+        }
+        TransferDestination::FederationPeer(canister_id) => (
+            PEER_JURISDICTIONS
+                .with(|s| s.borrow().get(&peer_key(canister_id)))
+                .map(|tag| tag.jurisdiction),
+            format!("peer {}", canister_id),
+        ),
+        TransferDestination::Jurisdiction(jurisdiction) => (
+            normalize_jurisdiction(jurisdiction).ok(),
+            format!("party in {}", jurisdiction),
+        ),
+    };
+    let mut sources: Vec<String> = patient
+        .hospitals_ids
+        .iter()
+        .filter_map(|id| hospital_jurisdiction(*id))
+        .collect();
+    sources.sort();
+    sources.dedup();
+    let blocked: Vec<String> = sources
+        .into_iter()
+        .filter(|from| match &to {
+            Some(to) => !transfer_allowed(from, to),
+            None => true,
+        })
+        .collect();
+    if blocked.is_empty() {
+        return Ok(());
+    }
+    let to = to.unwrap_or_else(|| "untagged".to_string());
+    audit(
+        actor,
+        hospital_id,
+        Some(patient.id),
+        "residency_refused",
+        format!(
+            "{} to {} ({}) from {}",
+            channel,
+            label,
+            to,
+            blocked.join(", ")
+        ),
+    );
+    Err(Error::Unauthorized {
+        msg: format!(
+            "Residency rules of {} do not allow moving this patient's data to {}",
+            blocked.join(", "),
+            to
+        ),
+    })
+}
+
+// controllers tag a hospital with the jurisdiction its data is held under
+#[ic_cdk::update]
+fn set_hospital_jurisdiction(
+    hospital_id: u64,
+    jurisdiction: String,
+) -> Result<JurisdictionTag, Error> {
+    authorize_controller()?;
+    if !HOSPITAL_STORAGE.with(|s| s.borrow().contains_key(&hospital_id)) {
+        return Err(Error::NotFound {
+            msg: format!("Hospital of id: {} not found", hospital_id),
+        });
+    }
+    let tag = JurisdictionTag {
+        jurisdiction: normalize_jurisdiction(&jurisdiction)?,
+        set_at: time(),
+    };
+    HOSPITAL_JURISDICTIONS.with(|s| s.borrow_mut().insert(hospital_id, tag.clone()));
+    Ok(tag)
+}
+
+#[ic_cdk::query]
+fn get_hospital_jurisdiction(hospital_id: u64) -> Option<JurisdictionTag> {
+    HOSPITAL_JURISDICTIONS.with(|s| s.borrow().get(&hospital_id))
+}
+
+// controllers tag a federation peer with the jurisdiction it is run under
+#[ic_cdk::update]
+fn set_peer_jurisdiction(
+    canister_id: Principal,
+    jurisdiction: String,
+) -> Result<JurisdictionTag, Error> {
+    authorize_controller()?;
+    let tag = JurisdictionTag {
+        jurisdiction: normalize_jurisdiction(&jurisdiction)?,
+        set_at: time(),
+    };
+    PEER_JURISDICTIONS.with(|s| s.borrow_mut().insert(peer_key(&canister_id), tag.clone()));
+    Ok(tag)
+}
+
+// allow data to move from one jurisdiction to another, one direction at a time
+#[ic_cdk::update]
+fn allow_jurisdiction_transfer(from: String, to: String) -> Result<JurisdictionTransfer, Error> {
+    authorize_controller()?;
+    let transfer = JurisdictionTransfer {
+        from: normalize_jurisdiction(&from)?,
+        to: normalize_jurisdiction(&to)?,
+        added_at: time(),
+    };
+    TRANSFER_MATRIX.with(|m| {
+        m.borrow_mut().insert(
+            (
+                jurisdiction_key(&transfer.from),
+                jurisdiction_key(&transfer.to),
+            ),
+            transfer.clone(),
+        )
+    });
+    Ok(transfer)
+}
+
+#[ic_cdk::update]
+fn disallow_jurisdiction_transfer(from: String, to: String) -> Result<JurisdictionTransfer, Error> {
+    authorize_controller()?;
+    let from = normalize_jurisdiction(&from)?;
+    let to = normalize_jurisdiction(&to)?;
+    TRANSFER_MATRIX
+        .with(|m| {
+            m.borrow_mut()
+                .remove(&(jurisdiction_key(&from), jurisdiction_key(&to)))
+        })
+        .ok_or(Error::NotFound {
+            msg: format!("No transfer from {} to {} is allowed", from, to),
+        })
+}
+
+#[ic_cdk::query]
+fn get_jurisdiction_matrix() -> Vec<JurisdictionTransfer> {
+    TRANSFER_MATRIX.with(|m| m.borrow().iter().map(|(_, transfer)| transfer).collect())
+}
@@ -0,0 +1,264 @@
+use crate::{
+    authorize_controller, authorize_doctor, authorize_patient_access, get_assigned_patient,
+    get_record, impl_storable, is_restricted, patient_records, records_after, Error, MedicalRecord,
+    Memory, PatientAccess, MAX_PAGE_SIZE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// characters of context on each side of the first match in a snippet
+const SNIPPET_CONTEXT: usize = 60;
+const STOP_WORDS: [&str; 12] = [
+    "a", "an", "and", "at", "for", "in", "is", "of", "on", "the", "to", "with",
+];
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub enum SearchScope {
+    Patient(u64),
+    // every patient assigned to the searching doctor
+    MyPatients,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub record_id: u64,
+    pub patient_id: u64,
+    pub title: String,
+    pub created_at: u64,
+    pub score: f64,
+    pub snippet: String,
+}
+
+// Number of times a term occurs in a record
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub struct Posting {
+    pub term_frequency: u32,
+}
+
+impl_storable!(Posting, 32);
+
+thread_local! {
+    // posting lists keyed by (term key, record id), the term key covers the patient so a
+    // search only reads the postings of the patients in scope
+    static SEARCH_INDEX: RefCell<StableBTreeMap<(u64, u64), Posting, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70)))
+    ));
+}
+
+// lowercase words of at least two characters, without stop words
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(|word| word.to_lowercase())
+        .filter(|word| word.chars().count() > 1 && !STOP_WORDS.contains(&word.as_str()))
+        .collect()
+}
+
+fn term_key(patient_id: u64, term: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(patient_id.to_be_bytes());
+    hasher.update(term.as_bytes());
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap_or_default())
+}
+
+fn term_frequencies(record: &MedicalRecord) -> BTreeMap<String, u32> {
+    let mut frequencies = BTreeMap::new();
+    for term in tokenize(&record.title)
+        .into_iter()
+        .chain(tokenize(&record.body))
+    {
+        *frequencies.entry(term).or_insert(0) += 1;
+    }
+    frequencies
+}
+
+pub(crate) fn index_record(record: &MedicalRecord) {
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for (term, term_frequency) in term_frequencies(record) {
+            index.insert(
+                (term_key(record.patient_id, &term), record.id),
+                Posting { term_frequency },
+            );
+        }
+    });
+}
+
+pub(crate) fn unindex_record(record: &MedicalRecord) {
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for term in term_frequencies(record).keys() {
+            index.remove(&(term_key(record.patient_id, term), record.id));
+        }
+    });
+}
+
+// records of the patient containing the term, with the term's frequency in each
+fn postings(patient_id: u64, term: &str) -> BTreeMap<u64, u32> {
+    let key = term_key(patient_id, term);
+    SEARCH_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((key, 0)..=(key, u64::MAX))
+            .map(|((_, record_id), posting)| (record_id, posting.term_frequency))
+            .collect()
+    })
+}
+
+// terms separated by OR form alternatives, terms within an alternative must all occur
+fn parse_query(query: &str) -> Vec<Vec<String>> {
+    query
+        .split(" OR ")
+        .map(tokenize)
+        .filter(|terms| !terms.is_empty())
+        .collect()
+}
+
+// text around the first occurrence of any of the terms, or the start of the body
+fn snippet(record: &MedicalRecord, terms: &[String]) -> String {
+    let body: Vec<char> = record.body.chars().collect();
+    let lower: Vec<char> = record.body.to_lowercase().chars().collect();
+    let first_match = terms
+        .iter()
+        .filter_map(|term| {
+            let term: Vec<char> = term.chars().collect();
+            lower
+                .windows(term.len())
+                .position(|window| window == term.as_slice())
+        })
+        .min();
+    let (start, end) = match first_match {
+        // lowercasing can change the length of a few characters, so stay within the body
+        Some(at) if lower.len() == body.len() => (
+            at.saturating_sub(SNIPPET_CONTEXT),
+            (at + SNIPPET_CONTEXT).min(body.len()),
+        ),
+        _ => (0, (2 * SNIPPET_CONTEXT).min(body.len())),
+    };
+    let mut text: String = body[start..end].iter().collect();
+    if start > 0 {
+        text.insert_str(0, "...");
+    }
+    if end < body.len() {
+        text.push_str("...");
+    }
+    text
+}
+
+fn search_patient(patient_id: u64, alternatives: &[Vec<String>]) -> Vec<SearchHit> {
+    let total_records = patient_records(patient_id).len().max(1) as f64;
+    let mut scores: BTreeMap<u64, (f64, Vec<String>)> = BTreeMap::new();
+    for terms in alternatives {
+        let term_postings: Vec<BTreeMap<u64, u32>> = terms
+            .iter()
+            .map(|term| postings(patient_id, term))
+            .collect();
+        let Some((first, rest)) = term_postings.split_first() else {
+            continue;
+        };
+        for record_id in first.keys() {
+            if !rest.iter().all(|postings| postings.contains_key(record_id)) {
+                continue;
+            }
+            // tf-idf: terms that are rare in the patient's records weigh more
+            let score: f64 = term_postings
+                .iter()
+                .map(|postings| {
+                    let idf = 1.0 + (total_records / postings.len() as f64).ln();
+                    postings[record_id] as f64 * idf
+                })
+                .sum();
+            let best = scores.entry(*record_id).or_insert((0.0, vec![]));
+            if score > best.0 {
+                *best = (score, terms.clone());
+            }
+        }
+    }
+    scores
+        .into_iter()
+        .filter_map(|(record_id, (score, terms))| {
+            if is_restricted(record_id) {
+                return None;
+            }
+            let record = get_record(record_id).ok()?;
+            Some(SearchHit {
+                record_id,
+                patient_id,
+                snippet: snippet(&record, &terms),
+                title: record.title,
+                created_at: record.created_at,
+                score,
+            })
+        })
+        .collect()
+}
+
+// full-text search over medical records, best matches first. "penicillin reaction" finds
+// records with both words, "penicillin OR amoxicillin" records with either
+#[ic_cdk::query]
+fn search_records(
+    scope: SearchScope,
+    query: String,
+    access: PatientAccess,
+    limit: u64,
+) -> Result<Vec<SearchHit>, Error> {
+    let patient_ids = match (scope, &access) {
+        (SearchScope::Patient(patient_id), _) => {
+            vec![authorize_patient_access(patient_id, &access)?.0.id]
+        }
+        (
+            SearchScope::MyPatients,
+            PatientAccess::Doctor {
+                doctor_id,
+                doctor_password,
+            },
+        ) => {
+            let doctor = authorize_doctor(*doctor_id, doctor_password)?;
+            doctor
+                .patient_ids
+                .iter()
+                .filter_map(|patient_id| get_assigned_patient(&doctor, *patient_id).ok())
+                .map(|patient| patient.id)
+                .collect()
+        }
+        (SearchScope::MyPatients, PatientAccess::Patient { .. }) => {
+            return Err(Error::InvalidPayload {
+                msg: "Only doctors can search across their patients".to_string(),
+            })
+        }
+    };
+    let alternatives = parse_query(&query);
+    if alternatives.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Search query has no searchable words".to_string(),
+        });
+    }
+
+    let mut hits: Vec<SearchHit> = patient_ids
+        .into_iter()
+        .flat_map(|patient_id| search_patient(patient_id, &alternatives))
+        .collect();
+    hits.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then(b.created_at.cmp(&a.created_at))
+    });
+    hits.truncate(limit.clamp(1, MAX_PAGE_SIZE) as usize);
+    Ok(hits)
+}
+
+// index records written before search existed, in batches of record ids; returns the last id
+// processed to pass as start_after for the next batch
+#[ic_cdk::update]
+fn rebuild_search_index(start_after: u64, limit: u64) -> Result<Option<u64>, Error> {
+    authorize_controller()?;
+    let records = records_after(start_after, limit.clamp(1, 1_000) as usize);
+    for record in &records {
+        index_record(record);
+    }
+    Ok(records.last().map(|record| record.id))
+}
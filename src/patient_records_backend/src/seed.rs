@@ -0,0 +1,212 @@
+// Demo data for local testing and frontend development, built only with the `dev` feature.
+// Everything goes through the regular endpoints so the data is linked the way real use links
+// it, and every account shares DEMO_PASSWORD
+use crate::{
+    add_allergy, add_doctor, add_hospital, add_medical_record, add_patient,
+    assign_patient_to_doctor, authorize_controller, set_patient_demographics, time,
+    AddPatientToDoctor, AllergyPayload, AllergySeverity, DoctorPayload, Error, HospitalPayload,
+    MedicalRecordPayload, PatientAccess, PatientPayload, RecordKind, Sex,
+};
+
+const DEMO_PASSWORD: &str = "demo-password";
+// keeps one call within the instruction limit
+const MAX_DEMO_PATIENTS: u32 = 500;
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+const CITIES: [&str; 5] = ["Nairobi", "Mombasa", "Kisumu", "Nakuru", "Eldoret"];
+const FIRST_NAMES: [&str; 10] = [
+    "Amina", "Brian", "Chebet", "David", "Esther", "Felix", "Grace", "Hassan", "Irene", "James",
+];
+const LAST_NAMES: [&str; 8] = [
+    "Otieno", "Wanjiru", "Kiptoo", "Mwangi", "Achieng", "Mutua", "Njeri", "Omondi",
+];
+const DIAGNOSES: [(&str, &str); 5] = [
+    (
+        "Hypertension",
+        "Blood pressure 150/95 on two visits, started on amlodipine",
+    ),
+    (
+        "Type 2 diabetes",
+        "HbA1c 7.9%, diet advice given and metformin started",
+    ),
+    ("Asthma", "Wheeze on exertion, salbutamol inhaler as needed"),
+    (
+        "Malaria",
+        "Positive rapid test, artemether-lumefantrine for three days",
+    ),
+    (
+        "Back pain",
+        "Lower back strain after lifting, physiotherapy referral",
+    ),
+];
+const ALLERGENS: [(&str, &str); 4] = [
+    ("Penicillin", "Rash"),
+    ("Peanuts", "Swelling of the lips"),
+    ("Sulfonamides", "Hives"),
+    ("Latex", "Itching"),
+];
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct DemoDataSummary {
+    pub hospital_ids: Vec<u64>,
+    pub doctor_ids: Vec<u64>,
+    pub patient_ids: Vec<u64>,
+    pub password: String,
+}
+
+// the same inputs always give the same data
+fn pick<T: Copy>(items: &[T], n: u32) -> T {
+    items[(n as usize).wrapping_mul(7919) % items.len()]
+}
+
+fn person_name(n: u32) -> String {
+    format!("{} {}", pick(&FIRST_NAMES, n), pick(&LAST_NAMES, n / 3))
+}
+
+// refuse anywhere but a local replica, even when the feature slipped into the build
+fn check_local_network() -> Result<(), Error> {
+    match option_env!("DFX_NETWORK") {
+        Some("local") => Ok(()),
+        Some(network) => Err(Error::Unauthorized {
+            msg: format!("Demo data cannot be seeded on the {} network", network),
+        }),
+        None => Err(Error::Unauthorized {
+            msg: "Demo data can only be seeded in a build for the local network".to_string(),
+        }),
+    }
+}
+
+fn seed_error(step: &str, error: Error) -> Error {
+    Error::InvalidPayload {
+        msg: format!(
+            "Could not seed {}: {}",
+            step,
+            match error {
+                Error::NotFound { msg }
+                | Error::AlreadyInit { msg }
+                | Error::InvalidPayload { msg }
+                | Error::Unauthorized { msg }
+                | Error::LimitExceeded { msg } => msg,
+            }
+        ),
+    }
+}
+
+// create hospitals, doctors spread over them and patients each assigned to a doctor with a
+// couple of records, an allergy for some and demographics
+#[ic_cdk::update]
+fn seed_demo_data(
+    n_hospitals: u32,
+    n_doctors: u32,
+    n_patients: u32,
+) -> Result<DemoDataSummary, Error> {
+    authorize_controller()?;
+    check_local_network()?;
+    if n_hospitals == 0 || n_doctors == 0 || n_patients > MAX_DEMO_PATIENTS {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Need at least one hospital and doctor, and at most {} patients per call",
+                MAX_DEMO_PATIENTS
+            ),
+        });
+    }
+    let mut summary = DemoDataSummary {
+        password: DEMO_PASSWORD.to_string(),
+        ..Default::default()
+    };
+
+    for n in 0..n_hospitals {
+        let city = pick(&CITIES, n);
+        let hospital = add_hospital(HospitalPayload {
+            name: format!("{} General Hospital {}", city, n + 1),
+            address: format!("{} Hospital Road", n + 1),
+            password: DEMO_PASSWORD.to_string(),
+            city: city.to_string(),
+            region: format!("{} County", city),
+            ..Default::default()
+        })
+        .map_err(|e| seed_error("hospital", e))?;
+        summary.hospital_ids.push(hospital.id);
+    }
+
+    for n in 0..n_doctors {
+        let hospital_id = summary.hospital_ids[n as usize % summary.hospital_ids.len()];
+        let doctor = add_doctor(DoctorPayload {
+            name: format!("Dr. {}", person_name(n + 100)),
+            hospital_id,
+            password: DEMO_PASSWORD.to_string(),
+            hospital_password: DEMO_PASSWORD.to_string(),
+        })
+        .map_err(|e| seed_error("doctor", e))?;
+        summary.doctor_ids.push(doctor.id);
+    }
+
+    for n in 0..n_patients {
+        let patient = add_patient(PatientPayload {
+            name: person_name(n),
+            history: "No significant past medical history".to_string(),
+            password: DEMO_PASSWORD.to_string(),
+            language: None,
+        })
+        .map_err(|e| seed_error("patient", e))?;
+        let doctor_id = summary.doctor_ids[n as usize % summary.doctor_ids.len()];
+        assign_patient_to_doctor(AddPatientToDoctor {
+            doctor_id,
+            patient_id: patient.id,
+            doctor_password: DEMO_PASSWORD.to_string(),
+            patient_password: DEMO_PASSWORD.to_string(),
+        })
+        .map_err(|e| seed_error("assignment", e))?;
+
+        // born between 1950 and 2019
+        let age_days = 365 * (5 + (n as u64 * 37) % 70);
+        set_patient_demographics(
+            patient.id,
+            PatientAccess::Patient {
+                patient_password: DEMO_PASSWORD.to_string(),
+            },
+            time().saturating_sub(age_days * DAY_NS),
+            if n % 2 == 0 { Sex::Female } else { Sex::Male },
+        )
+        .map_err(|e| seed_error("demographics", e))?;
+
+        let (diagnosis, plan) = pick(&DIAGNOSES, n);
+        for (kind, title, body) in [
+            (RecordKind::Diagnosis, diagnosis, plan),
+            (
+                RecordKind::Note,
+                "Follow-up visit",
+                "Doing well on treatment, review in three months",
+            ),
+        ] {
+            add_medical_record(MedicalRecordPayload {
+                doctor_id,
+                doctor_password: DEMO_PASSWORD.to_string(),
+                patient_id: patient.id,
+                kind,
+                title: title.to_string(),
+                body: body.to_string(),
+                sensitivity: None,
+            })
+            .map_err(|e| seed_error("record", e))?;
+        }
+        if n % 3 == 0 {
+            let (substance, reaction) = pick(&ALLERGENS, n);
+            add_allergy(AllergyPayload {
+                doctor_id,
+                doctor_password: DEMO_PASSWORD.to_string(),
+                patient_id: patient.id,
+                substance: substance.to_string(),
+                reaction: reaction.to_string(),
+                severity: if n % 2 == 0 {
+                    AllergySeverity::Moderate
+                } else {
+                    AllergySeverity::Mild
+                },
+            })
+            .map_err(|e| seed_error("allergy", e))?;
+        }
+        summary.patient_ids.push(patient.id);
+    }
+    Ok(summary)
+}
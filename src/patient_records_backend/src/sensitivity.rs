@@ -0,0 +1,429 @@
+use crate::time;
+use crate::{
+    all_patient_records, audit, authenticate_patient, authorize_doctor, get_assigned_patient,
+    get_record, impl_storable, issue_consent_receipt, scope_labels, Actor, ConsentAction, Error,
+    MedicalRecord, Memory, PatientConsent, DOCTOR_STORAGE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum RestrictedCategory {
+    MentalHealth,
+    SubstanceUse,
+    SexualHealth,
+    Reproductive,
+    Genetic,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Sensitivity {
+    Normal,
+    // shown to the care team, with every read audited
+    Sensitive,
+    // left out of charts, exports and searches; readable only by doctors the patient
+    // authorized for the category
+    Restricted(RestrictedCategory),
+}
+
+// The classification of one record, absent for normal records
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RecordClassification {
+    pub record_id: u64,
+    pub patient_id: u64,
+    pub sensitivity: Sensitivity,
+    pub classified_by: u64,
+    pub classified_at: u64,
+}
+
+// The restricted categories a patient opened to one doctor
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RestrictedGrant {
+    pub patient_id: u64,
+    pub doctor_id: u64,
+    pub categories: Vec<RestrictedCategory>,
+    pub updated_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ClassifyRecordPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub record_id: u64,
+    pub sensitivity: Option<Sensitivity>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RestrictedRecordsPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub category: RestrictedCategory,
+}
+
+impl_storable!(RecordClassification, 256);
+impl_storable!(RestrictedGrant, 512);
+
+thread_local! {
+    static CLASSIFICATION_STORAGE: RefCell<StableBTreeMap<u64, RecordClassification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(106)))
+    ));
+
+    static RESTRICTED_GRANTS: RefCell<StableBTreeMap<(u64, u64), RestrictedGrant, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(107)))
+    ));
+}
+
+pub(crate) fn record_sensitivity(record_id: u64) -> Sensitivity {
+    CLASSIFICATION_STORAGE
+        .with(|s| s.borrow().get(&record_id))
+        .map_or(Sensitivity::Normal, |classification| {
+            classification.sensitivity
+        })
+}
+
+pub(crate) fn is_restricted(record_id: u64) -> bool {
+    matches!(record_sensitivity(record_id), Sensitivity::Restricted(_))
+}
+
+// record or change a record's class; Normal drops the classification
+pub(crate) fn classify_record(record: &MedicalRecord, sensitivity: Sensitivity, doctor_id: u64) {
+    CLASSIFICATION_STORAGE.with(|s| {
+        let mut s = s.borrow_mut();
+        if sensitivity == Sensitivity::Normal {
+            s.remove(&record.id);
+        } else {
+            s.insert(
+                record.id,
+                RecordClassification {
+                    record_id: record.id,
+                    patient_id: record.patient_id,
+                    sensitivity,
+                    classified_by: doctor_id,
+                    classified_at: time(),
+                },
+            );
+        }
+    });
+}
+
+fn granted_categories(patient_id: u64, doctor_id: u64) -> Vec<RestrictedCategory> {
+    RESTRICTED_GRANTS
+        .with(|s| s.borrow().get(&(patient_id, doctor_id)))
+        .map(|grant| grant.categories)
+        .unwrap_or_default()
+}
+
+// the records a doctor may read: everything not restricted plus the restricted categories the
+// patient opened to them; reads of sensitive records are audited
+pub(crate) fn records_for_doctor(patient_id: u64, doctor_id: u64) -> Vec<MedicalRecord> {
+    let granted = granted_categories(patient_id, doctor_id);
+    let records: Vec<MedicalRecord> = all_patient_records(patient_id)
+        .into_iter()
+        .filter(|record| match record_sensitivity(record.id) {
+            Sensitivity::Restricted(category) => granted.contains(&category),
+            _ => true,
+        })
+        .collect();
+    let sensitive: Vec<String> = records
+        .iter()
+        .filter(|record| record_sensitivity(record.id) != Sensitivity::Normal)
+        .map(|record| record.id.to_string())
+        .collect();
+    if !sensitive.is_empty() {
+        audit(
+            Actor::Doctor(doctor_id),
+            None,
+            Some(patient_id),
+            "sensitive_records_viewed",
+            format!("records {}", sensitive.join(",")),
+        );
+    }
+    records
+}
+
+// change the class of a record of an assigned patient
+#[ic_cdk::update]
+fn set_record_sensitivity(payload: ClassifyRecordPayload) -> Result<RecordClassification, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let record = get_record(payload.record_id)?;
+    get_assigned_patient(&doctor, record.patient_id)?;
+    let sensitivity = payload.sensitivity.unwrap_or(Sensitivity::Normal);
+    let previous = record_sensitivity(record.id);
+    if let Sensitivity::Restricted(category) = previous {
+        if !granted_categories(record.patient_id, doctor.id).contains(&category) {
+            return Err(Error::Unauthorized {
+                msg: "Only doctors authorized for the record's category can reclassify it"
+                    .to_string(),
+            });
+        }
+    }
+    classify_record(&record, sensitivity, doctor.id);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(record.patient_id),
+        "record_reclassified",
+        format!("record {}", record.id),
+    );
+    Ok(RecordClassification {
+        record_id: record.id,
+        patient_id: record.patient_id,
+        sensitivity,
+        classified_by: doctor.id,
+        classified_at: time(),
+    })
+}
+
+// the patient opens a restricted category of their records to one doctor
+#[ic_cdk::update]
+fn grant_restricted_access(
+    consent: PatientConsent,
+    doctor_id: u64,
+    category: RestrictedCategory,
+) -> Result<RestrictedGrant, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    if !DOCTOR_STORAGE.with(|s| s.borrow().contains_key(&doctor_id)) {
+        return Err(Error::NotFound {
+            msg: format!("Doctor of id: {} not found", doctor_id),
+        });
+    }
+    let mut categories = granted_categories(patient.id, doctor_id);
+    if !categories.contains(&category) {
+        categories.push(category);
+    }
+    let grant = RestrictedGrant {
+        patient_id: patient.id,
+        doctor_id,
+        categories,
+        updated_at: time(),
+    };
+    RESTRICTED_GRANTS.with(|s| {
+        s.borrow_mut()
+            .insert((patient.id, doctor_id), grant.clone())
+    });
+    audit(
+        Actor::Patient(patient.id),
+        None,
+        Some(patient.id),
+        "restricted_access_granted",
+        format!("doctor {}", doctor_id),
+    );
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Granted,
+        "restricted_records",
+        doctor_id,
+        format!("doctor {}", doctor_id),
+        scope_labels(&[category]),
+        None,
+    );
+    Ok(grant)
+}
+
+#[ic_cdk::update]
+fn revoke_restricted_access(
+    consent: PatientConsent,
+    doctor_id: u64,
+    category: RestrictedCategory,
+) -> Result<(), Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    let categories: Vec<RestrictedCategory> = granted_categories(patient.id, doctor_id)
+        .into_iter()
+        .filter(|granted| *granted != category)
+        .collect();
+    RESTRICTED_GRANTS.with(|s| {
+        let mut s = s.borrow_mut();
+        if categories.is_empty() {
+            s.remove(&(patient.id, doctor_id));
+        } else {
+            s.insert(
+                (patient.id, doctor_id),
+                RestrictedGrant {
+                    patient_id: patient.id,
+                    doctor_id,
+                    categories,
+                    updated_at: time(),
+                },
+            );
+        }
+    });
+    audit(
+        Actor::Patient(patient.id),
+        None,
+        Some(patient.id),
+        "restricted_access_revoked",
+        format!("doctor {}", doctor_id),
+    );
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Revoked,
+        "restricted_records",
+        doctor_id,
+        format!("doctor {}", doctor_id),
+        scope_labels(&[category]),
+        None,
+    );
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_restricted_grants(consent: PatientConsent) -> Result<Vec<RestrictedGrant>, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    Ok(RESTRICTED_GRANTS.with(|s| {
+        s.borrow()
+            .range((patient.id, 0)..=(patient.id, u64::MAX))
+            .map(|(_, grant)| grant)
+            .collect()
+    }))
+}
+
+// restricted records of one category, for a doctor the patient authorized for it
+#[ic_cdk::update]
+fn get_restricted_records(payload: RestrictedRecordsPayload) -> Result<Vec<MedicalRecord>, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    if !granted_categories(patient.id, doctor.id).contains(&payload.category) {
+        audit(
+            Actor::Doctor(doctor.id),
+            Some(doctor.hospital_id),
+            Some(patient.id),
+            "restricted_access_denied",
+            "no patient consent for the category".to_string(),
+        );
+        return Err(Error::Unauthorized {
+            msg: "The patient has not authorized you for this category".to_string(),
+        });
+    }
+    let records: Vec<MedicalRecord> = all_patient_records(patient.id)
+        .into_iter()
+        .filter(|record| record_sensitivity(record.id) == Sensitivity::Restricted(payload.category))
+        .collect();
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "restricted_records_viewed",
+        format!("{} records", records.len()),
+    );
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{clinic, must, refused, Clinic, PASSWORD};
+    use crate::{
+        add_doctor, add_medical_record, assign_patient_to_doctor, AddPatientToDoctor,
+        DoctorPayload, MedicalRecordPayload, RecordKind,
+    };
+
+    const CATEGORY: RestrictedCategory = RestrictedCategory::MentalHealth;
+
+    fn consent(clinic: &Clinic) -> PatientConsent {
+        PatientConsent {
+            patient_id: clinic.patient_id,
+            patient_password: PASSWORD.to_string(),
+        }
+    }
+
+    fn record(clinic: &Clinic, sensitivity: Option<Sensitivity>) -> u64 {
+        must(add_medical_record(MedicalRecordPayload {
+            doctor_id: clinic.doctor_id,
+            doctor_password: PASSWORD.to_string(),
+            patient_id: clinic.patient_id,
+            kind: RecordKind::Note,
+            title: "Session".to_string(),
+            body: "Talked through the week".to_string(),
+            sensitivity,
+        }))
+        .id
+    }
+
+    // a second doctor of the clinic's hospital on the patient's care team
+    fn colleague(clinic: &Clinic) -> u64 {
+        let doctor = must(add_doctor(DoctorPayload {
+            name: "Colleague".to_string(),
+            hospital_id: clinic.hospital_id,
+            password: PASSWORD.to_string(),
+            hospital_password: PASSWORD.to_string(),
+        }));
+        must(assign_patient_to_doctor(AddPatientToDoctor {
+            doctor_id: doctor.id,
+            patient_id: clinic.patient_id,
+            doctor_password: PASSWORD.to_string(),
+            patient_password: PASSWORD.to_string(),
+        }));
+        doctor.id
+    }
+
+    fn restricted(clinic: &Clinic, doctor_id: u64, category: RestrictedCategory) -> bool {
+        refused(get_restricted_records(RestrictedRecordsPayload {
+            doctor_id,
+            doctor_password: PASSWORD.to_string(),
+            patient_id: clinic.patient_id,
+            category,
+        }))
+    }
+
+    #[test]
+    fn restricted_records_are_left_out_until_the_patient_grants_the_category() {
+        let clinic = clinic();
+        let doctor_id = colleague(&clinic);
+        let normal = record(&clinic, None);
+        let hidden = record(&clinic, Some(Sensitivity::Restricted(CATEGORY)));
+        let visible = |doctor_id| -> Vec<u64> {
+            records_for_doctor(clinic.patient_id, doctor_id)
+                .iter()
+                .map(|record| record.id)
+                .collect()
+        };
+        assert_eq!(visible(doctor_id), vec![normal]);
+        assert!(restricted(&clinic, doctor_id, CATEGORY));
+
+        must(grant_restricted_access(
+            consent(&clinic),
+            doctor_id,
+            CATEGORY,
+        ));
+        assert_eq!(visible(doctor_id), vec![normal, hidden]);
+        assert!(!restricted(&clinic, doctor_id, CATEGORY));
+        // the grant opens one category only
+        assert!(restricted(&clinic, doctor_id, RestrictedCategory::Genetic));
+
+        must(revoke_restricted_access(
+            consent(&clinic),
+            doctor_id,
+            CATEGORY,
+        ));
+        assert_eq!(visible(doctor_id), vec![normal]);
+        assert!(restricted(&clinic, doctor_id, CATEGORY));
+    }
+
+    #[test]
+    fn only_an_authorized_doctor_reclassifies_a_restricted_record() {
+        let clinic = clinic();
+        let doctor_id = colleague(&clinic);
+        let record_id = record(&clinic, Some(Sensitivity::Restricted(CATEGORY)));
+        let declassify = |doctor_id| {
+            set_record_sensitivity(ClassifyRecordPayload {
+                doctor_id,
+                doctor_password: PASSWORD.to_string(),
+                record_id,
+                sensitivity: None,
+            })
+        };
+        assert!(refused(declassify(doctor_id)));
+        assert!(is_restricted(record_id));
+        must(grant_restricted_access(
+            consent(&clinic),
+            doctor_id,
+            CATEGORY,
+        ));
+        must(declassify(doctor_id));
+        assert!(!is_restricted(record_id));
+    }
+}
@@ -0,0 +1,384 @@
+use crate::time;
+use crate::{
+    add_months, all_appointments, appointment_view, authenticate_patient,
+    authorize_appointment_actor, check_site, get_appointment, impl_storable, next_id, notify,
+    parse_local_time, place_appointment, release_appointment, reschedule_appointment,
+    save_appointment, text, utc_offset, Appointment, AppointmentActor, AppointmentStatus,
+    AppointmentView, Error, Memory, Priority, Recipient, DOCTOR_STORAGE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const WEEK_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+// occurrences are turned into appointments this far ahead
+const HORIZON_NS: u64 = 5 * WEEK_NS;
+const MAX_OCCURRENCES: u32 = 104;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub enum Recurrence {
+    Weekly { interval_weeks: u32 },
+    // same day of the month, or its last day when the month is shorter
+    Monthly { interval_months: u32 },
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum SeriesScope {
+    ThisOccurrence,
+    AllFuture,
+}
+
+// A recurring appointment. Occurrences become appointments shortly before they are due, so
+// open-ended series do not fill the schedule
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AppointmentSeries {
+    pub id: u64,
+    pub patient_id: u64,
+    pub doctor_id: u64,
+    pub hospital_id: u64,
+    pub site_id: Option<u64>,
+    pub first_start: u64,
+    pub duration: u64,
+    pub recurrence: Recurrence,
+    pub reason: String,
+    // the series ends after this many occurrences or at this time, whichever comes first
+    pub occurrences: Option<u32>,
+    pub until: Option<u64>,
+    // number of occurrences already turned into appointments or skipped
+    pub materialized: u32,
+    // starts of occurrences that clashed with another appointment
+    pub skipped: Vec<u64>,
+    pub created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SeriesView {
+    pub series: AppointmentSeries,
+    pub appointments: Vec<AppointmentView>,
+}
+
+impl_storable!(AppointmentSeries, 2048);
+
+thread_local! {
+    static SERIES_STORAGE: RefCell<StableBTreeMap<u64, AppointmentSeries, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct BookSeriesPayload {
+    pub patient_id: u64,
+    pub patient_password: String,
+    pub doctor_id: u64,
+    pub site_id: Option<u64>,
+    // local times of the first occurrence
+    pub start: String,
+    pub end: String,
+    pub recurrence: Recurrence,
+    pub occurrences: Option<u32>,
+    pub until: Option<String>,
+    pub reason: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EditSeriesPayload {
+    pub actor: AppointmentActor,
+    // the occurrence the change starts from
+    pub appointment_id: u64,
+    pub scope: SeriesScope,
+    // new local times of that occurrence, later ones keep the same recurrence
+    pub start: String,
+    pub end: String,
+}
+
+fn get_series(series_id: u64) -> Result<AppointmentSeries, Error> {
+    SERIES_STORAGE
+        .with(|s| s.borrow().get(&series_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Appointment series of id: {} not found", series_id),
+        })
+}
+
+fn save_series(series: &AppointmentSeries) {
+    SERIES_STORAGE.with(|s| s.borrow_mut().insert(series.id, series.clone()));
+}
+
+fn series_appointments(series_id: u64) -> Vec<Appointment> {
+    let mut appointments: Vec<Appointment> = all_appointments()
+        .into_iter()
+        .filter(|appointment| appointment.series_id == Some(series_id))
+        .collect();
+    appointments.sort_by_key(|appointment| appointment.start);
+    appointments
+}
+
+// start of the nth occurrence, None once the series has ended
+fn occurrence_start(series: &AppointmentSeries, n: u32) -> Option<u64> {
+    if series.occurrences.is_some_and(|count| n >= count) || n >= MAX_OCCURRENCES {
+        return None;
+    }
+    let start = match series.recurrence {
+        Recurrence::Weekly { interval_weeks } => {
+            series.first_start + n as u64 * interval_weeks as u64 * WEEK_NS
+        }
+        Recurrence::Monthly { interval_months } => add_months(
+            series.first_start,
+            n * interval_months,
+            utc_offset(&Recipient::Hospital(series.hospital_id)),
+        ),
+    };
+    match series.until {
+        Some(until) if start > until => None,
+        _ => Some(start),
+    }
+}
+
+// turn the occurrences inside the horizon into appointments, checking each for conflicts
+fn materialize(series: &mut AppointmentSeries) {
+    let now = time();
+    let doctor = match DOCTOR_STORAGE.with(|s| s.borrow().get(&series.doctor_id)) {
+        Some(doctor) => doctor,
+        None => return,
+    };
+    while let Some(start) = occurrence_start(series, series.materialized) {
+        if start > now + HORIZON_NS {
+            break;
+        }
+        series.materialized += 1;
+        if start < now {
+            continue;
+        }
+        let placed = place_appointment(
+            series.patient_id,
+            &doctor,
+            series.site_id,
+            start,
+            start + series.duration,
+            series.reason.clone(),
+            None,
+        );
+        match placed {
+            Ok(appointment) => save_appointment(&Appointment {
+                series_id: Some(series.id),
+                ..appointment
+            }),
+            Err(_) => {
+                series.skipped.push(start);
+                for recipient in [
+                    Recipient::Patient(series.patient_id),
+                    Recipient::Doctor(series.doctor_id),
+                ] {
+                    notify(
+                        recipient,
+                        Priority::Normal,
+                        text(
+                            "series.occurrence_skipped",
+                            "An occurrence of appointment series {series} was skipped because the slot is taken",
+                            vec![("series", series.id.to_string())],
+                        ),
+                    );
+                }
+            }
+        }
+    }
+    save_series(series);
+}
+
+// timer job: keep every series materialized up to the horizon
+pub(crate) fn materialize_appointment_series() {
+    let all: Vec<AppointmentSeries> =
+        SERIES_STORAGE.with(|s| s.borrow().iter().map(|(_, series)| series).collect());
+    for mut series in all {
+        materialize(&mut series);
+    }
+}
+
+fn series_view(series: AppointmentSeries, viewer: &Recipient) -> SeriesView {
+    SeriesView {
+        appointments: series_appointments(series.id)
+            .into_iter()
+            .map(|appointment| appointment_view(appointment, viewer))
+            .collect(),
+        series,
+    }
+}
+
+// end the series before the given occurrence and cancel its appointments from there on
+fn end_series_at(series: &mut AppointmentSeries, start: u64) -> Result<(), Error> {
+    series.until = Some(start - 1);
+    save_series(series);
+    for appointment in series_appointments(series.id) {
+        if appointment.start >= start
+            && matches!(
+                appointment.status,
+                AppointmentStatus::Scheduled | AppointmentStatus::Held
+            )
+        {
+            release_appointment(appointment)?;
+        }
+    }
+    Ok(())
+}
+
+// end every series of the patient that still has occurrences after the given time
+pub(crate) fn end_patient_series(patient_id: u64, from: u64) -> Result<(), Error> {
+    let series_of_patient: Vec<AppointmentSeries> = SERIES_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, series)| series)
+            .filter(|series| series.patient_id == patient_id)
+            .collect()
+    });
+    for mut series in series_of_patient {
+        if series.until.is_some_and(|until| until < from) {
+            continue;
+        }
+        end_series_at(&mut series, from)?;
+    }
+    Ok(())
+}
+
+fn check_recurrence(recurrence: Recurrence) -> Result<(), Error> {
+    let interval = match recurrence {
+        Recurrence::Weekly { interval_weeks } => interval_weeks,
+        Recurrence::Monthly { interval_months } => interval_months,
+    };
+    if !(1..=12).contains(&interval) {
+        return Err(Error::InvalidPayload {
+            msg: "Recurrence interval must be between 1 and 12".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// patient books a recurring appointment, e.g. weekly physio or a monthly checkup
+#[ic_cdk::update]
+fn book_appointment_series(payload: BookSeriesPayload) -> Result<SeriesView, Error> {
+    let patient = authenticate_patient(payload.patient_id, &payload.patient_password)?;
+    let doctor = DOCTOR_STORAGE
+        .with(|s| s.borrow().get(&payload.doctor_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Doctor of id: {} not found", payload.doctor_id),
+        })?;
+    check_recurrence(payload.recurrence)?;
+    check_site(doctor.hospital_id, payload.site_id)?;
+    let offset = utc_offset(&Recipient::Hospital(doctor.hospital_id));
+    let start = parse_local_time(&payload.start, offset)?;
+    let end = parse_local_time(&payload.end, offset)?;
+    let until = match &payload.until {
+        Some(until) => Some(parse_local_time(until, offset)?),
+        None => None,
+    };
+    if end <= start || start < time() {
+        return Err(Error::InvalidPayload {
+            msg: "The first occurrence must be in the future and end after it starts".to_string(),
+        });
+    }
+
+    let mut series = AppointmentSeries {
+        id: next_id(),
+        patient_id: patient.id,
+        doctor_id: doctor.id,
+        hospital_id: doctor.hospital_id,
+        site_id: payload.site_id,
+        first_start: start,
+        duration: end - start,
+        recurrence: payload.recurrence,
+        reason: payload.reason,
+        occurrences: payload.occurrences,
+        until,
+        materialized: 0,
+        skipped: vec![],
+        created_at: time(),
+    };
+    materialize(&mut series);
+    Ok(series_view(series, &Recipient::Patient(patient.id)))
+}
+
+// move one occurrence, or it and every later one, to a new time
+#[ic_cdk::update]
+fn edit_appointment_series(payload: EditSeriesPayload) -> Result<SeriesView, Error> {
+    let appointment = get_appointment(payload.appointment_id)?;
+    let mut series = get_series(appointment.series_id.ok_or(Error::InvalidPayload {
+        msg: format!(
+            "Appointment of id: {} is not part of a series",
+            appointment.id
+        ),
+    })?)?;
+    let viewer = authorize_appointment_actor(&payload.actor, series.patient_id, series.doctor_id)?;
+    let offset = utc_offset(&Recipient::Hospital(series.hospital_id));
+    let start = parse_local_time(&payload.start, offset)?;
+    let end = parse_local_time(&payload.end, offset)?;
+    if end <= start {
+        return Err(Error::InvalidPayload {
+            msg: "Appointment must end after it starts".to_string(),
+        });
+    }
+
+    match payload.scope {
+        SeriesScope::ThisOccurrence => {
+            reschedule_appointment(appointment, start, end)?;
+            Ok(series_view(series, &viewer))
+        }
+        // split the series: the old one ends here and a new one continues at the new time
+        SeriesScope::AllFuture => {
+            let done = series_appointments(series.id)
+                .iter()
+                .filter(|earlier| earlier.start < appointment.start)
+                .count()
+                + series
+                    .skipped
+                    .iter()
+                    .filter(|skipped| **skipped < appointment.start)
+                    .count();
+            end_series_at(&mut series, appointment.start)?;
+            let mut continued = AppointmentSeries {
+                id: next_id(),
+                first_start: start,
+                duration: end - start,
+                occurrences: series
+                    .occurrences
+                    .map(|count| count.saturating_sub(done as u32)),
+                until: None,
+                materialized: 0,
+                skipped: vec![],
+                created_at: time(),
+                ..series
+            };
+            materialize(&mut continued);
+            Ok(series_view(continued, &viewer))
+        }
+    }
+}
+
+// cancel one occurrence, or it and every later one
+#[ic_cdk::update]
+fn cancel_appointment_series(
+    appointment_id: u64,
+    scope: SeriesScope,
+    actor: AppointmentActor,
+) -> Result<SeriesView, Error> {
+    let appointment = get_appointment(appointment_id)?;
+    let mut series = get_series(appointment.series_id.ok_or(Error::InvalidPayload {
+        msg: format!(
+            "Appointment of id: {} is not part of a series",
+            appointment.id
+        ),
+    })?)?;
+    let viewer = authorize_appointment_actor(&actor, series.patient_id, series.doctor_id)?;
+    match scope {
+        SeriesScope::ThisOccurrence => {
+            release_appointment(appointment)?;
+        }
+        SeriesScope::AllFuture => end_series_at(&mut series, appointment.start)?,
+    }
+    Ok(series_view(series, &viewer))
+}
+
+#[ic_cdk::query]
+fn get_appointment_series(series_id: u64, actor: AppointmentActor) -> Result<SeriesView, Error> {
+    let series = get_series(series_id)?;
+    let viewer = authorize_appointment_actor(&actor, series.patient_id, series.doctor_id)?;
+    Ok(series_view(series, &viewer))
+}
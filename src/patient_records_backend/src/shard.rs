@@ -0,0 +1,162 @@
+use crate::time;
+use crate::{
+    authorize_controller, caller, impl_storable, next_id, patient_header, patient_records, Error,
+    MedicalRecord, Memory, MEMORY_MANAGER,
+};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Another deployment of this canister holding part of the records
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct RecordShard {
+    pub id: u64,
+    pub canister_id: Principal,
+    pub label: String,
+    pub added_at: u64,
+}
+
+impl_storable!(RecordShard, 256);
+
+thread_local! {
+    static SHARD_STORAGE: RefCell<StableBTreeMap<u64, RecordShard, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    // patient ids are only unique within a shard, so a patient is known globally by the shard
+    // that asks and its id there: (shard id, patient id on that shard) -> local patient id
+    static SHARD_PATIENT_LINKS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(149)))
+    ));
+}
+
+pub(crate) fn record_shards() -> Vec<RecordShard> {
+    SHARD_STORAGE.with(|s| s.borrow().iter().map(|(_, shard)| shard).collect())
+}
+
+fn shard_of(canister_id: &Principal) -> Option<RecordShard> {
+    record_shards()
+        .into_iter()
+        .find(|shard| &shard.canister_id == canister_id)
+}
+
+fn is_shard(canister_id: &Principal) -> bool {
+    shard_of(canister_id).is_some()
+}
+
+// records of a patient gathered from every registered shard, skipping shards that fail to answer.
+// peers resolve the local id through their links to this shard. only callable from composite
+// queries and updates
+pub(crate) async fn shard_patient_records(patient_id: u64) -> (Vec<MedicalRecord>, Vec<Principal>) {
+    let mut records = vec![];
+    let mut unavailable = vec![];
+    for shard in record_shards() {
+        let reply: Result<(Result<Vec<MedicalRecord>, Error>,), _> = ic_cdk::call(
+            shard.canister_id,
+            "get_shard_patient_records",
+            (patient_id,),
+        )
+        .await;
+        match reply {
+            Ok((Ok(shard_records),)) => records.extend(shard_records),
+            _ => unavailable.push(shard.canister_id),
+        }
+    }
+    (records, unavailable)
+}
+
+// register a peer canister; registration must be done on both sides
+#[ic_cdk::update]
+fn register_record_shard(canister_id: Principal, label: String) -> Result<RecordShard, Error> {
+    authorize_controller()?;
+    if is_shard(&canister_id) {
+        return Err(Error::AlreadyInit {
+            msg: format!("Shard {} is already registered", canister_id),
+        });
+    }
+    let shard = RecordShard {
+        id: next_id(),
+        canister_id,
+        label,
+        added_at: time(),
+    };
+    SHARD_STORAGE.with(|s| s.borrow_mut().insert(shard.id, shard.clone()));
+    Ok(shard)
+}
+
+#[ic_cdk::update]
+fn remove_record_shard(shard_id: u64) -> Result<RecordShard, Error> {
+    authorize_controller()?;
+    let shard = SHARD_STORAGE
+        .with(|s| s.borrow_mut().remove(&shard_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Shard of id: {} not found", shard_id),
+        })?;
+    SHARD_PATIENT_LINKS.with(|s| {
+        let mut links = s.borrow_mut();
+        let keys: Vec<(u64, u64)> = links
+            .range((shard_id, 0)..=(shard_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            links.remove(&key);
+        }
+    });
+    Ok(shard)
+}
+
+// record that patient_id here is the same person as shard_patient_id on the given shard
+#[ic_cdk::update]
+fn link_shard_patient(shard_id: u64, shard_patient_id: u64, patient_id: u64) -> Result<(), Error> {
+    authorize_controller()?;
+    if !SHARD_STORAGE.with(|s| s.borrow().contains_key(&shard_id)) {
+        return Err(Error::NotFound {
+            msg: format!("Shard of id: {} not found", shard_id),
+        });
+    }
+    if patient_header(patient_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("Patient of id: {} not found", patient_id),
+        });
+    }
+    SHARD_PATIENT_LINKS.with(|s| {
+        s.borrow_mut()
+            .insert((shard_id, shard_patient_id), patient_id)
+    });
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn unlink_shard_patient(shard_id: u64, shard_patient_id: u64) -> Result<u64, Error> {
+    authorize_controller()?;
+    SHARD_PATIENT_LINKS
+        .with(|s| s.borrow_mut().remove(&(shard_id, shard_patient_id)))
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "Patient {} of shard {} is not linked",
+                shard_patient_id, shard_id
+            ),
+        })
+}
+
+#[ic_cdk::query]
+fn get_record_shards() -> Result<Vec<RecordShard>, Error> {
+    authorize_controller()?;
+    Ok(record_shards())
+}
+
+// answer record reads from peer shards; patient authorization happens on the calling shard.
+// patient_id is the caller's id, a patient not linked to it has no records here
+#[ic_cdk::query]
+fn get_shard_patient_records(patient_id: u64) -> Result<Vec<MedicalRecord>, Error> {
+    let shard = shard_of(&caller()).ok_or(Error::Unauthorized {
+        msg: "Caller is not a registered shard".to_string(),
+    })?;
+    Ok(SHARD_PATIENT_LINKS
+        .with(|s| s.borrow().get(&(shard.id, patient_id)))
+        .map(patient_records)
+        .unwrap_or_default())
+}
@@ -0,0 +1,304 @@
+use crate::time;
+use crate::{
+    audit, authenticate_patient, authorize_hospital, authorize_patient, check_not_sealed,
+    check_residency, impl_storable, issue_consent_receipt, next_id, patient_caregivers,
+    patient_encounters, patient_history, patient_tokens, scope_labels, Actor, AppToken, BloodType,
+    CaregiverGrant, ConsentAction, Encounter, Error, HistoryEntry, Memory, Patient,
+    TransferDestination, MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Parts of a patient's record that can be shared with another hospital
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum RecordCategory {
+    Demographics,
+    History,
+    BloodType,
+    Encounters,
+}
+
+// A data-sharing agreement the patient consented to
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SharingAgreement {
+    pub id: u64,
+    pub from_hospital_id: u64,
+    pub to_hospital_id: u64,
+    pub patient_id: u64,
+    pub scope: Vec<RecordCategory>,
+    pub granted_at: u64,
+    pub expires_at: Option<u64>,
+    pub revoked_at: Option<u64>,
+}
+
+impl SharingAgreement {
+    pub fn is_active(&self, now: u64) -> bool {
+        match self.expires_at {
+            _ if self.revoked_at.is_some() => false,
+            Some(expiry) => now < expiry,
+            None => true,
+        }
+    }
+}
+
+// The parts of a record a receiving hospital may see under an agreement
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SharedRecord {
+    pub patient_id: u64,
+    pub name: Option<String>,
+    // history entries with their amendments
+    pub history: Option<Vec<HistoryEntry>>,
+    pub blood_type: Option<BloodType>,
+    pub encounters: Option<Vec<Encounter>>,
+}
+
+// Who has access to a patient's data: doctors through consent, other hospitals through sharing
+// agreements, apps through tokens and caregivers through delegated grants
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AccessReview {
+    pub doctors_ids: Vec<u64>,
+    pub hospitals_ids: Vec<u64>,
+    pub sharing_agreements: Vec<SharingAgreement>,
+    pub app_tokens: Vec<AppToken>,
+    pub caregivers: Vec<CaregiverGrant>,
+}
+
+impl_storable!(SharingAgreement, 512);
+
+thread_local! {
+    static SHARING_STORAGE: RefCell<StableBTreeMap<u64, SharingAgreement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct PatientConsent {
+    pub patient_id: u64,
+    pub patient_password: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SharePatientPayload {
+    pub from_hospital_id: u64,
+    pub from_hospital_password: String,
+    pub to_hospital_id: u64,
+    pub scope: Vec<RecordCategory>,
+    pub expires_at: Option<u64>,
+    pub patient_consent: PatientConsent,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SharedRecordPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub agreement_id: u64,
+}
+
+fn get_agreement(agreement_id: u64) -> Result<SharingAgreement, Error> {
+    SHARING_STORAGE
+        .with(|s| s.borrow().get(&agreement_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Sharing agreement of id: {} not found", agreement_id),
+        })
+}
+
+pub(crate) fn shared_record(agreement: &SharingAgreement, patient: &Patient) -> SharedRecord {
+    let allowed = |category| agreement.scope.contains(&category);
+    SharedRecord {
+        patient_id: patient.id,
+        name: allowed(RecordCategory::Demographics).then(|| patient.name.clone()),
+        history: allowed(RecordCategory::History).then(|| patient_history(patient.id)),
+        blood_type: if allowed(RecordCategory::BloodType) {
+            patient.blood_type
+        } else {
+            None
+        },
+        encounters: allowed(RecordCategory::Encounters).then(|| patient_encounters(patient.id)),
+    }
+}
+
+// grant a second hospital access to selected parts of a patient's record
+#[ic_cdk::update]
+fn share_patient_with_hospital(payload: SharePatientPayload) -> Result<SharingAgreement, Error> {
+    let from_hospital =
+        authorize_hospital(payload.from_hospital_id, &payload.from_hospital_password)?;
+    let patient = authorize_patient(
+        payload.patient_consent.patient_id,
+        &payload.patient_consent.patient_password,
+    )?;
+    if !from_hospital.patients_ids.contains(&patient.id) {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "Patient {} is not a patient of hospital {}",
+                patient.id, from_hospital.id
+            ),
+        });
+    }
+    if payload.to_hospital_id == from_hospital.id
+        || crate::HOSPITAL_STORAGE.with(|s| !s.borrow().contains_key(&payload.to_hospital_id))
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("Cannot share with hospital {}", payload.to_hospital_id),
+        });
+    }
+    if payload.scope.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Sharing scope cannot be empty".to_string(),
+        });
+    }
+    check_residency(
+        &patient,
+        &TransferDestination::Hospital(payload.to_hospital_id),
+        Actor::Hospital(from_hospital.id),
+        Some(from_hospital.id),
+        "sharing agreement",
+    )?;
+
+    let agreement = SharingAgreement {
+        id: next_id(),
+        from_hospital_id: from_hospital.id,
+        to_hospital_id: payload.to_hospital_id,
+        patient_id: patient.id,
+        scope: payload.scope,
+        granted_at: time(),
+        expires_at: payload.expires_at,
+        revoked_at: None,
+    };
+    SHARING_STORAGE.with(|s| s.borrow_mut().insert(agreement.id, agreement.clone()));
+    audit(
+        Actor::Hospital(from_hospital.id),
+        Some(from_hospital.id),
+        Some(patient.id),
+        "record_shared",
+        format!(
+            "agreement {} with hospital {}",
+            agreement.id, agreement.to_hospital_id
+        ),
+    );
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Granted,
+        "data_sharing",
+        agreement.id,
+        format!("hospital {}", agreement.to_hospital_id),
+        scope_labels(&agreement.scope),
+        agreement.expires_at,
+    );
+    Ok(agreement)
+}
+
+// the patient revokes an agreement, access stops immediately
+#[ic_cdk::update]
+fn revoke_data_sharing(
+    consent: PatientConsent,
+    agreement_id: u64,
+) -> Result<SharingAgreement, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    let agreement = get_agreement(agreement_id)?;
+    if agreement.patient_id != patient.id || agreement.revoked_at.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Sharing agreement of id: {} cannot be revoked",
+                agreement_id
+            ),
+        });
+    }
+    let revoked = SharingAgreement {
+        revoked_at: Some(time()),
+        ..agreement
+    };
+    SHARING_STORAGE.with(|s| s.borrow_mut().insert(revoked.id, revoked.clone()));
+    audit(
+        Actor::Patient(patient.id),
+        Some(revoked.from_hospital_id),
+        Some(patient.id),
+        "record_sharing_revoked",
+        format!("agreement {}", revoked.id),
+    );
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Revoked,
+        "data_sharing",
+        revoked.id,
+        format!("hospital {}", revoked.to_hospital_id),
+        scope_labels(&revoked.scope),
+        revoked.expires_at,
+    );
+    Ok(revoked)
+}
+
+// agreements a patient has given, including revoked and expired ones
+#[ic_cdk::query]
+fn get_patient_sharing_agreements(consent: PatientConsent) -> Result<Vec<SharingAgreement>, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    Ok(patient_sharing_agreements(patient.id))
+}
+
+pub(crate) fn patient_sharing_agreements(patient_id: u64) -> Vec<SharingAgreement> {
+    SHARING_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, agreement)| agreement)
+            .filter(|agreement| agreement.patient_id == patient_id)
+            .collect()
+    })
+}
+
+// everyone who can currently see some of the patient's data, for the patient to review
+#[ic_cdk::query]
+fn get_access_review(consent: PatientConsent) -> Result<AccessReview, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    let now = time();
+    Ok(AccessReview {
+        doctors_ids: patient.doctors_ids.clone(),
+        hospitals_ids: patient.hospitals_ids.clone(),
+        sharing_agreements: patient_sharing_agreements(patient.id)
+            .into_iter()
+            .filter(|agreement| agreement.is_active(now))
+            .collect(),
+        app_tokens: patient_tokens(patient.id)
+            .into_iter()
+            .filter(|token| token.revoked_at.is_none() && token.expires_at > now)
+            .collect(),
+        caregivers: patient_caregivers(patient.id)
+            .into_iter()
+            .filter(|grant| grant.is_active(now))
+            .collect(),
+    })
+}
+
+// receiving hospital reads the shared parts of the record; reads are audited
+#[ic_cdk::update]
+fn get_shared_patient_record(payload: SharedRecordPayload) -> Result<SharedRecord, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let agreement = get_agreement(payload.agreement_id)?;
+    if agreement.to_hospital_id != hospital.id || !agreement.is_active(time()) {
+        return Err(Error::Unauthorized {
+            msg: format!("Sharing agreement of id: {} is not active", agreement.id),
+        });
+    }
+    check_not_sealed(agreement.patient_id)?;
+    let patient = PATIENT_STORAGE
+        .with(|s| s.borrow().get(&agreement.patient_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Patient of id: {} not found", agreement.patient_id),
+        })?;
+    // the rules may have changed since the agreement was made
+    check_residency(
+        &patient,
+        &TransferDestination::Hospital(hospital.id),
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        "shared record read",
+    )?;
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        Some(patient.id),
+        "shared_record_read",
+        format!("agreement {}", agreement.id),
+    );
+    Ok(shared_record(&agreement, &patient))
+}
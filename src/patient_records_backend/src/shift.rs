@@ -0,0 +1,663 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_hospital, authorize_nurse, catalog_entry, get_nurse,
+    impl_storable, next_id, Actor, CatalogKind, Doctor, Error, Memory, DOCTOR_STORAGE,
+    MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MINUTE_NS: u64 = 60 * 1_000_000_000;
+const MAX_SHIFT_MINUTES: u64 = 24 * 60;
+const MAX_SHIFT_NAME_BYTES: usize = 64;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum StaffRef {
+    Doctor(u64),
+    Nurse(u64),
+}
+
+// A reusable shift pattern such as "Night" starting at 22:00 for 8 hours
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ShiftDefinition {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub name: String,
+    pub start_minute: u64,
+    pub duration_minutes: u64,
+    pub on_call: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ShiftAssignment {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub definition_id: u64,
+    pub staff: StaffRef,
+    pub start: u64,
+    pub end: u64,
+    pub on_call: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum SwapStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ShiftSwapRequest {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub assignment_id: u64,
+    pub from_staff: StaffRef,
+    pub to_staff: StaffRef,
+    pub status: SwapStatus,
+    pub requested_at: u64,
+    pub decided_at: Option<u64>,
+}
+
+impl_storable!(ShiftDefinition, 256);
+impl_storable!(ShiftAssignment, 256);
+impl_storable!(ShiftSwapRequest, 256);
+
+thread_local! {
+    static SHIFT_DEFINITION_STORAGE: RefCell<StableBTreeMap<u64, ShiftDefinition, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    static SHIFT_ASSIGNMENT_STORAGE: RefCell<StableBTreeMap<u64, ShiftAssignment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    static SHIFT_SWAP_STORAGE: RefCell<StableBTreeMap<u64, ShiftSwapRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ShiftDefinitionPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub name: String,
+    pub start_minute: u64,
+    pub duration_minutes: u64,
+    pub on_call: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AssignShiftPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub definition_id: u64,
+    pub staff: StaffRef,
+    // start of the day (UTC nanoseconds) the shift begins on
+    pub day_start: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SpecialtyPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub doctor_id: u64,
+    pub specialty: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SwapRequestPayload {
+    pub staff: StaffRef,
+    pub password: String,
+    pub assignment_id: u64,
+    pub to_staff: StaffRef,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SwapDecisionPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub request_id: u64,
+    pub approve: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct HospitalRotaPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub from: u64,
+    pub to: u64,
+}
+
+// helper function to get the hospital a staff member works at
+fn staff_hospital(staff: &StaffRef) -> Result<u64, Error> {
+    match staff {
+        StaffRef::Doctor(id) => DOCTOR_STORAGE
+            .with(|s| s.borrow().get(id))
+            .map(|doctor| doctor.hospital_id),
+        StaffRef::Nurse(id) => get_nurse(*id).map(|nurse| nurse.hospital_id),
+    }
+    .ok_or(Error::NotFound {
+        msg: "Staff member not found".to_string(),
+    })
+}
+
+fn authorize_staff(staff: &StaffRef, password: &str) -> Result<(), Error> {
+    match staff {
+        StaffRef::Doctor(id) => authorize_doctor(*id, password).map(|_| ()),
+        StaffRef::Nurse(id) => authorize_nurse(*id, password).map(|_| ()),
+    }
+}
+
+fn save_assignment(assignment: &ShiftAssignment) {
+    SHIFT_ASSIGNMENT_STORAGE.with(|s| s.borrow_mut().insert(assignment.id, assignment.clone()));
+}
+
+// reject a shift that overlaps another shift of the same staff member
+fn check_overlap(staff: &StaffRef, start: u64, end: u64, ignore_id: u64) -> Result<(), Error> {
+    let overlap = SHIFT_ASSIGNMENT_STORAGE.with(|s| {
+        s.borrow().iter().map(|(_, shift)| shift).find(|shift| {
+            shift.id != ignore_id && shift.staff == *staff && shift.start < end && start < shift.end
+        })
+    });
+    match overlap {
+        Some(shift) => Err(Error::InvalidPayload {
+            msg: format!(
+                "Shift overlaps shift {} from {} to {}",
+                shift.id, shift.start, shift.end
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+// doctors of the hospital on an on-call shift at the given time
+pub(crate) fn on_call_doctors(hospital_id: u64, specialty: Option<String>, at: u64) -> Vec<Doctor> {
+    let wanted = specialty.map(|specialty| specialty.to_lowercase());
+    let doctor_ids: Vec<u64> = SHIFT_ASSIGNMENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, shift)| shift)
+            .filter(|shift| {
+                shift.hospital_id == hospital_id
+                    && shift.on_call
+                    && shift.start <= at
+                    && at < shift.end
+            })
+            .filter_map(|shift| match shift.staff {
+                StaffRef::Doctor(id) => Some(id),
+                StaffRef::Nurse(_) => None,
+            })
+            .collect()
+    });
+    doctor_ids
+        .into_iter()
+        .filter_map(|id| DOCTOR_STORAGE.with(|s| s.borrow().get(&id)))
+        .filter(|doctor| match (&wanted, &doctor.specialty) {
+            (None, _) => true,
+            (Some(wanted), Some(specialty)) => specialty.to_lowercase() == *wanted,
+            (Some(_), None) => false,
+        })
+        .collect()
+}
+
+// the on-call doctor to page, one of the patient's own doctors when any of them is on call
+pub(crate) fn on_call_doctor(hospital_id: u64, care_team: &[u64], at: u64) -> Option<u64> {
+    let on_call: Vec<u64> = on_call_doctors(hospital_id, None, at)
+        .into_iter()
+        .map(|doctor| doctor.id)
+        .collect();
+    on_call
+        .iter()
+        .find(|id| care_team.contains(id))
+        .or(on_call.first())
+        .copied()
+}
+
+// set the specialty a doctor is on call for, a specialty code of the catalog
+#[ic_cdk::update]
+fn set_doctor_specialty(payload: SpecialtyPayload) -> Result<Doctor, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let specialty = catalog_entry(&payload.specialty)?;
+    if specialty.kind != CatalogKind::Specialty {
+        return Err(Error::InvalidPayload {
+            msg: format!("{} is a service, not a specialty", specialty.code),
+        });
+    }
+    match DOCTOR_STORAGE.with(|s| s.borrow().get(&payload.doctor_id)) {
+        Some(doctor) if doctor.hospital_id == hospital.id => {
+            let updated = Doctor {
+                specialty: Some(specialty.code),
+                ..doctor
+            };
+            DOCTOR_STORAGE.with(|s| s.borrow_mut().insert(updated.id, updated.clone()));
+            Ok(Doctor {
+                password: "-".to_string(),
+                ..updated
+            })
+        }
+        _ => Err(Error::NotFound {
+            msg: format!(
+                "Doctor of id: {} not found at hospital {}",
+                payload.doctor_id, hospital.id
+            ),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn add_shift_definition(payload: ShiftDefinitionPayload) -> Result<ShiftDefinition, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if payload.start_minute >= 24 * 60
+        || payload.duration_minutes == 0
+        || payload.duration_minutes > MAX_SHIFT_MINUTES
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Shift must start within the day and last from a minute to {} minutes",
+                MAX_SHIFT_MINUTES
+            ),
+        });
+    }
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_SHIFT_NAME_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("Shift names hold 1 to {} bytes", MAX_SHIFT_NAME_BYTES),
+        });
+    }
+    let definition = ShiftDefinition {
+        id: next_id(),
+        hospital_id: hospital.id,
+        name: payload.name,
+        start_minute: payload.start_minute,
+        duration_minutes: payload.duration_minutes,
+        on_call: payload.on_call,
+    };
+    SHIFT_DEFINITION_STORAGE.with(|s| s.borrow_mut().insert(definition.id, definition.clone()));
+    Ok(definition)
+}
+
+// put a doctor or nurse on a shift, rejecting overlaps with their other shifts
+#[ic_cdk::update]
+fn assign_shift(payload: AssignShiftPayload) -> Result<ShiftAssignment, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let definition = match SHIFT_DEFINITION_STORAGE.with(|s| s.borrow().get(&payload.definition_id))
+    {
+        Some(definition) if definition.hospital_id == hospital.id => definition,
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!(
+                    "Shift definition of id: {} not found",
+                    payload.definition_id
+                ),
+            })
+        }
+    };
+    if staff_hospital(&payload.staff)? != hospital.id {
+        return Err(Error::Unauthorized {
+            msg: "Staff member works at another hospital".to_string(),
+        });
+    }
+
+    let start = payload
+        .day_start
+        .checked_add(definition.start_minute * MINUTE_NS);
+    let end = start.and_then(|start| start.checked_add(definition.duration_minutes * MINUTE_NS));
+    let (Some(start), Some(end)) = (start, end) else {
+        return Err(Error::InvalidPayload {
+            msg: format!("Day start {} is out of range", payload.day_start),
+        });
+    };
+    check_overlap(&payload.staff, start, end, 0)?;
+    let assignment = ShiftAssignment {
+        id: next_id(),
+        hospital_id: hospital.id,
+        definition_id: definition.id,
+        staff: payload.staff,
+        start,
+        end,
+        on_call: definition.on_call,
+    };
+    save_assignment(&assignment);
+    Ok(assignment)
+}
+
+// shifts of the hospital overlapping a time window
+#[ic_cdk::query]
+fn get_hospital_rota(payload: HospitalRotaPayload) -> Result<Vec<ShiftAssignment>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let mut shifts: Vec<ShiftAssignment> = SHIFT_ASSIGNMENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, shift)| shift)
+            .filter(|shift| {
+                shift.hospital_id == hospital.id
+                    && shift.start < payload.to
+                    && payload.from < shift.end
+            })
+            .collect()
+    });
+    shifts.sort_by_key(|shift| shift.start);
+    Ok(shifts)
+}
+
+// find an on-call doctor of a hospital, optionally of a specialty, at a given time
+#[ic_cdk::query]
+fn get_on_call_doctor(
+    hospital_id: u64,
+    specialty: Option<String>,
+    at: u64,
+) -> Result<Doctor, Error> {
+    match on_call_doctors(hospital_id, specialty, at)
+        .into_iter()
+        .next()
+    {
+        Some(doctor) => Ok(Doctor {
+            password: "-".to_string(),
+            patient_ids: vec![],
+            ..doctor
+        }),
+        None => Err(Error::NotFound {
+            msg: format!("No on-call doctor at hospital {} at {}", hospital_id, at),
+        }),
+    }
+}
+
+// staff member asks to hand one of their shifts to a colleague
+#[ic_cdk::update]
+fn request_shift_swap(payload: SwapRequestPayload) -> Result<ShiftSwapRequest, Error> {
+    authorize_staff(&payload.staff, &payload.password)?;
+    let assignment = match SHIFT_ASSIGNMENT_STORAGE.with(|s| s.borrow().get(&payload.assignment_id))
+    {
+        Some(assignment) if assignment.staff == payload.staff => assignment,
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!("Shift of id: {} not found for staff", payload.assignment_id),
+            })
+        }
+    };
+    if assignment.start <= time() {
+        return Err(Error::InvalidPayload {
+            msg: "Shifts that already started cannot be swapped".to_string(),
+        });
+    }
+    if staff_hospital(&payload.to_staff)? != assignment.hospital_id {
+        return Err(Error::InvalidPayload {
+            msg: "Shifts can only be swapped with staff of the same hospital".to_string(),
+        });
+    }
+    let request = ShiftSwapRequest {
+        id: next_id(),
+        hospital_id: assignment.hospital_id,
+        assignment_id: assignment.id,
+        from_staff: payload.staff,
+        to_staff: payload.to_staff,
+        status: SwapStatus::Pending,
+        requested_at: time(),
+        decided_at: None,
+    };
+    SHIFT_SWAP_STORAGE.with(|s| s.borrow_mut().insert(request.id, request.clone()));
+    Ok(request)
+}
+
+// hospital admin approves or rejects a pending swap request
+#[ic_cdk::update]
+fn decide_shift_swap(payload: SwapDecisionPayload) -> Result<ShiftSwapRequest, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let request = match SHIFT_SWAP_STORAGE.with(|s| s.borrow().get(&payload.request_id)) {
+        Some(request) if request.hospital_id == hospital.id => request,
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!("Swap request of id: {} not found", payload.request_id),
+            })
+        }
+    };
+    if request.status != SwapStatus::Pending {
+        return Err(Error::InvalidPayload {
+            msg: format!("Swap request of id: {} was already decided", request.id),
+        });
+    }
+
+    let status = if payload.approve {
+        let assignment = SHIFT_ASSIGNMENT_STORAGE
+            .with(|s| s.borrow().get(&request.assignment_id))
+            .ok_or(Error::NotFound {
+                msg: format!("Shift of id: {} not found", request.assignment_id),
+            })?;
+        // the shift may have been handed over by another request or started since the ask
+        if assignment.staff != request.from_staff {
+            return Err(Error::InvalidPayload {
+                msg: format!("Shift {} is no longer held by the requester", assignment.id),
+            });
+        }
+        if assignment.start <= time() {
+            return Err(Error::InvalidPayload {
+                msg: "Shifts that already started cannot be swapped".to_string(),
+            });
+        }
+        check_overlap(
+            &request.to_staff,
+            assignment.start,
+            assignment.end,
+            assignment.id,
+        )?;
+        save_assignment(&ShiftAssignment {
+            staff: request.to_staff,
+            ..assignment
+        });
+        SwapStatus::Approved
+    } else {
+        SwapStatus::Rejected
+    };
+    let decided = ShiftSwapRequest {
+        status,
+        decided_at: Some(time()),
+        ..request
+    };
+    SHIFT_SWAP_STORAGE.with(|s| s.borrow_mut().insert(decided.id, decided.clone()));
+    if decided.status == SwapStatus::Approved {
+        reject_other_swaps(&decided);
+    }
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(hospital.id),
+        None,
+        "shift_swap_decided",
+        format!("request {} approved: {}", decided.id, payload.approve),
+    );
+    Ok(decided)
+}
+
+// the other pending requests for a shift that was just handed over can no longer be approved
+fn reject_other_swaps(approved: &ShiftSwapRequest) {
+    let others: Vec<ShiftSwapRequest> = SHIFT_SWAP_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| {
+                request.assignment_id == approved.assignment_id
+                    && request.id != approved.id
+                    && request.status == SwapStatus::Pending
+            })
+            .collect()
+    });
+    for request in others {
+        let rejected = ShiftSwapRequest {
+            status: SwapStatus::Rejected,
+            decided_at: approved.decided_at,
+            ..request
+        };
+        SHIFT_SWAP_STORAGE.with(|s| s.borrow_mut().insert(rejected.id, rejected));
+    }
+}
+
+// pending swap requests waiting for the hospital admin
+#[ic_cdk::query]
+fn get_pending_shift_swaps(
+    payload: crate::HospitalAccessPayload,
+) -> Result<Vec<ShiftSwapRequest>, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    Ok(SHIFT_SWAP_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| {
+                request.hospital_id == hospital.id && request.status == SwapStatus::Pending
+            })
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{advance_clock, clinic, must, Clinic, PASSWORD};
+    use crate::{
+        add_nurse, add_patient, enqueue_ticket, inbox, NursePayload, PatientPayload, Recipient,
+        Urgency,
+    };
+
+    const DAY_NS: u64 = 24 * 60 * MINUTE_NS;
+
+    fn definition(clinic: &Clinic, duration_minutes: u64) -> Result<ShiftDefinition, Error> {
+        add_shift_definition(ShiftDefinitionPayload {
+            hospital_id: clinic.hospital_id,
+            hospital_password: PASSWORD.to_string(),
+            name: "Day".to_string(),
+            start_minute: 8 * 60,
+            duration_minutes,
+            on_call: false,
+        })
+    }
+
+    fn assign(
+        clinic: &Clinic,
+        definition_id: u64,
+        day_start: u64,
+    ) -> Result<ShiftAssignment, Error> {
+        assign_shift(AssignShiftPayload {
+            hospital_id: clinic.hospital_id,
+            hospital_password: PASSWORD.to_string(),
+            definition_id,
+            staff: StaffRef::Doctor(clinic.doctor_id),
+            day_start,
+        })
+    }
+
+    fn nurse(clinic: &Clinic, name: &str) -> StaffRef {
+        let nurse = must(add_nurse(NursePayload {
+            hospital_id: clinic.hospital_id,
+            hospital_password: PASSWORD.to_string(),
+            name: name.to_string(),
+            password: PASSWORD.to_string(),
+        }));
+        StaffRef::Nurse(nurse.id)
+    }
+
+    fn ask(clinic: &Clinic, assignment_id: u64, to_staff: StaffRef) -> ShiftSwapRequest {
+        must(request_shift_swap(SwapRequestPayload {
+            staff: StaffRef::Doctor(clinic.doctor_id),
+            password: PASSWORD.to_string(),
+            assignment_id,
+            to_staff,
+        }))
+    }
+
+    fn decide(clinic: &Clinic, request_id: u64) -> Result<ShiftSwapRequest, Error> {
+        decide_shift_swap(SwapDecisionPayload {
+            hospital_id: clinic.hospital_id,
+            hospital_password: PASSWORD.to_string(),
+            request_id,
+            approve: true,
+        })
+    }
+
+    #[test]
+    fn a_shift_is_handed_over_once() {
+        let clinic = clinic();
+        let definition = must(definition(&clinic, 8 * 60));
+        let shift = must(assign(&clinic, definition.id, time() + DAY_NS));
+        let first = ask(&clinic, shift.id, nurse(&clinic, "Nurse B"));
+        let second = ask(&clinic, shift.id, nurse(&clinic, "Nurse C"));
+        assert!(must(decide(&clinic, first.id)).status == SwapStatus::Approved);
+        assert!(matches!(
+            decide(&clinic, second.id),
+            Err(Error::InvalidPayload { .. })
+        ));
+        let second = SHIFT_SWAP_STORAGE
+            .with(|s| s.borrow().get(&second.id))
+            .unwrap();
+        assert!(second.status == SwapStatus::Rejected);
+    }
+
+    #[test]
+    fn a_started_shift_is_not_handed_over() {
+        let clinic = clinic();
+        let definition = must(definition(&clinic, 8 * 60));
+        let shift = must(assign(&clinic, definition.id, time()));
+        let request = ask(&clinic, shift.id, nurse(&clinic, "Nurse B"));
+        advance_clock(8 * 60 * MINUTE_NS);
+        assert!(matches!(
+            decide(&clinic, request.id),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn shifts_stay_within_a_day_and_the_clock_range() {
+        let clinic = clinic();
+        assert!(matches!(
+            definition(&clinic, MAX_SHIFT_MINUTES + 1),
+            Err(Error::InvalidPayload { .. })
+        ));
+        let definition = must(definition(&clinic, MAX_SHIFT_MINUTES));
+        assert!(matches!(
+            assign(&clinic, definition.id, u64::MAX - DAY_NS),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+
+    fn pages(recipient: Recipient) -> usize {
+        inbox(&recipient, false, None, 10).items.len()
+    }
+
+    #[test]
+    fn emergency_arrivals_page_the_on_call_doctor() {
+        let clinic = clinic();
+        let definition = must(add_shift_definition(ShiftDefinitionPayload {
+            hospital_id: clinic.hospital_id,
+            hospital_password: PASSWORD.to_string(),
+            name: "On call".to_string(),
+            start_minute: 0,
+            duration_minutes: MAX_SHIFT_MINUTES,
+            on_call: true,
+        }));
+        let day_start = time() - time() % DAY_NS;
+        must(assign(&clinic, definition.id, day_start));
+        must(enqueue_ticket(
+            clinic.hospital_id,
+            None,
+            clinic.patient_id,
+            Urgency::Standard,
+            "Sprained wrist".to_string(),
+        ));
+        assert_eq!(pages(Recipient::Doctor(clinic.doctor_id)), 0);
+        let other = must(add_patient(PatientPayload {
+            name: "Second patient".to_string(),
+            history: "No known conditions".to_string(),
+            password: PASSWORD.to_string(),
+            language: None,
+        }));
+        must(enqueue_ticket(
+            clinic.hospital_id,
+            None,
+            other.id,
+            Urgency::Emergency,
+            "Chest pain".to_string(),
+        ));
+        assert_eq!(pages(Recipient::Doctor(clinic.doctor_id)), 1);
+        assert_eq!(pages(Recipient::Hospital(clinic.hospital_id)), 0);
+    }
+}
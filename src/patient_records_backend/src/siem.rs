@@ -0,0 +1,252 @@
+use crate::time;
+use crate::{
+    audit, audit_page, authorize_oversight, impl_storable, oversight_actor, sign_hash,
+    signing_settings, AuditEntry, EntityRef, Error, Memory, OversightRole, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+// Where a hospital's audit export stands: what was handed out and what the SIEM confirmed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AuditExportState {
+    pub hospital_id: u64,
+    pub batches_issued: u64,
+    // sequence number of the last exported entry, the since_seq of the next batch
+    pub last_exported_seq: Option<u64>,
+    // chain hash of the last issued batch, empty before the first
+    pub head: Vec<u8>,
+    // last batch the SIEM acknowledged, every batch up to it counts as collected
+    pub acknowledged_batch: Option<u64>,
+    pub acknowledged_seq: Option<u64>,
+    pub acknowledged_at: Option<u64>,
+}
+
+// What is kept of an issued batch so it can be delivered again and acknowledged
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AuditBatchHeader {
+    pub batch_no: u64,
+    pub since_seq: Option<u64>,
+    pub last_seq: u64,
+    pub entry_count: u64,
+    // SHA-256 of entries_jsonl
+    pub entries_hash: Vec<u8>,
+    pub prev_hash: Vec<u8>,
+    // SHA-256 of prev_hash || batch_no (big endian) || entries_hash
+    pub chain_hash: Vec<u8>,
+    // the canister's secp256k1 signature over chain_hash, empty until signing succeeds
+    pub signature: Vec<u8>,
+    pub issued_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AuditBatch {
+    pub hospital_id: u64,
+    pub header: AuditBatchHeader,
+    // one json audit entry per line, exactly the bytes that were hashed
+    pub entries_jsonl: String,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl_storable!(AuditExportState, 512);
+impl_storable!(AuditBatchHeader, 512);
+
+thread_local! {
+    static AUDIT_EXPORT_STATE: RefCell<StableBTreeMap<u64, AuditExportState, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76)))
+    ));
+
+    // keyed by (hospital id, batch number)
+    static AUDIT_BATCHES: RefCell<StableBTreeMap<(u64, u64), AuditBatchHeader, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AuditExportPayload {
+    pub role: OversightRole,
+    pub password: String,
+    // last_exported_seq for the next batch, or the since_seq of an issued batch to get it again
+    pub since_seq: Option<u64>,
+    pub limit: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct AuditAckPayload {
+    pub role: OversightRole,
+    pub password: String,
+    pub batch_no: u64,
+    // the chain hash the SIEM computed, proving it holds the chain up to this batch
+    pub chain_hash: Vec<u8>,
+}
+
+fn export_state(hospital_id: u64) -> AuditExportState {
+    AUDIT_EXPORT_STATE
+        .with(|s| s.borrow().get(&hospital_id))
+        .unwrap_or(AuditExportState {
+            hospital_id,
+            ..Default::default()
+        })
+}
+
+fn entries_jsonl(entries: &[AuditEntry]) -> String {
+    entries
+        .iter()
+        .map(|entry| serde_json::to_string(entry).expect("Cannot serialize audit entry") + "\n")
+        .collect()
+}
+
+// the hospital's entries after since_seq, at most limit of them
+fn entries_since(hospital_id: u64, since_seq: Option<u64>, limit: u64) -> Vec<AuditEntry> {
+    audit_page(EntityRef::Hospital(hospital_id), since_seq, limit).items
+}
+
+// hand out the next hash-chained, canister-signed batch of the hospital's audit entries for
+// a SIEM to ingest, or an already issued batch again when since_seq is where it started.
+// None when there is nothing new to export. exports are not audited themselves, the chain
+// records them, so polling comes to rest
+#[ic_cdk::update]
+async fn export_audit_batch(payload: AuditExportPayload) -> Result<Option<AuditBatch>, Error> {
+    let hospital_id = authorize_oversight(&payload.role, &payload.password)?;
+    let state = export_state(hospital_id);
+
+    let (header, entries_jsonl) = if payload.since_seq == state.last_exported_seq {
+        let entries = entries_since(hospital_id, payload.since_seq, payload.limit);
+        let Some(last) = entries.last() else {
+            return Ok(None);
+        };
+        let entries_jsonl = entries_jsonl(&entries);
+        let entries_hash = Sha256::digest(entries_jsonl.as_bytes()).to_vec();
+        let batch_no = state.batches_issued;
+        let mut hasher = Sha256::new();
+        hasher.update(&state.head);
+        hasher.update(batch_no.to_be_bytes());
+        hasher.update(&entries_hash);
+        let header = AuditBatchHeader {
+            batch_no,
+            since_seq: payload.since_seq,
+            last_seq: last.seq,
+            entry_count: entries.len() as u64,
+            entries_hash,
+            prev_hash: state.head.clone(),
+            chain_hash: hasher.finalize().to_vec(),
+            signature: vec![],
+            issued_at: time(),
+        };
+        // the chain moves on before signing so concurrent calls cannot fork it
+        AUDIT_BATCHES.with(|s| {
+            s.borrow_mut()
+                .insert((hospital_id, batch_no), header.clone())
+        });
+        AUDIT_EXPORT_STATE.with(|s| {
+            s.borrow_mut().insert(
+                hospital_id,
+                AuditExportState {
+                    batches_issued: batch_no + 1,
+                    last_exported_seq: Some(header.last_seq),
+                    head: header.chain_hash.clone(),
+                    ..state
+                },
+            )
+        });
+        (header, entries_jsonl)
+    } else {
+        let header = AUDIT_BATCHES
+            .with(|s| {
+                s.borrow()
+                    .range((hospital_id, 0)..=(hospital_id, u64::MAX))
+                    .map(|(_, header)| header)
+                    .find(|header| header.since_seq == payload.since_seq)
+            })
+            .ok_or(Error::InvalidPayload {
+                msg: format!(
+                    "No batch starts after entry {:?}, the next batch starts after {:?}",
+                    payload.since_seq, state.last_exported_seq
+                ),
+            })?;
+        let entries = entries_since(hospital_id, header.since_seq, header.entry_count);
+        let entries_jsonl = entries_jsonl(&entries);
+        if Sha256::digest(entries_jsonl.as_bytes()).to_vec() != header.entries_hash {
+            return Err(Error::NotFound {
+                msg: format!(
+                    "Entries of batch {} were rolled up and can no longer be delivered",
+                    header.batch_no
+                ),
+            });
+        }
+        (header, entries_jsonl)
+    };
+
+    let header = if header.signature.is_empty() {
+        let signed = AuditBatchHeader {
+            signature: sign_hash(header.chain_hash.clone()).await?,
+            ..header
+        };
+        AUDIT_BATCHES.with(|s| {
+            s.borrow_mut()
+                .insert((hospital_id, signed.batch_no), signed.clone())
+        });
+        signed
+    } else {
+        header
+    };
+    Ok(Some(AuditBatch {
+        hospital_id,
+        header,
+        entries_jsonl,
+        public_key: signing_settings().public_key,
+    }))
+}
+
+// the SIEM confirms it stored every batch up to batch_no by echoing that batch's chain hash
+#[ic_cdk::update]
+fn acknowledge_audit_batch(payload: AuditAckPayload) -> Result<AuditExportState, Error> {
+    let hospital_id = authorize_oversight(&payload.role, &payload.password)?;
+    let header = AUDIT_BATCHES
+        .with(|s| s.borrow().get(&(hospital_id, payload.batch_no)))
+        .ok_or(Error::NotFound {
+            msg: format!("Audit batch {} not found", payload.batch_no),
+        })?;
+    if header.chain_hash != payload.chain_hash {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Chain hash does not match batch {}, the SIEM copy is incomplete or altered",
+                header.batch_no
+            ),
+        });
+    }
+    let state = export_state(hospital_id);
+    if state
+        .acknowledged_batch
+        .is_some_and(|acknowledged| acknowledged >= header.batch_no)
+    {
+        return Ok(state);
+    }
+    let state = AuditExportState {
+        acknowledged_batch: Some(header.batch_no),
+        acknowledged_seq: Some(header.last_seq),
+        acknowledged_at: Some(time()),
+        ..state
+    };
+    AUDIT_EXPORT_STATE.with(|s| s.borrow_mut().insert(hospital_id, state.clone()));
+    audit(
+        oversight_actor(&payload.role),
+        Some(hospital_id),
+        None,
+        "audit_batch_acknowledged",
+        format!("batch {} up to entry {}", header.batch_no, header.last_seq),
+    );
+    Ok(state)
+}
+
+#[ic_cdk::query]
+fn get_audit_export_state(
+    role: OversightRole,
+    password: String,
+) -> Result<AuditExportState, Error> {
+    let hospital_id = authorize_oversight(&role, &password)?;
+    Ok(export_state(hospital_id))
+}
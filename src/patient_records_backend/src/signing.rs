@@ -0,0 +1,284 @@
+use crate::time;
+use crate::{
+    audit, authorize_controller, authorize_doctor, get_assigned_patient, get_encounter_by_id,
+    get_encounter_entry, impl_storable, language_of, limits, next_id, text, Actor,
+    EncounterEntryKind, Error, Memory, Recipient, MEMORY_MANAGER,
+};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum DocumentKind {
+    DischargeSummary,
+    Prescription,
+    VaccinationCertificate,
+}
+
+// A document signed with the canister's threshold ECDSA key
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SignedDocument {
+    pub id: u64,
+    pub kind: DocumentKind,
+    pub patient_id: u64,
+    pub doctor_id: u64,
+    pub hospital_id: u64,
+    pub issued_at: u64,
+    pub content: String,
+    // secp256k1 signature (r || s) over the SHA-256 of the document json
+    pub signature: Vec<u8>,
+}
+
+// Exactly what gets hashed and signed, verifiers rebuild the hash from this json
+#[derive(Serialize)]
+struct DocumentBody<'a> {
+    id: u64,
+    kind: DocumentKind,
+    patient_id: u64,
+    doctor_id: u64,
+    hospital_id: u64,
+    issued_at: u64,
+    content: &'a str,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SigningSettings {
+    // "dfx_test_key" locally, "key_1" on mainnet
+    pub key_name: String,
+    // SEC1 compressed public key, cached after the first fetch
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl Default for SigningSettings {
+    fn default() -> Self {
+        SigningSettings {
+            key_name: "dfx_test_key".to_string(),
+            public_key: None,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct DocumentView {
+    pub document: SignedDocument,
+    // the exact json that was signed
+    pub signed_json: String,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl_storable!(SignedDocument, 17408);
+impl_storable!(SigningSettings, 256);
+
+thread_local! {
+    static DOCUMENT_STORAGE: RefCell<StableBTreeMap<u64, SignedDocument, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    static SIGNING_SETTINGS: RefCell<Cell<SigningSettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))),
+            SigningSettings::default(),
+        )
+        .expect("Cannot create signing settings")
+    );
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SignDocumentPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub kind: DocumentKind,
+    // free text for summaries and certificates, ignored for prescriptions
+    pub content: String,
+    // encounter entry holding the prescription to sign
+    pub prescription_entry_id: Option<u64>,
+}
+
+pub(crate) fn signing_settings() -> SigningSettings {
+    SIGNING_SETTINGS.with(|s| s.borrow().get().clone())
+}
+
+fn save_signing_settings(settings: SigningSettings) {
+    SIGNING_SETTINGS
+        .with(|s| s.borrow_mut().set(settings))
+        .expect("Cannot update signing settings");
+}
+
+fn key_id() -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: signing_settings().key_name,
+    }
+}
+
+pub(crate) fn document_json(document: &SignedDocument) -> String {
+    serde_json::to_string(&DocumentBody {
+        id: document.id,
+        kind: document.kind,
+        patient_id: document.patient_id,
+        doctor_id: document.doctor_id,
+        hospital_id: document.hospital_id,
+        issued_at: document.issued_at,
+        content: &document.content,
+    })
+    .expect("Cannot serialize document")
+}
+
+pub(crate) fn get_document(document_id: u64) -> Result<SignedDocument, Error> {
+    DOCUMENT_STORAGE
+        .with(|s| s.borrow().get(&document_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Signed document of id: {} not found", document_id),
+        })
+}
+
+// sign a message hash with the canister's key
+pub(crate) async fn sign_hash(message_hash: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash,
+        derivation_path: vec![],
+        key_id: key_id(),
+    })
+    .await
+    .map_err(|(code, msg)| Error::InvalidPayload {
+        msg: format!("Signing failed: {:?} {}", code, msg),
+    })?;
+    Ok(response.signature)
+}
+
+// switch the threshold key, e.g. to "key_1" when deploying to mainnet
+#[ic_cdk::update]
+fn set_signing_key(key_name: String) -> Result<SigningSettings, Error> {
+    authorize_controller()?;
+    let settings = SigningSettings {
+        key_name,
+        public_key: None,
+    };
+    save_signing_settings(settings.clone());
+    Ok(settings)
+}
+
+// fetch and cache the canister's public key so verifiers can read it with a query
+#[ic_cdk::update]
+async fn refresh_signing_public_key() -> Result<Vec<u8>, Error> {
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: vec![],
+        key_id: key_id(),
+    })
+    .await
+    .map_err(|(code, msg)| Error::InvalidPayload {
+        msg: format!("Fetching public key failed: {:?} {}", code, msg),
+    })?;
+    save_signing_settings(SigningSettings {
+        public_key: Some(response.public_key.clone()),
+        ..signing_settings()
+    });
+    Ok(response.public_key)
+}
+
+#[ic_cdk::query]
+fn get_signing_public_key() -> Option<Vec<u8>> {
+    signing_settings().public_key
+}
+
+// issue a discharge summary, prescription or vaccination certificate signed by the canister
+#[ic_cdk::update]
+async fn sign_document(payload: SignDocumentPayload) -> Result<DocumentView, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let content = match (payload.kind, payload.prescription_entry_id) {
+        (DocumentKind::Prescription, Some(entry_id)) => {
+            let entry = get_encounter_entry(entry_id)?;
+            let encounter = get_encounter_by_id(entry.encounter_id)?;
+            match entry.kind {
+                EncounterEntryKind::Prescription(prescription)
+                    if encounter.patient_id == patient.id =>
+                {
+                    serde_json::to_string(&prescription).expect("Cannot serialize prescription")
+                }
+                _ => {
+                    return Err(Error::InvalidPayload {
+                        msg: format!("Entry {} is not a prescription of this patient", entry_id),
+                    })
+                }
+            }
+        }
+        (DocumentKind::Prescription, None) => {
+            return Err(Error::InvalidPayload {
+                msg: "Prescriptions are signed from their encounter entry".to_string(),
+            })
+        }
+        // free-text documents open with their title in the patient's language
+        (_, _) if payload.content.trim().is_empty() => {
+            return Err(Error::InvalidPayload {
+                msg: "Document content is empty or too large".to_string(),
+            })
+        }
+        (kind, _) => {
+            let title = match kind {
+                DocumentKind::VaccinationCertificate => text(
+                    "document.vaccination_certificate",
+                    "Vaccination certificate",
+                    vec![],
+                ),
+                _ => text("document.discharge_summary", "Discharge summary", vec![]),
+            };
+            let language = language_of(&Recipient::Patient(patient.id));
+            format!(
+                "{}\n\n{}",
+                title.render(language.as_deref()),
+                payload.content
+            )
+        }
+    };
+    if content.trim().is_empty() || content.len() as u64 > limits().max_record_body_bytes {
+        return Err(Error::InvalidPayload {
+            msg: "Document content is empty or too large".to_string(),
+        });
+    }
+
+    let mut document = SignedDocument {
+        id: next_id(),
+        kind: payload.kind,
+        patient_id: patient.id,
+        doctor_id: doctor.id,
+        hospital_id: doctor.hospital_id,
+        issued_at: time(),
+        content,
+        signature: vec![],
+    };
+    let signed_json = document_json(&document);
+    document.signature = sign_hash(Sha256::digest(signed_json.as_bytes()).to_vec()).await?;
+    DOCUMENT_STORAGE.with(|s| s.borrow_mut().insert(document.id, document.clone()));
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "document_signed",
+        format!("document {}", document.id),
+    );
+    Ok(DocumentView {
+        document,
+        signed_json,
+        public_key: signing_settings().public_key,
+    })
+}
+
+// a signed document with everything needed to verify it off-chain
+#[ic_cdk::query]
+fn get_signed_document(document_id: u64) -> Result<DocumentView, Error> {
+    let document = get_document(document_id)?;
+    Ok(DocumentView {
+        signed_json: document_json(&document),
+        document,
+        public_key: signing_settings().public_key,
+    })
+}
@@ -0,0 +1,124 @@
+use crate::{authorize_hospital, impl_storable, next_id, site_beds, Error, Memory, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// One location of a hospital organization. Doctors and patients belong to the
+// organization and are shared by all of its sites
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Site {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub name: String,
+    pub address: String,
+    pub city: String,
+    // the most beds its wards may have together
+    pub bed_capacity: u32,
+}
+
+impl_storable!(Site, 512);
+
+thread_local! {
+    static SITE_STORAGE: RefCell<StableBTreeMap<u64, Site, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct SitePayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub name: String,
+    pub address: String,
+    pub city: String,
+    pub bed_capacity: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct EditSitePayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub site_id: u64,
+    pub address: String,
+    pub city: String,
+    pub bed_capacity: u32,
+}
+
+// helper function to get a site and check it belongs to the hospital
+pub(crate) fn get_hospital_site(hospital_id: u64, site_id: u64) -> Result<Site, Error> {
+    let site = SITE_STORAGE
+        .with(|s| s.borrow().get(&site_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Site of id: {} not found", site_id),
+        })?;
+    if site.hospital_id != hospital_id {
+        return Err(Error::Unauthorized {
+            msg: format!("Site of id: {} belongs to another hospital", site.id),
+        });
+    }
+    Ok(site)
+}
+
+// an optional site filter has to name a site of the hospital
+pub(crate) fn check_site(hospital_id: u64, site_id: Option<u64>) -> Result<(), Error> {
+    match site_id {
+        Some(site_id) => get_hospital_site(hospital_id, site_id).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+#[ic_cdk::update]
+fn add_site(payload: SitePayload) -> Result<Site, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if payload.name.trim().is_empty() || payload.address.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Site name and address cannot be empty".to_string(),
+        });
+    }
+    let site = Site {
+        id: next_id(),
+        hospital_id: hospital.id,
+        name: payload.name,
+        address: payload.address,
+        city: payload.city,
+        bed_capacity: payload.bed_capacity,
+    };
+    SITE_STORAGE.with(|s| s.borrow_mut().insert(site.id, site.clone()));
+    Ok(site)
+}
+
+// change a site's address or capacity, which cannot drop below the beds its wards have
+#[ic_cdk::update]
+fn edit_site(payload: EditSitePayload) -> Result<Site, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let site = get_hospital_site(hospital.id, payload.site_id)?;
+    let beds = site_beds(site.id);
+    if payload.bed_capacity < beds {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Site of id: {} already has {} beds in its wards",
+                site.id, beds
+            ),
+        });
+    }
+    let edited = Site {
+        address: payload.address,
+        city: payload.city,
+        bed_capacity: payload.bed_capacity,
+        ..site
+    };
+    SITE_STORAGE.with(|s| s.borrow_mut().insert(edited.id, edited.clone()));
+    Ok(edited)
+}
+
+#[ic_cdk::query]
+fn get_hospital_sites(hospital_id: u64) -> Vec<Site> {
+    SITE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, site)| site)
+            .filter(|site| site.hospital_id == hospital_id)
+            .collect()
+    })
+}
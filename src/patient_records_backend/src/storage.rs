@@ -0,0 +1,74 @@
+use crate::{
+    attachment_dedup_stats, audit_log_len, audit_summary_count, authorize_controller, record_count,
+    AttachmentDedupStats, CacheStats, Error, DOCTOR_STORAGE, HOSPITAL_STORAGE, MEMORY_MANAGER,
+    PATIENT_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Memory as _;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct StoreUsage {
+    pub name: String,
+    pub memory_id: u8,
+    pub entries: u64,
+    // bytes reserved by the store's virtual memory, grown in whole pages
+    pub bytes: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct StorageBreakdown {
+    pub stores: Vec<StoreUsage>,
+    // all stable memory of the canister, including stores not listed above
+    pub total_stable_bytes: u64,
+    pub heap_bytes: u64,
+    // how much identical attachment chunks stored once save
+    pub attachment_dedup: AttachmentDedupStats,
+    // the heap caches in front of the hospital and doctor stores
+    pub hospital_cache: CacheStats,
+    pub doctor_cache: CacheStats,
+}
+
+fn store_usage(name: &str, memory_id: u8, entries: u64) -> StoreUsage {
+    let pages = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)).size());
+    StoreUsage {
+        name: name.to_string(),
+        memory_id,
+        entries,
+        bytes: pages * WASM_PAGE_SIZE,
+    }
+}
+
+fn heap_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+// entry counts and memory per store, for capacity planning
+#[ic_cdk::query]
+fn get_storage_breakdown() -> Result<StorageBreakdown, Error> {
+    authorize_controller()?;
+    let stores = vec![
+        store_usage("patients", 2, PATIENT_STORAGE.with(|s| s.borrow().len())),
+        store_usage("hospitals", 3, HOSPITAL_STORAGE.with(|s| s.borrow().len())),
+        store_usage("doctors", 5, DOCTOR_STORAGE.with(|s| s.borrow().len())),
+        store_usage("audit_log", 11, audit_log_len()),
+        store_usage("audit_summaries", 69, audit_summary_count()),
+        store_usage("medical_records", 26, record_count()),
+    ];
+    Ok(StorageBreakdown {
+        stores,
+        total_stable_bytes: ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE,
+        heap_bytes: heap_bytes(),
+        attachment_dedup: attachment_dedup_stats(),
+        hospital_cache: HOSPITAL_STORAGE.with(|s| s.borrow().cache_stats()),
+        doctor_cache: DOCTOR_STORAGE.with(|s| s.borrow().cache_stats()),
+    })
+}
@@ -0,0 +1,211 @@
+use crate::time;
+use crate::{
+    authorize_hospital, impl_storable, next_id, notify, text, to_hex, Encounter, Error, Memory,
+    Priority, Recipient, MEMORY_MANAGER,
+};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const SURVEY_VALID_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_COMMENT_LEN: usize = 1000;
+// fewer responses than this are not summarised so single answers cannot be traced back
+const MIN_RESPONSES: usize = 5;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SurveyResponse {
+    // ratings from 1 to 5
+    pub overall: u8,
+    pub communication: u8,
+    pub wait_time: u8,
+    pub cleanliness: u8,
+    pub comments: String,
+}
+
+// A survey offered after an encounter. It does not hold the patient id, the token is the
+// only link to the patient and is only stored as a hash
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Survey {
+    pub id: u64,
+    pub encounter_id: u64,
+    pub doctor_id: u64,
+    pub hospital_id: u64,
+    pub token_hash: Vec<u8>,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    pub submitted_at: Option<u64>,
+    pub response: Option<SurveyResponse>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct RatingSummary {
+    pub responses: u64,
+    // true when there are too few responses to show averages and comments
+    pub suppressed: bool,
+    pub overall: Option<f64>,
+    pub communication: Option<f64>,
+    pub wait_time: Option<f64>,
+    pub cleanliness: Option<f64>,
+    pub comments: Vec<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct DoctorSurveySummary {
+    pub doctor_id: u64,
+    pub summary: RatingSummary,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct SurveySummary {
+    pub hospital: RatingSummary,
+    pub doctors: Vec<DoctorSurveySummary>,
+}
+
+impl_storable!(Survey, 2048);
+
+thread_local! {
+    static SURVEY_STORAGE: RefCell<StableBTreeMap<u64, Survey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+// send the patient a one-time survey link for a closed encounter. without randomness
+// from the management canister no survey is offered, closing the encounter still counts
+pub(crate) async fn offer_survey(encounter: &Encounter) {
+    let random = match raw_rand().await {
+        Ok((random,)) => random,
+        Err(_) => return,
+    };
+    let token = to_hex(&random);
+    let now = time();
+    let survey = Survey {
+        id: next_id(),
+        encounter_id: encounter.id,
+        doctor_id: encounter.doctor_id,
+        hospital_id: encounter.hospital_id,
+        token_hash: hash_token(&token),
+        issued_at: now,
+        expires_at: now + SURVEY_VALID_NS,
+        submitted_at: None,
+        response: None,
+    };
+    SURVEY_STORAGE.with(|s| s.borrow_mut().insert(survey.id, survey));
+    notify(
+        Recipient::Patient(encounter.patient_id),
+        Priority::Low,
+        text(
+            "survey.offered",
+            "How was your visit? Share your feedback within 30 days with survey token {token}",
+            vec![("token", token)],
+        ),
+    );
+}
+
+fn summarise(surveys: &[&Survey]) -> RatingSummary {
+    let responses: Vec<&SurveyResponse> = surveys
+        .iter()
+        .filter_map(|survey| survey.response.as_ref())
+        .collect();
+    if responses.len() < MIN_RESPONSES {
+        return RatingSummary {
+            responses: responses.len() as u64,
+            suppressed: true,
+            ..Default::default()
+        };
+    }
+    let average = |rating: fn(&SurveyResponse) -> u8| {
+        Some(responses.iter().map(|r| rating(r) as f64).sum::<f64>() / responses.len() as f64)
+    };
+    RatingSummary {
+        responses: responses.len() as u64,
+        suppressed: false,
+        overall: average(|r| r.overall),
+        communication: average(|r| r.communication),
+        wait_time: average(|r| r.wait_time),
+        cleanliness: average(|r| r.cleanliness),
+        comments: responses
+            .iter()
+            .filter(|r| !r.comments.trim().is_empty())
+            .map(|r| r.comments.clone())
+            .collect(),
+    }
+}
+
+// answer a survey with the token from the notification, once
+#[ic_cdk::update]
+fn submit_survey(token: String, response: SurveyResponse) -> Result<(), Error> {
+    let ratings = [
+        response.overall,
+        response.communication,
+        response.wait_time,
+        response.cleanliness,
+    ];
+    if ratings.iter().any(|rating| !(1..=5).contains(rating))
+        || response.comments.len() > MAX_COMMENT_LEN
+    {
+        return Err(Error::InvalidPayload {
+            msg: "Ratings must be between 1 and 5 and comments at most 1000 characters".to_string(),
+        });
+    }
+    let token_hash = hash_token(&token);
+    let survey = SURVEY_STORAGE
+        .with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, survey)| survey)
+                .find(|survey| survey.token_hash == token_hash)
+        })
+        .ok_or(Error::NotFound {
+            msg: "Survey not found".to_string(),
+        })?;
+    if survey.submitted_at.is_some() || survey.expires_at <= time() {
+        return Err(Error::InvalidPayload {
+            msg: "Survey was already answered or has expired".to_string(),
+        });
+    }
+    let answered = Survey {
+        submitted_at: Some(time()),
+        response: Some(response),
+        ..survey
+    };
+    SURVEY_STORAGE.with(|s| s.borrow_mut().insert(answered.id, answered));
+    Ok(())
+}
+
+// survey results of the hospital and each of its doctors
+#[ic_cdk::query]
+fn get_survey_summary(hospital_id: u64, hospital_password: String) -> Result<SurveySummary, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    let surveys: Vec<Survey> = SURVEY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, survey)| survey)
+            .filter(|survey| survey.hospital_id == hospital.id)
+            .collect()
+    });
+    let mut doctor_ids: Vec<u64> = surveys.iter().map(|survey| survey.doctor_id).collect();
+    doctor_ids.sort();
+    doctor_ids.dedup();
+    Ok(SurveySummary {
+        hospital: summarise(&surveys.iter().collect::<Vec<_>>()),
+        doctors: doctor_ids
+            .into_iter()
+            .map(|doctor_id| DoctorSurveySummary {
+                doctor_id,
+                summary: summarise(
+                    &surveys
+                        .iter()
+                        .filter(|survey| survey.doctor_id == doctor_id)
+                        .collect::<Vec<_>>(),
+                ),
+            })
+            .collect(),
+    })
+}
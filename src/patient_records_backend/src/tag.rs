@@ -0,0 +1,194 @@
+use crate::{
+    audit, authorize_doctor, authorize_patient_access, get_assigned_patient, get_record,
+    impl_storable, patient_records, Actor, Error, MedicalRecord, Memory, Page, PatientAccess,
+    MAX_PAGE_SIZE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const MAX_TAGS_PER_RECORD: usize = 10;
+const MAX_TAG_LEN: usize = 32;
+
+// Categories a doctor attached to a record, such as cardiology, imaging or follow-up
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct RecordTags {
+    pub tags: Vec<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub records: u64,
+}
+
+impl_storable!(RecordTags, 512);
+
+thread_local! {
+    static RECORD_TAGS: RefCell<StableBTreeMap<u64, RecordTags, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71)))
+    ));
+
+    // (tag key, record id) -> record creation time, the tag key covers the patient so a
+    // lookup only reads that patient's tagged records
+    static TAG_INDEX: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct TagRecordPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub record_id: u64,
+    // replaces the record's tags, an empty list clears them
+    pub tags: Vec<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct TagFilter {
+    pub tag: String,
+    // creation time bounds, inclusive
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    // last record id of the previous page
+    pub after: Option<u64>,
+    pub limit: u64,
+}
+
+// lowercase with dashes for spaces, so "Follow up" and "follow-up" are the same tag
+fn normalize_tag(tag: &str) -> Result<String, Error> {
+    let tag = tag.trim().to_lowercase().replace(' ', "-");
+    if tag.is_empty()
+        || tag.chars().count() > MAX_TAG_LEN
+        || !tag.chars().all(|c| c.is_alphanumeric() || c == '-')
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Tag \"{}\" must be 1 to {} letters, digits or dashes",
+                tag, MAX_TAG_LEN
+            ),
+        });
+    }
+    Ok(tag)
+}
+
+fn tag_key(patient_id: u64, tag: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(patient_id.to_be_bytes());
+    hasher.update(tag.as_bytes());
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap_or_default())
+}
+
+pub(crate) fn record_tags(record_id: u64) -> Vec<String> {
+    RECORD_TAGS
+        .with(|s| s.borrow().get(&record_id))
+        .unwrap_or_default()
+        .tags
+}
+
+// attach tags to a record, replacing the ones it had
+#[ic_cdk::update]
+fn tag_record(payload: TagRecordPayload) -> Result<RecordTags, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let record = get_record(payload.record_id)?;
+    let patient = get_assigned_patient(&doctor, record.patient_id)?;
+    let mut tags = vec![];
+    for tag in &payload.tags {
+        let tag = normalize_tag(tag)?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    if tags.len() > MAX_TAGS_PER_RECORD {
+        return Err(Error::LimitExceeded {
+            msg: format!("A record can have at most {} tags", MAX_TAGS_PER_RECORD),
+        });
+    }
+
+    let previous = record_tags(record.id);
+    TAG_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for tag in &previous {
+            index.remove(&(tag_key(patient.id, tag), record.id));
+        }
+        for tag in &tags {
+            index.insert((tag_key(patient.id, tag), record.id), record.created_at);
+        }
+    });
+    let tagged = RecordTags { tags };
+    RECORD_TAGS.with(|s| {
+        let mut s = s.borrow_mut();
+        if tagged.tags.is_empty() {
+            s.remove(&record.id);
+        } else {
+            s.insert(record.id, tagged.clone());
+        }
+    });
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(doctor.hospital_id),
+        Some(patient.id),
+        "record_tagged",
+        format!("record {}: {}", record.id, tagged.tags.join(", ")),
+    );
+    Ok(tagged)
+}
+
+// the tags in use on a patient's records with how many records carry each, for filter menus
+#[ic_cdk::query]
+fn get_patient_tags(patient_id: u64, access: PatientAccess) -> Result<Vec<TagCount>, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+    for record in patient_records(patient.id) {
+        for tag in record_tags(record.id) {
+            *counts.entry(tag).or_insert(0) += 1;
+        }
+    }
+    Ok(counts
+        .into_iter()
+        .map(|(tag, records)| TagCount { tag, records })
+        .collect())
+}
+
+// a patient's records carrying a tag, optionally within a date range, in record id order.
+// archived records keep their tags and show up again once restored
+#[ic_cdk::query]
+fn get_records_by_tag(
+    patient_id: u64,
+    access: PatientAccess,
+    filter: TagFilter,
+) -> Result<Page<MedicalRecord>, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    let key = tag_key(patient.id, &normalize_tag(&filter.tag)?);
+    let from = filter.from.unwrap_or(0);
+    let to = filter.to.unwrap_or(u64::MAX);
+    let start = filter.after.map_or(0, |after| after.saturating_add(1));
+    let limit = filter.limit.clamp(1, MAX_PAGE_SIZE) as usize;
+
+    let record_ids: Vec<u64> = TAG_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((key, start)..=(key, u64::MAX))
+            .filter(|(_, created_at)| (from..=to).contains(created_at))
+            .map(|((_, record_id), _)| record_id)
+            .take(limit + 1)
+            .collect()
+    });
+    let next_cursor = if record_ids.len() > limit {
+        Some(record_ids[limit - 1])
+    } else {
+        None
+    };
+    let items = record_ids
+        .into_iter()
+        .take(limit)
+        .filter_map(|record_id| get_record(record_id).ok())
+        .collect();
+    Ok(Page { items, next_cursor })
+}
@@ -0,0 +1,413 @@
+use crate::time;
+use crate::{authorize_controller, impl_storable, Error, Memory, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const CODE_KEY_BYTES: usize = 16;
+const MAX_LOOKUP_RESULTS: usize = 20;
+const MAX_CHUNK_ENTRIES: usize = 2000;
+const MAX_DISPLAY_CHARS: usize = 200;
+const MAX_RELEASE_CHARS: usize = 64;
+// codes of superseded table versions removed per timer run
+const PRUNE_BATCH: usize = 5000;
+
+// built-in subsets, replaced once a full table is uploaded
+const DEFAULT_ICD10: &[(&str, &str)] = &[
+    ("A09", "Infectious gastroenteritis and colitis, unspecified"),
+    ("E11.9", "Type 2 diabetes mellitus without complications"),
+    ("E78.5", "Hyperlipidaemia, unspecified"),
+    ("F32.9", "Depressive episode, unspecified"),
+    ("I10", "Essential (primary) hypertension"),
+    ("I21.9", "Acute myocardial infarction, unspecified"),
+    (
+        "I48.9",
+        "Atrial fibrillation and atrial flutter, unspecified",
+    ),
+    ("J06.9", "Acute upper respiratory infection, unspecified"),
+    ("J18.9", "Pneumonia, unspecified"),
+    ("J45.9", "Asthma, unspecified"),
+    (
+        "K21.9",
+        "Gastro-oesophageal reflux disease without oesophagitis",
+    ),
+    ("M54.5", "Low back pain"),
+    ("N39.0", "Urinary tract infection, site not specified"),
+    ("R50.9", "Fever, unspecified"),
+    ("U07.1", "COVID-19, virus identified"),
+];
+const DEFAULT_LOINC: &[(&str, &str)] = &[
+    ("718-7", "Hemoglobin [Mass/volume] in Blood"),
+    ("2345-7", "Glucose [Mass/volume] in Serum or Plasma"),
+    ("2160-0", "Creatinine [Mass/volume] in Serum or Plasma"),
+    ("4548-4", "Hemoglobin A1c/Hemoglobin.total in Blood"),
+    ("2093-3", "Cholesterol [Mass/volume] in Serum or Plasma"),
+    ("2951-2", "Sodium [Moles/volume] in Serum or Plasma"),
+    ("2823-3", "Potassium [Moles/volume] in Serum or Plasma"),
+    (
+        "6690-2",
+        "Leukocytes [#/volume] in Blood by Automated count",
+    ),
+    ("777-3", "Platelets [#/volume] in Blood by Automated count"),
+    ("8867-4", "Heart rate"),
+    ("8480-6", "Systolic blood pressure"),
+    ("8462-4", "Diastolic blood pressure"),
+    ("8310-5", "Body temperature"),
+    (
+        "59408-5",
+        "Oxygen saturation in Arterial blood by Pulse oximetry",
+    ),
+];
+const DEFAULT_ATC: &[(&str, &str)] = &[
+    ("A10BA02", "metformin"),
+    ("B01AC06", "acetylsalicylic acid"),
+    ("C03CA01", "furosemide"),
+    ("C07AB02", "metoprolol"),
+    ("C09AA02", "enalapril"),
+    ("C10AA05", "atorvastatin"),
+    ("J01CA04", "amoxicillin"),
+    ("J01MA02", "ciprofloxacin"),
+    ("N02BE01", "paracetamol"),
+    ("M01AE01", "ibuprofen"),
+    ("N02AA01", "morphine"),
+    ("N05BA01", "diazepam"),
+    ("R03AC02", "salbutamol"),
+    ("H02AB06", "prednisolone"),
+];
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum CodeSystem {
+    Icd10,
+    Loinc,
+    Atc,
+}
+
+impl CodeSystem {
+    fn index(&self) -> u8 {
+        match self {
+            CodeSystem::Icd10 => 0,
+            CodeSystem::Loinc => 1,
+            CodeSystem::Atc => 2,
+        }
+    }
+
+    fn defaults(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            CodeSystem::Icd10 => DEFAULT_ICD10,
+            CodeSystem::Loinc => DEFAULT_LOINC,
+            CodeSystem::Atc => DEFAULT_ATC,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TerminologyCode {
+    pub system: CodeSystem,
+    pub code: String,
+    pub display: String,
+}
+
+// An upload of a new table version, invisible to lookups until it is committed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PendingCodeTable {
+    pub version: u32,
+    // release name, e.g. "ICD-10 2019" or "LOINC 2.76"
+    pub release: String,
+    pub codes: u64,
+    pub started_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CodeTable {
+    pub system: CodeSystem,
+    pub version: u32,
+    pub release: String,
+    pub codes: u64,
+    pub updated_at: u64,
+    pub pending: Option<PendingCodeTable>,
+    // versions whose codes are still being removed
+    pub superseded: Vec<u32>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CodeTableChunk {
+    pub system: CodeSystem,
+    // the version begin_code_table_upload returned
+    pub version: u32,
+    // (code, display) pairs
+    pub entries: Vec<(String, String)>,
+}
+
+impl_storable!(TerminologyCode, 512);
+impl_storable!(CodeTable, 1024);
+
+thread_local! {
+    // (system << 32 | table version, code padded to 16 bytes) -> code
+    static CODE_STORAGE: RefCell<StableBTreeMap<(u64, [u8; CODE_KEY_BYTES]), TerminologyCode, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(121)))
+    ));
+
+    static TABLE_STORAGE: RefCell<StableBTreeMap<u8, CodeTable, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(122)))
+    ));
+}
+
+fn table_key(system: CodeSystem, version: u32) -> u64 {
+    ((system.index() as u64) << 32) | version as u64
+}
+
+fn code_key(code: &str) -> Option<[u8; CODE_KEY_BYTES]> {
+    let bytes = code.as_bytes();
+    if bytes.is_empty() || bytes.len() > CODE_KEY_BYTES {
+        return None;
+    }
+    let mut key = [0; CODE_KEY_BYTES];
+    key[..bytes.len()].copy_from_slice(bytes);
+    Some(key)
+}
+
+fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+fn code_table(system: CodeSystem) -> Option<CodeTable> {
+    TABLE_STORAGE.with(|s| s.borrow().get(&system.index()))
+}
+
+fn save_table(table: &CodeTable) {
+    TABLE_STORAGE.with(|s| s.borrow_mut().insert(table.system.index(), table.clone()));
+}
+
+fn check_code(code: &str, display: &str) -> Result<[u8; CODE_KEY_BYTES], Error> {
+    let key = code_key(code).ok_or(Error::InvalidPayload {
+        msg: format!("Code {} must be 1 to 16 bytes", code),
+    })?;
+    if display.is_empty() || display.chars().count() > MAX_DISPLAY_CHARS {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Code {} needs a display name of at most 200 characters",
+                code
+            ),
+        });
+    }
+    Ok(key)
+}
+
+// returns whether the code is new to the table version
+fn insert_code(system: CodeSystem, version: u32, code: &str, display: &str) -> Result<bool, Error> {
+    let code = normalize_code(code);
+    let display = display.trim();
+    let key = check_code(&code, display)?;
+    let replaced = CODE_STORAGE.with(|s| {
+        s.borrow_mut().insert(
+            (table_key(system, version), key),
+            TerminologyCode {
+                system,
+                code,
+                display: display.to_string(),
+            },
+        )
+    });
+    Ok(replaced.is_none())
+}
+
+// load the built-in subset of every code system that has no table yet
+pub(crate) fn seed_terminology() {
+    for system in [CodeSystem::Icd10, CodeSystem::Loinc, CodeSystem::Atc] {
+        if code_table(system).is_some() {
+            continue;
+        }
+        for (code, display) in system.defaults() {
+            // the built-in codes are all valid
+            let _ = insert_code(system, 0, code, display);
+        }
+        save_table(&CodeTable {
+            system,
+            version: 0,
+            release: "built-in subset".to_string(),
+            codes: system.defaults().len() as u64,
+            updated_at: time(),
+            pending: None,
+            superseded: vec![],
+        });
+    }
+}
+
+// codes of the active table starting with the prefix, in code order
+fn codes_with_prefix(system: CodeSystem, version: u32, prefix: &str) -> Vec<TerminologyCode> {
+    let table = table_key(system, version);
+    let start = code_key(prefix).unwrap_or([0; CODE_KEY_BYTES]);
+    CODE_STORAGE.with(|s| {
+        s.borrow()
+            .range((table, start)..=(table, [u8::MAX; CODE_KEY_BYTES]))
+            .map(|(_, code)| code)
+            .take_while(|code| code.code.starts_with(prefix))
+            .take(MAX_LOOKUP_RESULTS)
+            .collect()
+    })
+}
+
+// codes of the active table whose display name contains every word of the query
+fn codes_matching_display(system: CodeSystem, version: u32, query: &str) -> Vec<TerminologyCode> {
+    let table = table_key(system, version);
+    let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    CODE_STORAGE.with(|s| {
+        s.borrow()
+            .range((table, [0; CODE_KEY_BYTES])..=(table, [u8::MAX; CODE_KEY_BYTES]))
+            .map(|(_, code)| code)
+            .filter(|code| {
+                let display = code.display.to_lowercase();
+                words.iter().all(|word| display.contains(word.as_str()))
+            })
+            .take(MAX_LOOKUP_RESULTS)
+            .collect()
+    })
+}
+
+// find codes by code prefix, e.g. "E11", or by words of their name, e.g. "type 2 diabetes"
+#[ic_cdk::query]
+fn lookup_code(system: CodeSystem, query: String) -> Result<Vec<TerminologyCode>, Error> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Lookup query cannot be empty".to_string(),
+        });
+    }
+    let table = code_table(system).ok_or(Error::NotFound {
+        msg: "Code system has no table loaded".to_string(),
+    })?;
+    let by_code = codes_with_prefix(system, table.version, &normalize_code(query));
+    if !by_code.is_empty() {
+        return Ok(by_code);
+    }
+    Ok(codes_matching_display(system, table.version, query))
+}
+
+#[ic_cdk::query]
+fn get_code_tables() -> Vec<CodeTable> {
+    TABLE_STORAGE.with(|s| s.borrow().iter().map(|(_, table)| table).collect())
+}
+
+// start uploading a new version of a code table, dropping any unfinished upload
+#[ic_cdk::update]
+fn begin_code_table_upload(system: CodeSystem, release: String) -> Result<CodeTable, Error> {
+    authorize_controller()?;
+    if release.trim().is_empty() || release.len() > MAX_RELEASE_CHARS {
+        return Err(Error::InvalidPayload {
+            msg: "Release name must be 1 to 64 characters".to_string(),
+        });
+    }
+    let mut table = code_table(system).ok_or(Error::NotFound {
+        msg: "Code system has no table loaded".to_string(),
+    })?;
+    let newest = table
+        .pending
+        .as_ref()
+        .map_or(table.version, |pending| pending.version)
+        .max(table.superseded.iter().copied().max().unwrap_or(0));
+    if let Some(abandoned) = table.pending.take() {
+        table.superseded.push(abandoned.version);
+    }
+    table.pending = Some(PendingCodeTable {
+        version: newest + 1,
+        release,
+        codes: 0,
+        started_at: time(),
+    });
+    save_table(&table);
+    Ok(table)
+}
+
+// add a chunk of codes to the pending upload, a code sent twice keeps its last display name
+#[ic_cdk::update]
+fn upload_code_table_chunk(chunk: CodeTableChunk) -> Result<CodeTable, Error> {
+    authorize_controller()?;
+    let mut table = code_table(chunk.system).ok_or(Error::NotFound {
+        msg: "Code system has no table loaded".to_string(),
+    })?;
+    let pending = match table.pending.as_mut() {
+        Some(pending) if pending.version == chunk.version => pending,
+        _ => {
+            return Err(Error::InvalidPayload {
+                msg: format!("No upload of version {} is in progress", chunk.version),
+            })
+        }
+    };
+    if chunk.entries.len() > MAX_CHUNK_ENTRIES {
+        return Err(Error::LimitExceeded {
+            msg: format!("A chunk can hold at most {} codes", MAX_CHUNK_ENTRIES),
+        });
+    }
+    // a chunk is stored whole or not at all
+    for (code, display) in &chunk.entries {
+        check_code(&normalize_code(code), display.trim())?;
+    }
+    for (code, display) in &chunk.entries {
+        if insert_code(chunk.system, chunk.version, code, display)? {
+            pending.codes += 1;
+        }
+    }
+    save_table(&table);
+    Ok(table)
+}
+
+// make the pending upload the table lookups use; the previous version is removed in the
+// background
+#[ic_cdk::update]
+fn commit_code_table_upload(system: CodeSystem, version: u32) -> Result<CodeTable, Error> {
+    authorize_controller()?;
+    let mut table = code_table(system).ok_or(Error::NotFound {
+        msg: "Code system has no table loaded".to_string(),
+    })?;
+    let pending = match table.pending.take() {
+        Some(pending) if pending.version == version && pending.codes > 0 => pending,
+        _ => {
+            return Err(Error::InvalidPayload {
+                msg: format!("No non-empty upload of version {} is in progress", version),
+            })
+        }
+    };
+    table.superseded.push(table.version);
+    table.version = pending.version;
+    table.release = pending.release;
+    table.codes = pending.codes;
+    table.updated_at = time();
+    save_table(&table);
+    Ok(table)
+}
+
+// timer job: remove the codes of superseded and abandoned table versions a batch at a time
+pub(crate) fn prune_code_tables() {
+    let tables: Vec<CodeTable> =
+        TABLE_STORAGE.with(|s| s.borrow().iter().map(|(_, table)| table).collect());
+    let mut budget = PRUNE_BATCH;
+    for mut table in tables {
+        while let Some(version) = table.superseded.first().copied() {
+            let table_id = table_key(table.system, version);
+            let keys: Vec<(u64, [u8; CODE_KEY_BYTES])> = CODE_STORAGE.with(|s| {
+                s.borrow()
+                    .range((table_id, [0; CODE_KEY_BYTES])..=(table_id, [u8::MAX; CODE_KEY_BYTES]))
+                    .map(|(key, _)| key)
+                    .take(budget)
+                    .collect()
+            });
+            budget -= keys.len();
+            CODE_STORAGE.with(|s| {
+                let mut codes = s.borrow_mut();
+                for key in &keys {
+                    codes.remove(key);
+                }
+            });
+            if budget == 0 {
+                break;
+            }
+            table.superseded.remove(0);
+        }
+        save_table(&table);
+        if budget == 0 {
+            return;
+        }
+    }
+}
@@ -0,0 +1,105 @@
+// The simulated runtime the tests run against and the fixtures they share. Each test runs on
+// its own thread, so it starts from empty stores, the start time and an anonymous caller
+use crate::{
+    add_doctor, add_hospital, add_patient, assign_patient_to_doctor, AddPatientToDoctor,
+    DoctorPayload, Error, HospitalPayload, PatientPayload,
+};
+use candid::Principal;
+use std::cell::RefCell;
+
+pub(crate) const PASSWORD: &str = "test-password";
+const START_TIME: u64 = 1_700_000_000_000_000_000;
+
+thread_local! {
+    static CLOCK: RefCell<u64> = const { RefCell::new(START_TIME) };
+    static CALLER: RefCell<Principal> = const { RefCell::new(Principal::anonymous()) };
+}
+
+pub(crate) fn time() -> u64 {
+    CLOCK.with(|clock| *clock.borrow())
+}
+
+pub(crate) fn advance_clock(ns: u64) {
+    CLOCK.with(|clock| *clock.borrow_mut() += ns);
+}
+
+pub(crate) fn caller() -> Principal {
+    CALLER.with(|caller| *caller.borrow())
+}
+
+pub(crate) fn sign_in(principal: Principal) {
+    CALLER.with(|caller| *caller.borrow_mut() = principal);
+}
+
+pub(crate) fn instruction_counter() -> u64 {
+    0
+}
+
+pub(crate) fn is_controller(_: &Principal) -> bool {
+    false
+}
+
+pub(crate) fn error_message(error: &Error) -> &str {
+    match error {
+        Error::NotFound { msg }
+        | Error::AlreadyInit { msg }
+        | Error::InvalidPayload { msg }
+        | Error::Unauthorized { msg }
+        | Error::LimitExceeded { msg } => msg,
+    }
+}
+
+// a signed-in identity, a different one for each n
+pub(crate) fn principal(n: u8) -> Principal {
+    Principal::from_slice(&[n; 29])
+}
+
+// whether a call was turned away by an authorization check
+pub(crate) fn refused<T>(result: Result<T, Error>) -> bool {
+    matches!(result, Err(Error::Unauthorized { .. }))
+}
+
+// the value of a call the test expects to succeed
+pub(crate) fn must<T>(result: Result<T, Error>) -> T {
+    result.unwrap_or_else(|error| panic!("{}", error_message(&error)))
+}
+
+// A hospital with one doctor and one patient assigned to them
+pub(crate) struct Clinic {
+    pub hospital_id: u64,
+    pub doctor_id: u64,
+    pub patient_id: u64,
+}
+
+pub(crate) fn clinic() -> Clinic {
+    let hospital = must(add_hospital(HospitalPayload {
+        name: "Clinic".to_string(),
+        address: "1 Test Road".to_string(),
+        password: PASSWORD.to_string(),
+        city: "Nairobi".to_string(),
+        ..Default::default()
+    }));
+    let doctor = must(add_doctor(DoctorPayload {
+        name: "Doctor".to_string(),
+        hospital_id: hospital.id,
+        password: PASSWORD.to_string(),
+        hospital_password: PASSWORD.to_string(),
+    }));
+    let patient = must(add_patient(PatientPayload {
+        name: "Patient".to_string(),
+        history: "No known conditions".to_string(),
+        password: PASSWORD.to_string(),
+        language: None,
+    }));
+    must(assign_patient_to_doctor(AddPatientToDoctor {
+        doctor_id: doctor.id,
+        patient_id: patient.id,
+        doctor_password: PASSWORD.to_string(),
+        patient_password: PASSWORD.to_string(),
+    }));
+    Clinic {
+        hospital_id: hospital.id,
+        doctor_id: doctor.id,
+        patient_id: patient.id,
+    }
+}
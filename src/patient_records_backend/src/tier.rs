@@ -0,0 +1,287 @@
+use crate::time;
+use crate::{
+    authorize_controller, authorize_hospital, check_limit, hospital_nurse_count, impl_storable,
+    limits, Error, Hospital, MedicalRecord, Memory, DOCTORS_PER_HOSPITAL_CEILING, HOSPITAL_STORAGE,
+    MEMORY_MANAGER, PATIENTS_PER_HOSPITAL_CEILING,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MIB: u64 = 1024 * 1024;
+// usage at or above this share of a quota is flagged on the dashboard
+const NEAR_LIMIT_PERCENT: u64 = 80;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum HospitalTier {
+    Trial,
+    Standard,
+    Enterprise,
+}
+
+impl HospitalTier {
+    fn index(&self) -> u8 {
+        match self {
+            HospitalTier::Trial => 0,
+            HospitalTier::Standard => 1,
+            HospitalTier::Enterprise => 2,
+        }
+    }
+
+    fn default_quota(&self) -> TierQuota {
+        match self {
+            HospitalTier::Trial => TierQuota {
+                max_patients: 20,
+                max_doctors: 3,
+                max_nurses: 10,
+                max_record_bytes: 16 * MIB,
+            },
+            HospitalTier::Standard => TierQuota {
+                max_patients: 100,
+                max_doctors: 10,
+                max_nurses: 50,
+                max_record_bytes: 1024 * MIB,
+            },
+            HospitalTier::Enterprise => TierQuota {
+                max_patients: 100,
+                max_doctors: 15,
+                max_nurses: 500,
+                max_record_bytes: 16 * 1024 * MIB,
+            },
+        }
+    }
+}
+
+// What a hospital of a tier may hold. Patients are also capped by the canister-wide limits
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TierQuota {
+    pub max_patients: u64,
+    pub max_doctors: u64,
+    pub max_nurses: u64,
+    // bytes of medical record bodies written by the hospital's doctors
+    pub max_record_bytes: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TierAssignment {
+    pub tier: HospitalTier,
+    pub set_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct QuotaLine {
+    pub used: u64,
+    pub limit: u64,
+    pub remaining: u64,
+    // at least 80% used, a soft warning before writes start failing
+    pub near_limit: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    pub hospital_id: u64,
+    pub tier: HospitalTier,
+    pub patients: QuotaLine,
+    pub doctors: QuotaLine,
+    pub nurses: QuotaLine,
+    pub record_bytes: QuotaLine,
+}
+
+impl_storable!(TierQuota, 128);
+impl_storable!(TierAssignment, 64);
+
+thread_local! {
+    // tier index -> quota, tiers without an entry use their default quota
+    static TIER_QUOTAS: RefCell<StableBTreeMap<u8, TierQuota, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(124)))
+    ));
+
+    static HOSPITAL_TIERS: RefCell<StableBTreeMap<u64, TierAssignment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(125)))
+    ));
+
+    // hospital -> bytes of record bodies it holds, kept in step by record writes
+    static RECORD_BYTES: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(126)))
+    ));
+}
+
+// hospitals that were never given a tier are on the standard one
+pub(crate) fn hospital_tier(hospital_id: u64) -> HospitalTier {
+    HOSPITAL_TIERS
+        .with(|s| s.borrow().get(&hospital_id))
+        .map_or(HospitalTier::Standard, |assignment| assignment.tier)
+}
+
+fn tier_quota(tier: HospitalTier) -> TierQuota {
+    TIER_QUOTAS
+        .with(|s| s.borrow().get(&tier.index()))
+        .unwrap_or_else(|| tier.default_quota())
+}
+
+fn hospital_quota(hospital_id: u64) -> TierQuota {
+    tier_quota(hospital_tier(hospital_id))
+}
+
+// the most patients the hospital may have, the lower of its tier quota and the global limit
+pub(crate) fn patient_quota(hospital_id: u64) -> u64 {
+    hospital_quota(hospital_id)
+        .max_patients
+        .min(limits().max_patients_per_hospital)
+}
+
+pub(crate) fn check_doctor_quota(hospital: &Hospital) -> Result<(), Error> {
+    check_limit(
+        "doctors for this hospital tier",
+        hospital.doctors_ids.len() as u64,
+        hospital_quota(hospital.id).max_doctors,
+    )
+}
+
+pub(crate) fn check_nurse_quota(hospital_id: u64) -> Result<(), Error> {
+    check_limit(
+        "nurses for this hospital tier",
+        hospital_nurse_count(hospital_id),
+        hospital_quota(hospital_id).max_nurses,
+    )
+}
+
+pub(crate) fn record_bytes(hospital_id: u64) -> u64 {
+    RECORD_BYTES
+        .with(|s| s.borrow().get(&hospital_id))
+        .unwrap_or(0)
+}
+
+// reject a record write that would take the hospital past its storage quota
+pub(crate) fn check_record_bytes_quota(hospital_id: u64, added_bytes: u64) -> Result<(), Error> {
+    let max = hospital_quota(hospital_id).max_record_bytes;
+    if record_bytes(hospital_id) + added_bytes > max {
+        return Err(Error::LimitExceeded {
+            msg: format!(
+                "Record storage quota of {} bytes for this hospital tier reached",
+                max
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn add_record_bytes(hospital_id: u64, bytes: u64, added: bool) {
+    RECORD_BYTES.with(|s| {
+        let mut usage = s.borrow_mut();
+        let current = usage.get(&hospital_id).unwrap_or(0);
+        let updated = if added {
+            current + bytes
+        } else {
+            current.saturating_sub(bytes)
+        };
+        usage.insert(hospital_id, updated);
+    });
+}
+
+// called by every record write and removal to keep the storage usage in step
+pub(crate) fn track_record_bytes(
+    previous: Option<&MedicalRecord>,
+    current: Option<&MedicalRecord>,
+) {
+    if let Some(record) = previous {
+        if let Some(hospital_id) = record.hospital_id {
+            add_record_bytes(hospital_id, record.body.len() as u64, false);
+        }
+    }
+    if let Some(record) = current {
+        if let Some(hospital_id) = record.hospital_id {
+            add_record_bytes(hospital_id, record.body.len() as u64, true);
+        }
+    }
+}
+
+// count the bytes of records written before usage was tracked, once after the upgrade that
+// introduced tiers
+pub(crate) fn backfill_record_bytes(records: impl Iterator<Item = MedicalRecord>) {
+    if RECORD_BYTES.with(|s| !s.borrow().is_empty()) {
+        return;
+    }
+    for record in records {
+        track_record_bytes(None, Some(&record));
+    }
+}
+
+fn quota_line(used: u64, limit: u64) -> QuotaLine {
+    QuotaLine {
+        used,
+        limit,
+        remaining: limit.saturating_sub(used),
+        near_limit: used as u128 * 100 >= limit as u128 * NEAR_LIMIT_PERCENT as u128,
+    }
+}
+
+// controllers move a hospital to another tier, existing data above a lower quota stays but
+// nothing new can be added until usage drops below it
+#[ic_cdk::update]
+fn set_hospital_tier(hospital_id: u64, tier: HospitalTier) -> Result<TierAssignment, Error> {
+    authorize_controller()?;
+    if !HOSPITAL_STORAGE.with(|s| s.borrow().contains_key(&hospital_id)) {
+        return Err(Error::NotFound {
+            msg: format!("Hospital of id: {} not found", hospital_id),
+        });
+    }
+    let assignment = TierAssignment {
+        tier,
+        set_at: time(),
+    };
+    HOSPITAL_TIERS.with(|s| s.borrow_mut().insert(hospital_id, assignment.clone()));
+    Ok(assignment)
+}
+
+#[ic_cdk::update]
+fn set_tier_quota(tier: HospitalTier, quota: TierQuota) -> Result<TierQuota, Error> {
+    authorize_controller()?;
+    // patients and doctors are listed on the hospital, which cannot grow past its size bound
+    if quota.max_patients > PATIENTS_PER_HOSPITAL_CEILING
+        || quota.max_doctors > DOCTORS_PER_HOSPITAL_CEILING
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Tier quotas cannot exceed {} patients or {} doctors per hospital",
+                PATIENTS_PER_HOSPITAL_CEILING, DOCTORS_PER_HOSPITAL_CEILING
+            ),
+        });
+    }
+    TIER_QUOTAS.with(|s| s.borrow_mut().insert(tier.index(), quota.clone()));
+    Ok(quota)
+}
+
+#[ic_cdk::query]
+fn get_tier_quotas() -> Vec<(HospitalTier, TierQuota)> {
+    [
+        HospitalTier::Trial,
+        HospitalTier::Standard,
+        HospitalTier::Enterprise,
+    ]
+    .into_iter()
+    .map(|tier| (tier, tier_quota(tier)))
+    .collect()
+}
+
+// how much of each quota the hospital uses, for the admin dashboard
+#[ic_cdk::query]
+fn get_quota_usage(hospital_id: u64, hospital_password: String) -> Result<QuotaUsage, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    let tier = hospital_tier(hospital.id);
+    let quota = tier_quota(tier);
+    Ok(QuotaUsage {
+        hospital_id: hospital.id,
+        tier,
+        patients: quota_line(
+            hospital.patients_ids.len() as u64,
+            patient_quota(hospital.id),
+        ),
+        doctors: quota_line(hospital.doctors_ids.len() as u64, quota.max_doctors),
+        nurses: quota_line(hospital_nurse_count(hospital.id), quota.max_nurses),
+        record_bytes: quota_line(record_bytes(hospital.id), quota.max_record_bytes),
+    })
+}
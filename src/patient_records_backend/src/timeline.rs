@@ -0,0 +1,183 @@
+use crate::{
+    all_appointments, authorize_patient_access, patient_encounters, patient_entries,
+    patient_records, AppointmentStatus, EncounterEntryKind, EncounterStatus, Error, PatientAccess,
+    MAX_PAGE_SIZE,
+};
+
+// characters of a record body shown on its timeline event
+const DETAIL_LEN: usize = 120;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum TimelineKind {
+    Record,
+    Prescription,
+    // an encounter, from the visit being opened
+    Admission,
+    LabResult,
+    Appointment,
+}
+
+// One thing that happened to the patient, pointing back at where it is stored
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TimelineEvent {
+    pub kind: TimelineKind,
+    // id of the record, encounter entry, encounter or appointment
+    pub source_id: u64,
+    pub at: u64,
+    pub title: String,
+    pub detail: String,
+}
+
+// Position of the last event of the previous page
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub struct TimelineCursor {
+    pub at: u64,
+    pub source_id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TimelinePage {
+    pub events: Vec<TimelineEvent>,
+    pub next_cursor: Option<TimelineCursor>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct TimelineQuery {
+    // inclusive time bounds
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    // empty for every kind
+    pub kinds: Vec<TimelineKind>,
+    pub after: Option<TimelineCursor>,
+    pub limit: u64,
+}
+
+fn event(
+    kind: TimelineKind,
+    source_id: u64,
+    at: u64,
+    title: &str,
+    detail: String,
+) -> TimelineEvent {
+    TimelineEvent {
+        kind,
+        source_id,
+        at,
+        title: title.to_string(),
+        detail,
+    }
+}
+
+// every timeline event of the patient, unordered
+fn patient_events(patient_id: u64) -> Vec<TimelineEvent> {
+    let mut events: Vec<TimelineEvent> = patient_records(patient_id)
+        .into_iter()
+        .map(|record| {
+            let detail = record.body.chars().take(DETAIL_LEN).collect();
+            event(
+                TimelineKind::Record,
+                record.id,
+                record.created_at,
+                &record.title,
+                detail,
+            )
+        })
+        .collect();
+    for entry in patient_entries(patient_id) {
+        match &entry.kind {
+            EncounterEntryKind::Prescription(prescription) => events.push(event(
+                TimelineKind::Prescription,
+                entry.id,
+                entry.recorded_at,
+                &prescription.medication,
+                format!(
+                    "{}, {} a day for {} days",
+                    prescription.dosage, prescription.doses_per_day, prescription.duration_days
+                ),
+            )),
+            EncounterEntryKind::LabResult { test, value, unit } => events.push(event(
+                TimelineKind::LabResult,
+                entry.id,
+                entry.recorded_at,
+                test,
+                format!("{} {}", value, unit),
+            )),
+            _ => {}
+        }
+    }
+    for encounter in patient_encounters(patient_id) {
+        let detail = match encounter.status {
+            EncounterStatus::Open => format!("Open at hospital {}", encounter.hospital_id),
+            EncounterStatus::Closed => format!("Closed at hospital {}", encounter.hospital_id),
+        };
+        events.push(event(
+            TimelineKind::Admission,
+            encounter.id,
+            encounter.opened_at,
+            &encounter.reason,
+            detail,
+        ));
+    }
+    for appointment in all_appointments() {
+        if appointment.patient_id != patient_id {
+            continue;
+        }
+        let status = match appointment.status {
+            // a slot the patient has not confirmed yet is not part of their history
+            AppointmentStatus::Held => continue,
+            AppointmentStatus::Scheduled => "Scheduled",
+            AppointmentStatus::Cancelled => "Cancelled",
+            AppointmentStatus::Completed => "Completed",
+        };
+        events.push(event(
+            TimelineKind::Appointment,
+            appointment.id,
+            appointment.start,
+            &appointment.reason,
+            format!("{} with doctor {}", status, appointment.doctor_id),
+        ));
+    }
+    events
+}
+
+// records, prescriptions, admissions, lab results and appointments of a patient merged into
+// one stream, oldest first, a page at a time
+#[ic_cdk::query]
+fn get_patient_timeline(
+    patient_id: u64,
+    access: PatientAccess,
+    query: TimelineQuery,
+) -> Result<TimelinePage, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    let from = query.from.unwrap_or(0);
+    let to = query.to.unwrap_or(u64::MAX);
+    let cursor = |event: &TimelineEvent| TimelineCursor {
+        at: event.at,
+        source_id: event.source_id,
+    };
+
+    let mut events: Vec<TimelineEvent> = patient_events(patient.id)
+        .into_iter()
+        .filter(|event| {
+            (from..=to).contains(&event.at)
+                && (query.kinds.is_empty() || query.kinds.contains(&event.kind))
+                && match query.after {
+                    Some(after) => cursor(event) > after,
+                    None => true,
+                }
+        })
+        .collect();
+    events.sort_by_key(|event| (event.at, event.source_id));
+
+    let limit = query.limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let next_cursor = if events.len() > limit {
+        Some(cursor(&events[limit - 1]))
+    } else {
+        None
+    };
+    events.truncate(limit);
+    Ok(TimelinePage {
+        events,
+        next_cursor,
+    })
+}
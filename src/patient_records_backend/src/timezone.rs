@@ -0,0 +1,179 @@
+use crate::{authorize_ref, impl_storable, Error, Memory, Recipient, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const DAY_SECONDS: i64 = 24 * 60 * 60;
+
+// A fixed offset from UTC, e.g. 120 for +02:00. Daylight saving changes are applied by
+// updating the offset
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub struct TimeZone {
+    pub utc_offset_minutes: i16,
+}
+
+impl_storable!(TimeZone, 32);
+
+thread_local! {
+    static TIMEZONES: RefCell<StableBTreeMap<(u8, u64), TimeZone, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+}
+
+// offset of a hospital or patient, UTC when none is set. doctors follow their hospital
+pub(crate) fn utc_offset(entity: &Recipient) -> i16 {
+    TIMEZONES
+        .with(|t| t.borrow().get(&entity.key()))
+        .map_or(0, |zone| zone.utc_offset_minutes)
+}
+
+// days since 1970-01-01 of a proleptic gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// the same local wall time the given number of months later, on the last day of the month
+// when it is shorter
+pub(crate) fn add_months(timestamp: u64, months: u32, offset_minutes: i16) -> u64 {
+    let local = timestamp as i64 + offset_minutes as i64 * 60 * 1_000_000_000;
+    let day_ns = DAY_SECONDS * 1_000_000_000;
+    let (year, month, day) = civil_from_days(local.div_euclid(day_ns));
+    let month_index = year * 12 + month - 1 + months as i64;
+    let (year, month) = (month_index.div_euclid(12), month_index.rem_euclid(12) + 1);
+    let month_length =
+        days_from_civil(year + month / 12, month % 12 + 1, 1) - days_from_civil(year, month, 1);
+    let days = days_from_civil(year, month, day.min(month_length));
+    (days * day_ns + local.rem_euclid(day_ns) - offset_minutes as i64 * 60 * 1_000_000_000) as u64
+}
+
+fn invalid_time(value: &str) -> Error {
+    Error::InvalidPayload {
+        msg: format!(
+            "Invalid time {}, expected e.g. 2024-03-01T09:30:00+01:00",
+            value
+        ),
+    }
+}
+
+// read "YYYY-MM-DDTHH:MM[:SS]" followed by "Z", "+HH:MM" or "-HH:MM". without an offset the
+// time is read in the given default offset
+pub(crate) fn parse_local_time(value: &str, default_offset_minutes: i16) -> Result<u64, Error> {
+    let number = |part: &str| -> Result<i64, Error> {
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid_time(value));
+        }
+        part.parse().map_err(|_| invalid_time(value))
+    };
+    let (date, rest) = value.split_once('T').ok_or_else(|| invalid_time(value))?;
+    let (clock, offset_minutes) = if let Some(clock) = rest.strip_suffix('Z') {
+        (clock, 0)
+    } else if let Some(index) = rest.rfind(['+', '-']) {
+        let (clock, offset) = rest.split_at(index);
+        let (hours, minutes) = offset[1..]
+            .split_once(':')
+            .ok_or_else(|| invalid_time(value))?;
+        let minutes = number(hours)? * 60 + number(minutes)?;
+        (
+            clock,
+            if offset.starts_with('-') {
+                -minutes
+            } else {
+                minutes
+            },
+        )
+    } else {
+        (rest, default_offset_minutes as i64)
+    };
+
+    let date: Vec<&str> = date.split('-').collect();
+    let clock: Vec<&str> = clock.split(':').collect();
+    if date.len() != 3 || !(2..=3).contains(&clock.len()) {
+        return Err(invalid_time(value));
+    }
+    let (year, month, day) = (number(date[0])?, number(date[1])?, number(date[2])?);
+    let hour = number(clock[0])?;
+    let minute = number(clock[1])?;
+    let second = clock.get(2).map_or(Ok(0), |second| number(second))?;
+    if !(1..=12).contains(&month)
+        || day < 1
+        || civil_from_days(days_from_civil(year, month, day)) != (year, month, day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+        || offset_minutes.abs() > 14 * 60
+    {
+        return Err(invalid_time(value));
+    }
+
+    let seconds =
+        days_from_civil(year, month, day) * DAY_SECONDS + hour * 3600 + minute * 60 + second
+            - offset_minutes * 60;
+    u64::try_from(seconds)
+        .map(|seconds| seconds * 1_000_000_000)
+        .map_err(|_| invalid_time(value))
+}
+
+// "YYYY-MM-DDTHH:MM:SS+HH:MM" of a UTC timestamp shown in the given offset
+pub(crate) fn format_local_time(timestamp: u64, offset_minutes: i16) -> String {
+    let local = timestamp as i64 / 1_000_000_000 + offset_minutes as i64 * 60;
+    let (year, month, day) = civil_from_days(local.div_euclid(DAY_SECONDS));
+    let seconds = local.rem_euclid(DAY_SECONDS);
+    let offset = (offset_minutes as i64).abs();
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        if offset_minutes < 0 { '-' } else { '+' },
+        offset / 60,
+        offset % 60
+    )
+}
+
+// set the UTC offset of a hospital or patient
+#[ic_cdk::update]
+fn set_timezone(entity: Recipient, password: String, zone: TimeZone) -> Result<TimeZone, Error> {
+    authorize_ref(entity, &password)?;
+    if let Recipient::Doctor(_) = entity {
+        return Err(Error::InvalidPayload {
+            msg: "Doctors use the timezone of their hospital".to_string(),
+        });
+    }
+    if !(-12 * 60..=14 * 60).contains(&zone.utc_offset_minutes) {
+        return Err(Error::InvalidPayload {
+            msg: "UTC offset must be between -12:00 and +14:00".to_string(),
+        });
+    }
+    TIMEZONES.with(|t| t.borrow_mut().insert(entity.key(), zone));
+    Ok(zone)
+}
+
+#[ic_cdk::query]
+fn get_timezone(entity: Recipient) -> TimeZone {
+    TimeZone {
+        utc_offset_minutes: utc_offset(&entity),
+    }
+}
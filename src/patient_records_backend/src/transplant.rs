@@ -0,0 +1,672 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_hospital, authorize_oversight, get_assigned_patient,
+    impl_storable, next_id, notify, text, Actor, BloodType, Error, Memory, OversightRole, Priority,
+    Recipient, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// how long the recipient hospital has to accept or decline an offer
+const OFFER_WINDOW_NS: u64 = 60 * 60 * 1_000_000_000;
+// waiting time stops adding to the score after ten years
+const MAX_WAITING_DAYS: u64 = 3650;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Organ {
+    Kidney,
+    Liver,
+    Heart,
+    Lung,
+    Pancreas,
+    Intestine,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum CandidateStatus {
+    Active,
+    // temporarily not eligible for offers, e.g. while too unwell to operate
+    Suspended { reason: String },
+    Transplanted { offer_id: u64 },
+    Removed { reason: String },
+}
+
+// What the priority score is computed from
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct PriorityInputs {
+    // 0 to 10, 10 the most urgent
+    pub medical_urgency: u8,
+    // panel reactive antibodies, 0 to 100; highly sensitised candidates match rarely
+    pub pra_percent: u8,
+    // MELD score for liver candidates, 6 to 40
+    pub meld_score: Option<u8>,
+    pub pediatric: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TransplantCandidate {
+    pub id: u64,
+    pub patient_id: u64,
+    pub hospital_id: u64,
+    pub doctor_id: u64,
+    pub organ: Organ,
+    pub blood_type: BloodType,
+    pub status: CandidateStatus,
+    pub inputs: PriorityInputs,
+    pub score: u64,
+    pub listed_at: u64,
+    pub updated_at: u64,
+}
+
+// One change to a candidate's priority or eligibility, never edited or removed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct PriorityChange {
+    pub id: u64,
+    pub candidate_id: u64,
+    pub hospital_id: u64,
+    pub actor: Actor,
+    pub change: String,
+    pub previous_score: Option<u64>,
+    pub new_score: u64,
+    pub reason: String,
+    pub at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum OfferStatus {
+    Pending,
+    Accepted,
+    Declined { reason: String },
+    Expired,
+}
+
+// An organ offered by the donor hospital to the hospital listing a candidate
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MatchOffer {
+    pub id: u64,
+    pub organ: Organ,
+    pub donor_hospital_id: u64,
+    pub donor_blood_type: BloodType,
+    pub candidate_id: u64,
+    pub recipient_hospital_id: u64,
+    pub status: OfferStatus,
+    pub offered_at: u64,
+    pub expires_at: u64,
+    pub responded_at: Option<u64>,
+}
+
+// A compatible candidate as the donor hospital sees it, without patient details
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TransplantMatch {
+    pub candidate_id: u64,
+    pub hospital_id: u64,
+    pub blood_type: BloodType,
+    pub score: u64,
+    pub listed_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ListCandidatePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub patient_id: u64,
+    pub organ: Organ,
+    pub inputs: PriorityInputs,
+    pub reason: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct PriorityUpdatePayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub candidate_id: u64,
+    pub inputs: PriorityInputs,
+    pub reason: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct CandidateStatusPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub candidate_id: u64,
+    // Active, Suspended or Removed; Transplanted is set by accepting an offer
+    pub status: CandidateStatus,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct MatchOfferPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub candidate_id: u64,
+    pub organ: Organ,
+    pub donor_blood_type: BloodType,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct OfferResponsePayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub offer_id: u64,
+    pub accept: bool,
+    pub reason: String,
+}
+
+impl_storable!(TransplantCandidate, 1024);
+impl_storable!(PriorityChange, 1024);
+impl_storable!(MatchOffer, 512);
+
+thread_local! {
+    static CANDIDATE_STORAGE: RefCell<StableBTreeMap<u64, TransplantCandidate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(99)))
+    ));
+
+    static PRIORITY_LOG: RefCell<StableBTreeMap<u64, PriorityChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(100)))
+    ));
+
+    static OFFER_STORAGE: RefCell<StableBTreeMap<u64, MatchOffer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(101)))
+    ));
+}
+
+fn validate_inputs(organ: Organ, inputs: &PriorityInputs) -> Result<(), Error> {
+    let meld_ok = match (organ, inputs.meld_score) {
+        (Organ::Liver, Some(meld)) => (6..=40).contains(&meld),
+        (Organ::Liver, None) => false,
+        (_, meld) => meld.is_none(),
+    };
+    if inputs.medical_urgency > 10 || inputs.pra_percent > 100 || !meld_ok {
+        return Err(Error::InvalidPayload {
+            msg: "Urgency is 0 to 10, PRA 0 to 100, and liver candidates need a MELD of 6 to 40"
+                .to_string(),
+        });
+    }
+    Ok(())
+}
+
+// urgency dominates, then waiting time in days, sensitisation, MELD and pediatric status
+fn priority_score(inputs: &PriorityInputs, listed_at: u64) -> u64 {
+    let waiting_days = (time().saturating_sub(listed_at) / DAY_NS).min(MAX_WAITING_DAYS);
+    inputs.medical_urgency as u64 * 10_000
+        + waiting_days
+        + inputs.pra_percent as u64 * 20
+        + inputs.meld_score.unwrap_or(0) as u64 * 100
+        + if inputs.pediatric { 1_000 } else { 0 }
+}
+
+// ABO compatibility is what matters for solid organs, the Rh factor does not
+fn abo_compatible(donor: BloodType, recipient: BloodType) -> bool {
+    let strip_rh = |blood_type: BloodType| match blood_type {
+        BloodType::APositive | BloodType::ANegative => BloodType::ANegative,
+        BloodType::BPositive | BloodType::BNegative => BloodType::BNegative,
+        BloodType::ABPositive | BloodType::ABNegative => BloodType::ABNegative,
+        BloodType::OPositive | BloodType::ONegative => BloodType::ONegative,
+    };
+    strip_rh(donor).can_donate_to(&strip_rh(recipient))
+}
+
+fn get_candidate(candidate_id: u64) -> Result<TransplantCandidate, Error> {
+    CANDIDATE_STORAGE
+        .with(|s| s.borrow().get(&candidate_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Transplant candidate of id: {} not found", candidate_id),
+        })
+}
+
+fn get_authorized_candidate(
+    doctor_id: u64,
+    doctor_password: &str,
+    candidate_id: u64,
+) -> Result<TransplantCandidate, Error> {
+    let doctor = authorize_doctor(doctor_id, doctor_password)?;
+    let candidate = get_candidate(candidate_id)?;
+    get_assigned_patient(&doctor, candidate.patient_id)?;
+    Ok(candidate)
+}
+
+fn save_candidate(candidate: &TransplantCandidate) {
+    CANDIDATE_STORAGE.with(|s| s.borrow_mut().insert(candidate.id, candidate.clone()));
+}
+
+fn get_offer(offer_id: u64) -> Result<MatchOffer, Error> {
+    OFFER_STORAGE
+        .with(|s| s.borrow().get(&offer_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Match offer of id: {} not found", offer_id),
+        })
+}
+
+// offers past their window count as expired even before anyone responds
+fn current_offer(offer: MatchOffer) -> MatchOffer {
+    if offer.status == OfferStatus::Pending && offer.expires_at <= time() {
+        MatchOffer {
+            status: OfferStatus::Expired,
+            ..offer
+        }
+    } else {
+        offer
+    }
+}
+
+fn has_pending_offer(candidate_id: u64) -> bool {
+    OFFER_STORAGE.with(|s| {
+        s.borrow().iter().any(|(_, offer)| {
+            offer.candidate_id == candidate_id
+                && current_offer(offer).status == OfferStatus::Pending
+        })
+    })
+}
+
+// append to the priority log and the audit log, so auditors see every change from both
+fn log_change(
+    candidate: &TransplantCandidate,
+    actor: Actor,
+    change: &str,
+    previous_score: Option<u64>,
+    reason: String,
+) {
+    let entry = PriorityChange {
+        id: next_id(),
+        candidate_id: candidate.id,
+        hospital_id: candidate.hospital_id,
+        actor: actor.clone(),
+        change: change.to_string(),
+        previous_score,
+        new_score: candidate.score,
+        reason,
+        at: time(),
+    };
+    PRIORITY_LOG.with(|s| s.borrow_mut().insert(entry.id, entry.clone()));
+    audit(
+        actor,
+        Some(candidate.hospital_id),
+        Some(candidate.patient_id),
+        "transplant_priority_changed",
+        format!(
+            "candidate {} {}: score {} -> {}, {}",
+            candidate.id,
+            change,
+            previous_score.map_or("none".to_string(), |score| score.to_string()),
+            candidate.score,
+            entry.reason
+        ),
+    );
+}
+
+fn require_reason(reason: &str) -> Result<(), Error> {
+    if reason.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Every transplant priority change needs a reason".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// put a patient on the waitlist for an organ
+#[ic_cdk::update]
+fn list_transplant_candidate(payload: ListCandidatePayload) -> Result<TransplantCandidate, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let patient = get_assigned_patient(&doctor, payload.patient_id)?;
+    let blood_type = patient.blood_type.ok_or(Error::InvalidPayload {
+        msg: format!("Patient of id: {} has no recorded blood type", patient.id),
+    })?;
+    validate_inputs(payload.organ, &payload.inputs)?;
+    require_reason(&payload.reason)?;
+    let already_listed = CANDIDATE_STORAGE.with(|s| {
+        s.borrow().iter().any(|(_, candidate)| {
+            candidate.patient_id == patient.id
+                && candidate.organ == payload.organ
+                && matches!(
+                    candidate.status,
+                    CandidateStatus::Active | CandidateStatus::Suspended { .. }
+                )
+        })
+    });
+    if already_listed {
+        return Err(Error::AlreadyInit {
+            msg: format!(
+                "Patient of id: {} is already listed for this organ",
+                patient.id
+            ),
+        });
+    }
+    let now = time();
+    let candidate = TransplantCandidate {
+        id: next_id(),
+        patient_id: patient.id,
+        hospital_id: doctor.hospital_id,
+        doctor_id: doctor.id,
+        organ: payload.organ,
+        blood_type,
+        status: CandidateStatus::Active,
+        score: priority_score(&payload.inputs, now),
+        inputs: payload.inputs,
+        listed_at: now,
+        updated_at: now,
+    };
+    save_candidate(&candidate);
+    log_change(
+        &candidate,
+        Actor::Doctor(doctor.id),
+        "listed",
+        None,
+        payload.reason,
+    );
+    Ok(candidate)
+}
+
+#[ic_cdk::update]
+fn update_transplant_priority(
+    payload: PriorityUpdatePayload,
+) -> Result<TransplantCandidate, Error> {
+    let candidate = get_authorized_candidate(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.candidate_id,
+    )?;
+    validate_inputs(candidate.organ, &payload.inputs)?;
+    require_reason(&payload.reason)?;
+    if !matches!(
+        candidate.status,
+        CandidateStatus::Active | CandidateStatus::Suspended { .. }
+    ) {
+        return Err(Error::InvalidPayload {
+            msg: format!("Candidate of id: {} is no longer listed", candidate.id),
+        });
+    }
+    let previous_score = candidate.score;
+    let updated = TransplantCandidate {
+        score: priority_score(&payload.inputs, candidate.listed_at),
+        inputs: payload.inputs,
+        updated_at: time(),
+        ..candidate
+    };
+    save_candidate(&updated);
+    log_change(
+        &updated,
+        Actor::Doctor(payload.doctor_id),
+        "inputs updated",
+        Some(previous_score),
+        payload.reason,
+    );
+    Ok(updated)
+}
+
+// suspend, reactivate or remove a candidate
+#[ic_cdk::update]
+fn set_transplant_status(payload: CandidateStatusPayload) -> Result<TransplantCandidate, Error> {
+    let candidate = get_authorized_candidate(
+        payload.doctor_id,
+        &payload.doctor_password,
+        payload.candidate_id,
+    )?;
+    let (change, reason) = match &payload.status {
+        CandidateStatus::Active => ("reactivated", "reactivated".to_string()),
+        CandidateStatus::Suspended { reason } => ("suspended", reason.clone()),
+        CandidateStatus::Removed { reason } => ("removed", reason.clone()),
+        CandidateStatus::Transplanted { .. } => {
+            return Err(Error::InvalidPayload {
+                msg: "A candidate is marked transplanted by accepting an offer".to_string(),
+            })
+        }
+    };
+    require_reason(&reason)?;
+    if matches!(
+        candidate.status,
+        CandidateStatus::Transplanted { .. } | CandidateStatus::Removed { .. }
+    ) {
+        return Err(Error::InvalidPayload {
+            msg: format!("Candidate of id: {} is no longer listed", candidate.id),
+        });
+    }
+    let previous_score = candidate.score;
+    let updated = TransplantCandidate {
+        status: payload.status,
+        score: priority_score(&candidate.inputs, candidate.listed_at),
+        updated_at: time(),
+        ..candidate
+    };
+    save_candidate(&updated);
+    log_change(
+        &updated,
+        Actor::Doctor(payload.doctor_id),
+        change,
+        Some(previous_score),
+        reason,
+    );
+    Ok(updated)
+}
+
+// active candidates a donor organ is ABO compatible with, highest priority first
+#[ic_cdk::query]
+fn get_transplant_matches(
+    hospital_id: u64,
+    hospital_password: String,
+    organ: Organ,
+    donor_blood_type: BloodType,
+) -> Result<Vec<TransplantMatch>, Error> {
+    authorize_hospital(hospital_id, &hospital_password)?;
+    let mut matches: Vec<TransplantMatch> = CANDIDATE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, candidate)| candidate)
+            .filter(|candidate| {
+                candidate.organ == organ
+                    && candidate.status == CandidateStatus::Active
+                    && abo_compatible(donor_blood_type, candidate.blood_type)
+                    && !has_pending_offer(candidate.id)
+            })
+            .map(|candidate| TransplantMatch {
+                candidate_id: candidate.id,
+                hospital_id: candidate.hospital_id,
+                blood_type: candidate.blood_type,
+                score: priority_score(&candidate.inputs, candidate.listed_at),
+                listed_at: candidate.listed_at,
+            })
+            .collect()
+    });
+    matches.sort_by_key(|candidate| std::cmp::Reverse(candidate.score));
+    Ok(matches)
+}
+
+// offer a donor organ to the hospital listing the candidate
+#[ic_cdk::update]
+fn make_match_offer(payload: MatchOfferPayload) -> Result<MatchOffer, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let candidate = get_candidate(payload.candidate_id)?;
+    if candidate.organ != payload.organ || candidate.status != CandidateStatus::Active {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Candidate of id: {} is not actively listed for this organ",
+                candidate.id
+            ),
+        });
+    }
+    if !abo_compatible(payload.donor_blood_type, candidate.blood_type) {
+        return Err(Error::InvalidPayload {
+            msg: "Donor blood group is incompatible with the candidate".to_string(),
+        });
+    }
+    if has_pending_offer(candidate.id) {
+        return Err(Error::AlreadyInit {
+            msg: format!(
+                "Candidate of id: {} already has a pending offer",
+                candidate.id
+            ),
+        });
+    }
+    let now = time();
+    let offer = MatchOffer {
+        id: next_id(),
+        organ: payload.organ,
+        donor_hospital_id: hospital.id,
+        donor_blood_type: payload.donor_blood_type,
+        candidate_id: candidate.id,
+        recipient_hospital_id: candidate.hospital_id,
+        status: OfferStatus::Pending,
+        offered_at: now,
+        expires_at: now + OFFER_WINDOW_NS,
+        responded_at: None,
+    };
+    OFFER_STORAGE.with(|s| s.borrow_mut().insert(offer.id, offer.clone()));
+    audit(
+        Actor::Hospital(hospital.id),
+        Some(candidate.hospital_id),
+        Some(candidate.patient_id),
+        "transplant_offer_made",
+        format!("offer {} to candidate {}", offer.id, candidate.id),
+    );
+    for recipient in [
+        Recipient::Hospital(candidate.hospital_id),
+        Recipient::Doctor(candidate.doctor_id),
+    ] {
+        notify(
+            recipient,
+            Priority::High,
+            text(
+                "transplant.offer",
+                "Organ offer {offer} for transplant candidate {candidate}, respond within the hour",
+                vec![
+                    ("offer", offer.id.to_string()),
+                    ("candidate", candidate.id.to_string()),
+                ],
+            ),
+        );
+    }
+    Ok(offer)
+}
+
+// the recipient hospital accepts or declines an offer within its window
+#[ic_cdk::update]
+fn respond_to_match_offer(payload: OfferResponsePayload) -> Result<MatchOffer, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let offer = current_offer(get_offer(payload.offer_id)?);
+    if offer.recipient_hospital_id != hospital.id {
+        return Err(Error::Unauthorized {
+            msg: format!("Match offer of id: {} is for another hospital", offer.id),
+        });
+    }
+    if offer.status != OfferStatus::Pending {
+        OFFER_STORAGE.with(|s| s.borrow_mut().insert(offer.id, offer.clone()));
+        return Err(Error::InvalidPayload {
+            msg: format!("Match offer of id: {} is no longer pending", offer.id),
+        });
+    }
+    if !payload.accept {
+        require_reason(&payload.reason)?;
+    }
+    let responded = MatchOffer {
+        status: if payload.accept {
+            OfferStatus::Accepted
+        } else {
+            OfferStatus::Declined {
+                reason: payload.reason.clone(),
+            }
+        },
+        responded_at: Some(time()),
+        ..offer
+    };
+    OFFER_STORAGE.with(|s| s.borrow_mut().insert(responded.id, responded.clone()));
+    let candidate = get_candidate(responded.candidate_id)?;
+    if payload.accept {
+        let transplanted = TransplantCandidate {
+            status: CandidateStatus::Transplanted {
+                offer_id: responded.id,
+            },
+            updated_at: time(),
+            ..candidate
+        };
+        save_candidate(&transplanted);
+        log_change(
+            &transplanted,
+            Actor::Hospital(hospital.id),
+            "offer accepted",
+            Some(transplanted.score),
+            format!("offer {}", responded.id),
+        );
+    } else {
+        audit(
+            Actor::Hospital(hospital.id),
+            Some(hospital.id),
+            Some(candidate.patient_id),
+            "transplant_offer_declined",
+            format!("offer {}: {}", responded.id, payload.reason),
+        );
+    }
+    notify(
+        Recipient::Hospital(responded.donor_hospital_id),
+        Priority::High,
+        text(
+            "transplant.offer_response",
+            "Organ offer {offer} was {response}",
+            vec![
+                ("offer", responded.id.to_string()),
+                (
+                    "response",
+                    if payload.accept {
+                        "accepted"
+                    } else {
+                        "declined"
+                    }
+                    .to_string(),
+                ),
+            ],
+        ),
+    );
+    Ok(responded)
+}
+
+// offers the hospital made or received
+#[ic_cdk::query]
+fn get_match_offers(hospital_id: u64, hospital_password: String) -> Result<Vec<MatchOffer>, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    Ok(OFFER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, offer)| current_offer(offer))
+            .filter(|offer| {
+                offer.donor_hospital_id == hospital.id || offer.recipient_hospital_id == hospital.id
+            })
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_transplant_candidates(
+    hospital_id: u64,
+    hospital_password: String,
+) -> Result<Vec<TransplantCandidate>, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    Ok(CANDIDATE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, candidate)| candidate)
+            .filter(|candidate| candidate.hospital_id == hospital.id)
+            .collect()
+    }))
+}
+
+// the full priority history of the hospital's candidates, or of one of them, for oversight
+#[ic_cdk::query]
+fn get_transplant_priority_log(
+    role: OversightRole,
+    password: String,
+    candidate_id: Option<u64>,
+) -> Result<Vec<PriorityChange>, Error> {
+    let hospital_id = authorize_oversight(&role, &password)?;
+    Ok(PRIORITY_LOG.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| {
+                entry.hospital_id == hospital_id
+                    && (candidate_id.is_none() || candidate_id == Some(entry.candidate_id))
+            })
+            .collect()
+    }))
+}
@@ -0,0 +1,451 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, authorize_hospital, authorize_patient, check_site, impl_storable,
+    next_id, on_call_doctor, page, patient_header, text, Actor, Error, Memory, Recipient,
+    HOSPITAL_STORAGE, MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// assumed time a doctor needs per patient until the hospital has its own history
+const DEFAULT_SERVICE_TIME_NS: u64 = 15 * 60 * 1_000_000_000;
+// keeps a ticket inside its store and the on-call page inside a notification
+const MAX_COMPLAINT_BYTES: usize = 500;
+
+// Urgency levels, most urgent first so they sort ahead in the queue
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum Urgency {
+    Immediate,
+    Emergency,
+    Urgent,
+    Standard,
+    NonUrgent,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum TicketStatus {
+    Waiting,
+    Claimed,
+    Completed,
+    Left,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct TriageTicket {
+    pub id: u64,
+    pub hospital_id: u64,
+    // the site the patient is waiting at, None for hospitals without sites
+    pub site_id: Option<u64>,
+    pub patient_id: u64,
+    pub urgency: Urgency,
+    pub complaint: String,
+    pub status: TicketStatus,
+    pub doctor_id: Option<u64>,
+    pub enqueued_at: u64,
+    pub claimed_at: Option<u64>,
+    pub closed_at: Option<u64>,
+}
+
+impl_storable!(TriageTicket, 1024);
+
+thread_local! {
+    static TRIAGE_STORAGE: RefCell<StableBTreeMap<u64, TriageTicket, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    // (hospital id, site id or 0 for the whole hospital) -> changes made to that queue
+    static QUEUE_VERSIONS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(115)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EnqueuePatientPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub site_id: Option<u64>,
+    pub patient_id: u64,
+    pub urgency: Urgency,
+    pub complaint: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct ClaimNextPatientPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    // claim from one site's queue instead of the whole organization's
+    pub site_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct CloseTicketPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub ticket_id: u64,
+    // true when the patient was seen, false when they left before being seen
+    pub seen: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct QueuePositionPayload {
+    pub ticket_id: u64,
+    pub patient_id: u64,
+    pub patient_password: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct QueuePosition {
+    pub ticket: TriageTicket,
+    // 1-based position among waiting tickets, 0 once the ticket left the queue
+    pub position: u64,
+    pub estimated_wait_ns: u64,
+}
+
+// What a waiting room display or patient phone polls, without any clinical details
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct QueueStatus {
+    pub ticket_id: u64,
+    pub status: TicketStatus,
+    // 1-based position among waiting tickets, 0 once the ticket left the queue
+    pub position: u64,
+    pub estimated_wait_ns: u64,
+    // bumped whenever a ticket of the queue changes, poll again with it to skip the rest
+    pub version: u64,
+    // false when the caller already has this version and the rest is left out
+    pub changed: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct TriageAnalyticsPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub site_id: Option<u64>,
+    pub from: u64,
+    pub to: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct TriageAnalytics {
+    pub enqueued: u64,
+    pub waiting: u64,
+    pub claimed: u64,
+    pub completed: u64,
+    pub left: u64,
+    pub average_wait_ns: u64,
+    pub average_service_ns: u64,
+}
+
+fn get_ticket(ticket_id: u64) -> Result<TriageTicket, Error> {
+    TRIAGE_STORAGE
+        .with(|s| s.borrow().get(&ticket_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Triage ticket of id: {} not found", ticket_id),
+        })
+}
+
+// tickets of a hospital, or only those of one of its sites
+fn hospital_tickets(hospital_id: u64, site_id: Option<u64>) -> Vec<TriageTicket> {
+    TRIAGE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, ticket)| ticket)
+            .filter(|ticket| {
+                ticket.hospital_id == hospital_id
+                    && (site_id.is_none() || ticket.site_id == site_id)
+            })
+            .collect()
+    })
+}
+
+pub(crate) fn queue_version(hospital_id: u64, site_id: Option<u64>) -> u64 {
+    QUEUE_VERSIONS
+        .with(|v| v.borrow().get(&(hospital_id, site_id.unwrap_or(0))))
+        .unwrap_or(0)
+}
+
+// record a change to a ticket, which changes its site's queue and the hospital's
+fn save_ticket(ticket: &TriageTicket) {
+    TRIAGE_STORAGE.with(|s| s.borrow_mut().insert(ticket.id, ticket.clone()));
+    QUEUE_VERSIONS.with(|v| {
+        let mut versions = v.borrow_mut();
+        let mut queues = vec![(ticket.hospital_id, 0)];
+        if let Some(site_id) = ticket.site_id {
+            queues.push((ticket.hospital_id, site_id));
+        }
+        for queue in queues {
+            let version = versions.get(&queue).unwrap_or(0) + 1;
+            versions.insert(queue, version);
+        }
+    });
+}
+
+// waiting tickets of a hospital or site in the order doctors will claim them
+pub(crate) fn waiting_queue(hospital_id: u64, site_id: Option<u64>) -> Vec<TriageTicket> {
+    let mut waiting: Vec<TriageTicket> = hospital_tickets(hospital_id, site_id)
+        .into_iter()
+        .filter(|ticket| ticket.status == TicketStatus::Waiting)
+        .collect();
+    waiting.sort_by_key(|ticket| (ticket.urgency, ticket.enqueued_at));
+    waiting
+}
+
+// average time from claim to completion, falling back to the default
+fn average_service_time(hospital_id: u64) -> u64 {
+    let durations: Vec<u64> = hospital_tickets(hospital_id, None)
+        .iter()
+        .filter(|ticket| ticket.status == TicketStatus::Completed)
+        .filter_map(|ticket| Some(ticket.closed_at? - ticket.claimed_at?))
+        .collect();
+    match durations.len() {
+        0 => DEFAULT_SERVICE_TIME_NS,
+        n => durations.iter().sum::<u64>() / n as u64,
+    }
+}
+
+// put a patient into the hospital's waiting list
+#[ic_cdk::update]
+fn enqueue_patient(payload: EnqueuePatientPayload) -> Result<TriageTicket, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    enqueue_ticket(
+        hospital.id,
+        payload.site_id,
+        payload.patient_id,
+        payload.urgency,
+        payload.complaint,
+    )
+}
+
+pub(crate) fn enqueue_ticket(
+    hospital_id: u64,
+    site_id: Option<u64>,
+    patient_id: u64,
+    urgency: Urgency,
+    complaint: String,
+) -> Result<TriageTicket, Error> {
+    check_site(hospital_id, site_id)?;
+    if complaint.len() > MAX_COMPLAINT_BYTES {
+        return Err(Error::InvalidPayload {
+            msg: format!("Complaints hold at most {} bytes", MAX_COMPLAINT_BYTES),
+        });
+    }
+    if !PATIENT_STORAGE.with(|patients| patients.borrow().contains_key(&patient_id)) {
+        return Err(Error::NotFound {
+            msg: format!("Patient of id: {} not found", patient_id),
+        });
+    }
+    if waiting_queue(hospital_id, None)
+        .iter()
+        .any(|ticket| ticket.patient_id == patient_id)
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("Patient of id: {} is already waiting", patient_id),
+        });
+    }
+
+    let ticket = TriageTicket {
+        id: next_id(),
+        hospital_id,
+        site_id,
+        patient_id,
+        urgency,
+        complaint,
+        status: TicketStatus::Waiting,
+        doctor_id: None,
+        enqueued_at: time(),
+        claimed_at: None,
+        closed_at: None,
+    };
+    save_ticket(&ticket);
+    if ticket.urgency <= Urgency::Emergency {
+        page_on_call(&ticket);
+    }
+    Ok(ticket)
+}
+
+// an emergency arrival pages the hospital's on-call doctor, preferring the patient's own
+// doctors, or the hospital when nobody is on call
+fn page_on_call(ticket: &TriageTicket) {
+    let care_team = patient_header(ticket.patient_id).map_or(vec![], |patient| patient.doctors_ids);
+    let message = text(
+        "triage.emergency",
+        "Emergency arrival: patient {patient}, ticket {ticket}: {complaint}",
+        vec![
+            ("patient", ticket.patient_id.to_string()),
+            ("ticket", ticket.id.to_string()),
+            ("complaint", ticket.complaint.clone()),
+        ],
+    );
+    let recipient = match on_call_doctor(ticket.hospital_id, &care_team, ticket.enqueued_at) {
+        Some(doctor_id) => Recipient::Doctor(doctor_id),
+        None => Recipient::Hospital(ticket.hospital_id),
+    };
+    page(recipient, message);
+    audit(
+        Actor::System,
+        Some(ticket.hospital_id),
+        Some(ticket.patient_id),
+        "triage_on_call_paged",
+        format!("ticket {}", ticket.id),
+    );
+}
+
+// doctor takes the most urgent, longest waiting patient of their hospital or site
+#[ic_cdk::update]
+fn claim_next_patient(payload: ClaimNextPatientPayload) -> Result<TriageTicket, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    check_site(doctor.hospital_id, payload.site_id)?;
+    match waiting_queue(doctor.hospital_id, payload.site_id)
+        .into_iter()
+        .next()
+    {
+        Some(ticket) => {
+            let claimed = TriageTicket {
+                status: TicketStatus::Claimed,
+                doctor_id: Some(doctor.id),
+                claimed_at: Some(time()),
+                ..ticket
+            };
+            save_ticket(&claimed);
+            Ok(claimed)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("No patients waiting at hospital: {}", doctor.hospital_id),
+        }),
+    }
+}
+
+// take a ticket out of the queue, either seen by the doctor or left unseen
+#[ic_cdk::update]
+fn close_triage_ticket(payload: CloseTicketPayload) -> Result<TriageTicket, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    let ticket = get_ticket(payload.ticket_id)?;
+    if ticket.hospital_id != hospital.id {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "Triage ticket of id: {} belongs to another hospital",
+                ticket.id
+            ),
+        });
+    }
+
+    let status = match (&ticket.status, payload.seen) {
+        (TicketStatus::Claimed, true) => TicketStatus::Completed,
+        (TicketStatus::Waiting, false) | (TicketStatus::Claimed, false) => TicketStatus::Left,
+        _ => {
+            return Err(Error::InvalidPayload {
+                msg: format!("Triage ticket of id: {} cannot be closed", ticket.id),
+            })
+        }
+    };
+    let closed = TriageTicket {
+        status,
+        closed_at: Some(time()),
+        ..ticket
+    };
+    save_ticket(&closed);
+    Ok(closed)
+}
+
+// patient checks their place in the queue and the estimated wait
+#[ic_cdk::query]
+fn get_queue_position(payload: QueuePositionPayload) -> Result<QueuePosition, Error> {
+    let patient = authorize_patient(payload.patient_id, &payload.patient_password)?;
+    let ticket = get_ticket(payload.ticket_id)?;
+    if ticket.patient_id != patient.id {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "Triage ticket of id: {} belongs to another patient",
+                ticket.id
+            ),
+        });
+    }
+    Ok(queue_position(ticket))
+}
+
+pub(crate) fn queue_position(ticket: TriageTicket) -> QueuePosition {
+    let position = waiting_queue(ticket.hospital_id, ticket.site_id)
+        .iter()
+        .position(|waiting| waiting.id == ticket.id)
+        .map_or(0, |index| index as u64 + 1);
+    let doctors = HOSPITAL_STORAGE
+        .with(|hospitals| hospitals.borrow().get(&ticket.hospital_id))
+        .map_or(1, |hospital| hospital.doctors_ids.len().max(1) as u64);
+    let estimated_wait_ns =
+        position.saturating_sub(1) * average_service_time(ticket.hospital_id) / doctors;
+    QueuePosition {
+        ticket,
+        position,
+        estimated_wait_ns,
+    }
+}
+
+// cheap to poll: callers pass the version they last saw and get the position again only
+// once the queue has changed since
+#[ic_cdk::query]
+fn get_queue_status(ticket_id: u64, known_version: Option<u64>) -> Result<QueueStatus, Error> {
+    let ticket = get_ticket(ticket_id)?;
+    let version = queue_version(ticket.hospital_id, ticket.site_id);
+    if known_version == Some(version) {
+        return Ok(QueueStatus {
+            ticket_id,
+            status: ticket.status,
+            position: 0,
+            estimated_wait_ns: 0,
+            version,
+            changed: false,
+        });
+    }
+    let position = queue_position(ticket);
+    Ok(QueueStatus {
+        ticket_id,
+        status: position.ticket.status,
+        position: position.position,
+        estimated_wait_ns: position.estimated_wait_ns,
+        version,
+        changed: true,
+    })
+}
+
+// throughput of the hospital's queue for tickets enqueued in the given period
+#[ic_cdk::query]
+fn get_triage_analytics(payload: TriageAnalyticsPayload) -> Result<TriageAnalytics, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    check_site(hospital.id, payload.site_id)?;
+    let tickets: Vec<TriageTicket> = hospital_tickets(hospital.id, payload.site_id)
+        .into_iter()
+        .filter(|ticket| ticket.enqueued_at >= payload.from && ticket.enqueued_at <= payload.to)
+        .collect();
+
+    let mut analytics = TriageAnalytics {
+        enqueued: tickets.len() as u64,
+        ..Default::default()
+    };
+    let mut waits = vec![];
+    let mut services = vec![];
+    for ticket in tickets.iter() {
+        match ticket.status {
+            TicketStatus::Waiting => analytics.waiting += 1,
+            TicketStatus::Claimed => analytics.claimed += 1,
+            TicketStatus::Completed => analytics.completed += 1,
+            TicketStatus::Left => analytics.left += 1,
+        }
+        if let Some(claimed_at) = ticket.claimed_at {
+            waits.push(claimed_at - ticket.enqueued_at);
+            if let (TicketStatus::Completed, Some(closed_at)) = (&ticket.status, ticket.closed_at) {
+                services.push(closed_at - claimed_at);
+            }
+        }
+    }
+    if !waits.is_empty() {
+        analytics.average_wait_ns = waits.iter().sum::<u64>() / waits.len() as u64;
+    }
+    if !services.is_empty() {
+        analytics.average_service_ns = services.iter().sum::<u64>() / services.len() as u64;
+    }
+    Ok(analytics)
+}
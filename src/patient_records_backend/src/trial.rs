@@ -0,0 +1,516 @@
+use crate::time;
+use crate::{
+    age_in_years, audit, authenticate_patient, authorize_doctor, authorize_hospital,
+    authorize_patient, caller, get_assigned_patient, impl_storable, issue_consent_receipt, next_id,
+    patient_prescriptions, patient_problems, to_hex, Actor, ConsentAction, Error, Memory, Patient,
+    PatientConsent, MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use candid::Principal;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const WEEK_NS: u64 = 7 * DAY_NS;
+
+// Who may take part, checked against the problem list and current medications
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct EligibilityCriteria {
+    // the patient needs an active problem under at least one of these ICD-10 prefixes
+    pub required_icd_prefixes: Vec<String>,
+    pub excluded_icd_prefixes: Vec<String>,
+    // medication names that exclude the patient while they are being taken
+    pub excluded_medications: Vec<String>,
+    pub min_age: Option<u64>,
+    pub max_age: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Trial {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub title: String,
+    // the principal the sponsor reads the blinded export with
+    pub sponsor: Principal,
+    pub arms: Vec<String>,
+    pub criteria: EligibilityCriteria,
+    pub open: bool,
+    // mixed into subject codes so the sponsor cannot link them back to patient ids
+    pub salt: Vec<u8>,
+    pub created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum EnrollmentStatus {
+    Consented,
+    Enrolled,
+    Withdrawn { reason: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Enrollment {
+    pub id: u64,
+    pub trial_id: u64,
+    pub patient_id: u64,
+    pub status: EnrollmentStatus,
+    // index into the trial's arms once enrolled
+    pub arm: Option<u32>,
+    pub enrolled_by: Option<u64>,
+    pub consented_at: u64,
+    pub enrolled_at: Option<u64>,
+    pub withdrawn_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct EligibilityResult {
+    pub eligible: bool,
+    // why the patient does not qualify, empty when eligible
+    pub reasons: Vec<String>,
+}
+
+// One enrolled subject as the sponsor sees it: a pseudonymous code and a coded arm
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct BlindedTrialRecord {
+    pub subject_code: String,
+    // "ARM-1", "ARM-2", ... so the export does not reveal the intervention
+    pub arm_code: String,
+    pub age_years: Option<u64>,
+    pub active_icd_codes: Vec<String>,
+    pub current_medications: Vec<String>,
+    // weeks since the unix epoch
+    pub enrolled_week: Option<u64>,
+    pub withdrawn: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct TrialPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub title: String,
+    pub sponsor: Option<Principal>,
+    pub arms: Vec<String>,
+    pub criteria: EligibilityCriteria,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct EnrollPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub enrollment_id: u64,
+}
+
+impl_storable!(Trial, 4096);
+impl_storable!(Enrollment, 512);
+
+thread_local! {
+    static TRIAL_STORAGE: RefCell<StableBTreeMap<u64, Trial, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(102)))
+    ));
+
+    static ENROLLMENT_STORAGE: RefCell<StableBTreeMap<u64, Enrollment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(103)))
+    ));
+}
+
+fn get_trial(trial_id: u64) -> Result<Trial, Error> {
+    TRIAL_STORAGE
+        .with(|s| s.borrow().get(&trial_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Trial of id: {} not found", trial_id),
+        })
+}
+
+fn get_enrollment(enrollment_id: u64) -> Result<Enrollment, Error> {
+    ENROLLMENT_STORAGE
+        .with(|s| s.borrow().get(&enrollment_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Enrollment of id: {} not found", enrollment_id),
+        })
+}
+
+fn save_enrollment(enrollment: &Enrollment) {
+    ENROLLMENT_STORAGE.with(|s| s.borrow_mut().insert(enrollment.id, enrollment.clone()));
+}
+
+fn trial_enrollments(trial_id: u64) -> Vec<Enrollment> {
+    ENROLLMENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, enrollment)| enrollment)
+            .filter(|enrollment| enrollment.trial_id == trial_id)
+            .collect()
+    })
+}
+
+// names of the medications whose course has not run out yet
+fn current_medications(patient_id: u64) -> Vec<String> {
+    let now = time();
+    patient_prescriptions(patient_id)
+        .into_iter()
+        .filter(|(entry, prescription)| {
+            entry.recorded_at + prescription.duration_days as u64 * DAY_NS > now
+        })
+        .map(|(_, prescription)| prescription.medication)
+        .collect()
+}
+
+fn active_icd_codes(patient_id: u64) -> Vec<String> {
+    patient_problems(patient_id, true)
+        .into_iter()
+        .map(|problem| problem.icd_code)
+        .collect()
+}
+
+fn check_eligibility(trial: &Trial, patient: &Patient) -> EligibilityResult {
+    let criteria = &trial.criteria;
+    let icd_codes = active_icd_codes(patient.id);
+    let medications: Vec<String> = current_medications(patient.id)
+        .iter()
+        .map(|medication| medication.to_lowercase())
+        .collect();
+    let mut reasons = vec![];
+    let has_prefix = |prefix: &String| icd_codes.iter().any(|code| code.starts_with(prefix));
+    if !criteria.required_icd_prefixes.is_empty()
+        && !criteria.required_icd_prefixes.iter().any(has_prefix)
+    {
+        reasons.push(format!(
+            "no active problem under {}",
+            criteria.required_icd_prefixes.join(", ")
+        ));
+    }
+    for prefix in criteria
+        .excluded_icd_prefixes
+        .iter()
+        .filter(|p| has_prefix(p))
+    {
+        reasons.push(format!("active problem under excluded code {}", prefix));
+    }
+    for excluded in &criteria.excluded_medications {
+        let excluded_lower = excluded.to_lowercase();
+        if medications
+            .iter()
+            .any(|medication| medication.contains(&excluded_lower))
+        {
+            reasons.push(format!("currently taking {}", excluded));
+        }
+    }
+    if criteria.min_age.is_some() || criteria.max_age.is_some() {
+        match age_in_years(patient) {
+            None => reasons.push("date of birth is not recorded".to_string()),
+            Some(age) => {
+                if criteria.min_age.is_some_and(|min| age < min)
+                    || criteria.max_age.is_some_and(|max| age > max)
+                {
+                    reasons.push(format!("age {} is outside the trial's range", age));
+                }
+            }
+        }
+    }
+    EligibilityResult {
+        eligible: reasons.is_empty(),
+        reasons,
+    }
+}
+
+fn subject_code(trial: &Trial, patient_id: u64) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(&trial.salt);
+    hasher.update(trial.id.to_be_bytes());
+    hasher.update(patient_id.to_be_bytes());
+    to_hex(&hasher.finalize()[..8])
+}
+
+// the arm with the fewest enrolled subjects, the first of them on a tie
+fn next_arm(trial: &Trial) -> u32 {
+    let mut counts = vec![0u64; trial.arms.len()];
+    for enrollment in trial_enrollments(trial.id) {
+        if let (Some(arm), EnrollmentStatus::Enrolled) = (enrollment.arm, &enrollment.status) {
+            if let Some(count) = counts.get_mut(arm as usize) {
+                *count += 1;
+            }
+        }
+    }
+    counts
+        .iter()
+        .enumerate()
+        .min_by_key(|(index, count)| (**count, *index))
+        .map_or(0, |(index, _)| index as u32)
+}
+
+#[ic_cdk::update]
+async fn create_trial(payload: TrialPayload) -> Result<Trial, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if payload.title.trim().is_empty() || payload.arms.len() < 2 {
+        return Err(Error::InvalidPayload {
+            msg: "A trial needs a title and at least two arms".to_string(),
+        });
+    }
+    let sponsor = payload.sponsor.ok_or(Error::InvalidPayload {
+        msg: "A trial needs a sponsor principal".to_string(),
+    })?;
+    if sponsor == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "The sponsor cannot be the anonymous principal".to_string(),
+        });
+    }
+    let (random,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| Error::InvalidPayload {
+            msg: format!("Could not create trial: {:?} {}", code, msg),
+        })?;
+    let normalize = |prefixes: Vec<String>| -> Vec<String> {
+        prefixes
+            .into_iter()
+            .map(|prefix| prefix.trim().to_uppercase())
+            .filter(|prefix| !prefix.is_empty())
+            .collect()
+    };
+    let criteria = EligibilityCriteria {
+        required_icd_prefixes: normalize(payload.criteria.required_icd_prefixes),
+        excluded_icd_prefixes: normalize(payload.criteria.excluded_icd_prefixes),
+        ..payload.criteria
+    };
+    let trial = Trial {
+        id: next_id(),
+        hospital_id: hospital.id,
+        title: payload.title,
+        sponsor,
+        arms: payload.arms,
+        criteria,
+        open: true,
+        salt: random[..16].to_vec(),
+        created_at: time(),
+    };
+    TRIAL_STORAGE.with(|s| s.borrow_mut().insert(trial.id, trial.clone()));
+    Ok(trial)
+}
+
+// stop taking new consents and enrollments
+#[ic_cdk::update]
+fn close_trial(hospital_id: u64, hospital_password: String, trial_id: u64) -> Result<(), Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    let trial = get_trial(trial_id)?;
+    if trial.hospital_id != hospital.id {
+        return Err(Error::Unauthorized {
+            msg: format!("Trial of id: {} belongs to another hospital", trial.id),
+        });
+    }
+    TRIAL_STORAGE.with(|s| {
+        s.borrow_mut().insert(
+            trial.id,
+            Trial {
+                open: false,
+                ..trial
+            },
+        )
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn check_trial_eligibility(
+    doctor_id: u64,
+    doctor_password: String,
+    patient_id: u64,
+    trial_id: u64,
+) -> Result<EligibilityResult, Error> {
+    let doctor = authorize_doctor(doctor_id, &doctor_password)?;
+    let patient = get_assigned_patient(&doctor, patient_id)?;
+    let trial = get_trial(trial_id)?;
+    Ok(check_eligibility(&trial, &patient))
+}
+
+// the patient's informed consent to take part, the first step before enrollment
+#[ic_cdk::update]
+fn consent_to_trial(consent: PatientConsent, trial_id: u64) -> Result<Enrollment, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    let trial = get_trial(trial_id)?;
+    if !trial.open {
+        return Err(Error::InvalidPayload {
+            msg: format!("Trial of id: {} is closed", trial.id),
+        });
+    }
+    let existing = trial_enrollments(trial.id).into_iter().any(|enrollment| {
+        enrollment.patient_id == patient.id
+            && !matches!(enrollment.status, EnrollmentStatus::Withdrawn { .. })
+    });
+    if existing {
+        return Err(Error::AlreadyInit {
+            msg: format!("Patient is already taking part in trial {}", trial.id),
+        });
+    }
+    let enrollment = Enrollment {
+        id: next_id(),
+        trial_id: trial.id,
+        patient_id: patient.id,
+        status: EnrollmentStatus::Consented,
+        arm: None,
+        enrolled_by: None,
+        consented_at: time(),
+        enrolled_at: None,
+        withdrawn_at: None,
+    };
+    save_enrollment(&enrollment);
+    audit(
+        Actor::Patient(patient.id),
+        Some(trial.hospital_id),
+        Some(patient.id),
+        "trial_consented",
+        format!("trial {}", trial.id),
+    );
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Granted,
+        "clinical_trial",
+        enrollment.id,
+        format!("trial {} {}", trial.id, trial.title),
+        vec![],
+        None,
+    );
+    Ok(enrollment)
+}
+
+// enroll a consented patient who meets the criteria and assign them an arm
+#[ic_cdk::update]
+fn enroll_in_trial(payload: EnrollPayload) -> Result<Enrollment, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let enrollment = get_enrollment(payload.enrollment_id)?;
+    let patient = get_assigned_patient(&doctor, enrollment.patient_id)?;
+    let trial = get_trial(enrollment.trial_id)?;
+    if !trial.open || enrollment.status != EnrollmentStatus::Consented {
+        return Err(Error::InvalidPayload {
+            msg: "Only consented patients of an open trial can be enrolled".to_string(),
+        });
+    }
+    let eligibility = check_eligibility(&trial, &patient);
+    if !eligibility.eligible {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Patient is not eligible: {}",
+                eligibility.reasons.join("; ")
+            ),
+        });
+    }
+    let enrolled = Enrollment {
+        status: EnrollmentStatus::Enrolled,
+        arm: Some(next_arm(&trial)),
+        enrolled_by: Some(doctor.id),
+        enrolled_at: Some(time()),
+        ..enrollment
+    };
+    save_enrollment(&enrolled);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(trial.hospital_id),
+        Some(patient.id),
+        "trial_enrolled",
+        format!("trial {} enrollment {}", trial.id, enrolled.id),
+    );
+    Ok(enrolled)
+}
+
+// patients can leave a trial at any time
+#[ic_cdk::update]
+fn withdraw_from_trial(
+    consent: PatientConsent,
+    enrollment_id: u64,
+    reason: String,
+) -> Result<Enrollment, Error> {
+    let patient = authorize_patient(consent.patient_id, &consent.patient_password)?;
+    let enrollment = get_enrollment(enrollment_id)?;
+    if enrollment.patient_id != patient.id {
+        return Err(Error::Unauthorized {
+            msg: format!("Enrollment of id: {} is another patient's", enrollment.id),
+        });
+    }
+    if matches!(enrollment.status, EnrollmentStatus::Withdrawn { .. }) {
+        return Err(Error::InvalidPayload {
+            msg: format!("Enrollment of id: {} is already withdrawn", enrollment.id),
+        });
+    }
+    let withdrawn = Enrollment {
+        status: EnrollmentStatus::Withdrawn { reason },
+        withdrawn_at: Some(time()),
+        ..enrollment
+    };
+    save_enrollment(&withdrawn);
+    audit(
+        Actor::Patient(patient.id),
+        None,
+        Some(patient.id),
+        "trial_withdrawn",
+        format!("trial {} enrollment {}", withdrawn.trial_id, withdrawn.id),
+    );
+    issue_consent_receipt(
+        patient.id,
+        ConsentAction::Revoked,
+        "clinical_trial",
+        withdrawn.id,
+        format!("trial {}", withdrawn.trial_id),
+        vec![],
+        None,
+    );
+    Ok(withdrawn)
+}
+
+#[ic_cdk::query]
+fn get_patient_trial_enrollments(consent: PatientConsent) -> Result<Vec<Enrollment>, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    Ok(ENROLLMENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, enrollment)| enrollment)
+            .filter(|enrollment| enrollment.patient_id == patient.id)
+            .collect()
+    }))
+}
+
+// enrollment and arm counts for the running hospital, unblinded
+#[ic_cdk::query]
+fn get_trial_enrollments(
+    hospital_id: u64,
+    hospital_password: String,
+    trial_id: u64,
+) -> Result<Vec<Enrollment>, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    let trial = get_trial(trial_id)?;
+    if trial.hospital_id != hospital.id {
+        return Err(Error::Unauthorized {
+            msg: format!("Trial of id: {} belongs to another hospital", trial.id),
+        });
+    }
+    Ok(trial_enrollments(trial.id))
+}
+
+// the sponsor's view: every enrolled or withdrawn subject under a pseudonym, with a coded arm
+#[ic_cdk::query]
+fn export_trial_data(trial_id: u64) -> Result<Vec<BlindedTrialRecord>, Error> {
+    let trial = get_trial(trial_id)?;
+    if caller() != trial.sponsor {
+        return Err(Error::Unauthorized {
+            msg: "Only the trial's sponsor can export its data".to_string(),
+        });
+    }
+    Ok(trial_enrollments(trial.id)
+        .into_iter()
+        .filter(|enrollment| enrollment.arm.is_some())
+        .map(|enrollment| {
+            let age_years = PATIENT_STORAGE
+                .with(|s| s.borrow().get(&enrollment.patient_id))
+                .and_then(|patient| age_in_years(&patient));
+            BlindedTrialRecord {
+                subject_code: subject_code(&trial, enrollment.patient_id),
+                arm_code: format!("ARM-{}", enrollment.arm.unwrap_or(0) + 1),
+                age_years,
+                active_icd_codes: active_icd_codes(enrollment.patient_id),
+                current_medications: current_medications(enrollment.patient_id),
+                enrolled_week: enrollment.enrolled_at.map(|at| at / WEEK_NS),
+                withdrawn: matches!(enrollment.status, EnrollmentStatus::Withdrawn { .. }),
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,367 @@
+use crate::time;
+use crate::{
+    actor_of, allergy_patient, audit, authenticate_patient, authorize_auditor,
+    authorize_controller, authorize_doctor, authorize_hospital, authorize_nurse, check_not_sealed,
+    get_record, impl_storable, insert_record, is_record_signed, next_id, restore_pin, save_patient,
+    set_allergy_active, AccountRole, Actor, Error, Memory, Pin, Sex, HOSPITAL_STORAGE,
+    MEMORY_MANAGER, PATIENT_STORAGE,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+const SECOND_NS: u64 = 1_000_000_000;
+// expired entries dropped each time a change is remembered
+const PURGE_BATCH: usize = 100;
+
+// What an undoable change was made to
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ChangeRef {
+    Patient(u64),
+    Hospital(u64),
+    MedicalRecord(u64),
+    Allergy(u64),
+    // (patient id, pin id)
+    Pin(u64, u64),
+}
+
+// The part of the entity the change overwrote or deleted
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub enum PreviousValue {
+    PatientDetails {
+        name: String,
+        date_of_birth: Option<u64>,
+        sex: Option<Sex>,
+    },
+    HospitalName(String),
+    RecordContent {
+        title: String,
+        body: String,
+    },
+    AllergyActive(bool),
+    Pin(Pin),
+}
+
+// One entry of the undo buffer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct UndoEntry {
+    pub id: u64,
+    pub target: ChangeRef,
+    pub action: String,
+    pub previous: PreviousValue,
+    pub actor: Actor,
+    // hospitals whose admin may undo the change besides its actor
+    pub hospital_ids: Vec<u64>,
+    pub changed_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct UndoSettings {
+    pub window_seconds: u64,
+}
+
+impl Default for UndoSettings {
+    fn default() -> Self {
+        UndoSettings {
+            window_seconds: 15 * 60,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct UndoAuth {
+    pub role: AccountRole,
+    pub password: String,
+}
+
+impl_storable!(UndoEntry, 16384);
+impl_storable!(UndoSettings, 64);
+
+thread_local! {
+    static UNDO_BUFFER: RefCell<StableBTreeMap<u64, UndoEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83)))
+    ));
+
+    static UNDO_SETTINGS: RefCell<Cell<UndoSettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84))),
+            UndoSettings::default(),
+        )
+        .expect("Cannot create undo settings")
+    );
+}
+
+fn undo_settings() -> UndoSettings {
+    UNDO_SETTINGS.with(|s| s.borrow().get().clone())
+}
+
+// keep what a change is about to overwrite so it can be undone within the window
+pub(crate) fn remember_change(
+    target: ChangeRef,
+    action: &str,
+    previous: PreviousValue,
+    actor: Actor,
+    hospital_ids: Vec<u64>,
+) {
+    let now = time();
+    let entry = UndoEntry {
+        id: next_id(),
+        target,
+        action: action.to_string(),
+        previous,
+        actor,
+        hospital_ids,
+        changed_at: now,
+        expires_at: now + undo_settings().window_seconds * SECOND_NS,
+    };
+    UNDO_BUFFER.with(|s| {
+        let mut buffer = s.borrow_mut();
+        let expired: Vec<u64> = buffer
+            .iter()
+            .take(PURGE_BATCH)
+            .filter(|(_, entry)| entry.expires_at <= now)
+            .map(|(id, _)| id)
+            .collect();
+        for id in expired {
+            buffer.remove(&id);
+        }
+        buffer.insert(entry.id, entry);
+    });
+}
+
+// helper function to check the credentials and return who is undoing
+fn authorize_undo(auth: &UndoAuth) -> Result<Actor, Error> {
+    match auth.role {
+        AccountRole::Hospital(id) => authorize_hospital(id, &auth.password).map(|_| ()),
+        AccountRole::Doctor(id) => authorize_doctor(id, &auth.password).map(|_| ()),
+        AccountRole::Nurse(id) => authorize_nurse(id, &auth.password).map(|_| ()),
+        AccountRole::Patient(id) => authenticate_patient(id, &auth.password).map(|_| ()),
+        AccountRole::Auditor(id) => authorize_auditor(id, &auth.password).map(|_| ()),
+    }?;
+    Ok(actor_of(auth.role))
+}
+
+// the patient whose data the change touched, None for hospital changes
+fn target_patient(target: &ChangeRef) -> Result<Option<u64>, Error> {
+    match target {
+        ChangeRef::Patient(id) | ChangeRef::Pin(id, _) => Ok(Some(*id)),
+        ChangeRef::Hospital(_) => Ok(None),
+        ChangeRef::MedicalRecord(id) => Ok(Some(get_record(*id)?.patient_id)),
+        ChangeRef::Allergy(id) => allergy_patient(*id).map(Some).ok_or(Error::NotFound {
+            msg: format!("Allergy of id: {} not found", id),
+        }),
+    }
+}
+
+// put the previous value back
+fn restore(entry: &UndoEntry) -> Result<(), Error> {
+    match (&entry.target, &entry.previous) {
+        (
+            ChangeRef::Patient(id),
+            PreviousValue::PatientDetails {
+                name,
+                date_of_birth,
+                sex,
+            },
+        ) => {
+            let mut patient =
+                PATIENT_STORAGE
+                    .with(|s| s.borrow().get(id))
+                    .ok_or(Error::NotFound {
+                        msg: format!("Patient of id: {} not found", id),
+                    })?;
+            patient.name = name.clone();
+            patient.date_of_birth = *date_of_birth;
+            patient.sex = *sex;
+            save_patient(&patient);
+            Ok(())
+        }
+        (ChangeRef::Hospital(id), PreviousValue::HospitalName(name)) => {
+            HOSPITAL_STORAGE.with(|s| {
+                let mut hospitals = s.borrow_mut();
+                let mut hospital = hospitals.get(id).ok_or(Error::NotFound {
+                    msg: format!("Hospital of id: {} not found", id),
+                })?;
+                hospital.name = name.clone();
+                hospitals.insert(*id, hospital);
+                Ok(())
+            })
+        }
+        (ChangeRef::MedicalRecord(id), PreviousValue::RecordContent { title, body }) => {
+            let record = get_record(*id)?;
+            if is_record_signed(record.id) {
+                return Err(Error::InvalidPayload {
+                    msg: format!(
+                        "Medical record of id: {} was signed since, add an addendum instead",
+                        record.id
+                    ),
+                });
+            }
+            insert_record(&crate::MedicalRecord {
+                title: title.clone(),
+                body: body.clone(),
+                ..record
+            });
+            Ok(())
+        }
+        (ChangeRef::Allergy(id), PreviousValue::AllergyActive(active)) => {
+            set_allergy_active(*id, *active).map(|_| ())
+        }
+        (ChangeRef::Pin(_, _), PreviousValue::Pin(pin)) => restore_pin(pin),
+        _ => Err(Error::InvalidPayload {
+            msg: "Undo entry does not match its target".to_string(),
+        }),
+    }
+}
+
+// revert the newest change to the target that is still within the undo window. only the
+// actor who made it or an admin of a hospital it concerns may undo it; undoing again goes
+// one change further back
+#[ic_cdk::update]
+fn undo_last_change(target: ChangeRef, auth: UndoAuth) -> Result<UndoEntry, Error> {
+    let actor = authorize_undo(&auth)?;
+    let now = time();
+    let entry = UNDO_BUFFER
+        .with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, entry)| entry)
+                .filter(|entry| entry.target == target && entry.expires_at > now)
+                .last()
+        })
+        .ok_or(Error::NotFound {
+            msg: "Nothing to undo, the change is older than the undo window".to_string(),
+        })?;
+    let admin = matches!(actor, Actor::Hospital(id) if entry.hospital_ids.contains(&id));
+    if entry.actor != actor && !admin {
+        return Err(Error::Unauthorized {
+            msg: "Only the author of the change or a hospital admin can undo it".to_string(),
+        });
+    }
+
+    // a death registered since the change seals the patient, their data is no longer edited
+    if let Some(patient_id) = target_patient(&entry.target)? {
+        check_not_sealed(patient_id)?;
+    }
+    restore(&entry)?;
+    UNDO_BUFFER.with(|s| s.borrow_mut().remove(&entry.id));
+    audit(
+        actor,
+        entry.hospital_ids.first().copied(),
+        None,
+        "change_undone",
+        format!("{} made at {}", entry.action, entry.changed_at),
+    );
+    Ok(entry)
+}
+
+#[ic_cdk::query]
+fn get_undo_settings() -> UndoSettings {
+    undo_settings()
+}
+
+#[ic_cdk::update]
+fn set_undo_window(window_seconds: u64) -> Result<UndoSettings, Error> {
+    authorize_controller()?;
+    if window_seconds == 0 || window_seconds > 24 * 60 * 60 {
+        return Err(Error::InvalidPayload {
+            msg: "Undo window must be between one second and one day".to_string(),
+        });
+    }
+    let settings = UndoSettings { window_seconds };
+    UNDO_SETTINGS
+        .with(|s| s.borrow_mut().set(settings.clone()))
+        .expect("Cannot update undo settings");
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{advance_clock, clinic, must, refused, Clinic, PASSWORD};
+    use crate::{
+        edit_patient, patient_header, register_death, DeathDetails, EditPatientPayload,
+        HospitalAccessPayload,
+    };
+
+    fn rename(clinic: &Clinic) {
+        must(edit_patient(EditPatientPayload {
+            name: "Renamed patient".to_string(),
+            password: PASSWORD.to_string(),
+            patient_id: clinic.patient_id,
+        }));
+    }
+
+    fn undo(clinic: &Clinic, role: AccountRole) -> Result<UndoEntry, Error> {
+        undo_last_change(
+            ChangeRef::Patient(clinic.patient_id),
+            UndoAuth {
+                role,
+                password: PASSWORD.to_string(),
+            },
+        )
+    }
+
+    fn name(clinic: &Clinic) -> String {
+        patient_header(clinic.patient_id).unwrap().name
+    }
+
+    #[test]
+    fn the_author_or_a_hospital_admin_undoes_a_change() {
+        let clinic = clinic();
+        rename(&clinic);
+        must(undo(&clinic, AccountRole::Patient(clinic.patient_id)));
+        assert_eq!(name(&clinic), "Patient");
+        rename(&clinic);
+        must(undo(&clinic, AccountRole::Hospital(clinic.hospital_id)));
+        assert_eq!(name(&clinic), "Patient");
+    }
+
+    #[test]
+    fn other_staff_cannot_undo_a_change() {
+        let clinic = clinic();
+        rename(&clinic);
+        assert!(refused(undo(
+            &clinic,
+            AccountRole::Doctor(clinic.doctor_id)
+        )));
+        assert_eq!(name(&clinic), "Renamed patient");
+    }
+
+    #[test]
+    fn a_change_past_the_window_stays() {
+        let clinic = clinic();
+        rename(&clinic);
+        advance_clock(undo_settings().window_seconds * SECOND_NS);
+        assert!(matches!(
+            undo(&clinic, AccountRole::Patient(clinic.patient_id)),
+            Err(Error::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn a_sealed_patient_is_not_rewritten() {
+        let clinic = clinic();
+        rename(&clinic);
+        must(register_death(
+            clinic.patient_id,
+            HospitalAccessPayload {
+                hospital_id: clinic.hospital_id,
+                hospital_password: PASSWORD.to_string(),
+            },
+            DeathDetails {
+                date_of_death: time(),
+                place_of_death: "Ward 3".to_string(),
+                cause_of_death: "Cardiac arrest".to_string(),
+                certifying_doctor_id: None,
+            },
+        ));
+        assert!(refused(undo(
+            &clinic,
+            AccountRole::Hospital(clinic.hospital_id)
+        )));
+        assert_eq!(name(&clinic), "Renamed patient");
+    }
+}
@@ -0,0 +1,324 @@
+use crate::time;
+use crate::{
+    authorize_controller, impl_storable, is_standby, store_memory_ids, store_writes, to_hex, Error,
+    RawMemory, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Memory as _};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::time::Duration;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+// memory is hashed in chunks of this size
+const DIGEST_CHUNK_BYTES: u64 = 1024 * 1024;
+// bytes hashed per call or timer tick, well inside the instruction limit
+const DIGEST_BATCH_BYTES: u64 = 64 * DIGEST_CHUNK_BYTES;
+
+// The state of one store's memory at a point in time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct StoreDigest {
+    pub memory_id: u8,
+    pub size_pages: u64,
+    pub digest: String,
+}
+
+// Digests of every store taken right before an upgrade
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct UpgradeSnapshot {
+    pub taken_at: u64,
+    pub stores: Vec<StoreDigest>,
+    // store writes between the start of the snapshot and the upgrade, None when the snapshot
+    // was not taken by the code being upgraded
+    #[serde(default)]
+    pub writes_before_upgrade: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum DigestStatus {
+    Unchanged,
+    // expected when the upgrade migrates the store, corruption otherwise
+    Changed,
+    Missing,
+    New,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct StoreComparison {
+    pub memory_id: u8,
+    pub status: DigestStatus,
+    pub before: Option<StoreDigest>,
+    pub after: Option<StoreDigest>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct UpgradeReport {
+    pub snapshot_taken_at: u64,
+    pub verified_at: u64,
+    pub stores: Vec<StoreComparison>,
+    pub changed: u32,
+    pub missing: u32,
+    // true when every store came through the upgrade byte for byte
+    pub intact: bool,
+    // writes that landed while the digests were taken, on either side of the upgrade. changed
+    // stores may come from that traffic rather than the upgrade when this is not zero; None
+    // when the snapshot was not taken by the upgraded code
+    #[serde(default)]
+    pub concurrent_writes: Option<u64>,
+}
+
+// Digests every store a batch at a time, across calls and timer ticks
+#[derive(Clone, Default)]
+struct DigestRun {
+    memory_ids: Vec<u8>,
+    digests: Vec<StoreDigest>,
+    // position in the store being hashed and its size when its hashing started
+    offset: u64,
+    size_pages: Option<u64>,
+    hasher: Sha256,
+    started_at: u64,
+    writes_at_start: u64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RunKind {
+    // before the upgrade, ends in a new snapshot
+    Snapshot,
+    // after the upgrade, ends in a report against the snapshot
+    Verification,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct DigestProgress {
+    pub stores_done: u32,
+    pub stores_total: u32,
+    pub started_at: u64,
+}
+
+impl_storable!(UpgradeSnapshot, 32768);
+impl_storable!(UpgradeReport, 65536);
+
+thread_local! {
+    static UPGRADE_SNAPSHOT: RefCell<Cell<UpgradeSnapshot, RawMemory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().raw(MemoryId::new(87))),
+            UpgradeSnapshot::default(),
+        )
+        .expect("Cannot create upgrade snapshot")
+    );
+
+    // verified_at stays zero until the first comparison
+    static UPGRADE_REPORT: RefCell<Cell<UpgradeReport, RawMemory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().raw(MemoryId::new(88))),
+            UpgradeReport::default(),
+        )
+        .expect("Cannot create upgrade report")
+    );
+}
+
+thread_local! {
+    // the run in progress, on the heap: an upgrade abandons it
+    static DIGEST_RUN: RefCell<Option<(RunKind, DigestRun)>> = const { RefCell::new(None) };
+
+    // store writes counted when the current snapshot started, unknown after an upgrade
+    static SNAPSHOT_WRITES_AT_START: RefCell<Option<u64>> = const { RefCell::new(None) };
+}
+
+fn start_run(kind: RunKind) -> DigestProgress {
+    let run = DigestRun {
+        memory_ids: store_memory_ids(),
+        started_at: time(),
+        writes_at_start: store_writes(),
+        ..Default::default()
+    };
+    let progress = run_progress(&run);
+    DIGEST_RUN.with(|r| *r.borrow_mut() = Some((kind, run)));
+    ic_cdk_timers::set_timer(Duration::ZERO, continue_run);
+    progress
+}
+
+fn run_progress(run: &DigestRun) -> DigestProgress {
+    DigestProgress {
+        stores_done: run.digests.len() as u32,
+        stores_total: run.memory_ids.len() as u32,
+        started_at: run.started_at,
+    }
+}
+
+// hash up to a batch worth of memory, returns true once every store has its digest
+fn digest_batch(run: &mut DigestRun) -> bool {
+    let mut budget = DIGEST_BATCH_BYTES;
+    let mut buffer = vec![0; DIGEST_CHUNK_BYTES as usize];
+    while let Some(&memory_id) = run.memory_ids.get(run.digests.len()) {
+        let memory = MEMORY_MANAGER.with(|m| m.borrow().raw(MemoryId::new(memory_id)));
+        let size_pages = *run.size_pages.get_or_insert(memory.size());
+        let size = size_pages * WASM_PAGE_SIZE;
+        while run.offset < size {
+            if budget == 0 {
+                return false;
+            }
+            let len = DIGEST_CHUNK_BYTES.min(size - run.offset).min(budget) as usize;
+            memory.read(run.offset, &mut buffer[..len]);
+            run.hasher.update(&buffer[..len]);
+            run.offset += len as u64;
+            budget -= len as u64;
+        }
+        run.digests.push(StoreDigest {
+            memory_id,
+            size_pages,
+            digest: to_hex(&std::mem::take(&mut run.hasher).finalize()),
+        });
+        run.offset = 0;
+        run.size_pages = None;
+    }
+    true
+}
+
+// timer step: hash the next batch and finish the run once every store is done
+fn continue_run() {
+    let Some((kind, mut run)) = DIGEST_RUN.with(|r| r.borrow_mut().take()) else {
+        return;
+    };
+    if !digest_batch(&mut run) {
+        DIGEST_RUN.with(|r| *r.borrow_mut() = Some((kind, run)));
+        ic_cdk_timers::set_timer(Duration::ZERO, continue_run);
+        return;
+    }
+    let writes = store_writes().saturating_sub(run.writes_at_start);
+    match kind {
+        RunKind::Snapshot => {
+            let snapshot = UpgradeSnapshot {
+                taken_at: run.started_at,
+                stores: run.digests,
+                writes_before_upgrade: None,
+            };
+            UPGRADE_SNAPSHOT
+                .with(|s| s.borrow_mut().set(snapshot))
+                .expect("Cannot store upgrade snapshot");
+            SNAPSHOT_WRITES_AT_START.with(|w| *w.borrow_mut() = Some(run.writes_at_start));
+        }
+        RunKind::Verification => {
+            compare_with_snapshot(run.digests, writes);
+        }
+    }
+}
+
+// run from pre_upgrade: only stored values are touched, noting whether traffic hit the stores
+// after the snapshot started
+fn seal_upgrade_snapshot() {
+    let mut snapshot = UPGRADE_SNAPSHOT.with(|s| s.borrow().get().clone());
+    snapshot.writes_before_upgrade = SNAPSHOT_WRITES_AT_START
+        .with(|w| *w.borrow())
+        .map(|at_start| store_writes().saturating_sub(at_start));
+    UPGRADE_SNAPSHOT
+        .with(|s| s.borrow_mut().set(snapshot))
+        .expect("Cannot store upgrade snapshot");
+}
+
+// run from post_upgrade: the stores are hashed again by timer, outside the hook
+pub(crate) fn start_upgrade_verification() {
+    start_run(RunKind::Verification);
+}
+
+// compare the stores against the snapshot taken before the upgrade
+fn compare_with_snapshot(after: Vec<StoreDigest>, writes_during_check: u64) {
+    let snapshot = UPGRADE_SNAPSHOT.with(|s| s.borrow().get().clone());
+    let mut stores: Vec<StoreComparison> = snapshot
+        .stores
+        .iter()
+        .map(|before| {
+            let current = after.iter().find(|d| d.memory_id == before.memory_id);
+            let status = match current {
+                None => DigestStatus::Missing,
+                Some(current) if current.digest == before.digest => DigestStatus::Unchanged,
+                Some(_) => DigestStatus::Changed,
+            };
+            StoreComparison {
+                memory_id: before.memory_id,
+                status,
+                before: Some(before.clone()),
+                after: current.cloned(),
+            }
+        })
+        .collect();
+    stores.extend(
+        after
+            .iter()
+            .filter(|d| !snapshot.stores.iter().any(|b| b.memory_id == d.memory_id))
+            .map(|d| StoreComparison {
+                memory_id: d.memory_id,
+                status: DigestStatus::New,
+                before: None,
+                after: Some(d.clone()),
+            }),
+    );
+    stores.sort_by_key(|store| store.memory_id);
+    let count = |status: DigestStatus| stores.iter().filter(|s| s.status == status).count() as u32;
+    let report = UpgradeReport {
+        snapshot_taken_at: snapshot.taken_at,
+        verified_at: time(),
+        changed: count(DigestStatus::Changed),
+        missing: count(DigestStatus::Missing),
+        intact: count(DigestStatus::Changed) == 0 && count(DigestStatus::Missing) == 0,
+        concurrent_writes: snapshot
+            .writes_before_upgrade
+            .map(|writes| writes.saturating_add(writes_during_check)),
+        stores,
+    };
+    UPGRADE_REPORT
+        .with(|s| s.borrow_mut().set(report))
+        .expect("Cannot store upgrade report");
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    // a standby refuses writes, its stores are checked on the primary
+    if !is_standby() {
+        seal_upgrade_snapshot();
+    }
+}
+
+// digest every store ahead of an upgrade, in batches by timer. Run it right before upgrading
+// and wait for get_upgrade_snapshot to show every store done
+#[ic_cdk::update]
+fn prepare_upgrade_snapshot() -> Result<DigestProgress, Error> {
+    authorize_controller()?;
+    Ok(start_run(RunKind::Snapshot))
+}
+
+// progress of the digests being taken, or of the last completed snapshot
+#[ic_cdk::query]
+fn get_upgrade_snapshot() -> Result<DigestProgress, Error> {
+    authorize_controller()?;
+    if let Some(progress) = DIGEST_RUN.with(|r| {
+        r.borrow()
+            .as_ref()
+            .filter(|(kind, _)| *kind == RunKind::Snapshot)
+            .map(|(_, run)| run_progress(run))
+    }) {
+        return Ok(progress);
+    }
+    let snapshot = UPGRADE_SNAPSHOT.with(|s| s.borrow().get().clone());
+    Ok(DigestProgress {
+        stores_done: snapshot.stores.len() as u32,
+        stores_total: snapshot.stores.len() as u32,
+        started_at: snapshot.taken_at,
+    })
+}
+
+// rehash the stores and compare them with the pre-upgrade snapshot again. post_upgrade already
+// starts this; later runs also show changes made since the upgrade
+#[ic_cdk::update]
+fn verify_post_upgrade() -> Result<DigestProgress, Error> {
+    authorize_controller()?;
+    Ok(start_run(RunKind::Verification))
+}
+
+// the last comparison, for operators checking how an upgrade went
+#[ic_cdk::query]
+fn get_upgrade_report() -> Result<Option<UpgradeReport>, Error> {
+    authorize_controller()?;
+    let report = UPGRADE_REPORT.with(|s| s.borrow().get().clone());
+    Ok(Some(report).filter(|report| report.verified_at > 0))
+}
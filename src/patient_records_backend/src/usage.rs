@@ -0,0 +1,182 @@
+use crate::time;
+use crate::{
+    authorize_controller, authorize_hospital, hospital_nurse_count, impl_storable,
+    instruction_counter, record_bytes, Error, Memory, HOSPITAL_STORAGE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::{Cell, RefCell};
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const GIB: u64 = 1024 * 1024 * 1024;
+// how often storage is sampled, each sample counts for the whole interval
+pub(crate) const STORAGE_SAMPLE_SECS: u64 = 60 * 60;
+// slots reserved per entity in the stores, their encoded size bounds
+const PATIENT_BYTES: u64 = 1024;
+const DOCTOR_BYTES: u64 = 1024;
+const NURSE_BYTES: u64 = 512;
+// list prices of a 13 node subnet, cycles per 10 instructions and per GiB per second
+const CYCLES_PER_10_INSTRUCTIONS: u64 = 4;
+const CYCLES_PER_GIB_SECOND: u64 = 127_000;
+const MAX_REPORT_DAYS: u64 = 366;
+
+// What one hospital consumed on one day (UTC)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct UsageDay {
+    // instructions of update calls made by or for the hospital
+    pub instructions: u64,
+    pub update_calls: u64,
+    // sum of storage bytes times seconds held, sampled hourly
+    pub storage_byte_seconds: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct HospitalUsageReport {
+    pub hospital_id: u64,
+    pub from_day: u64,
+    pub to_day: u64,
+    pub instructions: u64,
+    pub update_calls: u64,
+    pub storage_byte_seconds: u64,
+    // storage held right now
+    pub storage_bytes: u64,
+    // what the usage would cost at list prices, a basis for billing
+    pub compute_cycles: u64,
+    pub storage_cycles: u64,
+    pub total_cycles: u64,
+}
+
+impl_storable!(UsageDay, 64);
+
+thread_local! {
+    // (hospital, day since epoch) -> usage
+    static USAGE_STORAGE: RefCell<StableBTreeMap<(u64, u64), UsageDay, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(127)))
+    ));
+
+    // (time, instructions) of the last charge, so a call that charges twice is billed once
+    static LAST_CHARGE: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+}
+
+fn add_usage(hospital_id: u64, day: u64, change: impl FnOnce(&mut UsageDay)) {
+    USAGE_STORAGE.with(|s| {
+        let mut usage = s.borrow_mut();
+        let mut entry = usage.get(&(hospital_id, day)).unwrap_or_default();
+        change(&mut entry);
+        usage.insert((hospital_id, day), entry);
+    });
+}
+
+// charge the instructions the current call has used so far to the hospital. Called with every
+// audit entry; a later charge in the same call only adds what was used since the earlier one.
+// Calls share a timestamp within a round, so this is an approximation
+pub(crate) fn charge_call(hospital_id: u64) {
+    let now = time();
+    let used = instruction_counter();
+    let (last_time, last_used) = LAST_CHARGE.with(|last| last.replace((now, used)));
+    let same_call = last_time == now && used >= last_used;
+    add_usage(hospital_id, now / DAY_NS, |day| {
+        if same_call {
+            day.instructions += used - last_used;
+        } else {
+            day.instructions += used;
+            day.update_calls += 1;
+        }
+    });
+}
+
+// approximate bytes the hospital occupies: its staff and patients plus its record bodies
+fn storage_bytes(hospital_id: u64) -> u64 {
+    match HOSPITAL_STORAGE.with(|s| s.borrow().get(&hospital_id)) {
+        Some(hospital) => {
+            hospital.patients_ids.len() as u64 * PATIENT_BYTES
+                + hospital.doctors_ids.len() as u64 * DOCTOR_BYTES
+                + hospital_nurse_count(hospital_id) * NURSE_BYTES
+                + record_bytes(hospital_id)
+        }
+        None => 0,
+    }
+}
+
+// timer job: add an interval of storage held to every hospital's usage of the day
+pub(crate) fn sample_storage_usage() {
+    let now = time();
+    let hospital_ids: Vec<u64> =
+        HOSPITAL_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect());
+    for hospital_id in hospital_ids {
+        let bytes = storage_bytes(hospital_id);
+        add_usage(hospital_id, now / DAY_NS, |day| {
+            day.storage_byte_seconds += bytes * STORAGE_SAMPLE_SECS
+        });
+    }
+}
+
+fn usage_report(hospital_id: u64, from_day: u64, to_day: u64) -> HospitalUsageReport {
+    let mut report = HospitalUsageReport {
+        hospital_id,
+        from_day,
+        to_day,
+        instructions: 0,
+        update_calls: 0,
+        storage_byte_seconds: 0,
+        storage_bytes: storage_bytes(hospital_id),
+        compute_cycles: 0,
+        storage_cycles: 0,
+        total_cycles: 0,
+    };
+    USAGE_STORAGE.with(|s| {
+        for (_, day) in s
+            .borrow()
+            .range((hospital_id, from_day)..=(hospital_id, to_day))
+        {
+            report.instructions += day.instructions;
+            report.update_calls += day.update_calls;
+            report.storage_byte_seconds += day.storage_byte_seconds;
+        }
+    });
+    report.compute_cycles = report.instructions / 10 * CYCLES_PER_10_INSTRUCTIONS;
+    report.storage_cycles =
+        (report.storage_byte_seconds as u128 * CYCLES_PER_GIB_SECOND as u128 / GIB as u128) as u64;
+    report.total_cycles = report.compute_cycles + report.storage_cycles;
+    report
+}
+
+// days are counted since the unix epoch, to_day included
+fn check_days(from_day: u64, to_day: u64) -> Result<(), Error> {
+    if to_day < from_day || to_day - from_day >= MAX_REPORT_DAYS {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "A usage report covers 1 to {} days, from_day first",
+                MAX_REPORT_DAYS
+            ),
+        });
+    }
+    Ok(())
+}
+
+// what the hospital consumed, for its admins to follow their bill
+#[ic_cdk::query]
+fn get_hospital_usage(
+    hospital_id: u64,
+    hospital_password: String,
+    from_day: u64,
+    to_day: u64,
+) -> Result<HospitalUsageReport, Error> {
+    let hospital = authorize_hospital(hospital_id, &hospital_password)?;
+    check_days(from_day, to_day)?;
+    Ok(usage_report(hospital.id, from_day, to_day))
+}
+
+// every hospital's share of the canister's consumption, for the platform admin to bill
+#[ic_cdk::query]
+fn get_usage_reports(from_day: u64, to_day: u64) -> Result<Vec<HospitalUsageReport>, Error> {
+    authorize_controller()?;
+    check_days(from_day, to_day)?;
+    let hospital_ids: Vec<u64> =
+        HOSPITAL_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect());
+    Ok(hospital_ids
+        .into_iter()
+        .map(|hospital_id| usage_report(hospital_id, from_day, to_day))
+        .collect())
+}
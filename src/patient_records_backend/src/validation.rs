@@ -0,0 +1,146 @@
+use crate::time;
+use crate::{prescription_warnings, EncounterEntryKind, Patient, Prescription, Vitals};
+
+const YEAR_NS: u64 = 365 * 24 * 60 * 60 * 1_000_000_000;
+
+// medications to avoid below an age in years
+const MINIMUM_AGES: &[(&str, u64, &str)] = &[
+    ("aspirin", 16, "risk of Reye's syndrome in children"),
+    ("codeine", 12, "risk of respiratory depression in children"),
+    ("tramadol", 12, "risk of respiratory depression in children"),
+    ("doxycycline", 8, "tooth discolouration in young children"),
+    ("tetracycline", 8, "tooth discolouration in young children"),
+    ("ciprofloxacin", 18, "fluoroquinolone in a child"),
+];
+
+// medications that are potentially inappropriate for older adults
+const OLDER_ADULT_CAUTIONS: &[(&str, &str)] = &[
+    ("diazepam", "long-acting benzodiazepine, risk of falls"),
+    (
+        "amitriptyline",
+        "anticholinergic, risk of confusion and falls",
+    ),
+    ("diphenhydramine", "anticholinergic, risk of confusion"),
+    ("glibenclamide", "risk of prolonged hypoglycaemia"),
+];
+const OLDER_ADULT_AGE: u64 = 65;
+
+// Guidance about a value that is accepted but unusual enough to double-check
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ValidationWarning {
+    // stable identifier for clients, e.g. "unusual_dosage"
+    pub code: String,
+    pub message: String,
+}
+
+// A successful result together with any warnings raised while validating it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ResultWithWarnings<T> {
+    pub value: T,
+    pub warnings: Vec<ValidationWarning>,
+}
+
+fn warning(code: &str, message: String) -> ValidationWarning {
+    ValidationWarning {
+        code: code.to_string(),
+        message,
+    }
+}
+
+pub(crate) fn age_in_years(patient: &Patient) -> Option<u64> {
+    patient
+        .date_of_birth
+        .map(|date_of_birth| time().saturating_sub(date_of_birth) / YEAR_NS)
+}
+
+fn prescription_soft_warnings(
+    patient: &Patient,
+    prescription: &Prescription,
+) -> Vec<ValidationWarning> {
+    let medication = prescription.medication.to_lowercase();
+    let mut warnings = vec![];
+    if prescription.doses_per_day > 6 {
+        warnings.push(warning(
+            "unusual_dosage",
+            format!(
+                "{} doses a day is unusually frequent",
+                prescription.doses_per_day
+            ),
+        ));
+    }
+    if prescription.duration_days > 90 {
+        warnings.push(warning(
+            "long_course",
+            format!(
+                "a {} day course is longer than usual, consider a review date",
+                prescription.duration_days
+            ),
+        ));
+    }
+    if prescription.refills > 5 {
+        warnings.push(warning(
+            "many_refills",
+            format!("{} refills is more than usual", prescription.refills),
+        ));
+    }
+    if let Some(age) = age_in_years(patient) {
+        for (drug, minimum_age, reason) in MINIMUM_AGES {
+            if medication.contains(drug) && age < *minimum_age {
+                warnings.push(warning(
+                    "age_contraindication",
+                    format!("patient is {}: {}", age, reason),
+                ));
+            }
+        }
+        for (drug, reason) in OLDER_ADULT_CAUTIONS {
+            if medication.contains(drug) && age >= OLDER_ADULT_AGE {
+                warnings.push(warning(
+                    "age_caution",
+                    format!("patient is {}: {}", age, reason),
+                ));
+            }
+        }
+    }
+    for interaction in prescription_warnings(patient.id, &prescription.medication) {
+        warnings.push(warning("interaction", interaction.reason));
+    }
+    warnings
+}
+
+fn vitals_soft_warnings(vitals: &Vitals) -> Vec<ValidationWarning> {
+    // (value, low, high, what) of readings that are possible but rare
+    let readings = [
+        (vitals.heart_rate, 30.0, 200.0, "heart rate"),
+        (vitals.systolic_bp, 70.0, 220.0, "systolic blood pressure"),
+        (vitals.diastolic_bp, 40.0, 130.0, "diastolic blood pressure"),
+        (vitals.respiratory_rate, 6.0, 40.0, "respiratory rate"),
+        (vitals.temperature, 33.0, 42.0, "temperature"),
+        (vitals.oxygen_saturation, 70.0, 100.0, "oxygen saturation"),
+    ];
+    readings
+        .into_iter()
+        .filter_map(|(value, low, high, what)| {
+            let value = value?;
+            (value < low || value > high).then(|| {
+                warning(
+                    "unusual_reading",
+                    format!("{} of {} is outside {} to {}", what, value, low, high),
+                )
+            })
+        })
+        .collect()
+}
+
+// non-blocking checks on an encounter entry, the entry is recorded whatever they find
+pub(crate) fn entry_warnings(
+    patient: &Patient,
+    kind: &EncounterEntryKind,
+) -> Vec<ValidationWarning> {
+    match kind {
+        EncounterEntryKind::Prescription(prescription) => {
+            prescription_soft_warnings(patient, prescription)
+        }
+        EncounterEntryKind::Vitals(vitals) => vitals_soft_warnings(vitals),
+        _ => vec![],
+    }
+}
@@ -0,0 +1,194 @@
+use crate::time;
+use crate::{
+    audit, authenticate_patient, authorize_doctor, authorize_nurse, authorize_patient_access,
+    check_not_sealed, impl_storable, patient_header, AccountRole, Actor, BatchAuth, Error, Memory,
+    PatientAccess, Vitals, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_VITALS_BATCH: usize = 500;
+const MAX_VITALS_PAGE: usize = 1000;
+// device clocks run a little ahead, readings further in the future are refused
+const CLOCK_SKEW_NS: u64 = 5 * 60 * 1_000_000_000;
+
+// One reading as a monitor sends it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct VitalsReading {
+    pub taken_at: u64,
+    pub vitals: Vitals,
+}
+
+// A stored reading of a patient's vitals series
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct VitalsPoint {
+    pub patient_id: u64,
+    pub taken_at: u64,
+    pub vitals: Vitals,
+    pub recorded_by: Actor,
+}
+
+impl_storable!(VitalsPoint, 256);
+
+thread_local! {
+    // (patient, taken at) -> reading, so a patient's series reads in time order
+    static VITALS_SERIES: RefCell<StableBTreeMap<(u64, u64), VitalsPoint, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(141)))
+    ));
+}
+
+fn check_reading(reading: &VitalsReading, now: u64) -> Result<(), Error> {
+    let vitals = &reading.vitals;
+    let values = [
+        vitals.systolic_bp,
+        vitals.diastolic_bp,
+        vitals.heart_rate,
+        vitals.respiratory_rate,
+        vitals.temperature,
+        vitals.oxygen_saturation,
+        vitals.height_cm,
+        vitals.weight_kg,
+    ];
+    if values.iter().all(Option::is_none) {
+        return Err(Error::InvalidPayload {
+            msg: "A vitals reading needs at least one value".to_string(),
+        });
+    }
+    if values.iter().flatten().any(|v| !v.is_finite() || *v < 0.0) {
+        return Err(Error::InvalidPayload {
+            msg: "Vitals values must be non-negative numbers".to_string(),
+        });
+    }
+    if reading.taken_at == 0 || reading.taken_at > now + CLOCK_SKEW_NS {
+        return Err(Error::InvalidPayload {
+            msg: format!("Reading time {} is not valid", reading.taken_at),
+        });
+    }
+    Ok(())
+}
+
+// append readings to a patient's series after checking all of them, returns the stored points.
+// Two readings of the same nanosecond are kept one nanosecond apart
+pub(crate) fn store_vitals(
+    patient_id: u64,
+    readings: Vec<VitalsReading>,
+    recorded_by: &Actor,
+) -> Result<Vec<VitalsPoint>, Error> {
+    if readings.is_empty() || readings.len() > MAX_VITALS_BATCH {
+        return Err(Error::InvalidPayload {
+            msg: format!("A batch holds 1 to {} readings", MAX_VITALS_BATCH),
+        });
+    }
+    let now = time();
+    for reading in &readings {
+        check_reading(reading, now)?;
+    }
+    let points: Vec<VitalsPoint> = VITALS_SERIES.with(|s| {
+        let mut series = s.borrow_mut();
+        readings
+            .into_iter()
+            .map(|reading| {
+                let mut taken_at = reading.taken_at;
+                while series.contains_key(&(patient_id, taken_at)) {
+                    taken_at += 1;
+                }
+                let point = VitalsPoint {
+                    patient_id,
+                    taken_at,
+                    vitals: reading.vitals,
+                    recorded_by: recorded_by.clone(),
+                };
+                series.insert((patient_id, taken_at), point.clone());
+                point
+            })
+            .collect()
+    });
+    Ok(points)
+}
+
+// the readings of a patient taken between from and to, both included, oldest first
+pub(crate) fn vitals_between(
+    patient_id: u64,
+    from: u64,
+    to: u64,
+    limit: usize,
+) -> Vec<VitalsPoint> {
+    VITALS_SERIES.with(|s| {
+        s.borrow()
+            .range((patient_id, from)..=(patient_id, to))
+            .take(limit)
+            .map(|(_, point)| point)
+            .collect()
+    })
+}
+
+// who may write a patient's vitals: the patient, an assigned doctor or a nurse of one of the
+// patient's hospitals
+pub(crate) fn authorize_vitals_writer(
+    patient_id: u64,
+    auth: &BatchAuth,
+) -> Result<(Actor, Option<u64>), Error> {
+    let password = &auth.password;
+    let refused = Error::Unauthorized {
+        msg: format!("Not allowed to record vitals of patient {}", patient_id),
+    };
+    match auth.role {
+        AccountRole::Patient(id) if id == patient_id => {
+            authenticate_patient(id, password)?;
+            Ok((Actor::Patient(id), None))
+        }
+        AccountRole::Doctor(id) => {
+            let doctor = authorize_doctor(id, password)?;
+            check_not_sealed(patient_id)?;
+            match patient_header(patient_id) {
+                Some(patient) if patient.doctors_ids.contains(&doctor.id) => {
+                    Ok((Actor::Doctor(doctor.id), Some(doctor.hospital_id)))
+                }
+                _ => Err(refused),
+            }
+        }
+        AccountRole::Nurse(id) => {
+            let nurse = authorize_nurse(id, password)?;
+            check_not_sealed(patient_id)?;
+            match patient_header(patient_id) {
+                Some(patient) if patient.hospitals_ids.contains(&nurse.hospital_id) => {
+                    Ok((Actor::Nurse(nurse.id), Some(nurse.hospital_id)))
+                }
+                _ => Err(refused),
+            }
+        }
+        _ => Err(refused),
+    }
+}
+
+// bedside monitors push many readings in one message, checked once and inserted together
+#[ic_cdk::update]
+fn record_vitals_batch(
+    patient_id: u64,
+    readings: Vec<VitalsReading>,
+    auth: BatchAuth,
+) -> Result<u64, Error> {
+    let (actor, hospital_id) = authorize_vitals_writer(patient_id, &auth)?;
+    let points = store_vitals(patient_id, readings, &actor)?;
+    audit(
+        actor,
+        hospital_id,
+        Some(patient_id),
+        "vitals_batch_recorded",
+        format!("{} readings", points.len()),
+    );
+    Ok(points.len() as u64)
+}
+
+#[ic_cdk::query]
+fn get_vitals_series(
+    patient_id: u64,
+    access: PatientAccess,
+    from: u64,
+    to: u64,
+) -> Result<Vec<VitalsPoint>, Error> {
+    let (patient, _) = authorize_patient_access(patient_id, &access)?;
+    Ok(vitals_between(patient.id, from, to, MAX_VITALS_PAGE))
+}
@@ -0,0 +1,364 @@
+use crate::time;
+use crate::{
+    audit, authorize_doctor, impl_storable, next_id, on_call_doctor, on_call_doctors, page,
+    patient_header, text, vitals_breaches, Actor, Error, Memory, Recipient, Text, VitalsPoint,
+    MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// how long the paged doctor has to acknowledge, and the gap between later escalations
+const ACKNOWLEDGE_WITHIN_NS: u64 = 10 * 60 * 1_000_000_000;
+// an alert stays open after the last escalation, the hospital has been told often enough
+const MAX_ESCALATIONS: u32 = 6;
+const MAX_BREACH_BYTES: usize = 300;
+// index key of an open alert with no escalation left
+const NEVER: u64 = u64::MAX;
+
+// Device readings of a patient that breached the alert rules of one of the patient's hospitals.
+// Further breaches while the alert is open are counted on it instead of paging again
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct VitalsAlert {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub patient_id: u64,
+    pub device_id: u64,
+    // the rules breached by the first reading
+    pub breach: String,
+    pub breaching_readings: u64,
+    pub first_taken_at: u64,
+    pub last_taken_at: u64,
+    // on-call doctor paged first, None when nobody was on call
+    pub paged_doctor_id: Option<u64>,
+    pub raised_at: u64,
+    pub acknowledged_at: Option<u64>,
+    pub acknowledged_by: Option<u64>,
+    pub escalations: u32,
+    pub next_escalation_at: u64,
+}
+
+impl_storable!(VitalsAlert, 1024);
+
+thread_local! {
+    static VITALS_ALERT_STORAGE: RefCell<StableBTreeMap<u64, VitalsAlert, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(144)))
+    ));
+
+    // (next escalation time, alert id) of every unacknowledged alert
+    static OPEN_VITALS_ALERTS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(150)))
+    ));
+}
+
+fn escalation_key(alert: &VitalsAlert) -> (u64, u64) {
+    if alert.escalations >= MAX_ESCALATIONS {
+        (NEVER, alert.id)
+    } else {
+        (alert.next_escalation_at, alert.id)
+    }
+}
+
+// store the alert and keep the open index in step with it
+fn save_vitals_alert(alert: &VitalsAlert) {
+    let previous = VITALS_ALERT_STORAGE.with(|s| s.borrow_mut().insert(alert.id, alert.clone()));
+    OPEN_VITALS_ALERTS.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = previous.filter(|previous| previous.acknowledged_at.is_none()) {
+            index.remove(&escalation_key(&previous));
+        }
+        if alert.acknowledged_at.is_none() {
+            index.insert(escalation_key(alert), ());
+        }
+    });
+}
+
+// unacknowledged alerts in escalation order, acknowledged ones are never read back
+fn open_vitals_alerts(filter: impl Fn(&VitalsAlert) -> bool) -> Vec<VitalsAlert> {
+    let ids: Vec<u64> =
+        OPEN_VITALS_ALERTS.with(|index| index.borrow().iter().map(|((_, id), _)| id).collect());
+    VITALS_ALERT_STORAGE.with(|s| {
+        let storage = s.borrow();
+        ids.into_iter()
+            .filter_map(|id| storage.get(&id))
+            .filter(|alert| filter(alert))
+            .collect()
+    })
+}
+
+// index the open alerts stored before the index existed
+pub(crate) fn backfill_open_vitals_alerts() {
+    if !OPEN_VITALS_ALERTS.with(|index| index.borrow().is_empty()) {
+        return;
+    }
+    let open: Vec<VitalsAlert> = VITALS_ALERT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, alert)| alert)
+            .filter(|alert| alert.acknowledged_at.is_none())
+            .collect()
+    });
+    OPEN_VITALS_ALERTS.with(|index| {
+        let mut index = index.borrow_mut();
+        for alert in &open {
+            index.insert(escalation_key(alert), ());
+        }
+    });
+}
+
+// the breaches cut to MAX_BREACH_BYTES on a character boundary
+fn clip_breach(mut breach: String) -> String {
+    if breach.len() > MAX_BREACH_BYTES {
+        let end = (0..=MAX_BREACH_BYTES)
+            .rev()
+            .find(|i| breach.is_char_boundary(*i))
+            .unwrap_or(0);
+        breach.truncate(end);
+    }
+    breach
+}
+
+fn alert_text(alert: &VitalsAlert) -> Text {
+    text(
+        "vitals_alert.raised",
+        "Device vitals alert for patient {patient}: {breach}",
+        vec![
+            ("patient", alert.patient_id.to_string()),
+            ("breach", alert.breach.clone()),
+        ],
+    )
+}
+
+// check readings a device stored against the rules of each of the patient's hospitals and page
+// the hospital's on-call doctor on a breach
+pub(crate) fn raise_vitals_alerts(device_id: u64, points: &[VitalsPoint]) {
+    let Some(patient_id) = points.first().map(|point| point.patient_id) else {
+        return;
+    };
+    let Some(patient) = patient_header(patient_id) else {
+        return;
+    };
+    let now = time();
+    for hospital_id in patient.hospitals_ids {
+        let breaching: Vec<(&VitalsPoint, Vec<String>)> = points
+            .iter()
+            .map(|point| (point, vitals_breaches(hospital_id, &point.vitals)))
+            .filter(|(_, breaches)| !breaches.is_empty())
+            .collect();
+        let (Some((first, breaches)), Some((last, _))) = (breaching.first(), breaching.last())
+        else {
+            continue;
+        };
+        let open = open_vitals_alerts(|alert| {
+            alert.hospital_id == hospital_id && alert.patient_id == patient_id
+        });
+        if let Some(mut alert) = open.into_iter().next() {
+            alert.breaching_readings += breaching.len() as u64;
+            alert.last_taken_at = alert.last_taken_at.max(last.taken_at);
+            save_vitals_alert(&alert);
+            continue;
+        }
+        let breach = clip_breach(breaches.join("; "));
+        let alert = VitalsAlert {
+            id: next_id(),
+            hospital_id,
+            patient_id,
+            device_id,
+            breach,
+            breaching_readings: breaching.len() as u64,
+            first_taken_at: first.taken_at,
+            last_taken_at: last.taken_at,
+            paged_doctor_id: on_call_doctor(hospital_id, &patient.doctors_ids, now),
+            raised_at: now,
+            acknowledged_at: None,
+            acknowledged_by: None,
+            escalations: 0,
+            next_escalation_at: now + ACKNOWLEDGE_WITHIN_NS,
+        };
+        save_vitals_alert(&alert);
+        match alert.paged_doctor_id {
+            Some(doctor_id) => page(Recipient::Doctor(doctor_id), alert_text(&alert)),
+            None => page(Recipient::Hospital(hospital_id), alert_text(&alert)),
+        };
+        audit(
+            Actor::System,
+            Some(hospital_id),
+            Some(patient_id),
+            "vitals_alert_raised",
+            format!("vitals alert {} from device {}", alert.id, device_id),
+        );
+    }
+}
+
+// any doctor of the hospital can take over an alert, usually the one who was paged
+#[ic_cdk::update]
+fn acknowledge_vitals_alert(
+    doctor_id: u64,
+    doctor_password: String,
+    alert_id: u64,
+) -> Result<VitalsAlert, Error> {
+    let doctor = authorize_doctor(doctor_id, &doctor_password)?;
+    let alert = VITALS_ALERT_STORAGE
+        .with(|s| s.borrow().get(&alert_id))
+        .filter(|alert| alert.hospital_id == doctor.hospital_id)
+        .ok_or(Error::NotFound {
+            msg: format!("Vitals alert of id: {} not found", alert_id),
+        })?;
+    if alert.acknowledged_at.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: format!("Vitals alert of id: {} is already acknowledged", alert.id),
+        });
+    }
+    let acknowledged = VitalsAlert {
+        acknowledged_at: Some(time()),
+        acknowledged_by: Some(doctor.id),
+        ..alert
+    };
+    save_vitals_alert(&acknowledged);
+    audit(
+        Actor::Doctor(doctor.id),
+        Some(acknowledged.hospital_id),
+        Some(acknowledged.patient_id),
+        "vitals_alert_acknowledged",
+        format!("vitals alert {}", acknowledged.id),
+    );
+    Ok(acknowledged)
+}
+
+// unacknowledged vitals alerts of the doctor's hospital, oldest first
+#[ic_cdk::query]
+fn get_open_vitals_alerts(
+    doctor_id: u64,
+    doctor_password: String,
+) -> Result<Vec<VitalsAlert>, Error> {
+    let doctor = authorize_doctor(doctor_id, &doctor_password)?;
+    let mut alerts = open_vitals_alerts(|alert| alert.hospital_id == doctor.hospital_id);
+    alerts.sort_by_key(|alert| alert.id);
+    Ok(alerts)
+}
+
+// timer task: page the other on-call doctors and the hospital about alerts the paged doctor
+// left unacknowledged, then the hospital again after every further period up to MAX_ESCALATIONS
+pub(crate) fn escalate_vitals_alerts() {
+    let now = time();
+    let ids: Vec<u64> = OPEN_VITALS_ALERTS.with(|index| {
+        index
+            .borrow()
+            .range(..=(now, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    let overdue: Vec<VitalsAlert> =
+        VITALS_ALERT_STORAGE.with(|s| ids.iter().filter_map(|id| s.borrow().get(id)).collect());
+    for alert in overdue {
+        let escalation_text = || {
+            text(
+                "vitals_alert.overdue",
+                "Device vitals alert for patient {patient} is still unacknowledged: {breach}",
+                vec![
+                    ("patient", alert.patient_id.to_string()),
+                    ("breach", alert.breach.clone()),
+                ],
+            )
+        };
+        if alert.escalations == 0 {
+            let others = on_call_doctors(alert.hospital_id, None, now)
+                .into_iter()
+                .filter(|doctor| Some(doctor.id) != alert.paged_doctor_id);
+            for doctor in others {
+                page(Recipient::Doctor(doctor.id), escalation_text());
+            }
+        }
+        page(Recipient::Hospital(alert.hospital_id), escalation_text());
+        audit(
+            Actor::System,
+            Some(alert.hospital_id),
+            Some(alert.patient_id),
+            "vitals_alert_escalated",
+            format!("vitals alert {}", alert.id),
+        );
+        save_vitals_alert(&VitalsAlert {
+            escalations: alert.escalations + 1,
+            next_escalation_at: now + ACKNOWLEDGE_WITHIN_NS,
+            ..alert.clone()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{advance_clock, clinic, must, PASSWORD};
+
+    fn raise(hospital_id: u64, patient_id: u64) -> VitalsAlert {
+        let alert = VitalsAlert {
+            id: next_id(),
+            hospital_id,
+            patient_id,
+            device_id: 1,
+            breach: "pulse above 120".to_string(),
+            breaching_readings: 1,
+            first_taken_at: time(),
+            last_taken_at: time(),
+            paged_doctor_id: None,
+            raised_at: time(),
+            acknowledged_at: None,
+            acknowledged_by: None,
+            escalations: 0,
+            next_escalation_at: time() + ACKNOWLEDGE_WITHIN_NS,
+        };
+        save_vitals_alert(&alert);
+        alert
+    }
+
+    fn stored(alert_id: u64) -> VitalsAlert {
+        VITALS_ALERT_STORAGE.with(|s| s.borrow().get(&alert_id).unwrap())
+    }
+
+    #[test]
+    fn escalation_stops_at_the_maximum_but_the_alert_stays_open() {
+        let clinic = clinic();
+        let alert = raise(clinic.hospital_id, clinic.patient_id);
+        escalate_vitals_alerts();
+        assert_eq!(stored(alert.id).escalations, 0);
+        for _ in 0..MAX_ESCALATIONS + 3 {
+            advance_clock(ACKNOWLEDGE_WITHIN_NS);
+            escalate_vitals_alerts();
+        }
+        assert_eq!(stored(alert.id).escalations, MAX_ESCALATIONS);
+        let open = must(get_open_vitals_alerts(
+            clinic.doctor_id,
+            PASSWORD.to_string(),
+        ));
+        assert_eq!(open.len(), 1);
+    }
+
+    #[test]
+    fn an_acknowledged_alert_leaves_the_index_and_is_not_escalated() {
+        let clinic = clinic();
+        let alert = raise(clinic.hospital_id, clinic.patient_id);
+        must(acknowledge_vitals_alert(
+            clinic.doctor_id,
+            PASSWORD.to_string(),
+            alert.id,
+        ));
+        assert!(OPEN_VITALS_ALERTS.with(|index| index.borrow().is_empty()));
+        advance_clock(ACKNOWLEDGE_WITHIN_NS);
+        escalate_vitals_alerts();
+        assert_eq!(stored(alert.id).escalations, 0);
+        assert!(must(get_open_vitals_alerts(
+            clinic.doctor_id,
+            PASSWORD.to_string()
+        ))
+        .is_empty());
+    }
+
+    #[test]
+    fn a_long_breach_is_cut_on_a_character_boundary() {
+        let breach = clip_breach("é".repeat(MAX_BREACH_BYTES));
+        assert!(breach.len() <= MAX_BREACH_BYTES);
+        assert_eq!(breach, "é".repeat(MAX_BREACH_BYTES / 2));
+    }
+}
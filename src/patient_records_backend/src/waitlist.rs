@@ -0,0 +1,237 @@
+use crate::time;
+use crate::{
+    authenticate_patient, authorize_doctor, format_local_time, impl_storable, next_id, notify,
+    place_appointment, text, utc_offset, Appointment, Error, Memory, PatientConsent, Priority,
+    Recipient, DOCTOR_STORAGE, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// how long a waitlisted patient has to claim an offered slot
+const CLAIM_WINDOW_NS: u64 = 30 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub enum WaitlistStatus {
+    Waiting,
+    // a slot is held for the patient as the given appointment
+    Offered { appointment_id: u64 },
+    Booked { appointment_id: u64 },
+    // the patient let an offer lapse and has to join again
+    Expired,
+    Left,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct WaitlistEntry {
+    pub id: u64,
+    pub doctor_id: u64,
+    pub patient_id: u64,
+    pub priority: Priority,
+    pub reason: String,
+    pub joined_at: u64,
+    pub status: WaitlistStatus,
+}
+
+impl_storable!(WaitlistEntry, 512);
+
+thread_local! {
+    static WAITLIST_STORAGE: RefCell<StableBTreeMap<u64, WaitlistEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct JoinWaitlistPayload {
+    pub patient_id: u64,
+    pub patient_password: String,
+    pub doctor_id: u64,
+    pub reason: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct WaitlistPriorityPayload {
+    pub doctor_id: u64,
+    pub doctor_password: String,
+    pub entry_id: u64,
+    pub priority: Priority,
+}
+
+fn save_entry(entry: &WaitlistEntry) {
+    WAITLIST_STORAGE.with(|s| s.borrow_mut().insert(entry.id, entry.clone()));
+}
+
+fn entries(keep: impl Fn(&WaitlistEntry) -> bool) -> Vec<WaitlistEntry> {
+    WAITLIST_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| keep(entry))
+            .collect()
+    })
+}
+
+fn priority_rank(priority: Priority) -> u8 {
+    match priority {
+        Priority::High => 0,
+        Priority::Normal => 1,
+        Priority::Low => 2,
+    }
+}
+
+// waiting patients of a doctor, highest priority and longest wait first
+fn doctor_queue(doctor_id: u64) -> Vec<WaitlistEntry> {
+    let mut queue =
+        entries(|entry| entry.doctor_id == doctor_id && entry.status == WaitlistStatus::Waiting);
+    queue.sort_by_key(|entry| (priority_rank(entry.priority), entry.joined_at));
+    queue
+}
+
+// hold a freed slot for the first waiting patient who can take it
+pub(crate) fn offer_slot_to_waitlist(freed: &Appointment) {
+    // a lapsed offer sends its entry out of the queue
+    for mut entry in entries(|entry| {
+        entry.status
+            == WaitlistStatus::Offered {
+                appointment_id: freed.id,
+            }
+    }) {
+        entry.status = WaitlistStatus::Expired;
+        save_entry(&entry);
+    }
+    let doctor = match DOCTOR_STORAGE.with(|s| s.borrow().get(&freed.doctor_id)) {
+        Some(doctor) => doctor,
+        None => return,
+    };
+    for mut entry in doctor_queue(doctor.id) {
+        let offer = place_appointment(
+            entry.patient_id,
+            &doctor,
+            freed.site_id,
+            freed.start,
+            freed.end,
+            entry.reason.clone(),
+            Some(CLAIM_WINDOW_NS),
+        );
+        if let Ok(appointment) = offer {
+            entry.status = WaitlistStatus::Offered {
+                appointment_id: appointment.id,
+            };
+            save_entry(&entry);
+            notify(
+                Recipient::Patient(entry.patient_id),
+                Priority::High,
+                text(
+                    "waitlist.slot_offered",
+                    "A slot with doctor {doctor} at {start} is held for you as appointment {appointment}, confirm it within 30 minutes",
+                    vec![
+                        ("doctor", doctor.id.to_string()),
+                        (
+                            "start",
+                            format_local_time(
+                                appointment.start,
+                                utc_offset(&Recipient::Patient(entry.patient_id)),
+                            ),
+                        ),
+                        ("appointment", appointment.id.to_string()),
+                    ],
+                ),
+            );
+            return;
+        }
+    }
+}
+
+pub(crate) fn waitlist_offer_claimed(appointment: &Appointment) {
+    for mut entry in entries(|entry| {
+        entry.status
+            == WaitlistStatus::Offered {
+                appointment_id: appointment.id,
+            }
+    }) {
+        entry.status = WaitlistStatus::Booked {
+            appointment_id: appointment.id,
+        };
+        save_entry(&entry);
+    }
+}
+
+#[ic_cdk::update]
+fn join_waitlist(payload: JoinWaitlistPayload) -> Result<WaitlistEntry, Error> {
+    let patient = authenticate_patient(payload.patient_id, &payload.patient_password)?;
+    if !DOCTOR_STORAGE.with(|s| s.borrow().contains_key(&payload.doctor_id)) {
+        return Err(Error::NotFound {
+            msg: format!("Doctor of id: {} not found", payload.doctor_id),
+        });
+    }
+    if doctor_queue(payload.doctor_id)
+        .iter()
+        .any(|entry| entry.patient_id == patient.id)
+    {
+        return Err(Error::AlreadyInit {
+            msg: "Patient is already on this doctor's waitlist".to_string(),
+        });
+    }
+    let entry = WaitlistEntry {
+        id: next_id(),
+        doctor_id: payload.doctor_id,
+        patient_id: patient.id,
+        priority: Priority::Normal,
+        reason: payload.reason,
+        joined_at: time(),
+        status: WaitlistStatus::Waiting,
+    };
+    save_entry(&entry);
+    Ok(entry)
+}
+
+#[ic_cdk::update]
+fn leave_waitlist(consent: PatientConsent, entry_id: u64) -> Result<WaitlistEntry, Error> {
+    let patient = authenticate_patient(consent.patient_id, &consent.patient_password)?;
+    let mut entry = entries(|entry| entry.id == entry_id && entry.patient_id == patient.id)
+        .pop()
+        .ok_or(Error::NotFound {
+            msg: format!("Waitlist entry of id: {} not found", entry_id),
+        })?;
+    entry.status = WaitlistStatus::Left;
+    save_entry(&entry);
+    Ok(entry)
+}
+
+// take the patient off every waitlist they are still on
+pub(crate) fn leave_all_waitlists(patient_id: u64) {
+    for mut entry in entries(|entry| {
+        entry.patient_id == patient_id
+            && matches!(
+                entry.status,
+                WaitlistStatus::Waiting | WaitlistStatus::Offered { .. }
+            )
+    }) {
+        entry.status = WaitlistStatus::Left;
+        save_entry(&entry);
+    }
+}
+
+// the doctor raises or lowers a waiting patient's priority
+#[ic_cdk::update]
+fn set_waitlist_priority(payload: WaitlistPriorityPayload) -> Result<WaitlistEntry, Error> {
+    let doctor = authorize_doctor(payload.doctor_id, &payload.doctor_password)?;
+    let mut entry = entries(|entry| entry.id == payload.entry_id && entry.doctor_id == doctor.id)
+        .pop()
+        .ok_or(Error::NotFound {
+            msg: format!("Waitlist entry of id: {} not found", payload.entry_id),
+        })?;
+    entry.priority = payload.priority;
+    save_entry(&entry);
+    Ok(entry)
+}
+
+#[ic_cdk::query]
+fn get_doctor_waitlist(
+    doctor_id: u64,
+    doctor_password: String,
+) -> Result<Vec<WaitlistEntry>, Error> {
+    let doctor = authorize_doctor(doctor_id, &doctor_password)?;
+    Ok(doctor_queue(doctor.id))
+}
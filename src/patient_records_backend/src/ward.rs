@@ -0,0 +1,253 @@
+use crate::time;
+use crate::{
+    active_infection_flags, authorize_hospital, authorize_nurse, get_encounter_by_id,
+    get_hospital_site, impl_storable, next_id, requires_isolation, EncounterStatus, Error,
+    InfectionFlag, Memory, MEMORY_MANAGER,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct Ward {
+    pub id: u64,
+    pub hospital_id: u64,
+    pub name: String,
+    pub beds: u32,
+    // the site it is at, None for hospitals without sites
+    pub site_id: Option<u64>,
+}
+
+// The bed an admitted patient occupies, keyed by the admission's encounter
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct BedAssignment {
+    pub encounter_id: u64,
+    pub patient_id: u64,
+    pub ward_id: u64,
+    // 1-based bed number within the ward
+    pub bed: u32,
+    pub assigned_by: u64,
+    pub assigned_at: u64,
+}
+
+// An occupied bed with the patient's infection flags, so isolation is visible at a glance
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct OccupiedBed {
+    pub assignment: BedAssignment,
+    pub infection_flags: Vec<InfectionFlag>,
+}
+
+impl_storable!(Ward, 256);
+impl_storable!(BedAssignment, 256);
+
+thread_local! {
+    static WARD_STORAGE: RefCell<StableBTreeMap<u64, Ward, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    static BED_STORAGE: RefCell<StableBTreeMap<u64, BedAssignment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(97)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct AssignBedPayload {
+    pub nurse_id: u64,
+    pub nurse_password: String,
+    pub encounter_id: u64,
+    pub ward_id: u64,
+    pub bed: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub struct WardPayload {
+    pub hospital_id: u64,
+    pub hospital_password: String,
+    pub name: String,
+    pub beds: u32,
+    pub site_id: Option<u64>,
+}
+
+pub(crate) fn get_ward(ward_id: u64) -> Result<Ward, Error> {
+    WARD_STORAGE
+        .with(|s| s.borrow().get(&ward_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Ward of id: {} not found", ward_id),
+        })
+}
+
+// helper function to get a ward and check it belongs to the hospital
+pub(crate) fn get_hospital_ward(hospital_id: u64, ward_id: u64) -> Result<Ward, Error> {
+    let ward = get_ward(ward_id)?;
+    if ward.hospital_id != hospital_id {
+        return Err(Error::Unauthorized {
+            msg: format!("Ward of id: {} belongs to another hospital", ward.id),
+        });
+    }
+    Ok(ward)
+}
+
+fn wards(hospital_id: u64, site_id: Option<u64>) -> Vec<Ward> {
+    WARD_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, ward)| ward)
+            .filter(|ward| {
+                ward.hospital_id == hospital_id && (site_id.is_none() || ward.site_id == site_id)
+            })
+            .collect()
+    })
+}
+
+// beds in all wards of a site
+pub(crate) fn site_beds(site_id: u64) -> u32 {
+    WARD_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, ward)| ward)
+            .filter(|ward| ward.site_id == Some(site_id))
+            .map(|ward| ward.beds)
+            .sum()
+    })
+}
+
+#[ic_cdk::update]
+fn add_ward(payload: WardPayload) -> Result<Ward, Error> {
+    let hospital = authorize_hospital(payload.hospital_id, &payload.hospital_password)?;
+    if payload.name.trim().is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "Ward name cannot be empty".to_string(),
+        });
+    }
+    if let Some(site_id) = payload.site_id {
+        let site = get_hospital_site(hospital.id, site_id)?;
+        if site_beds(site.id) + payload.beds > site.bed_capacity {
+            return Err(Error::LimitExceeded {
+                msg: format!(
+                    "Site of id: {} has room for {} beds",
+                    site.id, site.bed_capacity
+                ),
+            });
+        }
+    }
+    let ward = Ward {
+        id: next_id(),
+        hospital_id: hospital.id,
+        name: payload.name,
+        beds: payload.beds,
+        site_id: payload.site_id,
+    };
+    WARD_STORAGE.with(|s| s.borrow_mut().insert(ward.id, ward.clone()));
+    Ok(ward)
+}
+
+// wards of the whole organization, or of one of its sites
+#[ic_cdk::query]
+fn get_hospital_wards(hospital_id: u64, site_id: Option<u64>) -> Vec<Ward> {
+    wards(hospital_id, site_id)
+}
+
+pub(crate) fn admission_bed(encounter_id: u64) -> Option<BedAssignment> {
+    BED_STORAGE.with(|s| s.borrow().get(&encounter_id))
+}
+
+// free the bed of an admission, when the patient is discharged
+pub(crate) fn release_admission_bed(encounter_id: u64) {
+    BED_STORAGE.with(|s| s.borrow_mut().remove(&encounter_id));
+}
+
+fn ward_occupancy(ward_id: u64) -> Vec<BedAssignment> {
+    BED_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, assignment)| assignment)
+            .filter(|assignment| assignment.ward_id == ward_id)
+            .collect()
+    })
+}
+
+// put an admitted patient in a bed, or move them to another; patients under isolation can
+// only go to single-bed wards
+#[ic_cdk::update]
+fn assign_bed(payload: AssignBedPayload) -> Result<BedAssignment, Error> {
+    let nurse = authorize_nurse(payload.nurse_id, &payload.nurse_password)?;
+    let encounter = get_encounter_by_id(payload.encounter_id)?;
+    if encounter.hospital_id != nurse.hospital_id {
+        return Err(Error::Unauthorized {
+            msg: format!("Encounter of id: {} is at another hospital", encounter.id),
+        });
+    }
+    if encounter.status != EncounterStatus::Open {
+        return Err(Error::InvalidPayload {
+            msg: format!("Encounter of id: {} is already closed", encounter.id),
+        });
+    }
+    let ward = get_hospital_ward(nurse.hospital_id, payload.ward_id)?;
+    if payload.bed == 0 || payload.bed > ward.beds {
+        return Err(Error::InvalidPayload {
+            msg: format!("Ward {} has beds 1 to {}", ward.name, ward.beds),
+        });
+    }
+    if ward.beds > 1 && requires_isolation(encounter.patient_id) {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "The patient requires isolation and cannot be placed in shared ward {}",
+                ward.name
+            ),
+        });
+    }
+    let occupied = ward_occupancy(ward.id)
+        .iter()
+        .any(|assignment| assignment.bed == payload.bed && assignment.encounter_id != encounter.id);
+    if occupied {
+        return Err(Error::AlreadyInit {
+            msg: format!("Bed {} of ward {} is occupied", payload.bed, ward.name),
+        });
+    }
+    let assignment = BedAssignment {
+        encounter_id: encounter.id,
+        patient_id: encounter.patient_id,
+        ward_id: ward.id,
+        bed: payload.bed,
+        assigned_by: nurse.id,
+        assigned_at: time(),
+    };
+    BED_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(assignment.encounter_id, assignment.clone())
+    });
+    Ok(assignment)
+}
+
+#[ic_cdk::update]
+fn release_bed(nurse_id: u64, nurse_password: String, encounter_id: u64) -> Result<(), Error> {
+    let nurse = authorize_nurse(nurse_id, &nurse_password)?;
+    let assignment = admission_bed(encounter_id).ok_or(Error::NotFound {
+        msg: format!("Encounter of id: {} has no bed", encounter_id),
+    })?;
+    get_hospital_ward(nurse.hospital_id, assignment.ward_id)?;
+    release_admission_bed(encounter_id);
+    Ok(())
+}
+
+// who is in which bed of a ward
+#[ic_cdk::query]
+fn get_ward_occupancy(
+    nurse_id: u64,
+    nurse_password: String,
+    ward_id: u64,
+) -> Result<Vec<OccupiedBed>, Error> {
+    let nurse = authorize_nurse(nurse_id, &nurse_password)?;
+    let ward = get_hospital_ward(nurse.hospital_id, ward_id)?;
+    let mut occupancy = ward_occupancy(ward.id);
+    occupancy.sort_by_key(|assignment| assignment.bed);
+    Ok(occupancy
+        .into_iter()
+        .map(|assignment| OccupiedBed {
+            infection_flags: active_infection_flags(assignment.patient_id),
+            assignment,
+        })
+        .collect())
+}
@@ -0,0 +1,203 @@
+use crate::{
+    account_of, authenticate_patient, authorize_auditor, authorize_doctor, authorize_hospital,
+    authorize_nurse, caller, caller_caregiver_grants, caller_kiosk_permissions, is_controller,
+    AccountRole, CaregiverScope, KioskPermission,
+};
+use candid::Principal;
+
+// Something the caller can do, for frontends to decide which screens to show
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Permission {
+    // hospital admin
+    ManageStaff,
+    ManageHospitalSettings,
+    ApproveRegistrations,
+    ReviewHospitalAuditLog,
+    // doctor
+    ReadAssignedPatients,
+    WriteMedicalRecords,
+    Prescribe,
+    // nurse
+    ViewHospitalPatients,
+    ManageOwnShifts,
+    // patient
+    ReadOwnRecord,
+    ManageOwnSharing,
+    // auditor
+    ReviewAuditLog,
+    ManageIncidents,
+    // caregiver, for the patient of the grant
+    ViewPatientAppointments(u64),
+    ReceivePatientNotifications(u64),
+    // waiting room kiosk device
+    KioskCheckIn,
+    KioskQueueDisplay,
+    // canister controller
+    AdministerCanister,
+}
+
+// A role the caller holds together with the entity it resolves to
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct ResolvedRole {
+    pub role: AccountRole,
+    pub name: String,
+    pub hospital_id: Option<u64>,
+    // patients of a doctor or hospital, doctors of a hospital or a patient
+    pub linked_patient_ids: Vec<u64>,
+    pub linked_doctor_ids: Vec<u64>,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub struct WhoAmI {
+    pub principal: Principal,
+    pub anonymous: bool,
+    pub controller: bool,
+    pub roles: Vec<ResolvedRole>,
+    // active caregiver grants, by patient id
+    pub caregiver_for: Vec<u64>,
+    // everything the caller can do across all roles and grants
+    pub permissions: Vec<Permission>,
+}
+
+// the role's entity through the caller's principal, which the authorize helpers accept in
+// place of the password; None when the entity no longer exists
+fn resolve_role(role: AccountRole) -> Option<ResolvedRole> {
+    let resolved =
+        |name, hospital_id, patients, doctors, permissions: &[Permission]| ResolvedRole {
+            role,
+            name,
+            hospital_id,
+            linked_patient_ids: patients,
+            linked_doctor_ids: doctors,
+            permissions: permissions.to_vec(),
+        };
+    let resolved = match role {
+        AccountRole::Hospital(id) => {
+            let hospital = authorize_hospital(id, "").ok()?;
+            resolved(
+                hospital.name,
+                Some(hospital.id),
+                hospital.patients_ids,
+                hospital.doctors_ids,
+                &[
+                    Permission::ManageStaff,
+                    Permission::ManageHospitalSettings,
+                    Permission::ApproveRegistrations,
+                    Permission::ReviewHospitalAuditLog,
+                ],
+            )
+        }
+        AccountRole::Doctor(id) => {
+            let doctor = authorize_doctor(id, "").ok()?;
+            resolved(
+                doctor.name,
+                Some(doctor.hospital_id),
+                doctor.patient_ids,
+                vec![],
+                &[
+                    Permission::ReadAssignedPatients,
+                    Permission::WriteMedicalRecords,
+                    Permission::Prescribe,
+                ],
+            )
+        }
+        AccountRole::Nurse(id) => {
+            let nurse = authorize_nurse(id, "").ok()?;
+            resolved(
+                nurse.name,
+                Some(nurse.hospital_id),
+                vec![],
+                vec![],
+                &[
+                    Permission::ViewHospitalPatients,
+                    Permission::ManageOwnShifts,
+                ],
+            )
+        }
+        AccountRole::Patient(id) => {
+            let patient = authenticate_patient(id, "").ok()?;
+            resolved(
+                patient.name,
+                None,
+                vec![patient.id],
+                patient.doctors_ids,
+                &[Permission::ReadOwnRecord, Permission::ManageOwnSharing],
+            )
+        }
+        AccountRole::Auditor(id) => {
+            let auditor = authorize_auditor(id, "").ok()?;
+            resolved(
+                auditor.name,
+                Some(auditor.hospital_id),
+                vec![],
+                vec![],
+                &[Permission::ReviewAuditLog, Permission::ManageIncidents],
+            )
+        }
+    };
+    Some(resolved)
+}
+
+// the caller's identity, linked roles and effective permissions in one call, so frontends
+// can render the right UI without probing endpoints
+#[ic_cdk::query]
+fn whoami() -> WhoAmI {
+    let principal = caller();
+    let anonymous = principal == Principal::anonymous();
+    let controller = is_controller(&principal);
+    let roles: Vec<ResolvedRole> = if anonymous {
+        vec![]
+    } else {
+        account_of(&principal)
+            .roles
+            .into_iter()
+            .filter_map(resolve_role)
+            .collect()
+    };
+    let grants = if anonymous {
+        vec![]
+    } else {
+        caller_caregiver_grants()
+    };
+
+    let mut permissions: Vec<Permission> = vec![];
+    let mut grant = |permission: Permission| {
+        if !permissions.contains(&permission) {
+            permissions.push(permission);
+        }
+    };
+    if controller {
+        grant(Permission::AdministerCanister);
+    }
+    for role in &roles {
+        role.permissions.iter().copied().for_each(&mut grant);
+    }
+    for permission in caller_kiosk_permissions() {
+        grant(match permission {
+            KioskPermission::CheckIn => Permission::KioskCheckIn,
+            KioskPermission::QueueDisplay => Permission::KioskQueueDisplay,
+        });
+    }
+    for caregiver_grant in &grants {
+        for scope in &caregiver_grant.scopes {
+            grant(match scope {
+                CaregiverScope::ViewAppointments => {
+                    Permission::ViewPatientAppointments(caregiver_grant.patient_id)
+                }
+                CaregiverScope::ReceiveNotifications => {
+                    Permission::ReceivePatientNotifications(caregiver_grant.patient_id)
+                }
+            });
+        }
+    }
+
+    WhoAmI {
+        principal,
+        anonymous,
+        controller,
+        roles,
+        caregiver_for: grants.iter().map(|grant| grant.patient_id).collect(),
+        permissions,
+    }
+}